@@ -3,10 +3,10 @@ use clap::Parser;
 use cli_table::{format::Justify, print_stdout, Cell, CellStruct, Style, Table};
 use mistralrs_core::{
     initialize_logging, paged_attn_supported, Constraint, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, DrySamplingParams, Loader, LoaderBuilder,
-    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected, NormalRequest,
-    PagedAttentionConfig, Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
-    TokenSource, Usage,
+    DeviceLayerMapMetadata, DeviceMapMetadata, DrySamplingParams, KVCacheDtype, Loader,
+    LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected,
+    NormalRequest, PagedAttentionConfig, Request, RequestMessage, Response, SamplingParams,
+    SchedulerConfig, TokenSource, Usage,
 };
 use std::sync::Arc;
 use std::{fmt::Display, num::NonZeroUsize};
@@ -58,14 +58,31 @@ fn run_bench(
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        xtc_probability: None,
+        xtc_threshold: None,
+        typical_p: None,
+        epsilon_cutoff: None,
+        eta_cutoff: None,
         top_n_logprobs: 0,
+        collect_all_logprobs: false,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        no_repeat_ngram_size: None,
         max_len: Some(n_gen),
+        min_new_tokens: None,
         stop_toks: None,
         logits_bias: None,
+        string_logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        beam_search: None,
+        contrastive_search: None,
+        cfg_scale: None,
+        negative_prompt: None,
+        seed: None,
+        bad_token_ids: None,
+        token_healing: false,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -82,6 +99,8 @@ fn run_bench(
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
 
@@ -115,6 +134,7 @@ fn run_bench(
                     }
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::ImageGenerationProgress(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -223,14 +243,31 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        xtc_probability: None,
+        xtc_threshold: None,
+        typical_p: None,
+        epsilon_cutoff: None,
+        eta_cutoff: None,
         top_n_logprobs: 0,
+        collect_all_logprobs: false,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        no_repeat_ngram_size: None,
         max_len: Some(5),
+        min_new_tokens: None,
         stop_toks: None,
         logits_bias: None,
+        string_logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        beam_search: None,
+        contrastive_search: None,
+        cfg_scale: None,
+        negative_prompt: None,
+        seed: None,
+        bad_token_ids: None,
+        token_healing: false,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -251,6 +288,8 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
 
@@ -294,6 +333,16 @@ struct Args {
     #[arg(short, long, value_parser, value_delimiter = ';')]
     num_device_layers: Option<Vec<String>>,
 
+    /// Keep at most this many repeating layers on the GPU, paging the others in from the CPU when
+    /// they are run. Only supported for unquantized plain models.
+    #[arg(long, conflicts_with = "num_device_layers")]
+    paged_gpu_layers: Option<usize>,
+
+    /// Split every repeating layer across the CUDA devices with these ordinals, separated by `;`.
+    /// The first ordinal must be the model device. Only supported for unquantized plain Llama models.
+    #[arg(long, value_delimiter = ';', conflicts_with_all = ["num_device_layers", "paged_gpu_layers"])]
+    tensor_parallel: Option<Vec<usize>>,
+
     /// GPU memory to allocate for KV cache with PagedAttention in MBs. If this is not set and the device is CUDA, it will default to
     /// using `pa-gpu-mem-usage` set to `0.9`. PagedAttention is only supported on CUDA and is always automatically activated.
     #[arg(long = "pa-gpu-mem")]
@@ -323,6 +372,10 @@ struct Args {
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
+
+    /// Dtype the KV cache is stored in: `full`, `int8` or `fp8`. Quantized KV caches reduce memory usage, they are not used with PagedAttention.
+    #[arg(long = "kv-cache-dtype", default_value_t = KVCacheDtype::FullPrecision)]
+    kv_cache_dtype: KVCacheDtype,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -347,6 +400,7 @@ fn main() -> anyhow::Result<()> {
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
         .with_use_flash_attn(use_flash_attn)
         .with_prompt_batchsize(prompt_batchsize)
+        .with_kv_cache_dtype(args.kv_cache_dtype)
         .build()?;
     let model_name = loader.get_id();
 
@@ -377,7 +431,11 @@ fn main() -> anyhow::Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let mapper = if let Some(ordinals) = args.tensor_parallel {
+        DeviceMapMetadata::tensor_parallel(ordinals)
+    } else if let Some(max_gpu_layers) = args.paged_gpu_layers {
+        DeviceMapMetadata::paged(max_gpu_layers)
+    } else if let Some(device_layers) = args.num_device_layers {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
             DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
@@ -0,0 +1,210 @@
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+
+use candle_core::Result;
+use candle_nn::VarMap;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+const CHECKPOINT_WEIGHTS: &str = "gate_checkpoint.safetensors";
+const CHECKPOINT_METADATA: &str = "gate_checkpoint.json";
+
+/// Describes the gating layers saved in a checkpoint, and how far their training got.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct AnyMoeCheckpointMetadata {
+    pub hidden_size: usize,
+    pub n_layers: usize,
+    pub n_experts: usize,
+    /// Number of completed epochs.
+    pub epochs: usize,
+    /// One for each gating layer
+    pub final_loss: Vec<f32>,
+}
+
+impl AnyMoeCheckpointMetadata {
+    /// The number of gating layers and experts are taken from the gating weights in `var_map`.
+    pub fn new(
+        var_map: &VarMap,
+        hidden_size: usize,
+        epochs: usize,
+        final_loss: Vec<f32>,
+    ) -> Result<Self> {
+        let (n_layers, n_experts) = gate_shape(var_map)?;
+        Ok(Self {
+            hidden_size,
+            n_layers,
+            n_experts,
+            epochs,
+            final_loss,
+        })
+    }
+
+    fn same_shape(&self, other: &Self) -> bool {
+        (self.hidden_size, self.n_layers, self.n_experts)
+            == (other.hidden_size, other.n_layers, other.n_experts)
+    }
+}
+
+/// Number of gating layers and experts of the gating weights in `var_map`.
+fn gate_shape(var_map: &VarMap) -> Result<(usize, usize)> {
+    let data = var_map.data().lock().unwrap();
+    let mut n_layers = 0;
+    let mut n_experts = 0;
+    for (name, var) in data.iter() {
+        if name.ends_with(".weight") {
+            n_layers += 1;
+            n_experts = var.dim(0)?;
+        }
+    }
+    Ok((n_layers, n_experts))
+}
+
+/// Save the gating weights of `var_map` and the metadata to `dir`, replacing any previous checkpoint.
+pub(crate) fn save_checkpoint(
+    dir: &Path,
+    var_map: &VarMap,
+    metadata: &AnyMoeCheckpointMetadata,
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    // Write to temporary files first so an interrupted save does not leave a broken checkpoint.
+    let weights_tmp = dir.join(format!("{CHECKPOINT_WEIGHTS}.tmp"));
+    let metadata_tmp = dir.join(format!("{CHECKPOINT_METADATA}.tmp"));
+    var_map.save(&weights_tmp)?;
+    serde_json::to_writer(File::create(&metadata_tmp)?, metadata)
+        .map_err(candle_core::Error::msg)?;
+    fs::rename(weights_tmp, dir.join(CHECKPOINT_WEIGHTS))?;
+    fs::rename(metadata_tmp, dir.join(CHECKPOINT_METADATA))?;
+    Ok(())
+}
+
+/// Load the gating weights of a checkpoint in `dir` into the vars of `var_map`, returning its
+/// metadata. Returns `None` and leaves the vars untouched if there is no checkpoint, or if it was
+/// saved for a different number of layers, experts, or hidden size.
+pub(crate) fn load_checkpoint(
+    dir: &Path,
+    var_map: &mut VarMap,
+    hidden_size: usize,
+) -> Result<Option<AnyMoeCheckpointMetadata>> {
+    let metadata_path = dir.join(CHECKPOINT_METADATA);
+    let weights_path = dir.join(CHECKPOINT_WEIGHTS);
+    if !metadata_path.exists() || !weights_path.exists() {
+        info!("No gating layer checkpoint found in `{}`.", dir.display());
+        return Ok(None);
+    }
+
+    let metadata: AnyMoeCheckpointMetadata =
+        serde_json::from_reader(File::open(&metadata_path)?).map_err(candle_core::Error::msg)?;
+    let expected = AnyMoeCheckpointMetadata::new(var_map, hidden_size, 0, vec![])?;
+    if !metadata.same_shape(&expected) {
+        warn!(
+            "Ignoring the gating layer checkpoint in `{}`: it has {} layers, {} experts and a hidden size of {}, but the model has {} layers, {} experts and a hidden size of {}.",
+            dir.display(),
+            metadata.n_layers,
+            metadata.n_experts,
+            metadata.hidden_size,
+            expected.n_layers,
+            expected.n_experts,
+            expected.hidden_size,
+        );
+        return Ok(None);
+    }
+
+    var_map.load(&weights_path)?;
+    info!(
+        "Loaded gating layer checkpoint from `{}` after {} epochs.",
+        dir.display(),
+        metadata.epochs
+    );
+    Ok(Some(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor, D};
+    use candle_nn::{linear, AdamW, Module, Optimizer, ParamsAdamW, VarBuilder, VarMap};
+
+    use super::{load_checkpoint, save_checkpoint, AnyMoeCheckpointMetadata};
+
+    const HIDDEN: usize = 4;
+    const STEPS_PER_EPOCH: usize = 5;
+
+    /// A gating layer as created by `MoeMlp`, with zeroed weights so runs are comparable.
+    fn gate(n_experts: usize) -> candle_core::Result<(VarMap, candle_nn::Linear)> {
+        let var_map = VarMap::new();
+        let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+        let lin = linear(HIDDEN, n_experts, vb.pp("moe_gate").pp(0))?;
+        for var in var_map.all_vars() {
+            var.set(&var.zeros_like()?)?;
+        }
+        Ok((var_map, lin))
+    }
+
+    /// Train the gate to route by the sign of the first feature, returning the last loss.
+    fn train(var_map: &VarMap, lin: &candle_nn::Linear, epochs: usize) -> candle_core::Result<f32> {
+        let xs = Tensor::new(
+            &[
+                [1f32, 0.5, -0.5, 0.],
+                [-1., 0.5, -0.5, 0.],
+                [0.8, -0.2, 0.1, 0.3],
+                [-0.8, -0.2, 0.1, 0.3],
+            ],
+            &Device::Cpu,
+        )?;
+        let labels = Tensor::new(&[0u32, 1, 0, 1], &Device::Cpu)?;
+        let mut optimizer = AdamW::new(
+            var_map.all_vars(),
+            ParamsAdamW {
+                lr: 1e-2,
+                ..Default::default()
+            },
+        )?;
+        let mut loss = f32::INFINITY;
+        for _ in 0..epochs * STEPS_PER_EPOCH {
+            let probs = candle_nn::ops::softmax(&lin.forward(&xs)?, D::Minus1)?;
+            let step_loss = candle_nn::loss::cross_entropy(&probs, &labels)?;
+            optimizer.backward_step(&step_loss)?;
+            loss = step_loss.to_scalar::<f32>()?;
+        }
+        Ok(loss)
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() -> candle_core::Result<()> {
+        let dir = std::env::temp_dir().join(format!("amoe_checkpoint_{}", std::process::id()));
+
+        let (var_map, lin) = gate(2)?;
+        let loss = train(&var_map, &lin, 2)?;
+        let metadata = AnyMoeCheckpointMetadata::new(&var_map, HIDDEN, 2, vec![loss])?;
+        assert_eq!((metadata.n_layers, metadata.n_experts), (1, 2));
+        save_checkpoint(&dir, &var_map, &metadata)?;
+
+        // Resuming continues from the trained weights.
+        let (mut resumed_map, resumed_lin) = gate(2)?;
+        let loaded = load_checkpoint(&dir, &mut resumed_map, HIDDEN)?;
+        assert_eq!(loaded, Some(metadata));
+        let resumed_loss = train(&resumed_map, &resumed_lin, 1)?;
+
+        let (scratch_map, scratch_lin) = gate(2)?;
+        let scratch_loss = train(&scratch_map, &scratch_lin, 1)?;
+        assert!(
+            resumed_loss < scratch_loss,
+            "{resumed_loss} >= {scratch_loss}"
+        );
+
+        // A checkpoint for a different number of experts or hidden size is ignored.
+        let (mut other_map, _) = gate(3)?;
+        assert_eq!(load_checkpoint(&dir, &mut other_map, HIDDEN)?, None);
+        let (mut same_map, _) = gate(2)?;
+        assert_eq!(load_checkpoint(&dir, &mut same_map, HIDDEN + 1)?, None);
+        // The ignored checkpoint leaves the weights untouched.
+        for var in other_map.all_vars() {
+            let sum = var.abs()?.sum_all()?.to_scalar::<f32>()?;
+            assert_eq!(sum, 0.);
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,12 +1,18 @@
 use std::{fs::File, path::Path};
 
 use csv::Reader;
+use rand::{seq::SliceRandom, Rng};
 use serde::Deserialize;
 
 pub struct AnyMoeTrainingResult {
     pub steps: usize,
-    /// One for each gating layer
+    /// One for each gating layer. With a validation split, this is the training loss of the last
+    /// step of the best epoch, whose gating layers are kept.
     pub final_loss: Vec<f32>,
+    /// The lowest validation loss, one for each gating layer, if there is a validation split.
+    pub validation_loss: Option<Vec<f32>>,
+    /// The epoch with the lowest validation loss, if there is a validation split.
+    pub best_epoch: Option<usize>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -46,7 +52,77 @@ impl AnyMoeTrainingInputs {
         self.rows.len()
     }
 
+    /// Randomly split off `validation_fraction` of the rows, returning the training and validation
+    /// inputs. The validation inputs are empty if `validation_fraction` is 0.
+    pub fn split(
+        mut self,
+        validation_fraction: f64,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<(Self, Self)> {
+        if !(0.0..1.0).contains(&validation_fraction) {
+            anyhow::bail!(
+                "The validation split must be at least 0 and less than 1, got {validation_fraction}."
+            );
+        }
+        if validation_fraction == 0.0 {
+            return Ok((self, Self { rows: Vec::new() }));
+        }
+        #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+        let n_validation = (self.rows.len() as f64 * validation_fraction).round() as usize;
+        if n_validation == 0 || n_validation == self.rows.len() {
+            anyhow::bail!(
+                "A validation split of {validation_fraction} leaves no training or validation samples out of {}.",
+                self.rows.len()
+            );
+        }
+        self.rows.shuffle(rng);
+        let validation = self.rows.split_off(self.rows.len() - n_validation);
+        Ok((self, Self { rows: validation }))
+    }
+
     pub fn into_inner(self) -> Vec<AnyMoeTrainingInputRow> {
         self.rows
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+
+    use super::{AnyMoeTrainingInputRow, AnyMoeTrainingInputs};
+
+    fn inputs(n: usize) -> AnyMoeTrainingInputs {
+        AnyMoeTrainingInputs {
+            rows: (0..n)
+                .map(|expert| AnyMoeTrainingInputRow {
+                    prompt: format!("prompt {expert}"),
+                    expert,
+                    image_urls: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_validation_split() {
+        let mut rng = Isaac64Rng::seed_from_u64(0);
+        let (train, validation) = inputs(10).split(0.25, &mut rng).unwrap();
+        assert_eq!((train.len(), validation.len()), (7, 3));
+        let mut experts = train
+            .into_inner()
+            .into_iter()
+            .chain(validation.into_inner())
+            .map(|row| row.expert)
+            .collect::<Vec<_>>();
+        experts.sort_unstable();
+        assert_eq!(experts, (0..10).collect::<Vec<_>>());
+
+        let (train, validation) = inputs(10).split(0., &mut rng).unwrap();
+        assert_eq!((train.len(), validation.len()), (10, 0));
+
+        for fraction in [0.01, 0.99, 1., -0.5] {
+            assert!(inputs(10).split(fraction, &mut rng).is_err(), "{fraction}");
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     fs,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
@@ -10,8 +10,10 @@ use candle_nn::{linear, Linear, ModuleT, VarBuilder, VarMap};
 use mistralrs_quant::QuantMethod;
 use serde::{Deserialize, Serialize};
 
+mod checkpoint;
 mod inputs;
 mod macros;
+pub(crate) use checkpoint::{load_checkpoint, save_checkpoint, AnyMoeCheckpointMetadata};
 pub use inputs::{AnyMoeTrainingInputRow, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use tracing::info;
 
@@ -29,6 +31,17 @@ pub trait AnyMoeBaseModelMixin {
             .map(|mlp| mlp.get_vars())
             .collect::<Vec<_>>()
     }
+    /// All vars of the gating layers, by name, for checkpointing.
+    fn get_var_map(&self) -> VarMap {
+        let var_map = VarMap::new();
+        {
+            let mut data = var_map.data().lock().unwrap();
+            for mlp in self.get_mlps().iter().filter(|mlp| mlp.is_moe_layer()) {
+                data.extend(mlp.get_named_vars());
+            }
+        }
+        var_map
+    }
     fn finish_training(&mut self, gate_model_id: Option<String>) -> Result<()> {
         let mut out = HashMap::new();
         for mlp in self
@@ -94,6 +107,10 @@ pub trait AnyMoeBaseModelMixin {
 pub trait MlpLayer: Send + Sync + AnyMoeTrainableLayer {
     fn forward(&self, xs: &Tensor) -> Result<Tensor>;
     fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>>;
+    /// Names of the layers returned by [`MlpLayer::get_isq_layers`], relative to the MLP, in the same order.
+    fn get_isq_layer_names(&self) -> Option<Vec<&'static str>> {
+        None
+    }
     fn clone(&self) -> Box<dyn MlpLayer>;
     /// WARNING: The deltas are not a struct but are instead assumed to
     /// be correctly ordered! for that model and it's implementation details
@@ -112,6 +129,9 @@ pub trait AnyMoeTrainableLayer {
     fn get_vars(&self) -> Vec<Var> {
         vec![]
     }
+    fn get_named_vars(&self) -> HashMap<String, Var> {
+        HashMap::new()
+    }
     fn finish_training(&mut self, _out: Option<&mut HashMap<String, Tensor>>) {}
     fn trainable_params(&self) -> usize {
         0
@@ -125,6 +145,7 @@ serde_default_fn!(f64, default_lr, 1e-3);
 serde_default_fn!(usize, default_epochs, 100);
 serde_default_fn!(usize, default_bs, 4);
 serde_default_fn!(bool, default_true, true);
+serde_default_fn!(bool, default_false, false);
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum AnyMoeExpertType {
@@ -154,6 +175,103 @@ pub struct AnyMoeConfig {
     /// If `training == true`, `loss_csv_path` will not save anything.
     /// Otherwise, this will save a .csv loss file here.
     pub loss_csv_path: Option<String>,
+    /// If specified, the gating layers are checkpointed here at the end of each training epoch.
+    #[serde(default)]
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Resume training from the checkpoint in `checkpoint_dir`, if it exists and was saved for the
+    /// same number of layers, experts, and hidden size. Training is skipped if the checkpoint has
+    /// already completed all epochs.
+    #[serde(default = "default_false")]
+    pub resume_from_checkpoint: bool,
+    /// Keep only the inputs of the gating layers during the training forward pass, in CPU memory,
+    /// and recompute the gating outputs for the backward pass. This trades compute and transfers
+    /// for device memory.
+    #[serde(default = "default_false")]
+    pub gradient_checkpointing: bool,
+    /// Fraction of the training inputs held out to compute the validation loss at the end of each
+    /// epoch. No inputs are held out if this is 0.
+    #[serde(default)]
+    pub validation_split: f64,
+    /// Stop training once the validation loss has not improved for this many epochs, keeping the
+    /// gating layers from the best epoch. Requires a `validation_split`.
+    #[serde(default)]
+    pub early_stopping_patience: Option<usize>,
+}
+
+/// Tracks the validation loss over the epochs, keeping the gating weights of the best epoch.
+pub(crate) struct EarlyStopping {
+    patience: Option<usize>,
+    best: Option<BestEpoch>,
+    epochs_without_improvement: usize,
+}
+
+/// The epoch with the lowest validation loss, summed over the gating layers.
+pub(crate) struct BestEpoch {
+    pub epoch: usize,
+    /// One for each gating layer
+    pub validation_loss: Vec<f32>,
+    /// The training loss of the last step of the epoch, one for each gating layer.
+    pub training_loss: Vec<f32>,
+    weights: HashMap<String, Tensor>,
+}
+
+impl EarlyStopping {
+    pub(crate) fn new(patience: Option<usize>) -> Self {
+        Self {
+            patience,
+            best: None,
+            epochs_without_improvement: 0,
+        }
+    }
+
+    /// Record the losses of an epoch, copying the weights in `var_map` if it is the best so far.
+    /// Returns whether training should stop.
+    pub(crate) fn update(
+        &mut self,
+        epoch: usize,
+        validation_loss: Vec<f32>,
+        training_loss: Vec<f32>,
+        var_map: &VarMap,
+    ) -> Result<bool> {
+        let total = validation_loss.iter().sum::<f32>();
+        if self
+            .best
+            .as_ref()
+            .is_some_and(|best| best.validation_loss.iter().sum::<f32>() <= total)
+        {
+            self.epochs_without_improvement += 1;
+        } else {
+            let weights = var_map
+                .data()
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, var)| Ok((name.clone(), var.as_tensor().copy()?)))
+                .collect::<Result<HashMap<_, _>>>()?;
+            self.best = Some(BestEpoch {
+                epoch,
+                validation_loss,
+                training_loss,
+                weights,
+            });
+            self.epochs_without_improvement = 0;
+        }
+        Ok(self
+            .patience
+            .is_some_and(|patience| self.epochs_without_improvement >= patience))
+    }
+
+    /// Restore the weights of the best epoch into `var_map`, returning that epoch.
+    pub(crate) fn restore_best(self, var_map: &VarMap) -> Result<Option<BestEpoch>> {
+        if let Some(best) = &self.best {
+            for (name, var) in var_map.data().lock().unwrap().iter() {
+                if let Some(weight) = best.weights.get(name) {
+                    var.set(weight)?;
+                }
+            }
+        }
+        Ok(self.best)
+    }
 }
 
 #[derive(Clone)]
@@ -176,8 +294,12 @@ pub struct MoeMlp {
     experts: Vec<Box<dyn MlpLayer>>,
     gate: MoeGate,
     training: bool,
+    var_map: VarMap,
     vars: Vec<Var>,
     gating_output: Arc<RwLock<Option<Tensor>>>,
+    /// With gradient checkpointing, the input of the gate, on the CPU, instead of its output.
+    gating_input: Arc<RwLock<Option<Tensor>>>,
+    gradient_checkpointing: bool,
     layer_idx: usize,
 }
 
@@ -213,8 +335,11 @@ impl MoeMlp {
             experts,
             gate: MoeGate { lin },
             training: true,
+            var_map,
             vars,
             gating_output: Arc::new(RwLock::new(None)),
+            gating_input: Arc::new(RwLock::new(None)),
+            gradient_checkpointing: config.gradient_checkpointing,
             layer_idx: layer,
         })
     }
@@ -248,7 +373,19 @@ impl AnyMoeTrainableLayer for MoeMlp {
     fn get_vars(&self) -> Vec<Var> {
         self.vars.clone()
     }
+    fn get_named_vars(&self) -> HashMap<String, Var> {
+        self.var_map.data().lock().unwrap().clone()
+    }
     fn take_cached_gating_output(&mut self) -> Tensor {
+        if let Some(xs) = self.gating_input.write().unwrap().take() {
+            // Recompute the gating output, this time keeping the ops for the backward pass.
+            let (_, device) = self.dtype_device();
+            return xs
+                .to_device(&device)
+                .and_then(|xs| self.gate.forward_t(&xs, true))
+                .and_then(|gate| gate.mean(1))
+                .expect("Failed to recompute the gating output.");
+        }
         self.gating_output.read().unwrap().clone().take().unwrap()
     }
 }
@@ -265,7 +402,10 @@ impl MlpLayer for MoeMlp {
         // Gate with topk 1 to get the highest ranked expert
         let TopKOutput { values: _, indices } = gate.topk(1)?;
 
-        if self.training {
+        if self.training && self.gradient_checkpointing {
+            // Dropping `gate` frees the ops recorded for it.
+            *self.gating_input.write().unwrap() = Some(xs.detach().to_device(&Device::Cpu)?);
+        } else if self.training {
             *self.gating_output.write().unwrap() = Some(gate.clone());
         }
 
@@ -304,8 +444,11 @@ impl MlpLayer for MoeMlp {
             experts,
             gate: self.gate.clone(),
             training: self.training,
+            var_map: self.var_map.clone(),
             vars: self.vars.clone(),
             gating_output: self.gating_output.clone(),
+            gating_input: self.gating_input.clone(),
+            gradient_checkpointing: self.gradient_checkpointing,
             layer_idx: self.layer_idx,
         })
     }
@@ -329,3 +472,203 @@ impl MlpLayer for MoeMlp {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::{AdamW, Init, Optimizer, ParamsAdamW, VarMap};
+    use mistralrs_quant::QuantMethod;
+
+    use super::{
+        AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainableLayer, EarlyStopping, MlpLayer, MoeMlp,
+    };
+
+    const HIDDEN: usize = 4;
+
+    /// An expert which scales its input.
+    struct Scale(f64, Vec<usize>);
+
+    impl AnyMoeTrainableLayer for Scale {}
+
+    impl MlpLayer for Scale {
+        fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+            xs.affine(self.0, 0.)
+        }
+        fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+            Vec::new()
+        }
+        fn clone(&self) -> Box<dyn MlpLayer> {
+            Box::new(Scale(self.0, self.1.clone()))
+        }
+        fn get_params(&self) -> &[usize] {
+            &self.1
+        }
+        fn new_added_delta(&self, _deltas: Vec<Option<Tensor>>) -> Result<Box<dyn MlpLayer>> {
+            unreachable!()
+        }
+        fn dtype_device(&self) -> (DType, Device) {
+            (DType::F32, Device::Cpu)
+        }
+    }
+
+    /// A MoE layer of two experts, with a zeroed gate so runs are comparable.
+    fn moe(gradient_checkpointing: bool) -> Result<MoeMlp> {
+        let config = AnyMoeConfig {
+            hidden_size: HIDDEN,
+            lr: 1e-1,
+            epochs: 1,
+            batch_size: 4,
+            expert_type: AnyMoeExpertType::FineTuned,
+            gate_model_id: None,
+            training: true,
+            loss_csv_path: None,
+            checkpoint_dir: None,
+            resume_from_checkpoint: false,
+            gradient_checkpointing,
+            validation_split: 0.,
+            early_stopping_patience: None,
+        };
+        let experts: Vec<Box<dyn MlpLayer>> = vec![
+            Box::new(Scale(1., vec![HIDDEN, HIDDEN])),
+            Box::new(Scale(-1., vec![HIDDEN, HIDDEN])),
+        ];
+        let moe = MoeMlp::new(experts, config, DType::F32, &Device::Cpu, 0, None)?;
+        for var in &moe.vars {
+            var.set(&var.zeros_like()?)?;
+        }
+        Ok(moe)
+    }
+
+    /// Inputs of shape (batch, seq len, hidden), labelled with the expert for the sign of the first
+    /// feature.
+    fn routing_data() -> Result<(Tensor, Tensor)> {
+        let xs = Tensor::new(
+            &[
+                [[1f32, 0.5, -0.5, 0.], [0.9, 0., 0.2, 0.1]],
+                [[-1., 0.5, -0.5, 0.], [-0.9, 0., 0.2, 0.1]],
+                [[0.8, -0.2, 0.1, 0.3], [1., 0.1, 0., -0.2]],
+                [[-0.8, -0.2, 0.1, 0.3], [-1., 0.1, 0., -0.2]],
+            ],
+            &Device::Cpu,
+        )?;
+        let labels = Tensor::new(&[0u32, 1, 0, 1], &Device::Cpu)?;
+        Ok((xs, labels))
+    }
+
+    /// Train the gate to route by the sign of the first feature, returning the losses.
+    fn train(mut moe: MoeMlp, steps: usize) -> Result<Vec<f32>> {
+        let (xs, labels) = routing_data()?;
+        let mut optimizer = AdamW::new(
+            moe.get_vars(),
+            ParamsAdamW {
+                lr: 1e-1,
+                ..Default::default()
+            },
+        )?;
+        let mut losses = Vec::new();
+        for _ in 0..steps {
+            moe.forward(&xs)?;
+            let output = moe.take_cached_gating_output();
+            let loss = candle_nn::loss::cross_entropy(&output, &labels)?;
+            optimizer.backward_step(&loss)?;
+            losses.push(loss.to_scalar::<f32>()?);
+        }
+        Ok(losses)
+    }
+
+    #[test]
+    fn test_gradient_checkpointing_converges() -> Result<()> {
+        let losses = train(moe(false)?, 30)?;
+        let checkpointed = train(moe(true)?, 30)?;
+        for (loss, checkpointed) in losses.iter().zip(&checkpointed) {
+            assert!(
+                (loss - checkpointed).abs() < 1e-5,
+                "{loss} != {checkpointed}"
+            );
+        }
+        // The gating output is a probability, so the loss is at least ln(1 + e^-1) ~= 0.313.
+        let (first, last) = (checkpointed[0], checkpointed[checkpointed.len() - 1]);
+        assert!(last < first && last < 0.45, "{first} -> {last}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_early_stopping_keeps_best_epoch() -> Result<()> {
+        let var_map = VarMap::new();
+        var_map.get((), "w", Init::Const(0.), DType::F32, &Device::Cpu)?;
+        let var = var_map.data().lock().unwrap()["w"].clone();
+        let mut early_stopping = EarlyStopping::new(Some(2));
+        let validation_losses = [[0.9, 0.8], [0.7, 0.6], [0.75, 0.6], [0.7, 0.7], [0.1, 0.1]];
+        let mut stopped_at = None;
+        for (epoch, loss) in (0..5u8).zip(validation_losses) {
+            // The weights and training losses record the epoch they come from.
+            var.set(&Tensor::new(f32::from(epoch), &Device::Cpu)?)?;
+            let training_loss = vec![f32::from(epoch); 2];
+            if early_stopping.update(epoch.into(), loss.to_vec(), training_loss, &var_map)? {
+                stopped_at = Some(epoch);
+                break;
+            }
+        }
+        // The loss did not improve after epoch 1 for the 2 epochs of patience.
+        assert_eq!(stopped_at, Some(3));
+        let best = early_stopping.restore_best(&var_map)?.unwrap();
+        assert_eq!(best.epoch, 1);
+        assert_eq!(best.validation_loss, vec![0.7, 0.6]);
+        assert_eq!(best.training_loss, vec![1., 1.]);
+        assert_eq!(var.to_scalar::<f32>()?, 1.);
+        Ok(())
+    }
+
+    #[test]
+    fn test_early_stopping_with_validation_split() -> Result<()> {
+        let mut moe = moe(false)?;
+        let (xs, labels) = routing_data()?;
+        // Validation samples which contradict the training samples, so the validation loss rises
+        // as the gate learns to route the training samples.
+        let validation_labels = Tensor::new(&[1u32, 0, 1, 0], &Device::Cpu)?;
+        let mut optimizer = AdamW::new(
+            moe.get_vars(),
+            ParamsAdamW {
+                lr: 1e-1,
+                ..Default::default()
+            },
+        )?;
+        let validation_loss = |moe: &mut MoeMlp| -> Result<Vec<f32>> {
+            moe.forward(&xs)?;
+            let output = moe.take_cached_gating_output().detach();
+            let loss = candle_nn::loss::cross_entropy(&output, &validation_labels)?;
+            Ok(vec![loss.to_scalar::<f32>()?])
+        };
+
+        let mut early_stopping = EarlyStopping::new(Some(3));
+        let mut losses = Vec::new();
+        let mut stopped = false;
+        for epoch in 0..30 {
+            moe.forward(&xs)?;
+            let output = moe.take_cached_gating_output();
+            let loss = candle_nn::loss::cross_entropy(&output, &labels)?;
+            optimizer.backward_step(&loss)?;
+
+            let loss = validation_loss(&mut moe)?;
+            losses.push(loss[0]);
+            if early_stopping.update(epoch, loss, vec![], &moe.var_map)? {
+                stopped = true;
+                break;
+            }
+        }
+        assert!(stopped, "validation losses: {losses:?}");
+
+        // The returned losses and the restored gate are those of the best epoch.
+        let best = early_stopping.restore_best(&moe.var_map)?.unwrap();
+        assert_eq!(best.epoch + 3, losses.len() - 1);
+        let min = losses.iter().copied().fold(f32::INFINITY, f32::min);
+        assert_eq!(best.validation_loss, vec![min]);
+        assert_eq!(losses[best.epoch], min);
+        assert!(min < losses[losses.len() - 1]);
+        let restored = validation_loss(&mut moe)?;
+        assert!((restored[0] - min).abs() < 1e-6, "{restored:?} != {min}");
+        Ok(())
+    }
+}
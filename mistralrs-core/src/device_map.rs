@@ -1,8 +1,14 @@
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{atomic::AtomicUsize, Arc, Mutex, RwLock},
+    time::Instant,
+};
 
-use crate::{utils::debug::DeviceRepr, Topology, TryIntoDType};
-use candle_core::{DType, Device, Result, Tensor};
+use crate::{tensor_parallel::TensorParallel, utils::debug::DeviceRepr, Topology, TryIntoDType};
+use candle_core::{DType, Device, DeviceLocation, Result, Tensor};
 use candle_nn::VarBuilder;
+use mistralrs_quant::{IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde};
 use serde::Deserialize;
 use tracing::info;
 
@@ -17,6 +23,9 @@ pub struct DeviceLayerMapMetadata {
 pub struct DeviceMapMetadata {
     device_layers: Option<Vec<DeviceLayerMapMetadata>>,
     host_layers: Option<usize>,
+    paged_gpu_layers: Option<usize>,
+    /// CUDA ordinals of the devices to split the layers across.
+    tensor_parallel: Option<Vec<usize>>,
 }
 
 impl DeviceMapMetadata {
@@ -24,6 +33,32 @@ impl DeviceMapMetadata {
         Self {
             device_layers: Some(device_layers),
             host_layers: None,
+            paged_gpu_layers: None,
+            tensor_parallel: None,
+        }
+    }
+    /// A device mapper which keeps at most `max_gpu_layers` repeating layers on the GPU. The other
+    /// layers are kept on the CPU and paged in when they are run, evicting the least recently used
+    /// layer. Only supported for unquantized plain models.
+    pub fn paged(max_gpu_layers: usize) -> Self {
+        Self {
+            device_layers: None,
+            host_layers: None,
+            paged_gpu_layers: Some(max_gpu_layers),
+            tensor_parallel: None,
+        }
+    }
+    /// A device mapper which splits the attention heads and the MLP intermediate dimension of every
+    /// repeating layer across the CUDA devices with the given ordinals, using column and row
+    /// parallel linear layers. The partial results of the row parallel layers are summed with an
+    /// NCCL all-reduce when the `nccl` feature is enabled. The other layers and the activations stay
+    /// on the first device. Only supported for unquantized plain models.
+    pub fn tensor_parallel(ordinals: Vec<usize>) -> Self {
+        Self {
+            device_layers: None,
+            host_layers: None,
+            paged_gpu_layers: None,
+            tensor_parallel: Some(ordinals),
         }
     }
     /// A device mapper to not map device.
@@ -31,10 +66,20 @@ impl DeviceMapMetadata {
         Self {
             device_layers: None,
             host_layers: None,
+            paged_gpu_layers: None,
+            tensor_parallel: None,
         }
     }
     pub fn is_dummy(&self) -> bool {
         self.device_layers.is_none()
+            && self.paged_gpu_layers.is_none()
+            && self.tensor_parallel.is_none()
+    }
+    pub fn is_paged(&self) -> bool {
+        self.paged_gpu_layers.is_some()
+    }
+    pub fn is_tensor_parallel(&self) -> bool {
+        self.tensor_parallel.is_some()
     }
     pub fn into_mapper(
         &self,
@@ -42,6 +87,65 @@ impl DeviceMapMetadata {
         device: &Device,
         topology: Option<&Topology>,
     ) -> Result<Box<dyn DeviceMapper + Send + Sync>> {
+        if let Some(ordinals) = &self.tensor_parallel {
+            if topology.is_some() {
+                candle_core::bail!("Tensor parallelism cannot be combined with a topology.");
+            }
+            if ordinals.is_empty() {
+                candle_core::bail!("Tensor parallelism needs at least 1 device.");
+            }
+            if device.is_metal() {
+                candle_core::bail!("Tensor parallelism is not supported on Metal.");
+            }
+            // Rank 0 is the device of the pipeline, which holds the activations.
+            if let DeviceLocation::Cuda { gpu_id } = device.location() {
+                if gpu_id != ordinals[0] {
+                    candle_core::bail!(
+                        "The first tensor parallel device must be the model device, cuda:{gpu_id}."
+                    );
+                }
+            }
+            let mut devices = vec![device.clone()];
+            for ordinal in &ordinals[1..] {
+                devices.push(match device {
+                    Device::Cpu => Device::Cpu,
+                    Device::Cuda(_) => Device::cuda_if_available(*ordinal)?,
+                    Device::Metal(_) => unreachable!(),
+                });
+            }
+            info!(
+                "Model has {model_layers} repeating layers, splitting them across {} devices.",
+                devices.len()
+            );
+            for (rank, dev) in devices.iter().enumerate() {
+                info!("Rank {rank}: {}", dev.device_pretty_repr());
+            }
+            return Ok(Box::new(TensorParallelDeviceMapper {
+                nm_device: devices[0].clone(),
+                tp: Arc::new(TensorParallel::new(devices)?),
+            }));
+        }
+
+        if let Some(max_gpu_layers) = self.paged_gpu_layers {
+            if topology.is_some() {
+                candle_core::bail!("Paged device mapping cannot be combined with a topology.");
+            }
+            if max_gpu_layers == 0 {
+                candle_core::bail!("Paged device mapping needs at least 1 GPU layer.");
+            }
+            if device.is_cpu() {
+                candle_core::bail!("Paged device mapping requires a GPU device.");
+            }
+            info!(
+                "Model has {model_layers} repeating layers, paging them with at most {max_gpu_layers} on {}.",
+                device.device_pretty_repr()
+            );
+            return Ok(Box::new(PagedDeviceMapper::new(
+                device.clone(),
+                max_gpu_layers,
+            )));
+        }
+
         if let Some(topology) = topology {
             if topology.0.iter().all(|x| x.is_none()) {
                 return Ok(Box::new(DummyDeviceMapper {
@@ -156,6 +260,19 @@ pub trait DeviceMapper: Debug {
 
     // === IMMEDIATELY AFTER INIT ===
     fn get_min_dtype(&self, dtype: &dyn TryIntoDType) -> Result<DType>;
+
+    // === AFTER LOADING ===
+    /// Take over the quantized layers of the model, as returned by
+    /// [`IsqModel::get_layers`](crate::pipeline::IsqModel::get_layers), if this mapper moves
+    /// them between devices while running. Does nothing by default.
+    #[allow(clippy::type_complexity)]
+    fn page_layers(&self, _layers: Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>) -> Result<()> {
+        Ok(())
+    }
+    /// The devices to split the repeating layers across, if this mapper uses tensor parallelism.
+    fn tensor_parallel(&self) -> Option<&Arc<TensorParallel>> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -254,3 +371,379 @@ impl DeviceMapper for DummyDeviceMapper {
             .map_err(candle_core::Error::msg)
     }
 }
+
+#[derive(Debug)]
+/// A device mapper which splits every repeating layer across several devices. The layers which are
+/// not split, and the activations, are on the first device.
+pub struct TensorParallelDeviceMapper {
+    nm_device: Device,
+    tp: Arc<TensorParallel>,
+}
+
+impl DeviceMapper for TensorParallelDeviceMapper {
+    fn map(&self, input: Tensor, _: usize) -> Result<Tensor> {
+        Ok(input)
+    }
+    fn set_device<'a>(&self, _: usize, varbuilder: VarBuilder<'a>, _: bool) -> VarBuilder<'a> {
+        varbuilder.set_device(self.nm_device.clone())
+    }
+    fn device_for(&self, _: usize, _: bool) -> Option<&Device> {
+        Some(&self.nm_device)
+    }
+    fn cast_nm_device(&self, x: &Tensor, _: bool) -> Result<Tensor> {
+        x.to_device(&self.nm_device)
+    }
+    fn set_nm_device<'a>(&self, varbuilder: VarBuilder<'a>, _: bool) -> VarBuilder<'a> {
+        varbuilder.set_device(self.nm_device.clone())
+    }
+    fn get_min_dtype(&self, dtype: &dyn TryIntoDType) -> Result<DType> {
+        dtype
+            .try_into_dtype(&self.tp.devices().iter().collect::<Vec<_>>())
+            .map_err(candle_core::Error::msg)
+    }
+    fn tensor_parallel(&self) -> Option<&Arc<TensorParallel>> {
+        Some(&self.tp)
+    }
+}
+
+/// A layer whose weights are moved between the CPU and the GPU by a [`PagedDeviceMapper`].
+#[derive(Debug)]
+struct PagedLayer {
+    inner: RwLock<Arc<dyn QuantMethod>>,
+}
+
+impl PagedLayer {
+    fn move_to(&self, device: &Device) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if inner.dtype_and_device().1.same_device(device) {
+            return Ok(());
+        }
+        *inner = inner
+            .clone()
+            .apply_isq(None, device.clone(), &AtomicUsize::new(0))?;
+        Ok(())
+    }
+}
+
+impl QuantMethod for PagedLayer {
+    fn new(_method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        candle_core::bail!("PagedLayer is only created by the paged device mapper.")
+    }
+    fn dequantize_w(&self) -> Result<Tensor> {
+        self.inner.read().unwrap().dequantize_w()
+    }
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        self.inner.read().unwrap().forward(a)
+    }
+    fn forward_via_half(&self, a: &Tensor) -> Result<Tensor> {
+        self.inner.read().unwrap().forward_via_half(a)
+    }
+    fn quantized_act_type(&self) -> Option<DType> {
+        self.inner.read().unwrap().quantized_act_type()
+    }
+    fn dtype_and_device(&self) -> (DType, Device) {
+        self.inner.read().unwrap().dtype_and_device()
+    }
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("Paged layers do not support adding delta weights.")
+    }
+    fn apply_isq(
+        self: Arc<Self>,
+        _dtype: Option<IsqType>,
+        _device: Device,
+        _n_quantized: &AtomicUsize,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("Paged layers do not support in-situ quantization.")
+    }
+    fn get_bias_mut(&mut self) -> Option<&mut Tensor> {
+        None
+    }
+    fn get_max_isq_cpu_threads(&self, _dtype: IsqType) -> Option<std::num::NonZeroUsize> {
+        None
+    }
+    fn unquant_weight_bias(&self) -> Option<(Tensor, Option<Tensor>)> {
+        self.inner.read().unwrap().unquant_weight_bias()
+    }
+}
+
+impl QuantizedSerde for PagedLayer {
+    fn name(&self) -> &'static str {
+        "paged"
+    }
+}
+
+/// Weight of the newest transfer in the moving average of the transfer latency of a layer.
+const TRANSFER_LATENCY_EMA: f64 = 0.25;
+
+/// Decides which layers are resident on the GPU.
+///
+/// This is the GreedyDual policy: each resident layer has a credit of the time at which it was last
+/// used plus the cost of transferring it back, and the layer with the lowest credit is evicted. The
+/// cost is the measured transfer latency of the layer, so cheaper layers are evicted first. When
+/// all layers cost the same, this is the least recently used policy.
+#[derive(Debug)]
+struct LayerPager {
+    max_resident: usize,
+    /// Credit of the last evicted layer, which advances with every eviction.
+    clock: f64,
+    /// Credit and the use counter at the last use of the resident layers.
+    resident: HashMap<usize, (f64, u64)>,
+    uses: u64,
+    /// Moving average of the transfer latency of each layer, in seconds.
+    latency: HashMap<usize, f64>,
+}
+
+/// What [`LayerPager::touch`] decided to do to make a layer resident.
+#[derive(Debug, PartialEq)]
+struct PageIn {
+    evict: Option<usize>,
+}
+
+impl LayerPager {
+    fn new(max_resident: usize) -> Self {
+        Self {
+            max_resident,
+            clock: 0.,
+            resident: HashMap::new(),
+            uses: 0,
+            latency: HashMap::new(),
+        }
+    }
+
+    /// Transfer cost of `layer`. Layers which were not transferred yet cost the average.
+    fn cost(&self, layer: usize) -> f64 {
+        self.latency.get(&layer).copied().unwrap_or_else(|| {
+            let known = self.latency.values();
+            let n = known.len();
+            if n == 0 {
+                0.
+            } else {
+                known.sum::<f64>() / f64::from(u32::try_from(n).unwrap_or(u32::MAX))
+            }
+        })
+    }
+
+    /// Mark `layer` as used. Returns `None` if it is already resident, otherwise the layer to
+    /// evict first, if any.
+    fn touch(&mut self, layer: usize) -> Option<PageIn> {
+        self.uses += 1;
+        let credit = self.clock + self.cost(layer);
+        if let Some(entry) = self.resident.get_mut(&layer) {
+            *entry = (credit, self.uses);
+            return None;
+        }
+        let mut evict = None;
+        if self.resident.len() >= self.max_resident {
+            let (&victim, &(victim_credit, _)) = self
+                .resident
+                .iter()
+                .min_by(|(_, (a, a_use)), (_, (b, b_use))| a.total_cmp(b).then(a_use.cmp(b_use)))
+                .expect("A full pager has resident layers.");
+            self.resident.remove(&victim);
+            self.clock = victim_credit;
+            evict = Some(victim);
+        }
+        self.resident
+            .insert(layer, (self.clock + self.cost(layer), self.uses));
+        Some(PageIn { evict })
+    }
+
+    /// Record that transferring `layer` to the GPU took `secs` seconds.
+    fn record_transfer(&mut self, layer: usize, secs: f64) {
+        let latency = self.latency.entry(layer).or_insert(secs);
+        *latency += TRANSFER_LATENCY_EMA * (secs - *latency);
+        if let Some((credit, _)) = self.resident.get_mut(&layer) {
+            *credit = self.clock + *latency;
+        }
+    }
+}
+
+#[derive(Debug)]
+/// A device mapper which runs every layer on the GPU, but keeps only some layers resident there.
+/// The weights of the other layers are stored on the CPU and moved to the GPU when they are run.
+pub struct PagedDeviceMapper {
+    nm_device: Device,
+    pager: Mutex<LayerPager>,
+    /// The paged layers of each repeating layer, set by [`DeviceMapper::page_layers`].
+    layers: Mutex<Vec<Vec<Arc<PagedLayer>>>>,
+}
+
+impl PagedDeviceMapper {
+    fn new(nm_device: Device, max_gpu_layers: usize) -> Self {
+        Self {
+            nm_device,
+            pager: Mutex::new(LayerPager::new(max_gpu_layers)),
+            layers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Make the weights of `layer` resident on the GPU.
+    fn page_in(&self, layer: usize) -> Result<()> {
+        let layers = self.layers.lock().unwrap();
+        let Some(paged) = layers.get(layer) else {
+            return Ok(());
+        };
+        let mut pager = self.pager.lock().unwrap();
+        let Some(PageIn { evict }) = pager.touch(layer) else {
+            return Ok(());
+        };
+        if let Some(evict) = evict {
+            for evicted in layers.get(evict).into_iter().flatten() {
+                evicted.move_to(&Device::Cpu)?;
+            }
+        }
+        let start = Instant::now();
+        for paged in paged {
+            paged.move_to(&self.nm_device)?;
+        }
+        self.nm_device.synchronize()?;
+        pager.record_transfer(layer, start.elapsed().as_secs_f64());
+        Ok(())
+    }
+}
+
+impl DeviceMapper for PagedDeviceMapper {
+    fn map(&self, input: Tensor, layer: usize) -> Result<Tensor> {
+        self.page_in(layer)?;
+        input.to_device(&self.nm_device)
+    }
+    fn set_device<'a>(
+        &self,
+        _: usize,
+        varbuilder: VarBuilder<'a>,
+        loading_isq: bool,
+    ) -> VarBuilder<'a> {
+        // The quantized layers are loaded on the CPU, and `page_layers` takes them over.
+        if loading_isq {
+            varbuilder.set_device(Device::Cpu)
+        } else {
+            varbuilder.set_device(self.nm_device.clone())
+        }
+    }
+    fn device_for(&self, _: usize, _loading_isq: bool) -> Option<&Device> {
+        Some(&self.nm_device)
+    }
+    fn cast_nm_device(&self, x: &Tensor, loading_isq: bool) -> Result<Tensor> {
+        if loading_isq {
+            x.to_device(&Device::Cpu)
+        } else {
+            x.to_device(&self.nm_device)
+        }
+    }
+    fn set_nm_device<'a>(&self, varbuilder: VarBuilder<'a>, loading_isq: bool) -> VarBuilder<'a> {
+        if loading_isq {
+            varbuilder.set_device(Device::Cpu)
+        } else {
+            varbuilder.set_device(self.nm_device.clone())
+        }
+    }
+    fn get_min_dtype(&self, dtype: &dyn TryIntoDType) -> Result<DType> {
+        dtype
+            .try_into_dtype(&[&self.nm_device])
+            .map_err(candle_core::Error::msg)
+    }
+    fn page_layers(&self, layers: Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>) -> Result<()> {
+        let n_quantized = AtomicUsize::new(0);
+        let mut paged_layers = self.layers.lock().unwrap();
+        for (layer, layer_idx) in layers {
+            if layer.unquant_weight_bias().is_none() {
+                candle_core::bail!(
+                    "Paged device mapping only supports unquantized layers, got `{}`.",
+                    layer.name()
+                );
+            }
+            let Some(layer_idx) = layer_idx else {
+                // Layers outside of the repeating layers always stay on the GPU.
+                *layer = layer
+                    .clone()
+                    .apply_isq(None, self.nm_device.clone(), &n_quantized)?;
+                continue;
+            };
+            let paged = Arc::new(PagedLayer {
+                inner: RwLock::new(layer.clone().apply_isq(None, Device::Cpu, &n_quantized)?),
+            });
+            if paged_layers.len() <= layer_idx {
+                paged_layers.resize_with(layer_idx + 1, Vec::new);
+            }
+            paged_layers[layer_idx].push(paged.clone());
+            *layer = paged;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+    use candle_nn::Linear;
+    use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+    use super::{DeviceMapper, LayerPager, PageIn, PagedDeviceMapper};
+
+    #[test]
+    fn test_pager_evicts_least_recently_used() {
+        let mut pager = LayerPager::new(2);
+        assert_eq!(pager.touch(0), Some(PageIn { evict: None }));
+        assert_eq!(pager.touch(1), Some(PageIn { evict: None }));
+        assert_eq!(pager.touch(0), None);
+        assert_eq!(pager.touch(2), Some(PageIn { evict: Some(1) }));
+        assert_eq!(pager.touch(3), Some(PageIn { evict: Some(0) }));
+        assert_eq!(pager.touch(2), None);
+    }
+
+    #[test]
+    fn test_pager_keeps_slow_layers() {
+        let mut pager = LayerPager::new(2);
+        for (layer, secs) in [(0, 1.), (1, 0.1)] {
+            pager.touch(layer);
+            pager.record_transfer(layer, secs);
+        }
+        // Layer 0 was used longest ago, but is much slower to page back in.
+        assert_eq!(pager.touch(2), Some(PageIn { evict: Some(1) }));
+        // Evictions age the credit of the resident layers, so layer 0 is eventually evicted.
+        pager.record_transfer(2, 0.1);
+        for layer in 3..20 {
+            if pager.touch(layer) == Some(PageIn { evict: Some(0) }) {
+                return;
+            }
+            pager.record_transfer(layer, 0.1);
+        }
+        panic!("Layer 0 was never evicted.");
+    }
+
+    #[test]
+    fn test_paged_mapper_runs_layers() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let mapper = PagedDeviceMapper::new(dev.clone(), 1);
+        let mut layers = (0..3u32)
+            .map(|i| -> candle_core::Result<Arc<dyn QuantMethod>> {
+                let w = Tensor::full(f32::from(u8::try_from(i).unwrap()), (2, 2), &dev)?;
+                Ok(Arc::new(UnquantLinear::new(
+                    QuantMethodConfig::Unquantized(Linear::new(w, None)),
+                )?))
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let (head, repeating) = layers.split_at_mut(1);
+        let mut tensors = vec![(&mut head[0], None)];
+        tensors.extend(repeating.iter_mut().enumerate().map(|(i, l)| (l, Some(i))));
+        mapper.page_layers(tensors)?;
+
+        assert_eq!(layers[0].name(), "unquant-linear");
+        let x = Tensor::ones((1, 2), candle_core::DType::F32, &dev)?;
+        for (i, layer) in layers[1..].iter().enumerate() {
+            assert_eq!(layer.name(), "paged");
+            let xs = mapper.map(x.clone(), i)?;
+            let expected = f32::from(u8::try_from(i + 1).unwrap()) * 2.;
+            assert_eq!(layer.forward(&xs)?.to_vec2::<f32>()?, [[expected; 2]]);
+        }
+        let pager = mapper.pager.lock().unwrap();
+        assert_eq!(pager.resident.keys().copied().collect::<Vec<_>>(), [1]);
+        assert_eq!(pager.latency.len(), 2);
+        Ok(())
+    }
+}
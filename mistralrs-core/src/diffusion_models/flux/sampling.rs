@@ -103,6 +103,7 @@ fn denoise_inner(
     vec_: &Tensor,
     timesteps: &[f64],
     guidance: Option<f64>,
+    on_step: &mut dyn FnMut(usize, usize),
 ) -> Result<Tensor> {
     let b_sz = img.dim(0)?;
     let dev = img.device();
@@ -112,14 +113,16 @@ fn denoise_inner(
         None
     };
     let mut img = img.clone();
-    for window in timesteps.windows(2) {
+    let total_steps = timesteps.len().saturating_sub(1);
+    for (step, window) in timesteps.windows(2).enumerate() {
         let (t_curr, t_prev) = match window {
             [a, b] => (a, b),
             _ => continue,
         };
         let t_vec = Tensor::full(*t_curr as f32, b_sz, dev)?;
         let pred = model.forward(&img, img_ids, txt, txt_ids, &t_vec, vec_, guidance.as_ref())?;
-        img = (img + pred * (t_prev - t_curr))?
+        img = (img + pred * (t_prev - t_curr))?;
+        on_step(step + 1, total_steps);
     }
     Ok(img)
 }
@@ -134,6 +137,7 @@ pub fn denoise(
     vec_: &Tensor,
     timesteps: &[f64],
     guidance: f64,
+    on_step: &mut dyn FnMut(usize, usize),
 ) -> Result<Tensor> {
     denoise_inner(
         model,
@@ -144,6 +148,7 @@ pub fn denoise(
         vec_,
         timesteps,
         Some(guidance),
+        on_step,
     )
 }
 
@@ -156,6 +161,9 @@ pub fn denoise_no_guidance(
     txt_ids: &Tensor,
     vec_: &Tensor,
     timesteps: &[f64],
+    on_step: &mut dyn FnMut(usize, usize),
 ) -> Result<Tensor> {
-    denoise_inner(model, img, img_ids, txt, txt_ids, vec_, timesteps, None)
+    denoise_inner(
+        model, img, img_ids, txt, txt_ids, vec_, timesteps, None, on_step,
+    )
 }
@@ -189,6 +189,7 @@ impl DiffusionModel for FluxStepper {
         &mut self,
         prompts: Vec<String>,
         params: DiffusionGenerationParams,
+        on_step: &mut dyn FnMut(usize, usize),
     ) -> Result<Tensor> {
         let mut t5_input_ids = get_tokenization(&self.t5_tok, prompts.clone(), &self.device)?;
         if !self.is_guidance {
@@ -247,6 +248,7 @@ impl DiffusionModel for FluxStepper {
                 &state.vec,
                 &timesteps,
                 guidance_cfg.guidance_scale,
+                on_step,
             )?
         } else {
             flux::sampling::denoise_no_guidance(
@@ -257,6 +259,7 @@ impl DiffusionModel for FluxStepper {
                 &state.txt_ids,
                 &state.vec,
                 &timesteps,
+                on_step,
             )?
         };
 
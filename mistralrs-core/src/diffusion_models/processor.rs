@@ -4,6 +4,7 @@ use anyhow::{Context, Result};
 use candle_core::Device;
 use indexmap::IndexMap;
 use tokenizers::Tokenizer;
+use tokio::sync::mpsc::Sender;
 
 use crate::{
     pipeline::{
@@ -11,7 +12,7 @@ use crate::{
         InputsProcessorType, MessagesAction, Processor,
     },
     sequence::Sequence,
-    MessageContent, Pipeline,
+    MessageContent, Pipeline, Response,
 };
 
 use super::DiffusionGenerationParams;
@@ -48,6 +49,8 @@ pub struct DiffusionInputsProcessor;
 pub struct ModelInputs {
     pub(crate) prompts: Vec<String>,
     pub(crate) params: DiffusionGenerationParams,
+    /// Creation time and responder of each sequence which requested streamed progress.
+    pub(crate) progress: Vec<(u128, Sender<Response>)>,
 }
 
 impl InputsProcessor for DiffusionInputsProcessor {
@@ -82,6 +85,11 @@ impl InputsProcessor for DiffusionInputsProcessor {
                     params: input_seqs[0]
                         .get_diffusion_diffusion_params()
                         .context("Diffusion model params must be present")?,
+                    progress: input_seqs
+                        .iter()
+                        .filter(|seq| seq.get_mut_group().is_streaming)
+                        .map(|seq| (u128::from(seq.creation_time()), seq.responder()))
+                        .collect::<Vec<_>>(),
                 };
                 Ok(InputProcessorOutput {
                     inputs: Box::new(inputs),
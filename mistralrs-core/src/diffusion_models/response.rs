@@ -2,13 +2,30 @@ use std::io::Cursor;
 
 use base64::{engine::general_purpose::STANDARD, Engine};
 use image::DynamicImage;
+use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
 use crate::{
     sequence::{Sequence, SequenceState, StopReason},
-    ImageChoice, ImageGenerationResponse, ImageGenerationResponseFormat,
+    ImageChoice, ImageGenerationProgress, ImageGenerationResponse, ImageGenerationResponseFormat,
+    Response,
 };
 
+/// Report a finished denoising step to every streaming request in the batch.
+///
+/// This runs inside the model forward pass, so it must not block: progress is best effort and an
+/// event is dropped if the receiver is full or gone. The final image is still sent by
+/// `send_responses`.
+pub(crate) fn send_progress(senders: &[(u128, Sender<Response>)], step: usize, total_steps: usize) {
+    for (created, sender) in senders {
+        let _ = sender.try_send(Response::ImageGenerationProgress(ImageGenerationProgress {
+            created: *created,
+            step,
+            total_steps,
+        }));
+    }
+}
+
 pub async fn send_responses(
     input_seqs: &mut [&mut Sequence],
     images: Vec<DynamicImage>,
@@ -68,3 +85,44 @@ pub async fn send_responses(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::send_progress;
+    use crate::{Response, ResponseOk};
+
+    #[test]
+    fn test_progress_events_in_order() {
+        let (tx_a, mut rx_a) = channel(16);
+        let (tx_b, mut rx_b) = channel(16);
+        let senders = vec![(1, tx_a), (2, tx_b)];
+
+        let total_steps = 4;
+        for step in 1..=total_steps {
+            send_progress(&senders, step, total_steps);
+        }
+        drop(senders);
+
+        for (created, rx) in [(1, &mut rx_a), (2, &mut rx_b)] {
+            let mut steps = Vec::new();
+            while let Ok(resp) = rx.try_recv() {
+                let Ok(ResponseOk::ImageGenerationProgress(progress)) = resp.as_result() else {
+                    panic!("Expected an image generation progress event");
+                };
+                assert_eq!(progress.created, created);
+                assert_eq!(progress.total_steps, total_steps);
+                steps.push(progress.step);
+            }
+            assert_eq!(steps, vec![1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn test_progress_ignores_closed_receiver() {
+        let (tx, rx) = channel::<Response>(1);
+        drop(rx);
+        send_progress(&[(0, tx)], 1, 1);
+    }
+}
@@ -14,11 +14,13 @@ use std::{
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
+use tokio::sync::mpsc::Sender;
 use tracing::warn;
 
 use crate::{
     get_mut_arcmutex,
     paged_attention::BlockEngine,
+    response::Response,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{Sequence, SequenceState, StopReason},
     TERMINATE_ALL_NEXT_STEP,
@@ -273,6 +275,28 @@ impl PagedAttentionScheduler {
         }
     }
 
+    fn remove_aborted(&mut self) -> Vec<(Sender<Response>, String)> {
+        let mut aborted = Vec::new();
+        for seq in self
+            .waiting
+            .iter()
+            .chain(self.running.iter())
+            .chain(self.swapped_out.iter())
+        {
+            let seq = get_mut_arcmutex!(seq);
+            if let Some(reason) = seq.abort_reason() {
+                aborted.push((seq.get_id(), seq.responder(), reason));
+            }
+        }
+        aborted
+            .into_iter()
+            .map(|(id, responder, reason)| {
+                self._abort_seq(id);
+                (responder, reason)
+            })
+            .collect()
+    }
+
     fn _abort_seq(&mut self, seq_id: usize) {
         let removed = self.remove_seq(seq_id);
         get_mut_arcmutex!(removed).set_state(SequenceState::FinishedAborted);
@@ -359,6 +383,9 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn remove_aborted(&mut self) -> Vec<(Sender<Response>, String)> {
+        self.remove_aborted()
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
@@ -5,12 +5,16 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Mutex,
 };
-use tokio::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    grammar::{GbnfParser, JsonSchemaFsm},
     pipeline::{
         text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
         CacheInstruction,
@@ -32,7 +36,7 @@ use crate::{
     prefix_cacher::PrefixCacheManager,
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
+    sampler::{Sampler, SamplerConfig, TokenHealing},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
     Constraint, StopTokens,
 };
@@ -49,6 +53,61 @@ pub static TERMINATE_ALL_NEXT_STEP: AtomicBool = AtomicBool::new(false);
 pub static ENGINE_INSTRUCTIONS: Lazy<std::sync::Mutex<HashMap<usize, Option<EngineInstruction>>>> =
     Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
 
+/// The adapters to activate for a batch. A batch mixing adapters selects them per sequence if the
+/// pipeline supports it, otherwise the adapters of the first sequence are used.
+fn adapter_instruction(seqs: &[&mut Sequence], mixed_adapter_batches: bool) -> AdapterInstruction {
+    let adapters = seqs[0].get_adapters();
+    if mixed_adapter_batches && seqs.iter().any(|seq| seq.get_adapters() != adapters) {
+        return AdapterInstruction::PerSequence;
+    }
+    adapters
+        .map(AdapterInstruction::Activate)
+        .unwrap_or(AdapterInstruction::None)
+}
+
+/// Why a beam search request can not be served, if it can not. Beam search is not supported:
+/// - by pipelines which do not select beams when sampling, such as speculative decoding,
+/// - with PagedAttention, whose cache blocks are not copied per beam,
+/// - without the KV cache,
+/// - when streaming or returning logprobs, as the beams are reordered every step,
+/// - with images, classifier-free guidance, token healing or grammars.
+fn beam_search_error(
+    request: &NormalRequest,
+    has_images: bool,
+    supports_beam_search: bool,
+    paged_attn: bool,
+    no_kv_cache: bool,
+) -> Option<&'static str> {
+    let beam_search = request.sampling_params.beam_search.as_ref()?;
+    if beam_search.num_beams < request.sampling_params.n_choices {
+        Some("Beam search requires at least as many beams as choices.")
+    } else if !supports_beam_search {
+        Some(
+            "Beam search is not supported by this pipeline, for example with speculative decoding.",
+        )
+    } else if paged_attn {
+        Some("Beam search is not supported with PagedAttention.")
+    } else if no_kv_cache {
+        Some("Beam search is not supported without the KV cache.")
+    } else if request.is_streaming
+        || request.return_logprobs
+        || request.sampling_params.collect_all_logprobs
+    {
+        Some("Beam search does not support streaming or logprobs.")
+    } else if has_images
+        || request.sampling_params.cfg_scale.is_some()
+        || request.sampling_params.token_healing
+        || !matches!(
+            request.constraint,
+            Constraint::None | Constraint::StopRegex(_)
+        )
+    {
+        Some("Beam search does not support images, classifier-free guidance, token healing or grammars.")
+    } else {
+        None
+    }
+}
+
 pub struct Engine {
     rx: Receiver<Request>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
@@ -60,6 +119,8 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    /// Cancellation flags of the running requests, by request id.
+    cancellations: HashMap<usize, Arc<AtomicBool>>,
 }
 
 impl Engine {
@@ -78,6 +139,7 @@ impl Engine {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
         let has_no_kv_cache = get_mut_arcmutex!(pipeline).get_metadata().has_no_kv_cache;
+        let mixed_adapter_batches = get_mut_arcmutex!(pipeline).supports_mixed_adapter_batches();
         if no_kv_cache {
             // Diffusion models...
             assert_eq!(has_no_kv_cache, no_kv_cache);
@@ -90,7 +152,7 @@ impl Engine {
         Self {
             rx,
             pipeline,
-            scheduler: config.into_scheduler(),
+            scheduler: config.into_scheduler(mixed_adapter_batches, &device),
             id: 0,
             truncate_sequence,
             no_kv_cache: no_kv_cache & !has_no_kv_cache,
@@ -103,6 +165,7 @@ impl Engine {
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
+            cancellations: HashMap::new(),
         }
     }
 
@@ -126,6 +189,8 @@ impl Engine {
                 }
                 self.handle_request(request).await;
             }
+            self.remove_aborted().await;
+
             let run_start = Instant::now();
             let scheduled = self.scheduler.schedule();
 
@@ -144,19 +209,15 @@ impl Engine {
                             let pre_op = if !self.no_kv_cache
                                 && last_completion_ids != current_completion_ids
                             {
-                                CacheInstruction::In(
-                                    scheduled.completion[0]
-                                        .get_adapters()
-                                        .map(AdapterInstruction::Activate)
-                                        .unwrap_or(AdapterInstruction::None),
-                                )
+                                CacheInstruction::In(adapter_instruction(
+                                    &scheduled.completion,
+                                    pipeline.supports_mixed_adapter_batches(),
+                                ))
                             } else {
-                                CacheInstruction::Nothing(
-                                    scheduled.completion[0]
-                                        .get_adapters()
-                                        .map(AdapterInstruction::Activate)
-                                        .unwrap_or(AdapterInstruction::None),
-                                )
+                                CacheInstruction::Nothing(adapter_instruction(
+                                    &scheduled.completion,
+                                    pipeline.supports_mixed_adapter_batches(),
+                                ))
                             };
                             let post_op = if !self.no_kv_cache {
                                 CacheInstruction::Out
@@ -216,10 +277,10 @@ impl Engine {
                                     adapter_inst: AdapterInstruction::None,
                                 }
                             };
-                            let adapter_inst = scheduled.prompt[0]
-                                .get_adapters()
-                                .map(AdapterInstruction::Activate)
-                                .unwrap_or(AdapterInstruction::None);
+                            let adapter_inst = adapter_instruction(
+                                &scheduled.prompt,
+                                pipeline.supports_mixed_adapter_batches(),
+                            );
 
                             // Reset non granular state because the old sequence must be dead.
                             // Technically we don't need to do this but it is better to be safe.
@@ -461,7 +522,11 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
-            Constraint::None => SequenceRecognizer::None,
+            Constraint::Gbnf(gbnf) => SequenceRecognizer::Gbnf(GbnfParser::parse(gbnf)?.into()),
+            Constraint::JsonSchema(schema) => {
+                SequenceRecognizer::JsonSchema(JsonSchemaFsm::new(schema)?.into())
+            }
+            Constraint::StopRegex(_) | Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
     }
@@ -474,17 +539,46 @@ impl Engine {
                     Err(e) => warn!("Adapter activation failed: {e:?}"),
                 }
             }
+            Request::ActivateWeightedAdapters(adapters) => {
+                match get_mut_arcmutex!(self.pipeline).activate_weighted_adapters(adapters) {
+                    Ok(n) => info!("Swapped adapters in {n} LoRA layers."),
+                    Err(e) => warn!("Adapter activation failed: {e:?}"),
+                }
+            }
             Request::Normal(request) => self.add_request(request).await,
             Request::ReIsq(level) => {
                 if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
                     warn!("ISQ requantization failed: {e:?}");
                 }
             }
+            Request::Cancel(id) => match self.cancellations.get(&id) {
+                Some(canceled) => canceled.store(true, Ordering::Relaxed),
+                None => warn!("Cannot cancel request {id}, it is not running."),
+            },
             Request::Terminate => panic!("This is unreachable in `handle_request`. Termination is handled in the `run` loop."),
         }
     }
 
+    /// Remove the canceled and timed out sequences, answering each request with a validation error.
+    async fn remove_aborted(&mut self) {
+        let mut responded: Vec<Sender<Response>> = Vec::new();
+        for (responder, reason) in self.scheduler.remove_aborted() {
+            if responded.iter().any(|r| r.same_channel(&responder)) {
+                continue;
+            }
+            // The receiver may already be gone, for example if it canceled the request.
+            let _ = responder
+                .send(Response::ValidationError(reason.into()))
+                .await;
+            responded.push(responder);
+        }
+        // Only the flags of requests which still have sequences are kept.
+        self.cancellations
+            .retain(|_, canceled| Arc::strong_count(canceled) > 1);
+    }
+
     async fn add_request(&mut self, request: NormalRequest) {
+        let received = Instant::now();
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -527,8 +621,14 @@ impl Engine {
         };
 
         let matcher = if request.tools.is_some() {
+            let tool_calling_model = get_mut_arcmutex!(self.pipeline)
+                .get_chat_template()
+                .and_then(|chat_template| chat_template.tool_calling_model());
             Some(Arc::new(handle_seq_error!(
-                ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto),),
+                ToolCallingMatcher::new(
+                    request.tool_choice.unwrap_or(ToolChoice::Auto),
+                    tool_calling_model,
+                ),
                 request.response
             )))
         } else {
@@ -616,6 +716,8 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
+        // As tokenized, before truncation and token healing change the tokens which are run.
+        let tokenized_prompt = request.return_prompt_tokens.then(|| prompt_tokens.clone());
 
         if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
             if !self.truncate_sequence {
@@ -642,6 +744,22 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
+        // The healed end of the prompt is generated again as part of the first token.
+        let token_healing = if request.sampling_params.token_healing {
+            let tok_trie = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .tok_trie
+                .clone();
+            let token_healing =
+                tok_trie.and_then(|tok_trie| TokenHealing::new(&prompt_tokens, &tok_trie));
+            if let Some(token_healing) = &token_healing {
+                prompt_tokens.truncate(prompt_tokens.len() - token_healing.n_removed);
+            }
+            token_healing
+        } else {
+            None
+        };
+
         let prefill_cache = handle_seq_error!(
             self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
             request.response
@@ -654,6 +772,8 @@ impl Engine {
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
         let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let xtc_probability = request.sampling_params.xtc_probability.unwrap_or(0.0);
+        let xtc_threshold = request.sampling_params.xtc_threshold.unwrap_or(0.1);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -739,18 +859,28 @@ impl Engine {
 
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
-        let sampler = Sampler::new(
-            Some(request.sampling_params.temperature.unwrap_or(1.0)),
-            request.sampling_params.top_n_logprobs,
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(request.sampling_params.temperature.unwrap_or(1.0)),
+            top_n_logprobs: request.sampling_params.top_n_logprobs,
             tokenizer,
-            request.sampling_params.frequency_penalty,
-            request.sampling_params.presence_penalty,
-            request.sampling_params.dry_params,
-            topk,
-            topp,
-            minp,
-            request.logits_processors.unwrap_or_default(),
-        );
+            frequency_penalty: request.sampling_params.frequency_penalty,
+            presence_penalty: request.sampling_params.presence_penalty,
+            dry_params: request.sampling_params.dry_params,
+            no_repeat_ngram_size: request.sampling_params.no_repeat_ngram_size,
+            top_k: topk,
+            top_p: topp,
+            min_p: minp,
+            xtc_probability,
+            xtc_threshold,
+            typical_p: request.sampling_params.typical_p,
+            epsilon_cutoff: request.sampling_params.epsilon_cutoff,
+            eta_cutoff: request.sampling_params.eta_cutoff,
+            mirostat: request.sampling_params.mirostat,
+            bad_token_ids: request.sampling_params.bad_token_ids,
+            logits_bias: request.sampling_params.logits_bias,
+            string_logits_bias: request.sampling_params.string_logits_bias,
+            logits_processors: request.logits_processors.unwrap_or_default(),
+        });
         let sampler = handle_seq_error!(sampler, request.response);
 
         if request.sampling_params.n_choices == 0 {
@@ -764,8 +894,160 @@ impl Engine {
             return;
         }
 
-        // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        // The beams of a request are stepped together, each with its own KV cache. The beams are
+        // reordered every step, so their tokens are not streamed.
+        if request.sampling_params.beam_search.is_some() {
+            let error = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                beam_search_error(
+                    &request,
+                    images.is_some(),
+                    pipeline.supports_beam_search(),
+                    pipeline.get_metadata().cache_config.is_some(),
+                    self.no_kv_cache,
+                )
+            };
+            if let Some(error) = error {
+                request
+                    .response
+                    .send(Response::ValidationError(error.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        // Contrastive search runs the model on the candidates of each token, on copies of the KV
+        // cache of the sequence, and selects one using the hidden states before the LM head.
+        if let Some(contrastive_search) = &request.sampling_params.contrastive_search {
+            let error = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                if contrastive_search.top_k == 0
+                    || !(0.0..=1.0).contains(&contrastive_search.penalty_alpha)
+                {
+                    Some("Contrastive search requires `top_k` > 0 and `penalty_alpha` in [0, 1].")
+                } else if !pipeline.supports_contrastive_search()
+                    || pipeline.get_metadata().cache_config.is_some()
+                    || self.no_kv_cache
+                {
+                    Some("Contrastive search is not supported by this pipeline, with PagedAttention or without the KV cache.")
+                } else if request.return_logprobs || request.sampling_params.collect_all_logprobs {
+                    Some("Contrastive search does not support logprobs.")
+                } else if request.sampling_params.beam_search.is_some()
+                    || images.is_some()
+                    || request.sampling_params.cfg_scale.is_some()
+                    || request.sampling_params.token_healing
+                    || !matches!(
+                        request.constraint,
+                        Constraint::None | Constraint::StopRegex(_)
+                    )
+                {
+                    Some("Contrastive search does not support beam search, images, classifier-free guidance, token healing or grammars.")
+                } else {
+                    None
+                }
+            };
+            if let Some(error) = error {
+                request
+                    .response
+                    .send(Response::ValidationError(error.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        let cfg = match request.sampling_params.cfg_scale {
+            None => None,
+            Some(cfg_scale) => {
+                if !cfg_scale.is_finite() || cfg_scale < 0. {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Classifier-free guidance scale must be finite and non-negative."
+                                .into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                if images.is_some()
+                    || get_mut_arcmutex!(self.pipeline)
+                        .get_metadata()
+                        .cache_config
+                        .is_some()
+                {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Classifier-free guidance is only supported for text prompts without PagedAttention.".into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                let negative_prompt_toks = match &request.sampling_params.negative_prompt {
+                    Some(negative_prompt) => {
+                        let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
+                            request
+                                .response
+                                .send(Response::ValidationError(
+                                    "Negative prompts require the pipeline to have a tokenizer"
+                                        .into(),
+                                ))
+                                .await
+                                .expect("Expected receiver.");
+                            return;
+                        };
+                        let encoded = tokenizer
+                            .encode(negative_prompt.clone(), true)
+                            .map_err(anyhow::Error::msg);
+                        handle_seq_error!(encoded, request.response)
+                            .get_ids()
+                            .to_vec()
+                    }
+                    None => vec![],
+                };
+                // Without a negative prompt, the last prompt token is the unconditional context.
+                let negative_prompt_toks = if negative_prompt_toks.is_empty() {
+                    vec![*prompt_tokens.last().unwrap()]
+                } else {
+                    negative_prompt_toks
+                };
+                Some((cfg_scale, negative_prompt_toks))
+            }
+        };
+
+        let stop_regex = match &request.constraint {
+            Constraint::StopRegex(rx) => match regex::bytes::Regex::new(rx) {
+                Ok(stop_regex) => Some(stop_regex),
+                Err(err) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("Invalid stop regex. {err}").into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        let canceled = Arc::new(AtomicBool::new(false));
+        self.cancellations.insert(request.id, canceled.clone());
+
+        // Add sequences. With beam search, there is one sequence per beam, and beam `i` returns
+        // the `i`th best hypothesis.
+        let n_seqs = request
+            .sampling_params
+            .beam_search
+            .as_ref()
+            .map_or(request.sampling_params.n_choices, |beam_search| {
+                beam_search.num_beams
+            });
+        for response_index in 0..n_seqs {
             let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
@@ -801,7 +1083,8 @@ impl Engine {
                 stop_toks.clone(),
                 stop_strings.clone(),
                 request.sampling_params.max_len,
-                request.return_logprobs,
+                request.sampling_params.min_new_tokens,
+                request.return_logprobs || request.sampling_params.collect_all_logprobs,
                 get_mut_arcmutex!(self.pipeline).get_metadata().is_xlora,
                 group.clone(),
                 response_index,
@@ -821,8 +1104,53 @@ impl Engine {
                 image_generation_format,
                 seq_step_type,
                 diffusion_params.clone(),
+                cfg.clone(),
             );
-            let seq = if let Some(prefill_cache) = prefill_cache.clone() {
+            let seq = if let Some(seed) = request.sampling_params.seed {
+                seq.with_seed(seed.wrapping_add(response_index as u64))
+            } else {
+                seq
+            };
+            let seq = if let Some(token_healing) = token_healing.clone() {
+                seq.with_token_healing(token_healing)
+            } else {
+                seq
+            };
+            let seq = if let Some(stop_regex) = stop_regex.clone() {
+                seq.with_stop_regex(stop_regex)
+            } else {
+                seq
+            };
+            let seq = if let Some(beam_search) = request.sampling_params.beam_search.clone() {
+                seq.with_beam_search(beam_search, response_index)
+            } else {
+                seq
+            };
+            let seq = if let Some(contrastive_search) =
+                request.sampling_params.contrastive_search.clone()
+            {
+                seq.with_contrastive_search(contrastive_search)
+            } else {
+                seq
+            };
+            let seq = seq.with_cancellation(canceled.clone());
+            let seq = if let Some(timeout_ms) = request.timeout_ms {
+                seq.with_timeout(received, Duration::from_millis(timeout_ms))
+            } else {
+                seq
+            };
+            let seq = if let Some(ids) = tokenized_prompt.clone() {
+                seq.with_prompt_token_ids(ids)
+            } else {
+                seq
+            };
+            // A prefix cache hit replaces the tokens the prompt step runs on, which would also
+            // apply to the unconditional context of a guided sequence, and leave out the hidden
+            // states of the cached tokens from the context of contrastive search.
+            let seq = if let Some(prefill_cache) = prefill_cache
+                .clone()
+                .filter(|_| cfg.is_none() && request.sampling_params.contrastive_search.is_none())
+            {
                 seq.prefill(
                     prefill_cache.normal,
                     prefill_cache.xlora,
@@ -836,3 +1164,48 @@ impl Engine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::beam_search_error;
+    use crate::{
+        request::NormalRequest, sampler::BeamSearchConfig, RequestMessage, SamplingParams,
+    };
+
+    fn beam_search_request(is_streaming: bool) -> NormalRequest {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let mut request = NormalRequest::new_simple(
+            RequestMessage::CompletionTokens(vec![0, 1]),
+            SamplingParams {
+                beam_search: Some(BeamSearchConfig {
+                    num_beams: 2,
+                    length_penalty: 1.0,
+                    early_stopping: false,
+                }),
+                ..SamplingParams::deterministic()
+            },
+            tx,
+            0,
+            None,
+            None,
+        );
+        request.is_streaming = is_streaming;
+        request
+    }
+
+    #[test]
+    fn beam_search_unsupported_cases_are_rejected() {
+        let request = beam_search_request(false);
+        assert_eq!(beam_search_error(&request, false, true, false, false), None);
+
+        // The speculative pipeline does not support beam search.
+        let error = beam_search_error(&request, false, false, false, false).unwrap();
+        assert!(error.contains("speculative decoding"), "{error}");
+        let error = beam_search_error(&request, false, true, true, false).unwrap();
+        assert!(error.contains("PagedAttention"), "{error}");
+        let error = beam_search_error(&request, false, true, false, true).unwrap();
+        assert!(error.contains("KV cache"), "{error}");
+        let error = beam_search_error(&beam_search_request(true), false, true, false, false);
+        assert!(error.unwrap().contains("streaming"));
+    }
+}
@@ -15,10 +15,16 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUFTemplate {
     fn try_from(c: ContentMetadata) -> Result<Self, Self::Error> {
         // No required keys
 
-        let props = Self {
-            chat_template: c.get_option_value("chat_template")?,
+        // Multiple templates may be stored as an array, in which case the first one is the default
+        let chat_template = match c.get_option_value::<String>("chat_template") {
+            Ok(chat_template) => chat_template,
+            Err(_) => c
+                .get_option_value::<Vec<String>>("chat_template")?
+                .and_then(|templates| templates.into_iter().next()),
         };
 
+        let props = Self { chat_template };
+
         Ok(props)
     }
 }
@@ -40,3 +46,58 @@ pub fn get_gguf_chat_template<R: std::io::Seek + std::io::Read>(
     }
     Ok(props.chat_template)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+    use candle_core::quantized::gguf_file::{self, Value};
+
+    use crate::gguf::Content;
+
+    fn chat_template_from_fixture(metadata: &[(&str, &Value)]) -> Result<Option<String>> {
+        let mut fixture = Cursor::new(Vec::new());
+        gguf_file::write(&mut fixture, metadata, &[])?;
+        fixture.set_position(0);
+
+        let mut readers = [&mut fixture];
+        let content = Content::from_readers(&mut readers)?;
+        super::get_gguf_chat_template(&content)
+    }
+
+    #[test]
+    fn test_gguf_chat_template() -> Result<()> {
+        let arch = Value::String("llama".to_string());
+        let template = "{% for message in messages %}{{ message['content'] }}{% endfor %}";
+
+        assert_eq!(
+            chat_template_from_fixture(&[("general.architecture", &arch)])?,
+            None
+        );
+
+        let single = Value::String(template.to_string());
+        assert_eq!(
+            chat_template_from_fixture(&[
+                ("general.architecture", &arch),
+                ("tokenizer.chat_template", &single),
+            ])?
+            .as_deref(),
+            Some(template)
+        );
+
+        let multiple = Value::Array(vec![
+            Value::String(template.to_string()),
+            Value::String("{{ messages[0]['content'] }}".to_string()),
+        ]);
+        assert_eq!(
+            chat_template_from_fixture(&[
+                ("general.architecture", &arch),
+                ("tokenizer.chat_template", &multiple),
+            ])?
+            .as_deref(),
+            Some(template)
+        );
+        Ok(())
+    }
+}
@@ -9,9 +9,9 @@ use candle_core::{
     Device, Result,
 };
 use indexmap::IndexMap;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::DEBUG;
+use crate::{layers::RopeScalingConfig, DEBUG};
 
 use super::GGUFArchitecture;
 
@@ -37,6 +37,17 @@ fn parse_gguf_value(value: &Value) -> String {
     }
 }
 
+/// `split.count` is written as a `u16` by llama.cpp, but accept any unsigned integer.
+fn parse_split_count(value: &Value) -> Result<u64> {
+    match value {
+        Value::U8(x) => Ok(u64::from(*x)),
+        Value::U16(x) => Ok(u64::from(*x)),
+        Value::U32(x) => Ok(u64::from(*x)),
+        Value::U64(x) => Ok(*x),
+        other => candle_core::bail!("`split.count` must be an unsigned integer, got {other:?}"),
+    }
+}
+
 // Internal invariant: contents and readers must be paired.
 /// This abstracts the files for a GGUF model and enables multiple files to be used.
 pub struct Content<'a, R: std::io::Seek + std::io::Read> {
@@ -47,26 +58,23 @@ pub struct Content<'a, R: std::io::Seek + std::io::Read> {
 }
 
 impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
-    /// Create a `Content` from a set of file readers.
+    /// Create a `Content` from a set of file readers, such as the shards of a split GGUF model.
+    /// Each tensor must be present in exactly one of them.
     pub fn from_readers(readers: &'a mut [&'a mut R]) -> Result<Self> {
         let mut contents = Vec::new();
         let n_readers = readers.len();
         for reader in readers.iter_mut() {
             contents.push(gguf_file::Content::read(reader)?);
         }
-        let n_splits = contents
-            .iter()
-            .filter_map(|ct| {
-                ct.metadata
-                    .get("split.count")
-                    .map(|val| val.to_u64().unwrap())
-            })
-            .fold(Vec::new(), |mut accum, x| {
-                if !accum.contains(&x) {
-                    accum.push(x);
+        let mut n_splits = Vec::new();
+        for ct in &contents {
+            if let Some(val) = ct.metadata.get("split.count") {
+                let n = parse_split_count(val)?;
+                if !n_splits.contains(&n) {
+                    n_splits.push(n);
                 }
-                accum
-            });
+            }
+        }
         if n_splits.len() > 1 {
             candle_core::bail!("GGUF files have differing `split.count` values: {n_splits:?}. Perhaps the GGUF files do not match?");
         }
@@ -80,6 +88,17 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
             info!("GGUF file has been split into {} shards", n_splits[0]);
         }
 
+        let mut tensor_shards = HashMap::new();
+        for (i, ct) in contents.iter().enumerate() {
+            for name in ct.tensor_infos.keys() {
+                if let Some(prev) = tensor_shards.insert(name, i) {
+                    candle_core::bail!(
+                        "Tensor `{name}` is present in both GGUF file {prev} and GGUF file {i}."
+                    );
+                }
+            }
+        }
+
         let mut arch = None;
         for ct in &contents {
             if !ct.metadata.contains_key("general.architecture") {
@@ -187,4 +206,156 @@ impl<'a, R: std::io::Seek + std::io::Read> Content<'a, R> {
     pub fn get_metadata(&self) -> &HashMap<String, Value> {
         &self.all_metadata
     }
+
+    /// Set the `rope.scaling` metadata, overriding any in the files. The `context_length` must be
+    /// the extended context length, so this warns if the files did not already have a
+    /// `rope.scaling` with the same factor.
+    pub fn set_rope_scaling(&mut self, rope_scaling: &RopeScalingConfig) -> Result<()> {
+        if !matches!(self.arch, GGUFArchitecture::Llama) {
+            candle_core::bail!(
+                "RoPE scaling is only supported for the `llama` GGUF architecture, got `{:?}`.",
+                self.arch
+            );
+        }
+
+        let factor = rope_scaling.factor() as f32;
+        let old_factor = self
+            .all_metadata
+            .get("llama.rope.scaling.factor")
+            .and_then(|old| old.to_f32().ok());
+        if old_factor != Some(factor) {
+            let context_length = self
+                .all_metadata
+                .get("llama.context_length")
+                .map(parse_gguf_value);
+            warn!(
+                "RoPE scaling with factor {factor} was set but the GGUF metadata has no matching `rope.scaling`, make sure `context_length` ({context_length:?}) is the extended context length (the trained context length times {factor})."
+            );
+        }
+
+        let scaling_type = match rope_scaling {
+            RopeScalingConfig::Linear { .. } => "linear",
+            RopeScalingConfig::NTK { .. } => "ntk",
+            RopeScalingConfig::YaRN {
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                self.all_metadata.insert(
+                    "llama.rope.scaling.yarn_beta_fast".to_string(),
+                    Value::F32(*beta_fast as f32),
+                );
+                self.all_metadata.insert(
+                    "llama.rope.scaling.yarn_beta_slow".to_string(),
+                    Value::F32(*beta_slow as f32),
+                );
+                "yarn"
+            }
+        };
+        self.all_metadata.insert(
+            "llama.rope.scaling.type".to_string(),
+            Value::String(scaling_type.to_string()),
+        );
+        self.all_metadata
+            .insert("llama.rope.scaling.factor".to_string(), Value::F32(factor));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+    use candle_core::{
+        quantized::{
+            gguf_file::{self, Value},
+            GgmlDType, QTensor,
+        },
+        DType, Device, Tensor,
+    };
+
+    use super::Content;
+
+    fn write_gguf(
+        metadata: &[(&str, &Value)],
+        tensors: &[(&str, &QTensor)],
+    ) -> Result<Cursor<Vec<u8>>> {
+        let mut file = Cursor::new(Vec::new());
+        gguf_file::write(&mut file, metadata, tensors)?;
+        file.set_position(0);
+        Ok(file)
+    }
+
+    #[test]
+    fn test_gguf_split_shards() -> Result<()> {
+        let dev = Device::Cpu;
+        let arch = Value::String("llama".to_string());
+        let split_count = Value::U16(2);
+        let names = ["token_embd.weight", "blk.0.attn_q.weight", "output.weight"];
+        let tensors = names
+            .iter()
+            .map(|name| {
+                let t = Tensor::randn(0f32, 1., (4, 32), &dev)?;
+                Ok((*name, QTensor::quantize(&t, GgmlDType::Q8_0)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tensors = tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>();
+
+        let mut merged_file = write_gguf(&[("general.architecture", &arch)], &tensors)?;
+        let mut merged_readers = [&mut merged_file];
+        let mut merged = Content::from_readers(&mut merged_readers)?;
+
+        // Only the first shard carries the model metadata, as written by llama.cpp's gguf-split
+        let mut shard0 = write_gguf(
+            &[
+                ("general.architecture", &arch),
+                ("split.count", &split_count),
+            ],
+            &tensors[..2],
+        )?;
+        let mut shard1 = write_gguf(&[("split.count", &split_count)], &tensors[2..])?;
+        let mut shard_readers = [&mut shard0, &mut shard1];
+        let mut sharded = Content::from_readers(&mut shard_readers)?;
+
+        // Reads alternate between the shards
+        for name in names.iter().chain(names.iter().rev()) {
+            let expected = merged.tensor(name, &dev)?;
+            let actual = sharded.tensor(name, &dev)?;
+            assert_eq!(actual.dtype(), expected.dtype());
+            assert_eq!(actual.shape(), expected.shape());
+            assert_eq!(
+                actual.dequantize(&dev)?.flatten_all()?.to_vec1::<f32>()?,
+                expected.dequantize(&dev)?.flatten_all()?.to_vec1::<f32>()?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_split_shards_invalid() -> Result<()> {
+        let dev = Device::Cpu;
+        let arch = Value::String("llama".to_string());
+        let split_count = Value::U16(2);
+        let t = QTensor::quantize(&Tensor::zeros((4, 32), DType::F32, &dev)?, GgmlDType::Q8_0)?;
+        let metadata = [
+            ("general.architecture", &arch),
+            ("split.count", &split_count),
+        ];
+
+        // The same tensor in two shards
+        let mut shard0 = write_gguf(&metadata, &[("a", &t), ("b", &t)])?;
+        let mut shard1 = write_gguf(&metadata, &[("b", &t)])?;
+        let mut readers = [&mut shard0, &mut shard1];
+        match Content::from_readers(&mut readers) {
+            Ok(_) => panic!("Duplicate tensors should be rejected"),
+            Err(e) => assert!(e.to_string().contains("Tensor `b`"), "{e}"),
+        }
+
+        // A missing shard
+        let mut shard0 = write_gguf(&metadata, &[("a", &t)])?;
+        let mut readers = [&mut shard0];
+        assert!(Content::from_readers(&mut readers).is_err());
+        Ok(())
+    }
 }
@@ -2,17 +2,18 @@
 
 use std::{collections::HashMap, sync::atomic::Ordering};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use itertools::Itertools;
 use tokenizers::{
     decoders::{
         self, byte_fallback::ByteFallback, byte_level::ByteLevel, fuse::Fuse, strip::Strip,
     },
-    models::{bpe::BpeBuilder, unigram::Unigram},
-    normalizers::{self, Prepend, Replace},
+    models::{bpe::BpeBuilder, unigram::Unigram, wordpiece::WordPiece},
+    normalizers::{self, BertNormalizer, Prepend, Replace},
     pre_tokenizers,
     processors::{
         self,
+        bert::BertProcessing,
         template::{self, TemplateProcessing},
     },
     AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, Tokenizer,
@@ -41,31 +42,62 @@ struct PropsGGUF {
     eos: u32,
     bos: u32,
     add_bos_token: Option<bool>,
+    cls: Option<u32>,
+    sep: Option<u32>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
     type Error = anyhow::Error;
 
     fn try_from(c: ContentMetadata) -> Result<Self, Self::Error> {
-        let required = ["model", "tokens", "eos_token_id", "bos_token_id"];
+        let required = ["model", "tokens"];
         c.has_required_keys(&required)?;
 
+        let model: String = c.get_value("model")?;
+        let cls = c.get_value("cls_token_id").ok();
+        let sep = c.get_value("seperator_token_id").ok();
+        // BERT vocabularies often only have `[CLS]` and `[SEP]`, which stand in for BOS and EOS
+        let (bos, eos) = match (model.as_str(), cls, sep) {
+            ("bert", Some(cls), Some(sep)) => (
+                c.get_value("bos_token_id").unwrap_or(cls),
+                c.get_value("eos_token_id").unwrap_or(sep),
+            ),
+            _ => (c.get_value("bos_token_id")?, c.get_value("eos_token_id")?),
+        };
+
         let props = Self {
-            model: c.get_value("model")?,
+            model,
             tokens: c.get_value("tokens")?,
             added_tokens: c.get_value("added_tokens").ok(),
             scores: c.get_value("scores").ok(),
             merges: c.get_value("merges").ok(),
             unk: c.get_value("unknown_token_id").ok(),
-            eos: c.get_value("eos_token_id")?,
-            bos: c.get_value("bos_token_id")?,
+            eos,
+            bos,
             add_bos_token: c.get_value("add_bos_token").ok(),
+            cls,
+            sep,
         };
 
         Ok(props)
     }
 }
 
+impl PropsGGUF {
+    /// The token with id `id`, the `name` special token.
+    fn token(&self, id: u32, name: &str) -> Result<&str> {
+        self.tokens
+            .get(id as usize)
+            .map(String::as_str)
+            .with_context(|| {
+                format!(
+                    "GGUF tokenizer {name} token id {id} is out of range for {} tokens",
+                    self.tokens.len()
+                )
+            })
+    }
+}
+
 struct AddedTokensCollection {
     bos: String,
     eos: String,
@@ -82,8 +114,11 @@ pub fn convert_gguf_to_hf_tokenizer<R: std::io::Seek + std::io::Read>(
     let props = PropsGGUF::try_from(metadata)?;
 
     let (tokenizer, kind, special_tokens) = match props.model.as_str() {
-        "llama" | "replit" => unigram_tokenizer(&props)?,
+        "llama" | "replit" | "spm" | "sentencepiece" | "unigram" | "t5" => {
+            unigram_tokenizer(&props)?
+        }
         "gpt2" => bpe_tokenizer(&props)?,
+        "bert" => wordpiece_tokenizer(&props)?,
         other => {
             anyhow::bail!("Tokenizer model `{other}` not supported.");
         }
@@ -111,12 +146,13 @@ pub fn convert_gguf_to_hf_tokenizer<R: std::io::Seek + std::io::Read>(
     })
 }
 
-// TODO: Add support for additional tokenizer models: WordPiece, WordLevel
+// TODO: Add support for additional tokenizer models: WordLevel
 // https://docs.rs/tokenizers/latest/tokenizers/models/enum.ModelWrapper.html
 #[derive(Debug)]
 enum TokenizerKind {
     Unigram,
     Bpe,
+    WordPiece,
 }
 
 /// Add the special tokens and return their string representations
@@ -126,14 +162,17 @@ fn add_special_tokens(
     bos: u32,
     eos: u32,
     unk: Option<u32>,
-) -> AddedTokensCollection {
+) -> Result<AddedTokensCollection> {
     // Add special tokens (bos, eos, unk):
     let mut special_tokens: [Option<String>; 3] = Default::default();
 
     // A little bit awkward here since eos/bos are assumed not options so we need to handle an Option
-    for (i, token_id) in [Some(bos), Some(eos), unk].into_iter().enumerate() {
+    for (i, (token_id, name)) in [(Some(bos), "BOS"), (Some(eos), "EOS"), (unk, "UNK")]
+        .into_iter()
+        .enumerate()
+    {
         if let Some(token_id) = token_id {
-            let token = p.tokens[token_id as usize].as_str();
+            let token = p.token(token_id, name)?;
             special_tokens[i] = Some(token.to_string());
             tokenizer.add_special_tokens(&[AddedToken::from(token.to_string(), true)]);
         }
@@ -142,11 +181,11 @@ fn add_special_tokens(
     // Destructure array of options:
     let [bos_str, eos_str, unk_str] = special_tokens;
     // Would need to unwrap bos/eos here, or change the struct types
-    AddedTokensCollection {
+    Ok(AddedTokensCollection {
         bos: bos_str.unwrap(),
         eos: eos_str.unwrap(),
         unk: unk_str,
-    }
+    })
 }
 
 fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokensCollection)> {
@@ -159,7 +198,8 @@ fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTo
         let vocab: Vec<(String, f64)> = {
             let Some(s) = p.scores.as_ref() else {
                 anyhow::bail!(
-                    "`llama` unigram tokenizer is missing required metadata `tokenizer.ggml.scores`"
+                    "`{}` unigram tokenizer is missing required metadata `tokenizer.ggml.scores`",
+                    p.model
                 );
             };
             let scores = s.iter().cloned().map(|f_32| f_32 as f64);
@@ -191,7 +231,7 @@ fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTo
         .build()?;
 
     // Add special tokens (bos, eos, unk):
-    let special_tokens = add_special_tokens(p, &mut tokenizer, bos, eos, Some(unk));
+    let special_tokens = add_special_tokens(p, &mut tokenizer, bos, eos, Some(unk))?;
 
     Ok((tokenizer, TokenizerKind::Unigram, special_tokens))
 }
@@ -229,7 +269,7 @@ fn bpe_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokens
 
     let mut bpe = BpeBuilder::new().vocab_and_merges(vocab, merges);
     if let Some(unk) = unk {
-        bpe = bpe.unk_token(p.tokens[unk as usize].to_string());
+        bpe = bpe.unk_token(p.token(unk, "UNK")?.to_string());
     };
 
     let bpe = bpe.build().map_err(anyhow::Error::msg)?;
@@ -242,21 +282,18 @@ fn bpe_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokens
         false, true, true,
     ));
     if add_bos_token.is_some_and(|x| x) {
+        let bos_token = p.token(bos, "BOS")?.to_string();
         let mut special_toks = HashMap::new();
         special_toks.insert(
-            p.tokens[bos as usize].clone(),
-            template::SpecialToken::new(
-                p.tokens[bos as usize].clone(),
-                vec![bos],
-                vec![p.tokens[bos as usize].clone()],
-            )
-            .unwrap(),
+            bos_token.clone(),
+            template::SpecialToken::new(bos_token.clone(), vec![bos], vec![bos_token.clone()])
+                .unwrap(),
         );
         tokenizer.with_post_processor(
             TemplateProcessing::builder()
-                .try_single(format!("{}:0 $A:0", p.tokens[bos as usize]))
+                .try_single(format!("{bos_token}:0 $A:0"))
                 .unwrap()
-                .try_pair(format!("{}:0 $A:0 $B:1", p.tokens[bos as usize]))
+                .try_pair(format!("{bos_token}:0 $A:0 $B:1"))
                 .unwrap()
                 .special_tokens(special_toks)
                 .build()
@@ -266,11 +303,70 @@ fn bpe_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokens
         tokenizer.with_post_processor(processors::byte_level::ByteLevel::new(true, false, true));
     }
 
-    let special_tokens = add_special_tokens(p, &mut tokenizer, bos, eos, unk);
+    let special_tokens = add_special_tokens(p, &mut tokenizer, bos, eos, unk)?;
 
     Ok((tokenizer, TokenizerKind::Bpe, special_tokens))
 }
 
+fn wordpiece_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokensCollection)> {
+    // llama.cpp stores BERT vocabularies in "phantom space" form: word-initial pieces are
+    // prefixed with `▁` and continuation pieces have their `##` prefix stripped. Undo that here.
+    // https://github.com/ggerganov/llama.cpp/blob/master/convert_hf_to_gguf.py (BertModel::set_vocab)
+    let mut vocab = HashMap::new();
+    for (i, token) in p.tokens.iter().enumerate() {
+        let token = if token.starts_with('[') && token.ends_with(']') {
+            token.clone()
+        } else if let Some(word) = token.strip_prefix('▁') {
+            word.to_string()
+        } else {
+            format!("##{token}")
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        vocab.entry(token).or_insert(i as u32);
+    }
+
+    let PropsGGUF {
+        eos,
+        bos,
+        unk,
+        cls,
+        sep,
+        ..
+    } = *p;
+
+    let mut wordpiece = WordPiece::builder()
+        .vocab(vocab)
+        .continuing_subword_prefix("##".to_string());
+    if let Some(unk) = unk {
+        wordpiece = wordpiece.unk_token(p.token(unk, "UNK")?.to_string());
+    }
+    let wordpiece = wordpiece.build().map_err(anyhow::Error::msg)?;
+
+    // The GGUF metadata does not record whether the model is uncased, so infer it from the vocab.
+    let lowercase = !p
+        .tokens
+        .iter()
+        .filter(|t| !(t.starts_with('[') && t.ends_with(']')))
+        .any(|t| t.chars().any(char::is_uppercase));
+
+    let mut tokenizer = TokenizerX::try_builder()
+        .with_model(wordpiece)
+        .with_decoder(Decoder::WordPiece("##", true))
+        .with_normalizer(Normalizer::Bert(lowercase))
+        .build()?;
+    tokenizer.with_pre_tokenizer(pre_tokenizers::bert::BertPreTokenizer);
+    if let (Some(cls), Some(sep)) = (cls, sep) {
+        tokenizer.with_post_processor(BertProcessing::new(
+            (p.token(sep, "SEP")?.to_string(), sep),
+            (p.token(cls, "CLS")?.to_string(), cls),
+        ));
+    }
+
+    let special_tokens = add_special_tokens(p, &mut tokenizer, bos, eos, unk)?;
+
+    Ok((tokenizer, TokenizerKind::WordPiece, special_tokens))
+}
+
 // This is a workaround to have a better builder API.
 // Upstream `TokenizerBuilder` is difficult to work with:
 // https://github.com/huggingface/tokenizers/issues/1549
@@ -308,6 +404,7 @@ enum Decoder<'a> {
     Strip(char, usize, usize),
     Sequence(Vec<Self>),
     ByteLevel(bool, bool, bool),
+    WordPiece(&'a str, bool),
 }
 
 // Convert into upstream type wrapped enum variants:
@@ -333,6 +430,9 @@ impl TryFrom<Decoder<'_>> for DecoderWrapper {
             Decoder::ByteLevel(add_prefix_space, trim_offsets, use_regex) => {
                 ByteLevel::new(add_prefix_space, trim_offsets, use_regex).into()
             }
+            Decoder::WordPiece(prefix, cleanup) => {
+                decoders::wordpiece::WordPiece::new(prefix.to_owned(), cleanup).into()
+            }
         };
 
         Ok(value)
@@ -345,6 +445,7 @@ enum Normalizer<'a> {
     Prepend(&'a str),
     Replace(&'a str, &'a str),
     Sequence(Vec<Self>),
+    Bert(bool),
 }
 
 impl TryFrom<Normalizer<'_>> for NormalizerWrapper {
@@ -364,6 +465,7 @@ impl TryFrom<Normalizer<'_>> for NormalizerWrapper {
 
                 normalizers::Sequence::new(seq).into()
             }
+            Normalizer::Bert(lowercase) => BertNormalizer::new(true, true, None, lowercase).into(),
         };
 
         Ok(value)
@@ -558,4 +660,308 @@ mod tests {
 
         Ok(())
     }
+
+    fn props(model: &str, tokens: &[&str], scores: Option<Vec<f32>>) -> super::PropsGGUF {
+        super::PropsGGUF {
+            model: model.to_string(),
+            tokens: tokens.iter().map(ToString::to_string).collect(),
+            added_tokens: None,
+            scores,
+            merges: None,
+            unk: Some(0),
+            eos: 2,
+            bos: 1,
+            add_bos_token: None,
+            cls: None,
+            sep: None,
+        }
+    }
+
+    /// Write a GGUF file with only `metadata` to disk and convert its tokenizer.
+    fn convert_gguf_file(
+        name: &str,
+        metadata: &[(&str, &candle_core::quantized::gguf_file::Value)],
+    ) -> Result<super::GgufTokenizerConversion> {
+        use std::fs::File;
+
+        use candle_core::quantized::gguf_file;
+
+        use crate::gguf::Content;
+
+        let path = std::env::temp_dir().join(format!("{name}_{}.gguf", std::process::id()));
+        gguf_file::write(&mut File::create(&path)?, metadata, &[])?;
+        let conversion = {
+            let mut file = File::open(&path)?;
+            let mut readers = [&mut file];
+            let content = Content::from_readers(&mut readers)?;
+            super::convert_gguf_to_hf_tokenizer(&content)
+        };
+        std::fs::remove_file(&path)?;
+        conversion
+    }
+
+    #[test]
+    fn test_encode_decode_sentencepiece_byte_fallback() -> Result<()> {
+        let tokens = ["<unk>", "<s>", "</s>", "▁", "▁hello", "<0xC3>", "<0xA9>"];
+        let scores = vec![0., 0., 0., -2., -1., -3., -3.];
+        let (tokenizer, _, special) =
+            super::unigram_tokenizer(&props("spm", &tokens, Some(scores)))?;
+        assert_eq!(special.bos, "<s>");
+
+        let passage = "hello é";
+        let encoded = tokenizer
+            .encode(passage, false)
+            .map_err(anyhow::Error::msg)?;
+        // `é` is not in the vocab, so it must fall back to its UTF-8 bytes rather than `<unk>`.
+        assert_eq!(encoded.get_ids(), &[4, 3, 5, 6]);
+        assert_eq!(decode(&tokenizer, encoded.get_ids(), false)?, passage);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode_bert() -> Result<()> {
+        let tokens = [
+            "[UNK]", "[CLS]", "[SEP]", "▁hello", "▁world", "▁play", "ing",
+        ];
+        let mut p = props("bert", &tokens, None);
+        p.cls = Some(1);
+        p.sep = Some(2);
+        let (tokenizer, _, _) = super::wordpiece_tokenizer(&p)?;
+
+        let encoded = tokenizer
+            .encode("Hello playing world", true)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(encoded.get_ids(), &[1, 3, 5, 6, 4, 2]);
+        assert_eq!(
+            decode(&tokenizer, encoded.get_ids(), true)?,
+            "hello playing world"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode_bert_gguf() -> Result<()> {
+        use std::collections::HashMap;
+
+        use candle_core::quantized::gguf_file::Value;
+        use tokenizers::{
+            decoders::wordpiece::WordPiece as WordPieceDecoder, models::wordpiece::WordPiece,
+            normalizers::BertNormalizer, pre_tokenizers::bert::BertPreTokenizer,
+            processors::bert::BertProcessing, AddedToken,
+        };
+
+        let vocab = [
+            "[PAD]", "[UNK]", "[CLS]", "[SEP]", "hello", "world", "play", "##ing", "##s", ",", "!",
+            "the",
+        ];
+
+        // Reference tokenizer, as in the `tokenizer.json` of an uncased BERT model
+        let mut hf_tokenizer = Tokenizer::new(
+            WordPiece::builder()
+                .vocab(
+                    vocab
+                        .iter()
+                        .zip(0..)
+                        .map(|(t, i)| (t.to_string(), i))
+                        .collect::<HashMap<_, _>>(),
+                )
+                .unk_token("[UNK]".to_string())
+                .build()
+                .map_err(anyhow::Error::msg)?,
+        );
+        hf_tokenizer
+            .with_normalizer(BertNormalizer::default())
+            .with_pre_tokenizer(BertPreTokenizer)
+            .with_post_processor(BertProcessing::new(
+                ("[SEP]".to_string(), 3),
+                ("[CLS]".to_string(), 2),
+            ))
+            .with_decoder(WordPieceDecoder::default());
+        hf_tokenizer.add_special_tokens(
+            &vocab[..4]
+                .iter()
+                .map(|t| AddedToken::from(t.to_string(), true))
+                .collect::<Vec<_>>(),
+        );
+
+        // The GGUF vocab is in llama.cpp's phantom space form, without BOS and EOS ids
+        let tokens = vocab.map(|t| {
+            let t = if t.starts_with('[') {
+                t.to_string()
+            } else if let Some(t) = t.strip_prefix("##") {
+                t.to_string()
+            } else {
+                format!("▁{t}")
+            };
+            Value::String(t)
+        });
+        let tokens = Value::Array(tokens.to_vec());
+        // The architecture is not used to convert the tokenizer
+        let arch = Value::String("llama".to_string());
+        let model = Value::String("bert".to_string());
+        let conversion = convert_gguf_file(
+            "bert_tokenizer",
+            &[
+                ("general.architecture", &arch),
+                ("tokenizer.ggml.model", &model),
+                ("tokenizer.ggml.tokens", &tokens),
+                ("tokenizer.ggml.unknown_token_id", &Value::U32(1)),
+                ("tokenizer.ggml.cls_token_id", &Value::U32(2)),
+                ("tokenizer.ggml.seperator_token_id", &Value::U32(3)),
+            ],
+        )?;
+        assert_eq!(conversion.bos.as_deref(), Some("[CLS]"));
+        assert_eq!(conversion.eos.as_deref(), Some("[SEP]"));
+        assert_eq!(conversion.unk.as_deref(), Some("[UNK]"));
+        let gguf_tokenizer = conversion.tokenizer;
+
+        for passage in [
+            "Hello, world!",
+            "The world is playing",
+            "hellos PLAYING, the worlds!",
+        ] {
+            let hf_encoded = hf_tokenizer
+                .encode(passage, true)
+                .map_err(anyhow::Error::msg)?;
+            let gguf_encoded = gguf_tokenizer
+                .encode(passage, true)
+                .map_err(anyhow::Error::msg)?;
+            assert_eq!(hf_encoded.get_ids(), gguf_encoded.get_ids(), "{passage}");
+            assert_eq!(
+                decode(&hf_tokenizer, hf_encoded.get_ids(), true)?,
+                decode(&gguf_tokenizer, gguf_encoded.get_ids(), true)?,
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode_unigram_gguf() -> Result<()> {
+        use candle_core::quantized::gguf_file::Value;
+
+        let tokens = [
+            "<pad>", "</s>", "<unk>", "▁", "▁hello", "▁world", "▁wor", "ld", "s",
+        ];
+        let scores = [0., 0., 0., -2., -1., -1., -3., -3., -2.];
+        let tokens = Value::Array(tokens.map(|t| Value::String(t.to_string())).to_vec());
+        let scores = Value::Array(scores.map(Value::F32).to_vec());
+        let arch = Value::String("llama".to_string());
+        let model = Value::String("unigram".to_string());
+
+        // T5-style special token ids, with a non-zero unk id.
+        let conversion = convert_gguf_file(
+            "unigram_tokenizer",
+            &[
+                ("general.architecture", &arch),
+                ("tokenizer.ggml.model", &model),
+                ("tokenizer.ggml.tokens", &tokens),
+                ("tokenizer.ggml.scores", &scores),
+                ("tokenizer.ggml.unknown_token_id", &Value::U32(2)),
+                ("tokenizer.ggml.bos_token_id", &Value::U32(0)),
+                ("tokenizer.ggml.eos_token_id", &Value::U32(1)),
+            ],
+        )?;
+        assert_eq!(conversion.unk.as_deref(), Some("<unk>"));
+        assert_eq!(conversion.eos.as_deref(), Some("</s>"));
+        let tokenizer = conversion.tokenizer;
+
+        for (passage, ids) in [
+            ("hello world", vec![4, 5]),
+            ("hello worlds", vec![4, 5, 8]),
+            ("world hello", vec![5, 4]),
+        ] {
+            let encoded = tokenizer
+                .encode(passage, false)
+                .map_err(anyhow::Error::msg)?;
+            assert_eq!(encoded.get_ids(), ids.as_slice(), "{passage}");
+            assert_eq!(codec_roundtrip(&tokenizer, passage, false)?, passage);
+        }
+
+        // Characters outside the vocab without byte fallback pieces map to the unk id.
+        let encoded = tokenizer
+            .encode("hello x", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(encoded.get_ids(), &[4, 3, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode_spm_gguf() -> Result<()> {
+        use candle_core::quantized::gguf_file::Value;
+
+        let tokens = [
+            "<unk>", "<s>", "</s>", "▁", "▁hello", "▁world", "<0xC3>", "<0xA9>",
+        ];
+        let scores = [0., 0., 0., -2., -1., -1., -3., -3.];
+        let tokens = Value::Array(tokens.map(|t| Value::String(t.to_string())).to_vec());
+        let scores = Value::Array(scores.map(Value::F32).to_vec());
+        let arch = Value::String("llama".to_string());
+        let model = Value::String("spm".to_string());
+
+        let conversion = convert_gguf_file(
+            "spm_tokenizer",
+            &[
+                ("general.architecture", &arch),
+                ("tokenizer.ggml.model", &model),
+                ("tokenizer.ggml.tokens", &tokens),
+                ("tokenizer.ggml.scores", &scores),
+                ("tokenizer.ggml.unknown_token_id", &Value::U32(0)),
+                ("tokenizer.ggml.bos_token_id", &Value::U32(1)),
+                ("tokenizer.ggml.eos_token_id", &Value::U32(2)),
+            ],
+        )?;
+        assert_eq!(conversion.bos.as_deref(), Some("<s>"));
+        assert_eq!(conversion.eos.as_deref(), Some("</s>"));
+        assert_eq!(conversion.unk.as_deref(), Some("<unk>"));
+        let tokenizer = conversion.tokenizer;
+
+        // `é` is not in the vocab, so it falls back to its UTF-8 bytes.
+        for (passage, ids) in [("hello world", vec![4, 5]), ("world é", vec![5, 3, 6, 7])] {
+            let encoded = tokenizer
+                .encode(passage, false)
+                .map_err(anyhow::Error::msg)?;
+            assert_eq!(encoded.get_ids(), ids.as_slice(), "{passage}");
+            assert_eq!(codec_roundtrip(&tokenizer, passage, false)?, passage);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bert_gguf_special_token_out_of_range() -> Result<()> {
+        use candle_core::quantized::gguf_file::Value;
+
+        let tokens = ["[UNK]", "[CLS]", "[SEP]", "▁hello"];
+        let tokens = Value::Array(tokens.map(|t| Value::String(t.to_string())).to_vec());
+        let arch = Value::String("llama".to_string());
+        let model = Value::String("bert".to_string());
+
+        for (key, name) in [
+            ("tokenizer.ggml.unknown_token_id", "UNK"),
+            ("tokenizer.ggml.cls_token_id", "CLS"),
+            ("tokenizer.ggml.seperator_token_id", "SEP"),
+        ] {
+            let mut ids = [
+                ("tokenizer.ggml.unknown_token_id", Value::U32(0)),
+                ("tokenizer.ggml.cls_token_id", Value::U32(1)),
+                ("tokenizer.ggml.seperator_token_id", Value::U32(2)),
+            ];
+            for (k, v) in &mut ids {
+                if *k == key {
+                    *v = Value::U32(4);
+                }
+            }
+            let mut metadata = vec![
+                ("general.architecture", &arch),
+                ("tokenizer.ggml.model", &model),
+                ("tokenizer.ggml.tokens", &tokens),
+            ];
+            metadata.extend(ids.iter().map(|(k, v)| (*k, v)));
+
+            let Err(err) = convert_gguf_file("bert_out_of_range", &metadata) else {
+                panic!("Expected an error for an out of range {name} token id");
+            };
+            assert!(err.to_string().contains(name), "{err}");
+        }
+        Ok(())
+    }
 }
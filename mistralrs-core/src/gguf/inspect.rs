@@ -0,0 +1,110 @@
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use anyhow::Context;
+use candle_core::quantized::gguf_file::{self, Value};
+
+/// A GGUF metadata value.
+pub type GgufValue = Value;
+
+/// Read the metadata of a GGUF file. Only the header is read, not the tensor data.
+pub fn read_gguf_metadata(path: &Path) -> anyhow::Result<HashMap<String, GgufValue>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open GGUF file `{}`", path.display()))?;
+    let content = gguf_file::Content::read(&mut BufReader::new(file))
+        .with_context(|| format!("Could not read GGUF header of `{}`", path.display()))?;
+    Ok(content.metadata)
+}
+
+/// Accessors for common keys of GGUF metadata, as returned by [`read_gguf_metadata`].
+pub trait GgufMetadata {
+    /// The `general.architecture`, for example `llama`.
+    fn architecture(&self) -> Option<&str>;
+    /// The `<architecture>.context_length`: the maximum sequence length the model was trained on.
+    fn context_length(&self) -> Option<usize>;
+    /// The `<architecture>.vocab_size`, or else the number of tokens of the GGUF tokenizer.
+    fn vocab_size(&self) -> Option<usize>;
+}
+
+impl GgufMetadata for HashMap<String, GgufValue> {
+    fn architecture(&self) -> Option<&str> {
+        match self.get("general.architecture")? {
+            Value::String(arch) => Some(arch),
+            _ => None,
+        }
+    }
+
+    fn context_length(&self) -> Option<usize> {
+        let arch = self.architecture()?;
+        value_to_usize(self.get(&format!("{arch}.context_length"))?)
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        let arch = self.architecture()?;
+        if let Some(vocab_size) = self.get(&format!("{arch}.vocab_size")) {
+            return value_to_usize(vocab_size);
+        }
+        match self.get("tokenizer.ggml.tokens")? {
+            Value::Array(tokens) => Some(tokens.len()),
+            _ => None,
+        }
+    }
+}
+
+/// An unsigned or non-negative integer value as a `usize`.
+fn value_to_usize(value: &Value) -> Option<usize> {
+    match value {
+        Value::U8(x) => Some(usize::from(*x)),
+        Value::U16(x) => Some(usize::from(*x)),
+        Value::U32(x) => usize::try_from(*x).ok(),
+        Value::U64(x) => usize::try_from(*x).ok(),
+        Value::I8(x) => usize::try_from(*x).ok(),
+        Value::I16(x) => usize::try_from(*x).ok(),
+        Value::I32(x) => usize::try_from(*x).ok(),
+        Value::I64(x) => usize::try_from(*x).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs::File};
+
+    use candle_core::quantized::gguf_file::{self, Value};
+
+    use super::{read_gguf_metadata, GgufMetadata};
+
+    #[test]
+    fn test_read_gguf_metadata() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("inspect_{}.gguf", std::process::id()));
+        let tokens = Value::Array(vec![Value::String("a".to_string()); 5]);
+        let metadata = [
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.context_length", Value::U32(4096)),
+            ("tokenizer.ggml.tokens", tokens),
+        ];
+        gguf_file::write(
+            &mut File::create(&path)?,
+            &metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>(),
+            &[],
+        )?;
+
+        let read = read_gguf_metadata(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(read.len(), 3);
+        assert_eq!(read.architecture(), Some("llama"));
+        assert_eq!(read.context_length(), Some(4096));
+        // Without `llama.vocab_size`, the tokenizer tokens are counted.
+        assert_eq!(read.vocab_size(), Some(5));
+
+        let mut other = HashMap::new();
+        other.insert(
+            "general.architecture".to_string(),
+            Value::String("phi3".to_string()),
+        );
+        other.insert("llama.context_length".to_string(), Value::U32(4096));
+        other.insert("phi3.vocab_size".to_string(), Value::U64(32064));
+        assert_eq!(other.context_length(), None);
+        assert_eq!(other.vocab_size(), Some(32064));
+        Ok(())
+    }
+}
@@ -1,12 +1,14 @@
 mod chat_template;
 mod content;
 mod gguf_tokenizer;
+mod inspect;
 use strum::EnumString;
 
 use anyhow::{Context, Result};
 pub(crate) use chat_template::get_gguf_chat_template;
 pub(crate) use content::Content;
 pub(crate) use gguf_tokenizer::{convert_gguf_to_hf_tokenizer, GgufTokenizerConversion};
+pub use inspect::{read_gguf_metadata, GgufMetadata, GgufValue};
 use std::str::FromStr;
 
 pub const GGUF_MULTI_FILE_DELIMITER: &str = " ";
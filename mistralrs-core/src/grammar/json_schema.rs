@@ -0,0 +1,923 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+use crate::aici::toktree::{Recognizer, SpecialToken};
+
+/// Keywords which only annotate a schema and do not constrain the instance.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+const SUPPORTED: &[&str] = &[
+    "type",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "enum",
+];
+
+/// Maximum number of consecutive whitespace bytes between JSON tokens. This stops the model from
+/// padding the output with whitespace forever.
+const MAX_WHITESPACE: u8 = 20;
+
+#[derive(Debug, Clone)]
+struct ObjectSchema {
+    /// Property names as they appear between the quotes of the serialized key, with their schemas.
+    properties: Vec<(Vec<u8>, usize)>,
+    required: Vec<bool>,
+    /// Schema for keys not listed in `properties`, or `None` if they are not allowed.
+    additional: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct ArraySchema {
+    items: usize,
+    min: usize,
+    max: Option<usize>,
+}
+
+/// A compiled schema. Values of the allowed types are told apart by their first byte.
+#[derive(Debug, Clone)]
+enum Node {
+    Types {
+        object: Option<ObjectSchema>,
+        array: Option<ArraySchema>,
+        string: bool,
+        /// `Some(true)` if only integers are allowed.
+        number: Option<bool>,
+        boolean: bool,
+        null: bool,
+    },
+    /// The compact serializations of the allowed values.
+    Enum(Vec<Vec<u8>>),
+}
+
+/// Lexer state inside a string, after the opening quote.
+#[derive(Debug, Clone, Copy, Default)]
+struct StrLex {
+    /// Set after a backslash.
+    escape: bool,
+    /// Hex digits left in a `\uXXXX` escape.
+    hex: u8,
+    /// Continuation bytes left in a multi-byte UTF-8 character.
+    utf8: u8,
+}
+
+enum StrStep {
+    Continue(StrLex),
+    Close,
+}
+
+impl StrLex {
+    fn step(self, byte: u8) -> Option<StrStep> {
+        let next = if self.utf8 > 0 {
+            if byte & 0xc0 != 0x80 {
+                return None;
+            }
+            Self {
+                utf8: self.utf8 - 1,
+                ..self
+            }
+        } else if self.hex > 0 {
+            if !byte.is_ascii_hexdigit() {
+                return None;
+            }
+            Self {
+                hex: self.hex - 1,
+                ..self
+            }
+        } else if self.escape {
+            match byte {
+                b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => Self::default(),
+                b'u' => Self {
+                    escape: false,
+                    hex: 4,
+                    utf8: 0,
+                },
+                _ => return None,
+            }
+        } else {
+            match byte {
+                b'"' => return Some(StrStep::Close),
+                b'\\' => Self {
+                    escape: true,
+                    ..self
+                },
+                0x00..=0x1f => return None,
+                0x20..=0x7f => self,
+                0xc2..=0xdf => Self { utf8: 1, ..self },
+                0xe0..=0xef => Self { utf8: 2, ..self },
+                0xf0..=0xf4 => Self { utf8: 3, ..self },
+                _ => return None,
+            }
+        };
+        Some(StrStep::Continue(next))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberPos {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+impl NumberPos {
+    fn is_complete(self) -> bool {
+        matches!(self, Self::Zero | Self::Int | Self::Frac | Self::ExpDigits)
+    }
+
+    fn step(self, byte: u8, integer: bool) -> Option<Self> {
+        match (self, byte) {
+            (Self::Minus, b'0') => Some(Self::Zero),
+            (Self::Minus | Self::Int, b'0'..=b'9') => Some(Self::Int),
+            (Self::Zero | Self::Int, b'.') if !integer => Some(Self::Dot),
+            (Self::Dot | Self::Frac, b'0'..=b'9') => Some(Self::Frac),
+            (Self::Zero | Self::Int | Self::Frac, b'e' | b'E') if !integer => Some(Self::Exp),
+            (Self::Exp, b'+' | b'-') => Some(Self::ExpSign),
+            (Self::Exp | Self::ExpSign | Self::ExpDigits, b'0'..=b'9') => Some(Self::ExpDigits),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ObjectPos {
+    /// After `{`.
+    Open,
+    /// Inside a key, whose bytes so far are kept to match it against the properties.
+    Key { lex: StrLex, key: Vec<u8> },
+    /// After a key, expecting `:` and then a value with this schema.
+    Colon(usize),
+    /// After a value, expecting `,` or `}`.
+    Next,
+    /// After `,`.
+    Comma,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArrayPos {
+    Open,
+    Next,
+    Comma,
+}
+
+#[derive(Debug, Clone)]
+enum Frame {
+    /// Expecting a value with this schema.
+    Value(usize),
+    Object {
+        node: usize,
+        pos: ObjectPos,
+        seen: Vec<bool>,
+    },
+    Array {
+        node: usize,
+        pos: ArrayPos,
+        count: usize,
+    },
+    String(StrLex),
+    Number {
+        pos: NumberPos,
+        integer: bool,
+    },
+    Keyword {
+        word: &'static [u8],
+        len: usize,
+    },
+    Enum {
+        node: usize,
+        alts: Vec<usize>,
+        len: usize,
+    },
+}
+
+enum Step {
+    Consumed,
+    Whitespace,
+    /// The top frame was replaced or popped without consuming the byte; feed it to the new top.
+    Again,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    /// Values being parsed, innermost last. Empty once the document is complete.
+    stack: Vec<Frame>,
+    whitespace: u8,
+}
+
+/// Pushdown automaton accepting the JSON documents which are valid under a JSON schema, one byte
+/// at a time.
+///
+/// The supported keywords are `type`, `properties`, `required`, `additionalProperties`, `items`,
+/// `minItems`, `maxItems` and `enum`; annotations such as `title` and `description` are ignored and
+/// any other keyword is rejected when the schema is compiled. Enum values must be generated in
+/// their compact serialization.
+#[derive(Debug, Clone)]
+pub(crate) struct JsonSchemaFsm {
+    nodes: Vec<Node>,
+    states: Vec<State>,
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn get_usize(schema: &Map<String, Value>, keyword: &str) -> Result<Option<usize>> {
+    schema
+        .get(keyword)
+        .map(|v| {
+            v.as_u64()
+                .and_then(|n| usize::try_from(n).ok())
+                .with_context(|| format!("`{keyword}` must be a non-negative integer"))
+        })
+        .transpose()
+}
+
+/// Compile `schema` into `nodes`, returning the index of its node.
+fn compile(schema: &Value, nodes: &mut Vec<Node>) -> Result<usize> {
+    let any = Map::new();
+    let schema = match schema {
+        Value::Bool(true) => &any,
+        Value::Bool(false) => bail!("the schema `false` does not match any value"),
+        Value::Object(schema) => schema,
+        other => bail!("expected a JSON schema object, got `{other}`"),
+    };
+    if let Some(keyword) = schema
+        .keys()
+        .find(|k| !SUPPORTED.contains(&k.as_str()) && !ANNOTATIONS.contains(&k.as_str()))
+    {
+        bail!("unsupported JSON schema keyword `{keyword}`");
+    }
+
+    let types = match schema.get("type") {
+        None => None,
+        Some(Value::String(t)) => Some(vec![t.as_str()]),
+        Some(Value::Array(ts)) => Some(
+            ts.iter()
+                .map(|t| {
+                    t.as_str()
+                        .context("`type` must be a string or an array of strings")
+                })
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        Some(_) => bail!("`type` must be a string or an array of strings"),
+    };
+    if let Some(t) = types.iter().flatten().find(|t| {
+        ![
+            "object", "array", "string", "number", "integer", "boolean", "null",
+        ]
+        .contains(t)
+    }) {
+        bail!("unknown JSON schema type `{t}`");
+    }
+    let allows = |t: &str| match &types {
+        None => true,
+        Some(ts) => ts.contains(&t) || (t == "integer" && ts.contains(&"number")),
+    };
+
+    if let Some(values) = schema.get("enum") {
+        let values = values.as_array().context("`enum` must be an array")?;
+        if values.is_empty() {
+            bail!("`enum` must not be empty");
+        }
+        let mut alts = Vec::new();
+        for value in values {
+            if !allows(json_type(value)) {
+                bail!("enum value `{value}` does not match the schema type");
+            }
+            let bytes = serde_json::to_vec(value)?;
+            if !alts.contains(&bytes) {
+                alts.push(bytes);
+            }
+        }
+        nodes.push(Node::Enum(alts));
+        return Ok(nodes.len() - 1);
+    }
+
+    // Reserve the index so schemas allowing any value can refer to themselves.
+    let id = nodes.len();
+    nodes.push(Node::Enum(Vec::new()));
+
+    let object = if allows("object") {
+        let mut properties = Vec::new();
+        if let Some(props) = schema.get("properties") {
+            let props = props
+                .as_object()
+                .context("`properties` must be an object")?;
+            for (name, prop) in props {
+                let key = serde_json::to_vec(name)?;
+                properties.push((key[1..key.len() - 1].to_vec(), compile(prop, nodes)?));
+            }
+        }
+        let mut required = vec![false; properties.len()];
+        if let Some(names) = schema.get("required") {
+            let names = names.as_array().context("`required` must be an array")?;
+            for name in names {
+                let name = name.as_str().context("`required` must contain strings")?;
+                let Some(i) = schema
+                    .get("properties")
+                    .and_then(Value::as_object)
+                    .and_then(|props| props.keys().position(|k| k == name))
+                else {
+                    bail!("required property `{name}` is not listed in `properties`");
+                };
+                required[i] = true;
+            }
+        }
+        let additional = match schema.get("additionalProperties") {
+            None if schema.is_empty() => Some(id),
+            None | Some(Value::Bool(true)) => Some(compile(&Value::Bool(true), nodes)?),
+            Some(Value::Bool(false)) => None,
+            Some(other) => Some(compile(other, nodes)?),
+        };
+        Some(ObjectSchema {
+            properties,
+            required,
+            additional,
+        })
+    } else {
+        None
+    };
+
+    let array = if allows("array") {
+        let items = match schema.get("items") {
+            Some(Value::Array(_)) => bail!("tuple validation with an `items` array is unsupported"),
+            Some(items) => compile(items, nodes)?,
+            None if schema.is_empty() => id,
+            None => compile(&Value::Bool(true), nodes)?,
+        };
+        let min = get_usize(schema, "minItems")?.unwrap_or(0);
+        let max = get_usize(schema, "maxItems")?;
+        if max.is_some_and(|max| min > max) {
+            bail!("`minItems` is greater than `maxItems`");
+        }
+        Some(ArraySchema { items, min, max })
+    } else {
+        None
+    };
+
+    let number = if allows("number") {
+        Some(false)
+    } else if allows("integer") {
+        Some(true)
+    } else {
+        None
+    };
+    nodes[id] = Node::Types {
+        object,
+        array,
+        string: allows("string"),
+        number,
+        boolean: allows("boolean"),
+        null: allows("null"),
+    };
+    Ok(id)
+}
+
+impl JsonSchemaFsm {
+    /// Validate and compile a JSON schema, returning a recognizer for the documents it accepts.
+    pub(crate) fn new(schema: &Value) -> Result<Self> {
+        let mut nodes = Vec::new();
+        let root = compile(schema, &mut nodes)?;
+        Ok(Self {
+            nodes,
+            states: vec![State {
+                stack: vec![Frame::Value(root)],
+                whitespace: 0,
+            }],
+        })
+    }
+
+    fn object(&self, node: usize) -> &ObjectSchema {
+        match &self.nodes[node] {
+            Node::Types {
+                object: Some(object),
+                ..
+            } => object,
+            _ => unreachable!("object frame for a non-object schema"),
+        }
+    }
+
+    fn array(&self, node: usize) -> &ArraySchema {
+        match &self.nodes[node] {
+            Node::Types {
+                array: Some(array), ..
+            } => array,
+            _ => unreachable!("array frame for a non-array schema"),
+        }
+    }
+
+    fn can_add_key(&self, node: usize, seen: &[bool]) -> bool {
+        self.object(node).additional.is_some() || seen.iter().any(|s| !s)
+    }
+
+    fn has_required(&self, node: usize, seen: &[bool]) -> bool {
+        let object = self.object(node);
+        object.required.iter().zip(seen).all(|(r, s)| !r || *s)
+    }
+
+    /// Whether the frame is a complete value which may still be extended, like `12` or `tru`e.
+    fn may_end(&self, frame: &Frame) -> bool {
+        match frame {
+            Frame::Number { pos, .. } => pos.is_complete(),
+            Frame::Enum { node, alts, len } => {
+                let Node::Enum(values) = &self.nodes[*node] else {
+                    unreachable!()
+                };
+                alts.iter().any(|a| values[*a].len() == *len)
+            }
+            _ => false,
+        }
+    }
+
+    /// Feed `byte` to the top of `stack`, or return `None` if it is not allowed there.
+    fn step(&self, stack: &mut Vec<Frame>, byte: u8) -> Option<Step> {
+        let Some(top) = stack.last_mut() else {
+            // The document is complete; only trailing whitespace may follow.
+            return is_whitespace(byte).then_some(Step::Whitespace);
+        };
+        match top {
+            Frame::Value(node) => {
+                if is_whitespace(byte) {
+                    return Some(Step::Whitespace);
+                }
+                let node = *node;
+                let (object, array, string, number, boolean, null) = match &self.nodes[node] {
+                    Node::Enum(values) => {
+                        *top = Frame::Enum {
+                            node,
+                            alts: (0..values.len()).collect(),
+                            len: 0,
+                        };
+                        return Some(Step::Again);
+                    }
+                    Node::Types {
+                        object,
+                        array,
+                        string,
+                        number,
+                        boolean,
+                        null,
+                    } => (object, array, *string, *number, *boolean, *null),
+                };
+                *top = match byte {
+                    b'{' if object.is_some() => Frame::Object {
+                        node,
+                        pos: ObjectPos::Open,
+                        seen: vec![false; self.object(node).properties.len()],
+                    },
+                    b'[' if array.is_some() => Frame::Array {
+                        node,
+                        pos: ArrayPos::Open,
+                        count: 0,
+                    },
+                    b'"' if string => Frame::String(StrLex::default()),
+                    b'-' | b'0'..=b'9' if number.is_some() => Frame::Number {
+                        pos: match byte {
+                            b'-' => NumberPos::Minus,
+                            b'0' => NumberPos::Zero,
+                            _ => NumberPos::Int,
+                        },
+                        integer: number == Some(true),
+                    },
+                    b't' if boolean => Frame::Keyword {
+                        word: b"true",
+                        len: 1,
+                    },
+                    b'f' if boolean => Frame::Keyword {
+                        word: b"false",
+                        len: 1,
+                    },
+                    b'n' if null => Frame::Keyword {
+                        word: b"null",
+                        len: 1,
+                    },
+                    _ => return None,
+                };
+                Some(Step::Consumed)
+            }
+            Frame::Object { node, pos, seen } => {
+                let node = *node;
+                match pos {
+                    ObjectPos::Open | ObjectPos::Comma | ObjectPos::Colon(_) | ObjectPos::Next
+                        if is_whitespace(byte) =>
+                    {
+                        Some(Step::Whitespace)
+                    }
+                    ObjectPos::Open | ObjectPos::Comma
+                        if byte == b'"' && self.can_add_key(node, seen) =>
+                    {
+                        *pos = ObjectPos::Key {
+                            lex: StrLex::default(),
+                            key: Vec::new(),
+                        };
+                        Some(Step::Consumed)
+                    }
+                    ObjectPos::Open | ObjectPos::Next
+                        if byte == b'}' && self.has_required(node, seen) =>
+                    {
+                        stack.pop();
+                        Some(Step::Consumed)
+                    }
+                    ObjectPos::Next if byte == b',' && self.can_add_key(node, seen) => {
+                        *pos = ObjectPos::Comma;
+                        Some(Step::Consumed)
+                    }
+                    ObjectPos::Colon(child) if byte == b':' => {
+                        let child = *child;
+                        *pos = ObjectPos::Next;
+                        stack.push(Frame::Value(child));
+                        Some(Step::Consumed)
+                    }
+                    ObjectPos::Key { lex, key } => {
+                        let object = self.object(node);
+                        match lex.step(byte)? {
+                            StrStep::Close => {
+                                let property =
+                                    object.properties.iter().position(|(k, _)| *k == *key);
+                                let child = match property {
+                                    Some(i) if seen[i] => return None,
+                                    Some(i) => {
+                                        seen[i] = true;
+                                        object.properties[i].1
+                                    }
+                                    None => object.additional?,
+                                };
+                                *pos = ObjectPos::Colon(child);
+                            }
+                            StrStep::Continue(next) => {
+                                key.push(byte);
+                                let viable = object.additional.is_some()
+                                    || object
+                                        .properties
+                                        .iter()
+                                        .zip(seen.iter())
+                                        .any(|((k, _), s)| !s && k.starts_with(key.as_slice()));
+                                if !viable {
+                                    return None;
+                                }
+                                *lex = next;
+                            }
+                        }
+                        Some(Step::Consumed)
+                    }
+                    _ => None,
+                }
+            }
+            Frame::Array { node, pos, count } => {
+                let array = self.array(*node);
+                if is_whitespace(byte) {
+                    return Some(Step::Whitespace);
+                }
+                match pos {
+                    ArrayPos::Open | ArrayPos::Next if byte == b']' && *count >= array.min => {
+                        stack.pop();
+                        Some(Step::Consumed)
+                    }
+                    ArrayPos::Next if byte == b',' && array.max.is_none_or(|max| *count < max) => {
+                        *pos = ArrayPos::Comma;
+                        Some(Step::Consumed)
+                    }
+                    ArrayPos::Open | ArrayPos::Comma
+                        if array.max.is_none_or(|max| *count < max) =>
+                    {
+                        *count += 1;
+                        *pos = ArrayPos::Next;
+                        stack.push(Frame::Value(array.items));
+                        Some(Step::Again)
+                    }
+                    _ => None,
+                }
+            }
+            Frame::String(lex) => {
+                match lex.step(byte)? {
+                    StrStep::Close => {
+                        stack.pop();
+                    }
+                    StrStep::Continue(next) => *lex = next,
+                }
+                Some(Step::Consumed)
+            }
+            Frame::Number { pos, integer } => match pos.step(byte, *integer) {
+                Some(next) => {
+                    *pos = next;
+                    Some(Step::Consumed)
+                }
+                None if pos.is_complete() => {
+                    stack.pop();
+                    Some(Step::Again)
+                }
+                None => None,
+            },
+            Frame::Keyword { word, len } => {
+                if word[*len] != byte {
+                    return None;
+                }
+                *len += 1;
+                if *len == word.len() {
+                    stack.pop();
+                }
+                Some(Step::Consumed)
+            }
+            Frame::Enum { node, alts, len } => {
+                let Node::Enum(values) = &self.nodes[*node] else {
+                    unreachable!()
+                };
+                let next = alts
+                    .iter()
+                    .copied()
+                    .filter(|a| values[*a].get(*len) == Some(&byte))
+                    .collect::<Vec<_>>();
+                if next.is_empty() {
+                    // A complete value followed by a byte which belongs to the enclosing value.
+                    return if alts.iter().any(|a| values[*a].len() == *len) {
+                        stack.pop();
+                        Some(Step::Again)
+                    } else {
+                        None
+                    };
+                }
+                *len += 1;
+                *alts = next;
+                if alts.iter().all(|a| values[*a].len() == *len) {
+                    stack.pop();
+                }
+                Some(Step::Consumed)
+            }
+        }
+    }
+}
+
+impl Recognizer for JsonSchemaFsm {
+    fn pop_bytes(&mut self, num: usize) {
+        self.states.truncate(self.states.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let top = self.states.pop().unwrap();
+        self.states.clear();
+        self.states.push(top);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let stack = &self.states.last().unwrap().stack;
+        match tok {
+            SpecialToken::EndOfSentence => match stack.as_slice() {
+                [] => true,
+                [frame] => self.may_end(frame),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.states.len() == 1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let mut state = self.states.last().unwrap().clone();
+        loop {
+            match self.step(&mut state.stack, byte) {
+                None => return false,
+                Some(Step::Again) => continue,
+                Some(Step::Consumed) => state.whitespace = 0,
+                Some(Step::Whitespace) => {
+                    if state.whitespace >= MAX_WHITESPACE {
+                        return false;
+                    }
+                    state.whitespace += 1;
+                }
+            }
+            break;
+        }
+        self.states.push(state);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::JsonSchemaFsm;
+    use crate::aici::toktree::{Recognizer, SpecialToken, TokRxInfo, TokTrie};
+
+    /// Returns whether `input` is a viable prefix, and whether it is a complete match.
+    fn check(schema: &serde_json::Value, input: &str) -> (bool, bool) {
+        let mut rec = JsonSchemaFsm::new(schema).unwrap();
+        if !input.bytes().all(|b| rec.try_push_byte(b)) {
+            return (false, false);
+        }
+        (true, rec.special_allowed(SpecialToken::EndOfSentence))
+    }
+
+    #[test]
+    fn test_json_schema_nested_objects() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "address": {
+                    "type": "object",
+                    "properties": {
+                        "city": {"type": "string"},
+                        "zip": {"type": ["string", "null"]}
+                    },
+                    "required": ["city"],
+                    "additionalProperties": false
+                }
+            },
+            "required": ["name", "address"],
+            "additionalProperties": false
+        });
+        for input in [
+            r#"{"name":"Ada","address":{"city":"London"}}"#,
+            r#"{"address": {"zip": null, "city": "Zürich"}, "age": 36, "name": "A\"da"}"#,
+            "{\n  \"name\": \"Ada\",\n  \"address\": {\"city\": \"\", \"zip\": \"N1\"}\n}",
+        ] {
+            assert_eq!(check(&schema, input), (true, true), "{input}");
+        }
+        assert_eq!(check(&schema, r#"{"name":"Ada","age":3"#), (true, false));
+        for input in [
+            // Missing the required `address`.
+            r#"{"name":"Ada"}"#,
+            // Missing the nested required `city`.
+            r#"{"name":"Ada","address":{}}"#,
+            // Unknown and duplicate keys.
+            r#"{"nick""#,
+            r#"{"name":"Ada","name""#,
+            r#"{"name":"Ada","address":{"city":"x","country""#,
+            // Wrong types.
+            r#"{"name":1"#,
+            r#"{"age":1.5"#,
+            r#"{"address":{"zip":true"#,
+            // Not JSON.
+            r#"{name"#,
+            r#"{"name" "Ada"#,
+            "{\"name\":\"a\x01",
+        ] {
+            assert!(!check(&schema, input).0, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_json_schema_arrays() {
+        let schema = json!({
+            "type": "array",
+            "items": {"type": "array", "items": {"type": "number"}, "maxItems": 2},
+            "minItems": 1,
+            "maxItems": 3
+        });
+        for input in ["[[]]", "[[1, -2.5e3], [0.25], []]", "[ [ 0 ] ]"] {
+            assert_eq!(check(&schema, input), (true, true), "{input}");
+        }
+        assert_eq!(check(&schema, "["), (true, false));
+        assert!(!check(&schema, "[]").0);
+        assert_eq!(check(&schema, "[[1,2]"), (true, false));
+        assert!(!check(&schema, "[[1,2,").0);
+        assert!(!check(&schema, "[[],[],[],").0);
+        assert!(!check(&schema, r#"[["1"]]"#).0);
+        assert!(!check(&schema, "[[01]]").0);
+        assert!(!check(&schema, "[[1,]]").0);
+
+        // Items default to any JSON value.
+        let schema = json!({"type": "array"});
+        assert_eq!(
+            check(&schema, r#"[{"a": [true, null]}, "x", 1e5, false]"#),
+            (true, true)
+        );
+    }
+
+    #[test]
+    fn test_json_schema_enums() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "color": {"enum": ["red", "green", "greenish"]},
+                "size": {"enum": [1, 10, null]}
+            },
+            "required": ["color", "size"]
+        });
+        for input in [
+            r#"{"color":"red","size":1}"#,
+            r#"{"color":"greenish","size":10}"#,
+            r#"{"size": null, "color": "green"}"#,
+        ] {
+            assert_eq!(check(&schema, input), (true, true), "{input}");
+        }
+        assert!(!check(&schema, r#"{"color":"blue""#).0);
+        assert!(!check(&schema, r#"{"color":"red","size":100"#).0);
+        assert!(!check(&schema, r#"{"color":"gre""#).0);
+
+        // A number which is a prefix of another value ends at the next structural byte.
+        let schema = json!({"enum": [1, 12]});
+        assert_eq!(check(&schema, "1"), (true, true));
+        assert_eq!(check(&schema, "12"), (true, true));
+        assert_eq!(check(&schema, "13"), (false, false));
+    }
+
+    #[test]
+    fn test_json_schema_whitespace_is_bounded() {
+        let schema = json!({"type": "boolean"});
+        assert_eq!(
+            check(&schema, &format!("{}true", " ".repeat(20))),
+            (true, true)
+        );
+        assert!(!check(&schema, &" ".repeat(21)).0);
+        assert_eq!(check(&schema, "false\n"), (true, true));
+    }
+
+    #[test]
+    fn test_json_schema_with_tok_trie() {
+        let words = [
+            b"{\"".to_vec(),
+            b"ok".to_vec(),
+            b"\":".to_vec(),
+            b"true".to_vec(),
+            b"}".to_vec(),
+            b"1".to_vec(),
+            b"\"".to_vec(),
+            vec![],
+        ];
+        let tok_trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: 8,
+                tok_eos: 7,
+            },
+            &words,
+        );
+        let schema = json!({
+            "type": "object",
+            "properties": {"ok": {"type": "boolean"}},
+            "required": ["ok"],
+            "additionalProperties": false
+        });
+        let mut rec = JsonSchemaFsm::new(&schema).unwrap();
+        // Token 7 is EOS, which is checked with `special_allowed`.
+        let allowed = |rec: &mut JsonSchemaFsm| {
+            (0..7)
+                .filter(|t| tok_trie.token_allowed(rec, *t))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(allowed(&mut rec), vec![0]);
+        tok_trie.append_token(&mut rec, 0).unwrap();
+        assert_eq!(allowed(&mut rec), vec![1]);
+        tok_trie.append_token(&mut rec, 1).unwrap();
+        assert_eq!(allowed(&mut rec), vec![2, 6]);
+        tok_trie.append_token(&mut rec, 2).unwrap();
+        assert_eq!(allowed(&mut rec), vec![3]);
+        assert!(!rec.special_allowed(SpecialToken::EndOfSentence));
+        assert!(tok_trie.append_token(&mut rec, 5).is_err());
+        tok_trie.append_tokens(&mut rec, &[3, 4]).unwrap();
+        assert!(allowed(&mut rec).is_empty());
+        assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+    }
+
+    #[test]
+    fn test_json_schema_invalid_schemas() {
+        for (schema, err) in [
+            (json!({"type": "object", "$ref": "#/a"}), "keyword `$ref`"),
+            (json!({"anyOf": []}), "keyword `anyOf`"),
+            (json!({"type": "tuple"}), "unknown JSON schema type"),
+            (json!({"enum": []}), "must not be empty"),
+            (json!({"type": "string", "enum": [1]}), "does not match"),
+            (json!({"minItems": 3, "maxItems": 2}), "greater than"),
+            (json!({"items": [{}]}), "tuple validation"),
+            (json!({"required": ["a"]}), "`a` is not listed"),
+            (json!(false), "does not match any value"),
+            (json!({"maxItems": -1}), "non-negative integer"),
+        ] {
+            let e = JsonSchemaFsm::new(&schema).unwrap_err().to_string();
+            assert!(e.contains(err), "{schema}: {e}");
+        }
+    }
+}
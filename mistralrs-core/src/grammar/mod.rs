@@ -0,0 +1,83 @@
+//! Grammar constrained decoding with GBNF (GGML BNF) grammars.
+//!
+//! A grammar is parsed by [`GbnfParser`] into a set of rules, each of which is a list of
+//! alternatives. The resulting [`CfgRecognizer`] is a pushdown automaton over Unicode code points
+//! which implements the tok-trie [`Recognizer`](crate::aici::toktree::Recognizer), so it can be
+//! used to bias and validate tokens in the same way as the regex and yacc constraints.
+//!
+//! [`JsonSchemaFsm`] is a pushdown automaton of the same kind, compiled from a JSON schema instead of
+//! a grammar.
+
+mod json_schema;
+mod parser;
+mod recognizer;
+
+pub(crate) use json_schema::JsonSchemaFsm;
+pub(crate) use parser::GbnfParser;
+pub(crate) use recognizer::CfgRecognizer;
+
+/// A single element of a rule alternative.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Element {
+    /// Match one code point within any of the inclusive ranges, or outside all of them if negated.
+    Char {
+        ranges: Vec<(u32, u32)>,
+        negated: bool,
+    },
+    /// Match the rule with this index.
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, cp: u32) -> bool {
+        match self {
+            Self::Char { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&cp)) != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+
+    /// Whether any code point in `lo..=hi` may match. Used to reject incomplete UTF-8 sequences early.
+    fn may_match_range(&self, lo: u32, hi: u32) -> bool {
+        match self {
+            Self::Char {
+                ranges,
+                negated: false,
+            } => ranges.iter().any(|(a, b)| *a <= hi && *b >= lo),
+            Self::Char {
+                ranges,
+                negated: true,
+            } => {
+                // Some code point is allowed unless the ranges cover all of `lo..=hi`.
+                let mut sorted = ranges.clone();
+                sorted.sort_unstable();
+                let mut next = lo;
+                for (a, b) in sorted {
+                    if a > next {
+                        break;
+                    }
+                    if b >= next {
+                        if b >= hi {
+                            return false;
+                        }
+                        next = b + 1;
+                    }
+                }
+                true
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+/// Alternatives are sequences of elements; an empty sequence matches the empty string.
+pub(crate) type Alternatives = Vec<Vec<Element>>;
+
+/// A validated grammar: every referenced rule is defined, no rule is left recursive, and every
+/// alternative reachable from `root` can finish, so the end of sequence is always reachable.
+#[derive(Debug, Clone)]
+pub(crate) struct Grammar {
+    rules: Vec<Alternatives>,
+    root: usize,
+}
@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use super::{Alternatives, CfgRecognizer, Element, Grammar};
+
+/// Parser for GBNF grammars, as used by llama.cpp.
+///
+/// Supported syntax:
+/// - Rules of the form `name ::= alternatives`, one per line. The entry point is `root`.
+/// - Literals (`"abc"`), character classes (`[a-z]`, `[^"\\]`), any character (`.`), rule
+///   references, groups (`( ... )`) and alternation (`|`).
+/// - Repetition with `*`, `+`, `?`, `{m}`, `{m,}` and `{m,n}`.
+/// - Escapes `\n`, `\r`, `\t`, `\\`, `\"`, `\[`, `\]`, `\-`, `\xHH`, `\uHHHH` and `\UHHHHHHHH`.
+/// - Comments starting with `#`. Newlines end a rule except inside groups.
+pub(crate) struct GbnfParser {
+    src: Vec<char>,
+    pos: usize,
+    symbols: HashMap<String, usize>,
+    names: Vec<String>,
+    rules: Vec<Option<Alternatives>>,
+}
+
+impl GbnfParser {
+    /// Parse and validate a GBNF grammar, returning a recognizer starting at the `root` rule.
+    pub(crate) fn parse(grammar: &str) -> Result<CfgRecognizer> {
+        let mut parser = Self {
+            src: grammar.chars().collect(),
+            pos: 0,
+            symbols: HashMap::new(),
+            names: Vec::new(),
+            rules: Vec::new(),
+        };
+        parser.skip_space(true);
+        while parser.peek().is_some() {
+            parser.parse_rule()?;
+            parser.skip_space(true);
+        }
+        Ok(CfgRecognizer::new(parser.finish()?))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn line(&self) -> usize {
+        1 + self.src[..self.pos.min(self.src.len())]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for c in s.chars() {
+            if self.peek() != Some(c) {
+                bail!("expected `{s}` on line {}", self.line());
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\r' && c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(Self::is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("expected rule name on line {}", self.line());
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn symbol_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.symbols.get(name) {
+            return *id;
+        }
+        let id = self.rules.len();
+        self.symbols.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        self.rules.push(None);
+        id
+    }
+
+    /// Create an anonymous rule used for groups and repetitions.
+    fn generated_rule(&mut self, base: &str) -> usize {
+        let mut n = self.rules.len();
+        while self.symbols.contains_key(&format!("{base}-{n}")) {
+            n += 1;
+        }
+        self.symbol_id(&format!("{base}-{n}"))
+    }
+
+    fn parse_rule(&mut self) -> Result<()> {
+        let name = self.parse_name()?;
+        self.skip_space(false);
+        self.expect("::=")?;
+        self.skip_space(true);
+        let alternatives = self.parse_alternatives(&name, false)?;
+        let id = self.symbol_id(&name);
+        if self.rules[id].is_some() {
+            bail!("rule `{name}` is defined more than once");
+        }
+        self.rules[id] = Some(alternatives);
+        match self.peek() {
+            None | Some('\r' | '\n') => Ok(()),
+            Some(c) => bail!("unexpected `{c}` on line {}", self.line()),
+        }
+    }
+
+    fn parse_alternatives(&mut self, rule: &str, nested: bool) -> Result<Alternatives> {
+        let mut alternatives = vec![self.parse_sequence(rule, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.parse_sequence(rule, nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, rule: &str, nested: bool) -> Result<Vec<Element>> {
+        let mut seq = Vec::new();
+        // Start of the last item, which repetition operators apply to.
+        let mut last_start = None;
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last_start = Some(seq.len());
+                    while self.peek() != Some('"') {
+                        if self.peek().is_none() {
+                            bail!("unterminated literal in rule `{rule}`");
+                        }
+                        let cp = self.parse_char()?;
+                        seq.push(Element::Char {
+                            ranges: vec![(cp, cp)],
+                            negated: false,
+                        });
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    self.pos += 1;
+                    last_start = Some(seq.len());
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    while self.peek() != Some(']') {
+                        if self.peek().is_none() {
+                            bail!("unterminated character class in rule `{rule}`");
+                        }
+                        let lo = self.parse_char()?;
+                        let hi = if self.peek() == Some('-')
+                            && self.src.get(self.pos + 1).is_some_and(|c| *c != ']')
+                        {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                        if hi < lo {
+                            bail!("invalid character range in rule `{rule}`");
+                        }
+                        ranges.push((lo, hi));
+                    }
+                    self.pos += 1;
+                    seq.push(Element::Char { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_start = Some(seq.len());
+                    seq.push(Element::Char {
+                        ranges: Vec::new(),
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let group = self.generated_rule(rule);
+                    let alternatives = self.parse_alternatives(rule, true)?;
+                    self.rules[group] = Some(alternatives);
+                    self.expect(")")?;
+                    last_start = Some(seq.len());
+                    seq.push(Element::Rule(group));
+                }
+                '*' | '+' | '?' | '{' => {
+                    let Some(start) = last_start.take() else {
+                        bail!("`{c}` without a preceding item in rule `{rule}`");
+                    };
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        '?' => (0, Some(1)),
+                        _ => self.parse_braces()?,
+                    };
+                    let item = seq.split_off(start);
+                    self.repeat(rule, &mut seq, item, min, max);
+                }
+                c if Self::is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    last_start = Some(seq.len());
+                    seq.push(Element::Rule(self.symbol_id(&name)));
+                }
+                _ => break,
+            }
+            self.skip_space(nested);
+        }
+        Ok(seq)
+    }
+
+    fn parse_int(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.src[start..self.pos].iter().collect::<String>();
+        digits
+            .parse()
+            .map_err(|_| anyhow::anyhow!("expected integer on line {}", self.line()))
+    }
+
+    /// Parse the rest of `{m}`, `{m,}` or `{m,n}` after the opening brace.
+    fn parse_braces(&mut self) -> Result<(usize, Option<usize>)> {
+        self.skip_space(false);
+        let min = self.parse_int()?;
+        self.skip_space(false);
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.skip_space(false);
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.parse_int()?)
+            }
+        } else {
+            Some(min)
+        };
+        self.skip_space(false);
+        self.expect("}")?;
+        if max.is_some_and(|max| max < min) {
+            bail!("invalid repetition bounds on line {}", self.line());
+        }
+        Ok((min, max))
+    }
+
+    /// Expand `item{min,max}` into `item` repeated `min` times followed by optional copies, which
+    /// are right recursive (`r ::= item r |`) so the recognizer stacks stay bounded.
+    fn repeat(
+        &mut self,
+        rule: &str,
+        seq: &mut Vec<Element>,
+        item: Vec<Element>,
+        min: usize,
+        max: Option<usize>,
+    ) {
+        for _ in 0..min {
+            seq.extend(item.iter().cloned());
+        }
+        match max {
+            None => {
+                let star = self.generated_rule(rule);
+                let mut repeated = item;
+                repeated.push(Element::Rule(star));
+                self.rules[star] = Some(vec![repeated, Vec::new()]);
+                seq.push(Element::Rule(star));
+            }
+            Some(max) => {
+                let mut rest = None;
+                for _ in min..max {
+                    let optional = self.generated_rule(rule);
+                    let mut repeated = item.clone();
+                    repeated.extend(rest.map(Element::Rule));
+                    self.rules[optional] = Some(vec![repeated, Vec::new()]);
+                    rest = Some(optional);
+                }
+                seq.extend(rest.map(Element::Rule));
+            }
+        }
+    }
+
+    fn parse_hex(&mut self, digits: usize) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..digits {
+            let Some(d) = self.peek().and_then(|c| c.to_digit(16)) else {
+                bail!("invalid hex escape on line {}", self.line());
+            };
+            value = value * 16 + d;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn parse_char(&mut self) -> Result<u32> {
+        let Some(c) = self.peek() else {
+            bail!("unexpected end of grammar");
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c.into());
+        }
+        let Some(escaped) = self.peek() else {
+            bail!("unexpected end of grammar");
+        };
+        self.pos += 1;
+        match escaped {
+            'n' => Ok('\n'.into()),
+            'r' => Ok('\r'.into()),
+            't' => Ok('\t'.into()),
+            '\\' | '"' | '[' | ']' | '-' => Ok(escaped.into()),
+            'x' => self.parse_hex(2),
+            'u' => self.parse_hex(4),
+            'U' => self.parse_hex(8),
+            other => bail!("unknown escape `\\{other}` on line {}", self.line()),
+        }
+    }
+
+    /// Check the grammar is complete and that generation under it can always terminate.
+    fn finish(self) -> Result<Grammar> {
+        let Some(root) = self.symbols.get("root").copied() else {
+            bail!("grammar has no `root` rule");
+        };
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (name, rule) in self.names.iter().zip(self.rules) {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => bail!("rule `{name}` is referenced but not defined"),
+            }
+        }
+
+        // Only rules used by `root` matter.
+        let mut reachable = vec![false; rules.len()];
+        let mut todo = vec![root];
+        reachable[root] = true;
+        while let Some(r) = todo.pop() {
+            for elem in rules[r].iter().flatten() {
+                if let Element::Rule(s) = elem {
+                    if !reachable[*s] {
+                        reachable[*s] = true;
+                        todo.push(*s);
+                    }
+                }
+            }
+        }
+
+        let is_char_productive = |elem: &Element| match elem {
+            Element::Char { ranges, negated } => *negated || !ranges.is_empty(),
+            Element::Rule(_) => false,
+        };
+        let nullable = fixpoint(&rules, |_| false);
+        let productive = fixpoint(&rules, is_char_productive);
+
+        // Without left recursion, expanding rules always reaches a character or the end.
+        let mut visiting = vec![0u8; rules.len()];
+        for r in (0..rules.len()).filter(|r| reachable[*r]) {
+            check_left_recursion(r, &rules, &nullable, &mut visiting, &self.names)?;
+        }
+
+        // If every alternative can finish, the end of sequence is reachable from every state.
+        for r in (0..rules.len()).filter(|r| reachable[*r]) {
+            let finishes = |seq: &Vec<Element>| {
+                seq.iter().all(|elem| match elem {
+                    Element::Rule(s) => productive[*s],
+                    elem => is_char_productive(elem),
+                })
+            };
+            if !rules[r].iter().all(finishes) {
+                bail!(
+                    "rule `{}` can never finish, so the end of sequence would be unreachable",
+                    self.names[r]
+                );
+            }
+        }
+
+        Ok(Grammar { rules, root })
+    }
+}
+
+/// Compute which rules derive a string satisfying `char_ok` for each character element.
+fn fixpoint(rules: &[Alternatives], char_ok: impl Fn(&Element) -> bool) -> Vec<bool> {
+    let mut derives = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for r in 0..rules.len() {
+            if derives[r] {
+                continue;
+            }
+            let ok = rules[r].iter().any(|seq| {
+                seq.iter().all(|elem| match elem {
+                    Element::Rule(s) => derives[*s],
+                    elem => char_ok(elem),
+                })
+            });
+            if ok {
+                derives[r] = true;
+                changed = true;
+            }
+        }
+    }
+    derives
+}
+
+fn check_left_recursion(
+    r: usize,
+    rules: &[Alternatives],
+    nullable: &[bool],
+    visiting: &mut [u8],
+    names: &[String],
+) -> Result<()> {
+    match visiting[r] {
+        1 => bail!("rule `{}` is left recursive", names[r]),
+        2 => return Ok(()),
+        _ => {}
+    }
+    visiting[r] = 1;
+    for seq in &rules[r] {
+        for elem in seq {
+            match elem {
+                Element::Rule(s) => {
+                    check_left_recursion(*s, rules, nullable, visiting, names)?;
+                    if !nullable[*s] {
+                        break;
+                    }
+                }
+                Element::Char { .. } => break,
+            }
+        }
+    }
+    visiting[r] = 2;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GbnfParser;
+    use crate::aici::toktree::{Recognizer, SpecialToken};
+
+    const JSON: &str = r#"
+# A JSON object, following llama.cpp's json.gbnf
+root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\bfnrt] | "u" [0-9a-fA-F]{4})
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+    const ISO_DATE: &str = r#"
+root  ::= year "-" month "-" day
+year  ::= [0-9]{4}
+month ::= "0" [1-9] | "1" [0-2]
+day   ::= "0" [1-9] | [12] [0-9] | "3" [01]
+"#;
+
+    const FLOAT: &str = r#"root ::= "-"? [0-9]+ ("." [0-9]+)? ([eE] [-+]? [0-9]+)?"#;
+
+    /// Returns whether `input` is a viable prefix, and whether it is a complete match.
+    fn check(grammar: &str, input: &str) -> (bool, bool) {
+        let mut rec = GbnfParser::parse(grammar).unwrap();
+        if !input.bytes().all(|b| rec.try_push_byte(b)) {
+            return (false, false);
+        }
+        (true, rec.special_allowed(SpecialToken::EndOfSentence))
+    }
+
+    #[test]
+    fn test_gbnf_json_object() {
+        for input in [
+            "{}",
+            r#"{"a": 1}"#,
+            r#"{"name": "café ☕", "tags": [true, null, -2.5e3], "nested": {"x": []}}"#,
+            "{\n  \"a\": \"b\"\n}",
+        ] {
+            assert_eq!(check(JSON, input), (true, true), "{input}");
+        }
+        assert_eq!(check(JSON, r#"{"a": "#), (true, false));
+        assert_eq!(check(JSON, r#"{"a" 1}"#), (false, false));
+        assert_eq!(check(JSON, "[1, 2]"), (false, false));
+        assert_eq!(check(JSON, "{\"a\x01\": 1}"), (false, false));
+    }
+
+    #[test]
+    fn test_gbnf_iso_date() {
+        for input in ["2024-02-29", "1999-12-31", "0001-01-01"] {
+            assert_eq!(check(ISO_DATE, input), (true, true), "{input}");
+        }
+        assert_eq!(check(ISO_DATE, "2024-1"), (true, false));
+        for input in [
+            "2024-13-01",
+            "2024-00-10",
+            "2024-1-01",
+            "2024-01-32",
+            "24-01-01",
+        ] {
+            assert!(!check(ISO_DATE, input).1, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_gbnf_float() {
+        for input in ["42", "3.14", "-0.5e-10", "1E+7"] {
+            assert_eq!(check(FLOAT, input), (true, true), "{input}");
+        }
+        assert_eq!(check(FLOAT, "1."), (true, false));
+        assert_eq!(check(FLOAT, "1e"), (true, false));
+        assert_eq!(check(FLOAT, ".5"), (false, false));
+        assert_eq!(check(FLOAT, "1.5.2"), (false, false));
+    }
+
+    #[test]
+    fn test_gbnf_multibyte_characters() {
+        let grammar = r#"root ::= [α-ω]+ "!""#;
+        assert_eq!(check(grammar, "λόγος!"), (false, false));
+        assert_eq!(check(grammar, "λογος!"), (true, true));
+        // Lead bytes are rejected as soon as no code point they start can match.
+        let mut rec = GbnfParser::parse(grammar).unwrap();
+        assert!(!rec.try_push_byte("é".as_bytes()[0]));
+        assert!(rec.try_push_byte("λ".as_bytes()[0]));
+        assert!(!rec.special_allowed(SpecialToken::EndOfSentence));
+    }
+
+    #[test]
+    fn test_gbnf_invalid_grammars() {
+        for (grammar, err) in [
+            (r#"start ::= "a""#, "no `root` rule"),
+            (r#"root ::= item"#, "`item` is referenced but not defined"),
+            (r#"root ::= "a" root"#, "`root` can never finish"),
+            (r#"root ::= "a" | "b" x"#, "`x` is referenced"),
+            (
+                "root ::= \"a\" | \"b\" x\nx ::= \"c\" x",
+                "`x` can never finish",
+            ),
+            (r#"root ::= root "a" | "a""#, "`root` is left recursive"),
+            (r#"root ::= "a"{3,1}"#, "invalid repetition bounds"),
+            (r#"root ::= "a"#, "unterminated literal"),
+            (r#"root ::= * "a""#, "without a preceding item"),
+        ] {
+            let e = GbnfParser::parse(grammar).unwrap_err().to_string();
+            assert!(e.contains(err), "{grammar}: {e}");
+        }
+    }
+}
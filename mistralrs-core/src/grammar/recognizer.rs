@@ -0,0 +1,192 @@
+use crate::aici::toktree::{Recognizer, SpecialToken};
+
+use super::{Element, Grammar};
+
+/// Position within a rule alternative: the element at `elem` is the next one to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    elem: usize,
+}
+
+/// Leading bits of a code point whose UTF-8 encoding is still missing `remaining` bytes.
+#[derive(Debug, Clone, Copy)]
+struct PartialChar {
+    value: u32,
+    remaining: u32,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    /// Every way the input so far can be parsed. The top of each non-empty stack is a character
+    /// element; an empty stack means the input matches `root` completely.
+    stacks: Vec<Vec<Pos>>,
+    partial: Option<PartialChar>,
+}
+
+/// Pushdown automaton recognizing the language of a GBNF grammar, one byte at a time.
+#[derive(Debug, Clone)]
+pub(crate) struct CfgRecognizer {
+    grammar: Grammar,
+    states: Vec<State>,
+}
+
+impl CfgRecognizer {
+    pub(crate) fn new(grammar: Grammar) -> Self {
+        let mut stacks = Vec::new();
+        for alt in 0..grammar.rules[grammar.root].len() {
+            let pos = Pos {
+                rule: grammar.root,
+                alt,
+                elem: 0,
+            };
+            expand(&grammar, vec![pos], &mut stacks);
+        }
+        stacks.sort_unstable();
+        stacks.dedup();
+        Self {
+            grammar,
+            states: vec![State {
+                stacks,
+                partial: None,
+            }],
+        }
+    }
+
+    fn top_elem(&self, pos: &Pos) -> &Element {
+        &self.grammar.rules[pos.rule][pos.alt][pos.elem]
+    }
+
+    /// Stacks after matching the code point `cp` from `stacks`.
+    fn advance(&self, stacks: &[Vec<Pos>], cp: u32) -> Vec<Vec<Pos>> {
+        let mut next = Vec::new();
+        for stack in stacks {
+            let Some(top) = stack.last() else {
+                continue;
+            };
+            if self.top_elem(top).matches(cp) {
+                let mut stack = stack.clone();
+                stack.pop();
+                stack.push(Pos {
+                    elem: top.elem + 1,
+                    ..*top
+                });
+                expand(&self.grammar, stack, &mut next);
+            }
+        }
+        next.sort_unstable();
+        next.dedup();
+        next
+    }
+}
+
+/// Expand rule references at the top of `stack` until it is empty or its top is a character,
+/// pushing every resulting stack into `out`. Finished positions are popped eagerly so right
+/// recursion does not grow the stack.
+fn expand(grammar: &Grammar, mut stack: Vec<Pos>, out: &mut Vec<Vec<Pos>>) {
+    while let Some(top) = stack.last().copied() {
+        let seq = &grammar.rules[top.rule][top.alt];
+        match seq.get(top.elem) {
+            None => {
+                stack.pop();
+            }
+            Some(Element::Char { .. }) => break,
+            Some(Element::Rule(r)) => {
+                stack.pop();
+                if top.elem + 1 < seq.len() {
+                    stack.push(Pos {
+                        elem: top.elem + 1,
+                        ..top
+                    });
+                }
+                for alt in 0..grammar.rules[*r].len() {
+                    let mut next = stack.clone();
+                    next.push(Pos {
+                        rule: *r,
+                        alt,
+                        elem: 0,
+                    });
+                    expand(grammar, next, out);
+                }
+                return;
+            }
+        }
+    }
+    out.push(stack);
+}
+
+impl Recognizer for CfgRecognizer {
+    fn pop_bytes(&mut self, num: usize) {
+        self.states.truncate(self.states.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let top = self.states.pop().unwrap();
+        self.states.clear();
+        self.states.push(top);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let top = self.states.last().unwrap();
+        match tok {
+            SpecialToken::EndOfSentence => {
+                top.partial.is_none() && top.stacks.iter().any(Vec::is_empty)
+            }
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.states.len() == 1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let top = self.states.last().unwrap();
+        let byte = u32::from(byte);
+        let (value, remaining) = match top.partial {
+            None => match byte {
+                0x00..=0x7f => (byte, 0),
+                0xc0..=0xdf => (byte & 0x1f, 1),
+                0xe0..=0xef => (byte & 0x0f, 2),
+                0xf0..=0xf7 => (byte & 0x07, 3),
+                _ => return false,
+            },
+            Some(PartialChar { value, remaining }) => {
+                if byte & 0xc0 != 0x80 {
+                    return false;
+                }
+                (value << 6 | (byte & 0x3f), remaining - 1)
+            }
+        };
+
+        let state = if remaining == 0 {
+            let stacks = self.advance(&top.stacks, value);
+            if stacks.is_empty() {
+                return false;
+            }
+            State {
+                stacks,
+                partial: None,
+            }
+        } else {
+            // Reject as soon as no code point starting with these bytes can match.
+            let lo = value << (6 * remaining);
+            let hi = lo | ((1 << (6 * remaining)) - 1);
+            let viable = top.stacks.iter().any(|stack| {
+                stack
+                    .last()
+                    .is_some_and(|pos| self.top_elem(pos).may_match_range(lo, hi))
+            });
+            if !viable {
+                return false;
+            }
+            State {
+                stacks: top.stacks.clone(),
+                partial: Some(PartialChar { value, remaining }),
+            }
+        };
+        self.states.push(state);
+        true
+    }
+}
@@ -424,6 +424,121 @@ pub struct Llama3RopeConfig {
     pub rope_type: Llama3RopeType,
 }
 
+pub(crate) fn default_yarn_beta_fast() -> f64 {
+    32.
+}
+
+pub(crate) fn default_yarn_beta_slow() -> f64 {
+    1.
+}
+
+/// RoPE scaling to extend the context window. The `max_position_embeddings` of the model is the
+/// extended context length, the model was trained with `max_position_embeddings / factor`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum RopeScalingConfig {
+    /// Position interpolation: all frequencies are divided by `factor`.
+    #[serde(rename = "linear")]
+    Linear { factor: f64 },
+    /// Static NTK-aware scaling: `rope_theta` is multiplied by `factor^(dim / (dim - 2))`. The
+    /// `dynamic` NTK scaling, which depends on the sequence length, is not supported.
+    #[serde(rename = "ntk")]
+    NTK { factor: f64 },
+    /// YaRN: frequencies completing more than `beta_fast` rotations over the original context
+    /// are kept, those completing less than `beta_slow` are interpolated, with a linear ramp in
+    /// between. See <https://arxiv.org/abs/2309.00071>.
+    #[serde(rename = "yarn")]
+    YaRN {
+        factor: f64,
+        #[serde(default = "default_yarn_beta_fast")]
+        beta_fast: f64,
+        #[serde(default = "default_yarn_beta_slow")]
+        beta_slow: f64,
+    },
+}
+
+impl RopeScalingConfig {
+    /// The factor by which the context window is extended.
+    pub fn factor(&self) -> f64 {
+        match self {
+            Self::Linear { factor } | Self::NTK { factor } | Self::YaRN { factor, .. } => *factor,
+        }
+    }
+
+    /// Inverse frequencies of the `head_dim / 2` rotary dimensions with the scaling applied.
+    pub fn inv_freq(
+        &self,
+        rope_theta: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+    ) -> Vec<f32> {
+        let calculate_inv_freq = |theta: f64| {
+            (0..head_dim)
+                .step_by(2)
+                .map(|i| 1. / theta.powf(i as f64 / head_dim as f64))
+                .collect::<Vec<_>>()
+        };
+        let rope_theta = rope_theta as f64;
+        let inv_freq = match self {
+            Self::Linear { factor } => calculate_inv_freq(rope_theta)
+                .into_iter()
+                .map(|freq| freq / factor)
+                .collect(),
+            Self::NTK { factor } => {
+                let dim = head_dim as f64;
+                calculate_inv_freq(rope_theta * factor.powf(dim / (dim - 2.)))
+            }
+            Self::YaRN {
+                factor,
+                beta_fast,
+                beta_slow,
+            } => {
+                let original_max_position_embeddings = max_position_embeddings as f64 / factor;
+                // The dimension which completes `num_rotations` over the original context
+                let correction_dim = |num_rotations: f64| {
+                    head_dim as f64
+                        * (original_max_position_embeddings
+                            / (num_rotations * 2. * std::f64::consts::PI))
+                            .ln()
+                        / (2. * rope_theta.ln())
+                };
+                let low = correction_dim(*beta_fast).floor().max(0.);
+                let mut high = correction_dim(*beta_slow).ceil().min(head_dim as f64 - 1.);
+                if low == high {
+                    high += 0.001;
+                }
+                calculate_inv_freq(rope_theta)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, freq)| {
+                        // 0 keeps the frequency, 1 interpolates it
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        freq / factor * ramp + freq * (1. - ramp)
+                    })
+                    .collect()
+            }
+        };
+        inv_freq.into_iter().map(|freq| freq as f32).collect()
+    }
+
+    /// Scale applied to the cos and sin tables, the YaRN attention factor.
+    pub fn mscale(&self) -> f64 {
+        match self {
+            Self::Linear { .. } | Self::NTK { .. } => 1.,
+            Self::YaRN { factor, .. } if *factor <= 1. => 1.,
+            Self::YaRN { factor, .. } => 0.1 * factor.ln() + 1.,
+        }
+    }
+}
+
+/// The `rope_scaling` of Llama models: the Llama 3 scaling or a generic [`RopeScalingConfig`].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LlamaRopeScalingConfig {
+    Llama3(Llama3RopeConfig),
+    Scaled(RopeScalingConfig),
+}
+
 fn calculate_default_inv_freq(cfg: &llama::Config) -> Vec<f32> {
     let head_dim = cfg.hidden_size / cfg.num_attention_heads;
     (0..head_dim)
@@ -442,10 +557,10 @@ impl Llama3RotaryEmbedding {
     ) -> Result<Self> {
         match &cfg.rope_scaling {
             None
-            | Some(Llama3RopeConfig {
+            | Some(LlamaRopeScalingConfig::Llama3(Llama3RopeConfig {
                 rope_type: Llama3RopeType::Default,
                 ..
-            }) => Ok(Self::Default(RotaryEmbedding::new(
+            })) => Ok(Self::Default(RotaryEmbedding::new(
                 cfg.rope_theta,
                 cfg.hidden_size / cfg.num_attention_heads,
                 cfg.max_position_embeddings,
@@ -453,7 +568,16 @@ impl Llama3RotaryEmbedding {
                 is_gpt_neox,
                 dtype,
             )?)),
-            Some(rope_scaling) => {
+            Some(LlamaRopeScalingConfig::Scaled(rope_scaling)) => Self::new_scaled(
+                dtype,
+                rope_scaling,
+                cfg.rope_theta,
+                cfg.hidden_size / cfg.num_attention_heads,
+                cfg.max_position_embeddings,
+                dev,
+                is_gpt_neox,
+            ),
+            Some(LlamaRopeScalingConfig::Llama3(rope_scaling)) => {
                 let low_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
                     / rope_scaling.low_freq_factor;
                 let high_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
@@ -494,6 +618,34 @@ impl Llama3RotaryEmbedding {
         }
     }
 
+    /// RoPE with the cos and sin tables computed from a [`RopeScalingConfig`].
+    pub fn new_scaled(
+        dtype: DType,
+        rope_scaling: &RopeScalingConfig,
+        rope_theta: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        dev: &Device,
+        is_gpt_neox: bool,
+    ) -> Result<Self> {
+        let inv_freq = rope_scaling.inv_freq(rope_theta, head_dim, max_position_embeddings);
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+
+        let t = Tensor::arange(0u32, max_position_embeddings as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_position_embeddings, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        let mscale = rope_scaling.mscale();
+        let sin = (freqs.sin()? * mscale)?.to_dtype(dtype)?;
+        let cos = (freqs.cos()? * mscale)?.to_dtype(dtype)?;
+        Ok(Self::Llama3 {
+            sin,
+            cos,
+            is_gptx: is_gpt_neox,
+        })
+    }
+
     pub fn new_mllama3(
         dtype: DType,
         cfg: &MLlamaTextConfig,
@@ -1001,4 +1153,91 @@ mod tests {
             )
         }
     }
+
+    #[test]
+    fn rope_scaling_at_twice_the_context() {
+        use candle_core::{DType, Device};
+
+        use crate::layers::{Llama3RotaryEmbedding, LlamaRopeScalingConfig, RopeScalingConfig};
+        use crate::models::llama;
+
+        const ORIGINAL_LEN: usize = 64;
+        const HEAD_DIM: usize = 64;
+        const THETA: f64 = 10_000.;
+
+        let tables = |rope_scaling: RopeScalingConfig| {
+            let cfg = llama::Config {
+                hidden_size: HEAD_DIM * 4,
+                num_attention_heads: 4,
+                rope_theta: THETA as f32,
+                max_position_embeddings: 2 * ORIGINAL_LEN,
+                rope_scaling: Some(LlamaRopeScalingConfig::Scaled(rope_scaling)),
+                ..Default::default()
+            };
+            match Llama3RotaryEmbedding::new_llama3(DType::F32, &cfg, &Device::Cpu, true).unwrap() {
+                Llama3RotaryEmbedding::Llama3 { cos, sin, .. } => {
+                    (cos.to_vec2::<f32>().unwrap(), sin.to_vec2::<f32>().unwrap())
+                }
+                Llama3RotaryEmbedding::Default(_) => panic!("Expected scaled RoPE tables"),
+            }
+        };
+        // Unscaled angle of rotary dimension `i` at position `pos`
+        let angle =
+            |pos: usize, i: usize| pos as f64 / THETA.powf((2 * i) as f64 / HEAD_DIM as f64);
+        let last = HEAD_DIM / 2 - 1;
+        let assert_close = |a: f32, b: f64| assert!((a as f64 - b).abs() < 1e-3, "{a} != {b}");
+
+        // Position `2 * pos` of the interpolated tables is position `pos` of the original ones
+        let (cos, sin) = tables(RopeScalingConfig::Linear { factor: 2. });
+        for pos in 0..ORIGINAL_LEN {
+            for i in 0..HEAD_DIM / 2 {
+                assert_close(cos[2 * pos][i], angle(pos, i).cos());
+                assert_close(sin[2 * pos][i], angle(pos, i).sin());
+            }
+        }
+
+        // NTK keeps the highest frequency and interpolates the lowest one
+        let (cos, sin) = tables(RopeScalingConfig::NTK { factor: 2. });
+        for pos in 0..ORIGINAL_LEN {
+            assert_close(cos[2 * pos][0], angle(2 * pos, 0).cos());
+            assert_close(sin[2 * pos][0], angle(2 * pos, 0).sin());
+            assert_close(cos[2 * pos][last], angle(pos, last).cos());
+            assert_close(sin[2 * pos][last], angle(pos, last).sin());
+        }
+
+        // YaRN does the same, and scales the tables by the attention factor
+        let (cos, sin) = tables(RopeScalingConfig::YaRN {
+            factor: 2.,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        });
+        let mscale = 0.1 * 2f64.ln() + 1.;
+        for i in 0..HEAD_DIM / 2 {
+            assert_close(cos[0][i], mscale);
+        }
+        for pos in 0..ORIGINAL_LEN {
+            assert_close(cos[2 * pos][0], mscale * angle(2 * pos, 0).cos());
+            assert_close(sin[2 * pos][0], mscale * angle(2 * pos, 0).sin());
+            assert_close(cos[2 * pos][last], mscale * angle(pos, last).cos());
+            assert_close(sin[2 * pos][last], mscale * angle(pos, last).sin());
+        }
+    }
+
+    #[test]
+    fn rope_scaling_ntk_is_static() {
+        use crate::layers::RopeScalingConfig;
+
+        let ntk: RopeScalingConfig =
+            serde_json::from_str(r#"{"type": "ntk", "factor": 2.0}"#).unwrap();
+        assert!(matches!(ntk, RopeScalingConfig::NTK { factor } if factor == 2.));
+        assert_eq!(
+            serde_json::to_value(&ntk).unwrap()["type"],
+            serde_json::json!("ntk")
+        );
+        // Dynamic NTK depends on the sequence length, it must not load as static NTK.
+        assert!(
+            serde_json::from_str::<RopeScalingConfig>(r#"{"type": "dynamic", "factor": 2.0}"#)
+                .is_err()
+        );
+    }
 }
@@ -1,10 +1,12 @@
 #![deny(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use candle_core::Device;
+use candle_core::{Device, Tensor};
 use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
 pub use engine::{EngineInstruction, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP};
 pub use lora::Ordering;
+#[cfg(feature = "test-utils")]
+pub use pipeline::test_utils::{MockPipeline, NextToken};
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -30,6 +32,7 @@ mod engine;
 mod lora;
 mod model_loader;
 mod ops;
+mod tensor_parallel;
 pub use model_loader::{get_model_dtype, get_tgt_non_granular_index, LoaderBuilder};
 
 mod model_selected;
@@ -41,6 +44,7 @@ mod cublaslt;
 #[cfg(not(all(feature = "cuda", target_family = "unix")))]
 mod dummy_paged_attention;
 mod gguf;
+mod grammar;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
@@ -67,19 +71,24 @@ mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
-pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+pub use gguf::{
+    read_gguf_metadata, GGUFArchitecture, GgufMetadata, GgufValue, GGUF_MULTI_FILE_DELIMITER,
+};
+pub use layers::RopeScalingConfig;
 pub use mistralrs_quant::IsqType;
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
+    chat_template::ChatTemplate, detect_quantization, parse_isq_value, AdaptiveGamma, AnyMoeLoader,
+    AnyMoePipeline, ContrastiveConfig, ContrastiveLoader, ContrastivePipeline,
     DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
-    DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader,
-    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, IsqOrganization,
-    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader,
-    MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
-    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader,
-    VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    DiffusionSpecificConfig, EmbeddingPooling, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig,
+    GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, HiddenStateLayer,
+    Idefics2Loader, IsqOrganization, KVCacheDtype, LLaVALoader, LLaVANextLoader, LlamaLoader,
+    Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
+    Phi3VLoader, PromptLookupConfig, PromptLookupLoader, QuantizationInfo, Qwen2Loader,
+    SpeculativeConfig, SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource,
+    VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig, VocabMismatchPolicy,
 };
 pub use request::{
     Constraint, ImageGenerationResponseFormat, MessageContent, NormalRequest, Request,
@@ -87,14 +96,19 @@ pub use request::{
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    BeamCandidate, BeamSearchConfig, ContrastiveSearchConfig, CustomLogitsProcessor,
+    DrySamplingParams, MinNewTokens, MirostatConfig, RepetitionPenaltyLogitsProcessor,
+    SamplingParams, StopTokens, TemperatureLogitsProcessor, TopLogprob,
+};
+pub use scheduler::{
+    DefaultSchedulerMethod, DynamicBatchingConfig, MemoryEstimator, SchedulerConfig,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    parse_tool_calls, CalledFunction, Function, Tool, ToolCallResponse, ToolCallType,
+    ToolCallingModel, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
@@ -497,4 +511,27 @@ impl MistralRs {
     pub fn config(&self) -> &MistralRsConfig {
         &self.config
     }
+
+    /// Cancel the request with this id. Its sequences are removed before the next engine step,
+    /// freeing their KV cache, and the request receives a [`Response::ValidationError`].
+    pub fn cancel_request(&self, id: usize) -> anyhow::Result<()> {
+        self.get_sender()?
+            .try_send(Request::Cancel(id))
+            .map_err(|e| anyhow::anyhow!("Failed to send the cancellation: {e}"))
+    }
+
+    /// Merge the LoRA adapters into the base weights of the model. The adapter weights are dropped,
+    /// so the adapters cannot be activated again afterwards.
+    pub fn merge_adapters(&self, adapter_names: &[String]) -> anyhow::Result<()> {
+        let n =
+            get_mut_arcmutex!(self.reboot_state.pipeline).merge_adapters(adapter_names.to_vec())?;
+        tracing::info!("Merged adapters into {n} LoRA layers.");
+        Ok(())
+    }
+
+    /// Dequantized weight of the layer with this checkpoint name, for example
+    /// `model.layers.0.self_attn.q_proj`.
+    pub fn get_layer_weights(&self, layer_name: &str) -> anyhow::Result<Tensor> {
+        get_mut_arcmutex!(self.reboot_state.pipeline).get_layer_weights(layer_name)
+    }
 }
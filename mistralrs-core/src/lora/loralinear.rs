@@ -7,7 +7,7 @@ use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
 
 use super::{
     apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge,
+    BatchAdapters, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge,
 };
 
 pub struct LoraLinear {
@@ -18,6 +18,7 @@ pub struct LoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    batch_adapters: Option<BatchAdapters>,
 }
 
 impl LoraLinear {
@@ -108,6 +109,7 @@ impl LoraLinear {
                 scale_adapters,
                 layer_n,
                 merged: false,
+                batch_adapters: None,
                 adapters,
             })
         } else {
@@ -120,6 +122,7 @@ impl LoraLinear {
                 scale_adapters,
                 layer_n,
                 merged: false,
+                batch_adapters: None,
                 adapters,
             })
         }
@@ -127,7 +130,14 @@ impl LoraLinear {
 }
 
 impl AdapterSwapper for LoraLinear {
-    fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()> {
+        if let Some((name, _)) = adapters
+            .iter()
+            .find(|(name, _)| !self.adapters.contains_key(name))
+        {
+            bail!("Cannot load adapter `{name}`.");
+        }
+        self.batch_adapters = None;
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -137,24 +147,33 @@ impl AdapterSwapper for LoraLinear {
                 a.clear();
                 b.clear();
                 s.clear();
-                for adapter_name in adapter_names {
+                for (adapter_name, weight) in adapters {
                     let Adapter {
                         a: a_w,
                         b: b_w,
                         scale,
-                    } = match self.adapters.get(adapter_name) {
-                        Some(a) => a,
-                        None => bail!("Cannot load adapter `{adapter_name}`."),
-                    };
+                    } = &self.adapters[adapter_name];
                     a.push(a_w.clone());
                     b.push(b_w.clone());
-                    s.push(*scale);
+                    s.push(*scale * *weight);
                 }
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
         }
         Ok(())
     }
+    fn _activate_adapters_per_seq(&mut self, adapters: &[Option<Vec<String>>]) -> Result<()> {
+        self.batch_adapters = if adapters.is_empty() {
+            None
+        } else {
+            BatchAdapters::new(
+                (&self.a_adapters, &self.b_adapters, &self.scale_adapters),
+                &self.adapters,
+                adapters,
+            )?
+        };
+        Ok(())
+    }
     fn can_load(&self) -> bool {
         true
     }
@@ -182,9 +201,16 @@ impl Merge for LoraLinear {
                 w_base_layer = Some(self.get_delta_weight(adapter)?)
             }
         }
-        self.old
+        self.old = self
+            .old
             .add_delta_w(w_base_layer.as_ref().expect("Found no adapters to merge."))?;
         self.merged = true;
+
+        // The adapters are now part of the base weight.
+        self.a_adapters = Either::Left(Vec::new());
+        self.b_adapters = Either::Left(Vec::new());
+        self.scale_adapters.clear();
+        self.adapters.clear();
         Ok(())
     }
 }
@@ -215,6 +241,10 @@ impl LinearLayerLike for LoraLinear {
             return Ok(result);
         }
 
+        if let Some(batch_adapters) = &self.batch_adapters {
+            return batch_adapters.forward(input, result, global_scaling_weight);
+        }
+
         if is_scaling_pass.is_some_and(|x| x == 0.) {
             return Ok(result);
         }
@@ -286,3 +316,140 @@ impl LinearLayerLike for LoraLinear {
         !self.adapters.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use candle_core::{DType, Device, IndexOp, Result, Tensor};
+    use candle_nn::{Linear, VarBuilder};
+
+    use super::LoraLinear;
+    use crate::lora::{AdapterSwapper, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge};
+
+    const IN: usize = 4;
+    const OUT: usize = 3;
+    const RANK: usize = 2;
+
+    fn adapter_vb(seed: f64, dev: &Device) -> Result<VarBuilder<'static>> {
+        let a = (Tensor::arange(0f32, (RANK * IN) as f32, dev)?.reshape((RANK, IN))? * seed)?;
+        let b = (Tensor::arange(0f32, (OUT * RANK) as f32, dev)?.reshape((OUT, RANK))? - seed)?;
+        Ok(VarBuilder::from_tensors(
+            HashMap::from([
+                ("lora_A.weight".to_string(), a),
+                ("lora_B.weight".to_string(), b),
+            ]),
+            DType::F32,
+            dev,
+        ))
+    }
+
+    fn lora_layer(dev: &Device) -> Result<LoraLinear> {
+        let cfg = LoraConfig {
+            rank: RANK,
+            alpha: RANK as f64,
+            dropout: None,
+            target_modules: HashSet::new(),
+        };
+        let base = Linear::new(
+            Tensor::arange(0f32, (OUT * IN) as f32, dev)?.reshape((OUT, IN))?,
+            None,
+        );
+        let preload = Some(HashMap::from([
+            ("a".to_string(), (adapter_vb(0.5, dev)?, cfg.clone())),
+            ("b".to_string(), (adapter_vb(-1.0, dev)?, cfg)),
+        ]));
+        LoraLinear::new(
+            &base,
+            &LoraLinearConfig::new(IN, OUT),
+            &[],
+            &VarBuilder::from_tensors(HashMap::new(), DType::F32, dev),
+            0,
+            &preload,
+        )
+    }
+
+    #[test]
+    fn test_per_seq_adapters() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut layer = lora_layer(&dev)?;
+        let x = Tensor::ones((1, 1, IN), DType::F32, &dev)?;
+        let xs = Tensor::cat(&[&x, &x, &x], 0)?;
+
+        layer.activate_per_seq(&[
+            Some(vec!["a".to_string()]),
+            Some(vec!["b".to_string()]),
+            Some(vec![]),
+        ])?;
+        let batched = layer.lora_forward(&xs, None, 1.0, None)?;
+
+        let row = |i: usize| -> Result<Vec<f32>> { batched.i(i)?.flatten_all()?.to_vec1() };
+        assert_ne!(row(0)?, row(1)?);
+
+        // Each row matches running that sequence alone with its adapters activated.
+        layer.activate(&["a".to_string()])?;
+        let alone_a = layer.lora_forward(&x, None, 1.0, None)?;
+        assert_eq!(row(0)?, alone_a.flatten_all()?.to_vec1::<f32>()?);
+        layer.activate(&["b".to_string()])?;
+        let alone_b = layer.lora_forward(&x, None, 1.0, None)?;
+        assert_eq!(row(1)?, alone_b.flatten_all()?.to_vec1::<f32>()?);
+        layer.activate(&[])?;
+        let base = layer.lora_forward(&x, None, 1.0, None)?;
+        assert_eq!(row(2)?, base.flatten_all()?.to_vec1::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_seq_adapters_default_to_activated() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut layer = lora_layer(&dev)?;
+        let x = Tensor::ones((1, 1, IN), DType::F32, &dev)?;
+        let xs = Tensor::cat(&[&x, &x], 0)?;
+
+        layer.activate(&["a".to_string()])?;
+        let activated = layer.lora_forward(&x, None, 1.0, None)?;
+
+        layer.activate_per_seq(&[None, Some(vec!["b".to_string()])])?;
+        let batched = layer.lora_forward(&xs, None, 1.0, None)?;
+        assert_eq!(
+            batched.i(0)?.flatten_all()?.to_vec1::<f32>()?,
+            activated.flatten_all()?.to_vec1::<f32>()?
+        );
+
+        // A batch of a different size must select adapters again.
+        assert!(layer.lora_forward(&x, None, 1.0, None).is_err());
+        layer.activate_per_seq(&[])?;
+        let reset = layer.lora_forward(&x, None, 1.0, None)?;
+        assert_eq!(
+            reset.flatten_all()?.to_vec1::<f32>()?,
+            activated.flatten_all()?.to_vec1::<f32>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_weighted_adapters() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut layer = lora_layer(&dev)?;
+        layer.activate(&["a".to_string()])?;
+        let delta_a = layer.get_delta_weight(0)?;
+        layer.activate(&["b".to_string()])?;
+        let delta_b = layer.get_delta_weight(0)?;
+
+        layer.activate_weighted(&[("a".to_string(), 0.7), ("b".to_string(), 0.3)])?;
+        let delta = (layer.get_delta_weight(0)? + layer.get_delta_weight(1)?)?;
+        let expected = ((delta_a * 0.7)? + (delta_b * 0.3)?)?;
+        let diff = (delta - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-4);
+
+        // Invalid adapters are rejected without changing the activated ones.
+        assert!(layer
+            .activate_weighted(&[("a".to_string(), f64::NAN)])
+            .is_err());
+        assert!(layer
+            .activate_weighted(&[("a".to_string(), 1.0), ("c".to_string(), 1.0)])
+            .is_err());
+        assert_eq!(layer.scale_adapters.len(), 2);
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
 #![allow(clippy::cast_precision_loss)]
 
-use std::{collections::HashSet, fmt::Debug, sync::Arc};
+use std::{collections::HashSet, fmt::Debug, iter::zip, ops::Mul, sync::Arc};
 
 use candle_core::{quantized::QTensor, DType, IndexOp, Result, Tensor, D};
 use candle_nn::{init, Linear, Module, VarBuilder};
+use either::Either;
 use loralinear::LoraLinear;
 use mistralrs_quant::QuantMethod;
 pub use qloralinear::QLoraLinear;
@@ -63,13 +64,107 @@ fn apply_scalings_to_x(x: Tensor, scalings_layer: &Tensor, adapter: usize) -> Re
     Ok(res)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Adapter {
     a: Linear,
     b: Linear,
     scale: f64,
 }
 
+/// Adapters selected per sequence of a batch, see [`AdapterSwapper::activate_per_seq`].
+#[derive(Debug)]
+struct BatchAdapters {
+    adapters: Vec<Adapter>,
+    /// (bs, 1, n_adapters): 1 where the sequence uses the adapter, otherwise 0.
+    mask: Tensor,
+}
+
+/// The activated adapters of a layer, as given by its `a_adapters`, `b_adapters` and `scale_adapters`.
+type ActiveAdapters<'a> = (
+    &'a Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
+    &'a Either<Vec<Linear>, (Tensor, Vec<Linear>)>,
+    &'a [f64],
+);
+
+impl BatchAdapters {
+    /// Sequences which did not select any adapters use the activated adapters. Returns `None` if no
+    /// sequence uses any adapter.
+    fn new(
+        (a, b, scales): ActiveAdapters<'_>,
+        available: &HashMap<String, Adapter>,
+        per_seq: &[Option<Vec<String>>],
+    ) -> Result<Option<Self>> {
+        let a = a.as_ref().map_right(|(_, a)| a).into_inner();
+        let b = b.as_ref().map_right(|(_, b)| b).into_inner();
+        let mut adapters = zip(a, zip(b, scales))
+            .map(|(a, (b, scale))| Adapter {
+                a: a.clone(),
+                b: b.clone(),
+                scale: *scale,
+            })
+            .collect::<Vec<_>>();
+        let n_active = adapters.len();
+        let mut names: Vec<&String> = Vec::new();
+        for name in per_seq.iter().flatten().flatten() {
+            if names.contains(&name) {
+                continue;
+            }
+            match available.get(name) {
+                Some(adapter) => adapters.push(adapter.clone()),
+                None => candle_core::bail!("Cannot load adapter `{name}`."),
+            }
+            names.push(name);
+        }
+        let Some(first) = adapters.first() else {
+            return Ok(None);
+        };
+
+        let mut mask = vec![0f32; per_seq.len() * adapters.len()];
+        for (row, seq_adapters) in mask.chunks_mut(adapters.len()).zip(per_seq) {
+            match seq_adapters {
+                Some(seq_adapters) => {
+                    for (i, name) in names.iter().enumerate() {
+                        if seq_adapters.contains(*name) {
+                            row[n_active + i] = 1.;
+                        }
+                    }
+                }
+                None => row[..n_active].fill(1.),
+            }
+        }
+        let weight = first.a.weight();
+        let mask = Tensor::from_vec(mask, (per_seq.len(), 1, adapters.len()), weight.device())?
+            .to_dtype(weight.dtype())?;
+        Ok(Some(Self { adapters, mask }))
+    }
+
+    fn forward(
+        &self,
+        input: &Tensor,
+        mut result: Tensor,
+        global_scaling_weight: f64,
+    ) -> Result<Tensor> {
+        let (bs, _, _) = input.dims3()?;
+        if bs != self.mask.dim(0)? {
+            candle_core::bail!(
+                "Adapters were selected for {} sequences, but the batch has {bs}.",
+                self.mask.dim(0)?
+            );
+        }
+        for (i, adapter) in self.adapters.iter().enumerate() {
+            let input_new = input.to_dtype(adapter.a.weight().dtype())?;
+            let input_new = apply_scalings_to_x(input_new, &self.mask, i)?;
+            let res = adapter
+                .b
+                .forward(&adapter.a.forward(&input_new)?)?
+                .mul(adapter.scale)?
+                .mul(global_scaling_weight)?;
+            result = (result + res)?;
+        }
+        Ok(result)
+    }
+}
+
 fn make_adapter(
     a_vb: VarBuilder,
     b_vb: VarBuilder,
@@ -94,6 +189,14 @@ fn make_adapter(
     Ok(Adapter { a, b, scale })
 }
 
+/// Give each adapter a weight of 1.
+pub(crate) fn unweighted(adapter_names: &[String]) -> Vec<(String, f64)> {
+    adapter_names
+        .iter()
+        .map(|name| (name.clone(), 1.0))
+        .collect()
+}
+
 /// Any layer that is linear-like.
 pub trait LinearLayerLike: Merge + AdapterSwapper {
     fn quantized_act_type(&self) -> Option<DType>;
@@ -108,6 +211,17 @@ pub trait LinearLayerLike: Merge + AdapterSwapper {
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
     ) -> Result<Tensor>;
+    /// Activate the adapters and merge them into the base weight. The adapter weights are dropped
+    /// afterwards. Returns 1 if this layer has adapters, otherwise 0.
+    fn merge_adapters(&mut self, adapter_names: &[String]) -> Result<usize> {
+        if self.can_load() {
+            self._activate_adapters(&unweighted(adapter_names))?;
+            self.merge_weights()?;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
 }
 
 pub trait Merge {
@@ -119,14 +233,34 @@ pub trait Merge {
 
 pub trait AdapterSwapper {
     fn activate(&mut self, adapter_names: &[String]) -> Result<usize> {
+        self.activate_weighted(&unweighted(adapter_names))
+    }
+    /// Activate the adapters, scaling the delta of each adapter by its weight. Returns 1 if this
+    /// layer has adapters, otherwise 0.
+    fn activate_weighted(&mut self, adapters: &[(String, f64)]) -> Result<usize> {
+        if let Some((name, weight)) = adapters.iter().find(|(_, w)| !w.is_finite()) {
+            candle_core::bail!("Weight of adapter `{name}` must be finite, got {weight}.");
+        }
         if self.can_load() {
-            self._activate_adapters(adapter_names)?;
+            self._activate_adapters(adapters)?;
             Ok(1)
         } else {
             Ok(0)
         }
     }
-    fn _activate_adapters(&mut self, adapters: &[String]) -> Result<()>;
+    /// Select the adapters of each sequence for the following forward passes: batch row `i` uses
+    /// `adapters[i]`, or the activated adapters if that is `None`. An empty slice returns every row
+    /// to the activated adapters. Returns 1 if this layer has adapters, otherwise 0.
+    fn activate_per_seq(&mut self, adapters: &[Option<Vec<String>>]) -> Result<usize> {
+        if self.can_load() {
+            self._activate_adapters_per_seq(adapters)?;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()>;
+    fn _activate_adapters_per_seq(&mut self, adapters: &[Option<Vec<String>>]) -> Result<()>;
     fn can_load(&self) -> bool;
 }
 
@@ -140,7 +274,10 @@ impl Merge for Linear {
 }
 
 impl AdapterSwapper for Linear {
-    fn _activate_adapters(&mut self, _adapter: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, _adapter: &[(String, f64)]) -> Result<()> {
+        unreachable!()
+    }
+    fn _activate_adapters_per_seq(&mut self, _adapters: &[Option<Vec<String>>]) -> Result<()> {
         unreachable!()
     }
     fn can_load(&self) -> bool {
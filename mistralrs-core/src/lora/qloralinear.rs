@@ -7,7 +7,7 @@ use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig, UnquantLinear}
 
 use super::{
     apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
+    BatchAdapters, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
 };
 
 #[derive(Debug)]
@@ -19,6 +19,7 @@ pub struct QLoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    batch_adapters: Option<BatchAdapters>,
     linear_config: Option<LoraLinearConfig>,
 }
 
@@ -64,6 +65,7 @@ impl QLoraLinear {
                 scale_adapters: vec![],
                 layer_n: usize::MAX,
                 merged: false,
+                batch_adapters: None,
                 adapters: HashMap::default(),
                 linear_config: None,
             });
@@ -155,6 +157,7 @@ impl QLoraLinear {
                 scale_adapters,
                 layer_n: layer,
                 merged: false,
+                batch_adapters: None,
                 adapters,
                 linear_config: Some(linear_config.clone()),
             })
@@ -166,6 +169,7 @@ impl QLoraLinear {
                 scale_adapters,
                 layer_n: layer,
                 merged: false,
+                batch_adapters: None,
                 adapters,
                 linear_config: Some(linear_config.clone()),
             })
@@ -174,7 +178,14 @@ impl QLoraLinear {
 }
 
 impl AdapterSwapper for QLoraLinear {
-    fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+    fn _activate_adapters(&mut self, adapters: &[(String, f64)]) -> Result<()> {
+        if let Some((name, _)) = adapters
+            .iter()
+            .find(|(name, _)| !self.adapters.contains_key(name))
+        {
+            bail!("Cannot load adapter `{name}`.");
+        }
+        self.batch_adapters = None;
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -184,24 +195,33 @@ impl AdapterSwapper for QLoraLinear {
                 a.clear();
                 b.clear();
                 s.clear();
-                for adapter_name in adapter_names {
+                for (adapter_name, weight) in adapters {
                     let Adapter {
                         a: a_w,
                         b: b_w,
                         scale,
-                    } = match self.adapters.get(adapter_name) {
-                        Some(a) => a,
-                        None => bail!("Cannot load adapter `{adapter_name}`."),
-                    };
+                    } = &self.adapters[adapter_name];
                     a.push(a_w.clone());
                     b.push(b_w.clone());
-                    s.push(*scale);
+                    s.push(*scale * *weight);
                 }
             }
             _ => unreachable!("Adapters should not be stacked if new ones are being activated."),
         }
         Ok(())
     }
+    fn _activate_adapters_per_seq(&mut self, adapters: &[Option<Vec<String>>]) -> Result<()> {
+        self.batch_adapters = if adapters.is_empty() {
+            None
+        } else {
+            BatchAdapters::new(
+                (&self.a_adapters, &self.b_adapters, &self.scale_adapters),
+                &self.adapters,
+                adapters,
+            )?
+        };
+        Ok(())
+    }
     fn can_load(&self) -> bool {
         self.linear_config.is_some()
     }
@@ -229,9 +249,16 @@ impl Merge for QLoraLinear {
                 w_base_layer = Some(self.get_delta_weight(adapter)?)
             }
         }
-        self.old
+        self.old = self
+            .old
             .add_delta_w(w_base_layer.as_ref().expect("Found no adapters to merge."))?;
         self.merged = true;
+
+        // The adapters are now part of the base weight.
+        self.a_adapters = Either::Left(Vec::new());
+        self.b_adapters = Either::Left(Vec::new());
+        self.scale_adapters.clear();
+        self.adapters.clear();
         Ok(())
     }
 }
@@ -262,6 +289,10 @@ impl LinearLayerLike for QLoraLinear {
             return Ok(result);
         }
 
+        if let Some(batch_adapters) = &self.batch_adapters {
+            return batch_adapters.forward(input, result, global_scaling_weight);
+        }
+
         if self
             .a_adapters
             .as_ref()
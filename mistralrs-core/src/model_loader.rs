@@ -5,7 +5,10 @@ use std::{
 
 use crate::{
     get_toml_selected_model_dtype,
-    pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
+    pipeline::{
+        GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, KVCacheDtype,
+        NormalSpecificConfig,
+    },
     DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, Loader, ModelDType,
     ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
     VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
@@ -18,6 +21,7 @@ pub struct LoaderBuilder {
     chat_template: Option<String>,
     use_flash_attn: bool,
     prompt_batchsize: Option<NonZeroUsize>,
+    kv_cache_dtype: KVCacheDtype,
 }
 
 impl LoaderBuilder {
@@ -28,6 +32,7 @@ impl LoaderBuilder {
             chat_template: None,
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_dtype: KVCacheDtype::FullPrecision,
         }
     }
 
@@ -47,6 +52,10 @@ impl LoaderBuilder {
         self.prompt_batchsize = prompt_batchsize;
         self
     }
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KVCacheDtype) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
 
     pub fn build(self) -> anyhow::Result<Box<dyn Loader>> {
         loader_from_model_selected(self)
@@ -63,7 +72,8 @@ pub fn get_tgt_non_granular_index(model: &ModelSelected) -> Option<usize> {
         | ModelSelected::LoraGGML { .. }
         | ModelSelected::Toml { .. }
         | ModelSelected::VisionPlain { .. }
-        | ModelSelected::DiffusionPlain { .. } => None,
+        | ModelSelected::DiffusionPlain { .. }
+        | ModelSelected::Inspect { .. } => None,
         ModelSelected::XLora {
             tgt_non_granular_index,
             ..
@@ -92,6 +102,9 @@ pub fn get_model_dtype(model: &ModelSelected) -> anyhow::Result<ModelDType> {
         | ModelSelected::LoraGGML { .. }
         | ModelSelected::XLoraGGUF { .. }
         | ModelSelected::XLoraGGML { .. } => Ok(ModelDType::Auto),
+        ModelSelected::Inspect { .. } => {
+            anyhow::bail!("`inspect` only reads GGUF metadata and does not select a model.")
+        }
         ModelSelected::Toml { file } => {
             let selector: TomlSelector = toml::from_str(
                 &fs::read_to_string(file.clone())
@@ -115,6 +128,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 chat_template: args.chat_template,
                 no_kv_cache: args.no_kv_cache,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
             };
             (selector, args).try_into()?
         }
@@ -131,8 +145,11 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.unwrap_or_default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -157,8 +174,11 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -191,8 +211,11 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -224,6 +247,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -246,6 +270,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -277,6 +302,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -300,6 +326,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -324,6 +351,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -356,6 +384,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -385,7 +414,10 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -402,6 +434,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             DiffusionLoaderBuilder::new(DiffusionSpecificConfig { use_flash_attn }, Some(model_id))
                 .build(arch)
         }
+        ModelSelected::Inspect { .. } => {
+            anyhow::bail!("`inspect` only reads GGUF metadata and does not select a model.")
+        }
     };
     Ok(loader)
 }
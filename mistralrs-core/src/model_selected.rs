@@ -391,4 +391,10 @@ pub enum ModelSelected {
         #[arg(short, long, default_value_t = ModelDType::Auto, value_parser = parse_model_dtype)]
         dtype: ModelDType,
     },
+
+    /// Print the metadata of a local GGUF file and exit, without loading a model
+    Inspect {
+        /// Path to the GGUF file.
+        path: PathBuf,
+    },
 }
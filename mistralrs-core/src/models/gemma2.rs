@@ -17,9 +17,9 @@ use crate::{
     layers::{Activation, CausalMasker, MatMul, RmsNorm, Sdpa},
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits,
+        capture_before_head, capture_layer_output, extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+        Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
 };
@@ -263,6 +263,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -343,6 +344,7 @@ impl Attention {
                     mask,
                     self.sliding_window,
                     false,
+                    kv_cache_dtype,
                 )?;
 
                 Sdpa.run_attention(
@@ -438,6 +440,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -452,6 +455,7 @@ impl DecoderLayer {
                 seqlen_offsets,
                 start_offsets_kernel,
                 kv_cache,
+                kv_cache_dtype,
                 metadata,
                 flash_params,
             )?
@@ -608,6 +612,7 @@ impl Model {
     ) -> Result<Tensor> {
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = self.cache.lock();
         let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
@@ -638,14 +643,17 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
                 flash_params,
             )?;
+            capture_layer_output(i, self.layers.len(), &xs);
         }
         let xs = xs.to_device(&self.device)?;
         let mut xs = xs.apply(&self.norm)?;
+        capture_before_head(&xs);
         if let Some(t) = self.lm_head.quantized_act_type() {
             xs = xs.to_dtype(t)?;
         }
@@ -890,3 +898,107 @@ impl AnyMoeBaseModelMixin for Model {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{Config, Model};
+    use crate::{
+        layers::Activation,
+        models::test_utils::{self, DecoderShapes},
+        paged_attention::AttentionImplementation,
+        pipeline::{text_models_inputs_processor::FlashParams, NormalLoadingMetadata, NormalModel},
+        DeviceMapMetadata,
+    };
+
+    fn config(max_position_embeddings: usize) -> Config {
+        Config {
+            head_dim: 4,
+            hidden_activation: Some(Activation::GeluPytorchTanh),
+            hidden_size: 8,
+            intermediate_size: 12,
+            num_attention_heads: 2,
+            num_hidden_layers: 1,
+            num_key_value_heads: 1,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10000.,
+            vocab_size: 16,
+            sliding_window: 4096,
+            query_pre_attn_scalar: 4,
+            max_position_embeddings,
+            ..Default::default()
+        }
+    }
+
+    /// Random weights with the shapes of a Gemma 2 checkpoint, which ties the LM head to the
+    /// embeddings and has norms around the MLP.
+    fn random_weights(cfg: &Config, dev: &Device) -> Result<HashMap<String, Tensor>> {
+        let mut shapes = DecoderShapes {
+            vocab_size: cfg.vocab_size,
+            hidden_size: cfg.hidden_size,
+            intermediate_size: cfg.intermediate_size,
+            num_hidden_layers: cfg.num_hidden_layers,
+            q_dim: cfg.num_attention_heads * cfg.head_dim,
+            kv_dim: cfg.num_key_value_heads * cfg.head_dim,
+            tie_word_embeddings: true,
+            qkv_bias: false,
+            gated_mlp: true,
+        }
+        .shapes();
+        for layer in 0..cfg.num_hidden_layers {
+            for norm in ["pre_feedforward_layernorm", "post_feedforward_layernorm"] {
+                shapes.push((
+                    format!("model.layers.{layer}.{norm}.weight"),
+                    vec![cfg.hidden_size],
+                ));
+            }
+        }
+        test_utils::random_weights(shapes, dev)
+    }
+
+    #[test]
+    fn test_max_seq_len_from_config() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg = config(8192);
+        let vb = VarBuilder::from_tensors(random_weights(&cfg, &dev)?, DType::F32, &dev);
+        let model = Model::new(
+            &cfg,
+            vb,
+            false,
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy().into_mapper(
+                    cfg.num_hidden_layers,
+                    &dev,
+                    None,
+                )?,
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            AttentionImplementation::Eager,
+        )?;
+        assert_eq!(NormalModel::max_seq_len(&model), 8192);
+
+        // The RoPE tables cover positions past the default of 4096.
+        let offset = 8000;
+        let cumulative_seqlens = Tensor::new(&[0u32, 1], &dev)?;
+        let logits = model.forward(
+            &Tensor::new(&[[1u32]], &dev)?,
+            &[offset],
+            Tensor::new(&[[8000i64]], &dev)?,
+            vec![(0, 1)],
+            None,
+            &FlashParams {
+                max_q: 1,
+                max_k: 1,
+                cumulative_seqlens_q: cumulative_seqlens.clone(),
+                cumulative_seqlens_k: cumulative_seqlens,
+            },
+        )?;
+        assert_eq!(logits.dims(), [1, 1, cfg.vocab_size]);
+        Ok(())
+    }
+}
@@ -14,15 +14,19 @@ use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{CausalMasker, Llama3RopeConfig, Llama3RotaryEmbedding, MatMul, RmsNorm, Sdpa},
+    layers::{CausalMasker, Llama3RotaryEmbedding, LlamaRopeScalingConfig, MatMul, RmsNorm, Sdpa},
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits,
+        capture_before_head, capture_layer_output, extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        IsqModel, NormalLoadingMetadata, NormalModel,
+        IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     serde_default_fn,
+    tensor_parallel::{
+        column_parallel_linear_no_bias, row_parallel_linear_no_bias, TensorParallel,
+        TensorParallelMlp,
+    },
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
 };
 
@@ -40,7 +44,7 @@ pub struct Config {
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
     pub max_position_embeddings: usize,
-    pub rope_scaling: Option<Llama3RopeConfig>,
+    pub rope_scaling: Option<LlamaRopeScalingConfig>,
     pub quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     pub tie_word_embeddings: bool,
@@ -68,8 +72,8 @@ impl CausalSelfAttention {
         attention_mask: &Option<Tensor>,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        block_idx: usize,
-        kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -137,7 +141,7 @@ impl CausalSelfAttention {
             }
             None => {
                 let (k, v) =
-                    crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+                    crate::pipeline::Cache::update_kv_cache(kv_cache, k, v, false, kv_cache_dtype)?;
 
                 Sdpa.run_attention(
                     &q,
@@ -198,13 +202,59 @@ impl CausalSelfAttention {
             &cfg.quantization_config,
             vb.pp("o_proj"),
         )?;
-        Ok(Self {
+        Ok(Self::new(
+            cfg,
+            [q_proj, k_proj, v_proj, o_proj],
+            1,
+            rope,
+            paged_attn,
+        ))
+    }
+
+    /// The attention of each rank of `tp`, with its share of the query and key-value heads. The
+    /// output of each is a partial sum of the output projection. `ropes` holds the rotary
+    /// embedding on the device of each rank.
+    fn load_tensor_parallel(
+        vb: VarBuilder,
+        cfg: &Config,
+        ropes: &[Arc<Llama3RotaryEmbedding>],
+        tp: &TensorParallel,
+    ) -> Result<Vec<Self>> {
+        let size_in = cfg.hidden_size;
+        let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
+        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
+        let q_proj = column_parallel_linear_no_bias(size_in, size_q, vb.pp("q_proj"), tp)?;
+        let k_proj = column_parallel_linear_no_bias(size_in, size_kv, vb.pp("k_proj"), tp)?;
+        let v_proj = column_parallel_linear_no_bias(size_in, size_kv, vb.pp("v_proj"), tp)?;
+        let o_proj = row_parallel_linear_no_bias(size_q, size_in, vb.pp("o_proj"), tp)?;
+        Ok(q_proj
+            .into_iter()
+            .zip(k_proj)
+            .zip(v_proj)
+            .zip(o_proj)
+            .zip(ropes)
+            .map(|((((q, k), v), o), rope)| {
+                Self::new(cfg, [q, k, v, o], tp.world_size(), rope.clone(), None)
+            })
+            .collect())
+    }
+
+    /// `projs` are the query, key, value and output projections of `1 / world_size` of the heads.
+    fn new(
+        cfg: &Config,
+        projs: [Arc<dyn QuantMethod>; 4],
+        world_size: usize,
+        rope: Arc<Llama3RotaryEmbedding>,
+        paged_attn: Option<PagedAttention>,
+    ) -> Self {
+        let [q_proj, k_proj, v_proj, o_proj] = projs;
+        Self {
             q_proj,
             k_proj,
             v_proj,
             o_proj,
-            num_attention_heads: cfg.num_attention_heads,
-            num_key_value_heads: cfg.num_key_value_heads,
+            num_attention_heads: cfg.num_attention_heads / world_size,
+            num_key_value_heads: cfg.num_key_value_heads / world_size,
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             rotary_emb: rope,
             max_seq_len: cfg.max_position_embeddings,
@@ -216,7 +266,7 @@ impl CausalSelfAttention {
                 softmax_scale: 1.0 / ((cfg.hidden_size / cfg.num_attention_heads) as f32).sqrt(),
                 sliding_window: None,
             },
-        })
+        }
     }
 }
 
@@ -257,6 +307,33 @@ impl Mlp {
             params: vec![h_size, i_size],
         })
     }
+
+    /// The MLP of each rank of `tp`, with its share of the intermediate features.
+    fn load_tensor_parallel(
+        vb: VarBuilder,
+        cfg: &Config,
+        tp: &Arc<TensorParallel>,
+    ) -> Result<TensorParallelMlp> {
+        let h_size = cfg.hidden_size;
+        let i_size = cfg.intermediate_size;
+        let c_fc1 = column_parallel_linear_no_bias(h_size, i_size, vb.pp("gate_proj"), tp)?;
+        let c_fc2 = column_parallel_linear_no_bias(h_size, i_size, vb.pp("up_proj"), tp)?;
+        let c_proj = row_parallel_linear_no_bias(i_size, h_size, vb.pp("down_proj"), tp)?;
+        let ranks = c_fc1
+            .into_iter()
+            .zip(c_fc2)
+            .zip(c_proj)
+            .map(|((c_fc1, c_fc2), c_proj)| {
+                Box::new(Self {
+                    c_fc1,
+                    c_fc2,
+                    c_proj,
+                    params: vec![h_size, i_size / tp.world_size()],
+                }) as Box<dyn MlpLayer>
+            })
+            .collect();
+        TensorParallelMlp::new(ranks, tp.clone())
+    }
 }
 
 impl AnyMoeTrainableLayer for Mlp {}
@@ -279,6 +356,9 @@ impl MlpLayer for Mlp {
     fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
         vec![&mut self.c_fc1, &mut self.c_fc2, &mut self.c_proj]
     }
+    fn get_isq_layer_names(&self) -> Option<Vec<&'static str>> {
+        Some(vec!["gate_proj", "up_proj", "down_proj"])
+    }
     fn clone(&self) -> Box<dyn MlpLayer> {
         Box::new(Clone::clone(self))
     }
@@ -318,9 +398,12 @@ impl MlpLayer for Mlp {
 
 struct Block {
     rms_1: RmsNorm,
-    attn: CausalSelfAttention,
+    /// The attention of each tensor parallel rank, or the whole attention without tensor
+    /// parallelism.
+    attn: Vec<CausalSelfAttention>,
     rms_2: RmsNorm,
     mlp: Box<dyn MlpLayer>,
+    tp: Option<Arc<TensorParallel>>,
 }
 
 impl Block {
@@ -333,42 +416,92 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(
-            &x,
-            attention_mask,
-            seqlen_offsets,
-            start_offsets_kernel,
-            block_idx,
-            kv_cache,
-            metadata,
-            flash_params,
-        )? + residual)?;
+        let x = match &self.tp {
+            Some(tp) => {
+                // Each rank attends with its heads and caches their keys and values in the entry
+                // `block_idx * world_size + rank`.
+                let world_size = tp.world_size();
+                let partials = self
+                    .attn
+                    .iter()
+                    .zip(tp.devices())
+                    .enumerate()
+                    .map(|(rank, (attn, dev))| {
+                        let cache = &mut kv_cache[block_idx * world_size + rank];
+                        // Caches restored by the prefix cacher are on the main device.
+                        if let Some((k, v)) = cache {
+                            *k = k.to_device(dev)?;
+                            *v = v.to_device(dev)?;
+                        }
+                        attn.forward(
+                            &x.to_device(dev)?,
+                            &attention_mask
+                                .as_ref()
+                                .map(|mask| mask.to_device(dev))
+                                .transpose()?,
+                            seqlen_offsets,
+                            start_offsets_kernel.to_device(dev)?,
+                            cache,
+                            kv_cache_dtype,
+                            None,
+                            &flash_params.to_device(dev)?,
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                tp.all_reduce(&partials)?
+            }
+            None => self.attn[0].forward(
+                &x,
+                attention_mask,
+                seqlen_offsets,
+                start_offsets_kernel,
+                &mut kv_cache[block_idx],
+                kv_cache_dtype,
+                metadata,
+                flash_params,
+            )?,
+        };
+        let x = (x + residual)?;
         let residual = &x;
         let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
         Ok(x)
     }
 
+    /// `ropes` holds the rotary embedding on the device of each tensor parallel rank, or on the
+    /// device of the layer without tensor parallelism.
     fn load(
         vb: VarBuilder,
         cfg: &Config,
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<Llama3RotaryEmbedding>,
+        ropes: Vec<Arc<Llama3RotaryEmbedding>>,
         paged_attn: Option<PagedAttention>,
     ) -> Result<Self> {
-        let attn = CausalSelfAttention::load(
-            mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
-            cfg,
-            rope,
-            paged_attn,
-        )?;
-        let mlp = Mlp::load(mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq), cfg)?;
+        let tp = mapper.tensor_parallel().cloned();
+        let attn_vb = mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq);
+        let mlp_vb = mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq);
+        let (attn, mlp): (_, Box<dyn MlpLayer>) = match &tp {
+            Some(tp) => (
+                CausalSelfAttention::load_tensor_parallel(attn_vb, cfg, &ropes, tp)?,
+                Box::new(Mlp::load_tensor_parallel(mlp_vb, cfg, tp)?),
+            ),
+            None => (
+                vec![CausalSelfAttention::load(
+                    attn_vb,
+                    cfg,
+                    ropes[0].clone(),
+                    paged_attn,
+                )?],
+                Box::new(Mlp::load(mlp_vb, cfg)?),
+            ),
+        };
         let rms_1 = RmsNorm::new(
             cfg.hidden_size,
             cfg.rms_norm_eps,
@@ -383,7 +516,8 @@ impl Block {
             rms_1,
             attn,
             rms_2,
-            mlp: Box::new(mlp),
+            mlp,
+            tp,
         })
     }
 }
@@ -410,6 +544,7 @@ impl Llama {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut x = self.wte.forward(input_ids)?;
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mut cache = self.kv_cache.lock();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
@@ -418,7 +553,7 @@ impl Llama {
                 .map(|(_, _)| &seqlen_offsets as &dyn PastKvLenCache)
                 .unwrap_or(&*cache as &dyn PastKvLenCache),
             x.dtype(),
-            self.blocks[0].attn.num_attention_heads,
+            self.blocks[0].attn[0].num_attention_heads,
         )?;
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = self.mapper.map(x, block_idx)?;
@@ -429,14 +564,17 @@ impl Llama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[block_idx].clone(), &mut **metadata)),
                 flash_params,
             )?;
+            capture_layer_output(block_idx, self.blocks.len(), &x);
         }
         let x = x.to_device(&self.device)?;
         let mut x = self.ln_f.forward(&x)?;
+        capture_before_head(&x);
         if let Some(t) = self.lm_head.quantized_act_type() {
             x = x.to_dtype(t)?;
         }
@@ -459,6 +597,22 @@ impl Llama {
             );
         }
         let mapper = normal_loading_metadata.mapper;
+        if let Some(tp) = mapper.tensor_parallel() {
+            let n = tp.world_size();
+            if cfg.quantization_config.is_some() || normal_loading_metadata.loading_isq {
+                candle_core::bail!("Tensor parallelism does not support quantized layers.");
+            }
+            if matches!(attention_mechanism, AttentionImplementation::PagedAttention) {
+                candle_core::bail!("Tensor parallelism does not support PagedAttention.");
+            }
+            if cfg.num_attention_heads % n != 0 || cfg.num_key_value_heads % n != 0 {
+                candle_core::bail!(
+                    "Cannot split {} attention heads and {} key-value heads across {n} devices.",
+                    cfg.num_attention_heads,
+                    cfg.num_key_value_heads
+                );
+            }
+        }
 
         let wte = embedding(
             cfg.vocab_size,
@@ -501,6 +655,21 @@ impl Llama {
                 )?),
             );
         }
+        if let Some(tp) = mapper.tensor_parallel() {
+            for device in tp.devices() {
+                if !ropes.contains_key(&device.location()) {
+                    ropes.insert(
+                        device.location(),
+                        Arc::new(Llama3RotaryEmbedding::new_llama3(
+                            vb.dtype(),
+                            cfg,
+                            device,
+                            is_gptx,
+                        )?),
+                    );
+                }
+            }
+        }
         let blocks: Vec<_> =
             NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
                 .into_iter()
@@ -508,10 +677,19 @@ impl Llama {
                     let device = mapper
                         .device_for(i, false)
                         .unwrap_or(&normal_loading_metadata.real_device);
-                    let rotary_emb = ropes
-                        .get(&device.location())
-                        .expect("No RoPE for device location!")
-                        .clone();
+                    let rope_devices = match mapper.tensor_parallel() {
+                        Some(tp) => tp.devices().iter().collect(),
+                        None => vec![device],
+                    };
+                    let rotary_embs = rope_devices
+                        .into_iter()
+                        .map(|device| {
+                            ropes
+                                .get(&device.location())
+                                .expect("No RoPE for device location!")
+                                .clone()
+                        })
+                        .collect();
                     let paged_attn = match &attention_mechanism {
                         AttentionImplementation::Eager => None,
                         AttentionImplementation::PagedAttention => Some(
@@ -533,7 +711,7 @@ impl Llama {
                         &*mapper,
                         i,
                         normal_loading_metadata.loading_isq,
-                        rotary_emb,
+                        rotary_embs,
                         paged_attn,
                     )
                     .expect("Failed to load block.")
@@ -545,7 +723,11 @@ impl Llama {
             blocks,
             ln_f,
             lm_head,
-            kv_cache: crate::pipeline::Cache::new(cfg.num_hidden_layers, false),
+            // One entry per layer and tensor parallel rank.
+            kv_cache: crate::pipeline::Cache::new(
+                cfg.num_hidden_layers * mapper.tensor_parallel().map_or(1, |tp| tp.world_size()),
+                false,
+            ),
             device: normal_loading_metadata.real_device,
             mapper,
             cfg: ModelConfigMetadata {
@@ -570,10 +752,12 @@ impl IsqModel for Llama {
         let mut tensors = Vec::new();
         tensors.push((&mut self.lm_head, None));
         for (i, layer) in self.blocks.iter_mut().enumerate() {
-            tensors.push((&mut layer.attn.q_proj, Some(i)));
-            tensors.push((&mut layer.attn.k_proj, Some(i)));
-            tensors.push((&mut layer.attn.v_proj, Some(i)));
-            tensors.push((&mut layer.attn.o_proj, Some(i)));
+            for attn in &mut layer.attn {
+                tensors.push((&mut attn.q_proj, Some(i)));
+                tensors.push((&mut attn.k_proj, Some(i)));
+                tensors.push((&mut attn.v_proj, Some(i)));
+                tensors.push((&mut attn.o_proj, Some(i)));
+            }
             tensors.extend(
                 layer
                     .mlp
@@ -586,6 +770,20 @@ impl IsqModel for Llama {
         (tensors, &*self.mapper)
     }
 
+    fn get_layer_names(&self) -> Option<Vec<String>> {
+        let mut names = vec!["lm_head".to_string()];
+        for (i, layer) in self.blocks.iter().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            for proj in ["q_proj", "k_proj", "v_proj", "o_proj"] {
+                names.push(format!("{prefix}.self_attn.{proj}"));
+            }
+            for proj in layer.mlp.get_isq_layer_names()? {
+                names.push(format!("{prefix}.mlp.{proj}"));
+            }
+        }
+        Some(names)
+    }
+
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb = UnVarBuilder::new();
 
@@ -650,7 +848,7 @@ impl NormalModel for Llama {
         false
     }
     fn max_seq_len(&self) -> usize {
-        self.blocks[0].attn.max_seq_len
+        self.blocks[0].attn[0].max_seq_len
     }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
@@ -707,6 +905,7 @@ impl AnyMoeBaseModelMixin for Llama {
                                 hidden_size: self.blocks[layer].mlp.get_params()[0],
                                 ..Default::default()
                             },
+                            None,
                         )?));
                     }
                     AnyMoeExpertType::LoraAdapter {
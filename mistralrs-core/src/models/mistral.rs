@@ -15,13 +15,16 @@ use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{Activation, CausalMasker, MatMul, RmsNorm, RotaryEmbedding, Sdpa},
+    layers::{
+        Activation, CausalMasker, Llama3RotaryEmbedding, MatMul, RmsNorm, RopeScalingConfig,
+        RotaryEmbedding, Sdpa,
+    },
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits,
+        capture_before_head, capture_layer_output, extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+        Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
 };
@@ -43,6 +46,7 @@ pub struct Config {
     pub(crate) head_dim: Option<usize>,
     pub(crate) quantization_config: Option<QuantizedConfig>,
     pub(crate) tie_word_embeddings: bool,
+    pub(crate) rope_scaling: Option<RopeScalingConfig>,
 }
 
 impl Config {
@@ -116,6 +120,9 @@ impl MlpLayer for MLP {
     fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
         vec![&mut self.gate_proj, &mut self.up_proj, &mut self.down_proj]
     }
+    fn get_isq_layer_names(&self) -> Option<Vec<&'static str>> {
+        Some(vec!["gate_proj", "up_proj", "down_proj"])
+    }
     fn clone(&self) -> Box<dyn MlpLayer> {
         Box::new(Clone::clone(self))
     }
@@ -162,7 +169,7 @@ struct Attention {
     num_heads: usize,
     num_kv_heads: usize,
     head_dim: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<Llama3RotaryEmbedding>,
     sliding_window: Option<usize>,
     paged_attn: Option<PagedAttention>,
     sdpa_params: SdpaParams,
@@ -170,7 +177,7 @@ struct Attention {
 
 impl Attention {
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<Llama3RotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         paged_attn: Option<PagedAttention>,
@@ -232,6 +239,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -305,6 +313,7 @@ impl Attention {
                     attention_mask,
                     self.sliding_window,
                     false,
+                    kv_cache_dtype,
                 )?;
 
                 Sdpa.run_attention(
@@ -343,7 +352,7 @@ struct DecoderLayer {
 
 impl DecoderLayer {
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<Llama3RotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         mapper: &dyn DeviceMapper,
@@ -384,6 +393,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -395,6 +405,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             metadata,
             flash_params,
         )?;
@@ -471,14 +482,25 @@ impl Model {
                 .unwrap_or(&normal_loading_metadata.real_device);
             ropes.insert(
                 device.location(),
-                Arc::new(RotaryEmbedding::new(
-                    cfg.rope_theta as f32,
-                    head_dim,
-                    cfg.max_position_embeddings,
-                    device,
-                    is_gptx,
-                    vb_m.dtype(),
-                )?),
+                Arc::new(match &cfg.rope_scaling {
+                    Some(rope_scaling) => Llama3RotaryEmbedding::new_scaled(
+                        vb_m.dtype(),
+                        rope_scaling,
+                        cfg.rope_theta as f32,
+                        head_dim,
+                        cfg.max_position_embeddings,
+                        device,
+                        is_gptx,
+                    )?,
+                    None => Llama3RotaryEmbedding::Default(RotaryEmbedding::new(
+                        cfg.rope_theta as f32,
+                        head_dim,
+                        cfg.max_position_embeddings,
+                        device,
+                        is_gptx,
+                        vb_m.dtype(),
+                    )?),
+                }),
             );
         }
 
@@ -597,6 +619,7 @@ impl Model {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut xs = input_embeds;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = self.cache.lock();
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
@@ -619,14 +642,17 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
                 flash_params,
             )?;
+            capture_layer_output(i, self.layers.len(), &xs);
         }
         let xs = xs.to_device(&self.device)?;
         let mut xs = xs.apply(&self.norm)?;
+        capture_before_head(&xs);
         if let Some(t) = self.lm_head.quantized_act_type() {
             xs = xs.to_dtype(t)?;
         }
@@ -660,6 +686,20 @@ impl IsqModel for Model {
         (tensors, &*self.mapper)
     }
 
+    fn get_layer_names(&self) -> Option<Vec<String>> {
+        let mut names = vec!["lm_head".to_string()];
+        for (i, layer) in self.layers.iter().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            for proj in ["q_proj", "k_proj", "v_proj", "o_proj"] {
+                names.push(format!("{prefix}.self_attn.{proj}"));
+            }
+            for proj in layer.mlp.get_isq_layer_names()? {
+                names.push(format!("{prefix}.mlp.{proj}"));
+            }
+        }
+        Some(names)
+    }
+
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb = UnVarBuilder::new();
 
@@ -13,3 +13,5 @@ pub(crate) mod quantized_qwen2;
 pub(crate) mod quantized_starcoder2;
 pub(crate) mod qwen2;
 pub(crate) mod starcoder2;
+#[cfg(test)]
+pub(crate) mod test_utils;
@@ -19,9 +19,9 @@ use crate::{
     ops::NonZeroOp,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits,
+        capture_before_head, capture_layer_output, extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+        Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
@@ -156,6 +156,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -219,6 +220,7 @@ impl Attention {
                     attention_mask,
                     self.sliding_window,
                     true,
+                    kv_cache_dtype,
                 )?;
 
                 Sdpa.run_attention(
@@ -506,6 +508,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -517,6 +520,7 @@ impl DecoderLayer {
             seqlen_offsets,
             position_ids,
             kv_cache,
+            kv_cache_dtype,
             metadata,
             flash_params,
         )?;
@@ -658,6 +662,7 @@ impl Model {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = self.cache.lock();
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
@@ -681,14 +686,17 @@ impl Model {
                 seqlen_offsets,
                 position_ids,
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
                 flash_params,
-            )?
+            )?;
+            capture_layer_output(i, self.layers.len(), &xs);
         }
         let xs = xs.to_device(&self.device)?;
         let mut xs = xs.apply(&self.norm)?;
+        capture_before_head(&xs);
         if let Some(t) = self.lm_head.quantized_act_type() {
             xs = xs.to_dtype(t)?;
         }
@@ -834,3 +842,121 @@ impl NormalModel for Model {
 }
 
 impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{Config, Model};
+    use crate::{
+        layers::Activation,
+        models::test_utils::{self, DecoderShapes},
+        paged_attention::AttentionImplementation,
+        pipeline::{text_models_inputs_processor::FlashParams, NormalLoadingMetadata},
+        DeviceMapMetadata,
+    };
+
+    fn config() -> Config {
+        Config {
+            vocab_size: 16,
+            hidden_act: Activation::Silu,
+            hidden_size: 8,
+            intermediate_size: 12,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 1,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            max_position_embeddings: 32,
+            original_max_position_embeddings: 32,
+            lm_head_bias: true,
+            attention_bias: true,
+            num_local_experts: 4,
+            router_jitter_noise: 0.01,
+            ..Default::default()
+        }
+    }
+
+    /// Random weights with the shapes of a Phi 3.5 MoE checkpoint, which has biases on all
+    /// projections, norms and the LM head, and a sparse MoE block instead of the MLP.
+    fn random_weights(cfg: &Config, dev: &Device) -> Result<HashMap<String, Tensor>> {
+        let (h, i, v) = (cfg.hidden_size, cfg.intermediate_size, cfg.vocab_size);
+        let mut shapes = DecoderShapes {
+            vocab_size: v,
+            hidden_size: h,
+            intermediate_size: i,
+            num_hidden_layers: cfg.num_hidden_layers,
+            q_dim: cfg.num_attention_heads * cfg.head_dim(),
+            kv_dim: cfg.num_key_value_heads * cfg.head_dim(),
+            tie_word_embeddings: false,
+            qkv_bias: true,
+            gated_mlp: false,
+        }
+        .shapes();
+        shapes.push(("model.norm.bias".to_string(), vec![h]));
+        shapes.push(("lm_head.bias".to_string(), vec![v]));
+        for layer in 0..cfg.num_hidden_layers {
+            let prefix = format!("model.layers.{layer}");
+            shapes.push((format!("{prefix}.self_attn.o_proj.bias"), vec![h]));
+            for norm in ["input_layernorm", "post_attention_layernorm"] {
+                shapes.push((format!("{prefix}.{norm}.bias"), vec![h]));
+            }
+            let moe = format!("{prefix}.block_sparse_moe");
+            shapes.push((format!("{moe}.gate.weight"), vec![cfg.num_local_experts, h]));
+            for expert in 0..cfg.num_local_experts {
+                for (w, shape) in [("w1", vec![i, h]), ("w2", vec![h, i]), ("w3", vec![i, h])] {
+                    shapes.push((format!("{moe}.experts.{expert}.{w}.weight"), shape));
+                }
+            }
+        }
+        test_utils::random_weights(shapes, dev)
+    }
+
+    #[test]
+    fn test_forward_random_weights() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg = config();
+        let vb = VarBuilder::from_tensors(random_weights(&cfg, &dev)?, DType::F32, &dev);
+        let model = Model::new(
+            &cfg,
+            vb,
+            false,
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy().into_mapper(
+                    cfg.num_hidden_layers,
+                    &dev,
+                    None,
+                )?,
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            AttentionImplementation::Eager,
+        )?;
+
+        let seq_len = 3;
+        let input_ids = Tensor::new(&[[1u32, 5, 9]], &dev)?;
+        let cumulative_seqlens = Tensor::new(&[0u32, 3], &dev)?;
+        let flash_params = FlashParams {
+            max_q: 3,
+            max_k: 3,
+            cumulative_seqlens_q: cumulative_seqlens.clone(),
+            cumulative_seqlens_k: cumulative_seqlens,
+        };
+        let logits = model.forward(
+            &input_ids,
+            &[0],
+            &[0],
+            vec![(0, seq_len)],
+            None,
+            &flash_params,
+        )?;
+        assert_eq!(logits.dtype(), DType::F32);
+        assert_eq!(logits.dims(), [1, seq_len, cfg.vocab_size]);
+        let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+        assert!(logits.iter().all(|x| x.is_finite()));
+        Ok(())
+    }
+}
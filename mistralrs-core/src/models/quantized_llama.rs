@@ -6,22 +6,26 @@ use std::sync::Arc;
 use candle_core::quantized::ggml_file;
 use candle_core::quantized::QTensor;
 use candle_core::{DType, Device, Result, Tensor};
-use candle_nn::{Embedding, Module, RotaryEmbedding};
+use candle_nn::{Embedding, Module};
 use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
 
 use crate::attention::SdpaParams;
 use crate::device_map::DeviceMapper;
 use crate::gguf::Content;
-use crate::layers::{CausalMasker, MatMul, QRmsNorm, Sdpa};
+use crate::layers::{
+    default_yarn_beta_fast, default_yarn_beta_slow, CausalMasker, Llama3RotaryEmbedding, MatMul,
+    QRmsNorm, RopeScalingConfig, RotaryEmbedding, Sdpa,
+};
 use crate::layers_masker::PastKvLenCache;
 use crate::paged_attention::{AttentionImplementation, PagedAttention};
 use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
-use crate::pipeline::{extract_logits, Cache};
+use crate::pipeline::{extract_logits, Cache, KVCacheDtype};
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::progress::NiceProgressBar;
 use crate::DeviceMapMetadata;
 use crate::Topology;
+use tracing::warn;
 const MAX_SEQ_LEN: u32 = 4096;
 
 struct Mlp {
@@ -129,7 +133,7 @@ struct LayerWeights {
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
-    rotary: Arc<RotaryEmbedding>,
+    rotary: Arc<Llama3RotaryEmbedding>,
     paged_attn: Option<PagedAttention>,
     sdpa_params: SdpaParams,
 }
@@ -142,6 +146,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -197,7 +202,7 @@ impl LayerWeights {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false, kv_cache_dtype)?;
 
                 Sdpa.run_attention(&q, &k, &v, mask, None, &self.sdpa_params)?
             }
@@ -228,7 +233,7 @@ pub struct ModelWeights {
 impl ModelConfig::FromGGML for ModelWeights {
     fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let rotary = RotaryEmbedding::new_partial(
+        let rotary = Llama3RotaryEmbedding::Default(RotaryEmbedding::new_partial(
             10000.,
             head_dim,
             ct.hparams.n_rot as usize,
@@ -236,7 +241,7 @@ impl ModelConfig::FromGGML for ModelWeights {
             &ct.device,
             false,
             DType::F32,
-        )?;
+        )?);
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
         let norm = QRmsNorm::new(ct.remove("norm.weight")?, 1e-5)?;
@@ -336,6 +341,7 @@ pub(crate) struct PropsGGUF {
     pub rms_norm_eps: f32,
     pub max_seq_len: usize,
     pub rope_freq_base: f32,
+    pub rope_scaling: Option<RopeScalingConfig>,
     pub key_length: usize,
     pub value_length: usize,
 }
@@ -376,6 +382,32 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
                 .ok()
                 .unwrap_or(MAX_SEQ_LEN as u64) as usize,
             rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
+            rope_scaling: match c
+                .get_option_value::<String>("rope.scaling.type")?
+                .as_deref()
+            {
+                None | Some("none") => None,
+                Some(scaling_type) => {
+                    let factor = c.get_value::<f32>("rope.scaling.factor")? as f64;
+                    match scaling_type {
+                        "linear" => Some(RopeScalingConfig::Linear { factor }),
+                        "ntk" => Some(RopeScalingConfig::NTK { factor }),
+                        "yarn" => Some(RopeScalingConfig::YaRN {
+                            factor,
+                            beta_fast: c
+                                .get_option_value::<f32>("rope.scaling.yarn_beta_fast")?
+                                .map_or_else(default_yarn_beta_fast, f64::from),
+                            beta_slow: c
+                                .get_option_value::<f32>("rope.scaling.yarn_beta_slow")?
+                                .map_or_else(default_yarn_beta_slow, f64::from),
+                        }),
+                        other => {
+                            warn!("Ignoring unsupported `rope.scaling.type` `{other}`.");
+                            None
+                        }
+                    }
+                }
+            },
             key_length: c
                 .get_value::<u32>("attention.key_length")
                 .ok()
@@ -416,6 +448,7 @@ impl ModelConfig::FromGGUF for ModelWeights {
             rms_norm_eps,
             max_seq_len,
             rope_freq_base,
+            rope_scaling,
             key_length,
             value_length,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
@@ -444,14 +477,25 @@ impl ModelConfig::FromGGUF for ModelWeights {
             let device = mapper.device_for(layer_idx, false).unwrap_or(device);
             ropes.insert(
                 device.location(),
-                Arc::new(RotaryEmbedding::new(
-                    rope_freq_base,
-                    rope_dim,
-                    max_seq_len,
-                    device,
-                    false,
-                    DType::F32,
-                )?),
+                Arc::new(match &rope_scaling {
+                    Some(rope_scaling) => Llama3RotaryEmbedding::new_scaled(
+                        DType::F32,
+                        rope_scaling,
+                        rope_freq_base,
+                        rope_dim,
+                        max_seq_len,
+                        device,
+                        false,
+                    )?,
+                    None => Llama3RotaryEmbedding::Default(RotaryEmbedding::new(
+                        rope_freq_base,
+                        rope_dim,
+                        max_seq_len,
+                        device,
+                        false,
+                        DType::F32,
+                    )?),
+                }),
             );
         }
 
@@ -654,6 +698,7 @@ impl ModelWeights {
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = self.cache.lock();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             x,
@@ -679,6 +724,7 @@ impl ModelWeights {
                 start_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
@@ -17,9 +17,9 @@ use crate::{
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
-        extract_logits,
+        capture_before_head, capture_layer_output, extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+        Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     serde_default_fn,
     utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
@@ -224,6 +224,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -290,7 +291,7 @@ impl Attention {
                 )?
             }
             None => {
-                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+                let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false, kv_cache_dtype)?;
 
                 Sdpa.run_attention(
                     &q,
@@ -369,6 +370,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
@@ -380,6 +382,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             metadata,
             flash_params,
         )?;
@@ -537,6 +540,7 @@ impl Model {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = self.cache.lock();
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
@@ -559,14 +563,17 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 metadata
                     .as_mut()
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
                 flash_params,
-            )?
+            )?;
+            capture_layer_output(i, self.layers.len(), &xs);
         }
         let xs = xs.to_device(&self.device)?;
         let mut xs = xs.apply(&self.norm)?;
+        capture_before_head(&xs);
         if let Some(t) = self.lm_head.quantized_act_type() {
             xs = xs.to_dtype(t)?;
         }
@@ -794,3 +801,98 @@ impl AnyMoeBaseModelMixin for Model {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use candle_nn::VarBuilder;
+
+    use super::{Config, Model};
+    use crate::{
+        layers::Activation,
+        models::test_utils::{self, DecoderShapes},
+        paged_attention::AttentionImplementation,
+        pipeline::{text_models_inputs_processor::FlashParams, NormalLoadingMetadata},
+        DeviceMapMetadata,
+    };
+
+    fn config(tie_word_embeddings: bool) -> Config {
+        Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            intermediate_size: 12,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            max_position_embeddings: 32,
+            sliding_window: 32,
+            rope_theta: 10000.,
+            rms_norm_eps: 1e-6,
+            hidden_act: Activation::Silu,
+            tie_word_embeddings,
+            ..Default::default()
+        }
+    }
+
+    /// Random weights with the shapes of a Qwen 2 checkpoint, which has biases on the QKV
+    /// projections.
+    fn random_weights(cfg: &Config, dev: &Device) -> Result<HashMap<String, Tensor>> {
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let shapes = DecoderShapes {
+            vocab_size: cfg.vocab_size,
+            hidden_size: cfg.hidden_size,
+            intermediate_size: cfg.intermediate_size,
+            num_hidden_layers: cfg.num_hidden_layers,
+            q_dim: cfg.hidden_size,
+            kv_dim: cfg.num_key_value_heads * head_dim,
+            tie_word_embeddings: cfg.tie_word_embeddings,
+            qkv_bias: true,
+            gated_mlp: true,
+        };
+        test_utils::random_weights(shapes.shapes(), dev)
+    }
+
+    #[test]
+    fn test_forward_shape() -> Result<()> {
+        let dev = Device::Cpu;
+        for tie_word_embeddings in [false, true] {
+            let cfg = config(tie_word_embeddings);
+            let vb = VarBuilder::from_tensors(random_weights(&cfg, &dev)?, DType::F32, &dev);
+            let model = Model::new(
+                &cfg,
+                vb,
+                false,
+                NormalLoadingMetadata {
+                    mapper: DeviceMapMetadata::dummy().into_mapper(
+                        cfg.num_hidden_layers,
+                        &dev,
+                        None,
+                    )?,
+                    loading_isq: false,
+                    real_device: dev.clone(),
+                },
+                AttentionImplementation::Eager,
+            )?;
+
+            let seq_len = 5;
+            let cumulative_seqlens = Tensor::new(&[0u32, seq_len as u32], &dev)?;
+            let logits = model.forward(
+                &Tensor::new(&[[1u32, 2, 3, 4, 5]], &dev)?,
+                &[0],
+                Tensor::new(&[[0i64]], &dev)?,
+                vec![(0, seq_len)],
+                None,
+                &FlashParams {
+                    max_q: seq_len as u32,
+                    max_k: seq_len as u32,
+                    cumulative_seqlens_q: cumulative_seqlens.clone(),
+                    cumulative_seqlens_k: cumulative_seqlens,
+                },
+            )?;
+            assert_eq!(logits.dims(), [1, seq_len, cfg.vocab_size]);
+        }
+        Ok(())
+    }
+}
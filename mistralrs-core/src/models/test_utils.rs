@@ -0,0 +1,82 @@
+//! Random weights to build small models in tests.
+
+use std::collections::HashMap;
+
+use candle_core::{Device, Result, Tensor};
+
+/// Parameter names and shapes of a Llama style decoder checkpoint: token embeddings, the final
+/// norm, the LM head unless tied, and per layer the attention projections, a gated MLP and the
+/// input and post attention norms. Models with other parameters extend [`DecoderShapes::shapes`].
+pub(crate) struct DecoderShapes {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    /// Output features of `q_proj`, the input features of `o_proj`.
+    pub(crate) q_dim: usize,
+    /// Output features of `k_proj` and `v_proj`.
+    pub(crate) kv_dim: usize,
+    pub(crate) tie_word_embeddings: bool,
+    /// Add biases to `q_proj`, `k_proj` and `v_proj`.
+    pub(crate) qkv_bias: bool,
+    /// Add the `gate_proj`, `up_proj` and `down_proj` MLP weights.
+    pub(crate) gated_mlp: bool,
+}
+
+impl DecoderShapes {
+    pub(crate) fn shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let (h, i, v) = (self.hidden_size, self.intermediate_size, self.vocab_size);
+        let mut shapes = vec![
+            ("model.embed_tokens.weight".to_string(), vec![v, h]),
+            ("model.norm.weight".to_string(), vec![h]),
+        ];
+        if !self.tie_word_embeddings {
+            shapes.push(("lm_head.weight".to_string(), vec![v, h]));
+        }
+        for layer in 0..self.num_hidden_layers {
+            let prefix = format!("model.layers.{layer}");
+            for (proj, out_dim) in [
+                ("q_proj", self.q_dim),
+                ("k_proj", self.kv_dim),
+                ("v_proj", self.kv_dim),
+            ] {
+                shapes.push((
+                    format!("{prefix}.self_attn.{proj}.weight"),
+                    vec![out_dim, h],
+                ));
+                if self.qkv_bias {
+                    shapes.push((format!("{prefix}.self_attn.{proj}.bias"), vec![out_dim]));
+                }
+            }
+            shapes.push((
+                format!("{prefix}.self_attn.o_proj.weight"),
+                vec![h, self.q_dim],
+            ));
+            if self.gated_mlp {
+                for (name, shape) in [
+                    ("gate_proj", vec![i, h]),
+                    ("up_proj", vec![i, h]),
+                    ("down_proj", vec![h, i]),
+                ] {
+                    shapes.push((format!("{prefix}.mlp.{name}.weight"), shape));
+                }
+            }
+            for norm in ["input_layernorm", "post_attention_layernorm"] {
+                shapes.push((format!("{prefix}.{norm}.weight"), vec![h]));
+            }
+        }
+        shapes
+    }
+}
+
+/// Normally distributed tensors (mean 0, standard deviation 0.5) for each name and shape. Random
+/// rather than zero weights make the tests sensitive to mixed up or transposed parameters.
+pub(crate) fn random_weights(
+    shapes: impl IntoIterator<Item = (String, Vec<usize>)>,
+    dev: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    shapes
+        .into_iter()
+        .map(|(name, shape)| Ok((name, Tensor::randn(0f32, 0.5, shape, dev)?)))
+        .collect()
+}
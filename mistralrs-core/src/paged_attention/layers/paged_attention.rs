@@ -1,4 +1,6 @@
-use candle_core::{Device, Result, Tensor};
+use std::sync::OnceLock;
+
+use candle_core::{DType, Device, Result, Tensor};
 
 use mistralrs_paged_attn::{paged_attention, reshape_and_cache};
 
@@ -6,6 +8,32 @@ use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
 
 const _PARTITION_SIZE: usize = 512;
 
+/// The absolute maximum of the first keys and values written to an FP8 cache is stored as these
+/// values, as in vLLM. The largest finite E4M3 value is 448, which leaves headroom for larger
+/// later tokens.
+const FP8_K_CALIBRATION_MAX: f32 = 200.;
+const FP8_V_CALIBRATION_MAX: f32 = 100.;
+
+/// Per-tensor scale of the keys or values of an FP8 cache, calibrated on the first nonzero `xs`
+/// written to it. Tensors of zeros are stored exactly with any scale, so they use 1 without
+/// calibrating.
+fn fp8_scale(scale: &OnceLock<f32>, xs: &Tensor, calibration_max: f32) -> Result<f32> {
+    if let Some(scale) = scale.get() {
+        return Ok(*scale);
+    }
+    let absmax = xs
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_dtype(DType::F32)?
+        .to_scalar::<f32>()?;
+    if absmax > 0. && absmax.is_finite() {
+        Ok(*scale.get_or_init(|| absmax / calibration_max))
+    } else {
+        Ok(1.)
+    }
+}
+
 #[allow(dead_code)]
 pub struct PagedAttention {
     num_attention_heads: usize,
@@ -15,6 +43,9 @@ pub struct PagedAttention {
     sliding_window: Option<usize>,
     num_queries_per_kv: usize,
     alibi_slopes: Option<Tensor>,
+    /// Scales of the keys and values of this layer in an FP8 cache, see [`fp8_scale`].
+    k_scale: OnceLock<f32>,
+    v_scale: OnceLock<f32>,
 }
 
 impl PagedAttention {
@@ -42,6 +73,8 @@ impl PagedAttention {
             sliding_window,
             num_queries_per_kv,
             alibi_slopes,
+            k_scale: OnceLock::new(),
+            v_scale: OnceLock::new(),
         })
     }
 
@@ -140,6 +173,16 @@ impl PagedAttention {
         // key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x] 48,32,16,16,8
         // value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size] 48,32,128,16
         // slot_mapping: Tensor,     // [num_tokens]
+        // An FP8 cache (see `KVCacheDtype::paged_cache_dtype`) is quantized here with the scales of
+        // the layer, and dequantized by `paged_attention`. The scales are ignored by caches of the
+        // model dtype. TODO: INT8 KV cache, which needs per-block scales.
+        let (k_scale, v_scale) = match &key_cache {
+            Some(key_cache) if key_cache.dtype() == DType::F8E4M3 => (
+                fp8_scale(&self.k_scale, &key, FP8_K_CALIBRATION_MAX)?,
+                fp8_scale(&self.v_scale, &value, FP8_V_CALIBRATION_MAX)?,
+            ),
+            _ => (1., 1.),
+        };
         if key_cache.as_ref().is_some_and(|_| value_cache.is_some()) {
             reshape_and_cache(
                 &key,
@@ -147,6 +190,8 @@ impl PagedAttention {
                 key_cache.as_mut().unwrap(),
                 value_cache.as_mut().unwrap(),
                 &slot_mapping,
+                k_scale,
+                v_scale,
             )?;
         }
 
@@ -178,6 +223,32 @@ impl PagedAttention {
             input_metadata.max_context_len.unwrap(),
             self.scale,
             softcapping.unwrap_or(1.0f64) as f32,
+            k_scale,
+            v_scale,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use candle_core::{Device, Tensor};
+
+    use super::fp8_scale;
+
+    #[test]
+    fn fp8_scale_is_calibrated_once_on_nonzero_values() -> candle_core::Result<()> {
+        let scale = OnceLock::new();
+        let zeros = Tensor::zeros((2, 4), candle_core::DType::F32, &Device::Cpu)?;
+        assert_eq!(fp8_scale(&scale, &zeros, 200.)?, 1.);
+        assert!(scale.get().is_none());
+
+        let xs = Tensor::new(&[[1f32, -800.], [3., 4.]], &Device::Cpu)?;
+        assert_eq!(fp8_scale(&scale, &xs, 200.)?, 4.);
+        // Later tokens are stored with the calibrated scale.
+        let larger = (xs * 10.)?;
+        assert_eq!(fp8_scale(&scale, &larger, 200.)?, 4.);
+        Ok(())
+    }
+}
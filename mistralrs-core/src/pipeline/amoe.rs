@@ -18,10 +18,13 @@ use rand_isaac::Isaac64Rng;
 use tracing::{info, warn};
 
 use crate::{
-    amoe::{AnyMoeConfig, AnyMoeTrainingInputRow, AnyMoeTrainingInputs, AnyMoeTrainingResult},
+    amoe::{
+        load_checkpoint, save_checkpoint, AnyMoeCheckpointMetadata, AnyMoeConfig,
+        AnyMoeTrainingInputRow, AnyMoeTrainingInputs, AnyMoeTrainingResult, EarlyStopping,
+    },
     get_mut_arcmutex,
     prefix_cacher::PrefixCacheManager,
-    sampler::Sampler,
+    sampler::{Sampler, SamplerConfig},
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
     DeviceMapMetadata, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig,
@@ -30,7 +33,7 @@ use crate::{
 
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
-    IsqPipelineMixin, MetadataMixin, PreProcessingMixin,
+    HiddenStateLayer, IsqPipelineMixin, MetadataMixin, PreProcessingMixin,
 };
 
 pub struct AnyMoeLoader {
@@ -167,8 +170,16 @@ impl AnyMoePipeline {
             layers,
             silent,
         )? {
-            Some(AnyMoeTrainingResult { steps, final_loss }) => {
-                info!("Finished training in {steps} steps. Final losses per layer: {final_loss:?}")
+            Some(AnyMoeTrainingResult {
+                steps,
+                final_loss,
+                validation_loss,
+                best_epoch,
+            }) => {
+                info!("Finished training in {steps} steps. Final losses per layer: {final_loss:?}");
+                if let (Some(validation_loss), Some(best_epoch)) = (validation_loss, best_epoch) {
+                    info!("Best validation losses per layer, at epoch {best_epoch}: {validation_loss:?}");
+                }
             }
             None => {
                 info!("Not training gating layer, using trained gating layer specified in config")
@@ -179,8 +190,14 @@ impl AnyMoePipeline {
 }
 
 impl AdapterActivationMixin for AnyMoePipeline {
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
-        get_mut_arcmutex!(self.target).activate_adapters(adapters)
+    fn activate_weighted_adapters(
+        &mut self,
+        adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).activate_weighted_adapters(adapters)
+    }
+    fn merge_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).merge_adapters(adapters)
     }
 }
 
@@ -203,6 +220,10 @@ impl IsqPipelineMixin for AnyMoePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)
     }
+
+    fn get_layer_weights(&mut self, name: &str) -> anyhow::Result<Tensor> {
+        get_mut_arcmutex!(self.target).get_layer_weights(name)
+    }
 }
 
 impl PreProcessingMixin for AnyMoePipeline {
@@ -260,6 +281,14 @@ impl Pipeline for AnyMoePipeline {
     fn category(&self) -> ModelCategory {
         get_mut_arcmutex!(self.target).category()
     }
+
+    fn supports_beam_search(&self) -> bool {
+        get_mut_arcmutex!(self.target).supports_beam_search()
+    }
+
+    fn get_hidden_states(&self, input: &[u32], layer: HiddenStateLayer) -> anyhow::Result<Tensor> {
+        get_mut_arcmutex!(self.target).get_hidden_states(input, layer)
+    }
 }
 
 impl AnyMoePipelineMixin for AnyMoePipeline {
@@ -287,7 +316,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
         let input_processor_cfg = target.get_input_processor_config().clone();
 
         let AnyMoeConfig {
-            hidden_size: _,
+            hidden_size,
             lr,
             epochs,
             batch_size,
@@ -295,10 +324,25 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             gate_model_id,
             training,
             loss_csv_path,
+            checkpoint_dir,
+            resume_from_checkpoint,
+            gradient_checkpointing,
+            validation_split,
+            early_stopping_patience,
         } = self.config.clone();
         let mut steps = 0;
 
+        if resume_from_checkpoint && checkpoint_dir.is_none() {
+            candle_core::bail!("`resume_from_checkpoint` requires a `checkpoint_dir`.");
+        }
+        if early_stopping_patience.is_some() && validation_split == 0.0 {
+            candle_core::bail!("`early_stopping_patience` requires a `validation_split`.");
+        }
+
         info!("Expert type: {expert_type:?}");
+        if gradient_checkpointing {
+            info!("Gradient checkpointing of the gating layers is enabled.");
+        }
         info!("Expert model ids: {model_ids:?}");
 
         // Inject the AnyMoE layers
@@ -321,6 +365,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             },
         )?;
         let layer_vars = target.amoe_layer_vars();
+        let mut var_map = target.amoe_var_map();
 
         // If there are no trainable params, assume we got a gate model id so no training
         if target.amoe_base_model_trainable_params() == 0 {
@@ -350,22 +395,26 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             .collect::<candle_core::Result<Vec<_>>>()?;
 
         let mut rng = thread_rng();
+        let (inputs, validation) = inputs
+            .split(validation_split, &mut rng)
+            .map_err(candle_core::Error::msg)?;
         let mut samples = inputs.into_inner();
+        let validation = validation.into_inner();
+        if !validation.is_empty() {
+            info!(
+                "Holding out {} samples for validation, training on {}.",
+                validation.len(),
+                samples.len()
+            );
+        }
+        let mut early_stopping = EarlyStopping::new(early_stopping_patience);
 
         // Create several dummy objects for the sequences. No custom logits processors.
         let (dummy_sender, _) = tokio::sync::mpsc::channel(10000);
-        let dummy_sampler = Sampler::new(
-            None,
-            0,
-            tokenizer.clone(),
-            None,
-            None,
-            None,
-            -1,
-            0.0,
-            0.0,
-            vec![],
-        )
+        let dummy_sampler = Sampler::new(SamplerConfig {
+            tokenizer: tokenizer.clone(),
+            ..Default::default()
+        })
         .map_err(candle_core::Error::msg)?;
 
         let dummy_group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
@@ -378,10 +427,33 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
         let mut latest_loss = vec![0.0; optimizers.len()];
         let mut all_losses = Vec::new();
 
-        for _ in NiceProgressBar::<_, 'g'>(0..epochs, "Training gating layers") {
+        let mut start_epoch = 0;
+        if resume_from_checkpoint {
+            let checkpoint_dir = checkpoint_dir.as_ref().unwrap();
+            if let Some(metadata) = load_checkpoint(checkpoint_dir, &mut var_map, hidden_size)? {
+                start_epoch = metadata.epochs.min(epochs);
+                latest_loss = metadata.final_loss;
+                if start_epoch == epochs {
+                    info!("Checkpoint has completed all {epochs} epochs, skipping training.");
+                } else {
+                    info!("Resuming training at epoch {start_epoch} of {epochs}.");
+                }
+            }
+        }
+
+        for epoch in NiceProgressBar::<_, 'g'>(start_epoch..epochs, "Training gating layers") {
             samples.as_mut_slice().shuffle(&mut rng);
-            for batch in samples.chunks(batch_size) {
-                steps += 1;
+            // The gating outputs and labels of the validation batches, run after the training batches.
+            let mut validation_outputs = vec![Vec::new(); optimizers.len()];
+            let mut validation_labels = Vec::new();
+            let batches = samples
+                .chunks(batch_size)
+                .map(|batch| (batch, false))
+                .chain(validation.chunks(batch_size).map(|batch| (batch, true)));
+            for (batch, is_validation) in batches {
+                if !is_validation {
+                    steps += 1;
+                }
 
                 // === PREPARE INPUTS ==
                 let mut seqs = Vec::new();
@@ -484,6 +556,13 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                 )?;
 
                 let cached = target.amoe_take_cached_gating_outputs();
+                if is_validation {
+                    for (outputs, output) in validation_outputs.iter_mut().zip(cached) {
+                        outputs.push(output.detach());
+                    }
+                    validation_labels.push(labels);
+                    continue;
+                }
                 for (layer, (optimizer, output)) in optimizers.iter_mut().zip(cached).enumerate() {
                     let loss = candle_nn::loss::cross_entropy(
                         &output,
@@ -495,6 +574,46 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                 }
                 all_losses.push(latest_loss.clone());
             }
+
+            if let Some(checkpoint_dir) = &checkpoint_dir {
+                let metadata = AnyMoeCheckpointMetadata::new(
+                    &var_map,
+                    hidden_size,
+                    epoch + 1,
+                    latest_loss.clone(),
+                )?;
+                save_checkpoint(checkpoint_dir, &var_map, &metadata)?;
+            }
+
+            if !validation.is_empty() {
+                let labels = Tensor::cat(&validation_labels, 0)?;
+                let validation_loss = validation_outputs
+                    .iter()
+                    .map(|outputs| {
+                        let output = Tensor::cat(outputs, 0)?;
+                        candle_nn::loss::cross_entropy(
+                            &output,
+                            &labels.to_device(output.device())?,
+                        )?
+                        .to_dtype(DType::F32)?
+                        .to_scalar::<f32>()
+                    })
+                    .collect::<candle_core::Result<Vec<_>>>()?;
+                info!("Epoch {epoch} validation losses per layer: {validation_loss:?}");
+                if early_stopping.update(epoch, validation_loss, latest_loss.clone(), &var_map)? {
+                    info!(
+                        "Validation loss has not improved for {} epochs, stopping early.",
+                        early_stopping_patience.unwrap_or_default()
+                    );
+                    break;
+                }
+            }
+        }
+
+        // Keep the gating layers of the epoch with the lowest validation loss.
+        let best = early_stopping.restore_best(&var_map)?;
+        if let Some(best) = &best {
+            latest_loss.clone_from(&best.training_loss);
         }
 
         target.amoe_finish_training(gate_model_id)?;
@@ -512,7 +631,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             let mut writer = csv::Writer::from_path(path).map_err(candle_core::Error::msg)?;
 
             let mut header = vec![format!("Step")];
-            header.extend((0..all_losses[0].len()).map(|i| format!("Gating layer {i}")));
+            header.extend((0..latest_loss.len()).map(|i| format!("Gating layer {i}")));
             writer
                 .write_record(&header)
                 .map_err(candle_core::Error::msg)?;
@@ -531,13 +650,15 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
         Ok(Some(AnyMoeTrainingResult {
             steps,
             final_loss: latest_loss,
+            validation_loss: best.as_ref().map(|best| best.validation_loss.clone()),
+            best_epoch: best.map(|best| best.epoch),
         }))
     }
 }
 
 /// Create a dummy sequence containing just the prompt. This is OK because we just want a sequence that
 /// has no information other than the input tokens (and maybe images).
-fn new_dummy_seq(
+pub(crate) fn new_dummy_seq(
     (tokens, prompt): (Vec<u32>, String),
     dummy_sender: tokio::sync::mpsc::Sender<Response>,
     dummy_sampler: Sampler,
@@ -555,6 +676,7 @@ fn new_dummy_seq(
         vec![],
         vec![],
         None,
+        None,
         false,
         false,
         dummy_group,
@@ -571,5 +693,6 @@ fn new_dummy_seq(
         None,
         SeqStepType::PromptAndDecode,
         None,
+        None,
     )
 }
@@ -1,6 +1,11 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
-use candle_core::{Tensor, D};
+use candle_core::{DType, Tensor, D};
+use tracing::{info, warn};
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
@@ -19,12 +24,194 @@ pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
 
 pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
 
+/// Data type the (non-paged) KV cache is stored in.
+///
+/// It is selected in the loader config and recorded in the pipeline metadata, from which it is
+/// passed to the model [`Cache`]. Quantized caches are written by [`Cache::update_kv_cache`] and
+/// dequantized to the activation dtype on read, so the attention computation itself is unchanged.
+/// The whole cache of a layer is dequantized at every step, which costs a temporary copy of it in
+/// the activation dtype and time growing with the context length.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KVCacheDtype {
+    /// Keys and values are kept in the model's activation dtype.
+    #[default]
+    FullPrecision,
+    /// Symmetric INT8. Each block written to the cache is quantized with one power-of-two scale,
+    /// stored as a biased exponent in an extra trailing column of the block.
+    Int8,
+    /// FP8 (E4M3). Each block written to the cache is scaled by one power-of-two scale, so that it
+    /// fits in the E4M3 range, stored as an FP8 value in an extra trailing column of the block.
+    Fp8,
+}
+
+impl KVCacheDtype {
+    /// The KV cache dtype a pipeline is loaded with. The PagedAttention kernels support FP8 cache
+    /// blocks, see [`KVCacheDtype::paged_cache_dtype`], but not INT8 ones.
+    pub(crate) fn for_pipeline(self, paged_attn: bool) -> Self {
+        if paged_attn && self == Self::Int8 {
+            warn!("KV cache dtype `{self}` is not supported with PagedAttention, using full precision.");
+            return Self::FullPrecision;
+        }
+        if self != Self::FullPrecision {
+            info!("Storing the KV cache as `{self}`.");
+        }
+        self
+    }
+
+    /// Dtype of the PagedAttention cache blocks of a model running in `dtype`. FP8 blocks are
+    /// quantized as they are written and dequantized by the attention kernels.
+    pub(crate) fn paged_cache_dtype(self, dtype: DType) -> DType {
+        match self {
+            Self::Fp8 => DType::F8E4M3,
+            Self::FullPrecision | Self::Int8 => dtype,
+        }
+    }
+
+    /// Largest magnitude of a quantized INT8 value.
+    const INT8_MAX: f64 = 127.;
+    /// Offset making the signed INT8 values fit in a `u8`.
+    const INT8_ZERO: f64 = 128.;
+    /// Bias of the stored power-of-two exponent.
+    const EXPONENT_BIAS: f64 = 127.;
+    /// Largest finite F8E4M3 value.
+    const FP8_MAX: f64 = 448.;
+    /// Exponent range of the powers of two F8E4M3 represents exactly, subnormals included.
+    const FP8_MIN_EXPONENT: f64 = -9.;
+    const FP8_MAX_EXPONENT: f64 = 8.;
+
+    fn of_cache(t: &Tensor) -> Self {
+        match t.dtype() {
+            DType::U8 => Self::Int8,
+            DType::F8E4M3 => Self::Fp8,
+            _ => Self::FullPrecision,
+        }
+    }
+
+    /// Exponent `e` of the scale `2^e = 2^ceil(log2(absmax / max))` of `xs`, so that every value
+    /// divided by the scale fits in `[-max, max]`. It is clamped to `[min_exponent, max_exponent]`.
+    fn scale_exponent(
+        xs: &Tensor,
+        max: f64,
+        min_exponent: f64,
+        max_exponent: f64,
+    ) -> candle_core::Result<Tensor> {
+        let absmax = xs.abs()?.flatten_all()?.max_keepdim(0)?;
+        (absmax / max)?
+            .log()?
+            .affine(1. / std::f64::consts::LN_2, 0.)?
+            .ceil()?
+            .clamp(min_exponent, max_exponent)
+    }
+
+    /// Quantize a `(bs, heads, seq_len, head_dim)` block of keys or values.
+    fn quantize(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::FullPrecision => Ok(xs.clone()),
+            Self::Fp8 => {
+                let xs = xs.to_dtype(DType::F32)?;
+                let exponent = Self::scale_exponent(
+                    &xs,
+                    Self::FP8_MAX,
+                    Self::FP8_MIN_EXPONENT,
+                    Self::FP8_MAX_EXPONENT,
+                )?;
+                let inv_scale = exponent.affine(-std::f64::consts::LN_2, 0.)?.exp()?;
+                let quantized = xs
+                    .broadcast_mul(&inv_scale)?
+                    .clamp(-Self::FP8_MAX, Self::FP8_MAX)?;
+                let (bs, heads, seq_len, _) = xs.dims4()?;
+                let scale = exponent
+                    .affine(std::f64::consts::LN_2, 0.)?
+                    .exp()?
+                    .reshape((1, 1, 1, 1))?
+                    .broadcast_as((bs, heads, seq_len, 1))?;
+                Tensor::cat(&[quantized, scale], D::Minus1)?.to_dtype(DType::F8E4M3)
+            }
+            Self::Int8 => {
+                let xs = xs.to_dtype(DType::F32)?;
+                let exponent = Self::scale_exponent(
+                    &xs,
+                    Self::INT8_MAX,
+                    -Self::EXPONENT_BIAS,
+                    Self::EXPONENT_BIAS,
+                )?;
+                let inv_scale = exponent.affine(-std::f64::consts::LN_2, 0.)?.exp()?;
+                let quantized = xs
+                    .broadcast_mul(&inv_scale)?
+                    .round()?
+                    .clamp(-Self::INT8_MAX, Self::INT8_MAX)?
+                    .affine(1., Self::INT8_ZERO)?;
+                let (bs, heads, seq_len, _) = xs.dims4()?;
+                let exponent = exponent
+                    .affine(1., Self::EXPONENT_BIAS)?
+                    .reshape((1, 1, 1, 1))?
+                    .broadcast_as((bs, heads, seq_len, 1))?;
+                Tensor::cat(&[quantized, exponent], D::Minus1)?.to_dtype(DType::U8)
+            }
+        }
+    }
+
+    /// Dequantize a cache tensor written by [`KVCacheDtype::quantize`] to `dtype`.
+    fn dequantize(xs: &Tensor, dtype: DType) -> candle_core::Result<Tensor> {
+        match Self::of_cache(xs) {
+            Self::FullPrecision => xs.to_dtype(dtype),
+            Self::Fp8 => {
+                let head_dim = xs.dim(D::Minus1)? - 1;
+                let quantized = xs.narrow(D::Minus1, 0, head_dim)?.to_dtype(DType::F32)?;
+                let scale = xs.narrow(D::Minus1, head_dim, 1)?.to_dtype(DType::F32)?;
+                quantized.broadcast_mul(&scale)?.to_dtype(dtype)
+            }
+            Self::Int8 => {
+                let head_dim = xs.dim(D::Minus1)? - 1;
+                let quantized = xs
+                    .narrow(D::Minus1, 0, head_dim)?
+                    .to_dtype(DType::F32)?
+                    .affine(1., -Self::INT8_ZERO)?;
+                let scale = xs
+                    .narrow(D::Minus1, head_dim, 1)?
+                    .to_dtype(DType::F32)?
+                    .affine(
+                        std::f64::consts::LN_2,
+                        -Self::EXPONENT_BIAS * std::f64::consts::LN_2,
+                    )?
+                    .exp()?;
+                quantized.broadcast_mul(&scale)?.to_dtype(dtype)
+            }
+        }
+    }
+}
+
+impl FromStr for KVCacheDtype {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "full" | "auto" => Ok(Self::FullPrecision),
+            "int8" => Ok(Self::Int8),
+            "fp8" => Ok(Self::Fp8),
+            other => Err(format!(
+                "KV cache dtype `{other}` is not supported, expected one of `full`, `int8`, `fp8`."
+            )),
+        }
+    }
+}
+
+impl Display for KVCacheDtype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FullPrecision => write!(f, "full"),
+            Self::Int8 => write!(f, "int8"),
+            Self::Fp8 => write!(f, "fp8"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
     cache: Arc<Mutex<LayerCaches>>,
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     draft_cache: Arc<Mutex<LayerCaches>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    kv_cache_dtype: Arc<Mutex<KVCacheDtype>>,
 }
 
 impl Cache {
@@ -42,9 +229,21 @@ impl Cache {
             } else {
                 None
             },
+            kv_cache_dtype: Arc::new(Mutex::new(KVCacheDtype::FullPrecision)),
         }
     }
 
+    /// Dtype the models store this cache in, see [`Cache::update_kv_cache`].
+    pub(crate) fn kv_cache_dtype(&self) -> KVCacheDtype {
+        *get_mut_arcmutex!(self.kv_cache_dtype)
+    }
+
+    /// Set by the pipeline from [`GeneralMetadata::kv_cache_dtype`](super::GeneralMetadata) once
+    /// the model is loaded.
+    pub(crate) fn set_kv_cache_dtype(&self, kv_cache_dtype: KVCacheDtype) {
+        *get_mut_arcmutex!(self.kv_cache_dtype) = kv_cache_dtype;
+    }
+
     pub(crate) fn lock(&self) -> MutexGuard<'_, LayerCaches> {
         get_mut_arcmutex!(self.cache)
     }
@@ -72,13 +271,19 @@ impl Cache {
         self.xlora_cache.is_some()
     }
 
-    /// Update the KV cache and return (k,v)
+    /// Update the KV cache and return (k,v). The cache is stored as `kv_cache_dtype`, see
+    /// [`Cache::kv_cache_dtype`].
     pub(crate) fn update_kv_cache(
         cache: &mut Option<(Tensor, Tensor)>,
         k: Tensor,
         v: Tensor,
         slow_cat: bool,
+        kv_cache_dtype: KVCacheDtype,
     ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        if kv_cache_dtype != KVCacheDtype::FullPrecision {
+            let prev = cache.take();
+            return Self::update_quantized_kv_cache(cache, prev, k, v, kv_cache_dtype);
+        }
         let (k, v) = match &*cache {
             None => (k, v),
             Some((k_cache, v_cache)) => {
@@ -105,8 +310,13 @@ impl Cache {
         attention_mask: Option<&Tensor>,
         sliding_window: Option<usize>,
         slow_cat: bool,
+        kv_cache_dtype: KVCacheDtype,
     ) -> Result<(Tensor, Tensor, Option<Tensor>), candle_core::Error> {
         let (k, v, attention_mask) = match cache.clone() {
+            None if kv_cache_dtype != KVCacheDtype::FullPrecision => {
+                let (k, v) = Self::update_quantized_kv_cache(cache, None, k, v, kv_cache_dtype)?;
+                return Ok((k, v, attention_mask.cloned()));
+            }
             None => (k, v, attention_mask.cloned()),
             Some((mut prev_k, mut prev_v)) => {
                 let mut mask = attention_mask.cloned();
@@ -137,6 +347,16 @@ impl Cache {
                         }
                     }
                 }
+                if kv_cache_dtype != KVCacheDtype::FullPrecision {
+                    let (k, v) = Self::update_quantized_kv_cache(
+                        cache,
+                        Some((prev_k, prev_v)),
+                        k,
+                        v,
+                        kv_cache_dtype,
+                    )?;
+                    return Ok((k, v, mask));
+                }
                 let (k, v) = if !slow_cat {
                     let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
                     let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
@@ -152,6 +372,42 @@ impl Cache {
         *cache = Some((k.clone(), v.clone()));
         Ok((k, v, attention_mask))
     }
+
+    /// Append `k` and `v` quantized to `kv_cache_dtype` to `prev` and store the result in `cache`.
+    /// Returns the dequantized previous keys and values concatenated with the unquantized new ones.
+    fn update_quantized_kv_cache(
+        cache: &mut Option<(Tensor, Tensor)>,
+        prev: Option<(Tensor, Tensor)>,
+        k: Tensor,
+        v: Tensor,
+        kv_cache_dtype: KVCacheDtype,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let k_q = kv_cache_dtype.quantize(&k)?;
+        let v_q = kv_cache_dtype.quantize(&v)?;
+        let Some((prev_k, prev_v)) = prev else {
+            *cache = Some((k_q, v_q));
+            return Ok((k, v));
+        };
+        let k_out = Tensor::cat(&[&KVCacheDtype::dequantize(&prev_k, k.dtype())?, &k], 2)?;
+        let v_out = Tensor::cat(&[&KVCacheDtype::dequantize(&prev_v, v.dtype())?, &v], 2)?;
+        *cache = Some((
+            Tensor::cat(&[prev_k, k_q], 2)?.contiguous()?,
+            Tensor::cat(&[prev_v, v_q], 2)?.contiguous()?,
+        ));
+        Ok((k_out, v_out))
+    }
+
+    /// Read a KV cache entry back in `dtype`, dequantizing it if it was stored quantized.
+    pub(crate) fn read_kv_cache(
+        k_cache: &Tensor,
+        v_cache: &Tensor,
+        dtype: DType,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        Ok((
+            KVCacheDtype::dequantize(k_cache, dtype)?,
+            KVCacheDtype::dequantize(v_cache, dtype)?,
+        ))
+    }
 }
 
 pub struct DefaultCacheManager;
@@ -329,3 +585,109 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{Cache, KVCacheDtype};
+
+    #[test]
+    fn int8_kv_cache_roundtrip() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let k1 = Tensor::randn(0f32, 1., (2, 4, 5, 8), &dev)?;
+        let v1 = Tensor::randn(0f32, 1., (2, 4, 5, 8), &dev)?;
+        let k2 = (Tensor::randn(0f32, 1., (2, 4, 1, 8), &dev)? * 20.)?;
+        let v2 = (Tensor::randn(0f32, 1., (2, 4, 1, 8), &dev)? * 20.)?;
+
+        let mut cache = None;
+        let (k, _) =
+            Cache::update_quantized_kv_cache(&mut cache, None, k1.clone(), v1, KVCacheDtype::Int8)?;
+        assert_eq!(
+            k.flatten_all()?.to_vec1::<f32>()?,
+            k1.flatten_all()?.to_vec1::<f32>()?
+        );
+        let prev = cache.take();
+        let (k, v) = Cache::update_quantized_kv_cache(
+            &mut cache,
+            prev,
+            k2.clone(),
+            v2.clone(),
+            KVCacheDtype::Int8,
+        )?;
+        assert_eq!(k.dims(), &[2, 4, 6, 8]);
+        assert_eq!(v.dims(), &[2, 4, 6, 8]);
+
+        // One u8 per element plus the scale exponent column.
+        let (k_cache, v_cache) = cache.as_ref().unwrap();
+        assert_eq!(k_cache.dtype(), DType::U8);
+        assert_eq!(k_cache.dims(), &[2, 4, 6, 9]);
+
+        // The newest block is returned unquantized, the older one within its quantization step.
+        let (k_read, v_read) = Cache::read_kv_cache(k_cache, v_cache, DType::F32)?;
+        assert_eq!(
+            k.narrow(2, 5, 1)?.flatten_all()?.to_vec1::<f32>()?,
+            k2.flatten_all()?.to_vec1::<f32>()?
+        );
+        let absmax = k1.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+        let err = (k_read.narrow(2, 0, 5)? - &k1)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(err <= absmax / 127. * 1.001);
+        let absmax = v2.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+        let err = (v_read.narrow(2, 5, 1)? - &v2)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(err <= absmax / 127. * 1.001);
+        Ok(())
+    }
+
+    #[test]
+    fn fp8_kv_cache_roundtrip() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // Beyond the E4M3 range, and small enough to be subnormal, before scaling.
+        for magnitude in [1000f32, 0.001] {
+            let k = (Tensor::randn(0f32, 1., (2, 4, 5, 8), &dev)? * magnitude as f64)?;
+            let v = (Tensor::randn(0f32, 1., (2, 4, 5, 8), &dev)? * magnitude as f64)?;
+
+            let mut cache = None;
+            Cache::update_quantized_kv_cache(&mut cache, None, k.clone(), v, KVCacheDtype::Fp8)?;
+
+            // One byte per element plus the scale column.
+            let (k_cache, v_cache) = cache.as_ref().unwrap();
+            assert_eq!(k_cache.dtype(), DType::F8E4M3);
+            assert_eq!(k_cache.dims(), &[2, 4, 5, 9]);
+
+            // E4M3 has 3 mantissa bits: the rounding error is at most 2^-4 of the largest value.
+            let (k_read, _) = Cache::read_kv_cache(k_cache, v_cache, DType::F32)?;
+            let absmax = k.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+            let err = (k_read - &k)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(err <= absmax / 16., "{err} > {absmax} / 16");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn kv_cache_dtype_is_per_cache() -> candle_core::Result<()> {
+        let quantized = Cache::new(1, false);
+        quantized.set_kv_cache_dtype(KVCacheDtype::Int8);
+        let full = Cache::new(1, false);
+
+        let k = Tensor::randn(0f32, 1., (1, 2, 3, 4), &Device::Cpu)?;
+        for (cache, dtype) in [(&quantized, DType::U8), (&full, DType::F32)] {
+            let kv_cache_dtype = cache.kv_cache_dtype();
+            let mut layers = cache.lock();
+            Cache::update_kv_cache(&mut layers[0], k.clone(), k.clone(), false, kv_cache_dtype)?;
+            assert_eq!(layers[0].as_ref().unwrap().0.dtype(), dtype);
+        }
+        Ok(())
+    }
+}
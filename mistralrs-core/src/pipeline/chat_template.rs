@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 use tracing::info;
 
-use crate::{MessageContent, Tool};
+use crate::{parse_tool_calls, MessageContent, Tool, ToolCallResponse, ToolCallingModel};
 
 const SUPPORTED_ALTERNATE_EOS: &[&str] = &[
     "<|im_end|>",    // Handle ChatML case
@@ -95,6 +95,20 @@ impl ChatTemplate {
             Either::Right(ref added) => Some(added.content.clone()),
         }
     }
+
+    /// The tool calling model family of the template used with tools, if it is known.
+    pub fn tool_calling_model(&self) -> Option<ToolCallingModel> {
+        let template = select_template(self.chat_template.as_ref()?, true).ok()?;
+        ToolCallingModel::from_template(&template)
+    }
+
+    /// Extract the tool calls from the raw output of a model of the `model` family.
+    pub fn parse_tool_calls(
+        response: &str,
+        model: ToolCallingModel,
+    ) -> Result<Vec<ToolCallResponse>> {
+        parse_tool_calls(response, model)
+    }
 }
 
 pub fn calculate_eos_tokens(
@@ -180,6 +194,42 @@ pub struct GenerationConfig {
     eos_token_id: Either<u32, Vec<u32>>,
 }
 
+/// Compact JSON with the separators of Python's `json.dumps`, which the HF `tojson` filter uses.
+struct PythonCompactFormatter;
+
+impl serde_json::ser::Formatter for PythonCompactFormatter {
+    fn begin_array_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        writer.write_all(b": ")
+    }
+}
+
 fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
     if let Ok(indent) = kwargs.get("indent") {
         let mut buf = Vec::new();
@@ -191,7 +241,10 @@ fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
             Error::new(ErrorKind::BadSerialization, "cannot serialize to JSON").with_source(err)
         })
     } else {
-        serde_json::to_string(&value).map_err(|err| {
+        let mut buf = Vec::new();
+        let mut ser = serde_json::Serializer::with_formatter(&mut buf, PythonCompactFormatter);
+        value.serialize(&mut ser).unwrap();
+        String::from_utf8(buf).map_err(|err| {
             Error::new(ErrorKind::BadSerialization, "cannot serialize to JSON").with_source(err)
         })
     }
@@ -214,41 +267,109 @@ fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
     })
 }
 
-pub fn apply_chat_template_to(
-    messages: Vec<IndexMap<String, MessageContent>>,
-    add_generation_prompt: bool,
-    template: &ChatTemplateValue,
-    bos_tok: Option<String>,
-    eos_tok: Option<String>,
-    unk_tok: Option<String>,
-    tools: Vec<Tool>,
-) -> Result<String> {
-    let mut env = Environment::new();
+#[derive(Serialize, Deserialize)]
+struct UntaggedContent(#[serde(with = "either::serde_untagged")] MessageContent);
 
-    // enable python methods such as .strip()
-    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+/// Convert a message to the template context. `tool_calls` are given as a list of `id`, `name`
+/// and JSON `arguments` and are expanded to `{"id", "type", "function": {"name", "arguments"}}`.
+fn message_to_context(
+    message: IndexMap<String, MessageContent>,
+    tool_calling_model: Option<ToolCallingModel>,
+) -> Result<IndexMap<String, serde_json::Value>> {
+    let tool_call_id = |id: &str| match tool_calling_model {
+        Some(model) => model.tool_call_id(id),
+        None => id.to_string(),
+    };
 
-    // https://github.com/huggingface/transformers/blob/76a33a10923ccc1074917f6b6a1e719e626b7dc9/src/transformers/tokenization_utils_base.py#L1842
-    env.set_lstrip_blocks(true);
-    env.set_trim_blocks(true);
+    let mut new_message = IndexMap::new();
+    for (k, v) in message {
+        let value = match (k.as_str(), v) {
+            ("tool_calls", Either::Right(tool_calls)) => {
+                let mut calls = Vec::new();
+                for call in tool_calls {
+                    let Some(name) = call.get("name") else {
+                        anyhow::bail!("Tool call is missing the function `name`.");
+                    };
+                    let arguments = call.get("arguments").map_or("{}", String::as_str);
+                    // Templates expect a mapping, but may fall back to the raw string
+                    let arguments = serde_json::from_str(arguments)
+                        .unwrap_or_else(|_| serde_json::Value::String(arguments.to_string()));
+                    let mut tool_call = serde_json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": name,
+                            "arguments": arguments,
+                        },
+                    });
+                    if let Some(id) = call.get("id") {
+                        tool_call["id"] = serde_json::Value::String(tool_call_id(id));
+                    }
+                    calls.push(tool_call);
+                }
+                serde_json::Value::Array(calls)
+            }
+            ("tool_call_id", Either::Left(id)) => serde_json::Value::String(tool_call_id(&id)),
+            (_, v) => serde_json::to_value(UntaggedContent(v))?,
+        };
+        new_message.insert(k, value);
+    }
+    Ok(new_message)
+}
 
-    #[derive(Serialize, Deserialize)]
-    struct UntaggedContent(#[serde(with = "either::serde_untagged")] MessageContent);
-    let mut new_messages = Vec::new();
-    for message in messages {
-        let mut new_message = IndexMap::new();
-        for (k, v) in message {
-            new_message.insert(k, UntaggedContent(v));
+/// Add the tools to the context of the model families whose templates do not render `tools`:
+/// - Gemma 2: the tools and how to call them are prepended to the first user message
+/// - Phi-3: the tools are given as a JSON list in the `tools` field of the system message
+fn add_tools_to_context(
+    tool_calling_model: Option<ToolCallingModel>,
+    tools: &[Tool],
+    messages: &mut Vec<IndexMap<String, serde_json::Value>>,
+) -> Result<()> {
+    let functions = serde_json::to_string(&tools.iter().map(|t| &t.function).collect::<Vec<_>>())?;
+    match tool_calling_model {
+        Some(ToolCallingModel::Gemma2) => {
+            let Some(content) = messages
+                .iter_mut()
+                .find(|m| m.get("role").is_some_and(|r| r == "user"))
+                .and_then(|m| m.get_mut("content"))
+            else {
+                anyhow::bail!("Expected a user message to describe the tools in.");
+            };
+            let serde_json::Value::String(text) = content else {
+                anyhow::bail!("Expected the content of the first user message to be text.");
+            };
+            *text = format!(
+                "You have access to the following functions:\n{functions}\n\nTo call functions, respond only with a list of calls in a ```json code block, for example:\n```json\n[{{\"name\": \"function_name\", \"arguments\": {{\"argument\": \"value\"}}}}]\n```\n\n{text}"
+            );
+        }
+        Some(ToolCallingModel::Phi3) => {
+            if !messages
+                .first()
+                .is_some_and(|m| m.get("role").is_some_and(|r| r == "system"))
+            {
+                messages.insert(
+                    0,
+                    IndexMap::from([
+                        ("role".to_string(), serde_json::json!("system")),
+                        ("content".to_string(), serde_json::json!("")),
+                    ]),
+                );
+            }
+            messages[0].insert("tools".to_string(), serde_json::Value::String(functions));
         }
-        new_messages.push(new_message);
+        Some(ToolCallingModel::Llama3 | ToolCallingModel::Mistral | ToolCallingModel::Hermes)
+        | None => (),
     }
+    Ok(())
+}
 
-    let template = match &template.0 {
-        Either::Left(x) => x.clone(),
+/// Select the `tool_use` template if there are tools and it exists, otherwise the `default` one.
+fn select_template(template: &ChatTemplateValue, has_tools: bool) -> Result<String> {
+    match &template.0 {
+        Either::Left(x) => Ok(x.clone()),
         Either::Right(map) => {
             let mut template = "".to_string();
             for t in map {
-                if t.contains_key("tool_use") && !tools.is_empty() {
+                if t.contains_key("tool_use") && has_tools {
                     template = t["tool_use"].clone();
                     break;
                 } else if t.contains_key("default") {
@@ -259,9 +380,39 @@ pub fn apply_chat_template_to(
             if template.is_empty() {
                 anyhow::bail!("Chat template does not contain a `tool_use` or `default` key. Please ensure it contains at least a `default` key, although `tool_use` should be specified for using tools.");
             }
-            template
+            Ok(template)
         }
-    };
+    }
+}
+
+pub fn apply_chat_template_to(
+    messages: Vec<IndexMap<String, MessageContent>>,
+    add_generation_prompt: bool,
+    template: &ChatTemplateValue,
+    bos_tok: Option<String>,
+    eos_tok: Option<String>,
+    unk_tok: Option<String>,
+    tools: Vec<Tool>,
+) -> Result<String> {
+    let mut env = Environment::new();
+
+    // enable python methods such as .strip()
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+
+    // https://github.com/huggingface/transformers/blob/76a33a10923ccc1074917f6b6a1e719e626b7dc9/src/transformers/tokenization_utils_base.py#L1842
+    env.set_lstrip_blocks(true);
+    env.set_trim_blocks(true);
+
+    let template = select_template(template, !tools.is_empty())?;
+
+    let tool_calling_model = ToolCallingModel::from_template(&template);
+    let mut new_messages = messages
+        .into_iter()
+        .map(|message| message_to_context(message, tool_calling_model))
+        .collect::<Result<Vec<_>>>()?;
+    if !tools.is_empty() {
+        add_tools_to_context(tool_calling_model, &tools, &mut new_messages)?;
+    }
 
     env.add_template("chat_template", &template)?;
     env.add_function("raise_exception", raise_exception);
@@ -0,0 +1,547 @@
+use std::{
+    any::Any,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result as anyhowResult;
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use tokenizers::Tokenizer;
+use tracing::warn;
+
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{
+        sampling::{finish_or_add_toks_to_seq, sample_sequence},
+        AdapterInstruction, Cache,
+    },
+    prefix_cacher::PrefixCacheManager,
+    sequence::Sequence,
+    DeviceMapMetadata, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource,
+    TryIntoDType,
+};
+
+use super::{
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, AdapterActivationMixin,
+    AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction, CacheManager, CacheManagerMixin,
+    ForwardInputsResult, GeneralMetadata, InputProcessorOutput, IsqPipelineMixin, MetadataMixin,
+    ModelCategory, ModelPaths, PreProcessingMixin, Processor,
+};
+
+/// A loader for a contrastive decoding pipeline using an expert [`Loader`] and the amateur
+/// [`Loader`] of the [`ContrastiveConfig`].
+pub struct ContrastiveLoader {
+    pub expert: Box<dyn Loader>,
+    pub config: ContrastiveConfig,
+}
+
+/// Metadata for a contrastive decoding pipeline
+pub struct ContrastiveConfig {
+    /// The weak model whose logits are subtracted from the expert's.
+    pub amateur: Box<dyn Loader>,
+    /// Weight of the amateur model's log probabilities.
+    pub alpha: f64,
+}
+
+impl Loader for ContrastiveLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Contrastive decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let expert = self.expert.load_model_from_hf(
+            revision.clone(),
+            token_source.clone(),
+            dtype,
+            device,
+            silent,
+            mapper.clone(),
+            in_situ_quant,
+            None,
+        )?;
+        let amateur = self.config.amateur.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(ContrastivePipeline::new(
+            expert,
+            amateur,
+            self.config.alpha,
+        )?)))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Contrastive decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let expert = self.expert.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper.clone(),
+            in_situ_quant,
+            None,
+        )?;
+        let amateur = self.config.amateur.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(ContrastivePipeline::new(
+            expert,
+            amateur,
+            self.config.alpha,
+        )?)))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Contrastive: expert = `{}`, amateur = `{}`, alpha = `{}`",
+            self.expert.get_id(),
+            self.config.amateur.get_id(),
+            self.config.alpha,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::Contrastive {
+            expert: Box::new(self.expert.get_kind()),
+            amateur: Box::new(self.config.amateur.get_kind()),
+        }
+    }
+}
+
+/// Contrastive decoding pipeline: <https://arxiv.org/abs/2210.15097>
+///
+/// # Algorithm
+/// Given expert model p and amateur model q, at each step both models are run on the context and
+/// the next token is sampled from the scores
+///
+/// - log p(x) - alpha * log q(x)
+///     - Tokens the expert assigns no probability to are never sampled
+///
+/// The amateur model's KV cache is held in the sequences' draft cache.
+pub struct ContrastivePipeline {
+    expert: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    amateur: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    alpha: f64,
+    metadata: Arc<GeneralMetadata>,
+    category: ModelCategory,
+}
+
+impl ContrastivePipeline {
+    pub fn new(
+        expert: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        amateur: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        alpha: f64,
+    ) -> Result<Self> {
+        if !alpha.is_finite() || alpha < 0. {
+            candle_core::bail!(
+                "Contrastive decoding `alpha` must be a non-negative number, got {alpha}."
+            );
+        }
+        if get_mut_arcmutex!(expert)
+            .tokenizer()
+            .as_ref()
+            .ok_or(candle_core::Error::Msg(
+                "`ContrastivePipeline::new` requires the expert pipeline to have a tokenizer"
+                    .to_string(),
+            ))?
+            .get_vocab(true)
+            != get_mut_arcmutex!(amateur)
+                .tokenizer()
+                .as_ref()
+                .ok_or(candle_core::Error::Msg(
+                    "`ContrastivePipeline::new` requires the amateur pipeline to have a tokenizer"
+                        .to_string(),
+                ))?
+                .get_vocab(true)
+        {
+            candle_core::bail!("Expert and amateur models' tokenizer vocab do not match. This is required for contrastive decoding.");
+        }
+        if get_mut_arcmutex!(expert).category() != get_mut_arcmutex!(amateur).category() {
+            candle_core::bail!("Expert and amateur models' category do not match. This is required for contrastive decoding.");
+        }
+        // The amateur's cache is held in the sequences' draft cache, which has one entry per
+        // layer of the expert.
+        if get_mut_arcmutex!(amateur).get_metadata().num_hidden_layers
+            > get_mut_arcmutex!(expert).get_metadata().num_hidden_layers
+        {
+            candle_core::bail!("The amateur model must not have more layers than the expert model for contrastive decoding.");
+        }
+        let metadata = get_mut_arcmutex!(expert).get_metadata().clone();
+        let category = get_mut_arcmutex!(expert).category();
+        Ok(Self {
+            expert,
+            amateur,
+            alpha,
+            metadata,
+            category,
+        })
+    }
+}
+
+impl PreProcessingMixin for ContrastivePipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        get_mut_arcmutex!(self.expert).get_processor()
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        get_mut_arcmutex!(self.expert).get_chat_template()
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        get_mut_arcmutex!(self.expert).get_input_processor_config()
+    }
+}
+
+impl IsqPipelineMixin for ContrastivePipeline {
+    fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.expert).re_isq_model(dtype)?;
+        get_mut_arcmutex!(self.amateur).re_isq_model(dtype)
+    }
+}
+
+impl CacheManagerMixin for ContrastivePipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {
+        DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.amateur), seqs, true);
+        DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.expert), seqs, false);
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {
+        DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.amateur), seqs, true);
+        DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.expert), seqs, false);
+    }
+    fn set_none_cache(&self, reset_non_granular: bool, _modify_draft_cache: bool) {
+        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.amateur), true);
+        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.expert), false);
+        if reset_non_granular {
+            self.reset_non_granular_state()
+        }
+    }
+    fn cache(&self) -> &Cache {
+        unreachable!()
+    }
+}
+
+impl AdapterActivationMixin for ContrastivePipeline {
+    /// Returns the number of activated adapters.
+    fn activate_weighted_adapters(
+        &mut self,
+        adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        let mut res = 0;
+        res += get_mut_arcmutex!(self.amateur).activate_weighted_adapters(adapters.clone())?;
+        res += get_mut_arcmutex!(self.expert).activate_weighted_adapters(adapters)?;
+        Ok(res)
+    }
+    fn merge_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+        let mut res = 0;
+        res += get_mut_arcmutex!(self.amateur).merge_adapters(adapters.clone())?;
+        res += get_mut_arcmutex!(self.expert).merge_adapters(adapters)?;
+        Ok(res)
+    }
+}
+
+impl MetadataMixin for ContrastivePipeline {
+    fn device(&self) -> Device {
+        get_mut_arcmutex!(self.expert).device()
+    }
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        get_mut_arcmutex!(self.expert).tokenizer()
+    }
+    fn name(&self) -> String {
+        format!(
+            "Contrastive: expert = `{}`, amateur = `{}`, alpha = `{}`",
+            get_mut_arcmutex!(self.expert).name(),
+            get_mut_arcmutex!(self.amateur).name(),
+            self.alpha,
+        )
+    }
+    fn reset_non_granular_state(&self) {
+        get_mut_arcmutex!(self.expert).reset_non_granular_state();
+        get_mut_arcmutex!(self.amateur).reset_non_granular_state();
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+}
+
+/// Contrastive scores `log_softmax(expert) - alpha * log_softmax(amateur)` over the last dim.
+///
+/// Tokens the expert rules out (`-inf`) stay ruled out, which also keeps `-inf - -inf` from
+/// producing NaN. If the vocab sizes differ, only the shared prefix of the vocab is kept.
+fn contrastive_logits(expert: &Tensor, amateur: &Tensor, alpha: f64) -> Result<Tensor> {
+    let vocab = expert.dim(D::Minus1)?.min(amateur.dim(D::Minus1)?);
+    let expert = candle_nn::ops::log_softmax(
+        &expert.narrow(D::Minus1, 0, vocab)?.to_dtype(DType::F32)?,
+        D::Minus1,
+    )?;
+    let amateur = candle_nn::ops::log_softmax(
+        &amateur.narrow(D::Minus1, 0, vocab)?.to_dtype(DType::F32)?,
+        D::Minus1,
+    )?;
+    let scores = (&expert - (amateur * alpha)?)?;
+    let ruled_out = Tensor::full(f32::NEG_INFINITY, scores.shape(), scores.device())?;
+    expert
+        .ne(f32::NEG_INFINITY)?
+        .where_cond(&scores, &ruled_out)
+}
+
+impl ContrastivePipeline {
+    /// Run `pipeline` on the sequences whose caches are in the model, returning the logits of the
+    /// last position of each sequence.
+    fn run_model(
+        &self,
+        pipeline: &Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+    ) -> Result<Vec<Tensor>> {
+        // Gather these before locking `pipeline`, they lock the expert.
+        let processor = self.get_processor();
+        let tokenizer = self.tokenizer();
+        let input_processor_config = self.get_input_processor_config();
+
+        let mut pipeline = get_mut_arcmutex!(pipeline);
+        let metadata = pipeline.get_metadata();
+        let inputs_iter = processor.inputs_processor().process_inputs(
+            tokenizer,
+            seqs,
+            is_prompt,
+            metadata.is_xlora,
+            &pipeline.device(),
+            metadata.has_no_kv_cache,
+            None,
+            input_processor_config,
+            None,
+            metadata.prompt_batchsize,
+        );
+
+        let mut logits = vec![None; seqs.len()];
+        for inputs in inputs_iter {
+            let InputProcessorOutput {
+                inputs,
+                seq_indices,
+            } = inputs.map_err(candle_core::Error::msg)?;
+            let ForwardInputsResult::CausalGeneration { logits: raw_logits } =
+                pipeline.forward_inputs(inputs)?
+            else {
+                candle_core::bail!(
+                    "Contrastive decoding requires `CausalGeneration` forward results"
+                );
+            };
+            for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
+                logits[seq_idx] = Some(raw_logits.i(logit_idx)?);
+            }
+        }
+        Ok(logits
+            .into_iter()
+            .map(|l| l.expect("Did not get any inputs. This is shocking."))
+            .collect())
+    }
+
+    fn activate_adapter_instruction(&mut self, adapter_inst: AdapterInstruction) -> Result<()> {
+        match adapter_inst {
+            AdapterInstruction::Activate(adapters) => {
+                self.activate_adapters(adapters).map_err(|e| {
+                    candle_core::Error::msg(
+                        <anyhow::Error as AsRef<dyn std::error::Error>>::as_ref(&e),
+                    )
+                })?;
+            }
+            AdapterInstruction::PerSequence => {
+                candle_core::bail!("Contrastive decoding does not support batches mixing adapters.")
+            }
+            AdapterInstruction::None => (),
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for ContrastivePipeline {
+    fn forward_inputs(&mut self, _inputs: Box<dyn Any>) -> Result<ForwardInputsResult> {
+        unreachable!()
+    }
+    async fn sample_causal_gen(
+        &self,
+        seqs: &mut [&mut Sequence],
+        logits: Vec<Tensor>,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        let eos_owned = self.metadata.eos_tok.clone();
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(&eos_owned[..])
+        };
+        for (logits, seq) in std::iter::zip(logits, seqs.iter_mut()) {
+            let sample = sample_sequence(
+                logits,
+                seq,
+                seq.return_logprobs(),
+                rng.clone(),
+                false,
+                true, // Append result to trie
+                false,
+                &eos_owned,
+            )
+            .await;
+            let sample = crate::handle_seq_error_stateaware_ok!(sample, seq);
+            // Do not use the prefix cacher, it does not hold the amateur's cache.
+            finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false).await?;
+        }
+        Ok(())
+    }
+    async fn step(
+        &mut self,
+        input_seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        backend_metadata: CacheBackendMetadata<'_>,
+    ) -> Result<()> {
+        self.metadata.apply_adapter_swaps(input_seqs);
+        match backend_metadata {
+            CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
+                match pre_op {
+                    CacheInstruction::In(adapter_inst) => {
+                        self.activate_adapter_instruction(adapter_inst)?;
+                        self.clone_in_cache(input_seqs, false)
+                    }
+                    CacheInstruction::Nothing(adapter_inst) => {
+                        self.activate_adapter_instruction(adapter_inst)?
+                    }
+                    CacheInstruction::Reset {
+                        reset_non_granular,
+                        adapter_inst,
+                    } => {
+                        self.activate_adapter_instruction(adapter_inst)?;
+                        self.set_none_cache(reset_non_granular, false)
+                    }
+                    _ => unreachable!("Unreachable PRE cache op."),
+                }
+
+                // Both models see the same context, so their caches advance in lockstep and the
+                // sequences can be run as one batch.
+                let expert_logits = self.run_model(&self.expert, input_seqs, is_prompt)?;
+                let amateur_logits = self.run_model(&self.amateur, input_seqs, is_prompt)?;
+                let logits = std::iter::zip(expert_logits, amateur_logits)
+                    .map(|(expert, amateur)| {
+                        contrastive_logits(&expert, &amateur, self.alpha)?.to_device(&Device::Cpu)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                match post_op {
+                    CacheInstruction::Out => self.clone_out_cache(input_seqs, false),
+                    CacheInstruction::Nothing(_) => (),
+                    CacheInstruction::Reset {
+                        reset_non_granular,
+                        adapter_inst: _,
+                    } => self.set_none_cache(reset_non_granular, false),
+                    _ => unreachable!("Unreachable POST cache op."),
+                }
+
+                self.sample_causal_gen(input_seqs, logits, prefix_cacher, disable_eos_stop, rng)
+                    .await
+            }
+            CacheBackendMetadata::PagedAttention {
+                metadata: _,
+                blocks_to_copy: _,
+                blocks_to_swap_in: _,
+                blocks_to_swap_out: _,
+            } => unreachable!(),
+        }
+    }
+    fn category(&self) -> ModelCategory {
+        self.category
+    }
+    fn prewarm(&mut self) -> anyhowResult<()> {
+        get_mut_arcmutex!(self.expert).prewarm()?;
+        get_mut_arcmutex!(self.amateur).prewarm()
+    }
+}
+
+// TODO
+impl AnyMoePipelineMixin for ContrastivePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::contrastive_logits;
+
+    #[test]
+    fn test_contrastive_logits() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        // The expert and amateur agree on token 0, the expert alone prefers token 1 and rules out
+        // token 3.
+        let expert = Tensor::new(&[3f32, 2.5, 0., f32::NEG_INFINITY], &dev)?;
+        let amateur = Tensor::new(&[3f32, 0., 0., 1.], &dev)?;
+
+        let scores = contrastive_logits(&expert, &amateur, 0.)?.to_vec1::<f32>()?;
+        assert_eq!(
+            scores
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap()
+                .0,
+            0
+        );
+
+        let scores = contrastive_logits(&expert, &amateur, 1.)?.to_vec1::<f32>()?;
+        assert!(scores.iter().all(|s| !s.is_nan()));
+        assert_eq!(scores[3], f32::NEG_INFINITY);
+        assert_eq!(
+            scores
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap()
+                .0,
+            1
+        );
+
+        // Only the shared part of the vocab is kept.
+        let amateur = Tensor::new(&[3f32, 0., 0.], &dev)?;
+        assert_eq!(contrastive_logits(&expert, &amateur, 1.)?.dims(), &[3]);
+        Ok(())
+    }
+}
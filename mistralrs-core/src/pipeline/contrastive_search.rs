@@ -0,0 +1,203 @@
+//! The steps of contrastive search which run the model, see [`ContrastiveSearchConfig`]. The
+//! context of a sequence is the hidden states before the LM head of the tokens the model ran on,
+//! and the candidates of its next token are run on copies of its KV cache. The token is selected
+//! when the pipeline samples with `sample_and_add_toks`.
+//!
+//! [`ContrastiveSearchConfig`]: crate::sampler::ContrastiveSearchConfig
+
+use std::{any::Any, iter::zip};
+
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+
+use crate::sequence::Sequence;
+
+use super::{
+    hidden_states::with_hidden_state_capture,
+    speculative::repeat_batch,
+    text_models_inputs_processor::{FlashParams, ModelInputs},
+    ForwardInputsResult, HiddenStateLayer, Pipeline,
+};
+
+fn no_hidden_states() -> candle_core::Error {
+    candle_core::Error::Msg(
+        "Contrastive search requires the model to report its hidden states before the LM head."
+            .to_string(),
+    )
+}
+
+/// Run `inputs` through the model, appending the hidden states of the new tokens of each
+/// contrastive search sequence to its context. Batch row `i` is the sequence `seq_indices[i]`.
+pub(crate) fn forward_inputs_with_context<P: Pipeline + ?Sized>(
+    pipeline: &mut P,
+    inputs: Box<dyn Any>,
+    seqs: &mut [&mut Sequence],
+    seq_indices: &[usize],
+) -> Result<ForwardInputsResult> {
+    // The rows are right padded, so the new tokens of a row come first.
+    let n_new_tokens = match inputs.downcast_ref::<ModelInputs>() {
+        Some(inputs) => zip(&inputs.position_ids, &inputs.seqlen_offsets)
+            .map(|(position, offset)| position - offset)
+            .collect::<Vec<_>>(),
+        None => candle_core::bail!("Contrastive search requires text model inputs."),
+    };
+    let (logits, hidden_states) = with_hidden_state_capture(HiddenStateLayer::BeforeHead, || {
+        pipeline.forward_inputs(inputs)
+    })?;
+    let hidden_states = hidden_states.ok_or_else(no_hidden_states)?;
+    for (row, (seq_idx, n_new)) in zip(seq_indices, n_new_tokens).enumerate() {
+        if let Some(state) = seqs[*seq_idx].contrastive_mut() {
+            state.push_context(hidden_states.i((row, ..n_new))?)?;
+        }
+    }
+    Ok(logits)
+}
+
+/// Find the candidates of the next token of a contrastive search sequence from its `logits`, and
+/// run the model on them to get their hidden states. The candidates are run as one batch on
+/// copies of the KV cache of the sequence, which is left in the model cache.
+pub(crate) fn run_candidates<P: Pipeline + ?Sized>(
+    pipeline: &mut P,
+    seq: &mut Sequence,
+    logits: &ForwardInputsResult,
+) -> Result<()> {
+    #[allow(irrefutable_let_patterns)]
+    let ForwardInputsResult::CausalGeneration { logits } = logits
+    else {
+        candle_core::bail!("Contrastive search requires `CausalGeneration` forward results");
+    };
+    let top_k = seq
+        .contrastive()
+        .expect("Sequence must do contrastive search.")
+        .config
+        .top_k;
+    let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    let logits = match seq.min_new_tokens(&pipeline.get_metadata().eos_tok) {
+        Some(min_new_tokens) => {
+            let mut logits: Vec<f32> = logits.to_vec1()?;
+            min_new_tokens.apply(&mut logits);
+            let vocab_size = logits.len();
+            Tensor::from_vec(logits, vocab_size, &Device::Cpu)?
+        }
+        None => logits,
+    };
+    let candidates = seq
+        .sampler()
+        .contrastive_search_candidates(&logits, seq.get_toks(), top_k)?;
+    let tokens = candidates
+        .iter()
+        .map(|(token, _)| *token)
+        .collect::<Vec<_>>();
+
+    let cache_len = seq.len();
+    let mut candidate_seqs = [&mut *seq];
+    pipeline.clone_in_cache(&mut candidate_seqs, false);
+    repeat_batch(&mut pipeline.cache().lock(), tokens.len())?;
+    let inputs = candidate_inputs(tokens, cache_len, &pipeline.device())?;
+    let (_, hidden_states) = with_hidden_state_capture(HiddenStateLayer::BeforeHead, || {
+        pipeline.forward_inputs(Box::new(inputs))
+    })?;
+    let hidden_states = hidden_states.ok_or_else(no_hidden_states)?.squeeze(1)?;
+
+    seq.contrastive_mut()
+        .expect("Sequence must do contrastive search.")
+        .candidates = Some((candidates, hidden_states));
+    Ok(())
+}
+
+/// Inputs running each of `tokens` in its own batch row, after a cache of `cache_len` tokens.
+fn candidate_inputs(tokens: Vec<u32>, cache_len: usize, device: &Device) -> Result<ModelInputs> {
+    let n_rows = tokens.len();
+    let input_ids = Tensor::from_vec(tokens, (n_rows, 1), device)?;
+    let positions_kernel = Tensor::full(cache_len as i64, (n_rows, 1), device)?;
+    let n_rows_u32 = u32::try_from(n_rows).map_err(candle_core::Error::msg)?;
+    let cumulative_seqlens = Tensor::arange(0, n_rows_u32 + 1, device)?;
+    Ok(ModelInputs {
+        input_ids,
+        input_ids_full: None,
+        seqlen_offsets: vec![cache_len; n_rows],
+        seqlen_offsets_full: None,
+        seqlen_offsets_kernel: positions_kernel,
+        seqlen_offsets_kernel_full: None,
+        context_lens: vec![(0, 1); n_rows],
+        position_ids: vec![cache_len + 1; n_rows],
+        paged_attn_meta: None,
+        flash_meta: FlashParams {
+            max_q: 1,
+            max_k: 1,
+            cumulative_seqlens_q: cumulative_seqlens.clone(),
+            cumulative_seqlens_k: cumulative_seqlens,
+        },
+        flash_meta_full: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use candle_core::Device;
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+
+    use crate::{
+        pipeline::{
+            test_utils::{new_mock_seq, MockPipeline},
+            AdapterInstruction, CacheBackendMetadata, CacheInstruction, Pipeline,
+        },
+        prefix_cacher::PrefixCacheManager,
+        sampler::ContrastiveSearchConfig,
+    };
+
+    #[tokio::test]
+    async fn test_step_contrastive_search() -> candle_core::Result<()> {
+        // A degenerate model which always predicts token 1. The hidden states of the mock are the
+        // one-hot encodings of the tokens, so a candidate is penalized if it is in the context.
+        let mut pipeline = MockPipeline::new(64, Arc::new(|_| 1));
+        let (seq, _rx) = new_mock_seq(0, vec![1], Some(4));
+        let mut contrastive = seq.with_contrastive_search(ContrastiveSearchConfig {
+            top_k: 4,
+            penalty_alpha: 0.6,
+        });
+        // A greedy sequence with a longer prompt, so that the prompt of the other is padded.
+        let (mut greedy, _rx) = new_mock_seq(1, vec![1, 1, 1], Some(4));
+
+        for step in 0..4 {
+            let is_prompt = step == 0;
+            let pre_op = if is_prompt {
+                CacheInstruction::Reset {
+                    reset_non_granular: false,
+                    adapter_inst: AdapterInstruction::None,
+                }
+            } else {
+                CacheInstruction::In(AdapterInstruction::None)
+            };
+            pipeline
+                .step(
+                    &mut [&mut contrastive, &mut greedy],
+                    is_prompt,
+                    &mut PrefixCacheManager::new(Device::Cpu, 0, false, true),
+                    false,
+                    Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0))),
+                    CacheBackendMetadata::DefaultInstructions {
+                        pre_op,
+                        post_op: CacheInstruction::Out,
+                    },
+                )
+                .await?;
+        }
+
+        // The candidates are 1 then 0, 2 and 3 by token id, as the others are equally likely.
+        // Tokens of the context are avoided until every candidate is in it.
+        assert_eq!(contrastive.get_toks()[1..], [0, 2, 3, 1]);
+        assert_eq!(greedy.get_toks()[3..], [1, 1, 1, 1]);
+        // The prompt token and the tokens the model ran on at each later step.
+        let context = contrastive
+            .contrastive()
+            .and_then(|state| state.context_hidden_states.clone())
+            .expect("No context hidden states.");
+        assert_eq!(context.dims(), [4, 64]);
+        // Each step runs the batch, then the candidates of the contrastive search sequence.
+        assert_eq!(pipeline.forward_batch_sizes, [2, 4, 2, 4, 2, 4, 2, 4]);
+        Ok(())
+    }
+}
@@ -2,10 +2,11 @@ use super::loaders::{DiffusionModelPaths, DiffusionModelPathsInner};
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, Cache, CacheManagerMixin, DiffusionLoaderType,
     DiffusionModel, DiffusionModelLoader, FluxLoader, ForwardInputsResult, GeneralMetadata,
-    IsqPipelineMixin, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
+    IsqPipelineMixin, KVCacheDtype, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
     PreProcessingMixin, Processor, TokenSource,
 };
 use crate::diffusion_models::processor::{DiffusionProcessor, ModelInputs};
+use crate::diffusion_models::response::send_progress;
 use crate::paged_attention::AttentionImplementation;
 use crate::pipeline::ChatTemplate;
 use crate::prefix_cacher::PrefixCacheManager;
@@ -21,6 +22,7 @@ use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::io;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -139,6 +141,13 @@ impl Loader for DiffusionLoader {
             .expect("Path downcast failed.")
             .0;
 
+        if mapper.is_paged() {
+            anyhow::bail!("Paged device mapping is not supported for diffusion models.");
+        }
+        if mapper.is_tensor_parallel() {
+            anyhow::bail!("Tensor parallelism is not supported for diffusion models.");
+        }
+
         // Otherwise, the device mapper will print it
         if mapper.is_dummy() {
             info!(
@@ -224,9 +233,15 @@ impl Loader for DiffusionLoader {
                 has_no_kv_cache: true, // NOTE(EricLBuehler): no cache for these.
                 activation_dtype: dtype,
                 sliding_window: None,
+                kv_cache_dtype: KVCacheDtype::FullPrecision,
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: None,
+                speculative_accepted_tokens: AtomicUsize::new(0),
+                speculative_drafted_tokens: AtomicUsize::new(0),
+                speculative_draft_calls: AtomicUsize::new(0),
+                per_request_adapter_override: false,
+                pending_adapter_swaps: Default::default(),
             }),
             dummy_cache: Cache::new(0, false),
         })))
@@ -269,7 +284,7 @@ impl CacheManagerMixin for DiffusionPipeline {
 }
 
 impl AdapterActivationMixin for DiffusionPipeline {
-    fn activate_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+    fn activate_weighted_adapters(&mut self, _adapters: Vec<(String, f64)>) -> Result<usize> {
         anyhow::bail!("Diffusion models do not support adapter activation.");
     }
 }
@@ -293,8 +308,17 @@ impl MetadataMixin for DiffusionPipeline {
 #[async_trait::async_trait]
 impl Pipeline for DiffusionPipeline {
     fn forward_inputs(&mut self, inputs: Box<dyn Any>) -> candle_core::Result<ForwardInputsResult> {
-        let ModelInputs { prompts, params } = *inputs.downcast().expect("Downcast failed.");
-        let img = self.model.forward(prompts, params)?.to_dtype(DType::U8)?;
+        let ModelInputs {
+            prompts,
+            params,
+            progress,
+        } = *inputs.downcast().expect("Downcast failed.");
+        let img = self
+            .model
+            .forward(prompts, params, &mut |step, total_steps| {
+                send_progress(&progress, step, total_steps)
+            })?
+            .to_dtype(DType::U8)?;
         let (_b, c, h, w) = img.dims4()?;
         let mut images = Vec::new();
         for b_img in img.chunk(img.dim(0)?, 0)? {
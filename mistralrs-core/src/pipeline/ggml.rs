@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind, TokenSource,
-    XLoraPaths,
+    CacheManager, GeneralMetadata, KVCacheDtype, Loader, ModelKind, ModelPaths, QuantizationKind,
+    TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
@@ -40,6 +40,7 @@ use std::fs;
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -80,6 +81,8 @@ pub struct GGMLLoader {
 pub struct GGMLSpecificConfig {
     pub gqa: usize,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    /// Dtype the KV cache is stored in.
+    pub kv_cache_dtype: KVCacheDtype,
     pub topology: Option<Topology>,
 }
 
@@ -357,10 +360,13 @@ impl Loader for GGMLLoader {
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
-        let num_hidden_layers = match model {
-            Model::Llama(ref model) => model.cache.lock().len(),
-            Model::XLoraLlama(ref model) => model.cache.lock().len(),
+        let cache = match model {
+            Model::Llama(ref model) => &model.cache,
+            Model::XLoraLlama(ref model) => &model.cache,
         };
+        let num_hidden_layers = cache.lock().len();
+        let kv_cache_dtype = self.config.kv_cache_dtype.for_pipeline(false);
+        cache.set_kv_cache_dtype(kv_cache_dtype);
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         Ok(Arc::new(Mutex::new(GGMLPipeline {
             model,
@@ -384,9 +390,15 @@ impl Loader for GGMLLoader {
                 is_xlora,
                 activation_dtype: DType::F32,
                 sliding_window: None,
+                kv_cache_dtype,
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: self.config.prompt_batchsize,
+                speculative_accepted_tokens: AtomicUsize::new(0),
+                speculative_drafted_tokens: AtomicUsize::new(0),
+                speculative_draft_calls: AtomicUsize::new(0),
+                per_request_adapter_override: self.kind.is_adapted_and(|a| a.is_lora()),
+                pending_adapter_swaps: Default::default(),
             }),
         })))
     }
@@ -475,7 +487,10 @@ impl CacheManagerMixin for GGMLPipeline {
 }
 
 impl AdapterActivationMixin for GGMLPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_weighted_adapters(
+        &mut self,
+        adapter_names: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
         let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
         if !is_lora {
             anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
@@ -488,6 +503,19 @@ impl AdapterActivationMixin for GGMLPipeline {
             _ => unreachable!(),
         }
     }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .merge_adapters(adapter_names)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
 }
 
 impl MetadataMixin for GGMLPipeline {
@@ -570,6 +598,9 @@ impl Pipeline for GGMLPipeline {
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn supports_beam_search(&self) -> bool {
+        true
+    }
 }
 
 // TODO
@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource, XLoraPaths,
+    CacheManager, GeneralMetadata, KVCacheDtype, Loader, ModelKind, ModelPaths, PrettyName,
+    QuantizationKind, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
@@ -14,6 +14,7 @@ use crate::gguf::{
     get_gguf_chat_template, {convert_gguf_to_hf_tokenizer, GgufTokenizerConversion},
 };
 use crate::gguf::{Content, GGUFArchitecture};
+use crate::layers::RopeScalingConfig;
 use crate::lora::Ordering;
 use crate::paged_attention::{
     calculate_cache_config, AttentionImplementation, CacheEngine, ModelConfigLike,
@@ -52,6 +53,7 @@ use std::fs;
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -89,12 +91,15 @@ pub struct GGUFLoader {
     kind: ModelKind,
     tgt_non_granular_index: Option<usize>,
     config: GGUFSpecificConfig,
+    rope_scaling: Option<RopeScalingConfig>,
 }
 
 #[derive(Clone, Default)]
 /// Config for a GGUF loader.
 pub struct GGUFSpecificConfig {
     pub prompt_batchsize: Option<NonZeroUsize>,
+    /// Dtype the KV cache is stored in. With PagedAttention, only FP8 is supported.
+    pub kv_cache_dtype: KVCacheDtype,
     pub topology: Option<Topology>,
 }
 
@@ -111,6 +116,7 @@ pub struct GGUFLoaderBuilder {
     chat_template: Option<String>,
     tgt_non_granular_index: Option<usize>,
     config: GGUFSpecificConfig,
+    rope_scaling: Option<RopeScalingConfig>,
 }
 
 impl GGUFLoaderBuilder {
@@ -147,6 +153,14 @@ impl GGUFLoaderBuilder {
         self
     }
 
+    /// Scale the RoPE frequencies to extend the context window, this is written into the
+    /// `rope.scaling` metadata of the GGUF file before loading. Only the `llama` architecture
+    /// supports it.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScalingConfig) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
     fn with_adapter(
         mut self,
         xlora_model_id: String,
@@ -205,6 +219,7 @@ impl GGUFLoaderBuilder {
             quantized_filenames: self.quantized_filenames,
             quantized_model_id: self.quantized_model_id,
             config: self.config,
+            rope_scaling: self.rope_scaling,
         })
     }
 }
@@ -245,6 +260,7 @@ impl GGUFLoader {
             kind,
             tgt_non_granular_index,
             config,
+            rope_scaling: None,
         }
     }
 }
@@ -340,6 +356,13 @@ impl Loader for GGUFLoader {
                 "You are trying to in-situ quantize a GGUF model. This will not do anything."
             );
         }
+        if mapper.is_paged() {
+            anyhow::bail!("Paged device mapping is not supported for GGUF models.");
+        }
+        if mapper.is_tensor_parallel() {
+            anyhow::bail!("Tensor parallelism is not supported for GGUF models.");
+        }
+
         // Otherwise, the device mapper will print it
         if mapper.is_dummy()
             && (self.config.topology.is_none()
@@ -365,7 +388,10 @@ impl Loader for GGUFLoader {
         }
         let mut readers = readers.iter_mut().collect::<Vec<_>>();
 
-        let model = Content::from_readers(&mut readers)?;
+        let mut model = Content::from_readers(&mut readers)?;
+        if let Some(rope_scaling) = &self.rope_scaling {
+            model.set_rope_scaling(rope_scaling)?;
+        }
         model.print_metadata()?;
         let arch = model.arch();
 
@@ -454,17 +480,26 @@ impl Loader for GGUFLoader {
             _ => unreachable!(),
         };
 
+        let kv_cache_dtype = self
+            .config
+            .kv_cache_dtype
+            .for_pipeline(paged_attn_config.is_some());
         let (cache_config, cache_engine) = if let Some(paged_attn_config) = paged_attn_config {
             let model_config: &dyn ModelConfigLike = &model_config_metadata;
             let cache_config = calculate_cache_config(
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
-                DType::F32,
+                kv_cache_dtype.paged_cache_dtype(DType::F32),
+                model_config,
+                device,
+            )?;
+            let cache_engine = CacheEngine::new(
                 model_config,
+                &cache_config,
+                kv_cache_dtype.paged_cache_dtype(DType::F32),
                 device,
             )?;
-            let cache_engine = CacheEngine::new(model_config, &cache_config, DType::F32, device)?;
             (Some(cache_config), Some(cache_engine))
         } else {
             (None, None)
@@ -485,15 +520,17 @@ impl Loader for GGUFLoader {
             Model::Qwen2(ref p) => p.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
-        let num_hidden_layers = match model {
-            Model::Llama(ref model) => model.cache.lock().len(),
-            Model::Phi2(ref model) => model.cache.lock().len(),
-            Model::XLoraLlama(ref model) => model.cache.lock().len(),
-            Model::Phi3(ref model) => model.cache.lock().len(),
-            Model::XLoraPhi3(ref model) => model.cache.lock().len(),
-            Model::Starcoder2(ref model) => model.cache.lock().len(),
-            Model::Qwen2(ref model) => model.cache.lock().len(),
+        let cache = match model {
+            Model::Llama(ref model) => &model.cache,
+            Model::Phi2(ref model) => &model.cache,
+            Model::XLoraLlama(ref model) => &model.cache,
+            Model::Phi3(ref model) => &model.cache,
+            Model::XLoraPhi3(ref model) => &model.cache,
+            Model::Starcoder2(ref model) => &model.cache,
+            Model::Qwen2(ref model) => &model.cache,
         };
+        let num_hidden_layers = cache.lock().len();
+        cache.set_kv_cache_dtype(kv_cache_dtype);
 
         if chat_template.bos_token.is_none() && bos.is_some() {
             chat_template.bos_token = Some(BeginEndUnkTok(Either::Left(bos.unwrap())));
@@ -531,9 +568,15 @@ impl Loader for GGUFLoader {
                 is_xlora,
                 activation_dtype: DType::F32,
                 sliding_window: None,
+                kv_cache_dtype,
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                speculative_accepted_tokens: AtomicUsize::new(0),
+                speculative_drafted_tokens: AtomicUsize::new(0),
+                speculative_draft_calls: AtomicUsize::new(0),
+                per_request_adapter_override: self.kind.is_adapted_and(|a| a.is_lora()),
+                pending_adapter_swaps: Default::default(),
             }),
         })))
     }
@@ -594,7 +637,10 @@ impl CacheManagerMixin for GGUFPipeline {
 }
 
 impl AdapterActivationMixin for GGUFPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_weighted_adapters(
+        &mut self,
+        adapter_names: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
         let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
         if !is_lora {
             anyhow::bail!("Activating adapters is only supported for models fine-tuned with LoRA.")
@@ -610,6 +656,22 @@ impl AdapterActivationMixin for GGUFPipeline {
             _ => unreachable!(),
         }
     }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .merge_adapters(adapter_names)
+                .map_err(anyhow::Error::msg),
+            Model::XLoraPhi3(ref mut model) => model
+                .merge_adapters(adapter_names)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
 }
 
 impl MetadataMixin for GGUFPipeline {
@@ -744,6 +806,9 @@ impl Pipeline for GGUFPipeline {
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn supports_beam_search(&self) -> bool {
+        true
+    }
 }
 
 // TODO
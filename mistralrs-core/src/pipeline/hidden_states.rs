@@ -0,0 +1,155 @@
+use std::cell::RefCell;
+
+use candle_core::{Result, Tensor};
+
+/// Which hidden states [`Pipeline::get_hidden_states`](super::Pipeline::get_hidden_states) returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HiddenStateLayer {
+    /// Output of the last decoder layer, before the final norm.
+    LastLayer,
+    /// Input of the LM head, which is the output of the final norm.
+    BeforeHead,
+    /// Output of the decoder layer with this index, starting at 0.
+    LayerIndex(usize),
+}
+
+/// How the hidden states of the tokens of one input are reduced to a single embedding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmbeddingPooling {
+    /// Mean of the hidden states of all tokens.
+    #[default]
+    Mean,
+    /// Hidden state of the last token, which has attended to the whole input.
+    LastToken,
+}
+
+impl EmbeddingPooling {
+    /// Pool `hidden_states` of shape `(seq_len, hidden_size)` into an embedding of shape
+    /// `(hidden_size,)`.
+    pub fn pool(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let (seq_len, _) = hidden_states.dims2()?;
+        if seq_len == 0 {
+            candle_core::bail!("Cannot pool the hidden states of an empty input.");
+        }
+        match self {
+            Self::Mean => hidden_states.mean(0),
+            Self::LastToken => hidden_states.get(seq_len - 1),
+        }
+    }
+}
+
+/// Captures the hidden states requested during one forward pass.
+struct HiddenStateSink {
+    layer: HiddenStateLayer,
+    captured: Option<Tensor>,
+}
+
+thread_local! {
+    static HIDDEN_STATE_SINK: RefCell<Option<HiddenStateSink>> = const { RefCell::new(None) };
+}
+
+/// Run `forward` on this thread, capturing the hidden states of `layer` that the model reports via
+/// [`capture_layer_output`] and [`capture_before_head`].
+pub(crate) fn with_hidden_state_capture<T>(
+    layer: HiddenStateLayer,
+    forward: impl FnOnce() -> Result<T>,
+) -> Result<(T, Option<Tensor>)> {
+    HIDDEN_STATE_SINK.with_borrow_mut(|sink| {
+        *sink = Some(HiddenStateSink {
+            layer,
+            captured: None,
+        })
+    });
+    let out = forward();
+    let captured = HIDDEN_STATE_SINK
+        .with_borrow_mut(|sink| sink.take())
+        .and_then(|sink| sink.captured);
+    Ok((out?, captured))
+}
+
+/// Report the output of decoder layer `layer_idx` of `n_layers`. This is a no-op unless the
+/// hidden states are being captured.
+pub(crate) fn capture_layer_output(layer_idx: usize, n_layers: usize, xs: &Tensor) {
+    HIDDEN_STATE_SINK.with_borrow_mut(|sink| {
+        if let Some(sink) = sink {
+            let matches = match sink.layer {
+                HiddenStateLayer::LastLayer => layer_idx + 1 == n_layers,
+                HiddenStateLayer::LayerIndex(idx) => layer_idx == idx,
+                HiddenStateLayer::BeforeHead => false,
+            };
+            if matches {
+                sink.captured = Some(xs.clone());
+            }
+        }
+    })
+}
+
+/// Report the input of the LM head. This is a no-op unless the hidden states are being captured.
+pub(crate) fn capture_before_head(xs: &Tensor) {
+    HIDDEN_STATE_SINK.with_borrow_mut(|sink| {
+        if let Some(sink) = sink {
+            if sink.layer == HiddenStateLayer::BeforeHead {
+                sink.captured = Some(xs.clone());
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{
+        capture_before_head, capture_layer_output, with_hidden_state_capture, EmbeddingPooling,
+        HiddenStateLayer,
+    };
+
+    /// A model with 3 layers whose hidden states are the layer index, and 100 before the head.
+    fn forward() -> candle_core::Result<()> {
+        for layer in 0..3u32 {
+            capture_layer_output(layer as usize, 3, &Tensor::new(&[layer], &Device::Cpu)?);
+        }
+        capture_before_head(&Tensor::new(&[100u32], &Device::Cpu)?);
+        Ok(())
+    }
+
+    fn capture(layer: HiddenStateLayer) -> candle_core::Result<Option<Vec<u32>>> {
+        let ((), captured) = with_hidden_state_capture(layer, forward)?;
+        captured.map(|xs| xs.to_vec1::<u32>()).transpose()
+    }
+
+    #[test]
+    fn test_hidden_state_capture() -> candle_core::Result<()> {
+        assert_eq!(capture(HiddenStateLayer::LastLayer)?, Some(vec![2]));
+        assert_eq!(capture(HiddenStateLayer::BeforeHead)?, Some(vec![100]));
+        assert_eq!(capture(HiddenStateLayer::LayerIndex(1))?, Some(vec![1]));
+        assert_eq!(capture(HiddenStateLayer::LayerIndex(3))?, None);
+        // Outside of a capture, reporting hidden states does nothing.
+        forward()?;
+        assert_eq!(capture(HiddenStateLayer::LayerIndex(3))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedding_pooling() -> candle_core::Result<()> {
+        const HIDDEN_SIZE: usize = 4;
+        let hidden_states = Tensor::arange(0f32, (3 * HIDDEN_SIZE) as f32, &Device::Cpu)?
+            .reshape((3, HIDDEN_SIZE))?;
+        for (pooling, expected) in [
+            (EmbeddingPooling::Mean, [4., 5., 6., 7.]),
+            (EmbeddingPooling::LastToken, [8., 9., 10., 11.]),
+        ] {
+            let embedding = pooling.pool(&hidden_states)?.to_vec1::<f32>()?;
+            assert_eq!(embedding.len(), HIDDEN_SIZE);
+            assert_eq!(embedding, expected);
+            // Identical inputs give identical embeddings.
+            assert_eq!(
+                pooling.pool(&hidden_states.copy()?)?.to_vec1::<f32>()?,
+                embedding
+            );
+        }
+        let empty = Tensor::zeros((0, HIDDEN_SIZE), candle_core::DType::F32, &Device::Cpu)?;
+        assert!(EmbeddingPooling::Mean.pool(&empty).is_err());
+        Ok(())
+    }
+}
@@ -102,6 +102,18 @@ pub mod text_models_inputs_processor {
         pub cumulative_seqlens_k: Tensor,
     }
 
+    impl FlashParams {
+        /// The same parameters with the cumulative sequence lengths on `device`.
+        pub fn to_device(&self, device: &Device) -> candle_core::Result<Self> {
+            Ok(Self {
+                max_q: self.max_q,
+                max_k: self.max_k,
+                cumulative_seqlens_q: self.cumulative_seqlens_q.to_device(device)?,
+                cumulative_seqlens_k: self.cumulative_seqlens_k.to_device(device)?,
+            })
+        }
+    }
+
     pub struct InputMetadata {
         pub input: Tensor,
         pub positions: Vec<usize>,
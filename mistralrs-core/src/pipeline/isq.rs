@@ -12,14 +12,14 @@ use anyhow::Result;
 use candle_core::{Context, Device, Tensor};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use mistralrs_quant::{
-    FP8Linear, GgufMatMul, HqqLayer, IsqType, QuantMethod, QuantizedSerde, QuantizedSerdeType,
-    UnquantLinear,
+    artifact_version, FP8Linear, GgufMatMul, HqqLayer, IsqType, QuantMethod, QuantizedSerde,
+    QuantizedSerdeType, UnquantLinear, HQFF_VERSION_VISION_ISQ,
 };
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use regex::Regex;
 use serde::Deserialize;
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{device_map::DeviceMapper, topology::LayerTopology, Topology};
 
@@ -43,6 +43,7 @@ pub(crate) const UQFF_RESIDUAL_SAFETENSORS: &str = "residual.safetensors";
 /// - `HQQ3`
 /// - `HQQ4`
 /// - `HQQ8`
+/// - `FP8`
 pub fn parse_isq_value(s: &str) -> Result<IsqType, String> {
     let tp = match s.to_lowercase().as_str() {
         "q4_0" => IsqType::Q4_0,
@@ -59,11 +60,11 @@ pub fn parse_isq_value(s: &str) -> Result<IsqType, String> {
         "q8k" => IsqType::Q8K,
         "hqq8" => IsqType::HQQ8,
         "hqq4" => IsqType::HQQ4,
+        "hqq3" => IsqType::HQQ3,
+        "hqq2" => IsqType::HQQ2,
+        "hqq1" => IsqType::HQQ1,
         "fp8" => IsqType::F8E4M3,
-        // "hqq3" => IsqType::HQQ3,
-        // "hqq2" => IsqType::HQQ2,
-        // "hqq1" => IsqType::HQQ1,
-        _ => return Err(format!("ISQ type {s} unknown, choose one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q8_1`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `Q8K`, `HQQ8`, `HQQ4`, `FP8`.")),
+        _ => return Err(format!("ISQ type {s} unknown, choose one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q8_1`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `Q8K`, `HQQ8`, `HQQ4`, `HQQ3`, `HQQ2`, `HQQ1`, `FP8`.")),
     };
     #[cfg(feature = "cuda")]
     {
@@ -81,11 +82,12 @@ pub fn parse_isq_value(s: &str) -> Result<IsqType, String> {
                 | IsqType::Q6K
                 | IsqType::HQQ8
                 | IsqType::HQQ4
-                | IsqType::F8E4M3 // | IsqType::HQQ3
-                                  // | IsqType::HQQ2
-                                  // | IsqType::HQQ1
+                | IsqType::HQQ3
+                | IsqType::HQQ2
+                | IsqType::HQQ1
+                | IsqType::F8E4M3
         ) {
-            return Err("ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `FP8`".to_string());
+            return Err("ISQ type on CUDA must be one of `Q4_0`, `Q4_1`, `Q5_0`, `Q5_1`, `Q8_0`, `Q2K`, `Q3K`, `Q4K`, `Q5K`, `Q6K`, `HQQ8`, `HQQ4`, `HQQ3`, `HQQ2`, `HQQ1`, `FP8`".to_string());
         }
     }
     Ok(tp)
@@ -146,18 +148,57 @@ pub trait IsqModel {
         self.get_layers()
     }
 
+    /// Checkpoint names of the layers returned by [`get_layers`](IsqModel::get_layers), in the same
+    /// order. These are matched against the ISQ exclusion patterns; `None` if the model does not
+    /// provide them.
+    fn get_layer_names(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Dequantized weight of the layer with this checkpoint name, see
+    /// [`get_layer_names`](IsqModel::get_layer_names).
+    fn get_layer_weights(&mut self, name: &str) -> candle_core::Result<Tensor> {
+        let Some(names) = self.get_layer_names() else {
+            candle_core::bail!("This model does not provide layer names.");
+        };
+        let Some(idx) = names.iter().position(|n| n == name) else {
+            candle_core::bail!("No layer named `{name}`.");
+        };
+        let (layers, _) = self.get_layers();
+        match layers.get(idx) {
+            Some((layer, _)) => layer.dequantize_w(),
+            None => candle_core::bail!("Expected {} layers, got {}.", names.len(), layers.len()),
+        }
+    }
+
     /// Residual tensors for generating a UQFF file. Counterpart to [`get_layers`].
     fn residual_tensors(&self) -> Vec<(String, Tensor)>;
 
+    /// Whether [`get_layers`](IsqModel::get_layers) returns the vision encoder and projector
+    /// layers, which UQFF files older than [`HQFF_VERSION_VISION_ISQ`] do not contain.
+    fn has_vision_isq_layers(&self) -> bool {
+        false
+    }
+
     /// Residual tensors for generating a UQFF file. Counterpart to [`get_layers_moe_experts_only`].
     fn residual_tensors_moe_experts_only(&self) -> Option<Vec<(String, Tensor)>> {
         None
     }
 
+    /// Hand the layers to the device mapper, which may move them between devices while running.
+    fn page_layers(&mut self) -> candle_core::Result<()> {
+        let (tensors, mapper) = self.get_layers();
+        mapper.page_layers(tensors)
+    }
+
     /// Quantize the model in-situ.
     ///
     /// This function will also create a UQFF file, or, if the model supports it (residual tensors are returned),
     /// a full serialization is created.
+    ///
+    /// Layers whose name (see [`get_layer_names`](IsqModel::get_layer_names)) matches any of the
+    /// `exclude` regexes are kept unquantized. Otherwise, the first regex of `layer_types` matching
+    /// the name selects the ISQ type, taking precedence over the topology and `dtype`.
     #[allow(clippy::too_many_arguments)]
     fn quantize(
         &mut self,
@@ -166,16 +207,52 @@ pub trait IsqModel {
         topology: Option<&Topology>,
         silent: bool,
         organization: IsqOrganization,
+        exclude: &[String],
+        layer_types: &[(String, IsqType)],
         write_artifacts: Option<&PathBuf>,
         full_ser: UqffFullSer<'_>,
     ) -> candle_core::Result<()> {
         {
+            // Exclusions are matched first, and select no ISQ type
+            let patterns = exclude
+                .iter()
+                .map(|pat| (pat, None))
+                .chain(layer_types.iter().map(|(pat, ty)| (pat, Some(*ty))))
+                .map(|(pat, ty)| {
+                    Regex::new(pat)
+                        .map(|re| (re, ty))
+                        .map_err(candle_core::Error::msg)
+                })
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            let names = if !patterns.is_empty() && matches!(organization, IsqOrganization::Default)
+            {
+                self.get_layer_names()
+            } else {
+                None
+            };
             let (mut tensors, mapper) = match organization {
                 IsqOrganization::Default => self.get_layers(),
                 IsqOrganization::MoeExpertsOnly => self.get_layers_moe_experts_only(),
             };
 
             let total_tensors = tensors.len();
+            let name_dtypes = match names {
+                Some(names) if names.len() == total_tensors => names
+                    .iter()
+                    .map(|name| {
+                        patterns
+                            .iter()
+                            .find(|(re, _)| re.is_match(name))
+                            .map(|(_, ty)| *ty)
+                    })
+                    .collect::<Vec<_>>(),
+                _ => {
+                    if !patterns.is_empty() {
+                        warn!("This model does not provide ISQ layer names, ignoring the ISQ layer patterns.");
+                    }
+                    vec![None; total_tensors]
+                }
+            };
             let n_quantized = AtomicUsize::new(0);
             if let Some(topology) = topology {
                 let mut dtypes = HashSet::new();
@@ -207,7 +284,7 @@ pub trait IsqModel {
             });
 
             let mut devices_and_dtypes = Vec::new();
-            for (_, layer_num) in &tensors {
+            for ((_, layer_num), name_dtype) in tensors.iter().zip(name_dtypes) {
                 let device = if let Some(ref layers) = layers {
                     if let Some(layer) = layer_num {
                         layers
@@ -236,6 +313,7 @@ pub trait IsqModel {
                 } else {
                     dtype
                 };
+                let dtype = name_dtype.unwrap_or(dtype);
                 devices_and_dtypes.push((device, dtype));
             }
 
@@ -249,8 +327,9 @@ pub trait IsqModel {
                 let current_rayon_threads = rayon::current_num_threads();
                 tensors
                     .iter()
-                    .map(|(q, _)| {
-                        if let Some(dtype) = dtype {
+                    .zip(&devices_and_dtypes)
+                    .map(|((q, _), (_, dtype))| {
+                        if let Some(dtype) = *dtype {
                             q.get_max_isq_cpu_threads(dtype)
                                 .map(usize::from)
                                 .unwrap_or(current_rayon_threads)
@@ -458,6 +537,7 @@ pub trait IsqModel {
         silent: bool,
         artifacts: &PathBuf,
     ) -> candle_core::Result<()> {
+        let has_vision_isq_layers = self.has_vision_isq_layers();
         let (tensors, mapper) = self.get_layers();
         let total_tensors = tensors.len();
 
@@ -505,6 +585,16 @@ pub trait IsqModel {
             })
             .collect::<HashMap<_, _>>();
 
+        if has_vision_isq_layers {
+            if let Some(artifact) = artifact_isqs.values().next() {
+                if artifact_version(artifact.data())? < HQFF_VERSION_VISION_ISQ {
+                    candle_core::bail!(
+                        "This UQFF file was made before the vision encoder and projector of vision models were quantized, so it does not contain these layers. Regenerate it from the original model with `--write-uqff`."
+                    );
+                }
+            }
+        }
+
         if artifact_isqs.len() != total_tensors {
             candle_core::bail!(
                 "Number of artifacts ({}) does not match the number of ISQ layers ({total_tensors})",
@@ -604,3 +694,232 @@ pub(crate) trait IsqModelLoader {
         self.isq_layer_regexes(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::Linear;
+    use mistralrs_quant::{IsqType, QuantMethod, QuantMethodConfig, UnquantLinear};
+    use tokenizers::{models::bpe::BPE, Tokenizer};
+
+    use super::{IsqModel, IsqOrganization, UqffFullSer};
+    use crate::{device_map::DeviceMapper, DeviceMapMetadata};
+
+    struct TestModel {
+        layers: Vec<Arc<dyn QuantMethod>>,
+        names: Vec<String>,
+        mapper: Box<dyn DeviceMapper + Send + Sync>,
+        vision: bool,
+    }
+
+    impl IsqModel for TestModel {
+        fn get_layers(
+            &mut self,
+        ) -> (
+            Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+            &dyn DeviceMapper,
+        ) {
+            let layers = self
+                .layers
+                .iter_mut()
+                .enumerate()
+                .map(|(i, layer)| (layer, Some(i)))
+                .collect();
+            (layers, &*self.mapper)
+        }
+
+        fn get_layer_names(&self) -> Option<Vec<String>> {
+            Some(self.names.clone())
+        }
+
+        fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+            Vec::new()
+        }
+
+        fn has_vision_isq_layers(&self) -> bool {
+            self.vision
+        }
+    }
+
+    #[test]
+    fn test_isq_exclusions_stay_unquantized() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let names = [
+            "lm_head",
+            "model.layers.0.self_attn.q_proj",
+            "model.layers.0.mlp.down_proj",
+            "model.layers.1.self_attn.q_proj",
+        ];
+        let layers = names
+            .iter()
+            .map(|_| {
+                let w = Tensor::randn(0f32, 1f32, (32, 64), &dev)?;
+                Ok(Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                    Linear::new(w, None),
+                ))?) as Arc<dyn QuantMethod>)
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let mut model = TestModel {
+            layers,
+            names: names.iter().map(ToString::to_string).collect(),
+            mapper: DeviceMapMetadata::dummy().into_mapper(names.len(), &dev, None)?,
+            vision: false,
+        };
+
+        let tokenizer = Tokenizer::new(BPE::default());
+        model.quantize(
+            Some(IsqType::Q4_0),
+            dev.clone(),
+            None,
+            true,
+            IsqOrganization::Default,
+            &["^lm_head$".to_string(), r"layers\.0\.self_attn".to_string()],
+            &[],
+            None,
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: String::new(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+            },
+        )?;
+
+        let kinds = model.layers.iter().map(|l| l.name()).collect::<Vec<_>>();
+        assert_eq!(kinds, ["unquant-linear", "unquant-linear", "gguf", "gguf"]);
+        assert_eq!(model.layers[0].dtype_and_device().0, DType::F32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_layer_weights() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let names = [
+            "model.layers.0.self_attn.q_proj",
+            "model.layers.0.mlp.down_proj",
+        ];
+        let weights = names
+            .iter()
+            .map(|_| Tensor::randn(0f32, 1f32, (32, 64), &dev))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let layers = weights
+            .iter()
+            .map(|w| {
+                Ok(Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                    Linear::new(w.clone(), None),
+                ))?) as Arc<dyn QuantMethod>)
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let mut model = TestModel {
+            layers,
+            names: names.iter().map(ToString::to_string).collect(),
+            mapper: DeviceMapMetadata::dummy().into_mapper(names.len(), &dev, None)?,
+            vision: false,
+        };
+
+        let w = model.get_layer_weights("model.layers.0.mlp.down_proj")?;
+        let diff = (w - &weights[1])?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert_eq!(diff, 0.0);
+        assert!(model.get_layer_weights("lm_head").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_isq_layer_types() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let names = [
+            "lm_head",
+            "model.layers.0.self_attn.q_proj",
+            "model.layers.0.mlp.down_proj",
+        ];
+        let layers = names
+            .iter()
+            .map(|_| {
+                let w = Tensor::randn(0f32, 1f32, (32, 256), &dev)?;
+                Ok(Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                    Linear::new(w, None),
+                ))?) as Arc<dyn QuantMethod>)
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let mut model = TestModel {
+            layers,
+            names: names.iter().map(ToString::to_string).collect(),
+            mapper: DeviceMapMetadata::dummy().into_mapper(names.len(), &dev, None)?,
+            vision: false,
+        };
+
+        let tokenizer = Tokenizer::new(BPE::default());
+        model.quantize(
+            Some(IsqType::Q4_0),
+            dev.clone(),
+            None,
+            true,
+            IsqOrganization::Default,
+            &[],
+            &[
+                ("self_attn".to_string(), IsqType::Q8_0),
+                ("mlp".to_string(), IsqType::Q4K),
+            ],
+            None,
+            UqffFullSer {
+                tokenizer: &tokenizer,
+                template_filename: &None,
+                generation_config: None,
+                config: String::new(),
+                processor_filename: &None,
+                preprocessor_filename: &None,
+            },
+        )?;
+
+        // The GGML dtype is serialized as a u32 after the version, type, length and bias fields.
+        let dtypes = model
+            .layers
+            .iter()
+            .map(|l| {
+                let data = l.serialize()?;
+                Ok(u32::from_le_bytes(data[10..14].try_into().unwrap()))
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        assert_eq!(dtypes, [2, 8, 12]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_old_uqff_rejected_for_vision_layers() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let w = Tensor::randn(0f32, 1f32, (32, 64), &dev)?;
+        let layer = Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+            Linear::new(w, None),
+        ))?) as Arc<dyn QuantMethod>;
+        let mut artifact = layer.serialize()?.into_owned();
+        // UQFF v0.1.4, made before the vision layers were quantized.
+        artifact[..4].copy_from_slice(&((1u32 << 8) | 4).to_le_bytes());
+        let path = std::env::temp_dir().join(format!("vision_isq_{}.uqff", std::process::id()));
+        safetensors::serialize_to_file(
+            vec![("0".to_string(), Tensor::new(artifact, &dev)?)],
+            &None,
+            &path,
+        )?;
+
+        let new_model = |vision| -> candle_core::Result<TestModel> {
+            Ok(TestModel {
+                layers: vec![layer.clone()],
+                names: vec!["model.layers.0.mlp.down_proj".to_string()],
+                mapper: DeviceMapMetadata::dummy().into_mapper(1, &dev, None)?,
+                vision,
+            })
+        };
+        let err = new_model(true)?
+            .load_from_artifacts(dev.clone(), None, true, &path)
+            .unwrap_err();
+        assert!(err.to_string().contains("Regenerate it"));
+        // Models without vision layers still load older files.
+        new_model(false)?.load_from_artifacts(dev.clone(), None, true, &path)?;
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
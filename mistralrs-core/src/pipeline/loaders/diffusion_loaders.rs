@@ -36,10 +36,12 @@ use crate::{
 
 pub trait DiffusionModel {
     /// This returns a tensor of shape (bs, c, h, w), with values in [0, 255].
+    /// `on_step(step, total_steps)` is called after each denoising step.
     fn forward(
         &mut self,
         prompts: Vec<String>,
         params: DiffusionGenerationParams,
+        on_step: &mut dyn FnMut(usize, usize),
     ) -> candle_core::Result<Tensor>;
     fn device(&self) -> &Device;
     fn max_seq_len(&self) -> usize;
@@ -257,8 +257,17 @@ pub enum ModelKind {
         draft: Box<ModelKind>,
     },
 
+    #[strum(to_string = "contrastive: expert: `{expert}`, amateur: `{amateur}`")]
+    Contrastive {
+        expert: Box<ModelKind>,
+        amateur: Box<ModelKind>,
+    },
+
     #[strum(to_string = "anymoe: target: `{target}`")]
     AnyMoe { target: Box<ModelKind> },
+
+    #[strum(to_string = "prompt lookup: target: `{target}`")]
+    PromptLookup { target: Box<ModelKind> },
 }
 
 #[derive(Clone, Copy, strum::Display, strum::EnumIs, strum::EnumMessage)]
@@ -318,7 +327,13 @@ impl ModelKind {
 
                 [t.quantized_kind(), d.quantized_kind()].concat()
             }
-            AnyMoe { target } => target.quantized_kind(),
+            Contrastive { expert, amateur } => {
+                let e = *expert.clone();
+                let a = *amateur.clone();
+
+                [e.quantized_kind(), a.quantized_kind()].concat()
+            }
+            AnyMoe { target } | PromptLookup { target } => target.quantized_kind(),
         }
     }
 
@@ -343,7 +358,13 @@ impl ModelKind {
 
                 [t.adapted_kind(), d.adapted_kind()].concat()
             }
-            AnyMoe { target } => target.adapted_kind(),
+            Contrastive { expert, amateur } => {
+                let e = *expert.clone();
+                let a = *amateur.clone();
+
+                [e.adapted_kind(), a.adapted_kind()].concat()
+            }
+            AnyMoe { target } | PromptLookup { target } => target.adapted_kind(),
         }
     }
 }
@@ -7,7 +7,7 @@ use std::{
 use crate::{
     amoe::AnyMoeBaseModelMixin,
     device_map::DeviceMapper,
-    layers::{Activation, Llama3RopeConfig, PhiRopeScalingConfig},
+    layers::{Activation, LlamaRopeScalingConfig, PhiRopeScalingConfig, RopeScalingConfig},
     lora::{LoraConfig, Ordering},
     paged_attention::{AttentionImplementation, ModelConfigMetadata},
     pipeline::{
@@ -67,12 +67,26 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
     fn max_seq_len(&self) -> usize;
-    fn activate_adapters(&mut self, _: Vec<String>) -> candle_core::Result<usize> {
+    /// Activate the adapters, scaling the delta of each adapter by its weight.
+    fn activate_adapters(&mut self, _: Vec<(String, f64)>) -> candle_core::Result<usize> {
         // NOTE: While X-LoRA shares a similar name, it is not equivalent. Its adapter set must remain the same.
         candle_core::bail!(
             "Activating adapters is only supported for models fine-tuned with LoRA."
         );
     }
+    /// Select the adapters of each sequence in the batch, `None` meaning the activated adapters.
+    /// An empty list returns every sequence to the activated adapters.
+    fn activate_adapters_per_seq(
+        &mut self,
+        _: Vec<Option<Vec<String>>>,
+    ) -> candle_core::Result<usize> {
+        candle_core::bail!(
+            "Activating adapters per sequence is only supported for models fine-tuned with LoRA."
+        );
+    }
+    fn merge_adapters(&mut self, _: Vec<String>) -> candle_core::Result<usize> {
+        candle_core::bail!("Merging adapters is only supported for models fine-tuned with LoRA.");
+    }
     fn config(&self) -> &ModelConfigMetadata;
 }
 
@@ -111,6 +125,10 @@ pub trait NormalModelLoader: IsqModelLoader {
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>>;
     /// Get total num_hidden_layers for the layers which will be device mapped.
     fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize>;
+    /// Whether the model can split its repeating layers across devices with tensor parallelism.
+    fn supports_tensor_parallel(&self, _config: &str) -> Result<bool> {
+        Ok(false)
+    }
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
@@ -275,6 +293,9 @@ impl NormalModelLoader for AutoLoader {
     fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
         Self::get_loader(config)?.get_total_device_mapping_num_layers(config)
     }
+    fn supports_tensor_parallel(&self, config: &str) -> Result<bool> {
+        Self::get_loader(config)?.supports_tensor_parallel(config)
+    }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
         Self::get_loader(config)?.get_config_repr(config, use_flash_attn)
     }
@@ -310,6 +331,7 @@ struct MistralBasicConfig {
     quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
+    rope_scaling: Option<RopeScalingConfig>,
 }
 
 impl MistralBasicConfig {
@@ -331,6 +353,7 @@ impl MistralBasicConfig {
             head_dim: basic_config.head_dim,
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
+            rope_scaling: basic_config.rope_scaling,
         })
     }
 }
@@ -549,7 +572,7 @@ struct LlamaBasicConfig {
     #[serde(default = "default_rope")]
     rope_theta: f32,
     max_position_embeddings: usize,
-    rope_scaling: Option<Llama3RopeConfig>,
+    rope_scaling: Option<LlamaRopeScalingConfig>,
     quantization_config: Option<QuantizedConfig>,
     #[serde(default = "word_emb_default")]
     tie_word_embeddings: bool,
@@ -638,6 +661,9 @@ impl NormalModelLoader for LlamaLoader {
     fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
         Ok(LlamaBasicConfig::deserialize(config, false)?.num_hidden_layers)
     }
+    fn supports_tensor_parallel(&self, _config: &str) -> Result<bool> {
+        Ok(true)
+    }
 }
 
 impl IsqModelLoader for LlamaLoader {
@@ -1028,11 +1054,14 @@ struct Qwen2BasicConfig {
     num_attention_heads: usize,
     num_key_value_heads: usize,
     max_position_embeddings: usize,
-    sliding_window: usize,
+    sliding_window: Option<usize>,
+    #[serde(default)]
+    use_sliding_window: bool,
     rope_theta: f64,
     rms_norm_eps: f64,
     hidden_act: Activation,
     quantization_config: Option<QuantizedConfig>,
+    #[serde(default)]
     tie_word_embeddings: bool,
 }
 
@@ -1050,7 +1079,11 @@ impl Qwen2BasicConfig {
             max_position_embeddings: basic_config.max_position_embeddings,
             rope_theta: basic_config.rope_theta,
             rms_norm_eps: basic_config.rms_norm_eps,
-            sliding_window: basic_config.sliding_window,
+            // The window only applies if `use_sliding_window` is set, which the checkpoints do not.
+            sliding_window: match basic_config.sliding_window {
+                Some(window) if basic_config.use_sliding_window => window,
+                _ => basic_config.max_position_embeddings,
+            },
             use_flash_attn,
             quantization_config: basic_config.quantization_config,
             tie_word_embeddings: basic_config.tie_word_embeddings,
@@ -1512,3 +1545,73 @@ impl IsqModelLoader for Phi3_5MoELoader {
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Qwen2BasicConfig;
+
+    #[test]
+    fn test_qwen2_config() -> anyhow::Result<()> {
+        // From Qwen/Qwen2-0.5B-Instruct, which ties the word embeddings.
+        let small = r#"{
+            "architectures": ["Qwen2ForCausalLM"],
+            "hidden_act": "silu",
+            "hidden_size": 896,
+            "intermediate_size": 4864,
+            "max_position_embeddings": 32768,
+            "max_window_layers": 24,
+            "model_type": "qwen2",
+            "num_attention_heads": 14,
+            "num_hidden_layers": 24,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-06,
+            "rope_theta": 1000000.0,
+            "sliding_window": 32768,
+            "tie_word_embeddings": true,
+            "torch_dtype": "bfloat16",
+            "use_cache": true,
+            "use_sliding_window": false,
+            "vocab_size": 151936
+        }"#;
+        let cfg = Qwen2BasicConfig::deserialize(small, false)?;
+        assert_eq!((cfg.num_attention_heads, cfg.num_key_value_heads), (14, 2));
+        assert_eq!(cfg.hidden_size / cfg.num_attention_heads, 64);
+        assert!(cfg.tie_word_embeddings);
+        assert_eq!(cfg.sliding_window, 32768);
+
+        // From Qwen/Qwen2-72B-Instruct, with a sliding window larger than the context and no
+        // `tie_word_embeddings`.
+        let large = r#"{
+            "architectures": ["Qwen2ForCausalLM"],
+            "hidden_act": "silu",
+            "hidden_size": 8192,
+            "intermediate_size": 29568,
+            "max_position_embeddings": 32768,
+            "max_window_layers": 80,
+            "model_type": "qwen2",
+            "num_attention_heads": 64,
+            "num_hidden_layers": 80,
+            "num_key_value_heads": 8,
+            "rms_norm_eps": 1e-06,
+            "rope_theta": 1000000.0,
+            "sliding_window": 131072,
+            "use_sliding_window": false,
+            "vocab_size": 152064
+        }"#;
+        let cfg = Qwen2BasicConfig::deserialize(large, true)?;
+        assert_eq!(cfg.num_attention_heads / cfg.num_key_value_heads, 8);
+        assert!(!cfg.tie_word_embeddings);
+        assert!(cfg.use_flash_attn);
+        assert_eq!(cfg.sliding_window, 32768);
+
+        let windowed = large.replace(
+            r#""use_sliding_window": false"#,
+            r#""use_sliding_window": true"#,
+        );
+        assert_eq!(
+            Qwen2BasicConfig::deserialize(&windowed, false)?.sliding_window,
+            131072
+        );
+        Ok(())
+    }
+}
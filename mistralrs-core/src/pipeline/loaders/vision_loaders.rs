@@ -1,4 +1,5 @@
 use std::any::Any;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::{fmt::Debug, str::FromStr};
 
@@ -14,6 +15,7 @@ use serde::Deserialize;
 
 use super::NormalLoadingMetadata;
 use crate::amoe::AnyMoeBaseModelMixin;
+use crate::lora::{LoraConfig, Ordering};
 use crate::paged_attention::{AttentionImplementation, ModelConfigMetadata};
 use crate::pipeline::isq::IsqModelLoader;
 use crate::pipeline::text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata};
@@ -21,6 +23,7 @@ use crate::pipeline::{Cache, IsqModel, Processor, ProcessorCreator};
 use crate::vision_models::idefics2::{Config as Idefics2Config, Idefics2};
 use crate::vision_models::idefics2_input_processor::Idefics2Processor;
 use crate::vision_models::llava::config::Config as LLaVAConfig;
+use crate::vision_models::llava::llava_llm::LLaVAAdapters;
 use crate::vision_models::llava15::Model as LLaVA;
 use crate::vision_models::llava_inputs_processor::LLaVAProcessor;
 use crate::vision_models::llava_next::Model as LLaVANext;
@@ -30,6 +33,7 @@ use crate::vision_models::phi3::{Config as Phi3Config, Model as Phi3};
 use crate::vision_models::phi3_inputs_processor::Phi3Processor;
 use crate::vision_models::preprocessor_config::PreProcessorConfig;
 use crate::vision_models::processor_config::ProcessorConfig;
+use crate::xlora_models::XLoraConfig;
 
 pub trait VisionModel: IsqModel + AnyMoeBaseModelMixin {
     // pixel_values and pixel_attention_mask only specified for prompt seqs
@@ -62,6 +66,21 @@ pub trait VisionModelLoader: IsqModelLoader {
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
     ) -> Result<Box<dyn VisionModel + Send + Sync>>;
+    /// Load the model with LoRA or X-LoRA adapters on the language model.
+    #[allow(clippy::too_many_arguments)]
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn VisionModel + Send + Sync>> {
+        anyhow::bail!("Adapters are not supported for this vision model.")
+    }
     fn is_gptx(&self) -> bool;
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>>;
     /// Get total num_hidden_layers for the layers which will be device mapped.
@@ -166,6 +185,10 @@ impl IsqModelLoader for Phi3VLoader {
             // MLP
             Regex::new(r"layers\.(\d+)\.mlp\.gate__up_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+            // CLIP vision encoder
+            Regex::new(r"layers\.(\d+)\.self_attn\.(q|k|v|out)_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc2\.(weight|bias)$")?,
         ])
     }
 }
@@ -266,6 +289,34 @@ impl VisionModelLoader for LLaVANextLoader {
             self.is_gptx(),
             normal_loading_metadata,
             attention_mechanism,
+            None,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        normal_loading_metadata: NormalLoadingMetadata,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn VisionModel + Send + Sync>> {
+        let mut config: LLaVAConfig = serde_json::from_str(config)?;
+        config.use_flash_attn = use_flash_attn;
+        Ok(Box::new(LLaVANext::new(
+            &config,
+            vb,
+            self.is_gptx(),
+            normal_loading_metadata,
+            AttentionImplementation::Eager,
+            Some(LLaVAAdapters {
+                lora_config,
+                xlora_config,
+                xlora_ordering,
+                preload_adapters,
+            }),
         )?))
     }
     fn is_gptx(&self) -> bool {
@@ -307,6 +358,12 @@ impl IsqModelLoader for LLaVANextLoader {
             Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+            // CLIP vision encoder
+            Regex::new(r"layers\.(\d+)\.self_attn\.out_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc2\.(weight|bias)$")?,
+            // Projector
+            Regex::new(r"multi_modal_projector\.linear_(1|2)\.(weight|bias)$")?,
         ])
     }
 }
@@ -335,6 +392,34 @@ impl VisionModelLoader for LLaVALoader {
             self.is_gptx(),
             normal_loading_metadata,
             attention_mechanism,
+            None,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        normal_loading_metadata: NormalLoadingMetadata,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn VisionModel + Send + Sync>> {
+        let mut config: LLaVAConfig = serde_json::from_str(config)?;
+        config.use_flash_attn = use_flash_attn;
+        Ok(Box::new(LLaVA::new(
+            &config,
+            vb,
+            self.is_gptx(),
+            normal_loading_metadata,
+            AttentionImplementation::Eager,
+            Some(LLaVAAdapters {
+                lora_config,
+                xlora_config,
+                xlora_ordering,
+                preload_adapters,
+            }),
         )?))
     }
     fn is_gptx(&self) -> bool {
@@ -376,6 +461,12 @@ impl IsqModelLoader for LLaVALoader {
             Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+            // CLIP vision encoder
+            Regex::new(r"layers\.(\d+)\.self_attn\.out_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc2\.(weight|bias)$")?,
+            // Projector
+            Regex::new(r"multi_modal_projector\.linear_(1|2)\.(weight|bias)$")?,
         ])
     }
 }
@@ -445,6 +536,84 @@ impl IsqModelLoader for VLlamaLoader {
             Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
             Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+            // Vision encoder
+            Regex::new(r"layers\.(\d+)\.mlp\.fc1\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.fc2\.(weight|bias)$")?,
+            // Projector
+            Regex::new(r"multi_modal_projector\.(weight|bias)$")?,
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline::isq::IsqModelLoader;
+
+    use super::{LLaVALoader, LLaVANextLoader, Phi3VLoader, VLlamaLoader};
+
+    fn is_isq_layer(loader: &dyn IsqModelLoader, name: &str) -> bool {
+        loader
+            .isq_layer_regexes("")
+            .unwrap()
+            .iter()
+            .any(|r| r.is_match(name))
+    }
+
+    #[test]
+    fn test_vision_isq_layers() {
+        for loader in [&LLaVALoader as &dyn IsqModelLoader, &LLaVANextLoader] {
+            for name in [
+                "vision_tower.vision_model.encoder.layers.0.self_attn.q_proj.weight",
+                "vision_tower.vision_model.encoder.layers.0.self_attn.out_proj.bias",
+                "vision_tower.vision_model.encoder.layers.11.mlp.fc2.weight",
+                "multi_modal_projector.linear_1.weight",
+            ] {
+                assert!(is_isq_layer(loader, name), "{name}");
+            }
+            for name in [
+                "vision_tower.vision_model.encoder.layers.0.layer_norm1.weight",
+                "vision_tower.vision_model.embeddings.position_embedding.weight",
+                "vision_tower.vision_model.pre_layrnorm.weight",
+                "language_model.model.embed_tokens.weight",
+                "language_model.model.layers.0.input_layernorm.weight",
+            ] {
+                assert!(!is_isq_layer(loader, name), "{name}");
+            }
+        }
+
+        let prefix = "model.vision_embed_tokens.img_processor.vision_model";
+        assert!(is_isq_layer(
+            &Phi3VLoader,
+            &format!("{prefix}.encoder.layers.3.self_attn.k_proj.weight")
+        ));
+        assert!(is_isq_layer(
+            &Phi3VLoader,
+            &format!("{prefix}.encoder.layers.3.mlp.fc1.bias")
+        ));
+        assert!(!is_isq_layer(
+            &Phi3VLoader,
+            &format!("{prefix}.embeddings.patch_embedding.weight")
+        ));
+        assert!(!is_isq_layer(
+            &Phi3VLoader,
+            &format!("{prefix}.encoder.layers.3.layer_norm2.weight")
+        ));
+
+        for name in [
+            "vision_model.transformer.layers.0.mlp.fc1.weight",
+            "vision_model.global_transformer.layers.1.self_attn.o_proj.weight",
+            "multi_modal_projector.bias",
+        ] {
+            assert!(is_isq_layer(&VLlamaLoader, name), "{name}");
+        }
+        for name in [
+            "vision_model.gated_positional_embedding.tile_embedding.weight",
+            "vision_model.layernorm_pre.weight",
+            "vision_model.transformer.layers.0.input_layernorm.bias",
+            "language_model.model.layers.3.cross_attn.q_norm.weight",
+            "language_model.model.embed_tokens.weight",
+        ] {
+            assert!(!is_isq_layer(&VLlamaLoader, name), "{name}");
+        }
+    }
+}
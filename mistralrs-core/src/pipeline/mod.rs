@@ -1,9 +1,12 @@
 mod amoe;
 mod cache_manager;
 pub mod chat_template;
+mod contrastive;
+mod contrastive_search;
 mod diffusion;
 mod ggml;
 mod gguf;
+mod hidden_states;
 mod inputs_processor;
 mod isq;
 mod loaders;
@@ -11,21 +14,30 @@ mod macros;
 mod normal;
 mod paths;
 mod processing;
+mod quantization;
 mod sampling;
 mod speculative;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 mod vision;
 
 pub use super::diffusion_models::DiffusionGenerationParams;
 use crate::aici::toktree::TokTrie;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use crate::diffusion_models::response::send_responses;
+use crate::layers::RopeScalingConfig;
 use crate::paged_attention::{CacheConfig, CacheEngine};
 use crate::prefix_cacher::PrefixCacheManager;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
 use chat_template::ChatTemplate;
+pub use contrastive::{ContrastiveConfig, ContrastiveLoader, ContrastivePipeline};
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder, DiffusionSpecificConfig};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
+pub(crate) use hidden_states::{
+    capture_before_head, capture_layer_output, with_hidden_state_capture,
+};
+pub use hidden_states::{EmbeddingPooling, HiddenStateLayer};
 use image::DynamicImage;
 pub use inputs_processor::InputProcessorOutput;
 pub use isq::{parse_isq_value, IsqModel, IsqOrganization};
@@ -43,21 +55,29 @@ pub(crate) use paths::{get_chat_template, get_model_paths, get_xlora_paths, XLor
 pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
+pub use quantization::{detect_quantization, QuantizationInfo};
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub use speculative::{
+    AdaptiveGamma, PromptLookupConfig, PromptLookupLoader, SpeculativeConfig, SpeculativeLoader,
+    SpeculativePipeline, VocabMismatchPolicy,
+};
 use std::any::Any;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokenizers::Tokenizer;
+use tracing::warn;
 pub use vision::{VisionLoader, VisionLoaderBuilder, VisionSpecificConfig};
 
 use anyhow::Result;
 use candle_core::{DType, Device, IndexOp, Tensor, Var};
+use candle_nn::VarMap;
 
-use crate::sequence::Sequence;
+use crate::sampler::{Sampler, SamplerConfig};
+use crate::sequence::{Sequence, SequenceGroup};
 
-pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
+pub use self::cache_manager::{Cache, CacheManager, KVCacheDtype, LayerCaches};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
@@ -75,14 +95,69 @@ pub struct GeneralMetadata {
     pub is_xlora: bool,
     pub activation_dtype: DType,
     pub sliding_window: Option<usize>,
+    /// Dtype the non-paged KV cache is stored in
+    pub kv_cache_dtype: KVCacheDtype,
     // PagedAttention stuff
     pub cache_config: Option<CacheConfig>,
     pub cache_engine: Option<CacheEngine>,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    // Speculative decoding stats, only updated when this is the target of a speculative pipeline
+    pub speculative_accepted_tokens: AtomicUsize,
+    pub speculative_drafted_tokens: AtomicUsize,
+    pub speculative_draft_calls: AtomicUsize,
+    /// Whether the adapters of running sequences can be swapped with `Pipeline::swap_adapter_for_seq`
+    pub per_request_adapter_override: bool,
+    /// Adapter swaps by sequence id, applied when the sequence is next stepped
+    pub pending_adapter_swaps: std::sync::Mutex<HashMap<usize, Vec<String>>>,
+}
+
+impl GeneralMetadata {
+    /// Record one speculative decoding step which drafted `drafted` tokens, of which the target
+    /// model accepted `accepted`.
+    pub(crate) fn record_speculative_step(&self, drafted: usize, accepted: usize) {
+        self.speculative_drafted_tokens
+            .fetch_add(drafted, Ordering::Relaxed);
+        self.speculative_accepted_tokens
+            .fetch_add(accepted, Ordering::Relaxed);
+        self.speculative_draft_calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fraction of drafted tokens accepted by the target model. `None` if nothing was drafted.
+    pub fn acceptance_rate(&self) -> Option<f64> {
+        let drafted = self.speculative_drafted_tokens.load(Ordering::Relaxed);
+        let accepted = self.speculative_accepted_tokens.load(Ordering::Relaxed);
+        #[allow(clippy::cast_precision_loss)]
+        (drafted > 0).then(|| accepted as f64 / drafted as f64)
+    }
+
+    /// Average number of drafted tokens accepted per speculative step (one round of drafting and
+    /// verification). `None` if no step was run.
+    pub fn tokens_per_draft_call(&self) -> Option<f64> {
+        let calls = self.speculative_draft_calls.load(Ordering::Relaxed);
+        let accepted = self.speculative_accepted_tokens.load(Ordering::Relaxed);
+        #[allow(clippy::cast_precision_loss)]
+        (calls > 0).then(|| accepted as f64 / calls as f64)
+    }
+
+    /// Apply the pending adapter swaps of `seqs`. The scheduler sees the new adapters from the next
+    /// step on, so the adapters activated for the current batch are unchanged.
+    pub(crate) fn apply_adapter_swaps(&self, seqs: &mut [&mut Sequence]) {
+        let mut swaps = self.pending_adapter_swaps.lock().unwrap();
+        if swaps.is_empty() {
+            return;
+        }
+        for seq in seqs.iter_mut() {
+            if let Some(adapters) = swaps.remove(seq.id()) {
+                seq.set_active_adapters(adapters);
+            }
+        }
+    }
 }
 
 pub enum AdapterInstruction {
     Activate(Vec<String>),
+    /// Each sequence of the batch uses its own adapters.
+    PerSequence,
     None,
 }
 
@@ -107,6 +182,11 @@ pub trait PreProcessingMixin: MetadataMixin {
 
 pub trait IsqPipelineMixin {
     fn re_isq_model(&mut self, dtype: IsqType) -> Result<()>;
+    /// Dequantized weight of the layer with this checkpoint name, for example
+    /// `model.layers.0.self_attn.q_proj`.
+    fn get_layer_weights(&mut self, _name: &str) -> Result<Tensor> {
+        anyhow::bail!("Getting layer weights is not supported for this pipeline.")
+    }
 }
 
 pub trait CacheManagerMixin {
@@ -125,7 +205,28 @@ pub trait CacheManagerMixin {
 
 pub trait AdapterActivationMixin {
     /// Returns the number of activated adapters.
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize>;
+    fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize> {
+        self.activate_weighted_adapters(adapters.into_iter().map(|a| (a, 1.0)).collect())
+    }
+    /// Activate the adapters, scaling the delta of each adapter by its weight so that several
+    /// adapters can be blended. Returns the number of activated adapters.
+    fn activate_weighted_adapters(&mut self, adapters: Vec<(String, f64)>) -> Result<usize>;
+    /// Whether one batch may hold sequences using different adapters, see
+    /// [`AdapterActivationMixin::activate_adapters_per_seq`].
+    fn supports_mixed_adapter_batches(&self) -> bool {
+        false
+    }
+    /// Select the adapters of each sequence in the next batch, `None` meaning the activated
+    /// adapters. An empty list returns every sequence to the activated adapters. Returns the
+    /// number of layers with adapters.
+    fn activate_adapters_per_seq(&mut self, _adapters: Vec<Option<Vec<String>>>) -> Result<usize> {
+        anyhow::bail!("Batches mixing adapters are not supported for this pipeline.")
+    }
+    /// Merge the adapters into the base weights and drop the adapter weights. Returns the number of
+    /// merged layers.
+    fn merge_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+        anyhow::bail!("Merging adapters is not supported for this pipeline.")
+    }
 }
 
 pub trait MetadataMixin {
@@ -143,6 +244,10 @@ pub trait AnyMoePipelineMixin {
     fn amoe_layer_vars(&self) -> Vec<Vec<Var>> {
         unreachable!()
     }
+    /// Named vars of all gating layers, for checkpointing
+    fn amoe_var_map(&self) -> VarMap {
+        unreachable!()
+    }
     fn amoe_finish_training(&mut self, _gate_model_id: Option<String>) -> candle_core::Result<()> {
         unreachable!()
     }
@@ -241,6 +346,9 @@ impl ForwardInputsResult {
 }
 
 #[async_trait::async_trait]
+/// Number of tokens of the dummy prompt run by [`Pipeline::prewarm`].
+const PREWARM_TOKENS: usize = 4;
+
 pub trait Pipeline:
     Send
     + Sync
@@ -266,6 +374,7 @@ pub trait Pipeline:
         rng: Arc<std::sync::Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<(), candle_core::Error> {
+        self.get_metadata().apply_adapter_swaps(input_seqs);
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 let inputs_iter = self.get_processor().inputs_processor().process_inputs(
@@ -282,6 +391,25 @@ pub trait Pipeline:
                 );
 
                 let mut logits = vec![None; input_seqs.len()];
+                let contrastive = input_seqs.iter().any(|seq| seq.contrastive().is_some());
+
+                // Adapters selected per sequence are activated for each chunk of the batch, and
+                // reset once the batch has been run.
+                let per_seq_adapters = matches!(
+                    pre_op,
+                    CacheInstruction::In(AdapterInstruction::PerSequence)
+                        | CacheInstruction::Nothing(AdapterInstruction::PerSequence)
+                        | CacheInstruction::Reset {
+                            adapter_inst: AdapterInstruction::PerSequence,
+                            ..
+                        }
+                )
+                .then(|| {
+                    input_seqs
+                        .iter()
+                        .map(|seq| seq.get_adapters())
+                        .collect::<Vec<_>>()
+                });
 
                 for (i, inputs) in inputs_iter.enumerate() {
                     let InputProcessorOutput {
@@ -301,7 +429,7 @@ pub trait Pipeline:
                                             ))
                                         })?
                                     }
-                                    AdapterInstruction::None => 0,
+                                    AdapterInstruction::PerSequence | AdapterInstruction::None => 0,
                                 };
                                 self.clone_in_cache(input_seqs, false)
                             }
@@ -316,7 +444,7 @@ pub trait Pipeline:
                                             ))
                                         })?
                                     }
-                                    AdapterInstruction::None => 0,
+                                    AdapterInstruction::PerSequence | AdapterInstruction::None => 0,
                                 };
                             }
                             CacheInstruction::Reset {
@@ -333,7 +461,7 @@ pub trait Pipeline:
                                             ))
                                         })?
                                     }
-                                    AdapterInstruction::None => 0,
+                                    AdapterInstruction::PerSequence | AdapterInstruction::None => 0,
                                 };
                                 self.set_none_cache(reset_non_granular, false)
                             }
@@ -341,7 +469,26 @@ pub trait Pipeline:
                         }
                     }
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    if let Some(per_seq_adapters) = &per_seq_adapters {
+                        self.activate_adapters_per_seq(
+                            seq_indices
+                                .iter()
+                                .map(|i| per_seq_adapters[*i].clone())
+                                .collect(),
+                        )
+                        .map_err(candle_core::Error::msg)?;
+                    }
+
+                    let raw_logits = if contrastive {
+                        contrastive_search::forward_inputs_with_context(
+                            self,
+                            inputs,
+                            input_seqs,
+                            &seq_indices,
+                        )?
+                    } else {
+                        self.forward_inputs(inputs)?
+                    };
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
                         logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
@@ -366,6 +513,94 @@ pub trait Pipeline:
                     _ => unreachable!("Unreachable POST cache op."),
                 }
 
+                // Classifier-free guidance: run each guided sequence again on its unconditional
+                // context, which has its own KV cache. The model cache is restored afterwards.
+                if input_seqs.iter().any(|seq| seq.cfg_scale().is_some()) {
+                    if matches!(post_op, CacheInstruction::Nothing(_)) {
+                        self.clone_out_cache(input_seqs, false);
+                    }
+                    for seq in input_seqs
+                        .iter_mut()
+                        .filter(|seq| seq.cfg_scale().is_some())
+                    {
+                        if per_seq_adapters.is_some() {
+                            self.activate_adapters_per_seq(vec![seq.get_adapters()])
+                                .map_err(candle_core::Error::msg)?;
+                        }
+                        let mut cfg_seqs = [&mut **seq];
+                        cfg_seqs[0].swap_cfg_context();
+                        self.clone_in_cache(&mut cfg_seqs, false);
+                        let inputs_iter = self.get_processor().inputs_processor().process_inputs(
+                            self.tokenizer(),
+                            &mut cfg_seqs,
+                            is_prompt,
+                            self.get_metadata().is_xlora,
+                            &self.device(),
+                            self.get_metadata().has_no_kv_cache,
+                            None,
+                            self.get_input_processor_config(),
+                            None,
+                            self.get_metadata().prompt_batchsize,
+                        );
+                        let mut uncond_logits = None;
+                        for inputs in inputs_iter {
+                            let InputProcessorOutput {
+                                inputs,
+                                seq_indices: _,
+                            } = inputs.map_err(candle_core::Error::msg)?;
+                            uncond_logits = Some(self.forward_inputs(inputs)?);
+                        }
+                        self.clone_out_cache(&mut cfg_seqs, false);
+                        cfg_seqs[0].swap_cfg_context();
+
+                        if let Some(ForwardInputsResult::CausalGeneration { logits }) =
+                            uncond_logits
+                                .map(|l| l.index_bs(0)?.to_device(&Device::Cpu))
+                                .transpose()?
+                        {
+                            cfg_seqs[0].set_cfg_uncond_logits(logits);
+                        }
+                    }
+                    match post_op {
+                        CacheInstruction::Reset {
+                            reset_non_granular,
+                            adapter_inst: _,
+                        } => self.set_none_cache(reset_non_granular, false),
+                        _ => self.clone_in_cache(input_seqs, false),
+                    }
+                }
+
+                // Contrastive search: run the candidates of the next token of each contrastive
+                // search sequence on copies of its KV cache. The model cache is restored afterwards.
+                if contrastive {
+                    if matches!(post_op, CacheInstruction::Nothing(_)) {
+                        self.clone_out_cache(input_seqs, false);
+                    }
+                    for (seq, seq_logits) in input_seqs
+                        .iter_mut()
+                        .zip(&logits)
+                        .filter(|(seq, _)| seq.contrastive().is_some())
+                    {
+                        if per_seq_adapters.is_some() {
+                            self.activate_adapters_per_seq(vec![seq.get_adapters()])
+                                .map_err(candle_core::Error::msg)?;
+                        }
+                        contrastive_search::run_candidates(self, seq, seq_logits)?;
+                    }
+                    match post_op {
+                        CacheInstruction::Reset {
+                            reset_non_granular,
+                            adapter_inst: _,
+                        } => self.set_none_cache(reset_non_granular, false),
+                        _ => self.clone_in_cache(input_seqs, false),
+                    }
+                }
+
+                if per_seq_adapters.is_some() {
+                    self.activate_adapters_per_seq(Vec::new())
+                        .map_err(candle_core::Error::msg)?;
+                }
+
                 match &logits[0] {
                     ForwardInputsResult::CausalGeneration { .. } => {
                         self.sample_causal_gen(
@@ -521,6 +756,110 @@ pub trait Pipeline:
     ) -> Result<(), candle_core::Error>;
 
     fn category(&self) -> ModelCategory;
+
+    /// Whether sequences doing beam search can be stepped. Their beams are selected when the
+    /// pipeline samples with `sample_and_add_toks`.
+    fn supports_beam_search(&self) -> bool {
+        false
+    }
+
+    /// Whether sequences doing contrastive search can be stepped. This requires the default
+    /// `step`, text model inputs, a model which reports its hidden states before the LM head and
+    /// a KV cache which the default cache manager can copy.
+    fn supports_contrastive_search(&self) -> bool {
+        false
+    }
+
+    /// Swap the adapters of the running sequence `seq_id`, for example to compare adapters within
+    /// one conversation. The new adapters are used from the sequence's next step on. Adapters are
+    /// activated for a whole batch, so the scheduler only batches sequences with the same adapters.
+    fn swap_adapter_for_seq(&self, seq_id: u64, new_adapters: Vec<String>) -> Result<()> {
+        let metadata = self.get_metadata();
+        if !metadata.per_request_adapter_override {
+            anyhow::bail!("Swapping adapters per sequence is not supported for this pipeline.");
+        }
+        metadata
+            .pending_adapter_swaps
+            .lock()
+            .unwrap()
+            .insert(usize::try_from(seq_id)?, new_adapters);
+        Ok(())
+    }
+
+    /// Run a short dummy prompt through the model, so that the kernels are compiled and the caches
+    /// allocated before the first request instead of during it, then clear the cache. This is safe
+    /// to call before any request. It does nothing for diffusion models, and with PagedAttention,
+    /// whose cache is allocated when the model is loaded.
+    fn prewarm(&mut self) -> Result<()> {
+        if self.category() == ModelCategory::Diffusion || self.get_metadata().cache_engine.is_some()
+        {
+            return Ok(());
+        }
+
+        // A few tokens, as for a prompt. No custom logits processors.
+        let (dummy_sender, _) = tokio::sync::mpsc::channel(1);
+        let dummy_sampler = Sampler::new(SamplerConfig {
+            tokenizer: self.tokenizer(),
+            ..Default::default()
+        })?;
+        let dummy_group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, 0,
+        )));
+        let mut seq = amoe::new_dummy_seq(
+            (vec![0; PREWARM_TOKENS], String::new()),
+            dummy_sender,
+            dummy_sampler,
+            dummy_group,
+            None,
+        );
+
+        self.set_none_cache(true, true);
+        let inputs_iter = self.get_processor().inputs_processor().process_inputs(
+            self.tokenizer(),
+            &mut [&mut seq],
+            true,
+            self.get_metadata().is_xlora,
+            &self.device(),
+            self.get_metadata().has_no_kv_cache,
+            None,
+            self.get_input_processor_config(),
+            None,
+            self.get_metadata().prompt_batchsize,
+        );
+        for inputs in inputs_iter {
+            let InputProcessorOutput { inputs, .. } = inputs?;
+            self.forward_inputs(inputs)?;
+        }
+        self.device().synchronize()?;
+        self.set_none_cache(true, true);
+        Ok(())
+    }
+
+    /// Run the model on `input` without sampling, returning the hidden states of `layer` for each
+    /// token, of shape `(input.len(), hidden_size)`. This can be used to compute embeddings.
+    fn get_hidden_states(&self, _input: &[u32], _layer: HiddenStateLayer) -> Result<Tensor> {
+        anyhow::bail!("Getting hidden states is not supported for this pipeline.");
+    }
+
+    /// Run the model on each of `inputs` without sampling and pool the hidden states of `layer` into
+    /// one embedding of `hidden_size` values per input.
+    fn get_embeddings(
+        &self,
+        inputs: &[Vec<u32>],
+        layer: HiddenStateLayer,
+        pooling: EmbeddingPooling,
+    ) -> Result<Vec<Vec<f32>>> {
+        inputs
+            .iter()
+            .map(|input| {
+                let hidden_states = self.get_hidden_states(input, layer)?;
+                Ok(pooling
+                    .pool(&hidden_states)?
+                    .to_dtype(candle_core::DType::F32)?
+                    .to_vec1()?)
+            })
+            .collect()
+    }
 }
 
 pub(crate) fn extract_logits(
@@ -534,6 +873,40 @@ pub(crate) fn extract_logits(
     Tensor::cat(&toks, 0)
 }
 
+/// Set `rope_scaling` in a model's config, or in its `text_config` if there is one. The
+/// `max_position_embeddings` of the config must be the extended context length, so this warns if
+/// the config did not already have a `rope_scaling` with the same factor.
+pub(crate) fn apply_rope_scaling(config: &str, rope_scaling: &RopeScalingConfig) -> Result<String> {
+    let mut config: serde_json::Value = serde_json::from_str(config)?;
+    let target = if config.get("text_config").is_some() {
+        &mut config["text_config"]
+    } else {
+        &mut config
+    };
+    let Some(target) = target.as_object_mut() else {
+        anyhow::bail!("Expected the model config to be a JSON object.");
+    };
+
+    let factor = rope_scaling.factor();
+    let old_factor = target
+        .get("rope_scaling")
+        .and_then(|old| old.get("factor"))
+        .and_then(|factor| factor.as_f64());
+    if old_factor != Some(factor) {
+        let max_pos = target
+            .get("max_position_embeddings")
+            .and_then(|max_pos| max_pos.as_u64());
+        warn!(
+            "RoPE scaling with factor {factor} was set but the model config has no matching `rope_scaling`, make sure `max_position_embeddings` ({max_pos:?}) was updated to the extended context length (the trained context length times {factor})."
+        );
+    }
+    target.insert(
+        "rope_scaling".to_string(),
+        serde_json::to_value(rope_scaling)?,
+    );
+    Ok(serde_json::to_string(&config)?)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::MessageContent;
@@ -664,6 +1037,168 @@ mod tests {
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
 
+    #[test]
+    /// Generating these cases:
+    /// ```py
+    /// >>> t=transformers.AutoTokenizer.from_pretrained(...)
+    /// >>> tools=[{"type":"function","function":{"description":"Get the current weather","name":"get_weather","parameters":{"properties":{"location":{"type":"string"},"unit":{"type":"string"}},"required":["location"],"type":"object"}}}]
+    /// >>> tool_calls=[{"id":id,"type":"function","function":{"arguments":{"location":"Paris","unit":"celsius"},"name":"get_weather"}}]
+    /// >>> t.apply_chat_template([{"role":"system","content":"You are a helpful assistant"},{"role":"user","content":"What is the weather in Paris?"},{"role":"assistant","content":"","tool_calls":tool_calls},{"role":"tool","content":"{\"temperature\": 22}","tool_call_id":id},{"role":"assistant","content":"It is 22 degrees in Paris."},{"role":"user","content":"Thanks!"}], tools=tools, add_generation_prompt=True, tokenize=False)
+    /// ```
+    /// With `id="3f9a1c2b7"` for Mistral, which is what `call-3f9a1c2b7` is normalized to, and
+    /// `id="call-3f9a1c2b7"` for Hermes.
+    fn test_tool_chat_templates() {
+        use std::collections::HashMap;
+
+        use crate::pipeline::chat_template::{apply_chat_template_to, ChatTemplateValue};
+        use crate::{Function, Tool, ToolCallingModel, ToolType};
+
+        let templates = [
+            // mistralai/Mistral-7B-Instruct-v0.3
+            (
+                ToolCallingModel::Mistral,
+                "<s>",
+                "</s>",
+                r#"{%- if messages[0]["role"] == "system" %}{%- set system_message = messages[0]["content"] %}{%- set loop_messages = messages[1:] %}{%- else %}{%- set loop_messages = messages %}{%- endif %}{%- if not tools is defined %}{%- set tools = none %}{%- endif %}{%- set user_messages = loop_messages | selectattr("role", "equalto", "user") | list %}{#- This block checks for alternating user/assistant messages, skipping tool calling messages #}{%- set ns = namespace() %}{%- set ns.index = 0 %}{%- for message in loop_messages %}{%- if not (message.role == "tool" or message.role == "tool_results" or (message.tool_calls is defined and message.tool_calls is not none)) %}{%- if (message["role"] == "user") != (ns.index % 2 == 0) %}{{- raise_exception("After the optional system message, conversation roles must alternate user/assistant/user/assistant/...") }}{%- endif %}{%- set ns.index = ns.index + 1 %}{%- endif %}{%- endfor %}{{- bos_token }}{%- for message in loop_messages %}{%- if message["role"] == "user" %}{%- if tools is not none and (message == user_messages[-1]) %}{{- "[AVAILABLE_TOOLS] [" }}{%- for tool in tools %}{%- set tool = tool.function %}{{- '{"type": "function", "function": {' }}{%- for key, val in tool.items() if key != "return" %}{%- if val is string %}{{- '"' + key + '": "' + val + '"' }}{%- else %}{{- '"' + key + '": ' + val|tojson }}{%- endif %}{%- if not loop.last %}{{- ", " }}{%- endif %}{%- endfor %}{{- "}}" }}{%- if not loop.last %}{{- ", " }}{%- else %}{{- "]" }}{%- endif %}{%- endfor %}{{- "[/AVAILABLE_TOOLS]" }}{%- endif %}{%- if loop.last and system_message is defined %}{{- "[INST] " + system_message + "\n\n" + message["content"] + "[/INST]" }}{%- else %}{{- "[INST] " + message["content"] + "[/INST]" }}{%- endif %}{%- elif message.tool_calls is defined and message.tool_calls is not none %}{{- "[TOOL_CALLS] [" }}{%- for tool_call in message.tool_calls %}{%- set out = tool_call.function|tojson %}{{- out[:-1] }}{%- if not tool_call.id is defined or tool_call.id|length != 9 %}{{- raise_exception("Tool call IDs should be alphanumeric strings with length 9!") }}{%- endif %}{{- ', "id": "' + tool_call.id + '"}' }}{%- if not loop.last %}{{- ", " }}{%- else %}{{- "]" + eos_token }}{%- endif %}{%- endfor %}{%- elif message["role"] == "assistant" %}{{- " " + message["content"]|trim + eos_token}}{%- elif message["role"] == "tool_results" or message["role"] == "tool" %}{%- if message.content is defined and message.content.content is defined %}{%- set content = message.content.content %}{%- else %}{%- set content = message.content %}{%- endif %}{{- '[TOOL_RESULTS] {"content": ' + content|string + ", " }}{%- if not message.tool_call_id is defined or message.tool_call_id|length != 9 %}{{- raise_exception("Tool call IDs should be alphanumeric strings with length 9!") }}{%- endif %}{{- '"call_id": "' + message.tool_call_id + '"}[/TOOL_RESULTS]' }}{%- else %}{{- raise_exception("Only user and assistant roles are supported, with the exception of an initial optional system message!") }}{%- endif %}{%- endfor %}"#,
+            ),
+            // NousResearch/Hermes-2-Pro-Llama-3-8B, `tool_use`
+            (
+                ToolCallingModel::Hermes,
+                "<|begin_of_text|>",
+                "<|im_end|>",
+                r#"{{- bos_token }}{%- if tools is defined and tools %}{{- '<|im_start|>system\nYou are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. Here are the available tools: <tools> ' }}{%- for tool in tools %}{{- tool|tojson }}{%- if not loop.last %}{{- ' ' }}{%- endif %}{%- endfor %}{{- ' </tools> For each function call return a json object with function name and arguments within <tool_call></tool_call> XML tags.<|im_end|>\n' }}{%- endif %}{%- for message in messages %}{%- if message.role == "user" or message.role == "system" or (message.role == "assistant" and message.tool_calls is not defined) %}{{- '<|im_start|>' + message.role + '\n' + message.content + '<|im_end|>' + '\n' }}{%- elif message.role == "assistant" %}{{- '<|im_start|>' + message.role }}{%- for tool_call in message.tool_calls %}{{- '\n<tool_call>\n' }}{%- if tool_call.function is defined %}{%- set tool_call = tool_call.function %}{%- endif %}{{- '{' }}{{- '"name": "' }}{{- tool_call.name }}{{- '"' }}{{- ', '}}{%- if tool_call.arguments is defined %}{{- '"arguments": ' }}{%- if tool_call.arguments is string %}{{- tool_call.arguments }}{%- else %}{{- tool_call.arguments|tojson }}{%- endif %}{%- endif %}{{- '}' }}{{- '\n</tool_call>' }}{%- endfor %}{{- '<|im_end|>\n' }}{%- elif message.role == "tool" %}{%- if loop.previtem and loop.previtem.role != "tool" %}{{- '<|im_start|>tool\n' }}{%- endif %}{{- '<tool_response>\n' }}{{- message.content }}{%- if not loop.last %}{{- '\n</tool_response>\n' }}{%- else %}{{- '\n</tool_response>' }}{%- endif %}{%- if not loop.last and loop.nextitem.role != "tool" %}{{- '<|im_end|>' }}{%- elif loop.last %}{{- '<|im_end|>' }}{%- endif %}{%- endif %}{%- endfor %}{%- if add_generation_prompt %}{{- '<|im_start|>assistant\n' }}{%- endif %}"#,
+            ),
+        ];
+        let expected_outputs = [
+            // mistralai/Mistral-7B-Instruct-v0.3
+            "<s>[INST] What is the weather in Paris?[/INST][TOOL_CALLS] [{\"arguments\": {\"location\": \"Paris\", \"unit\": \"celsius\"}, \"name\": \"get_weather\", \"id\": \"3f9a1c2b7\"}]</s>[TOOL_RESULTS] {\"content\": {\"temperature\": 22}, \"call_id\": \"3f9a1c2b7\"}[/TOOL_RESULTS] It is 22 degrees in Paris.</s>[AVAILABLE_TOOLS] [{\"type\": \"function\", \"function\": {\"description\": \"Get the current weather\", \"name\": \"get_weather\", \"parameters\": {\"properties\": {\"location\": {\"type\": \"string\"}, \"unit\": {\"type\": \"string\"}}, \"required\": [\"location\"], \"type\": \"object\"}}}][/AVAILABLE_TOOLS][INST] You are a helpful assistant\n\nThanks![/INST]",
+            // NousResearch/Hermes-2-Pro-Llama-3-8B, `tool_use`
+            "<|begin_of_text|><|im_start|>system\nYou are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. Here are the available tools: <tools> {\"function\": {\"description\": \"Get the current weather\", \"name\": \"get_weather\", \"parameters\": {\"properties\": {\"location\": {\"type\": \"string\"}, \"unit\": {\"type\": \"string\"}}, \"required\": [\"location\"], \"type\": \"object\"}}, \"type\": \"function\"} </tools> For each function call return a json object with function name and arguments within <tool_call></tool_call> XML tags.<|im_end|>\n<|im_start|>system\nYou are a helpful assistant<|im_end|>\n<|im_start|>user\nWhat is the weather in Paris?<|im_end|>\n<|im_start|>assistant\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"location\": \"Paris\", \"unit\": \"celsius\"}}\n</tool_call><|im_end|>\n<|im_start|>tool\n<tool_response>\n{\"temperature\": 22}\n</tool_response>\n<|im_end|><|im_start|>assistant\nIt is 22 degrees in Paris.<|im_end|>\n<|im_start|>user\nThanks!<|im_end|>\n<|im_start|>assistant\n",
+        ];
+
+        let tools = vec![Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: Some("Get the current weather".to_string()),
+                name: "get_weather".to_string(),
+                parameters: Some(HashMap::from([
+                    ("type".to_string(), serde_json::json!("object")),
+                    (
+                        "properties".to_string(),
+                        serde_json::json!({
+                            "location": {"type": "string"},
+                            "unit": {"type": "string"},
+                        }),
+                    ),
+                    ("required".to_string(), serde_json::json!(["location"])),
+                ])),
+            },
+        }];
+        let tool_call: IndexMap<String, String> = hashmap! {
+            "id".to_string() => "call-3f9a1c2b7".to_string(),
+            "name".to_string() => "get_weather".to_string(),
+            "arguments".to_string() => r#"{"location": "Paris", "unit": "celsius"}"#.to_string(),
+        };
+        let inputs: Vec<IndexMap<String, MessageContent>> = vec![
+            hashmap! {
+                "role".to_string() => Either::Left("system".to_string()),
+                "content".to_string() => Either::Left("You are a helpful assistant".to_string()),
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("user".to_string()),
+                "content".to_string() => Either::Left("What is the weather in Paris?".to_string()),
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("assistant".to_string()),
+                "content".to_string() => Either::Left("".to_string()),
+                "tool_calls".to_string() => Either::Right(vec![tool_call]),
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("tool".to_string()),
+                "content".to_string() => Either::Left(r#"{"temperature": 22}"#.to_string()),
+                "tool_call_id".to_string() => Either::Left("call-3f9a1c2b7".to_string()),
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("assistant".to_string()),
+                "content".to_string() => Either::Left("It is 22 degrees in Paris.".to_string()),
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("user".to_string()),
+                "content".to_string() => Either::Left("Thanks!".to_string()),
+            },
+        ];
+
+        for ((model, bos, eos, template), expected) in templates.iter().zip(expected_outputs) {
+            assert_eq!(ToolCallingModel::from_template(template), Some(*model));
+            let output = apply_chat_template_to(
+                inputs.clone(),
+                true,
+                &ChatTemplateValue(Either::Left(template.to_string())),
+                Some(bos.to_string()),
+                Some(eos.to_string()),
+                Some("<unk>".to_string()),
+                tools.clone(),
+            )
+            .unwrap();
+            assert_eq!(output, expected, "{model:?}");
+        }
+    }
+
+    #[test]
+    fn test_tool_context_templates() {
+        use crate::pipeline::chat_template::{apply_chat_template_to, ChatTemplateValue};
+        use crate::{Function, Tool, ToolCallingModel, ToolType};
+
+        let templates = [
+            // google/gemma-2-9b-it
+            (
+                ToolCallingModel::Gemma2,
+                r#"{{ bos_token }}{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}"#,
+            ),
+            // microsoft/Phi-4-mini-instruct
+            (
+                ToolCallingModel::Phi3,
+                r#"{% for message in messages %}{% if message['role'] == 'system' and 'tools' in message and message['tools'] is not none %}{{ '<|' + message['role'] + '|>' + message['content'] + '<|tool|>' + message['tools'] + '<|/tool|>' + '<|end|>' }}{% else %}{{ '<|' + message['role'] + '|>' + message['content'] + '<|end|>' }}{% endif %}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>' }}{% else %}{{ eos_token }}{% endif %}"#,
+            ),
+        ];
+        let functions =
+            r#"[{"description":"Get the current weather","name":"get_weather","parameters":null}]"#;
+        let expected_outputs = [
+            // google/gemma-2-9b-it
+            format!("<bos><start_of_turn>user\nYou have access to the following functions:\n{functions}\n\nTo call functions, respond only with a list of calls in a ```json code block, for example:\n```json\n[{{\"name\": \"function_name\", \"arguments\": {{\"argument\": \"value\"}}}}]\n```\n\nWhat is the weather in Paris?<end_of_turn>\n<start_of_turn>model\n"),
+            // microsoft/Phi-4-mini-instruct
+            format!("<|system|><|tool|>{functions}<|/tool|><|end|><|user|>What is the weather in Paris?<|end|><|assistant|>"),
+        ];
+
+        let tools = vec![Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: Some("Get the current weather".to_string()),
+                name: "get_weather".to_string(),
+                parameters: None,
+            },
+        }];
+        let inputs: Vec<IndexMap<String, MessageContent>> = vec![hashmap! {
+            "role".to_string() => Either::Left("user".to_string()),
+            "content".to_string() => Either::Left("What is the weather in Paris?".to_string()),
+        }];
+
+        for ((model, template), expected) in templates.iter().zip(expected_outputs) {
+            assert_eq!(ToolCallingModel::from_template(template), Some(*model));
+            let output = apply_chat_template_to(
+                inputs.clone(),
+                true,
+                &ChatTemplateValue(Either::Left(template.to_string())),
+                Some("<bos>".to_string()),
+                Some("<eos>".to_string()),
+                Some("<unk>".to_string()),
+                tools.clone(),
+            )
+            .unwrap();
+            assert_eq!(output, expected, "{model:?}");
+        }
+    }
+
     #[test]
     /// Generating these cases:
     /// ```py
@@ -778,4 +1313,68 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+
+    #[test]
+    fn test_speculative_metrics() {
+        use super::{GeneralMetadata, KVCacheDtype, ModelKind};
+        use candle_core::DType;
+        use std::sync::atomic::AtomicUsize;
+
+        let metadata = GeneralMetadata {
+            max_seq_len: 4096,
+            tok_trie: None,
+            has_no_kv_cache: false,
+            num_hidden_layers: 1,
+            eos_tok: vec![],
+            kind: ModelKind::Normal,
+            is_xlora: false,
+            activation_dtype: DType::F32,
+            sliding_window: None,
+            kv_cache_dtype: KVCacheDtype::FullPrecision,
+            cache_config: None,
+            cache_engine: None,
+            prompt_batchsize: None,
+            speculative_accepted_tokens: AtomicUsize::new(0),
+            speculative_drafted_tokens: AtomicUsize::new(0),
+            speculative_draft_calls: AtomicUsize::new(0),
+            per_request_adapter_override: false,
+            pending_adapter_swaps: Default::default(),
+        };
+        assert_eq!(metadata.acceptance_rate(), None);
+        assert_eq!(metadata.tokens_per_draft_call(), None);
+
+        // gamma = 4: all accepted, first rejected, then two accepted before a rejection.
+        for n_accepted in [4, 0, 2] {
+            metadata.record_speculative_step(4, n_accepted);
+        }
+        assert_eq!(metadata.acceptance_rate(), Some(6. / 12.));
+        assert_eq!(metadata.tokens_per_draft_call(), Some(2.));
+    }
+
+    #[test]
+    fn test_apply_rope_scaling() {
+        use super::apply_rope_scaling;
+        use crate::layers::RopeScalingConfig;
+
+        let rope_scaling = RopeScalingConfig::YaRN {
+            factor: 4.,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        };
+
+        let config = r#"{"max_position_embeddings": 32768, "rope_scaling": null}"#;
+        let config: serde_json::Value =
+            serde_json::from_str(&apply_rope_scaling(config, &rope_scaling).unwrap()).unwrap();
+        assert_eq!(
+            config["rope_scaling"],
+            serde_json::json!({"type": "yarn", "factor": 4.0, "beta_fast": 32.0, "beta_slow": 1.0})
+        );
+
+        // Vision models scale the language model in `text_config`
+        let config = r#"{"text_config": {"max_position_embeddings": 32768}}"#;
+        let config: serde_json::Value =
+            serde_json::from_str(&apply_rope_scaling(config, &rope_scaling).unwrap()).unwrap();
+        assert!(config.get("rope_scaling").is_none());
+        assert_eq!(config["text_config"]["rope_scaling"]["type"], "yarn");
+    }
 }
@@ -1,8 +1,11 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    apply_rope_scaling, get_model_paths, get_xlora_paths,
+    quantization::{apply_quantized_config, QuantizationInfo},
+    text_models_inputs_processor::{FlashParams, ModelInputs},
+    with_hidden_state_capture, AdapterKind, CacheManager, GeneralMetadata, HiddenStateLayer,
+    KVCacheDtype, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader, TokenSource,
+    XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
@@ -15,6 +18,7 @@ use super::{
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
 use crate::amoe::AnyMoeExpertType;
+use crate::layers::RopeScalingConfig;
 use crate::lora::Ordering;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
@@ -35,8 +39,9 @@ use crate::{
 };
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
+use candle_nn::VarMap;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
-use mistralrs_quant::IsqType;
+use mistralrs_quant::{IsqType, QuantizedConfig};
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
 use std::any::Any;
@@ -44,6 +49,7 @@ use std::fs;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, RwLock};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -60,6 +66,8 @@ pub struct NormalPipeline {
     topology: Option<Topology>,
     silent: bool,
     organization: IsqOrganization,
+    isq_exclude: Vec<String>,
+    isq_layer_types: Vec<(String, IsqType)>,
     // For full UQFF serialization
     template_filename: Option<PathBuf>,
     generation_config: Option<PathBuf>,
@@ -78,6 +86,8 @@ pub struct NormalLoader {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    rope_scaling: Option<RopeScalingConfig>,
+    quantized_config: Option<QuantizedConfig>,
     token_source: RwLock<Option<TokenSource>>,
     revision: RwLock<Option<String>>,
     from_uqff: RwLock<Option<PathBuf>>,
@@ -95,6 +105,8 @@ pub struct NormalLoaderBuilder {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    rope_scaling: Option<RopeScalingConfig>,
+    quantized_config: Option<QuantizedConfig>,
 }
 
 #[derive(Clone, Default)]
@@ -102,8 +114,16 @@ pub struct NormalLoaderBuilder {
 pub struct NormalSpecificConfig {
     pub use_flash_attn: bool,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    /// Dtype the KV cache is stored in. With PagedAttention, only FP8 is supported.
+    pub kv_cache_dtype: KVCacheDtype,
     pub topology: Option<Topology>,
     pub organization: IsqOrganization,
+    /// Regexes matched against layer names, such as `lm_head` or `layers\.0\.self_attn`. Matching
+    /// layers are not quantized by ISQ.
+    pub isq_exclude: Vec<String>,
+    /// ISQ types by layer name regex, used instead of the model ISQ type for matching layers. The
+    /// first matching regex is used, for example `(r"self_attn", IsqType::Q8_0)`.
+    pub isq_layer_types: Vec<(String, IsqType)>,
     pub write_uqff: Option<PathBuf>,
     pub from_uqff: Option<PathBuf>,
 }
@@ -132,6 +152,29 @@ impl NormalLoaderBuilder {
         self
     }
 
+    /// Scale the RoPE frequencies to extend the context window, this is written into the model
+    /// config before loading.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScalingConfig) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
+    /// Load the weights with this quantization, usually from [`detect_quantization`]. This is
+    /// written into the model config before loading if it does not have a `quantization_config`,
+    /// for example if it was only in `quantize_config.json`. Only GPTQ and AWQ can be loaded.
+    ///
+    /// [`detect_quantization`]: crate::detect_quantization
+    pub fn with_quantization(mut self, quantization: &QuantizationInfo) -> anyhow::Result<Self> {
+        self.quantized_config = match quantization {
+            QuantizationInfo::Unquantized => None,
+            QuantizationInfo::Hqq { .. } => {
+                anyhow::bail!("Loading HQQ quantized weights is not supported, use ISQ instead.")
+            }
+            _ => quantization.quantized_config(),
+        };
+        Ok(self)
+    }
+
     fn with_adapter(
         mut self,
         xlora_model_id: String,
@@ -207,6 +250,8 @@ impl NormalLoaderBuilder {
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
             tgt_non_granular_index: self.tgt_non_granular_index,
+            rope_scaling: self.rope_scaling,
+            quantized_config: self.quantized_config,
             token_source: RwLock::new(None),
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
@@ -267,7 +312,13 @@ impl Loader for NormalLoader {
         in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let mut config = std::fs::read_to_string(paths.get_config_filename())?;
+        if let Some(rope_scaling) = &self.rope_scaling {
+            config = apply_rope_scaling(&config, rope_scaling)?;
+        }
+        if let Some(quantized_config) = &self.quantized_config {
+            config = apply_quantized_config(&config, quantized_config)?;
+        }
         // Otherwise, the device mapper will print it
         if mapper.is_dummy()
             && (self.config.topology.is_none()
@@ -287,6 +338,27 @@ impl Loader for NormalLoader {
             paged_attn_config = None;
         }
 
+        let paged = mapper.is_paged();
+        if paged {
+            if in_situ_quant.is_some() || self.config.from_uqff.is_some() {
+                anyhow::bail!("Paged device mapping does not support quantized layers.");
+            }
+            if matches!(self.kind, ModelKind::Adapter { .. }) {
+                anyhow::bail!("Paged device mapping is not supported for adapter models.");
+            }
+        }
+        if mapper.is_tensor_parallel() {
+            if in_situ_quant.is_some() || self.config.from_uqff.is_some() {
+                anyhow::bail!("Tensor parallelism does not support quantized layers.");
+            }
+            if matches!(self.kind, ModelKind::Adapter { .. }) {
+                anyhow::bail!("Tensor parallelism is not supported for adapter models.");
+            }
+            if !self.inner.supports_tensor_parallel(&config)? {
+                anyhow::bail!("Tensor parallelism is not supported for this model architecture.");
+            }
+        }
+
         let mapper = mapper.into_mapper(
             self.inner.get_total_device_mapping_num_layers(&config)?,
             device,
@@ -300,7 +372,8 @@ impl Loader for NormalLoader {
                 .get_config_repr(&config, self.config.use_flash_attn)?
         );
 
-        let mut loading_isq = in_situ_quant.is_some() || self.config.from_uqff.is_some();
+        // Paged layers are loaded on the CPU like ISQ layers, and moved to the GPU when they are run.
+        let mut loading_isq = in_situ_quant.is_some() || self.config.from_uqff.is_some() || paged;
         if let Some(ref topology) = self.config.topology {
             loading_isq |= topology
                 .0
@@ -375,7 +448,13 @@ impl Loader for NormalLoader {
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
         let chat_template = get_chat_template(paths, &self.chat_template, None);
 
-        if (in_situ_quant.is_some() || self.config.topology.is_some())
+        if paged {
+            model.page_layers()?;
+        }
+
+        if (in_situ_quant.is_some()
+            || self.config.topology.is_some()
+            || !self.config.isq_layer_types.is_empty())
             && self.config.from_uqff.is_none()
         {
             model.quantize(
@@ -384,6 +463,8 @@ impl Loader for NormalLoader {
                 self.config.topology.as_ref(),
                 silent,
                 self.config.organization,
+                &self.config.isq_exclude,
+                &self.config.isq_layer_types,
                 self.config.write_uqff.as_ref(),
                 UqffFullSer {
                     tokenizer: &tokenizer,
@@ -410,16 +491,25 @@ impl Loader for NormalLoader {
             paged_attn_config
         };
 
+        let kv_cache_dtype = self
+            .config
+            .kv_cache_dtype
+            .for_pipeline(paged_attn_config.is_some());
         let (cache_config, cache_engine) = if let Some(paged_attn_config) = paged_attn_config {
             let cache_config = calculate_cache_config(
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
-                dtype,
+                kv_cache_dtype.paged_cache_dtype(dtype),
                 model.config(),
                 device,
             )?;
-            let cache_engine = CacheEngine::new(model.config(), &cache_config, dtype, device)?;
+            let cache_engine = CacheEngine::new(
+                model.config(),
+                &cache_config,
+                kv_cache_dtype.paged_cache_dtype(dtype),
+                device,
+            )?;
             (Some(cache_config), Some(cache_engine))
         } else {
             (None, None)
@@ -428,6 +518,7 @@ impl Loader for NormalLoader {
         let max_seq_len = model.max_seq_len();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = model.cache().lock().len();
+        model.cache().set_kv_cache_dtype(kv_cache_dtype);
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
         Ok(Arc::new(Mutex::new(NormalPipeline {
@@ -452,13 +543,21 @@ impl Loader for NormalLoader {
                 is_xlora,
                 activation_dtype: dtype,
                 sliding_window,
+                kv_cache_dtype,
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                speculative_accepted_tokens: AtomicUsize::new(0),
+                speculative_drafted_tokens: AtomicUsize::new(0),
+                speculative_draft_calls: AtomicUsize::new(0),
+                per_request_adapter_override: self.kind.is_adapted_and(|a| a.is_lora()),
+                pending_adapter_swaps: Default::default(),
             }),
             topology: self.config.topology.clone(),
             silent,
             organization: self.config.organization,
+            isq_exclude: self.config.isq_exclude.clone(),
+            isq_layer_types: self.config.isq_layer_types.clone(),
             template_filename: paths.get_template_filename().clone(),
             generation_config: paths.get_gen_conf_filename().cloned(),
             config,
@@ -496,6 +595,8 @@ impl IsqPipelineMixin for NormalPipeline {
                 self.topology.as_ref(),
                 self.silent,
                 self.organization,
+                &self.isq_exclude,
+                &self.isq_layer_types,
                 None,
                 UqffFullSer {
                     tokenizer: &self.tokenizer,
@@ -508,6 +609,12 @@ impl IsqPipelineMixin for NormalPipeline {
             )
             .map_err(anyhow::Error::msg)
     }
+
+    fn get_layer_weights(&mut self, name: &str) -> Result<Tensor> {
+        self.model
+            .get_layer_weights(name)
+            .map_err(anyhow::Error::msg)
+    }
 }
 
 impl CacheManagerMixin for NormalPipeline {
@@ -529,11 +636,30 @@ impl CacheManagerMixin for NormalPipeline {
 }
 
 impl AdapterActivationMixin for NormalPipeline {
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_weighted_adapters(
+        &mut self,
+        adapter_names: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
         self.model
             .activate_adapters(adapter_names)
             .map_err(anyhow::Error::msg)
     }
+    fn supports_mixed_adapter_batches(&self) -> bool {
+        self.kind.is_adapted_and(|a| a.is_lora())
+    }
+    fn activate_adapters_per_seq(
+        &mut self,
+        adapter_names: Vec<Option<Vec<String>>>,
+    ) -> anyhow::Result<usize> {
+        self.model
+            .activate_adapters_per_seq(adapter_names)
+            .map_err(anyhow::Error::msg)
+    }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> anyhow::Result<usize> {
+        self.model
+            .merge_adapters(adapter_names)
+            .map_err(anyhow::Error::msg)
+    }
 }
 
 impl MetadataMixin for NormalPipeline {
@@ -631,6 +757,66 @@ impl Pipeline for NormalPipeline {
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn supports_beam_search(&self) -> bool {
+        true
+    }
+    fn supports_contrastive_search(&self) -> bool {
+        !self.model.is_xlora()
+    }
+    fn get_hidden_states(&self, input: &[u32], layer: HiddenStateLayer) -> Result<Tensor> {
+        if self.model.is_xlora() {
+            anyhow::bail!("Getting hidden states is not supported for X-LoRA models.");
+        }
+        if self.metadata.cache_engine.is_some() {
+            anyhow::bail!("Getting hidden states is not supported with PagedAttention.");
+        }
+        if input.is_empty() {
+            anyhow::bail!("Cannot get the hidden states of an empty input.");
+        }
+        let num_layers = self.model.config().num_layers;
+        if let HiddenStateLayer::LayerIndex(idx) = layer {
+            if idx >= num_layers {
+                anyhow::bail!(
+                    "Layer index {idx} is out of range for a model with {num_layers} layers."
+                );
+            }
+        }
+
+        let device = self.device();
+        let seq_len = input.len();
+        let input_ids = Tensor::new(input, &device)?.unsqueeze(0)?;
+        let positions_kernel = Tensor::arange(0i64, seq_len as i64, &device)?.unsqueeze(0)?;
+        let cumulative_seqlens = Tensor::new(&[0, u32::try_from(seq_len)?], &device)?;
+        let flash_params = FlashParams {
+            max_q: u32::try_from(seq_len)?,
+            max_k: u32::try_from(seq_len)?,
+            cumulative_seqlens_q: cumulative_seqlens.clone(),
+            cumulative_seqlens_k: cumulative_seqlens,
+        };
+
+        // Run as a single prompt from an empty cache, and leave the cache empty as between steps.
+        self.set_none_cache(true, false);
+        let result = with_hidden_state_capture(layer, || {
+            self.model.forward(
+                &input_ids,
+                &[0],
+                positions_kernel,
+                vec![(seq_len - 1, 1)],
+                vec![seq_len],
+                None,
+                &flash_params,
+            )
+        });
+        self.set_none_cache(true, false);
+        let (_, hidden_states) = result?;
+        let Some(hidden_states) = hidden_states else {
+            anyhow::bail!(
+                "Model `{}` does not support getting hidden states.",
+                self.model_id
+            );
+        };
+        Ok(hidden_states.squeeze(0)?)
+    }
 }
 
 impl AnyMoePipelineMixin for NormalPipeline {
@@ -640,6 +826,9 @@ impl AnyMoePipelineMixin for NormalPipeline {
     fn amoe_layer_vars(&self) -> Vec<Vec<Var>> {
         self.model.get_vars()
     }
+    fn amoe_var_map(&self) -> VarMap {
+        self.model.get_var_map()
+    }
     fn amoe_base_model_trainable_params(&self) -> usize {
         self.model.trainable_params()
     }
@@ -764,3 +953,170 @@ impl AnyMoePipelineMixin for NormalPipeline {
         self.model.amoe_supported()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device};
+    use candle_nn::VarBuilder;
+    use tokenizers::{
+        models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, PreTokenizerWrapper,
+        Tokenizer,
+    };
+    use tokio::sync::mpsc::channel;
+
+    use super::NormalPipeline;
+    use crate::{
+        layers::Activation,
+        models::qwen2,
+        paged_attention::AttentionImplementation,
+        pipeline::{
+            CacheManagerMixin, ChatTemplate, GeneralMetadata, IsqOrganization, KVCacheDtype,
+            ModelKind, NormalLoadingMetadata, Pipeline,
+        },
+        DefaultSchedulerMethod, DeviceMapMetadata, MistralRsBuilder, NormalRequest, Request,
+        RequestMessage, Response, SamplingParams, SchedulerConfig,
+    };
+
+    /// A pipeline around a tiny Qwen 2 model with zero weights.
+    fn tiny_pipeline(dev: &Device) -> NormalPipeline {
+        let cfg = qwen2::Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            intermediate_size: 12,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            max_position_embeddings: 32,
+            sliding_window: 32,
+            rope_theta: 10000.,
+            rms_norm_eps: 1e-6,
+            hidden_act: Activation::Silu,
+            ..Default::default()
+        };
+        let model = qwen2::Model::new(
+            &cfg,
+            VarBuilder::zeros(DType::F32, dev),
+            false,
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy()
+                    .into_mapper(cfg.num_hidden_layers, dev, None)
+                    .unwrap(),
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            AttentionImplementation::Eager,
+        )
+        .unwrap();
+        let vocab = (0..16u32)
+            .map(|i| (format!("t{i}"), i))
+            .collect::<HashMap<_, _>>();
+        let tokenizer = Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("t0".to_string())
+                .build()
+                .unwrap(),
+        );
+
+        NormalPipeline {
+            model: Box::new(model),
+            tokenizer: Arc::new(tokenizer),
+            no_kv_cache: false,
+            chat_template: Arc::new(ChatTemplate::default()),
+            non_granular_state: None,
+            model_id: "tiny".to_string(),
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len: cfg.max_position_embeddings,
+                tok_trie: None,
+                has_no_kv_cache: false,
+                num_hidden_layers: cfg.num_hidden_layers,
+                eos_tok: vec![0],
+                kind: ModelKind::Normal,
+                is_xlora: false,
+                activation_dtype: DType::F32,
+                sliding_window: None,
+                kv_cache_dtype: KVCacheDtype::FullPrecision,
+                cache_config: None,
+                cache_engine: None,
+                prompt_batchsize: None,
+                speculative_accepted_tokens: AtomicUsize::new(0),
+                speculative_drafted_tokens: AtomicUsize::new(0),
+                speculative_draft_calls: AtomicUsize::new(0),
+                per_request_adapter_override: false,
+                pending_adapter_swaps: Default::default(),
+            }),
+            topology: None,
+            silent: true,
+            organization: IsqOrganization::Default,
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
+            template_filename: None,
+            generation_config: None,
+            config: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let mut pipeline = tiny_pipeline(&Device::Cpu);
+        assert_eq!(pipeline.cache().lock().len(), 2);
+
+        // Prewarming twice must work, as each run starts and ends with an empty cache.
+        for _ in 0..2 {
+            pipeline.prewarm().unwrap();
+            assert!(pipeline.cache().lock().iter().all(Option::is_none));
+        }
+    }
+
+    #[test]
+    fn test_return_prompt_tokens() {
+        let mut pipeline = tiny_pipeline(&Device::Cpu);
+        let mut tokenizer = (*pipeline.tokenizer).clone();
+        tokenizer.with_pre_tokenizer(PreTokenizerWrapper::from(Whitespace {}));
+        let tokenizer = Arc::new(tokenizer);
+        pipeline.tokenizer = tokenizer.clone();
+        let mistralrs = MistralRsBuilder::new(
+            Arc::new(tokio::sync::Mutex::new(pipeline)),
+            SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(1usize.try_into().unwrap()),
+            },
+        )
+        .with_no_prefix_cache(true)
+        .build();
+
+        let prompt = "t3 t5 t1 t7";
+        let (tx, mut rx) = channel(1);
+        let mut request = NormalRequest::new_simple(
+            RequestMessage::Completion {
+                text: prompt.to_string(),
+                echo_prompt: false,
+                best_of: 1,
+            },
+            SamplingParams {
+                max_len: Some(1),
+                ..SamplingParams::deterministic()
+            },
+            tx,
+            0,
+            None,
+            None,
+        );
+        request.return_prompt_tokens = true;
+        mistralrs
+            .get_sender()
+            .unwrap()
+            .blocking_send(Request::Normal(request))
+            .unwrap();
+
+        let Some(Response::CompletionDone(response)) = rx.blocking_recv() else {
+            panic!("Expected a completion response.");
+        };
+        let ids = response.prompt_token_ids.unwrap();
+        assert_eq!(ids, vec![3, 5, 1, 7]);
+        assert_eq!(tokenizer.decode(&ids, false).unwrap(), prompt);
+    }
+}
@@ -0,0 +1,262 @@
+use std::path::Path;
+
+use anyhow::Result;
+use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use mistralrs_quant::{QuantMethodType, QuantizedConfig};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{utils::tokens::get_token, TokenSource};
+
+/// Quantization of the weights of a model, as described by its config files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuantizationInfo {
+    /// No quantization config was found.
+    Unquantized,
+    Gptq {
+        bits: usize,
+        group_size: usize,
+        checkpoint_format: Option<String>,
+    },
+    Awq {
+        bits: usize,
+        group_size: usize,
+    },
+    Hqq {
+        bits: usize,
+        group_size: usize,
+    },
+}
+
+impl QuantizationInfo {
+    /// The config used to load the quantized layers, if they can be loaded directly.
+    pub(crate) fn quantized_config(&self) -> Option<QuantizedConfig> {
+        match self {
+            Self::Gptq {
+                bits,
+                group_size,
+                checkpoint_format,
+            } => Some(QuantizedConfig {
+                bits: *bits,
+                quant_method: QuantMethodType::Gptq,
+                group_size: *group_size,
+                checkpoint_format: checkpoint_format.clone(),
+            }),
+            Self::Awq { bits, group_size } => Some(QuantizedConfig {
+                bits: *bits,
+                quant_method: QuantMethodType::Awq,
+                group_size: *group_size,
+                checkpoint_format: None,
+            }),
+            Self::Unquantized | Self::Hqq { .. } => None,
+        }
+    }
+
+    /// Parse the `quantization_config` of `config.json`, or a `quantize_config.json`.
+    fn from_value(cfg: &Value, default_method: Option<&str>) -> Result<Self> {
+        let method = cfg
+            .get("quant_method")
+            .or_else(|| cfg.get("quant_type"))
+            .and_then(Value::as_str)
+            .or(default_method)
+            .map(str::to_lowercase);
+        let bits = find_usize(cfg, &["bits", "w_bit", "nbits"]);
+        let group_size = find_usize(cfg, &["group_size", "q_group_size"]);
+        let (bits, group_size) = match (bits, group_size) {
+            (Some(bits), Some(group_size)) => (bits?, group_size?),
+            _ => anyhow::bail!("Quantization config must specify `bits` and `group_size`."),
+        };
+        match method.as_deref() {
+            Some("gptq") => Ok(Self::Gptq {
+                bits,
+                group_size,
+                checkpoint_format: cfg
+                    .get("checkpoint_format")
+                    .and_then(Value::as_str)
+                    .map(ToString::to_string),
+            }),
+            Some("awq") => Ok(Self::Awq { bits, group_size }),
+            Some("hqq") => Ok(Self::Hqq { bits, group_size }),
+            Some(other) => anyhow::bail!("Unsupported quantization method `{other}`."),
+            None => anyhow::bail!("Quantization config does not specify the quantization method."),
+        }
+    }
+}
+
+/// Find the first of `keys` in `cfg`, looking into nested objects such as the
+/// `weight_quant_params` of HQQ.
+fn find_usize(cfg: &Value, keys: &[&str]) -> Option<Result<usize>> {
+    let obj = cfg.as_object()?;
+    for key in keys {
+        if let Some(value) = obj.get(*key) {
+            return Some(
+                value
+                    .as_u64()
+                    .and_then(|v| usize::try_from(v).ok())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Expected `{key}` to be a positive integer, got {value}.")
+                    }),
+            );
+        }
+    }
+    obj.values().find_map(|v| find_usize(v, keys))
+}
+
+/// Detect the quantization of a model from `config.json`, falling back to `quantize_config.json`
+/// as written by AutoGPTQ. `get_file` returns the contents of a file, or `None` if the model does
+/// not have it.
+pub(crate) fn detect_quantization_with(
+    mut get_file: impl FnMut(&str) -> Result<Option<String>>,
+) -> Result<QuantizationInfo> {
+    if let Some(config) = get_file("config.json")? {
+        let config: Value = serde_json::from_str(&config)?;
+        if let Some(cfg) = config.get("quantization_config") {
+            return QuantizationInfo::from_value(cfg, None);
+        }
+    }
+    if let Some(cfg) = get_file("quantize_config.json")? {
+        // Older AutoGPTQ checkpoints do not record the method
+        return QuantizationInfo::from_value(&serde_json::from_str(&cfg)?, Some("gptq"));
+    }
+    Ok(QuantizationInfo::Unquantized)
+}
+
+/// Detect the quantization of a model from its config files, which are read from the Hugging Face
+/// Hub, or from the directory if `model_id` is a local path.
+pub fn detect_quantization(
+    model_id: &str,
+    revision: Option<String>,
+    token_source: &TokenSource,
+) -> Result<QuantizationInfo> {
+    let info = if Path::new(model_id).exists() {
+        detect_quantization_with(|file| {
+            let path = Path::new(model_id).join(file);
+            Ok(if path.exists() {
+                Some(std::fs::read_to_string(path)?)
+            } else {
+                None
+            })
+        })?
+    } else {
+        let api = ApiBuilder::new()
+            .with_progress(false)
+            .with_token(get_token(token_source)?)
+            .build()?;
+        let api = api.repo(Repo::with_revision(
+            model_id.to_string(),
+            RepoType::Model,
+            revision.unwrap_or("main".to_string()),
+        ));
+        let files = api
+            .info()?
+            .siblings
+            .into_iter()
+            .map(|x| x.rfilename)
+            .collect::<Vec<_>>();
+        detect_quantization_with(|file| {
+            if files.iter().any(|f| f == file) {
+                Ok(Some(std::fs::read_to_string(api.get(file)?)?))
+            } else {
+                Ok(None)
+            }
+        })?
+    };
+    if info != QuantizationInfo::Unquantized {
+        info!("Detected {info:?} quantization for `{model_id}`.");
+    }
+    Ok(info)
+}
+
+/// Add the detected quantization to the model config, unless it already has a
+/// `quantization_config`.
+pub(crate) fn apply_quantized_config(config: &str, quantized: &QuantizedConfig) -> Result<String> {
+    let mut config: Value = serde_json::from_str(config)?;
+    let Some(target) = config.as_object_mut() else {
+        anyhow::bail!("Expected the model config to be a JSON object.");
+    };
+    if !target.contains_key("quantization_config") {
+        target.insert(
+            "quantization_config".to_string(),
+            serde_json::to_value(quantized)?,
+        );
+    }
+    Ok(serde_json::to_string(&config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{apply_quantized_config, detect_quantization_with, QuantizationInfo};
+
+    fn detect(files: &[(&str, &str)]) -> anyhow::Result<QuantizationInfo> {
+        let hub = files.iter().copied().collect::<HashMap<_, _>>();
+        detect_quantization_with(|file| Ok(hub.get(file).map(ToString::to_string)))
+    }
+
+    #[test]
+    fn test_detect_quantization() {
+        assert_eq!(
+            detect(&[("config.json", r#"{"hidden_size": 64}"#)]).unwrap(),
+            QuantizationInfo::Unquantized
+        );
+        assert_eq!(
+            detect(&[(
+                "config.json",
+                r#"{"quantization_config": {"quant_method": "awq", "bits": 4, "group_size": 128, "version": "gemm"}}"#
+            )])
+            .unwrap(),
+            QuantizationInfo::Awq {
+                bits: 4,
+                group_size: 128
+            }
+        );
+        assert_eq!(
+            detect(&[
+                ("config.json", r#"{"hidden_size": 64}"#),
+                (
+                    "quantize_config.json",
+                    r#"{"bits": 8, "group_size": 32, "desc_act": false}"#
+                ),
+            ])
+            .unwrap(),
+            QuantizationInfo::Gptq {
+                bits: 8,
+                group_size: 32,
+                checkpoint_format: None
+            }
+        );
+        assert_eq!(
+            detect(&[(
+                "config.json",
+                r#"{"quantization_config": {"quant_method": "hqq", "quant_config": {"weight_quant_params": {"nbits": 4, "group_size": 64}}}}"#
+            )])
+            .unwrap(),
+            QuantizationInfo::Hqq {
+                bits: 4,
+                group_size: 64
+            }
+        );
+        assert!(detect(&[("quantize_config.json", r#"{"bits": 4, "group_size": -1}"#)]).is_err());
+        assert!(detect(&[(
+            "config.json",
+            r#"{"quantization_config": {"quant_method": "bitsandbytes", "bits": 4, "group_size": 64}}"#
+        )])
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply_quantized_config() {
+        let info = QuantizationInfo::Gptq {
+            bits: 4,
+            group_size: 128,
+            checkpoint_format: None,
+        };
+        let config =
+            apply_quantized_config(r#"{"hidden_size": 64}"#, &info.quantized_config().unwrap())
+                .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["quantization_config"]["quant_method"], "gptq");
+        assert_eq!(config["quantization_config"]["bits"], 4);
+    }
+}
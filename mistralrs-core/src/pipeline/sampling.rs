@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use candle_core::{DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
@@ -6,8 +6,8 @@ use rand_isaac::Isaac64Rng;
 use crate::{
     get_bias_if_not_allowed,
     prefix_cacher::PrefixCacheManager,
-    sampler::Logprobs,
-    sequence::{Sequence, SequenceRecognizer},
+    sampler::{Logprobs, MinNewTokens, Sampler},
+    sequence::{BeamHypothesis, Sequence, SequenceRecognizer, SequenceState, StopReason},
 };
 
 use super::Pipeline;
@@ -20,69 +20,70 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     eos_tok: Option<&[u32]>,
     use_prefix_cacher: bool,
 ) -> Result<()> {
-    let is_done = seq.is_done(logprobs.token, eos_tok, this.get_metadata().max_seq_len);
-    seq.add_token(
-        logprobs.clone(),
-        this.get_metadata()
-            .tok_trie
-            .as_ref()
-            .ok_or(candle_core::Error::Msg(
-                "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie"
-                    .to_string(),
-            ))?
-            .decode(&[logprobs.token]),
-        &is_done,
-    );
+    let mut is_done = seq.is_done(logprobs.token, eos_tok, this.get_metadata().max_seq_len);
+    let token_text = this
+        .get_metadata()
+        .tok_trie
+        .as_ref()
+        .ok_or(candle_core::Error::Msg(
+            "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie".to_string(),
+        ))?
+        .decode(&[logprobs.token]);
+    seq.add_token(logprobs.clone(), token_text.clone(), &is_done);
+    if is_done.is_none() {
+        // Stop strings may span several tokens, so they are matched after this token is added.
+        is_done = seq.check_stop_strings().or_else(|| seq.check_stop_regex());
+    }
     // Handle streaming requests
     if seq.get_mut_group().is_streaming {
         const STREAMING_RATE_LIMIT: usize = 3;
 
         let token_index = seq.get_toks().len();
-        let rate_limit_allowed = is_done.is_some() || token_index % STREAMING_RATE_LIMIT == 0;
+        // With logprobs, each token is sent in its own chunk, which carries the logprob of that token.
+        let rate_limit_allowed =
+            is_done.is_some() || seq.return_logprobs() || token_index % STREAMING_RATE_LIMIT == 0;
 
         if rate_limit_allowed {
-            if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+            let delta = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder());
+            // The text of a token may be held back, such as an incomplete UTF-8 character or a
+            // possible stop string prefix, but its logprob is still sent with an empty delta.
+            let delta = if seq.return_logprobs() {
+                Some(delta.unwrap_or_default())
+            } else {
+                delta
+            };
+            if let Some(delta) = delta {
+                let chunk_logprobs = seq.return_logprobs().then(|| crate::ResponseLogprob {
+                    token: token_text,
+                    bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
+                    logprob: logprobs.logprob,
+                    top_logprobs: logprobs.top_logprobs.clone().unwrap_or_default(),
+                });
                 if seq.get_mut_group().is_chat {
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content: delta,
                             role: "assistant".to_string(),
                         },
                         index: seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
-                        logprobs: if seq.return_logprobs() {
-                            Some(crate::ResponseLogprob {
-                                token: delta,
-                                bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                logprob: logprobs.logprob,
-                                top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                            })
-                        } else {
-                            None
-                        },
+                        logprobs: chunk_logprobs,
                     });
                 } else {
                     seq.add_streaming_completion_chunk_choice_to_group(
                         crate::CompletionChunkChoice {
-                            text: delta.clone(),
+                            text: delta,
                             index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
-                            logprobs: if seq.return_logprobs() {
-                                Some(crate::ResponseLogprob {
-                                    token: delta,
-                                    bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                    logprob: logprobs.logprob,
-                                    top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                })
-                            } else {
-                                None
-                            },
+                            logprobs: chunk_logprobs,
                         },
                     );
                 }
 
                 if let Some(reason) = is_done {
-                    if use_prefix_cacher {
+                    // A canceled sequence is incomplete, so it is not cached.
+                    if use_prefix_cacher && !matches!(reason, crate::sequence::StopReason::Canceled)
+                    {
                         prefix_cacher.add_sequence(seq);
                         prefix_cacher.evict_to_cpu()?;
                     }
@@ -105,139 +106,147 @@ pub(crate) async fn finish_or_add_toks_to_seq(
             }
         }
     } else if let Some(reason) = is_done {
-        /*
-        ***********************
-        Finish the sequence now
-        ***********************
-        */
-        {
-            seq.set_state(crate::sequence::SequenceState::Done(reason));
-            let (tokenizer, pipeline_name) = {
-                let pipeline_name = this.name();
-                let tokenizer = this.tokenizer();
-                (tokenizer, pipeline_name)
-            };
+        finish_seq(this, prefix_cacher, seq, reason, use_prefix_cacher).await?;
+    }
 
-            let logprobs = if seq.return_logprobs() {
-                let mut logprobs = Vec::new();
-                for logprob in seq.logprobs() {
-                    let resp_logprob = crate::ResponseLogprob {
-                        token: crate::handle_seq_error_ok!(
-                            tokenizer
-                            .as_ref()
-                            .ok_or(candle_core::Error::Msg(
-                                "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
-                                    .to_string(),
-                            ))?.decode(&[logprob.token], false),
-                            seq.responder()
-                        ),
-                        bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
-                        logprob: logprob.logprob,
-                        top_logprobs: logprob.top_logprobs.clone().unwrap(),
-                    };
-                    logprobs.push(resp_logprob);
-                }
-                Some(logprobs)
-            } else {
-                None
-            };
+    Ok(())
+}
 
-            let text = match reason {
-                crate::sequence::StopReason::Length(_)
-                | crate::sequence::StopReason::ModelLength(_)
-                | crate::sequence::StopReason::Eos
-                | crate::sequence::StopReason::StopTok(_)
-                | crate::sequence::StopReason::Canceled => {
-                    String::from_utf8_lossy(seq.completion_bytes())
-                        .trim_start()
-                        .to_string()
-                }
-                crate::sequence::StopReason::StopString {
-                    completion_bytes_pos,
-                    ..
-                } => {
-                    let txt = String::from_utf8_lossy(seq.completion_bytes());
-                    txt[..completion_bytes_pos].trim_start().to_string()
-                }
-                crate::sequence::StopReason::GeneratedImage => {
-                    candle_core::bail!("Stop reason was `GeneratedImage`.")
-                }
-            };
+/// Finish the sequence with `reason`, adding its choice to the group. The response is sent once
+/// the group has all of its choices.
+async fn finish_seq(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManager,
+    seq: &mut Sequence,
+    reason: StopReason,
+    use_prefix_cacher: bool,
+) -> Result<()> {
+    seq.set_state(crate::sequence::SequenceState::Done(reason));
+    let (tokenizer, pipeline_name) = {
+        let pipeline_name = this.name();
+        let tokenizer = this.tokenizer();
+        (tokenizer, pipeline_name)
+    };
 
-            if seq.get_mut_group().is_chat {
-                let mut tool_calls = Vec::new();
-                let mut text_new = Some(text.clone());
-                if let Some(ref matcher) = seq.tools {
-                    let calls = matcher.get_call(&text).map_err(candle_core::Error::msg)?;
-                    if !calls.is_empty() {
-                        text_new = None;
-                    }
-                    tool_calls = calls;
-                }
-                let choice = crate::Choice {
-                    finish_reason: reason.to_string(),
-                    index: seq.get_response_index(),
-                    message: crate::ResponseMessage {
-                        content: text_new,
-                        role: "assistant".to_string(),
-                        tool_calls,
-                    },
-                    logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
-                };
-                seq.add_choice_to_group(choice);
-            } else {
-                let choice = crate::CompletionChoice {
-                    finish_reason: reason.to_string(),
-                    index: seq.get_response_index(),
-                    text,
-                    logprobs: None,
-                };
-                seq.add_completion_choice_to_group(choice);
-            }
+    let logprobs = if seq.return_logprobs() {
+        let mut logprobs = Vec::new();
+        for logprob in seq.logprobs() {
+            let resp_logprob = crate::ResponseLogprob {
+                token: crate::handle_seq_error_ok!(
+                    tokenizer
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
+                                .to_string(),
+                        ))?
+                        .decode(&[logprob.token], false),
+                    seq.responder()
+                ),
+                bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
+                logprob: logprob.logprob,
+                top_logprobs: logprob.top_logprobs.clone().unwrap(),
+            };
+            logprobs.push(resp_logprob);
+        }
+        Some(logprobs)
+    } else {
+        None
+    };
 
-            if use_prefix_cacher {
-                prefix_cacher.add_sequence(seq);
-                prefix_cacher.evict_to_cpu()?;
-            }
+    let text = match reason {
+        crate::sequence::StopReason::Length(_)
+        | crate::sequence::StopReason::ModelLength(_)
+        | crate::sequence::StopReason::Eos
+        | crate::sequence::StopReason::StopTok(_)
+        | crate::sequence::StopReason::StopRegex
+        | crate::sequence::StopReason::Canceled => String::from_utf8_lossy(seq.completion_bytes())
+            .trim_start()
+            .to_string(),
+        crate::sequence::StopReason::StopString {
+            completion_bytes_pos,
+            ..
+        } => {
+            let txt = String::from_utf8_lossy(seq.completion_bytes());
+            txt[..completion_bytes_pos].trim_start().to_string()
+        }
+        crate::sequence::StopReason::GeneratedImage => {
+            candle_core::bail!("Stop reason was `GeneratedImage`.")
+        }
+    };
 
-            let group = seq.get_mut_group();
-            if group.is_chat {
-                group
-                    .maybe_send_chat_done_response(
-                        crate::ChatCompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "chat.completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
-            } else {
-                group
-                    .maybe_send_completion_done_response(
-                        crate::CompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_completion_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "text_completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
+    if seq.get_mut_group().is_chat {
+        let mut tool_calls = Vec::new();
+        let mut text_new = Some(text.clone());
+        if let Some(ref matcher) = seq.tools {
+            let calls = matcher.get_call(&text).map_err(candle_core::Error::msg)?;
+            if !calls.is_empty() {
+                text_new = None;
             }
+            tool_calls = calls;
         }
-        this.reset_non_granular_state();
+        let choice = crate::Choice {
+            finish_reason: reason.to_string(),
+            index: seq.get_response_index(),
+            message: crate::ResponseMessage {
+                content: text_new,
+                role: "assistant".to_string(),
+                tool_calls,
+            },
+            logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+        };
+        seq.add_choice_to_group(choice);
+    } else {
+        let choice = crate::CompletionChoice {
+            finish_reason: reason.to_string(),
+            index: seq.get_response_index(),
+            text,
+            logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+        };
+        seq.add_completion_choice_to_group(choice);
+    }
+
+    if use_prefix_cacher && !matches!(reason, crate::sequence::StopReason::Canceled) {
+        prefix_cacher.add_sequence(seq);
+        prefix_cacher.evict_to_cpu()?;
     }
 
+    let group = seq.get_mut_group();
+    if group.is_chat {
+        group
+            .maybe_send_chat_done_response(
+                crate::ChatCompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "chat.completion".to_string(),
+                    usage: group.get_usage(),
+                    prompt_token_ids: seq.prompt_token_ids().map(<[u32]>::to_vec),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    } else {
+        group
+            .maybe_send_completion_done_response(
+                crate::CompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_completion_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                    usage: group.get_usage(),
+                    prompt_token_ids: seq.prompt_token_ids().map(<[u32]>::to_vec),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    }
+    this.reset_non_granular_state();
     Ok(())
 }
 
@@ -249,12 +258,55 @@ pub async fn sample_and_add_toks(
     disable_eos_stop: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
 ) -> Result<()> {
-    let seqs_len = seqs.len();
-    debug_assert_eq!(logits_seq.len(), seqs_len);
+    debug_assert_eq!(logits_seq.len(), seqs.len());
+
+    let eos_tok = this.get_metadata().eos_tok.clone();
+
+    // The beams of a beam search request are stepped together.
+    let mut beam_groups: HashMap<usize, Vec<(Tensor, &mut Sequence)>> = HashMap::new();
+    let mut sampled_logits = Vec::new();
+    for (logits, seq) in std::iter::zip(logits_seq, seqs.iter_mut()) {
+        match seq.beam_group_id() {
+            Some(group_id) => beam_groups
+                .entry(group_id)
+                .or_default()
+                .push((logits, &mut **seq)),
+            // Contrastive search sequences select among the candidates run by the step.
+            None if seq.contrastive().is_some() => (),
+            None => sampled_logits.push(logits),
+        }
+    }
+    if !beam_groups.is_empty() {
+        for beams in beam_groups.into_values() {
+            step_beams(
+                this,
+                prefix_cacher,
+                beams,
+                (!disable_eos_stop).then_some(&eos_tok[..]),
+            )
+            .await?;
+        }
+        // The beams may continue other beams, so the model cache is replaced by their caches.
+        this.clone_in_cache(seqs, false);
+    }
+
+    for seq in seqs.iter_mut().filter(|seq| seq.contrastive().is_some()) {
+        step_contrastive(
+            this,
+            prefix_cacher,
+            seq,
+            (!disable_eos_stop).then_some(&eos_tok[..]),
+        )
+        .await?;
+    }
 
-    let use_async_pool = seqs_len > 1;
+    let mut sampled_seqs = seqs
+        .iter_mut()
+        .filter(|seq| seq.beam_group_id().is_none() && seq.contrastive().is_none())
+        .collect::<Vec<_>>();
+    let use_async_pool = sampled_seqs.len() > 1;
 
-    let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
+    let sampling_futures: Vec<_> = std::iter::zip(sampled_logits, sampled_seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
             sample_sequence(
@@ -265,12 +317,13 @@ pub async fn sample_and_add_toks(
                 use_async_pool,
                 true, // Append result to trie
                 false,
+                &eos_tok,
             )
         })
         .collect();
     let sampled_vec = futures::future::join_all(sampling_futures).await;
 
-    for (sampled, seq) in std::iter::zip(sampled_vec, seqs.iter_mut()) {
+    for (sampled, seq) in std::iter::zip(sampled_vec, sampled_seqs.iter_mut()) {
         let next_token = crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
         let metadata = this.get_metadata();
@@ -286,7 +339,253 @@ pub async fn sample_and_add_toks(
     Ok(())
 }
 
+/// Add the token selected by [`ContrastiveSearchConfig::select`] to a contrastive search
+/// sequence, among the candidates the pipeline step ran the model on.
+///
+/// [`ContrastiveSearchConfig::select`]: crate::sampler::ContrastiveSearchConfig::select
+async fn step_contrastive(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManager,
+    seq: &mut Sequence,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    let state = seq
+        .contrastive_mut()
+        .expect("Sequence must do contrastive search.");
+    let (Some((candidates, candidate_hidden_states)), Some(context_hidden_states)) =
+        (state.candidates.take(), &state.context_hidden_states)
+    else {
+        candle_core::bail!("The pipeline step did not run the contrastive search candidates.");
+    };
+    let token =
+        state
+            .config
+            .select(&candidates, &candidate_hidden_states, context_hidden_states)?;
+    let prob = candidates
+        .iter()
+        .find_map(|(candidate, prob)| (*candidate == token).then_some(*prob))
+        .expect("The selected token must be a candidate.");
+    let logprobs = Logprobs {
+        token,
+        // Base 10, as returned by the sampler
+        logprob: prob.log10(),
+        bytes: None,
+        top_logprobs: None,
+    };
+    finish_or_add_toks_to_seq(this, prefix_cacher, seq, logprobs, eos_tok, true).await
+}
+
+/// Step the beams of one beam search request, ordered by beam index. Each beam is replaced by one
+/// of the best candidates of [`Sampler::sample_beam`] which does not finish, and the finished
+/// candidates are kept as hypotheses by the group. Once the search is done, the best hypotheses are
+/// returned as the choices of the request.
+async fn step_beams(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManager,
+    mut beams: Vec<(Tensor, &mut Sequence)>,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    beams.sort_by_key(|(_, seq)| seq.beam().map(|beam| beam.index));
+    let config = beams[0]
+        .1
+        .beam()
+        .expect("Beams must have a beam state.")
+        .config
+        .clone();
+    if beams.len() != config.num_beams {
+        candle_core::bail!(
+            "Expected all {} beams of a request in the batch, got {}.",
+            config.num_beams,
+            beams.len()
+        );
+    }
+    let metadata = this.get_metadata();
+    let tok_trie = metadata.tok_trie.as_ref().ok_or(candle_core::Error::Msg(
+        "Beam search requires the pipeline to have a token trie".to_string(),
+    ))?;
+    let prompt_len = beams[0].1.prompt_tokens();
+
+    // Terminated sequences are finished as they are.
+    let canceled = beams
+        .iter()
+        .any(|(_, seq)| matches!(seq.getstate(), SequenceState::Done(StopReason::Canceled)));
+
+    let mut running = vec![false; beams.len()];
+    if !canceled {
+        let mut logits = Vec::new();
+        for (beam_logits, seq) in &beams {
+            let beam_logits = beam_logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+            logits.push(match seq.min_new_tokens(&metadata.eos_tok) {
+                Some(min_new_tokens) => {
+                    let mut beam_logits: Vec<f32> = beam_logits.to_vec1()?;
+                    min_new_tokens.apply(&mut beam_logits);
+                    let vocab_size = beam_logits.len();
+                    Tensor::from_vec(beam_logits, vocab_size, &Device::Cpu)?
+                }
+                None => beam_logits,
+            });
+        }
+        let logits = Tensor::stack(&logits, 0)?;
+        let sampler = beams[0].1.sampler();
+        let scores = beams
+            .iter()
+            .map(|(_, seq)| seq.beam().map_or(0., |beam| beam.score))
+            .collect::<Vec<_>>();
+        let contexts = beams
+            .iter()
+            .map(|(_, seq)| seq.get_toks())
+            .collect::<Vec<_>>();
+        let candidates = sampler.sample_beam(&logits, &scores, &contexts, config.num_beams)?;
+
+        let snapshots = beams
+            .iter()
+            .map(|(_, seq)| seq.beam_snapshot())
+            .collect::<Vec<_>>();
+        let mut candidates = candidates.into_iter().filter(|c| c.score.is_finite());
+        for ((_, seq), is_running) in beams.iter_mut().zip(&mut running) {
+            // Candidates which finish become hypotheses, until one continues this beam.
+            for candidate in candidates.by_ref() {
+                seq.restore_beam_snapshot(snapshots[candidate.parent].clone());
+                let is_done = seq.is_done(candidate.token, eos_tok, metadata.max_seq_len);
+                #[allow(clippy::cast_possible_truncation)]
+                let logprobs = Logprobs {
+                    token: candidate.token,
+                    // Base 10, as returned by the sampler
+                    logprob: ((candidate.score - scores[candidate.parent])
+                        / std::f64::consts::LN_10) as f32,
+                    bytes: None,
+                    top_logprobs: None,
+                };
+                seq.add_token(logprobs, tok_trie.decode(&[candidate.token]), &is_done);
+                let is_done =
+                    is_done.or_else(|| seq.check_stop_strings().or_else(|| seq.check_stop_regex()));
+                seq.set_beam_score(candidate.score);
+                match is_done {
+                    Some(reason) => {
+                        let snapshot = seq.beam_snapshot();
+                        let hypothesis = BeamHypothesis {
+                            normalized_score: config.normalized_score(
+                                candidate.score,
+                                snapshot.generated_len(prompt_len),
+                            ),
+                            reason,
+                            snapshot,
+                        };
+                        seq.get_mut_group()
+                            .add_beam_hypothesis(hypothesis, config.num_beams);
+                    }
+                    None => {
+                        *is_running = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let done = canceled || running.contains(&false) || {
+        let group = beams[0].1.get_mut_group();
+        group.beam_hypotheses.len() >= config.num_beams
+            && (config.early_stopping || {
+                // The running beams cannot improve on the hypotheses if even the best one, with
+                // its current length, scores below the worst hypothesis.
+                let best_running = beams
+                    .iter()
+                    .filter_map(|(_, seq)| seq.beam().map(|beam| beam.score))
+                    .fold(f64::NEG_INFINITY, f64::max);
+                let generated = beams[0].1.get_toks().len().saturating_sub(prompt_len);
+                group.beam_hypotheses.last().is_some_and(|worst| {
+                    config.normalized_score(best_running, generated) <= worst.normalized_score
+                })
+            })
+    };
+    if !done {
+        return Ok(());
+    }
+
+    // The running beams are hypotheses too, which only matters if too few beams finished.
+    for ((_, seq), is_running) in beams.iter().zip(&running) {
+        if *is_running || canceled {
+            let snapshot = seq.beam_snapshot();
+            let generated = snapshot.generated_len(prompt_len);
+            let hypothesis = BeamHypothesis {
+                normalized_score: config
+                    .normalized_score(seq.beam().map_or(0., |beam| beam.score), generated),
+                reason: if canceled {
+                    StopReason::Canceled
+                } else {
+                    StopReason::Length(generated)
+                },
+                snapshot,
+            };
+            seq.get_mut_group()
+                .add_beam_hypothesis(hypothesis, config.num_beams);
+        }
+    }
+    let (hypotheses, n_choices) = {
+        let mut group = beams[0].1.get_mut_group();
+        (
+            std::mem::take(&mut group.beam_hypotheses),
+            group.n_choices(),
+        )
+    };
+
+    // Beam `i` returns the `i`th best hypothesis, so the response is sent once the last choice is
+    // added. The other beams are finished without a choice.
+    for (_, seq) in &beams[n_choices.min(hypotheses.len())..] {
+        seq.set_state(SequenceState::Done(
+            hypotheses.last().map_or(StopReason::Canceled, |h| h.reason),
+        ));
+    }
+    for ((_, seq), hypothesis) in beams.iter_mut().zip(hypotheses).take(n_choices) {
+        let BeamHypothesis {
+            reason, snapshot, ..
+        } = hypothesis;
+        seq.restore_beam_snapshot(snapshot);
+        finish_seq(this, prefix_cacher, seq, reason, true).await?;
+    }
+    Ok(())
+}
+
+/// Sample with classifier-free guidance if the unconditional logits were computed for this step.
+#[allow(clippy::too_many_arguments)]
+fn sample_guided(
+    sampler: &Sampler,
+    logits: Tensor,
+    cfg: Option<&(Tensor, f64)>,
+    context: &[u32],
+    return_logprobs: bool,
+    rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    sample_speculative: bool,
+    min_new_tokens: Option<&MinNewTokens>,
+) -> Result<Logprobs> {
+    match cfg {
+        Some((uncond_logits, cfg_scale)) => sampler.sample_cfg(
+            logits,
+            uncond_logits,
+            *cfg_scale,
+            context,
+            return_logprobs,
+            rng,
+            sample_speculative,
+            min_new_tokens,
+        ),
+        None => sampler.sample(
+            logits,
+            context,
+            return_logprobs,
+            rng,
+            sample_speculative,
+            min_new_tokens,
+        ),
+    }
+}
+
 /// Async sample optionally adding to trie.
+///
+/// The `eos_tok` are masked while fewer than the sequence's `min_new_tokens` have been generated.
+/// The first token of a sequence with token healing must start with the healed end of the prompt.
+/// If the pipeline computed unconditional logits for the sequence, classifier-free guidance is applied.
 #[allow(clippy::too_many_arguments)]
 pub async fn sample_sequence(
     logits: Tensor,
@@ -296,31 +595,62 @@ pub async fn sample_sequence(
     use_async_pool: bool,
     add_to_trie: bool,
     sample_speculative: bool,
+    eos_tok: &[u32],
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    let logits = match seq.token_healing() {
+        Some(token_healing) => {
+            let device = logits.device().clone();
+            let mut logits: Vec<f32> = logits.to_vec1()?;
+            token_healing.apply(&mut logits);
+            let vocab_size = logits.len();
+            Tensor::from_vec(logits, vocab_size, &device)?
+        }
+        None => logits,
+    };
 
+    let min_new_tokens = seq.min_new_tokens(eos_tok);
+    let cfg = seq
+        .take_cfg_uncond_logits()
+        .map(|(uncond_logits, cfg_scale)| {
+            Ok::<_, candle_core::Error>((
+                uncond_logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?,
+                cfg_scale,
+            ))
+        })
+        .transpose()?;
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
+    // A seeded sequence samples with its own RNG.
+    let rng = seq.rng().unwrap_or(rng);
     let rng_clone = rng.clone();
     let logits_clone = logits.clone();
+    let min_new_tokens_clone = min_new_tokens.clone();
+    let cfg_clone = cfg.clone();
     let first_lobprobs_response = if use_async_pool {
         tokio_rayon::spawn(move || {
-            sampler.sample(
+            sample_guided(
+                &sampler,
                 logits_clone,
+                cfg_clone.as_ref(),
                 &ctx_clone,
                 return_logprobs,
                 rng_clone,
                 sample_speculative,
+                min_new_tokens_clone.as_ref(),
             )
         })
         .await?
     } else {
-        sampler.sample(
+        sample_guided(
+            &sampler,
             logits_clone,
+            cfg.as_ref(),
             &ctx_clone,
             return_logprobs,
             rng_clone,
             sample_speculative,
+            min_new_tokens.as_ref(),
         )?
     };
 
@@ -331,6 +661,12 @@ pub async fn sample_sequence(
         SequenceRecognizer::Cfg(ref mut cfg) => {
             get_bias_if_not_allowed!(seq.tok_trie, cfg.as_mut(), first_lobprobs_response.token)
         }
+        SequenceRecognizer::Gbnf(ref mut gbnf) => {
+            get_bias_if_not_allowed!(seq.tok_trie, gbnf.as_mut(), first_lobprobs_response.token)
+        }
+        SequenceRecognizer::JsonSchema(ref mut fsm) => {
+            get_bias_if_not_allowed!(seq.tok_trie, fsm.as_mut(), first_lobprobs_response.token)
+        }
         SequenceRecognizer::None => None,
     };
     let second_logprobs_response = match bias_if_not_allowed {
@@ -350,24 +686,31 @@ pub async fn sample_sequence(
             let ctx_clone = seq.get_toks().to_vec();
             let rng_clone = rng.clone();
             let sampler = seq.sampler();
+            let min_new_tokens_clone = min_new_tokens.clone();
             if use_async_pool {
                 tokio_rayon::spawn(move || {
-                    sampler.sample(
+                    sample_guided(
+                        &sampler,
                         new_logits,
+                        cfg.as_ref(),
                         &ctx_clone,
                         return_logprobs,
                         rng_clone,
                         sample_speculative,
+                        min_new_tokens_clone.as_ref(),
                     )
                 })
                 .await?
             } else {
-                sampler.sample(
+                sample_guided(
+                    &sampler,
                     new_logits,
+                    cfg.as_ref(),
                     &ctx_clone,
                     return_logprobs,
                     rng_clone,
                     sample_speculative,
+                    min_new_tokens.as_ref(),
                 )?
             }
         }
@@ -390,6 +733,20 @@ pub async fn sample_sequence(
                     .append_token(cfg.as_mut(), second_logprobs_response.token)
                     .map_err(candle_core::Error::msg)?;
             }
+            SequenceRecognizer::Gbnf(ref mut gbnf) => {
+                seq.tok_trie
+                    .as_ref()
+                    .unwrap()
+                    .append_token(gbnf.as_mut(), second_logprobs_response.token)
+                    .map_err(candle_core::Error::msg)?;
+            }
+            SequenceRecognizer::JsonSchema(ref mut fsm) => {
+                seq.tok_trie
+                    .as_ref()
+                    .unwrap()
+                    .append_token(fsm.as_mut(), second_logprobs_response.token)
+                    .map_err(candle_core::Error::msg)?;
+            }
             SequenceRecognizer::None => {}
         }
     }
@@ -408,6 +765,7 @@ pub async fn sample_target_sequence_speculative(
     return_logprobs: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
     n_toks: usize,
+    eos_tok: &[u32],
 ) -> Result<Vec<SpeculativeSample>> {
     let mut sampled = Vec::new();
     for chunk in logits.chunk(n_toks, 1)? {
@@ -420,6 +778,7 @@ pub async fn sample_target_sequence_speculative(
                 true,  // TODO(EricLBuehler): does this hurt perf?
                 false, // Do not append to trie (yet)
                 true,
+                eos_tok,
             )
             .await?,
         });
@@ -1,11 +1,15 @@
 use std::{
     any::Any,
+    collections::{HashMap, HashSet, VecDeque},
     iter::zip,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use anyhow::Result as anyhowResult;
-use candle_core::{Device, IndexOp, Result, Tensor};
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
@@ -20,13 +24,17 @@ use crate::{
         AdapterInstruction, Cache,
     },
     prefix_cacher::PrefixCacheManager,
+    sampler::{suffix_match_lengths, Logprobs},
     sequence::{Sequence, SequenceRecognizer},
     DeviceMapMetadata, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource,
     TryIntoDType,
 };
 
 use super::{
-    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
+    cache_manager::{DefaultCacheManager, LayerCaches},
+    chat_template::ChatTemplate,
+    sampling::SpeculativeSample,
+    text_models_inputs_processor::{FlashParams, ModelInputs},
     AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
     CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
     MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
@@ -61,6 +69,13 @@ impl Loader for SpeculativeLoader {
             paged_attn_config
         };
 
+        // A pre-quantized (e.g. GGUF) draft model cannot be quantized again.
+        let draft_in_situ_quant = if self.draft.get_kind().is_quantized() {
+            None
+        } else {
+            in_situ_quant
+        };
+
         let target = self.target.load_model_from_hf(
             revision.clone(),
             token_source.clone(),
@@ -78,7 +93,7 @@ impl Loader for SpeculativeLoader {
             device,
             silent,
             mapper,
-            in_situ_quant,
+            draft_in_situ_quant,
             paged_attn_config,
         )?;
         Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
@@ -108,6 +123,13 @@ impl Loader for SpeculativeLoader {
             paged_attn_config
         };
 
+        // A pre-quantized (e.g. GGUF) draft model cannot be quantized again.
+        let draft_in_situ_quant = if self.draft.get_kind().is_quantized() {
+            None
+        } else {
+            in_situ_quant
+        };
+
         let target = self.target.load_model_from_path(
             paths,
             dtype,
@@ -123,7 +145,7 @@ impl Loader for SpeculativeLoader {
             device,
             silent,
             mapper.clone(),
-            in_situ_quant,
+            draft_in_situ_quant,
             paged_attn_config,
         )?;
         Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
@@ -148,6 +170,92 @@ impl Loader for SpeculativeLoader {
     }
 }
 
+/// A loader for a speculative pipeline drafting by prompt lookup, which only loads the target
+/// [`Loader`].
+pub struct PromptLookupLoader {
+    pub target: Box<dyn Loader>,
+    pub config: PromptLookupConfig,
+}
+
+impl Loader for PromptLookupLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_prompt_lookup(target, self.config)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_prompt_lookup(target, self.config)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Prompt lookup: tgt = `{}`, gamma = `{}`, max n-gram size = `{}`",
+            self.target.get_id(),
+            self.config.gamma,
+            self.config.max_ngram_size,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::PromptLookup {
+            target: Box::new(self.target.get_kind()),
+        }
+    }
+}
+
 /// Speculative decoding pipeline: <https://arxiv.org/pdf/2211.17192>
 ///
 /// # Algorithm
@@ -159,19 +267,348 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+/// Instead of a draft model, the draft tokens may come from prompt lookup
+/// (<https://github.com/apoorvumang/prompt-lookup-decoding>): the tokens which followed an earlier
+/// occurrence of the end of the sequence. They are verified by the target model in the same way.
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    draft: Draft,
+    /// Only for a draft model with [`VocabMismatchPolicy::TokenMap`].
+    token_map: Option<DraftTokenMap>,
     gamma: usize,
+    /// Only with [`SpeculativeConfig::adaptive_gamma`].
+    gamma_controller: Option<Mutex<GammaController>>,
+    draft_branches: usize,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
 }
 
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+/// How the tokenizer vocabs of the target and draft models are compared.
+pub enum VocabMismatchPolicy {
+    /// The vocabs, including the special tokens, must be identical.
+    #[default]
+    Strict,
+    /// Special tokens are ignored when comparing the vocabs. This allows a draft model whose
+    /// tokenizer comes from another source (for example, a GGUF file) and registers different
+    /// special tokens. All other tokens must still have the same ids.
+    IgnoreSpecial,
+    /// The draft tokens are mapped to the target tokens with the same content, which may have
+    /// other ids. At least `min_overlap` (a fraction between 0 and 1) of the draft vocab must be
+    /// shared with the target. Draft tokens without a target counterpart are rejected. Sequences
+    /// with a target token without a draft counterpart are generated without drafting.
+    TokenMap { min_overlap: f32 },
+}
+
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
     /// γ completions to run of the draft model
     pub gamma: usize,
+    /// How the target and draft tokenizer vocabs are compared.
+    pub vocab_mismatch_policy: VocabMismatchPolicy,
+    /// Number of draft branches. With more than 1, the draft tokens form a [`DraftTree`] which
+    /// may branch at any depth, and whose branches the target model verifies in one batch.
+    pub draft_branches: usize,
+    /// Adjust the number of draft tokens per step to the recent acceptance rate, starting from
+    /// `gamma`. `None` always drafts `gamma` tokens.
+    pub adaptive_gamma: Option<AdaptiveGamma>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// Bounds of the number of draft tokens per step with an adaptive gamma.
+pub struct AdaptiveGamma {
+    pub gamma_min: usize,
+    pub gamma_max: usize,
+}
+
+#[derive(Copy, Clone)]
+/// Metadata for a speculative pipeline drafting by prompt lookup
+pub struct PromptLookupConfig {
+    /// Maximum number of draft tokens per step
+    pub gamma: usize,
+    /// Maximum length of the end of the sequence which is looked up in the sequence.
+    pub max_ngram_size: usize,
+}
+
+/// Where the draft tokens of a [`SpeculativePipeline`] come from.
+enum Draft {
+    /// A draft model, run `gamma` times per step.
+    Model(Arc<tokio::sync::Mutex<dyn Pipeline>>),
+    /// Prompt lookup, see [`prompt_lookup`].
+    PromptLookup { max_ngram_size: usize },
+}
+
+/// Draft up to `gamma` tokens by prompt lookup: the tokens which followed the most recent earlier
+/// occurrence of the longest end of `toks`, of at most `max_ngram_size` tokens. Empty if the last
+/// token never occurred before.
+fn prompt_lookup(toks: &[u32], max_ngram_size: usize, gamma: usize) -> Vec<u32> {
+    let match_lengths = suffix_match_lengths(toks);
+    let best = match_lengths[..toks.len().saturating_sub(1)]
+        .iter()
+        .map(|len| (*len).min(max_ngram_size))
+        .enumerate()
+        .filter(|(_, len)| *len > 0)
+        .max_by_key(|(i, len)| (*len, *i));
+    match best {
+        Some((i, _)) => toks[i + 1..toks.len().min(i + 1 + gamma)].to_vec(),
+        None => Vec::new(),
+    }
+}
+
+/// Accept the target samples while the draft tokens agree with them. The first target sample
+/// which disagrees replaces its draft token, and a target sample following all of the draft
+/// tokens is accepted too. Returns the accepted samples and the number of accepted draft tokens.
+fn accept_draft_tokens(
+    target_samples: Vec<SpeculativeSample>,
+    draft_tokens: &[u32],
+) -> (Vec<Logprobs>, usize) {
+    let mut accepted = Vec::new();
+    let mut n_accepted_draft = 0;
+    for (i, target_sample) in target_samples.into_iter().enumerate() {
+        let tok = target_sample.sample.token;
+        accepted.push(target_sample.sample);
+        if draft_tokens.get(i) != Some(&tok) {
+            break;
+        }
+        n_accepted_draft += 1;
+    }
+    (accepted, n_accepted_draft)
+}
+
+/// Number of recent steps whose acceptance rate adjusts an adaptive gamma.
+const GAMMA_WINDOW: usize = 8;
+/// Gamma grows when at least this fraction of the recent draft tokens was accepted...
+const GAMMA_GROW_RATE: f32 = 0.8;
+/// ...and shrinks when less than this fraction was.
+const GAMMA_SHRINK_RATE: f32 = 0.5;
+
+/// Chooses the number of draft tokens of each step from the acceptance rate of the last
+/// [`GAMMA_WINDOW`] steps, within the [`AdaptiveGamma`] bounds.
+struct GammaController {
+    gamma: usize,
+    bounds: AdaptiveGamma,
+    /// (drafted, accepted) of each recent step
+    recent: VecDeque<(usize, usize)>,
+}
+
+impl GammaController {
+    fn new(gamma: usize, bounds: AdaptiveGamma) -> Self {
+        Self {
+            gamma,
+            bounds,
+            recent: VecDeque::with_capacity(GAMMA_WINDOW),
+        }
+    }
+
+    fn gamma(&self) -> usize {
+        self.gamma
+    }
+
+    /// Record a step which drafted `drafted` tokens, of which `accepted` were accepted, and adjust
+    /// gamma by 1 if the recent acceptance rate is high or low.
+    fn record(&mut self, drafted: usize, accepted: usize) {
+        if self.recent.len() == GAMMA_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((drafted, accepted));
+        let (drafted, accepted) = self
+            .recent
+            .iter()
+            .fold((0, 0), |(d, a), (drafted, accepted)| {
+                (d + drafted, a + accepted)
+            });
+        if drafted == 0 {
+            return;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let rate = accepted as f32 / drafted as f32;
+        if rate >= GAMMA_GROW_RATE {
+            self.gamma = (self.gamma + 1).min(self.bounds.gamma_max);
+        } else if rate < GAMMA_SHRINK_RATE {
+            self.gamma = self.gamma.saturating_sub(1).max(self.bounds.gamma_min);
+        }
+    }
+}
+
+/// Draft tokens as a tree of candidate continuations. Each node holds a token and the index of
+/// its parent, the node it follows. Roots, with no parent, follow the last token of the sequence.
+#[derive(Default)]
+struct DraftTree {
+    tokens: Vec<u32>,
+    parents: Vec<Option<usize>>,
+}
+
+impl DraftTree {
+    /// Add a node with `token` after `parent`, returning its index.
+    fn push(&mut self, token: u32, parent: Option<usize>) -> usize {
+        self.tokens.push(token);
+        self.parents.push(parent);
+        self.tokens.len() - 1
+    }
+
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// The child of `parent` (or the root, for `None`) holding `token`, if any.
+    fn child_with_token(&self, parent: Option<usize>, token: u32) -> Option<usize> {
+        (0..self.len()).find(|i| self.parents[*i] == parent && self.tokens[*i] == token)
+    }
+
+    /// The nodes without children, in the order they were added.
+    fn leaves(&self) -> Vec<usize> {
+        (0..self.len())
+            .filter(|i| !self.parents.contains(&Some(*i)))
+            .collect()
+    }
+
+    /// Whether `node` is `ancestor` or one of its descendants.
+    fn descends_from(&self, mut node: usize, ancestor: usize) -> bool {
+        loop {
+            if node == ancestor {
+                return true;
+            }
+            match self.parents[node] {
+                Some(parent) => node = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// The tokens from a root to `node`, inclusive.
+    fn path(&self, node: usize) -> Vec<u32> {
+        let mut path = vec![self.tokens[node]];
+        let mut node = node;
+        while let Some(parent) = self.parents[node] {
+            path.push(self.tokens[parent]);
+            node = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// A branch of a [`DraftTree`] while it is drafted.
+struct DraftBranch {
+    tokens: Vec<u32>,
+    /// Draft log probability of `tokens`.
+    logprob: f32,
+    /// The draft cache after running the draft model on the branch, except on the token of the
+    /// last depth.
+    draft_cache: LayerCaches,
+    /// The draft logits and sample of the next token, before the last depth.
+    next: Option<(Vec<f32>, u32)>,
+}
+
+/// Log probabilities of the tokens with `logits`.
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum).collect()
+}
+
+/// The `k` tokens with the highest logits, except `exclude`, from the highest.
+fn top_k_tokens(logits: &[f32], k: usize, exclude: u32) -> Vec<u32> {
+    let mut candidates = (0u32..)
+        .zip(logits)
+        .filter(|(tok, _)| *tok != exclude)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    candidates.into_iter().take(k).map(|(tok, _)| tok).collect()
+}
+
+/// Maps the tokens of a draft model to the target model's tokens with the same content.
+struct DraftTokenMap {
+    draft_to_target: HashMap<u32, u32>,
+    target_to_draft: HashMap<u32, u32>,
+    /// Whether a target token without a draft counterpart was already logged
+    unmapped_warned: AtomicBool,
+}
+
+impl DraftTokenMap {
+    /// Fails if less than `min_overlap` of the draft vocab has a target counterpart.
+    fn new(target: &Tokenizer, draft: &Tokenizer, min_overlap: f32) -> Result<Self> {
+        let target_vocab = target.get_vocab(true);
+        let draft_vocab = draft.get_vocab(true);
+        let draft_to_target = draft_vocab
+            .iter()
+            .filter_map(|(tok, draft_id)| Some((*draft_id, *target_vocab.get(tok)?)))
+            .collect::<HashMap<_, _>>();
+        #[allow(clippy::cast_precision_loss)]
+        let overlap = draft_to_target.len() as f32 / draft_vocab.len().max(1) as f32;
+        if overlap < min_overlap {
+            candle_core::bail!(
+                "Only {:.1}% of the draft model's tokenizer vocab is shared with the target model's, below the minimum of {:.1}%.",
+                overlap * 100.,
+                min_overlap * 100.
+            );
+        }
+        let target_to_draft = draft_to_target
+            .iter()
+            .map(|(draft_id, target_id)| (*target_id, *draft_id))
+            .collect();
+        Ok(Self {
+            draft_to_target,
+            target_to_draft,
+            unmapped_warned: AtomicBool::new(false),
+        })
+    }
+
+    /// The token fed to the draft model for a target token, if it has a draft counterpart.
+    fn to_draft(&self, target_tok: u32) -> Option<u32> {
+        self.target_to_draft.get(&target_tok).copied()
+    }
+
+    /// The target tokens as draft tokens, or `None` if one of them, such as a target-only special
+    /// token, has no draft counterpart. The first such token is logged.
+    fn context_to_draft(&self, target_toks: &[u32]) -> Option<Vec<u32>> {
+        let draft_toks = target_toks
+            .iter()
+            .map(|tok| self.to_draft(*tok))
+            .collect::<Option<Vec<_>>>();
+        if draft_toks.is_none() && !self.unmapped_warned.swap(true, Ordering::Relaxed) {
+            warn!("A sequence has a token without a counterpart in the draft model's vocab, so the target model generates it without drafting. Further sequences with such tokens are not logged.");
+        }
+        draft_toks
+    }
+
+    /// The draft tokens as target tokens, up to the first draft token without a target
+    /// counterpart. That token is rejected, and the following ones are dropped.
+    fn draft_tokens_to_target(&self, draft_tokens: &[u32]) -> Vec<u32> {
+        draft_tokens
+            .iter()
+            .map_while(|tok| self.draft_to_target.get(tok).copied())
+            .collect()
+    }
+}
+
+/// Check that the target and draft tokenizers map tokens to the same ids, according to `policy`.
+/// With [`VocabMismatchPolicy::TokenMap`], check that they share enough tokens.
+fn vocabs_match(target: &Tokenizer, draft: &Tokenizer, policy: VocabMismatchPolicy) -> bool {
+    match policy {
+        VocabMismatchPolicy::Strict => target.get_vocab(true) == draft.get_vocab(true),
+        VocabMismatchPolicy::IgnoreSpecial => {
+            let special = target
+                .get_added_tokens_decoder()
+                .into_values()
+                .chain(draft.get_added_tokens_decoder().into_values())
+                .filter(|tok| tok.special)
+                .map(|tok| tok.content)
+                .collect::<HashSet<_>>();
+            let without_special = |tokenizer: &Tokenizer| {
+                tokenizer
+                    .get_vocab(true)
+                    .into_iter()
+                    .filter(|(tok, _)| !special.contains(tok))
+                    .collect::<HashMap<_, _>>()
+            };
+            without_special(target) == without_special(draft)
+        }
+        VocabMismatchPolicy::TokenMap { min_overlap } => {
+            DraftTokenMap::new(target, draft, min_overlap).is_ok()
+        }
+    }
 }
 
 impl SpeculativePipeline {
@@ -180,25 +617,35 @@ impl SpeculativePipeline {
         draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
         config: SpeculativeConfig,
     ) -> Result<Self> {
-        if get_mut_arcmutex!(target)
-            .tokenizer()
-            .as_ref()
-            .ok_or(candle_core::Error::Msg(
-                "`SpeculativePipeline::new` requires the target pipeline to have a token trie"
-                    .to_string(),
-            ))?
-            .get_vocab(true)
-            != get_mut_arcmutex!(draft)
+        let target_tokenizer =
+            get_mut_arcmutex!(target)
+                .tokenizer()
+                .ok_or(candle_core::Error::Msg(
+                    "`SpeculativePipeline::new` requires the target pipeline to have a token trie"
+                        .to_string(),
+                ))?;
+        let draft_tokenizer =
+            get_mut_arcmutex!(draft)
                 .tokenizer()
-                .as_ref()
                 .ok_or(candle_core::Error::Msg(
                     "`SpeculativePipeline::new` requires the draft pipeline to have a token trie"
                         .to_string(),
-                ))?
-                .get_vocab(true)
-        {
-            candle_core::bail!("Target and draft models' tokenizer vocab do not match. This is required for speculative decoding.");
-        }
+                ))?;
+        let token_map = match config.vocab_mismatch_policy {
+            VocabMismatchPolicy::TokenMap { min_overlap } => Some(DraftTokenMap::new(
+                &target_tokenizer,
+                &draft_tokenizer,
+                min_overlap,
+            )?),
+            policy if !vocabs_match(&target_tokenizer, &draft_tokenizer, policy) => {
+                if policy == VocabMismatchPolicy::Strict {
+                    candle_core::bail!("Target and draft models' tokenizer vocab do not match. This is required for speculative decoding. Use `VocabMismatchPolicy::IgnoreSpecial` if they only differ by special tokens, or `VocabMismatchPolicy::TokenMap` if their ids differ.");
+                } else {
+                    candle_core::bail!("Target and draft models' tokenizer vocab do not match, excluding special tokens. This is required for speculative decoding. Use `VocabMismatchPolicy::TokenMap` if their ids differ.");
+                }
+            }
+            _ => None,
+        };
         if get_mut_arcmutex!(target).category() != get_mut_arcmutex!(draft).category() {
             candle_core::bail!("Target and draft models' category do not match. This is required for speculative decoding.");
         }
@@ -215,15 +662,99 @@ impl SpeculativePipeline {
         }
         let metadata = get_mut_arcmutex!(target).get_metadata().clone();
         let category = get_mut_arcmutex!(target).category();
+        if config.draft_branches == 0 {
+            candle_core::bail!("Speculative decoding requires at least 1 draft branch.");
+        }
+        if let Some(AdaptiveGamma {
+            gamma_min,
+            gamma_max,
+        }) = config.adaptive_gamma
+        {
+            if gamma_min == 0 || !(gamma_min..=gamma_max).contains(&config.gamma) {
+                candle_core::bail!("An adaptive gamma requires 0 < gamma_min <= gamma <= gamma_max, got gamma_min = {gamma_min}, gamma = {}, gamma_max = {gamma_max}.", config.gamma);
+            }
+        }
+        if config.draft_branches > 1 {
+            let draft_metadata = get_mut_arcmutex!(draft).get_metadata();
+            if config.gamma == 0 {
+                candle_core::bail!("Multiple draft branches require a gamma greater than 0.");
+            }
+            if category != ModelCategory::Text {
+                candle_core::bail!("Multiple draft branches are only supported for text models.");
+            }
+            if token_map.is_some() {
+                candle_core::bail!("Multiple draft branches are not supported with `VocabMismatchPolicy::TokenMap`.");
+            }
+            if metadata.is_xlora || draft_metadata.is_xlora {
+                candle_core::bail!("Multiple draft branches are not supported for X-LoRA models.");
+            }
+            if metadata.has_no_kv_cache || draft_metadata.has_no_kv_cache {
+                candle_core::bail!("Multiple draft branches require the KV cache.");
+            }
+        }
         // TODO: some checks or relaxation here?
         Ok(Self {
             target,
-            draft,
+            draft: Draft::Model(draft),
+            token_map,
+            gamma: config.gamma,
+            gamma_controller: config
+                .adaptive_gamma
+                .map(|bounds| Mutex::new(GammaController::new(config.gamma, bounds))),
+            draft_branches: config.draft_branches,
+            metadata,
+            category,
+        })
+    }
+
+    /// Speculative decoding without a draft model, drafting by prompt lookup.
+    pub fn new_prompt_lookup(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: PromptLookupConfig,
+    ) -> Result<Self> {
+        if config.gamma == 0 || config.max_ngram_size == 0 {
+            candle_core::bail!(
+                "Prompt lookup requires a gamma and maximum n-gram size greater than 0."
+            );
+        }
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let category = get_mut_arcmutex!(target).category();
+        Ok(Self {
+            target,
+            draft: Draft::PromptLookup {
+                max_ngram_size: config.max_ngram_size,
+            },
+            token_map: None,
             gamma: config.gamma,
+            gamma_controller: None,
+            draft_branches: 1,
             metadata,
             category,
         })
     }
+
+    /// The number of draft tokens of the next step.
+    fn current_gamma(&self) -> usize {
+        match &self.gamma_controller {
+            Some(controller) => controller.lock().unwrap().gamma(),
+            None => self.gamma,
+        }
+    }
+
+    /// Adjust an adaptive gamma after a step which drafted `drafted` tokens in a chain, of which
+    /// `accepted` were accepted.
+    fn adapt_gamma(&self, drafted: usize, accepted: usize) {
+        if let Some(controller) = &self.gamma_controller {
+            controller.lock().unwrap().record(drafted, accepted);
+        }
+    }
+
+    fn draft_model(&self) -> Option<&Arc<tokio::sync::Mutex<dyn Pipeline>>> {
+        match &self.draft {
+            Draft::Model(draft) => Some(draft),
+            Draft::PromptLookup { .. } => None,
+        }
+    }
 }
 
 impl PreProcessingMixin for SpeculativePipeline {
@@ -238,29 +769,38 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        if let Some(draft) = self.draft_model() {
+            get_mut_arcmutex!(draft).re_isq_model(dtype)?;
+        }
+        Ok(())
     }
 }
 
 impl CacheManagerMixin for SpeculativePipeline {
     fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
+        if let Some(draft) = self.draft_model() {
+            DefaultCacheManager.clone_in_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+            );
+        }
         DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
+        if let Some(draft) = self.draft_model() {
+            DefaultCacheManager.clone_out_cache(
+                &*get_mut_arcmutex!(draft),
+                seqs,
+                modify_draft_cache,
+            );
+        }
         DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.draft), modify_draft_cache);
+        if let Some(draft) = self.draft_model() {
+            DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(draft), modify_draft_cache);
+        }
         DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.target), false);
         if reset_non_granular {
             self.reset_non_granular_state()
@@ -273,10 +813,23 @@ impl CacheManagerMixin for SpeculativePipeline {
 
 impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
-    fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+    fn activate_weighted_adapters(
+        &mut self,
+        adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        let mut res = 0;
+        if let Some(draft) = self.draft_model() {
+            res += get_mut_arcmutex!(draft).activate_weighted_adapters(adapters.clone())?;
+        }
+        res += get_mut_arcmutex!(self.target).activate_weighted_adapters(adapters)?;
+        Ok(res)
+    }
+    fn merge_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
-        res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
+        if let Some(draft) = self.draft_model() {
+            res += get_mut_arcmutex!(draft).merge_adapters(adapters.clone())?;
+        }
+        res += get_mut_arcmutex!(self.target).merge_adapters(adapters)?;
         Ok(res)
     }
 }
@@ -289,49 +842,936 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
-        )
+        match &self.draft {
+            Draft::Model(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                get_mut_arcmutex!(draft).name(),
+                self.gamma,
+            ),
+            Draft::PromptLookup { max_ngram_size } => format!(
+                "Prompt lookup: tgt = `{}`, gamma = `{}`, max n-gram size = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                self.gamma,
+                max_ngram_size,
+            ),
+        }
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Some(draft) = self.draft_model() {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> Arc<GeneralMetadata> {
         self.metadata.clone()
     }
 }
 
-#[async_trait::async_trait]
-impl Pipeline for SpeculativePipeline {
-    fn forward_inputs(&mut self, _inputs: Box<dyn Any>) -> Result<ForwardInputsResult> {
-        unreachable!()
+/// How the caches of each sequence are brought into the models before it is stepped.
+#[derive(Clone, Copy)]
+enum PreCache {
+    In,
+    Nothing,
+    Reset(bool),
+}
+
+/// The draft tokens of a sequence for one step.
+struct SeqDraft {
+    tokens: Vec<u32>,
+    /// The target model runs on the first `n_fed` draft tokens.
+    n_fed: usize,
+    /// Number of tokens drafted, for the acceptance metrics.
+    n_drafted: usize,
+}
+
+/// Drop the last `n_not_accepted` positions of each layer's KV cache, those of rejected draft tokens.
+fn narrow_rejected(cache: &mut LayerCaches, n_not_accepted: usize) -> Result<()> {
+    for (k, v) in cache.iter_mut().flatten() {
+        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
     }
-    async fn sample_causal_gen(
-        &self,
-        _seqs: &mut [&mut Sequence],
-        _logits: Vec<Tensor>,
-        _prefix_cacher: &mut PrefixCacheManager,
-        _disable_eos_stop: bool,
-        _rng: Arc<std::sync::Mutex<Isaac64Rng>>,
-    ) -> Result<()> {
-        unreachable!()
+    Ok(())
+}
+
+/// Repeat each layer's KV cache `n` times along the batch dimension, to run `n` branches at once.
+pub(super) fn repeat_batch(cache: &mut LayerCaches, n: usize) -> Result<()> {
+    for (k, v) in cache.iter_mut().flatten() {
+        *k = Tensor::cat(&vec![k.clone(); n], 0)?;
+        *v = Tensor::cat(&vec![v.clone(); n], 0)?;
     }
-    async fn step(
-        &mut self,
-        input_seqs: &mut [&mut Sequence],
+    Ok(())
+}
+
+/// Keep only batch row `row` of each layer's KV cache.
+fn select_batch_row(cache: &mut LayerCaches, row: usize) -> Result<()> {
+    for (k, v) in cache.iter_mut().flatten() {
+        *k = k.i(row..row + 1)?;
+        *v = v.i(row..row + 1)?;
+    }
+    Ok(())
+}
+
+impl SpeculativePipeline {
+    /// Run the draft model on the sequence, returning its logits and sample.
+    async fn draft_forward(
+        &self,
+        draft: &Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        seq: &mut &mut Sequence,
         is_prompt: bool,
-        prefix_cacher: &mut PrefixCacheManager,
-        disable_eos_stop: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
-        backend_metadata: CacheBackendMetadata<'_>,
-    ) -> Result<()> {
-        match backend_metadata {
+        eos_tok: &[u32],
+    ) -> Result<(Tensor, Logprobs)> {
+        let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+        let device = get_mut_arcmutex!(draft).device();
+        let has_no_kv_cache = get_mut_arcmutex!(draft).get_metadata().has_no_kv_cache;
+        let inputs = self
+            .get_processor()
+            .inputs_processor()
+            .process_inputs(
+                self.tokenizer(),
+                &mut [seq],
+                is_prompt,
+                is_xlora,
+                &device,
+                has_no_kv_cache,
+                None,
+                None,
+                None, // TODO: get block tables/handle it
+                None, // TODO: do we support???
+            )
+            .nth(0)
+            .unwrap()
+            .unwrap();
+        let logits = get_mut_arcmutex!(draft).forward_inputs(Box::new(inputs))?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+
+        let sample = sample_sequence(
+            logits.clone(),
+            seq,
+            seq.return_logprobs(),
+            rng,
+            false, // todo tune
+            false, // do not add to tok trie yet
+            true,
+            eos_tok,
+        )
+        .await?;
+        Ok((logits, sample))
+    }
+
+    /// Add the accepted tokens to the sequence and its recognizer.
+    async fn add_accepted_tokens(
+        &self,
+        seq: &mut &mut Sequence,
+        accepted_tokens: Vec<Logprobs>,
+        prefix_cacher: &mut PrefixCacheManager,
+        eos_owned: &[u32],
+        disable_eos_stop: bool,
+    ) -> Result<()> {
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(eos_owned)
+        };
+        // Add the tokens to the seq and the trie
+        for accepted in accepted_tokens {
+            // Do not use the prefix cacher
+            finish_or_add_toks_to_seq(self, prefix_cacher, seq, accepted.clone(), eos_tok, false)
+                .await?;
+            // Tokens accepted after the sequence finished are not part of it.
+            if seq.is_finished_paged_attn() {
+                break;
+            }
+            match seq.recognizer {
+                SequenceRecognizer::Regex(ref mut rx) => {
+                    get_mut_arcmutex!(self.target)
+                        .get_metadata()
+                        .tok_trie
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`SpeculativePipeline::step` requires a token trie".to_string(),
+                        ))?
+                        .append_token(rx.as_mut(), accepted.token)
+                        .map_err(candle_core::Error::msg)?;
+                }
+                SequenceRecognizer::Cfg(ref mut cfg) => {
+                    get_mut_arcmutex!(self.target)
+                        .get_metadata()
+                        .tok_trie
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`SpeculativePipeline::step` requires a token trie".to_string(),
+                        ))?
+                        .append_token(cfg.as_mut(), accepted.token)
+                        .map_err(candle_core::Error::msg)?;
+                }
+                SequenceRecognizer::Gbnf(ref mut gbnf) => {
+                    get_mut_arcmutex!(self.target)
+                        .get_metadata()
+                        .tok_trie
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`SpeculativePipeline::step` requires a token trie".to_string(),
+                        ))?
+                        .append_token(gbnf.as_mut(), accepted.token)
+                        .map_err(candle_core::Error::msg)?;
+                }
+                SequenceRecognizer::JsonSchema(ref mut fsm) => {
+                    get_mut_arcmutex!(self.target)
+                        .get_metadata()
+                        .tok_trie
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`SpeculativePipeline::step` requires a token trie".to_string(),
+                        ))?
+                        .append_token(fsm.as_mut(), accepted.token)
+                        .map_err(candle_core::Error::msg)?;
+                }
+                SequenceRecognizer::None => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draft the tokens of a step for the sequence, with the draft model or by prompt lookup. The
+    /// draft model runs from the cache it holds.
+    async fn draft_seq(
+        &self,
+        seq: &mut &mut Sequence,
+        is_prompt: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        eos_owned: &[u32],
+        gamma: usize,
+    ) -> Result<SeqDraft> {
+        match &self.draft {
+            Draft::Model(draft) => {
+                // With a token map, the draft model runs on its own tokens, set as prefill tokens.
+                // A sequence with a token the draft model can not run on is not drafted for, and
+                // as the token stays in its context, it is not drafted for again.
+                let mut draft_context = match &self.token_map {
+                    Some(map) => match map.context_to_draft(seq.get_toks()) {
+                        Some(context) => Some(context),
+                        None => {
+                            return Ok(SeqDraft {
+                                tokens: Vec::new(),
+                                n_fed: 0,
+                                n_drafted: 0,
+                            })
+                        }
+                    },
+                    None => None,
+                };
+                let mut draft_tokens = Vec::new();
+                for i in 0..gamma {
+                    if let Some(context) = &draft_context {
+                        seq.set_prefill_toks(context.clone());
+                    }
+                    // Only prompt (no kv cache) if first
+                    let (_, sample) = self
+                        .draft_forward(draft, seq, is_prompt && i == 0, rng.clone(), eos_owned)
+                        .await?;
+                    match &mut draft_context {
+                        Some(context) => context.push(sample.token),
+                        None => seq.add_tmp_tok(sample.token),
+                    }
+                    draft_tokens.push(sample.token);
+                }
+                let tokens = match &self.token_map {
+                    Some(map) => {
+                        seq.reset_prefill_toks();
+                        map.draft_tokens_to_target(&draft_tokens)
+                    }
+                    None => {
+                        seq.remove_tmp_tok(gamma);
+                        draft_tokens
+                    }
+                };
+                // The last draft token is only checked against the last target sample, the
+                // target model does not run on it.
+                let n_fed = tokens.len().min(gamma.saturating_sub(1));
+                Ok(SeqDraft {
+                    tokens,
+                    n_fed,
+                    n_drafted: gamma,
+                })
+            }
+            Draft::PromptLookup { max_ngram_size } => {
+                let tokens = prompt_lookup(seq.get_toks(), *max_ngram_size, gamma);
+                let n_fed = tokens.len();
+                Ok(SeqDraft {
+                    tokens,
+                    n_fed,
+                    n_drafted: n_fed,
+                })
+            }
+        }
+    }
+
+    /// Accept the target samples of the sequence while they match its draft tokens, record the
+    /// acceptance and add the accepted tokens to the sequence. Returns the number of accepted
+    /// tokens, including the target sample which replaces the first rejected draft token.
+    async fn accept_seq(
+        &self,
+        seq: &mut &mut Sequence,
+        samples: Vec<SpeculativeSample>,
+        draft: &SeqDraft,
+        prefix_cacher: &mut PrefixCacheManager,
+        eos_owned: &[u32],
+        disable_eos_stop: bool,
+    ) -> Result<usize> {
+        let (accepted_tokens, n_accepted_draft) = accept_draft_tokens(samples, &draft.tokens);
+        self.metadata
+            .record_speculative_step(draft.n_drafted, n_accepted_draft);
+        seq.record_speculative_step(draft.n_drafted, n_accepted_draft);
+        self.adapt_gamma(draft.n_drafted, n_accepted_draft);
+        let n_accepted = accepted_tokens.len();
+        self.add_accepted_tokens(
+            seq,
+            accepted_tokens,
+            prefix_cacher,
+            eos_owned,
+            disable_eos_stop,
+        )
+        .await?;
+        Ok(n_accepted)
+    }
+
+    /// Run one speculative decoding step for a single sequence whose caches are in the models.
+    async fn step_seq(
+        &self,
+        seq: &mut &mut Sequence,
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        let eos_owned = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .eos_tok
+            .clone();
+        let gamma = self.current_gamma();
+
+        // ======================= Run draft model gamma times producing tokens ============================
+        let seq_draft = self
+            .draft_seq(seq, is_prompt, rng.clone(), &eos_owned, gamma)
+            .await?;
+        // The target model samples after the last token of the sequence and each fed draft token.
+        let n_verified = seq_draft.n_fed + 1;
+
+        // ======================= Add the fed draft tokens. Add the last from the seq. ============================
+        let mut draft_prefill_tokens = if is_prompt {
+            seq.get_toks().to_vec()
+        } else {
+            vec![*seq.get_toks().last().unwrap()]
+        };
+        draft_prefill_tokens.extend_from_slice(&seq_draft.tokens[..seq_draft.n_fed]);
+        seq.set_prefill_toks(draft_prefill_tokens);
+
+        // ======================= Run the model with all draft tokens. ============================
+
+        let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+            .as_ref()
+            .map(|(k, _)| k.dims()[2])
+            .unwrap_or(0);
+
+        // ========= Run the model ============
+        let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+        let device = get_mut_arcmutex!(self.target).device();
+        let has_no_kv_cache = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .has_no_kv_cache;
+        let inputs = self
+            .get_processor()
+            .inputs_processor()
+            .process_inputs(
+                self.tokenizer(),
+                &mut [seq],
+                true, // use the "prefill" tokens
+                is_xlora,
+                &device,
+                has_no_kv_cache,
+                Some((n_verified, initial_cache_len)), // Get the last n_verified, see above
+                None,
+                None, // TODO: get block tables/handle it
+                None, // TODO: do we support???
+            )
+            .nth(0)
+            .unwrap()
+            .unwrap();
+
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+
+        // Reset the prefill tokens
+        seq.reset_prefill_toks();
+
+        // ======================= Rejection sampling. ============================
+        // Map from each target sample to corresponding in draft sample
+        let samples = sample_target_sequence_speculative(
+            logits.clone(),
+            seq,
+            seq.return_logprobs(),
+            rng.clone(),
+            n_verified,
+            &eos_owned,
+        )
+        .await?;
+
+        let n_accepted = self
+            .accept_seq(
+                seq,
+                samples,
+                &seq_draft,
+                prefix_cacher,
+                &eos_owned,
+                disable_eos_stop,
+            )
+            .await?;
+
+        // ======================= Narrow caches to account for rejections ============================
+        let n_not_accepted = n_verified - n_accepted;
+        if let Some(draft) = self.draft_model().filter(|_| seq_draft.n_drafted > 0) {
+            // The draft model ran on all but its last token, which may be more than were verified
+            // if a draft token had no target counterpart.
+            let n_not_accepted = gamma.saturating_sub(1) + 1 - n_accepted;
+            narrow_rejected(&mut get_mut_arcmutex!(draft).cache().lock(), n_not_accepted)?;
+            if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                narrow_rejected(
+                    &mut get_mut_arcmutex!(draft).cache().xlora_lock(),
+                    n_not_accepted,
+                )?;
+            }
+        }
+        narrow_rejected(
+            &mut get_mut_arcmutex!(self.target).cache().lock(),
+            n_not_accepted,
+        )?;
+        if get_mut_arcmutex!(self.target).get_metadata().is_xlora {
+            narrow_rejected(
+                &mut get_mut_arcmutex!(self.target).cache().xlora_lock(),
+                n_not_accepted,
+            )?;
+        }
+
+        // Trick to improve lower bounds. Sample last token in multinomial
+        /*
+        let sample = sample_sequence(
+            logits.clone(),
+            seq,
+            seq.return_logprobs(),
+            rng.clone(),
+            false, // todo tune
+            true, // do not add to tok trie yet
+            true,
+        )
+        .await?;
+        finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
+        */
+
+        Ok(())
+    }
+
+    /// Whether the target model can verify several sequences in one batch, see
+    /// [`SpeculativePipeline::step_batch`]. The batch inputs are those of text models with a KV
+    /// cache, and the X-LoRA caches are not kept per sequence.
+    fn can_batch_target(&self) -> bool {
+        let draft_is_xlora = self
+            .draft_model()
+            .is_some_and(|draft| get_mut_arcmutex!(draft).get_metadata().is_xlora);
+        self.category == ModelCategory::Text
+            && !self.metadata.has_no_kv_cache
+            && !self.metadata.is_xlora
+            && !draft_is_xlora
+    }
+
+    /// Run one speculative decoding step for a batch of sequences. Each sequence is drafted on its
+    /// own, from its draft cache. The target model then verifies the sequences whose caches have
+    /// the same length in one batch. The caches of each sequence are narrowed by the number of
+    /// tokens it rejected and left in the sequence, not in the models.
+    #[allow(clippy::too_many_arguments)]
+    async fn step_batch(
+        &self,
+        seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        pre_cache: PreCache,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        let eos_owned = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .eos_tok
+            .clone();
+        let gamma = self.current_gamma();
+        let reset_cache = match pre_cache {
+            PreCache::Reset(reset_non_granular) => {
+                self.set_none_cache(reset_non_granular, false);
+                true
+            }
+            // The sequences hold their caches after each batched step.
+            PreCache::In | PreCache::Nothing => false,
+        };
+
+        // ======================= Draft each sequence from its own draft cache ============================
+        let mut drafts = Vec::with_capacity(seqs.len());
+        for seq in seqs.iter_mut() {
+            if let Some(draft) = self.draft_model() {
+                if reset_cache {
+                    DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(draft), false);
+                } else {
+                    DefaultCacheManager.clone_in_cache(
+                        &*get_mut_arcmutex!(draft),
+                        &mut [seq],
+                        true,
+                    );
+                }
+            }
+            drafts.push(
+                self.draft_seq(seq, is_prompt, rng.clone(), &eos_owned, gamma)
+                    .await?,
+            );
+            if let Some(draft) = self.draft_model() {
+                DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(draft), &mut [seq], true);
+            }
+        }
+
+        // ======================= Verify the sequences in batches of equal cache length ============================
+        let cache_lens = seqs
+            .iter_mut()
+            .map(|seq| match &seq.cache()[0] {
+                Some((k, _)) if !reset_cache => k.dims()[2],
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        let mut batch_cache_lens = Vec::new();
+        for cache_len in &cache_lens {
+            if !batch_cache_lens.contains(cache_len) {
+                batch_cache_lens.push(*cache_len);
+            }
+        }
+        for batch_cache_len in batch_cache_lens {
+            let (mut batch, batch_drafts): (Vec<&mut Sequence>, Vec<&SeqDraft>) =
+                zip(zip(seqs.iter_mut(), &drafts), &cache_lens)
+                    .filter(|(_, cache_len)| **cache_len == batch_cache_len)
+                    .map(|((seq, draft), _)| (&mut **seq, draft))
+                    .unzip();
+            self.verify_batch(
+                &mut batch,
+                &batch_drafts,
+                batch_cache_len,
+                is_prompt,
+                reset_cache,
+                gamma,
+                prefix_cacher,
+                disable_eos_stop,
+                rng.clone(),
+                &eos_owned,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Verify the draft tokens of sequences whose target caches hold `cache_len` positions in one
+    /// target model forward pass. Each batch row is the new tokens of a sequence followed by its
+    /// fed draft tokens, right padded to the longest row.
+    #[allow(clippy::too_many_arguments)]
+    async fn verify_batch(
+        &self,
+        seqs: &mut [&mut Sequence],
+        drafts: &[&SeqDraft],
+        cache_len: usize,
+        is_prompt: bool,
+        reset_cache: bool,
+        gamma: usize,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        eos_owned: &[u32],
+    ) -> Result<()> {
+        if reset_cache {
+            DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.target), false);
+        } else {
+            DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+        }
+
+        // ======================= Run the target model on the padded rows ============================
+        let rows = zip(seqs.iter(), drafts)
+            .map(|(seq, draft)| {
+                let mut row = if is_prompt {
+                    seq.get_toks().to_vec()
+                } else {
+                    vec![*seq.get_toks().last().unwrap()]
+                };
+                row.extend_from_slice(&draft.tokens[..draft.n_fed]);
+                row
+            })
+            .collect::<Vec<_>>();
+        // All rows select as many logits as the row verifying the most tokens, starting at their
+        // last new token. The rows are padded so that this fits in each of them.
+        let n_logits = drafts
+            .iter()
+            .map(|draft| draft.n_fed + 1)
+            .max()
+            .expect("No sequences");
+        let context_lens = zip(&rows, drafts)
+            .map(|(row, draft)| (row.len() - draft.n_fed - 1, n_logits))
+            .collect::<Vec<_>>();
+        let seq_len = context_lens
+            .iter()
+            .map(|(start, _)| start + n_logits)
+            .max()
+            .expect("No sequences");
+        let position_ids = rows.iter().map(|row| cache_len + row.len()).collect();
+        let n_rows = rows.len();
+        let input_ids = rows
+            .into_iter()
+            .flat_map(|mut row| {
+                row.resize(seq_len, 0);
+                row
+            })
+            .collect::<Vec<_>>();
+
+        let device = get_mut_arcmutex!(self.target).device();
+        let input_ids = Tensor::from_vec(input_ids, (n_rows, seq_len), &device)?;
+        let positions_kernel =
+            Tensor::arange(cache_len as i64, (cache_len + seq_len) as i64, &device)?
+                .unsqueeze(0)?
+                .repeat((n_rows, 1))?;
+        let seq_len_u32 = u32::try_from(seq_len).map_err(candle_core::Error::msg)?;
+        let n_rows_u32 = u32::try_from(n_rows).map_err(candle_core::Error::msg)?;
+        let cumulative_seqlens =
+            Tensor::arange_step(0, n_rows_u32 * seq_len_u32 + 1, seq_len_u32, &device)?;
+        let inputs = ModelInputs {
+            input_ids,
+            input_ids_full: None,
+            seqlen_offsets: vec![cache_len; n_rows],
+            seqlen_offsets_full: None,
+            seqlen_offsets_kernel: positions_kernel,
+            seqlen_offsets_kernel_full: None,
+            context_lens: context_lens.clone(),
+            position_ids,
+            paged_attn_meta: None,
+            flash_meta: FlashParams {
+                max_q: seq_len_u32,
+                max_k: seq_len_u32,
+                cumulative_seqlens_q: cumulative_seqlens.clone(),
+                cumulative_seqlens_k: cumulative_seqlens,
+            },
+            flash_meta_full: None,
+        };
+
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+        DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+
+        // ======================= Rejection sampling per sequence ============================
+        for (row, (seq, draft)) in zip(seqs.iter_mut(), drafts).enumerate() {
+            let n_verified = draft.n_fed + 1;
+            let samples = sample_target_sequence_speculative(
+                logits.i((row..row + 1, ..n_verified))?,
+                seq,
+                seq.return_logprobs(),
+                rng.clone(),
+                n_verified,
+                eos_owned,
+            )
+            .await?;
+            let n_accepted = self
+                .accept_seq(
+                    seq,
+                    samples,
+                    draft,
+                    prefix_cacher,
+                    eos_owned,
+                    disable_eos_stop,
+                )
+                .await?;
+
+            // The target cache keeps the new tokens of the row up to its last one, and the
+            // accepted tokens but the last, dropping the rejected draft tokens and the padding.
+            let (start, _) = context_lens[row];
+            narrow_rejected(seq.cache(), seq_len - start - n_accepted)?;
+            if self.draft_model().is_some() && draft.n_drafted > 0 {
+                narrow_rejected(seq.draft_cache(), gamma.saturating_sub(1) + 1 - n_accepted)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run one speculative decoding step for a single sequence with a tree of draft tokens. At
+    /// each of the `gamma` depths, the draft model continues every branch with its sample and the
+    /// `draft_branches - 1` next most likely tokens, and the `draft_branches` most likely branches
+    /// are kept. The target model runs on all branches in one batch, and the target samples are
+    /// accepted along the tree while they match a draft token.
+    async fn step_seq_tree(
+        &self,
+        seq: &mut &mut Sequence,
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        let Some(draft) = self.draft_model() else {
+            candle_core::bail!("Multiple draft branches require a draft model.");
+        };
+        let eos_owned = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .eos_tok
+            .clone();
+        let gamma = self.current_gamma();
+
+        // ======================= Draft the tree depth by depth ============================
+        // At each depth, every branch is continued by its draft sample and the next most likely
+        // tokens. The `draft_branches` continuations with the highest draft log probability are
+        // kept, so the tree may branch at any depth.
+        let (logits, sample) = self
+            .draft_forward(draft, seq, is_prompt, rng.clone(), &eos_owned)
+            .await?;
+        let mut branches = vec![DraftBranch {
+            tokens: Vec::new(),
+            logprob: 0.,
+            draft_cache: get_mut_arcmutex!(draft).cache().lock().clone(),
+            next: Some((
+                logits.flatten_all()?.to_dtype(DType::F32)?.to_vec1()?,
+                sample.token,
+            )),
+        }];
+        for depth in 0..gamma {
+            let mut candidates = Vec::new();
+            for (parent, branch) in branches.iter().enumerate() {
+                let (logits, sample) = branch
+                    .next
+                    .as_ref()
+                    .expect("Branches are continued before the last depth.");
+                let logprobs = log_softmax(logits);
+                let tokens = std::iter::once(*sample).chain(top_k_tokens(
+                    logits,
+                    self.draft_branches - 1,
+                    *sample,
+                ));
+                candidates.extend(
+                    tokens.map(|tok| (parent, tok, branch.logprob + logprobs[tok as usize])),
+                );
+            }
+            // Stable, so that ties keep the continuations of the more likely branch first.
+            candidates.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+            candidates.truncate(self.draft_branches);
+
+            let mut next_branches = Vec::new();
+            for (parent, tok, logprob) in candidates {
+                let parent = &branches[parent];
+                let mut tokens = parent.tokens.clone();
+                tokens.push(tok);
+                // The draft model does not run on the tokens of the last depth.
+                let mut branch = DraftBranch {
+                    tokens,
+                    logprob,
+                    draft_cache: parent.draft_cache.clone(),
+                    next: None,
+                };
+                if depth + 1 < gamma {
+                    *get_mut_arcmutex!(draft).cache().lock() = branch.draft_cache;
+                    for tok in &branch.tokens {
+                        seq.add_tmp_tok(*tok);
+                    }
+                    let (logits, sample) = self
+                        .draft_forward(draft, seq, false, rng.clone(), &eos_owned)
+                        .await?;
+                    seq.remove_tmp_tok(branch.tokens.len());
+                    branch.draft_cache = get_mut_arcmutex!(draft).cache().lock().clone();
+                    branch.next = Some((
+                        logits.flatten_all()?.to_dtype(DType::F32)?.to_vec1()?,
+                        sample.token,
+                    ));
+                }
+                next_branches.push(branch);
+            }
+            branches = next_branches;
+        }
+
+        // The branches share their common prefixes in the tree. They have distinct tokens, so each
+        // ends in its own leaf.
+        let mut tree = DraftTree::default();
+        let mut branch_draft_caches = Vec::new();
+        for branch in branches {
+            let mut node = None;
+            for tok in branch.tokens {
+                node = Some(
+                    tree.child_with_token(node, tok)
+                        .unwrap_or_else(|| tree.push(tok, node)),
+                );
+            }
+            branch_draft_caches.push(branch.draft_cache);
+        }
+
+        // ======================= Run the target model on all branches ============================
+        // One batch row per branch: the new tokens of the sequence followed by the branch, except
+        // its last token, which is only checked against the last target sample.
+        let branches = tree.leaves();
+        let n_verified = gamma;
+        let prefix = if is_prompt {
+            seq.get_toks().to_vec()
+        } else {
+            vec![*seq.get_toks().last().unwrap()]
+        };
+        let rows = branches
+            .iter()
+            .flat_map(|leaf| {
+                let mut row = prefix.clone();
+                row.extend_from_slice(&tree.path(*leaf)[..n_verified - 1]);
+                row
+            })
+            .collect::<Vec<_>>();
+        let n_rows = branches.len();
+        let seq_len = prefix.len() + n_verified - 1;
+
+        let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+            .as_ref()
+            .map(|(k, _)| k.dims()[2])
+            .unwrap_or(0);
+        let device = get_mut_arcmutex!(self.target).device();
+        let input_ids = Tensor::from_vec(rows, (n_rows, seq_len), &device)?;
+        let positions_kernel = Tensor::arange(
+            initial_cache_len as i64,
+            (initial_cache_len + seq_len) as i64,
+            &device,
+        )?
+        .unsqueeze(0)?
+        .repeat((n_rows, 1))?;
+        let seq_len_u32 = u32::try_from(seq_len).map_err(candle_core::Error::msg)?;
+        let n_rows_u32 = u32::try_from(n_rows).map_err(candle_core::Error::msg)?;
+        let cumulative_seqlens =
+            Tensor::arange_step(0, n_rows_u32 * seq_len_u32 + 1, seq_len_u32, &device)?;
+        let inputs = ModelInputs {
+            input_ids,
+            input_ids_full: None,
+            seqlen_offsets: vec![initial_cache_len; n_rows],
+            seqlen_offsets_full: None,
+            seqlen_offsets_kernel: positions_kernel,
+            seqlen_offsets_kernel_full: None,
+            context_lens: vec![(seq_len - n_verified, n_verified); n_rows],
+            position_ids: vec![seq_len; n_rows],
+            paged_attn_meta: None,
+            flash_meta: FlashParams {
+                max_q: seq_len_u32,
+                max_k: seq_len_u32,
+                cumulative_seqlens_q: cumulative_seqlens.clone(),
+                cumulative_seqlens_k: cumulative_seqlens,
+            },
+            flash_meta_full: None,
+        };
+
+        repeat_batch(&mut get_mut_arcmutex!(self.target).cache().lock(), n_rows)?;
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+
+        // ======================= Walk the tree greedily ============================
+        // Each target sample is taken from a branch containing all draft tokens accepted so far.
+        let mut accepted_tokens = Vec::new();
+        let mut node = None;
+        let mut row = 0;
+        for depth in 0..n_verified {
+            let sample = sample_sequence(
+                logits.i((row..row + 1, depth..depth + 1))?,
+                seq,
+                seq.return_logprobs(),
+                rng.clone(),
+                true,
+                false, // Do not append to trie (yet)
+                true,
+                &eos_owned,
+            )
+            .await?;
+            let token = sample.token;
+            accepted_tokens.push(sample);
+            let Some(child) = tree.child_with_token(node, token) else {
+                break;
+            };
+            node = Some(child);
+            row = branches
+                .iter()
+                .position(|leaf| tree.descends_from(*leaf, child))
+                .expect("Every node is on a branch");
+        }
+        let n_accepted_draft = node.map(|node| tree.path(node).len()).unwrap_or(0);
+        self.metadata
+            .record_speculative_step(tree.len(), n_accepted_draft);
+        seq.record_speculative_step(tree.len(), n_accepted_draft);
+        self.adapt_gamma(gamma, n_accepted_draft);
+
+        // ======================= Keep the caches of the chosen branch ============================
+        let n_not_accepted = n_verified - accepted_tokens.len();
+        {
+            let target = get_mut_arcmutex!(self.target);
+            let mut cache = target.cache().lock();
+            select_batch_row(&mut cache, row)?;
+            narrow_rejected(&mut cache, n_not_accepted)?;
+        }
+        *get_mut_arcmutex!(draft).cache().lock() = branch_draft_caches.swap_remove(row);
+        narrow_rejected(
+            &mut get_mut_arcmutex!(draft).cache().lock(),
+            gamma - accepted_tokens.len(),
+        )?;
+
+        self.add_accepted_tokens(
+            seq,
+            accepted_tokens,
+            prefix_cacher,
+            &eos_owned,
+            disable_eos_stop,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for SpeculativePipeline {
+    fn forward_inputs(&mut self, _inputs: Box<dyn Any>) -> Result<ForwardInputsResult> {
+        unreachable!()
+    }
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManager,
+        _disable_eos_stop: bool,
+        _rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        unreachable!()
+    }
+    async fn step(
+        &mut self,
+        input_seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        backend_metadata: CacheBackendMetadata<'_>,
+    ) -> Result<()> {
+        self.metadata.apply_adapter_swaps(input_seqs);
+        match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
-                match pre_op {
+                let pre_cache = match pre_op {
                     CacheInstruction::In(adapter_inst) => {
                         match adapter_inst {
                             AdapterInstruction::Activate(adapters) => {
@@ -343,9 +1783,12 @@ impl Pipeline for SpeculativePipeline {
                                     ))
                                 })?
                             }
+                            AdapterInstruction::PerSequence => candle_core::bail!(
+                                "Speculative decoding does not support batches mixing adapters."
+                            ),
                             AdapterInstruction::None => 0,
                         };
-                        self.clone_in_cache(input_seqs, false)
+                        PreCache::In
                     }
                     CacheInstruction::Nothing(adapter_inst) => {
                         match adapter_inst {
@@ -358,8 +1801,12 @@ impl Pipeline for SpeculativePipeline {
                                     ))
                                 })?
                             }
+                            AdapterInstruction::PerSequence => candle_core::bail!(
+                                "Speculative decoding does not support batches mixing adapters."
+                            ),
                             AdapterInstruction::None => 0,
                         };
+                        PreCache::Nothing
                     }
                     CacheInstruction::Reset {
                         reset_non_granular,
@@ -375,264 +1822,81 @@ impl Pipeline for SpeculativePipeline {
                                     ))
                                 })?
                             }
+                            AdapterInstruction::PerSequence => candle_core::bail!(
+                                "Speculative decoding does not support batches mixing adapters."
+                            ),
                             AdapterInstruction::None => 0,
                         };
-                        self.set_none_cache(reset_non_granular, false)
+                        PreCache::Reset(reset_non_granular)
                     }
                     _ => unreachable!("Unreachable PRE cache op."),
-                }
+                };
 
-                assert_eq!(input_seqs.len(), 1);
-
-                let seq = &mut input_seqs[0];
-
-                // ======================= Run draft model gamma times producing tokens ============================
-                // ======================= Sample the `gamma` logits. ============================
-                let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let has_no_kv_cache =
-                        get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
-                    let inputs = self
-                        .get_processor()
-                        .inputs_processor()
-                        .process_inputs(
-                            self.tokenizer(),
-                            &mut [seq],
-                            is_prompt && i == 0, // Only prompt (no kv cache) if first
-                            is_xlora,
-                            &device,
-                            has_no_kv_cache,
-                            None,
-                            None,
-                            None, // TODO: get block tables/handle it
-                            None, // TODO: do we support???
-                        )
-                        .nth(0)
-                        .unwrap()
-                        .unwrap();
-                    let logits = get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs))?;
-                    #[allow(irrefutable_let_patterns)]
-                    let ForwardInputsResult::CausalGeneration { logits } = logits
-                    else {
-                        candle_core::bail!(
-                            "Speculative decoding requires `CausalGeneration` forward results"
-                        );
-                    };
-
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        false, // do not add to tok trie yet
-                        true,
+                if self.draft_branches == 1 && self.can_batch_target() {
+                    self.step_batch(
+                        input_seqs,
+                        is_prompt,
+                        pre_cache,
+                        prefix_cacher,
+                        disable_eos_stop,
+                        rng,
                     )
                     .await?;
-                    seq.add_tmp_tok(sample.token);
-                    draft_samples.push(SpeculativeSample { sample });
-                }
-                seq.remove_tmp_tok(self.gamma);
-
-                // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
-                let mut draft_prefill_tokens = if is_prompt {
-                    seq.get_toks().to_vec()
-                } else {
-                    vec![*seq.get_toks().last().unwrap()]
-                };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
+                    match post_op {
+                        // The batched step already left the caches in the sequences.
+                        CacheInstruction::Out | CacheInstruction::Nothing(_) => (),
+                        CacheInstruction::Reset {
+                            reset_non_granular,
+                            adapter_inst: _,
+                        } => self.set_none_cache(reset_non_granular, true),
+                        _ => unreachable!("Unreachable post cache op."),
                     }
-                    draft_prefill_tokens.push(sample.sample.token);
+                    return Ok(());
                 }
-                seq.set_prefill_toks(draft_prefill_tokens);
-
-                // ======================= Run the model with all draft tokens. ============================
-
-                let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
-                    .as_ref()
-                    .map(|(k, _)| k.dims()[2])
-                    .unwrap_or(0);
-
-                // ========= Run the model ============
-                let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
-                let device = get_mut_arcmutex!(self.target).device();
-                let has_no_kv_cache = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .has_no_kv_cache;
-                let inputs = self
-                    .get_processor()
-                    .inputs_processor()
-                    .process_inputs(
-                        self.tokenizer(),
-                        &mut [seq],
-                        true, // use the "prefill" tokens
-                        is_xlora,
-                        &device,
-                        has_no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
-                        None,
-                        None, // TODO: get block tables/handle it
-                        None, // TODO: do we support???
-                    )
-                    .nth(0)
-                    .unwrap()
-                    .unwrap();
-
-                let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
-                #[allow(irrefutable_let_patterns)]
-                let ForwardInputsResult::CausalGeneration { logits } = logits
-                else {
-                    candle_core::bail!(
-                        "Speculative decoding requires `CausalGeneration` forward results"
-                    );
-                };
 
-                // Reset the prefill tokens
-                seq.reset_prefill_toks();
-
-                // ======================= Rejection sampling. ============================
-                // Map from each target sample to corresponding in draft sample
-                let samples = sample_target_sequence_speculative(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    self.gamma,
-                )
-                .await?;
-
-                let mut accepted_tokens = Vec::new();
-                for (target_sample, draft_sample) in zip(samples, draft_samples) {
-                    let tok = target_sample.sample.token;
-                    accepted_tokens.push(target_sample.sample);
-                    if draft_sample.sample.token != tok {
-                        break;
+                // Otherwise, each sequence is drafted and verified on its own: sequences accept
+                // different numbers of draft tokens, so their caches are narrowed by different
+                // amounts and cannot share one batched cache.
+                let n_seqs = input_seqs.len();
+                for seq in input_seqs.iter_mut() {
+                    match pre_cache {
+                        PreCache::Reset(reset_non_granular) => {
+                            self.set_none_cache(reset_non_granular, false)
+                        }
+                        PreCache::In => self.clone_in_cache(&mut [seq], true),
+                        // The models only hold the cache of the last sequence which was run.
+                        PreCache::Nothing if n_seqs > 1 => self.clone_in_cache(&mut [seq], true),
+                        PreCache::Nothing => (),
                     }
-                }
 
-                // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
-                for (k, v) in get_mut_arcmutex!(self.draft)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.draft)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
-                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                    }
-                }
-                for (k, v) in get_mut_arcmutex!(self.target)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.target)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
-                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                    if self.draft_branches > 1 {
+                        self.step_seq_tree(
+                            seq,
+                            is_prompt,
+                            prefix_cacher,
+                            disable_eos_stop,
+                            rng.clone(),
+                        )
+                        .await?;
+                    } else {
+                        self.step_seq(seq, is_prompt, prefix_cacher, disable_eos_stop, rng.clone())
+                            .await?;
                     }
-                }
 
-                let eos_owned = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .eos_tok
-                    .clone();
-                let eos_tok = if disable_eos_stop {
-                    None
-                } else {
-                    Some(&eos_owned[..])
-                };
-                // Add the tokens to the seq and the trie
-                for accepted in accepted_tokens {
-                    // Do not use the prefix cacher
-                    finish_or_add_toks_to_seq(
-                        self,
-                        prefix_cacher,
-                        seq,
-                        accepted.clone(),
-                        eos_tok,
-                        false,
-                    )
-                    .await?;
-                    match seq.recognizer {
-                        SequenceRecognizer::Regex(ref mut rx) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(rx.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
-                        }
-                        SequenceRecognizer::Cfg(ref mut cfg) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(cfg.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
+                    match post_op {
+                        CacheInstruction::Out => {
+                            self.clone_out_cache(&mut [seq], true);
                         }
-                        SequenceRecognizer::None => {}
-                    }
-                }
-
-                // Trick to improve lower bounds. Sample last token in multinomial
-                /*
-                let sample = sample_sequence(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    false, // todo tune
-                    true, // do not add to tok trie yet
-                    true,
-                )
-                .await?;
-                finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
-                */
-
-                match post_op {
-                    CacheInstruction::Out => {
-                        self.clone_out_cache(input_seqs, true);
+                        CacheInstruction::Nothing(_) => (),
+                        CacheInstruction::Reset {
+                            reset_non_granular,
+                            adapter_inst: _,
+                        } => self.set_none_cache(reset_non_granular, true),
+                        _ => unreachable!("Unreachable pre cache op."),
                     }
-                    CacheInstruction::Nothing(_) => (),
-                    CacheInstruction::Reset {
-                        reset_non_granular,
-                        adapter_inst: _,
-                    } => self.set_none_cache(reset_non_granular, true),
-                    _ => unreachable!("Unreachable pre cache op."),
                 }
 
-                // Done! We have:
+                // Done! For each sequence we have:
                 // - Run the draft model gamma times
                 // - Reset draft model cache fully
                 // - Sampled draft model's distributions
@@ -654,7 +1918,677 @@ impl Pipeline for SpeculativePipeline {
     fn category(&self) -> ModelCategory {
         self.category
     }
+    fn prewarm(&mut self) -> anyhowResult<()> {
+        get_mut_arcmutex!(self.target).prewarm()?;
+        if let Some(draft) = self.draft_model() {
+            get_mut_arcmutex!(draft).prewarm()?;
+        }
+        Ok(())
+    }
 }
 
 // TODO
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{atomic::Ordering, Arc, Mutex},
+    };
+
+    use candle_core::{DType, Device, Tensor};
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+    use tokenizers::{models::wordlevel::WordLevel, AddedToken, Tokenizer};
+
+    use super::{
+        accept_draft_tokens, narrow_rejected, prompt_lookup, repeat_batch, select_batch_row,
+        top_k_tokens, vocabs_match, AdapterInstruction, AdaptiveGamma, CacheBackendMetadata,
+        CacheInstruction, DraftTokenMap, DraftTree, GammaController, SpeculativeConfig,
+        SpeculativePipeline, VocabMismatchPolicy,
+    };
+    use crate::{
+        pipeline::{
+            sampling::SpeculativeSample,
+            test_utils::{new_mock_seq, MockPipeline, NextToken},
+        },
+        prefix_cacher::PrefixCacheManager,
+        response::Response,
+        sampler::Logprobs,
+        sequence::{Sequence, SequenceState, StopReason},
+        MetadataMixin, Pipeline,
+    };
+
+    fn tokenizer(vocab: &[(&str, u32)], special: &[&str]) -> Tokenizer {
+        let vocab = vocab
+            .iter()
+            .map(|(tok, id)| (tok.to_string(), *id))
+            .collect::<HashMap<_, _>>();
+        let mut tokenizer = Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("<unk>".to_string())
+                .build()
+                .unwrap(),
+        );
+        for tok in special {
+            tokenizer.add_special_tokens(&[AddedToken::from(tok.to_string(), true)]);
+        }
+        tokenizer
+    }
+
+    #[test]
+    fn test_vocabs_match() {
+        let vocab = [("<unk>", 0), ("hello", 1), ("world", 2)];
+        let target = tokenizer(&vocab, &["<|eot_id|>"]);
+        let draft = tokenizer(&vocab, &["</s>"]);
+        assert!(!vocabs_match(&target, &draft, VocabMismatchPolicy::Strict));
+        assert!(vocabs_match(
+            &target,
+            &draft,
+            VocabMismatchPolicy::IgnoreSpecial
+        ));
+
+        let other = tokenizer(&[("<unk>", 0), ("hello", 2), ("world", 1)], &["</s>"]);
+        assert!(!vocabs_match(
+            &target,
+            &other,
+            VocabMismatchPolicy::IgnoreSpecial
+        ));
+    }
+
+    #[test]
+    fn test_draft_token_map() {
+        // The tokenizers only differ in their added tokens, which get the ids after the vocab.
+        let vocab = [("<unk>", 0), ("hello", 1), ("world", 2)];
+        let target = tokenizer(&vocab, &["<|eot_id|>"]);
+        let draft = tokenizer(&vocab, &["</s>", "<pad>"]);
+        assert_eq!(target.token_to_id("<|eot_id|>"), Some(3));
+        assert_eq!(draft.token_to_id("</s>"), Some(3));
+
+        // 3 of the 5 draft tokens are shared.
+        assert!(DraftTokenMap::new(&target, &draft, 0.7).is_err());
+        assert!(!vocabs_match(
+            &target,
+            &draft,
+            VocabMismatchPolicy::TokenMap { min_overlap: 0.7 }
+        ));
+        let map = DraftTokenMap::new(&target, &draft, 0.6).unwrap();
+        assert_eq!(map.to_draft(2), Some(2));
+        // The target-only `<|eot_id|>` has no draft counterpart.
+        assert_eq!(map.to_draft(3), None);
+        assert_eq!(map.context_to_draft(&[1, 2]), Some(vec![1, 2]));
+        assert_eq!(map.context_to_draft(&[1, 3, 2]), None);
+        // The draft-only `</s>` is rejected, the following draft tokens are dropped.
+        assert_eq!(map.draft_tokens_to_target(&[1, 2, 3, 1]), vec![1, 2]);
+        assert_eq!(map.draft_tokens_to_target(&[2, 1]), vec![2, 1]);
+
+        // Shared tokens with other ids are mapped.
+        let swapped = tokenizer(&[("<unk>", 0), ("hello", 2), ("world", 1)], &[]);
+        let map = DraftTokenMap::new(&target, &swapped, 1.).unwrap();
+        assert_eq!(map.draft_tokens_to_target(&[1, 2]), vec![2, 1]);
+        assert_eq!(map.to_draft(1), Some(2));
+
+        // The target samples after the last token and each mapped draft token. Both mapped draft
+        // tokens are accepted, and the last target sample replaces the rejected `</s>`.
+        let samples = [1, 2, 3]
+            .into_iter()
+            .map(|token| SpeculativeSample {
+                sample: Logprobs {
+                    token,
+                    logprob: 0.,
+                    bytes: None,
+                    top_logprobs: None,
+                },
+            })
+            .collect();
+        let (accepted, n_accepted_draft) = accept_draft_tokens(samples, &[1, 2]);
+        assert_eq!(n_accepted_draft, 2);
+        assert_eq!(
+            accepted.iter().map(|x| x.token).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_prompt_lookup() {
+        // The most recent match of the longest end, at most 3 tokens, is continued.
+        let toks = [1, 2, 3, 4, 1, 2, 3, 4, 5, 2, 3];
+        assert_eq!(prompt_lookup(&toks, 3, 4), vec![4, 5, 2, 3]);
+        assert_eq!(prompt_lookup(&toks, 3, 2), vec![4, 5]);
+        assert_eq!(prompt_lookup(&toks, 1, 4), vec![2, 3]);
+        // The last token never occurred before.
+        assert!(prompt_lookup(&[1, 2, 3], 3, 4).is_empty());
+        assert!(prompt_lookup(&[], 3, 4).is_empty());
+    }
+
+    #[test]
+    fn test_prompt_lookup_accepts_multiple_tokens() {
+        let samples = |toks: &[u32]| {
+            toks.iter()
+                .map(|token| SpeculativeSample {
+                    sample: Logprobs {
+                        token: *token,
+                        logprob: 0.,
+                        bytes: None,
+                        top_logprobs: None,
+                    },
+                })
+                .collect::<Vec<_>>()
+        };
+        let tokens =
+            |accepted: Vec<Logprobs>| accepted.into_iter().map(|x| x.token).collect::<Vec<_>>();
+
+        // A repetitive sequence, which a target model continues with the same cycle.
+        let cycle = [1, 2, 3, 4];
+        let toks = (0..10).map(|i| cycle[i % 4]).collect::<Vec<_>>();
+        let draft = prompt_lookup(&toks, 3, 4);
+        assert_eq!(draft, vec![3, 4, 1, 2]);
+
+        // The target samples after the last token and each draft token: every draft token is
+        // accepted, and the sample after them too.
+        let target = (10..15).map(|i| cycle[i % 4]).collect::<Vec<_>>();
+        let (accepted, n_accepted_draft) = accept_draft_tokens(samples(&target), &draft);
+        assert_eq!(n_accepted_draft, 4);
+        assert_eq!(tokens(accepted), vec![3, 4, 1, 2, 3]);
+
+        // The target disagrees with the third draft token, which it replaces.
+        let (accepted, n_accepted_draft) = accept_draft_tokens(samples(&[3, 4, 9, 2, 3]), &draft);
+        assert_eq!(n_accepted_draft, 2);
+        assert_eq!(tokens(accepted), vec![3, 4, 9]);
+
+        // Without a draft, only the target sample after the last token is accepted.
+        let (accepted, n_accepted_draft) = accept_draft_tokens(samples(&[7]), &[]);
+        assert_eq!(n_accepted_draft, 0);
+        assert_eq!(tokens(accepted), vec![7]);
+    }
+
+    #[test]
+    fn test_narrow_rejected_ragged() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let gamma = 4;
+        // Four sequences with different prompt lengths, each holding its own cache after the
+        // target model has run on the `gamma` draft tokens.
+        let mut caches = [5usize, 9, 2, 7]
+            .into_iter()
+            .map(|prompt_len| {
+                let layer = (
+                    Tensor::zeros((1, 2, prompt_len + gamma, 8), DType::F32, &dev)?,
+                    Tensor::zeros((1, 2, prompt_len + gamma, 8), DType::F32, &dev)?,
+                );
+                Ok(vec![Some(layer.clone()), None, Some(layer)])
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+
+        // The sequences accept different numbers of the draft tokens.
+        for (cache, n_accepted) in caches.iter_mut().zip([1, 4, 0, 3]) {
+            narrow_rejected(cache, gamma - n_accepted)?;
+        }
+
+        for (cache, expected_len) in caches.iter().zip([5 + 1, 9 + 4, 2, 7 + 3]) {
+            assert!(cache[1].is_none());
+            for (k, v) in cache.iter().flatten() {
+                assert_eq!(k.dims(), &[1, 2, expected_len, 8]);
+                assert_eq!(v.dims(), &[1, 2, expected_len, 8]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_draft_tree() {
+        // Two branches of 3 tokens: 5 -> 6 -> 7 and 8 -> 6 -> 9.
+        let mut tree = DraftTree::default();
+        let mut leaves = Vec::new();
+        for branch in [[5, 6, 7], [8, 6, 9]] {
+            let mut node = None;
+            for tok in branch {
+                node = Some(tree.push(tok, node));
+            }
+            leaves.push(node.unwrap());
+        }
+        assert_eq!(tree.len(), 6);
+        assert_eq!(tree.leaves(), leaves);
+        assert_eq!(tree.path(leaves[1]), vec![8, 6, 9]);
+
+        // Walking the tree follows the branch which holds the tokens.
+        let second = tree.child_with_token(None, 8).unwrap();
+        let third = tree.child_with_token(Some(second), 6).unwrap();
+        assert_eq!(tree.child_with_token(Some(third), 7), None);
+        assert_eq!(tree.child_with_token(Some(third), 9), Some(leaves[1]));
+        assert!(tree.descends_from(leaves[1], third));
+        assert!(!tree.descends_from(leaves[0], third));
+        assert_eq!(tree.child_with_token(None, 6), None);
+    }
+
+    #[test]
+    fn test_top_k_tokens() {
+        let logits = [0.1f32, 3., -1., 2., 5.];
+        assert_eq!(top_k_tokens(&logits, 2, 4), vec![1, 3]);
+        assert_eq!(top_k_tokens(&logits, 10, 1), vec![4, 3, 0, 2]);
+    }
+
+    #[test]
+    fn test_batch_cache_rows() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let layer = (
+            Tensor::zeros((1, 2, 5, 8), DType::F32, &dev)?,
+            Tensor::zeros((1, 2, 5, 8), DType::F32, &dev)?,
+        );
+        let mut cache = vec![Some(layer), None];
+        repeat_batch(&mut cache, 3)?;
+        assert_eq!(cache[0].as_ref().unwrap().0.dims(), &[3, 2, 5, 8]);
+        select_batch_row(&mut cache, 2)?;
+        narrow_rejected(&mut cache, 2)?;
+        let (k, v) = cache[0].as_ref().unwrap();
+        assert_eq!(k.dims(), &[1, 2, 3, 8]);
+        assert_eq!(v.dims(), &[1, 2, 3, 8]);
+        assert!(cache[1].is_none());
+        Ok(())
+    }
+
+    /// Run `steps` steps with a mock draft model, which proposes the target's tokens up to its
+    /// first disagreement, returning the gamma of each step.
+    fn run_gamma_controller(
+        controller: &mut GammaController,
+        steps: usize,
+        disagree_at: Option<usize>,
+    ) -> Vec<usize> {
+        let mut gammas = Vec::new();
+        for _ in 0..steps {
+            let gamma = controller.gamma();
+            gammas.push(gamma);
+            let target = (0u32..).take(gamma).collect::<Vec<_>>();
+            let draft = target
+                .iter()
+                .enumerate()
+                .map(|(i, tok)| {
+                    if Some(i) == disagree_at {
+                        tok + 1
+                    } else {
+                        *tok
+                    }
+                })
+                .collect::<Vec<_>>();
+            let samples = target
+                .iter()
+                .map(|token| SpeculativeSample {
+                    sample: Logprobs {
+                        token: *token,
+                        logprob: 0.,
+                        bytes: None,
+                        top_logprobs: None,
+                    },
+                })
+                .collect();
+            let (_, n_accepted_draft) = accept_draft_tokens(samples, &draft);
+            controller.record(gamma, n_accepted_draft);
+        }
+        gammas
+    }
+
+    #[test]
+    fn test_adaptive_gamma() {
+        let bounds = AdaptiveGamma {
+            gamma_min: 2,
+            gamma_max: 8,
+        };
+
+        // A draft model which always agrees ramps gamma up to the max, one step at a time.
+        let mut controller = GammaController::new(4, bounds);
+        let gammas = run_gamma_controller(&mut controller, 8, None);
+        assert_eq!(gammas, vec![4, 5, 6, 7, 8, 8, 8, 8]);
+
+        // Once it always disagrees on the first token, gamma falls back to the min after the
+        // recent acceptance rate drops.
+        let gammas = run_gamma_controller(&mut controller, 12, Some(0));
+        assert_eq!(gammas.last(), Some(&2));
+        assert!(gammas.windows(2).all(|w| w[1] <= w[0]));
+
+        // A middling acceptance rate keeps gamma where it is.
+        let mut controller = GammaController::new(4, bounds);
+        let gammas = run_gamma_controller(&mut controller, 8, Some(3));
+        assert!(gammas.iter().all(|gamma| *gamma == 4));
+    }
+
+    const MOCK_VOCAB_SIZE: usize = 64;
+
+    /// The target model of the step tests, which counts up from the last token.
+    fn count_up(context: &[u32]) -> u32 {
+        context.last().unwrap() + 1
+    }
+
+    /// A speculative pipeline with a [`count_up`] target model and a draft model predicting
+    /// `draft`, both mocks. The target model is returned to inspect its forward passes.
+    fn mock_speculative_pipeline(
+        draft: NextToken,
+        gamma: usize,
+    ) -> candle_core::Result<(SpeculativePipeline, Arc<tokio::sync::Mutex<MockPipeline>>)> {
+        let target = Arc::new(tokio::sync::Mutex::new(MockPipeline::new(
+            MOCK_VOCAB_SIZE,
+            Arc::new(count_up),
+        )));
+        let draft = MockPipeline::new(MOCK_VOCAB_SIZE, draft);
+        let pipeline = SpeculativePipeline::new(
+            target.clone(),
+            Arc::new(tokio::sync::Mutex::new(draft)),
+            SpeculativeConfig {
+                gamma,
+                vocab_mismatch_policy: VocabMismatchPolicy::Strict,
+                draft_branches: 1,
+                adaptive_gamma: None,
+            },
+        )?;
+        Ok((pipeline, target))
+    }
+
+    /// Run one step with the cache instructions of the engine: prompts start from an empty cache,
+    /// completions from the sequences' caches, and the caches are copied back to the sequences.
+    async fn run_step(
+        pipeline: &mut SpeculativePipeline,
+        seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+    ) -> candle_core::Result<()> {
+        let pre_op = if is_prompt {
+            CacheInstruction::Reset {
+                reset_non_granular: false,
+                adapter_inst: AdapterInstruction::None,
+            }
+        } else {
+            CacheInstruction::In(AdapterInstruction::None)
+        };
+        pipeline
+            .step(
+                seqs,
+                is_prompt,
+                &mut PrefixCacheManager::new(Device::Cpu, 0, false, true),
+                false,
+                Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0))),
+                CacheBackendMetadata::DefaultInstructions {
+                    pre_op,
+                    post_op: CacheInstruction::Out,
+                },
+            )
+            .await
+    }
+
+    /// The target and draft caches of `seq` hold all of its tokens but the last, which the models
+    /// run on at the next step.
+    fn assert_caches_match_tokens(seq: &mut Sequence) -> candle_core::Result<()> {
+        let toks = seq.get_toks().to_vec();
+        let expected = &toks[..toks.len() - 1];
+        for cache in [seq.cache().clone(), seq.draft_cache().clone()] {
+            let (k, _) = cache[0].as_ref().expect("The cache was not copied out.");
+            assert_eq!(k.flatten_all()?.to_vec1::<u32>()?, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_acceptance_patterns() -> candle_core::Result<()> {
+        let gamma = 3;
+        let agree: NextToken = Arc::new(count_up);
+        let disagree: NextToken = Arc::new(|context| context.last().unwrap() + 2);
+        // Disagrees when the context length is a multiple of 3, which depends on the draft cache
+        // being narrowed to the accepted tokens.
+        let periodic: NextToken = Arc::new(|context| {
+            context.last().unwrap() + if context.len() % 3 == 0 { 2 } else { 1 }
+        });
+
+        // The draft tokens accepted at each step. The target samples after the last token and the
+        // first `gamma - 1` draft tokens, so each step adds the accepted draft tokens, plus the
+        // target sample replacing the first rejected one.
+        for (draft, accepted, n_new) in [
+            (agree, [3, 3, 3, 3], 12),
+            (disagree, [0, 0, 0, 0], 4),
+            (periodic, [0, 2, 2, 2], 10),
+        ] {
+            let (mut pipeline, _) = mock_speculative_pipeline(draft, gamma)?;
+            let (mut seq, _rx) = new_mock_seq(0, vec![1, 2, 3], None);
+            let mut total_accepted = 0;
+            for (step, n_accepted) in accepted.into_iter().enumerate() {
+                let len = seq.get_toks().len();
+                run_step(&mut pipeline, &mut [&mut seq], step == 0).await?;
+                total_accepted += n_accepted;
+
+                let metadata = pipeline.get_metadata();
+                assert_eq!(
+                    metadata.speculative_accepted_tokens.load(Ordering::Relaxed),
+                    total_accepted
+                );
+                assert_eq!(
+                    metadata.speculative_drafted_tokens.load(Ordering::Relaxed),
+                    gamma * (step + 1)
+                );
+                assert_eq!(
+                    metadata.speculative_draft_calls.load(Ordering::Relaxed),
+                    step + 1
+                );
+                assert_eq!(seq.get_toks().len(), len + (n_accepted + 1).min(gamma));
+                assert_caches_match_tokens(&mut seq)?;
+            }
+
+            // The output is the target model's, however many draft tokens were accepted.
+            assert_eq!(seq.get_toks(), (1..=3 + n_new).collect::<Vec<u32>>());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_unmapped_token_ends_drafting() -> candle_core::Result<()> {
+        // The draft vocab is the first half of the target vocab, so the tokens from 32 on have no
+        // draft counterpart.
+        let target = Arc::new(tokio::sync::Mutex::new(MockPipeline::new(
+            MOCK_VOCAB_SIZE,
+            Arc::new(count_up),
+        )));
+        let draft = Arc::new(tokio::sync::Mutex::new(MockPipeline::new(
+            MOCK_VOCAB_SIZE / 2,
+            Arc::new(count_up),
+        )));
+        let mut pipeline = SpeculativePipeline::new(
+            target,
+            draft.clone(),
+            SpeculativeConfig {
+                gamma: 3,
+                vocab_mismatch_policy: VocabMismatchPolicy::TokenMap { min_overlap: 1. },
+                draft_branches: 1,
+                adaptive_gamma: None,
+            },
+        )?;
+        let (mut seq, _rx) = new_mock_seq(0, vec![38, 39, 40], None);
+        for step in 0..3 {
+            run_step(&mut pipeline, &mut [&mut seq], step == 0).await?;
+        }
+
+        // The target model generates the sequence alone, one token per step.
+        assert_eq!(seq.get_toks(), (38..=43).collect::<Vec<u32>>());
+        assert!(draft.lock().await.forward_batch_sizes.is_empty());
+        let usage = seq.get_mut_group().get_usage();
+        assert_eq!(usage.speculative_acceptance_rate, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_usage_is_per_request() -> candle_core::Result<()> {
+        let gamma = 3;
+        // Accepts 0, 2, 2 and 2 draft tokens in the first four steps from a 3 token prompt.
+        let periodic: NextToken = Arc::new(|context| {
+            context.last().unwrap() + if context.len() % 3 == 0 { 2 } else { 1 }
+        });
+        let (mut pipeline, _) = mock_speculative_pipeline(periodic, gamma)?;
+        let (mut first, _rx) = new_mock_seq(0, vec![1, 2, 3], None);
+        for step in 0..4 {
+            run_step(&mut pipeline, &mut [&mut first], step == 0).await?;
+        }
+        let (mut second, _rx) = new_mock_seq(1, vec![1, 2, 3], None);
+        run_step(&mut pipeline, &mut [&mut second], true).await?;
+
+        // The pipeline counts the steps of both requests, the usage only those of its request.
+        let metadata = pipeline.get_metadata();
+        assert_eq!(metadata.acceptance_rate(), Some(6. / 15.));
+        let usage = first.get_mut_group().get_usage();
+        assert_eq!(usage.speculative_acceptance_rate, Some(0.5));
+        assert_eq!(usage.speculative_tokens_per_draft_call, Some(1.5));
+        let usage = second.get_mut_group().get_usage();
+        assert_eq!(usage.speculative_acceptance_rate, Some(0.0));
+        assert_eq!(usage.speculative_tokens_per_draft_call, Some(0.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_ragged_batch_to_completion() -> candle_core::Result<()> {
+        // The draft model disagrees when the context length is a multiple of 3, so sequences with
+        // different lengths accept different numbers of draft tokens at each step.
+        let draft: NextToken = Arc::new(|context| {
+            context.last().unwrap() + if context.len() % 3 == 0 { 2 } else { 1 }
+        });
+        let (mut pipeline, _) = mock_speculative_pipeline(draft, 3)?;
+        let prompts = [vec![1, 2, 3], vec![20, 21, 22, 23, 24, 25, 26, 27]];
+        let max_lens = [6, 10];
+        let (mut seq_a, mut rx_a) = new_mock_seq(0, prompts[0].clone(), Some(max_lens[0]));
+        let (mut seq_b, mut rx_b) = new_mock_seq(1, prompts[1].clone(), Some(max_lens[1]));
+
+        // As the engine does, only the running sequences are scheduled.
+        let mut is_prompt = true;
+        for _ in 0..10 {
+            let mut running = [&mut seq_a, &mut seq_b]
+                .into_iter()
+                .filter(|seq| !seq.is_finished_paged_attn())
+                .collect::<Vec<_>>();
+            if running.is_empty() {
+                break;
+            }
+            run_step(&mut pipeline, &mut running, is_prompt).await?;
+            for seq in running {
+                if !seq.is_finished_paged_attn() {
+                    assert_caches_match_tokens(seq)?;
+                }
+            }
+            is_prompt = false;
+        }
+
+        for (((seq, rx), prompt), max_len) in [(&mut seq_a, &mut rx_a), (&mut seq_b, &mut rx_b)]
+            .into_iter()
+            .zip(prompts)
+            .zip(max_lens)
+        {
+            assert!(matches!(
+                seq.getstate(),
+                SequenceState::Done(StopReason::Length(len)) if len == max_len
+            ));
+            // Exactly `max_len` tokens, even if more were accepted at the last step.
+            let first = prompt[0];
+            let n_toks = u32::try_from(prompt.len() + max_len).unwrap();
+            assert_eq!(
+                seq.get_toks(),
+                (first..first + n_toks).collect::<Vec<u32>>()
+            );
+            let Ok(Response::CompletionDone(response)) = rx.try_recv() else {
+                panic!("The sequence did not send its completion.");
+            };
+            assert_eq!(response.choices[0].finish_reason, "length");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_batch_of_four() -> candle_core::Result<()> {
+        // The draft model never predicts multiples of 4, so each sequence accepts the draft tokens
+        // up to the next one.
+        let draft: NextToken = Arc::new(|context| {
+            let next = context.last().unwrap() + 1;
+            if next % 4 == 0 {
+                next + 1
+            } else {
+                next
+            }
+        });
+        let (mut pipeline, target) = mock_speculative_pipeline(draft, 3)?;
+        let prompts = [
+            vec![0, 1, 2, 3],
+            vec![10, 11, 12, 13],
+            vec![21, 22, 23, 24],
+            vec![30, 31, 32, 33],
+        ];
+        let mut seqs = prompts
+            .iter()
+            .enumerate()
+            .map(|(id, prompt)| new_mock_seq(id, prompt.clone(), None).0)
+            .collect::<Vec<_>>();
+
+        // The tokens added to each sequence at each step: the accepted draft tokens, plus the
+        // target sample replacing the first rejected one.
+        let steps = [[1, 3, 3, 3], [3, 3, 1, 3], [1, 1, 3, 1]];
+        for (step, new_tokens) in steps.into_iter().enumerate() {
+            let lens = seqs
+                .iter()
+                .map(|seq| seq.get_toks().len())
+                .collect::<Vec<_>>();
+            let mut batch = seqs.iter_mut().collect::<Vec<_>>();
+            run_step(&mut pipeline, &mut batch, step == 0).await?;
+            for ((seq, len), n_new) in seqs.iter_mut().zip(lens).zip(new_tokens) {
+                assert_eq!(seq.get_toks().len(), len + n_new);
+                assert_caches_match_tokens(seq)?;
+            }
+        }
+        for (seq, prompt) in seqs.iter().zip(prompts) {
+            let first = prompt[0];
+            let n_toks = u32::try_from(seq.get_toks().len()).unwrap();
+            assert_eq!(
+                seq.get_toks(),
+                (first..first + n_toks).collect::<Vec<u32>>()
+            );
+        }
+
+        // The prompts are verified in one batch. The completions are verified in batches of the
+        // sequences with the same length: [0] alone, then [1, 2, 3], then [0, 2] and [1, 3].
+        assert_eq!(target.lock().await.forward_batch_sizes, [4, 1, 3, 2, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_step_tree_branches_after_root() -> candle_core::Result<()> {
+        // The target counts up, wrapping around the vocab. The draft model agrees, except that it
+        // predicts 5 after 63. Its next most likely token, as for all mocks, is the lowest id.
+        let target = MockPipeline::new(
+            MOCK_VOCAB_SIZE,
+            Arc::new(|context| (context.last().unwrap() + 1) % MOCK_VOCAB_SIZE as u32),
+        );
+        let draft = MockPipeline::new(
+            MOCK_VOCAB_SIZE,
+            Arc::new(|context| match context.last().unwrap() {
+                63 => 5,
+                last => last + 1,
+            }),
+        );
+        let mut pipeline = SpeculativePipeline::new(
+            Arc::new(tokio::sync::Mutex::new(target)),
+            Arc::new(tokio::sync::Mutex::new(draft)),
+            SpeculativeConfig {
+                gamma: 2,
+                vocab_mismatch_policy: VocabMismatchPolicy::Strict,
+                draft_branches: 2,
+                adaptive_gamma: None,
+            },
+        )?;
+        let (mut seq, _rx) = new_mock_seq(0, vec![61, 62], None);
+        run_step(&mut pipeline, &mut [&mut seq], true).await?;
+
+        // Both branches keep the draft's first token, 63, and split at the second depth into 5
+        // and 0. Branching only at the root would have drafted 63 -> 5 and 0 -> 1 instead, and
+        // rejected the second token.
+        assert_eq!(seq.get_toks(), [61, 62, 63, 0]);
+        let metadata = &pipeline.metadata;
+        assert_eq!(
+            metadata.speculative_drafted_tokens.load(Ordering::Relaxed),
+            3
+        );
+        assert_eq!(
+            metadata.speculative_accepted_tokens.load(Ordering::Relaxed),
+            2
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+//! A mock [`Pipeline`] to test the pipelines which drive other pipelines, such as speculative
+//! decoding, and the crates serving a pipeline. It is exported with the `test-utils` feature.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{atomic::AtomicUsize, Arc},
+};
+
+use candle_core::{DType, Device, Result, Tensor};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use tokenizers::{decoders::byte_level::ByteLevel, models::wordlevel::WordLevel, Tokenizer};
+
+use super::{
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate,
+    hidden_states::capture_before_head, sampling::sample_and_add_toks,
+    text_models_inputs_processor::ModelInputs, AdapterActivationMixin, AnyMoePipelineMixin, Cache,
+    CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
+    KVCacheDtype, MetadataMixin, ModelCategory, ModelKind, Pipeline, PreProcessingMixin,
+};
+use crate::{
+    aici::bintokens::build_tok_trie, prefix_cacher::PrefixCacheManager, sequence::Sequence,
+};
+#[cfg(test)]
+use crate::{
+    response::Response,
+    sampler::{Sampler, SamplerConfig},
+    sequence::{SeqStepType, SequenceGroup, SequenceRecognizer},
+};
+#[cfg(test)]
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// The next token of a [`MockPipeline`] after a context.
+pub type NextToken = Arc<dyn Fn(&[u32]) -> u32 + Send + Sync>;
+
+/// Logit of the predicted token, all others are 0.
+const MOCK_LOGIT: f32 = 10.;
+
+/// A text model without weights, whose prediction after a context is `next_token(context)`. Its
+/// single layer KV cache holds the ids of the tokens it ran on, so predictions depend on the
+/// cache as they would for a real model: a cache which is not narrowed, restored or batched
+/// correctly changes the context, and so the predictions. Its hidden states before the LM head
+/// are the one-hot encodings of the tokens it runs on.
+pub struct MockPipeline {
+    next_token: NextToken,
+    vocab_size: usize,
+    cache: Cache,
+    tokenizer: Arc<Tokenizer>,
+    metadata: Arc<GeneralMetadata>,
+    /// Batch size of each forward pass
+    pub forward_batch_sizes: Vec<usize>,
+}
+
+impl MockPipeline {
+    pub fn new(vocab_size: usize, next_token: NextToken) -> Self {
+        let tokenizer = mock_tokenizer(vocab_size);
+        let metadata = GeneralMetadata {
+            max_seq_len: 4096,
+            tok_trie: Some(build_tok_trie(tokenizer.clone()).into()),
+            has_no_kv_cache: false,
+            num_hidden_layers: 1,
+            eos_tok: vec![],
+            kind: ModelKind::Normal,
+            is_xlora: false,
+            activation_dtype: DType::F32,
+            sliding_window: None,
+            kv_cache_dtype: KVCacheDtype::FullPrecision,
+            cache_config: None,
+            cache_engine: None,
+            prompt_batchsize: None,
+            speculative_accepted_tokens: AtomicUsize::new(0),
+            speculative_drafted_tokens: AtomicUsize::new(0),
+            speculative_draft_calls: AtomicUsize::new(0),
+            per_request_adapter_override: false,
+            pending_adapter_swaps: Default::default(),
+        };
+        Self {
+            next_token,
+            vocab_size,
+            cache: Cache::new(1, false),
+            tokenizer: Arc::new(tokenizer),
+            metadata: Arc::new(metadata),
+            forward_batch_sizes: Vec::new(),
+        }
+    }
+}
+
+/// A byte level tokenizer whose tokens `t0`, `t1`, ... have the ids 0, 1, ...
+fn mock_tokenizer(vocab_size: usize) -> Tokenizer {
+    let vocab = (0..vocab_size)
+        .map(|id| (format!("t{id}"), u32::try_from(id).unwrap()))
+        .collect::<HashMap<_, _>>();
+    let mut tokenizer = Tokenizer::new(
+        WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("t0".to_string())
+            .build()
+            .unwrap(),
+    );
+    tokenizer.with_decoder(ByteLevel::default());
+    tokenizer
+}
+
+/// A sequence with the `prompt` tokens, sampled greedily, which finishes after `max_len` new
+/// tokens if given. The receiver gets its responses.
+#[cfg(test)]
+pub(crate) fn new_mock_seq(
+    id: usize,
+    prompt: Vec<u32>,
+    max_len: Option<usize>,
+) -> (Sequence, Receiver<Response>) {
+    let (tx, rx) = channel(16);
+    let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+        1, false, false, 1,
+    )));
+    let seq = Sequence::new_waiting(
+        prompt,
+        "prompt".to_string(),
+        id,
+        0,
+        1,
+        tx,
+        Sampler::new(SamplerConfig::default()).unwrap(),
+        vec![],
+        vec![],
+        max_len,
+        None,
+        false,
+        false,
+        group,
+        0,
+        0,
+        SequenceRecognizer::None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        SeqStepType::PromptAndDecode,
+        None,
+        None,
+    );
+    (seq, rx)
+}
+
+impl PreProcessingMixin for MockPipeline {
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        None
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for MockPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> anyhow::Result<()> {
+        anyhow::bail!("The mock pipeline has no weights.")
+    }
+}
+
+impl CacheManagerMixin for MockPipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        DefaultCacheManager.clone_in_cache(self, seqs, modify_draft_cache)
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        DefaultCacheManager.clone_out_cache(self, seqs, modify_draft_cache)
+    }
+    fn set_none_cache(&self, _reset_non_granular: bool, modify_draft_cache: bool) {
+        DefaultCacheManager.set_none_cache(self, modify_draft_cache);
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+}
+
+impl AdapterActivationMixin for MockPipeline {
+    fn activate_weighted_adapters(
+        &mut self,
+        _adapters: Vec<(String, f64)>,
+    ) -> anyhow::Result<usize> {
+        anyhow::bail!("The mock pipeline has no adapters.")
+    }
+}
+
+impl MetadataMixin for MockPipeline {
+    fn device(&self) -> Device {
+        Device::Cpu
+    }
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        Some(self.tokenizer.clone())
+    }
+    fn name(&self) -> String {
+        "mock".to_string()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for MockPipeline {
+    fn forward_inputs(&mut self, inputs: Box<dyn Any>) -> Result<ForwardInputsResult> {
+        let ModelInputs {
+            input_ids,
+            context_lens,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        let (batch, seq_len) = input_ids.dims2()?;
+        self.forward_batch_sizes.push(batch);
+        capture_before_head(&candle_nn::encoding::one_hot(
+            input_ids.clone(),
+            self.vocab_size,
+            1f32,
+            0f32,
+        )?);
+
+        // The cached and new tokens of each batch row, as the KV cache of a real model would be.
+        let new_ids = input_ids.reshape((batch, 1, seq_len, 1))?;
+        let mut cache = self.cache.lock();
+        let ids = match &cache[0] {
+            Some((k, _)) => Tensor::cat(&[k, &new_ids], 2)?,
+            None => new_ids,
+        };
+        cache[0] = Some((ids.clone(), ids.clone()));
+        let ids = ids.squeeze(3)?.squeeze(1)?.to_vec2::<u32>()?;
+
+        // Logits of the positions selected by `context_lens`, as `extract_logits` returns them.
+        let n_logits = context_lens[0].1;
+        let mut logits = vec![0f32; batch * n_logits * self.vocab_size];
+        for (row, (start, len)) in context_lens.into_iter().enumerate() {
+            if len != n_logits {
+                candle_core::bail!("All batch rows must select the same number of logits.");
+            }
+            let n_cached = ids[row].len() - seq_len;
+            for i in 0..len {
+                let context = &ids[row][..n_cached + start + i + 1];
+                let token = usize::try_from((self.next_token)(context)).unwrap();
+                logits[(row * n_logits + i) * self.vocab_size + token] = MOCK_LOGIT;
+            }
+        }
+        let logits = Tensor::from_vec(logits, (batch, n_logits, self.vocab_size), &Device::Cpu)?;
+        Ok(ForwardInputsResult::CausalGeneration { logits })
+    }
+    async fn sample_causal_gen(
+        &self,
+        seqs: &mut [&mut Sequence],
+        logits: Vec<Tensor>,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
+    }
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Text
+    }
+}
+
+impl AnyMoePipelineMixin for MockPipeline {}
@@ -1,14 +1,16 @@
 use super::cache_manager::DefaultCacheManager;
 use super::isq::UqffFullSer;
 use super::{
-    get_model_paths, get_xlora_paths, AdapterActivationMixin, AnyMoePipelineMixin, Cache,
-    CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
-    Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths, PreProcessingMixin, Processor,
-    TokenSource, VLlamaLoader, VisionModel, VisionModelLoader, XLoraPaths,
+    apply_rope_scaling, get_model_paths, get_xlora_paths, AdapterActivationMixin, AdapterKind,
+    AnyMoePipelineMixin, Cache, CacheManager, CacheManagerMixin, ForwardInputsResult,
+    GeneralMetadata, IsqPipelineMixin, KVCacheDtype, Loader, MetadataMixin, ModelCategory,
+    ModelKind, ModelPaths, PreProcessingMixin, Processor, TokenSource, VLlamaLoader, VisionModel,
+    VisionModelLoader, XLoraPaths,
 };
 use super::{Idefics2Loader, LLaVALoader, LLaVANextLoader, Phi3VLoader, VisionLoaderType};
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
+use crate::layers::RopeScalingConfig;
 use crate::paged_attention::{calculate_cache_config, AttentionImplementation, CacheEngine};
 use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
 use crate::pipeline::sampling::sample_and_add_toks;
@@ -22,12 +24,13 @@ use crate::vision_models::preprocessor_config::PreProcessorConfig;
 use crate::vision_models::processor_config::ProcessorConfig;
 use crate::vision_models::ModelInputs;
 use crate::{
-    api_dir_list, api_get_file, get_paths, get_uqff_paths, vision_normal_model_loader,
-    AnyMoeExpertType, DeviceMapMetadata, Ordering, PagedAttentionConfig, Pipeline, Topology,
-    TryIntoDType,
+    api_dir_list, api_get_file, get_paths, get_uqff_paths, lora_model_loader,
+    vision_normal_model_loader, xlora_model_loader, AnyMoeExpertType, DeviceMapMetadata, Ordering,
+    PagedAttentionConfig, Pipeline, Topology, TryIntoDType,
 };
 use anyhow::Result;
 use candle_core::{Device, Tensor, Var};
+use candle_nn::VarMap;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
@@ -37,6 +40,7 @@ use std::fs;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, RwLock};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
@@ -52,6 +56,8 @@ pub struct VisionPipeline {
     preprocessor_config: Arc<PreProcessorConfig>,
     topology: Option<Topology>,
     silent: bool,
+    isq_exclude: Vec<String>,
+    isq_layer_types: Vec<(String, IsqType)>,
     // For full UQFF serialization
     template_filename: Option<PathBuf>,
     generation_config: Option<PathBuf>,
@@ -70,6 +76,7 @@ pub struct VisionLoader {
     tokenizer_json: Option<String>,
     xlora_model_id: Option<String>,
     xlora_order: Option<Ordering>,
+    rope_scaling: Option<RopeScalingConfig>,
     token_source: RwLock<Option<TokenSource>>,
     revision: RwLock<Option<String>>,
     from_uqff: RwLock<Option<PathBuf>>,
@@ -83,6 +90,9 @@ pub struct VisionLoaderBuilder {
     kind: ModelKind,
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
+    rope_scaling: Option<RopeScalingConfig>,
+    xlora_model_id: Option<String>,
+    xlora_order: Option<Ordering>,
 }
 
 #[derive(Clone, Default)]
@@ -90,7 +100,15 @@ pub struct VisionLoaderBuilder {
 pub struct VisionSpecificConfig {
     pub use_flash_attn: bool,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    /// Dtype the KV cache is stored in. With PagedAttention, only FP8 is supported.
+    pub kv_cache_dtype: KVCacheDtype,
     pub topology: Option<Topology>,
+    /// Regexes matched against layer names, such as `lm_head` or `layers\.0\.self_attn`. Matching
+    /// layers are not quantized by ISQ.
+    pub isq_exclude: Vec<String>,
+    /// ISQ types by layer name regex, used instead of the model ISQ type for matching layers. The
+    /// first matching regex is used, for example `(r"self_attn", IsqType::Q8_0)`.
+    pub isq_layer_types: Vec<(String, IsqType)>,
     pub write_uqff: Option<PathBuf>,
     pub from_uqff: Option<PathBuf>,
 }
@@ -108,9 +126,52 @@ impl VisionLoaderBuilder {
             tokenizer_json,
             model_id,
             kind: ModelKind::Normal,
+            rope_scaling: None,
+            xlora_model_id: None,
+            xlora_order: None,
         }
     }
 
+    fn with_adapter(mut self, xlora_model_id: String, xlora_order: Ordering) -> Self {
+        self.xlora_model_id = Some(xlora_model_id);
+        self.xlora_order = Some(xlora_order);
+        self.model_id = if let Some(id) = self.model_id {
+            Some(id)
+        } else {
+            info!(
+                "Using adapter base model ID: `{}`",
+                self.xlora_order.as_ref().unwrap().base_model_id
+            );
+            Some(self.xlora_order.as_ref().unwrap().base_model_id.clone())
+        };
+        self
+    }
+
+    /// Load X-LoRA adapters for the language model. This is supported for LLaVA and LLaVANext
+    /// models with a Mistral language model. The KV cache is always used.
+    pub fn with_xlora(mut self, xlora_model_id: String, xlora_order: Ordering) -> Self {
+        self.kind = ModelKind::Adapter {
+            adapter: AdapterKind::XLora,
+        };
+        self.with_adapter(xlora_model_id, xlora_order)
+    }
+
+    /// Load LoRA adapters for the language model. This is supported for LLaVA and LLaVANext
+    /// models with a Mistral language model.
+    pub fn with_lora(mut self, lora_model_id: String, lora_order: Ordering) -> Self {
+        self.kind = ModelKind::Adapter {
+            adapter: AdapterKind::Lora,
+        };
+        self.with_adapter(lora_model_id, lora_order)
+    }
+
+    /// Scale the RoPE frequencies of the language model to extend the context window, this is
+    /// written into the model config before loading.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScalingConfig) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
     pub fn build(self, loader: VisionLoaderType) -> Box<dyn Loader> {
         let loader: Box<dyn VisionModelLoader> = match loader {
             VisionLoaderType::Phi3V => Box::new(Phi3VLoader),
@@ -126,8 +187,9 @@ impl VisionLoaderBuilder {
             kind: self.kind,
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
-            xlora_model_id: None,
-            xlora_order: None,
+            xlora_model_id: self.xlora_model_id,
+            xlora_order: self.xlora_order,
+            rope_scaling: self.rope_scaling,
             token_source: RwLock::new(None),
             revision: RwLock::new(None),
             from_uqff: RwLock::new(None),
@@ -188,7 +250,17 @@ impl Loader for VisionLoader {
         in_situ_quant: Option<IsqType>,
         mut paged_attn_config: Option<PagedAttentionConfig>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let mut config = std::fs::read_to_string(paths.get_config_filename())?;
+        if let Some(rope_scaling) = &self.rope_scaling {
+            config = apply_rope_scaling(&config, rope_scaling)?;
+        }
+
+        if mapper.is_paged() {
+            anyhow::bail!("Paged device mapping is not supported for vision models.");
+        }
+        if mapper.is_tensor_parallel() {
+            anyhow::bail!("Tensor parallelism is not supported for vision models.");
+        }
 
         // Otherwise, the device mapper will print it
         if mapper.is_dummy()
@@ -261,6 +333,34 @@ impl Loader for VisionLoader {
                 device.clone(),
                 attention_mechanism
             ),
+            ModelKind::Adapter {
+                adapter: AdapterKind::XLora,
+            } => xlora_model_loader!(
+                paths,
+                Some(dtype),
+                &load_device,
+                config,
+                self.inner,
+                self.config.use_flash_attn,
+                silent,
+                mapper,
+                loading_isq,
+                device.clone()
+            ),
+            ModelKind::Adapter {
+                adapter: AdapterKind::Lora,
+            } => lora_model_loader!(
+                paths,
+                dtype,
+                &load_device,
+                config,
+                self.inner,
+                self.config.use_flash_attn,
+                silent,
+                mapper,
+                loading_isq,
+                device.clone()
+            ),
             _ => unreachable!(),
         };
         let preprocessor_config: PreProcessorConfig = serde_json::from_str(
@@ -292,7 +392,9 @@ impl Loader for VisionLoader {
             .map(|f| serde_json::from_str(&fs::read_to_string(f).unwrap()).unwrap());
         let chat_template = get_chat_template(paths, &self.chat_template, None);
 
-        if (in_situ_quant.is_some() || self.config.topology.is_some())
+        if (in_situ_quant.is_some()
+            || self.config.topology.is_some()
+            || !self.config.isq_layer_types.is_empty())
             && self.config.from_uqff.is_none()
         {
             model.quantize(
@@ -301,6 +403,8 @@ impl Loader for VisionLoader {
                 self.config.topology.as_ref(),
                 silent,
                 IsqOrganization::Default,
+                &self.config.isq_exclude,
+                &self.config.isq_layer_types,
                 self.config.write_uqff.as_ref(),
                 UqffFullSer {
                     tokenizer: &tokenizer,
@@ -320,6 +424,10 @@ impl Loader for VisionLoader {
             )?;
         }
 
+        let kv_cache_dtype = self
+            .config
+            .kv_cache_dtype
+            .for_pipeline(paged_attn_config.is_some());
         let (cache_config, cache_engine) = if let Some(paged_attn_config) = paged_attn_config {
             anyhow::ensure!(
                 !matches!(self.kind, ModelKind::Adapter { .. }),
@@ -329,11 +437,16 @@ impl Loader for VisionLoader {
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
-                dtype,
+                kv_cache_dtype.paged_cache_dtype(dtype),
+                model.config(),
+                device,
+            )?;
+            let cache_engine = CacheEngine::new(
                 model.config(),
+                &cache_config,
+                kv_cache_dtype.paged_cache_dtype(dtype),
                 device,
             )?;
-            let cache_engine = CacheEngine::new(model.config(), &cache_config, dtype, device)?;
             (Some(cache_config), Some(cache_engine))
         } else {
             (None, None)
@@ -342,6 +455,7 @@ impl Loader for VisionLoader {
         let max_seq_len = model.max_seq_len();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = model.cache().lock().len();
+        model.cache().set_kv_cache_dtype(kv_cache_dtype);
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
         let sliding_window = model.config().sliding_window;
         Ok(Arc::new(Mutex::new(VisionPipeline {
@@ -352,21 +466,29 @@ impl Loader for VisionLoader {
             metadata: Arc::new(GeneralMetadata {
                 max_seq_len,
                 tok_trie: Some(tok_trie),
-                is_xlora: false,
+                is_xlora: self.kind.is_adapted_and(|a| a.is_x_lora()),
                 num_hidden_layers,
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 has_no_kv_cache: false,
                 activation_dtype: dtype,
                 sliding_window,
+                kv_cache_dtype,
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                speculative_accepted_tokens: AtomicUsize::new(0),
+                speculative_drafted_tokens: AtomicUsize::new(0),
+                speculative_draft_calls: AtomicUsize::new(0),
+                per_request_adapter_override: false,
+                pending_adapter_swaps: Default::default(),
             }),
             processor,
             preprocessor_config: Arc::new(preprocessor_config),
             topology: self.config.topology.clone(),
             silent,
+            isq_exclude: self.config.isq_exclude.clone(),
+            isq_layer_types: self.config.isq_layer_types.clone(),
             template_filename: paths.get_template_filename().clone(),
             generation_config: paths.get_gen_conf_filename().cloned(),
             config,
@@ -406,6 +528,8 @@ impl IsqPipelineMixin for VisionPipeline {
                 self.topology.as_ref(),
                 self.silent,
                 IsqOrganization::Default,
+                &self.isq_exclude,
+                &self.isq_layer_types,
                 None,
                 UqffFullSer {
                     tokenizer: &self.tokenizer,
@@ -418,6 +542,12 @@ impl IsqPipelineMixin for VisionPipeline {
             )
             .map_err(anyhow::Error::msg)
     }
+
+    fn get_layer_weights(&mut self, name: &str) -> Result<Tensor> {
+        self.model
+            .get_layer_weights(name)
+            .map_err(anyhow::Error::msg)
+    }
 }
 
 impl CacheManagerMixin for VisionPipeline {
@@ -439,7 +569,7 @@ impl CacheManagerMixin for VisionPipeline {
 }
 
 impl AdapterActivationMixin for VisionPipeline {
-    fn activate_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+    fn activate_weighted_adapters(&mut self, _adapters: Vec<(String, f64)>) -> Result<usize> {
         anyhow::bail!("Vision models do not support adapter activation.");
     }
 }
@@ -516,6 +646,9 @@ impl Pipeline for VisionPipeline {
         let has_conv2d = self.model.has_conv2d();
         ModelCategory::Vision { has_conv2d }
     }
+    fn supports_beam_search(&self) -> bool {
+        true
+    }
 }
 
 impl AnyMoePipelineMixin for VisionPipeline {
@@ -525,6 +658,9 @@ impl AnyMoePipelineMixin for VisionPipeline {
     fn amoe_layer_vars(&self) -> Vec<Vec<Var>> {
         self.model.get_vars()
     }
+    fn amoe_var_map(&self) -> VarMap {
+        self.model.get_var_map()
+    }
     fn amoe_base_model_trainable_params(&self) -> usize {
         self.model.trainable_params()
     }
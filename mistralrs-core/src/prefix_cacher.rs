@@ -1,7 +1,13 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
+use anyhow::Context;
 use candle_core::{Device, Result, Tensor};
 use radix_trie::{Trie, TrieCommon, TrieKey};
+use safetensors::SafeTensors;
 
 use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
 
@@ -23,7 +29,31 @@ impl From<Vec<u32>> for Tokens {
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+const PREFIX_CACHE_MODEL_ID: &str = "model_id";
+const PREFIX_CACHE_NUM_LAYERS: &str = "num_layers";
+const PREFIX_CACHE_IS_XLORA: &str = "is_xlora";
+const PREFIX_CACHE_SEQS: &str = "seqs";
+
+/// How to choose the cached sequence to drop when the prefix cache exceeds its capacity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// Drop the least recently used sequence.
+    Lru,
+    /// Drop the least frequently used sequence, the least recently used one on ties.
+    Lfu,
+    /// Drop the sequence with the lowest `recency_weight * recency + (1 - recency_weight) *
+    /// frequency`, both normalized to `[0, 1]` over the cached sequences.
+    Scored { recency_weight: f64 },
+}
+
+struct EvictionCacheGroup {
+    toks: Vec<u32>,
+    cache: Arc<Mutex<LayerCaches>>,
+    xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
+    size_in_bytes: usize,
+    last_access: u64,
+    n_accesses: u64,
+}
 
 pub struct PrefixCacheManager {
     caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
@@ -32,6 +62,9 @@ pub struct PrefixCacheManager {
     pub n_on_device: usize,
     no_prefix_cache: bool,
     eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    capacity_bytes: usize,
+    policy: EvictionPolicy,
+    clock: u64,
 }
 
 #[derive(Clone)]
@@ -43,6 +76,27 @@ pub struct MatchingCache {
 
 impl PrefixCacheManager {
     pub fn new(device: Device, n_on_device: usize, is_xlora: bool, no_prefix_cache: bool) -> Self {
+        Self::new_with_eviction(
+            device,
+            n_on_device,
+            is_xlora,
+            no_prefix_cache,
+            usize::MAX,
+            EvictionPolicy::Lru,
+        )
+    }
+
+    /// Limit the total size of the cached KV tensors, on any device, to `capacity_bytes`. When a
+    /// new sequence exceeds it, cached sequences are dropped according to `policy`. Sequences
+    /// larger than the capacity are not cached.
+    pub fn new_with_eviction(
+        device: Device,
+        n_on_device: usize,
+        is_xlora: bool,
+        no_prefix_cache: bool,
+        capacity_bytes: usize,
+        policy: EvictionPolicy,
+    ) -> Self {
         PrefixCacheManager {
             caches: Trie::new(),
             xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
@@ -50,6 +104,9 @@ impl PrefixCacheManager {
             n_on_device,
             no_prefix_cache,
             eviction_cache_ptrs: Vec::new(),
+            capacity_bytes,
+            policy,
+            clock: 0,
         }
     }
 
@@ -59,21 +116,99 @@ impl PrefixCacheManager {
         if self.no_prefix_cache {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
+        let xlora_cache = seq.is_xlora().then(|| seq.xlora_cache().clone());
+        self.insert(seq.get_toks().to_vec(), seq.cache().clone(), xlora_cache);
+    }
+
+    fn cache_size_in_bytes(cache: &LayerCaches) -> usize {
+        cache
+            .iter()
+            .flatten()
+            .map(|(k, v)| {
+                k.elem_count() * k.dtype().size_in_bytes()
+                    + v.elem_count() * v.dtype().size_in_bytes()
+            })
+            .sum()
+    }
+
+    /// Total size of the cached KV tensors.
+    pub fn size_in_bytes(&self) -> usize {
+        self.eviction_cache_ptrs
+            .iter()
+            .map(|entry| entry.size_in_bytes)
+            .sum()
+    }
+
+    fn insert(&mut self, toks: Vec<u32>, cache: LayerCaches, xlora_cache: Option<LayerCaches>) {
+        let size_in_bytes = Self::cache_size_in_bytes(&cache)
+            + xlora_cache.as_ref().map_or(0, Self::cache_size_in_bytes);
+        if let Some(idx) = self.eviction_cache_ptrs.iter().position(|e| e.toks == toks) {
+            self.remove(idx);
+        }
+        if size_in_bytes > self.capacity_bytes {
+            return;
+        }
+
+        let cache = Arc::new(Mutex::new(cache));
+        self.caches.insert(toks.clone().into(), cache.clone());
+        let xlora_cache = xlora_cache.map(|xlora_cache| {
+            let xlora_cache = Arc::new(Mutex::new(xlora_cache));
             self.xlora_caches
                 .as_mut()
-                .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
-        } else {
-            self.eviction_cache_ptrs.push((cache, None));
+                .expect("X-LoRA prefix cache is not enabled.")
+                .insert(toks.clone().into(), xlora_cache.clone());
+            xlora_cache
+        });
+        self.clock += 1;
+        self.eviction_cache_ptrs.push(EvictionCacheGroup {
+            toks,
+            cache,
+            xlora_cache,
+            size_in_bytes,
+            last_access: self.clock,
+            n_accesses: 1,
+        });
+
+        while self.size_in_bytes() > self.capacity_bytes {
+            let idx = self.eviction_candidate();
+            self.remove(idx);
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let entry = self.eviction_cache_ptrs.remove(idx);
+        let toks = Tokens(entry.toks);
+        self.caches.remove(&toks);
+        if let Some(ref mut xlora_caches) = self.xlora_caches {
+            xlora_caches.remove(&toks);
         }
     }
 
+    /// Index of the cached sequence to drop first under the eviction policy.
+    fn eviction_candidate(&self) -> usize {
+        let entries = self.eviction_cache_ptrs.iter().enumerate();
+        let candidate = match self.policy {
+            EvictionPolicy::Lru => entries.min_by_key(|(_, e)| e.last_access),
+            EvictionPolicy::Lfu => entries.min_by_key(|(_, e)| (e.n_accesses, e.last_access)),
+            EvictionPolicy::Scored { recency_weight } => {
+                let max_accesses = self
+                    .eviction_cache_ptrs
+                    .iter()
+                    .map(|e| e.n_accesses)
+                    .max()
+                    .unwrap_or(1);
+                #[allow(clippy::cast_precision_loss)]
+                let score = |e: &EvictionCacheGroup| {
+                    let recency = e.last_access as f64 / self.clock as f64;
+                    let frequency = e.n_accesses as f64 / max_accesses as f64;
+                    recency_weight * recency + (1. - recency_weight) * frequency
+                };
+                entries.min_by(|(_, a), (_, b)| score(a).total_cmp(&score(b)))
+            }
+        };
+        candidate.map(|(idx, _)| idx).expect("No cached sequence.")
+    }
+
     fn cache_to<'a>(
         cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
         device: &Device,
@@ -93,7 +228,7 @@ impl PrefixCacheManager {
             return Ok(0);
         }
         let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
+        for EvictionCacheGroup { cache, .. } in &self.eviction_cache_ptrs {
             if !matches!(
                 get_mut_arcmutex!(cache.as_ref())[0]
                     .as_ref()
@@ -107,7 +242,10 @@ impl PrefixCacheManager {
         }
         let mut n_evicted = 0;
         // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
+        for EvictionCacheGroup {
+            cache, xlora_cache, ..
+        } in &self.eviction_cache_ptrs
+        {
             if n_on_device - n_evicted == self.n_on_device {
                 break;
             }
@@ -138,7 +276,10 @@ impl PrefixCacheManager {
             return Ok(0);
         }
         // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
+        for EvictionCacheGroup {
+            cache, xlora_cache, ..
+        } in &self.eviction_cache_ptrs
+        {
             if !matches!(
                 get_mut_arcmutex!(cache.as_ref())[0]
                     .as_ref()
@@ -159,6 +300,131 @@ impl PrefixCacheManager {
         Ok(self.caches.len())
     }
 
+    fn cache_tensors(
+        prefix: &str,
+        cache: &LayerCaches,
+        out: &mut Vec<(String, Tensor)>,
+    ) -> Result<()> {
+        for (layer, kv) in cache.iter().enumerate() {
+            if let Some((k, v)) = kv {
+                out.push((format!("{prefix}.{layer}.k"), k.to_device(&Device::Cpu)?));
+                out.push((format!("{prefix}.{layer}.v"), v.to_device(&Device::Cpu)?));
+            }
+        }
+        Ok(())
+    }
+
+    fn cache_from_tensors(
+        prefix: &str,
+        num_layers: usize,
+        tensors: &mut HashMap<String, Tensor>,
+    ) -> anyhow::Result<LayerCaches> {
+        let mut cache = Vec::with_capacity(num_layers);
+        for layer in 0..num_layers {
+            let k = tensors.remove(&format!("{prefix}.{layer}.k"));
+            let v = tensors.remove(&format!("{prefix}.{layer}.v"));
+            match (k, v) {
+                (Some(k), Some(v)) => cache.push(Some((k, v))),
+                (None, None) => cache.push(None),
+                _ => anyhow::bail!(
+                    "Prefix cache `{prefix}` has a K or V tensor missing for layer {layer}."
+                ),
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Serialize the cached sequences and their KV caches to a safetensors file. The model ID and
+    /// number of layers are recorded so that the file is not loaded for a different model.
+    pub fn save_to_file(&self, path: &Path, model_id: &str) -> anyhow::Result<()> {
+        let mut seqs = Vec::new();
+        let mut tensors = Vec::new();
+        let mut num_layers = 0;
+        for (i, (toks, cache)) in self.caches.iter().enumerate() {
+            let cache = get_mut_arcmutex!(cache.as_ref());
+            num_layers = cache.len();
+            Self::cache_tensors(&i.to_string(), &cache, &mut tensors)?;
+            if let Some(ref xlora_caches) = self.xlora_caches {
+                let xlora_cache = xlora_caches
+                    .get(toks)
+                    .context("X-LoRA prefix cache is missing a sequence.")?;
+                Self::cache_tensors(
+                    &format!("xlora.{i}"),
+                    &get_mut_arcmutex!(xlora_cache.as_ref()),
+                    &mut tensors,
+                )?;
+            }
+            seqs.push(toks.0.clone());
+        }
+
+        let metadata = HashMap::from([
+            (PREFIX_CACHE_MODEL_ID.to_string(), model_id.to_string()),
+            (PREFIX_CACHE_NUM_LAYERS.to_string(), num_layers.to_string()),
+            (
+                PREFIX_CACHE_IS_XLORA.to_string(),
+                self.xlora_caches.is_some().to_string(),
+            ),
+            (PREFIX_CACHE_SEQS.to_string(), serde_json::to_string(&seqs)?),
+        ]);
+        safetensors::serialize_to_file(tensors, &Some(metadata), path)?;
+        Ok(())
+    }
+
+    /// Load a prefix cache written by [`PrefixCacheManager::save_to_file`]. The caches are loaded
+    /// to the CPU and moved to `device` when they are matched. Errors if the file was written for a
+    /// different model ID or number of layers.
+    pub fn load_from_file(
+        path: &Path,
+        device: Device,
+        n_on_device: usize,
+        model_id: &str,
+        num_hidden_layers: usize,
+    ) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        let (_, header) = SafeTensors::read_metadata(&data)?;
+        let metadata = header
+            .metadata()
+            .as_ref()
+            .context("Prefix cache file has no metadata.")?;
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .with_context(|| format!("Prefix cache metadata is missing `{key}`."))
+        };
+
+        let saved_model_id = get(PREFIX_CACHE_MODEL_ID)?;
+        if saved_model_id != model_id {
+            anyhow::bail!(
+                "Prefix cache was saved for model `{saved_model_id}`, but the model is `{model_id}`."
+            );
+        }
+        let num_layers: usize = get(PREFIX_CACHE_NUM_LAYERS)?.parse()?;
+        let seqs: Vec<Vec<u32>> = serde_json::from_str(get(PREFIX_CACHE_SEQS)?)?;
+        if !seqs.is_empty() && num_layers != num_hidden_layers {
+            anyhow::bail!(
+                "Prefix cache was saved for a model with {num_layers} layers, but the model has {num_hidden_layers}."
+            );
+        }
+        let is_xlora: bool = get(PREFIX_CACHE_IS_XLORA)?.parse()?;
+
+        let mut tensors = candle_core::safetensors::load_buffer(&data, &Device::Cpu)?;
+        let mut this = Self::new(device, n_on_device, is_xlora, false);
+        for (i, toks) in seqs.into_iter().enumerate() {
+            let cache = Self::cache_from_tensors(&i.to_string(), num_layers, &mut tensors)?;
+            let xlora_cache = if is_xlora {
+                Some(Self::cache_from_tensors(
+                    &format!("xlora.{i}"),
+                    num_layers,
+                    &mut tensors,
+                )?)
+            } else {
+                None
+            };
+            this.insert(toks, cache, xlora_cache);
+        }
+        Ok(this)
+    }
+
     /// Search for a matching cache given some toks
     pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
         if self.no_prefix_cache || toks.is_empty() {
@@ -167,6 +433,15 @@ impl PrefixCacheManager {
 
         let toks = Tokens(toks.to_vec());
         if let Some(cache) = self.caches.get(&toks) {
+            if let Some(entry) = self
+                .eviction_cache_ptrs
+                .iter_mut()
+                .find(|entry| entry.toks == toks.0)
+            {
+                self.clock += 1;
+                entry.last_access = self.clock;
+                entry.n_accesses += 1;
+            }
             Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
             let cache = get_mut_arcmutex!(cache.as_ref()).clone();
             let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
@@ -194,3 +469,147 @@ impl PrefixCacheManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{EvictionPolicy, PrefixCacheManager, Tokens};
+
+    /// A single layer KV cache of `len` tokens, taking `64 * len` bytes.
+    fn kv_cache(len: usize) -> candle_core::Result<Vec<Option<(Tensor, Tensor)>>> {
+        let k = Tensor::zeros((1, 1, len, 8), DType::F32, &Device::Cpu)?;
+        Ok(vec![Some((k.clone(), k))])
+    }
+
+    #[test]
+    fn test_prefix_cache_capacity() -> candle_core::Result<()> {
+        const CAPACITY: usize = 16 * 1024;
+        for policy in [
+            EvictionPolicy::Lru,
+            EvictionPolicy::Lfu,
+            EvictionPolicy::Scored {
+                recency_weight: 0.5,
+            },
+        ] {
+            let mut cacher = PrefixCacheManager::new_with_eviction(
+                Device::Cpu,
+                16,
+                false,
+                false,
+                CAPACITY,
+                policy,
+            );
+            let mut state = 7u32;
+            for i in 0..100u32 {
+                // Sizes between 64 bytes and the whole capacity
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let len = 1 + (state >> 16) as usize % 256;
+                let toks = vec![i; len];
+                cacher.insert(toks.clone(), kv_cache(len)?, None);
+                if i % 3 == 0 {
+                    cacher.search_for_matching_cache(&toks)?;
+                }
+
+                assert!(cacher.size_in_bytes() <= CAPACITY, "{policy:?}");
+                assert_eq!(cacher.caches.len(), cacher.eviction_cache_ptrs.len());
+            }
+            assert!(cacher.size_in_bytes() > 0);
+        }
+
+        // A sequence larger than the capacity is not cached
+        let mut cacher = PrefixCacheManager::new_with_eviction(
+            Device::Cpu,
+            16,
+            false,
+            false,
+            64,
+            EvictionPolicy::Lru,
+        );
+        cacher.insert(vec![1, 2], kv_cache(2)?, None);
+        assert_eq!(cacher.size_in_bytes(), 0);
+        assert!(cacher.search_for_matching_cache(&[1, 2])?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_cache_eviction_policy() -> candle_core::Result<()> {
+        let evicted = |policy| -> candle_core::Result<Vec<u32>> {
+            // Room for two sequences
+            let mut cacher = PrefixCacheManager::new_with_eviction(
+                Device::Cpu,
+                16,
+                false,
+                false,
+                2 * 64,
+                policy,
+            );
+            cacher.insert(vec![1], kv_cache(1)?, None);
+            cacher.insert(vec![2], kv_cache(1)?, None);
+            cacher.search_for_matching_cache(&[2])?;
+            cacher.search_for_matching_cache(&[2])?;
+            cacher.search_for_matching_cache(&[1])?;
+            cacher.insert(vec![3], kv_cache(1)?, None);
+            Ok([1, 2, 3]
+                .into_iter()
+                .filter(|t| cacher.caches.get(&Tokens(vec![*t])).is_none())
+                .collect())
+        };
+        // 1 was used after 2
+        assert_eq!(evicted(EvictionPolicy::Lru)?, [2]);
+        // 3 has only been used once
+        assert_eq!(evicted(EvictionPolicy::Lfu)?, [3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_cache_save_load() -> anyhow::Result<()> {
+        let dev = Device::Cpu;
+        let toks = vec![1u32, 15043, 29892, 920];
+        let k = Tensor::rand(0f32, 1f32, (1, 2, toks.len(), 8), &dev)?;
+        let v = Tensor::rand(0f32, 1f32, (1, 2, toks.len(), 8), &dev)?.to_dtype(DType::BF16)?;
+
+        // A cross-attention layer without a KV cache is kept as `None`.
+        let mut cacher = PrefixCacheManager::new(dev.clone(), 16, false, false);
+        let cache = vec![Some((k.clone(), v.clone())), None];
+        cacher.caches.insert(
+            Tokens(toks.clone()),
+            std::sync::Arc::new(std::sync::Mutex::new(cache)),
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "mistralrs-prefix-cache-{}.safetensors",
+            std::process::id()
+        ));
+        cacher.save_to_file(&path, "test-model")?;
+
+        assert!(
+            PrefixCacheManager::load_from_file(&path, dev.clone(), 16, "other-model", 2).is_err()
+        );
+        assert!(
+            PrefixCacheManager::load_from_file(&path, dev.clone(), 16, "test-model", 3).is_err()
+        );
+
+        let mut loaded = PrefixCacheManager::load_from_file(&path, dev, 16, "test-model", 2)?;
+        std::fs::remove_file(&path)?;
+
+        let hit = loaded
+            .search_for_matching_cache(&toks)?
+            .expect("Expected a prefix cache hit.");
+        assert!(hit.normal[1].is_none());
+        let (hit_k, hit_v) = hit.normal[0].as_ref().unwrap();
+        assert_eq!(
+            hit_k.flatten_all()?.to_vec1::<f32>()?,
+            k.flatten_all()?.to_vec1::<f32>()?
+        );
+        assert_eq!(hit_v.dtype(), DType::BF16);
+        assert_eq!(
+            hit_v
+                .to_dtype(DType::F32)?
+                .flatten_all()?
+                .to_vec1::<f32>()?,
+            v.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?
+        );
+        Ok(())
+    }
+}
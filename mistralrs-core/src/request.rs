@@ -13,10 +13,18 @@ use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc, a GBNF grammar, or a JSON schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    Gbnf(String),
+    /// Only generate JSON documents which are valid under this JSON schema. The supported keywords
+    /// are `type`, `properties`, `required`, `additionalProperties`, `items`, `minItems`,
+    /// `maxItems` and `enum`.
+    JsonSchema(serde_json::Value),
+    /// Do not constrain the tokens, but stop generating once the completion matches this regex,
+    /// for example `\}\s*$` to stop when a JSON object closes. The match is part of the output.
+    StopRegex(String),
     None,
 }
 
@@ -82,6 +90,12 @@ pub struct NormalRequest {
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
+    /// If the request has not finished this many milliseconds after the engine received it, its
+    /// sequences are removed and a [`Response::ValidationError`] is sent.
+    pub timeout_ms: Option<u64>,
+    /// Include the token ids the prompt was tokenized into in the response, for debugging
+    /// tokenization.
+    pub return_prompt_tokens: bool,
 }
 
 impl NormalRequest {
@@ -106,6 +120,8 @@ impl NormalRequest {
             suffix: None,
             adapters: None,
             logits_processors: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
         }
     }
 }
@@ -117,6 +133,11 @@ pub enum Request {
     Normal(NormalRequest),
     ReIsq(IsqType),
     ActivateAdapters(Vec<String>),
+    /// Activate adapters with a weight each, blending their deltas as the weighted sum.
+    ActivateWeightedAdapters(Vec<(String, f64)>),
+    /// Cancel the [`NormalRequest`] with this id. Its sequences are removed before the next step
+    /// and a [`Response::ValidationError`] is sent.
+    Cancel(usize),
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -141,9 +162,13 @@ impl Debug for Request {
             Request::ActivateAdapters(adapters) => {
                 write!(f, "Activate Adapters Request {adapters:?}",)
             }
+            Request::ActivateWeightedAdapters(adapters) => {
+                write!(f, "Activate Weighted Adapters Request {adapters:?}",)
+            }
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::Cancel(id) => write!(f, "Cancel Request {id}"),
             Request::Terminate => write!(f, "Termination Request"),
         }
     }
@@ -122,6 +122,12 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Fraction of the drafted tokens of this request accepted by the target model, if using
+    /// speculative decoding.
+    pub speculative_acceptance_rate: Option<f32>,
+    /// Average number of drafted tokens of this request accepted per speculative step, if using
+    /// speculative decoding.
+    pub speculative_tokens_per_draft_call: Option<f32>,
 }
 
 generate_repr!(Usage);
@@ -138,6 +144,9 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// The token ids the prompt was tokenized into, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<u32>>,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -165,7 +174,7 @@ pub struct CompletionChoice {
     pub finish_reason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    pub logprobs: Option<Logprobs>,
 }
 
 generate_repr!(CompletionChoice);
@@ -182,6 +191,9 @@ pub struct CompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// The token ids the prompt was tokenized into, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_token_ids: Option<Vec<u32>>,
 }
 
 generate_repr!(CompletionResponse);
@@ -221,6 +233,19 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Sent after each denoising step of a streaming image generation request.
+pub struct ImageGenerationProgress {
+    pub created: u128,
+    /// 1-based index of the step which just finished.
+    pub step: usize,
+    pub total_steps: usize,
+}
+
+generate_repr!(ImageGenerationProgress);
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
@@ -238,6 +263,7 @@ pub enum Response {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    ImageGenerationProgress(ImageGenerationProgress),
 }
 
 #[derive(Debug, Clone)]
@@ -250,6 +276,7 @@ pub enum ResponseOk {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    ImageGenerationProgress(ImageGenerationProgress),
 }
 
 pub enum ResponseErr {
@@ -312,6 +339,7 @@ impl Response {
                 Err(Box::new(ResponseErr::CompletionModelError(e, x)))
             }
             Self::ImageGeneration(x) => Ok(ResponseOk::ImageGeneration(x)),
+            Self::ImageGenerationProgress(x) => Ok(ResponseOk::ImageGenerationProgress(x)),
         }
     }
 }
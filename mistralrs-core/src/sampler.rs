@@ -11,12 +11,16 @@ use candle_core::{Device, Error, Result, Tensor, D};
 use pyo3::pyclass;
 
 use once_cell::sync::Lazy;
-use rand::distributions::{Distribution, WeightedIndex};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng,
+};
 use rand_isaac::Isaac64Rng;
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+use crate::aici::toktree::TokTrie;
+
 static DRY_SEQUENCE_BREAKERS: Lazy<Vec<String>> =
     Lazy::new(|| ["\n", ":", "\"", "*"].map(String::from).to_vec());
 
@@ -30,39 +34,96 @@ pub enum StopTokens {
 #[derive(Clone, Debug)]
 /// Sampling params are used to control sampling.
 pub struct SamplingParams {
+    /// A temperature below `1e-7`, including `0.0`, selects greedy (argmax) sampling.
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub min_p: Option<f64>,
+    pub xtc_probability: Option<f64>,
+    pub xtc_threshold: Option<f64>,
+    pub typical_p: Option<f64>,
+    /// Epsilon sampling: tokens with a probability below `epsilon_cutoff` are not sampled.
+    pub epsilon_cutoff: Option<f32>,
+    /// Eta sampling: tokens with a probability below `min(eta, sqrt(eta) * exp(-entropy))` are not
+    /// sampled, where `entropy` is the entropy of the distribution.
+    pub eta_cutoff: Option<f32>,
     pub top_n_logprobs: usize,
+    /// Return the top `top_n_logprobs` logprobs of every generated position with the final
+    /// response, parallel to the tokens. Off by default as it computes them at every step.
+    pub collect_all_logprobs: bool,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    pub no_repeat_ngram_size: Option<usize>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
+    pub min_new_tokens: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
+    /// Logit bias keyed by text. Each text is tokenized and the bias is added to each of its
+    /// tokens, on top of `logits_bias`.
+    pub string_logits_bias: Option<HashMap<String, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    pub mirostat: Option<MirostatConfig>,
+    pub beam_search: Option<BeamSearchConfig>,
+    pub contrastive_search: Option<ContrastiveSearchConfig>,
+    /// Classifier-free guidance scale. The logits are `uncond + cfg_scale * (cond - uncond)` where
+    /// `uncond` are the logits given the negative prompt: `1.0` is unguided sampling and larger
+    /// values steer the generation away from the negative prompt.
+    pub cfg_scale: Option<f64>,
+    pub negative_prompt: Option<String>,
+    /// Seed of the RNG used to sample this request. Two requests with the same seed and inputs
+    /// generate the same tokens. Choice `i` of the request is seeded with `seed + i`. If `None`,
+    /// the engine's RNG is used.
+    pub seed: Option<u64>,
+    /// Token IDs which are never sampled, whatever their probability. Each ID must be smaller than
+    /// the vocab size of the model.
+    pub bad_token_ids: Option<Vec<u32>>,
+    /// Heal the end of the prompt: remove its last tokens if other tokens start with their text,
+    /// and make the first generated token start with it. The removed text is not part of the
+    /// completion.
+    pub token_healing: bool,
 }
 
 impl SamplingParams {
     /// This sets up the parameters so that there is:
-    /// - No temperature, topk, topp, minp
+    /// - No temperature, topk, topp, minp, typical p, epsilon or eta cutoff
     /// - No penalties, stop tokens, or logit bias
     /// - No maximum length
+    /// - No classifier-free guidance
+    /// - No seed
+    /// - No banned tokens
+    /// - No token healing
     pub fn deterministic() -> Self {
         Self {
             temperature: None,
             top_k: None,
             top_p: None,
             min_p: None,
+            xtc_probability: None,
+            xtc_threshold: None,
+            typical_p: None,
+            epsilon_cutoff: None,
+            eta_cutoff: None,
             top_n_logprobs: 0,
+            collect_all_logprobs: false,
             frequency_penalty: None,
             presence_penalty: None,
+            no_repeat_ngram_size: None,
             stop_toks: None,
             max_len: None,
+            min_new_tokens: None,
             logits_bias: None,
+            string_logits_bias: None,
             n_choices: 1,
             dry_params: None,
+            mirostat: None,
+            beam_search: None,
+            contrastive_search: None,
+            cfg_scale: None,
+            negative_prompt: None,
+            seed: None,
+            bad_token_ids: None,
+            token_healing: false,
         }
     }
 }
@@ -128,14 +189,7 @@ impl DrySamplingParamsInner {
                             //        and ambiguous encodings.
                             .encode(["a", &breaker].concat(), true)
                             .map_err(anyhow::Error::msg)
-                            .map(|enc| {
-                                let ids = enc.get_ids();
-                                if !ids.is_empty() {
-                                    None
-                                } else {
-                                    Some(ids[ids.len() - 1])
-                                }
-                            })
+                            .map(|enc| enc.get_ids().last().copied())
                     })
                     .collect::<anyhow::Result<Vec<_>>>()?
                     .into_iter()
@@ -147,6 +201,240 @@ impl DrySamplingParamsInner {
     }
 }
 
+#[derive(Clone, Debug)]
+/// Mirostat sampling, which adapts the candidate set to keep the surprise (`-log2(p)`) of the
+/// sampled tokens close to `tau`. See <https://arxiv.org/abs/2007.14966>.
+/// - `version`: 1 or 2
+/// - `tau`: Target surprise
+/// - `eta`: Learning rate of `mu`
+/// - `mu`: Initial maximum surprise, updated after every sampled token
+pub struct MirostatConfig {
+    pub version: u8,
+    pub tau: f64,
+    pub eta: f64,
+    pub mu: f64,
+}
+
+impl MirostatConfig {
+    /// Initialize `mu` to `2 * tau`, as in the paper.
+    pub fn new(version: u8, tau: f64, eta: f64) -> Self {
+        Self {
+            version,
+            tau,
+            eta,
+            mu: 2. * tau,
+        }
+    }
+
+    /// Mirostat with a `tau` of 5 and an `eta` of 0.1 unless specified.
+    pub fn new_with_defaults(version: u8, tau: Option<f64>, eta: Option<f64>) -> Self {
+        Self::new(version, tau.unwrap_or(5.0), eta.unwrap_or(0.1))
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Beam search configuration.
+/// - `num_beams`: Number of hypotheses kept at every step
+/// - `length_penalty`: Exponent of the length normalization of finished hypotheses. Values `> 0.0`
+///   favor longer sequences.
+/// - `early_stopping`: Stop as soon as `num_beams` hypotheses are finished
+pub struct BeamSearchConfig {
+    pub num_beams: usize,
+    pub length_penalty: f64,
+    pub early_stopping: bool,
+}
+
+impl BeamSearchConfig {
+    /// Score used to rank finished hypotheses: `score / len^length_penalty`.
+    pub fn normalized_score(&self, score: f64, len: usize) -> f64 {
+        score / (len as f64).powf(self.length_penalty)
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Contrastive search configuration, see <https://arxiv.org/abs/2202.06417>. Each of the `top_k`
+/// most likely tokens is scored by `(1 - penalty_alpha) * prob - penalty_alpha * max_sim`, where
+/// `max_sim` is the largest cosine similarity between its hidden state and the hidden states of
+/// the context, and the best scoring token is selected.
+/// - `top_k`: Number of candidate tokens
+/// - `penalty_alpha`: Weight of the degeneration penalty, in `[0, 1]`. `0.0` is greedy decoding.
+pub struct ContrastiveSearchConfig {
+    pub top_k: usize,
+    pub penalty_alpha: f32,
+}
+
+impl ContrastiveSearchConfig {
+    /// Select the next token among `candidates`, the `(token, prob)` pairs of
+    /// [`Sampler::contrastive_search_candidates`]. `candidate_hidden_states` of shape
+    /// `(candidates.len(), hidden_size)` are the hidden states of the candidates, and
+    /// `context_hidden_states` of shape `(context_len, hidden_size)` those of the previous tokens.
+    pub fn select(
+        &self,
+        candidates: &[(u32, f32)],
+        candidate_hidden_states: &Tensor,
+        context_hidden_states: &Tensor,
+    ) -> Result<u32> {
+        if !(0.0..=1.0).contains(&self.penalty_alpha) {
+            candle_core::bail!(
+                "Contrastive search `penalty_alpha` must be in [0, 1], got {}.",
+                self.penalty_alpha
+            );
+        }
+        let (n_candidates, _) = candidate_hidden_states.dims2()?;
+        if n_candidates != candidates.len() {
+            candle_core::bail!(
+                "Expected the hidden states of {} candidates, got {n_candidates}.",
+                candidates.len()
+            );
+        }
+        let max_sims = if context_hidden_states.dim(0)? == 0 {
+            vec![0f32; n_candidates]
+        } else {
+            let normalize = |xs: &Tensor| -> Result<Tensor> {
+                let xs = xs.to_dtype(candle_core::DType::F32)?;
+                xs.broadcast_div(&xs.sqr()?.sum_keepdim(1)?.sqrt()?.clamp(1e-12, f32::MAX)?)
+            };
+            normalize(candidate_hidden_states)?
+                .matmul(&normalize(context_hidden_states)?.t()?)?
+                .max(1)?
+                .to_vec1()?
+        };
+
+        let alpha = self.penalty_alpha;
+        let mut best: Option<(u32, f32)> = None;
+        for (&(token, prob), max_sim) in candidates.iter().zip(max_sims) {
+            let score = (1. - alpha) * prob - alpha * max_sim;
+            // Ties keep the more likely candidate, which comes first.
+            if !best.is_some_and(|(_, best_score)| score <= best_score) {
+                best = Some((token, score));
+            }
+        }
+        best.map(|(token, _)| token)
+            .ok_or_else(|| Error::Msg("Contrastive search has no candidates.".to_string()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A continuation of a beam, produced by [`Sampler::sample_beam`].
+pub struct BeamCandidate {
+    /// Index of the beam being continued.
+    pub parent: usize,
+    pub token: u32,
+    /// Cumulative (natural) log probability of the beam including `token`.
+    pub score: f64,
+}
+
+#[derive(Clone, Debug)]
+/// Suppresses the EOS tokens until `min_new_tokens` tokens have been generated.
+/// - `min_new_tokens`: Minimum number of generated tokens before EOS may be sampled
+/// - `generated`: Number of tokens generated so far, excluding the prompt
+/// - `eos_toks`: Token ids which are masked while the minimum is not reached
+pub struct MinNewTokens {
+    pub min_new_tokens: usize,
+    pub generated: usize,
+    pub eos_toks: Vec<u32>,
+}
+
+impl MinNewTokens {
+    /// Set the logits of the EOS tokens to `-inf` if fewer than `min_new_tokens` were generated.
+    pub(crate) fn apply(&self, logits: &mut [f32]) {
+        if self.generated >= self.min_new_tokens {
+            return;
+        }
+        for tok in &self.eos_toks {
+            if let Some(logit) = logits.get_mut(*tok as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// Token healing of the end of a prompt. The last prompt tokens may be a split of text which the
+/// model would generate as one token, like the start of a word or the bytes of a multi-byte
+/// character. They are removed from the prompt, and the first generated token must start with
+/// their bytes.
+/// - `n_removed`: Number of tokens removed from the end of the prompt
+/// - `removed`: Bytes of the removed tokens, which are not part of the completion
+/// - `allowed_toks`: Token ids which start with `removed`
+pub(crate) struct TokenHealing {
+    pub n_removed: usize,
+    pub removed: Vec<u8>,
+    pub allowed_toks: Vec<u32>,
+}
+
+impl TokenHealing {
+    /// Remove the last token of the prompt, and the tokens before it while the removed bytes
+    /// start inside a UTF-8 character. Returns `None` if no other token starts with the removed
+    /// bytes, so the prompt cannot be healed, or if the whole prompt would be removed.
+    pub fn new(prompt: &[u32], tok_trie: &TokTrie) -> Option<Self> {
+        let mut removed = Vec::new();
+        let mut n_removed = 0;
+        for tok in prompt.iter().rev() {
+            // Special tokens have no bytes and are never healed.
+            if *tok as usize >= tok_trie.vocab_size() || tok_trie.token(*tok).is_empty() {
+                return None;
+            }
+            removed.splice(0..0, tok_trie.token(*tok).iter().copied());
+            n_removed += 1;
+            // Stop at the first byte of a UTF-8 character, which is not a continuation byte.
+            if removed[0] & 0b1100_0000 != 0b1000_0000 {
+                break;
+            }
+        }
+        if n_removed == prompt.len() {
+            return None;
+        }
+
+        let last = prompt[prompt.len() - 1];
+        let allowed_toks = (0..tok_trie.vocab_size() as u32)
+            .filter(|tok| tok_trie.token(*tok).starts_with(&removed))
+            .collect::<Vec<_>>();
+        if allowed_toks
+            .iter()
+            .all(|tok| n_removed == 1 && *tok == last)
+        {
+            return None;
+        }
+        Some(Self {
+            n_removed,
+            removed,
+            allowed_toks,
+        })
+    }
+
+    /// Set the logits of the tokens which do not start with the removed bytes to `-inf`.
+    pub fn apply(&self, logits: &mut [f32]) {
+        let mut healed = vec![f32::NEG_INFINITY; logits.len()];
+        for tok in &self.allowed_toks {
+            if let Some(logit) = logits.get(*tok as usize) {
+                healed[*tok as usize] = *logit;
+            }
+        }
+        logits.copy_from_slice(&healed);
+    }
+}
+
+#[derive(Debug)]
+struct MirostatInner {
+    version: u8,
+    tau: f64,
+    eta: f64,
+    mu: Mutex<f64>,
+}
+
+// Each sequence gets a clone of the sampler, so `mu` must be copied rather than shared.
+impl Clone for MirostatInner {
+    fn clone(&self) -> Self {
+        Self {
+            version: self.version,
+            tau: self.tau,
+            eta: self.eta,
+            mu: Mutex::new(*self.mu.lock().expect("could not lock mirostat mu")),
+        }
+    }
+}
+
 /// Customizable logits processor.
 ///
 /// # Example
@@ -177,6 +465,105 @@ impl<T: Fn(&Tensor, &[u32]) -> Result<Tensor> + Send + Sync> CustomLogitsProcess
     }
 }
 
+/// Divides the logits by `temperature`. This is applied before, and in addition to, the
+/// temperature of the sampling params.
+#[derive(Clone, Debug)]
+pub struct TemperatureLogitsProcessor {
+    pub temperature: f64,
+}
+
+impl CustomLogitsProcessor for TemperatureLogitsProcessor {
+    fn apply(&self, logits: &Tensor, _context: &[u32]) -> Result<Tensor> {
+        if self.temperature <= 0. {
+            candle_core::bail!("Temperature must be positive, got {}", self.temperature);
+        }
+        logits / self.temperature
+    }
+}
+
+/// Repetition penalty as in <https://arxiv.org/abs/1909.05858>: the logits of tokens in the
+/// context are divided by `penalty` if positive and multiplied by it otherwise.
+#[derive(Clone, Debug)]
+pub struct RepetitionPenaltyLogitsProcessor {
+    pub penalty: f32,
+}
+
+impl CustomLogitsProcessor for RepetitionPenaltyLogitsProcessor {
+    fn apply(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        let mut new_logits = logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+        let mut seen = HashSet::new();
+        for tok in context {
+            let tok = *tok as usize;
+            if tok >= new_logits.len() || !seen.insert(tok) {
+                continue;
+            }
+            let logit = &mut new_logits[tok];
+            if *logit >= 0. {
+                *logit /= self.penalty;
+            } else {
+                *logit *= self.penalty;
+            }
+        }
+        Tensor::from_vec(new_logits, logits.shape(), logits.device())?.to_dtype(logits.dtype())
+    }
+}
+
+/// Parameters of a [`Sampler`]. The defaults disable every sampling method, so only the fields
+/// which are used need to be set, with `..Default::default()` for the rest.
+#[derive(Clone)]
+pub struct SamplerConfig {
+    /// Greedy sampling if `None` or below `1e-7`.
+    pub temperature: Option<f64>,
+    pub top_n_logprobs: usize,
+    pub tokenizer: Option<Arc<Tokenizer>>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub dry_params: Option<DrySamplingParams>,
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Disabled if `-1`.
+    pub top_k: i64,
+    pub top_p: f64,
+    pub min_p: f64,
+    pub xtc_probability: f64,
+    pub xtc_threshold: f64,
+    pub typical_p: Option<f64>,
+    pub epsilon_cutoff: Option<f32>,
+    pub eta_cutoff: Option<f32>,
+    pub mirostat: Option<MirostatConfig>,
+    pub bad_token_ids: Option<Vec<u32>>,
+    pub logits_bias: Option<HashMap<u32, f32>>,
+    /// Biases keyed by text, applied to each token of the text. Requires `tokenizer`.
+    pub string_logits_bias: Option<HashMap<String, f32>>,
+    pub logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            temperature: None,
+            top_n_logprobs: 0,
+            tokenizer: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            dry_params: None,
+            no_repeat_ngram_size: None,
+            top_k: -1,
+            top_p: 1.0,
+            min_p: 0.0,
+            xtc_probability: 0.0,
+            xtc_threshold: 0.1,
+            typical_p: None,
+            epsilon_cutoff: None,
+            eta_cutoff: None,
+            mirostat: None,
+            bad_token_ids: None,
+            logits_bias: None,
+            string_logits_bias: None,
+            logits_processors: Vec::new(),
+        }
+    }
+}
+
 /// Sampler for sampling.
 #[derive(Clone)]
 pub struct Sampler {
@@ -186,9 +573,18 @@ pub struct Sampler {
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
     dry_params: Option<DrySamplingParamsInner>,
+    no_repeat_ngram_size: Option<usize>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    xtc_probability: f64,
+    xtc_threshold: f64,
+    typical_p: Option<f64>,
+    epsilon_cutoff: Option<f32>,
+    eta_cutoff: Option<f32>,
+    mirostat: Option<MirostatInner>,
+    bad_token_ids: Vec<u32>,
+    logits_bias: HashMap<u32, f32>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
 }
 
@@ -214,25 +610,63 @@ fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
 
+/// Entry `i` is the length of the longest common suffix of `context[..=i]` and `context`. This is
+/// the Z-function of the reversed context, which takes linear time.
+pub(crate) fn suffix_match_lengths(context: &[u32]) -> Vec<usize> {
+    let n = context.len();
+    let rev = |k: usize| context[n - 1 - k];
+    let mut z = vec![0; n];
+    if n == 0 {
+        return z;
+    }
+    z[0] = n;
+    // `rev[l..r]` is the rightmost match with a prefix of `rev` found so far.
+    let (mut l, mut r) = (0, 0);
+    for k in 1..n {
+        if k < r {
+            z[k] = z[k - l].min(r - k);
+        }
+        while k + z[k] < n && rev(z[k]) == rev(k + z[k]) {
+            z[k] += 1;
+        }
+        if k + z[k] > r {
+            l = k;
+            r = k + z[k];
+        }
+    }
+    z.reverse();
+    z
+}
+
 impl Sampler {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
-        temperature: Option<f64>,
-        top_n_logprobs: usize,
-        tokenizer: Option<Arc<Tokenizer>>,
-        frequency_penalty: Option<f32>,
-        presence_penalty: Option<f32>,
-        dry_params: Option<DrySamplingParams>,
-        top_k: i64,
-        top_p: f64,
-        min_p: f64,
-        logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
-    ) -> anyhow::Result<Self> {
-        let temperature = if temperature.map_or(true, |v| v < 1e-7) {
-            None
-        } else {
-            temperature
-        };
+    pub fn new(config: SamplerConfig) -> anyhow::Result<Self> {
+        let SamplerConfig {
+            temperature,
+            top_n_logprobs,
+            tokenizer,
+            frequency_penalty,
+            presence_penalty,
+            dry_params,
+            no_repeat_ngram_size,
+            top_k,
+            top_p,
+            min_p,
+            xtc_probability,
+            xtc_threshold,
+            typical_p,
+            epsilon_cutoff,
+            eta_cutoff,
+            mirostat,
+            bad_token_ids,
+            logits_bias,
+            string_logits_bias,
+            logits_processors,
+        } = config;
+        // Greedy sampling below this temperature, which also rules out NaN.
+        let temperature = temperature.filter(|v| *v >= 1e-7);
+        let typical_p = typical_p.filter(|p| *p >= 0.0 && *p < 1.0);
+        let epsilon_cutoff = epsilon_cutoff.filter(|e| *e > 0.0 && *e < 1.0);
+        let eta_cutoff = eta_cutoff.filter(|e| *e > 0.0 && *e < 1.0);
         let dry_params = if let Some(ref tokenizer) = tokenizer {
             dry_params.map(|params| DrySamplingParamsInner::from(params, tokenizer))
         } else {
@@ -242,6 +676,47 @@ impl Sampler {
             Some(fallible) => Some(fallible?),
             None => None,
         };
+        let mirostat = match mirostat {
+            Some(MirostatConfig { version, .. }) if !matches!(version, 1 | 2) => {
+                anyhow::bail!("Mirostat version must be 1 or 2, got {version}.")
+            }
+            Some(MirostatConfig {
+                version,
+                tau,
+                eta,
+                mu,
+            }) => Some(MirostatInner {
+                version,
+                tau,
+                eta,
+                mu: Mutex::new(mu),
+            }),
+            None => None,
+        };
+        let mut logits_bias = logits_bias.unwrap_or_default();
+        if let Some(string_logits_bias) = string_logits_bias {
+            let Some(ref tokenizer) = tokenizer else {
+                anyhow::bail!(
+                    "Logit bias keyed by text requires the pipeline to have a tokenizer."
+                );
+            };
+            for (text, bias) in string_logits_bias {
+                let ids = tokenizer
+                    .encode(text.as_str(), false)
+                    .map_err(anyhow::Error::msg)?
+                    .get_ids()
+                    .to_vec();
+                if ids.len() != 1 {
+                    tracing::warn!(
+                        "Logit bias text {text:?} is {} tokens, the bias is applied to each of them.",
+                        ids.len()
+                    );
+                }
+                for id in ids {
+                    *logits_bias.entry(id).or_insert(0.) += bias;
+                }
+            }
+        }
         Ok(Self {
             temperature,
             top_n_logprobs,
@@ -249,13 +724,75 @@ impl Sampler {
             frequency_penalty,
             presence_penalty,
             dry_params,
+            no_repeat_ngram_size,
             top_k,
             top_p,
             min_p,
+            xtc_probability,
+            xtc_threshold,
+            typical_p,
+            epsilon_cutoff,
+            eta_cutoff,
+            mirostat,
+            bad_token_ids: bad_token_ids.unwrap_or_default(),
+            logits_bias,
             logits_processors,
         })
     }
 
+    /// Zero the probabilities below the epsilon and eta cutoffs. `probs` must sum to 1. If both are
+    /// set, the larger threshold is used. The most likely token is always kept, so the cutoffs never
+    /// remove every token.
+    fn apply_cutoffs(&self, probs: &mut [f32]) {
+        let mut threshold = self.epsilon_cutoff.unwrap_or(0.0);
+        if let Some(eta) = self.eta_cutoff {
+            let entropy: f32 = probs
+                .iter()
+                .filter(|p| **p > 0.0)
+                .map(|p| -p * p.ln())
+                .sum();
+            threshold = threshold.max(eta.min(eta.sqrt() * (-entropy).exp()));
+        }
+        if threshold <= 0.0 {
+            return;
+        }
+        let Some(most_likely) = probs
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+        else {
+            return;
+        };
+        for (i, p) in probs.iter_mut().enumerate() {
+            if i != most_likely && *p < threshold {
+                *p = 0.0;
+            }
+        }
+    }
+
+    /// XTC (exclude top choices) sampling removes all tokens whose prob is at least
+    /// `xtc_threshold`, except the least likely of them, with probability `xtc_probability`. This
+    /// steers away from the most predictable choices while keeping a viable one. `argsort_indices`
+    /// must sort the tokens by descending probability, and tokens already filtered out are ignored.
+    fn apply_xtc(&self, probs: &mut [f32], argsort_indices: &[usize], rng: &Mutex<Isaac64Rng>) {
+        if self.xtc_probability <= 0.0 {
+            return;
+        }
+        let above = argsort_indices
+            .iter()
+            .filter(|index| probs[**index] > 0.0 && probs[**index] as f64 >= self.xtc_threshold)
+            .collect::<Vec<_>>();
+        if above.len() >= 2 {
+            let roll: f64 = rng.lock().expect("could not lock rng mutex").gen();
+            if roll < self.xtc_probability {
+                for index in &above[..above.len() - 1] {
+                    probs[**index] = 0.0;
+                }
+            }
+        }
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -265,18 +802,14 @@ impl Sampler {
         // Sort by descending prob
         argsort_indices_sorted
             .sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).expect("No ordering."));
-        // These are where the top n are
-        let top_n_toks_range = 0..self.top_n_logprobs;
+        // The top n tokens, at most the whole vocab
+        let top_n_toks = &argsort_indices_sorted[..self.top_n_logprobs.min(probs.len())];
         // The top n's values
-        let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
+        let top_n_logprobs = top_n_toks
             .iter()
-            .map(|x| probs[*x].log(10.0))
+            .map(|x| probs[*x].ln())
             .collect::<Vec<_>>();
-        // Find where they actually are in the logits
-        let mut top_n_toks = Vec::new();
-        for val in top_n_toks_range {
-            top_n_toks.push(argsort_indices[val]);
-        }
+        let top_n_toks = top_n_toks.to_vec();
 
         if let Some(tokenizer) = &self.tokenizer {
             let mut bytes = Vec::new();
@@ -309,10 +842,15 @@ impl Sampler {
     fn sample_argmax(&self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
         let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
 
-        let probs: Vec<f32> = logits.to_vec1()?;
+        // The logits already have the penalties and biases applied.
+        let logprobs: Vec<f32> = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1()?;
+        let logprob = logprobs[next_token as usize];
 
-        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-        let logprob = probs[next_token as usize].log(10.0);
+        let probs = logprobs.iter().map(|x| x.exp()).collect::<Vec<_>>();
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
         let top_logprobs = if return_logprobs {
             Some(self.get_top_logprobs(&probs, &argsort_indices)?)
@@ -345,6 +883,7 @@ impl Sampler {
         top_k: i64,
         top_p: f32,
         min_p: f32,
+        rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
         let mut probs: Vec<f32> = logits.to_vec1()?;
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
@@ -353,6 +892,20 @@ impl Sampler {
         argsort_indices
             .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
+        let max_p = probs[argsort_indices[0]];
+
+        // MIN P
+
+        // min-p sampling samples from the tokens whose prob are greater than
+        // (max prob of token in dist) * min_p
+
+        // Clamp smaller probabilities to zero.
+        for index in &argsort_indices {
+            if max_p * min_p >= probs[*index] {
+                probs[*index] = 0.0;
+            }
+        }
+
         if top_k > 0 {
             // Clamp smaller probabilities to zero.
             for (index, val) in argsort_indices.iter().enumerate() {
@@ -378,25 +931,13 @@ impl Sampler {
             }
         }
 
-        let max_p = probs[argsort_indices[0]];
-
-        // MIN P
-
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
-
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
-                probs[*index] = 0.0;
-            }
-        }
+        self.apply_xtc(&mut probs, &argsort_indices, &rng);
 
         let logits = Tensor::from_slice(&probs, logits.shape(), &Device::Cpu)?;
 
         let next_token = argmax_sample_last_dim(&logits)?.to_scalar::<u32>()?;
 
-        let logprob = probs[next_token as usize].log(10.0);
+        let logprob = probs[next_token as usize].ln();
 
         let top_logprobs = if return_logprobs {
             Some(self.get_top_logprobs(&probs, &argsort_indices)?)
@@ -433,7 +974,7 @@ impl Sampler {
 
         let mut mut_ref_rng = &mut *rng.lock().expect("could not lock rng mutex");
         let next_token = distr.sample(&mut mut_ref_rng); // "Find the first item which has a weight *higher* than the chosen weight."
-        let logprob = probs[next_token].log(10.0);
+        let logprob = probs[next_token].ln();
 
         let top_logprobs = if return_logprobs {
             Some(self.get_top_logprobs(probs, &argsort_indices)?)
@@ -473,6 +1014,22 @@ impl Sampler {
         argsort_indices
             .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
+        if min_p > 0.0 && min_p < 1.0 {
+            let max_p = probs[argsort_indices[0]];
+
+            // MIN P
+
+            // min-p sampling samples from the tokens whose prob are greater than
+            // (max prob of token in dist) * min_p
+
+            // Clamp smaller probabilities to zero.
+            for index in &argsort_indices {
+                if max_p * min_p >= probs[*index] {
+                    probs[*index] = 0.0;
+                }
+            }
+        }
+
         if top_k > 0 {
             // Clamp smaller probabilities to zero.
             for (index, val) in argsort_indices.iter().enumerate() {
@@ -482,63 +1039,303 @@ impl Sampler {
             }
         }
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
-
-        // TOP P
+        if top_p > 0.0 && top_p < 1.0 {
+            // TOP P
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+            // top-p sampling (or "nucleus sampling") samples from the smallest set of
+            // tokens that exceed probability top_p. This way we never sample tokens that
+            // have very low probabilities and are less likely to go "off the rails".
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
+            // Clamp smaller probabilities to zero.
+            let mut cumsum = 0.;
+            for index in &argsort_indices {
+                if cumsum >= top_p {
+                    probs[*index] = 0.0;
+                } else {
+                    cumsum += probs[*index];
+                }
             }
         }
 
-        if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
+        self.apply_xtc(probs, &argsort_indices, &rng);
 
-        let max_p = probs[argsort_indices[0]];
+        // Sample with clamped probabilities.
+        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+    }
 
-        // MIN P
+    fn sample_typical(
+        &self,
+        probs: &mut Vec<f32>,
+        top_k: i64,
+        typical_p: f32,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
+        // TYPICAL P
 
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
+        // Locally typical sampling samples from the smallest set of tokens that exceed
+        // probability typical_p, taking the tokens whose surprise (negative log-probability)
+        // is closest to the entropy of the distribution first.
+        let entropy: f32 = -probs
+            .iter()
+            .filter(|p| **p > 0.0)
+            .map(|p| p * p.ln())
+            .sum::<f32>();
+
+        if top_k > 0 {
+            // Clamp smaller probabilities to zero.
+            for (index, val) in argsort_indices.iter().enumerate() {
+                if index >= top_k as usize {
+                    probs[*val] = 0.0;
+                }
+            }
+        }
+
+        let distance = |p: f32| {
+            if p > 0.0 {
+                (-p.ln() - entropy).abs()
+            } else {
+                f32::INFINITY
+            }
+        };
+        let mut typical_indices = argsort_indices.clone();
+        typical_indices.sort_by(|&i, &j| {
+            distance(probs[i])
+                .partial_cmp(&distance(probs[j]))
+                .expect("No ordering.")
+        });
+
+        // Clamp the probabilities of the atypical tokens to zero, always keeping the most typical.
+        let mut cumsum = 0.;
+        for index in &typical_indices {
+            if cumsum > 0.0 && cumsum >= typical_p {
                 probs[*index] = 0.0;
+            } else {
+                cumsum += probs[*index];
             }
         }
 
+        self.apply_xtc(probs, &argsort_indices, &rng);
+
         // Sample with clamped probabilities.
         self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
     }
 
-    fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
-        if context.is_empty() {
-            candle_core::bail!("Penalty context is empty, this should not happen.");
-        }
-
+    fn sample_mirostat(
+        &self,
+        probs: &mut Vec<f32>,
+        mirostat: &MirostatInner,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        let mut mu = mirostat.mu.lock().expect("could not lock mirostat mu");
+
+        if mirostat.version == 1 {
+            // Estimate the Zipf exponent `s_hat` of the distribution from the top `m` tokens, and
+            // from it the top-k which yields an expected surprise of `mu`.
+            let m = 100.min(probs.len());
+            let mut sum_ti_bi = 0.;
+            let mut sum_ti_sq = 0.;
+            for i in 0..m.saturating_sub(1) {
+                let (p_i, p_next) = (probs[argsort_indices[i]], probs[argsort_indices[i + 1]]);
+                if p_next <= 0. {
+                    break;
+                }
+                let t_i = ((i + 2) as f64 / (i + 1) as f64).ln();
+                let b_i = (p_i as f64 / p_next as f64).ln();
+                sum_ti_bi += t_i * b_i;
+                sum_ti_sq += t_i * t_i;
+            }
+            let s_hat = sum_ti_bi / sum_ti_sq;
+            let epsilon_hat = s_hat - 1.;
+            let n = probs.len() as f64;
+            let k = ((epsilon_hat * 2f64.powf(*mu)) / (1. - n.powf(-epsilon_hat))).powf(1. / s_hat);
+            // A NaN `k` (degenerate distribution) casts to 0, so this falls back to argmax.
+            let k = (k as usize).clamp(1, probs.len());
+
+            for index in &argsort_indices[k..] {
+                probs[*index] = 0.0;
+            }
+        } else {
+            // Keep the tokens whose surprise is at most `mu`, and always the most likely one.
+            for index in argsort_indices.iter().skip(1) {
+                if -(probs[*index] as f64).log2() > *mu {
+                    probs[*index] = 0.0;
+                }
+            }
+        }
+
+        // The surprise which updates `mu` is measured before XTC.
+        let total: f32 = probs.iter().sum();
+        self.apply_xtc(probs, &argsort_indices, &rng);
+        let res = self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)?;
+
+        let surprise = -((probs[res.token as usize] / total) as f64).log2();
+        *mu -= mirostat.eta * (surprise - mirostat.tau);
+
+        Ok(res)
+    }
+
+    /// Current Mirostat `mu`, if Mirostat sampling is enabled.
+    pub fn mirostat_mu(&self) -> Option<f64> {
+        self.mirostat
+            .as_ref()
+            .map(|m| *m.mu.lock().expect("could not lock mirostat mu"))
+    }
+
+    /// Expand each beam by one token and return the best `2 * num_beams` candidates by cumulative
+    /// log probability, in descending order. Twice as many as needed are returned so that enough
+    /// candidates remain after the caller moves those ending in EOS to the finished hypotheses.
+    ///
+    /// `logits` has one row per beam, and `beam_scores` and `contexts` hold the cumulative log
+    /// probability and tokens of each beam. Penalties, custom logits processors and banned tokens are
+    /// applied per beam, temperature and the top-k/p filters are not.
+    pub fn sample_beam(
+        &self,
+        logits: &Tensor,
+        beam_scores: &[f64],
+        contexts: &[&[u32]],
+        num_beams: usize,
+    ) -> Result<Vec<BeamCandidate>> {
+        let n_beams = logits.dim(0)?;
+        if beam_scores.len() != n_beams || contexts.len() != n_beams {
+            candle_core::bail!(
+                "Expected {n_beams} beam scores and contexts, got {} and {}.",
+                beam_scores.len(),
+                contexts.len()
+            );
+        }
+
+        let mut candidates = Vec::new();
+        for (parent, (score, context)) in zip(beam_scores, contexts).enumerate() {
+            let logits = logits.get(parent)?.to_dtype(candle_core::DType::F32)?;
+            let mut logits = self.apply_penalties(logits.to_vec1()?, context)?;
+            for processor in &self.logits_processors {
+                logits = processor.apply(&logits, context)?;
+            }
+            let logits = self.apply_bad_token_ids(logits)?;
+            let logprobs: Vec<f32> = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1()?;
+
+            candidates.extend(logprobs.into_iter().enumerate().filter_map(|(token, lp)| {
+                lp.is_finite().then_some(BeamCandidate {
+                    parent,
+                    token: token as u32,
+                    score: score + lp as f64,
+                })
+            }));
+        }
+
+        candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).expect("No ordering."));
+        candidates.truncate(2 * num_beams);
+        Ok(candidates)
+    }
+
+    /// The `top_k` most likely next tokens with their probabilities, most likely first, after
+    /// applying the penalties, logits processors and banned tokens. These are the candidates of
+    /// [`ContrastiveSearchConfig::select`].
+    pub fn contrastive_search_candidates(
+        &self,
+        logits: &Tensor,
+        context: &[u32],
+        top_k: usize,
+    ) -> Result<Vec<(u32, f32)>> {
+        if top_k == 0 {
+            candle_core::bail!("Contrastive search `top_k` must be greater than 0.");
+        }
+        let logits = logits.to_dtype(candle_core::DType::F32)?;
+        let mut logits = self.apply_penalties(logits.to_vec1()?, context)?;
+        for processor in &self.logits_processors {
+            logits = processor.apply(&logits, context)?;
+        }
+        let logits = self.apply_bad_token_ids(logits)?;
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+
+        let mut candidates = probs
+            .into_iter()
+            .enumerate()
+            .filter(|(_, p)| *p > 0.0)
+            .map(|(token, p)| (token as u32, p))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        candidates.truncate(top_k);
+        Ok(candidates)
+    }
+
+    fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
+        if context.is_empty() {
+            candle_core::bail!("Penalty context is empty, this should not happen.");
+        }
+
         // Dry penalty
         self.apply_dry_penalty(&mut logits, context)?;
 
         // Frequency and Presence penalty
         self.apply_freq_presc_penalty(&mut logits, context)?;
 
+        // No repeat n-gram
+        self.apply_no_repeat_ngram(&mut logits, context);
+
+        // Logit bias
+        self.apply_logit_bias(&mut logits)?;
+
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    fn apply_logit_bias(&self, logits: &mut [f32]) -> Result<()> {
+        let vocab_size = logits.len();
+        for (tok, bias) in &self.logits_bias {
+            let Some(logit) = logits.get_mut(*tok as usize) else {
+                candle_core::bail!(
+                    "Logit bias token id {tok} is out of range for a vocab size of {vocab_size}."
+                );
+            };
+            *logit += bias;
+        }
+        Ok(())
+    }
+
+    /// Set the logits of the banned tokens to `-inf` so that their probability is zero after
+    /// normalization. If no other token has a nonzero probability, the argmax over the tokens
+    /// which are not banned is forced instead.
+    fn apply_bad_token_ids(&self, logits: Tensor) -> Result<Tensor> {
+        if self.bad_token_ids.is_empty() {
+            return Ok(logits);
+        }
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        let vocab_size = logits.len();
+        for tok in &self.bad_token_ids {
+            if *tok as usize >= vocab_size {
+                candle_core::bail!(
+                    "Banned token id {tok} is out of range for a vocab size of {vocab_size}."
+                );
+            }
+            logits[*tok as usize] = f32::NEG_INFINITY;
+        }
+
+        if logits.iter().all(|x| *x == f32::NEG_INFINITY) {
+            let banned = self.bad_token_ids.iter().copied().collect::<HashSet<_>>();
+            let Some(best) = (0..vocab_size)
+                .filter(|tok| !banned.contains(&(*tok as u32)))
+                .max_by(|a, b| logits[*a].total_cmp(&logits[*b]))
+            else {
+                candle_core::bail!("All {vocab_size} tokens of the vocab are banned.");
+            };
+            logits[best] = 0.;
+        }
+        Tensor::from_vec(logits, vocab_size, &Device::Cpu)
+    }
+
     fn apply_freq_presc_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
         if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
             let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
@@ -565,56 +1362,57 @@ impl Sampler {
         Ok(())
     }
 
-    fn apply_dry_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
-        if let Some(ref params) = self.dry_params {
-            let match_indices = context
-                .par_iter()
-                .enumerate()
-                .take(context.len() - 1)
-                .filter(|(_i, x)| *context.last().unwrap() == **x)
-                .map(|(i, _)| i)
-                .collect::<Vec<_>>();
+    /// Ban every token which would complete an n-gram of size `no_repeat_ngram_size` already in
+    /// the context by setting its logit to `-inf`. If this would ban every token, nothing is banned.
+    fn apply_no_repeat_ngram(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(n) = self.no_repeat_ngram_size.filter(|n| *n > 0) else {
+            return;
+        };
+        if context.len() < n {
+            return;
+        }
 
-            let mut match_lengths = HashMap::new();
+        // Every earlier occurrence of the current (n-1)-gram suffix bans the token following it.
+        let suffix = &context[context.len() - (n - 1)..];
+        let banned = context
+            .windows(n)
+            .filter(|ngram| &ngram[..n - 1] == suffix)
+            .map(|ngram| ngram[n - 1] as usize)
+            // Llama 3.2 uses a hack triggering this error... we wouldn't want a weight on it anyway
+            .filter(|tok| *tok < logits.len())
+            .collect::<HashSet<_>>();
+
+        let all_banned = logits
+            .iter()
+            .enumerate()
+            .all(|(tok, logit)| banned.contains(&tok) || *logit == f32::NEG_INFINITY);
+        if all_banned {
+            return;
+        }
+        for tok in banned {
+            logits[tok] = f32::NEG_INFINITY;
+        }
+    }
 
-            for i in match_indices {
-                let next_token = context[i + 1];
+    fn apply_dry_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        // The penalty of a long match is infinite, which a zero multiplier would turn into NaN.
+        if let Some(params) = self.dry_params.as_ref().filter(|p| p.multiplier != 0.) {
+            // A match extends back from the last token until a sequence breaker.
+            let max_match_length = 1 + context[..context.len() - 1]
+                .iter()
+                .rev()
+                .take_while(|tok| !params.sequence_breakers.contains(tok))
+                .count();
+            let suffix_match_lengths = suffix_match_lengths(context);
 
-                if params.sequence_breakers.contains(&next_token) {
+            let mut match_lengths = HashMap::new();
+            for (i, next_token) in context.iter().enumerate().skip(1) {
+                let match_length = suffix_match_lengths[i - 1].min(max_match_length);
+                if match_length == 0 || params.sequence_breakers.contains(next_token) {
                     continue;
                 }
-
-                let mut match_length = 1;
-
-                // Limit match length to avoid quadratic runtime and potential DoS with adversarial inputs.
-                while match_length < 50 {
-                    if match_length > i {
-                        // Start of input
-                        break;
-                    }
-
-                    let j = i - match_length;
-
-                    let prev_tok = context[context.len() - (match_length + 1)];
-                    if context[j] != prev_tok {
-                        // Start of match reached
-                        break;
-                    }
-
-                    if params.sequence_breakers.contains(&prev_tok) {
-                        // Seq breaking tok reached
-                        break;
-                    }
-
-                    match_length += 1;
-                }
-
-                #[allow(clippy::map_entry)]
-                if match_lengths.contains_key(&next_token) {
-                    match_lengths.insert(next_token, match_length.max(match_lengths[&next_token]));
-                } else {
-                    match_lengths.insert(next_token, match_length);
-                }
+                let longest = match_lengths.entry(*next_token).or_insert(0);
+                *longest = match_length.max(*longest);
             }
 
             // Actually apply penalties
@@ -635,8 +1433,20 @@ impl Sampler {
 
     /// Sample the provided tokens.
     ///
-    /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
-    /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    /// If the temperature is `None`, argmax sampling is used, both when sampling normally and
+    /// speculatively. It is applied after the penalties, logit biases, custom logits processors and
+    /// banned tokens, and ignores the top-k/p, min-p, XTC, typical and Mirostat settings. Otherwise,
+    /// the selected sampling is used.
+    /// The min-p, top-k and top-p filters are applied in sequence. A `top-p` or `min-p` value `<= 0.0`
+    /// or `>= 1.0` disables that filter. If Mirostat is enabled, it replaces these filters when not
+    /// sampling speculatively. Otherwise, if `typical_p` is in `[0.0, 1.0)`, locally typical
+    /// sampling replaces the top-p and min-p filters, after top-k. It always keeps the most typical
+    /// token, which is the only one kept at `0.0`. On every path, XTC is applied last if
+    /// `xtc_probability > 0.0`. Unless Mirostat is enabled, the epsilon and eta cutoffs are applied to the
+    /// probabilities after temperature, before any other filter.
+    ///
+    /// If `min_new_tokens` is specified, the EOS tokens are masked until the minimum is reached.
+    /// The banned tokens are masked after the penalties and custom logits processors.
     pub fn sample(
         &self,
         logits: Tensor,
@@ -644,24 +1454,30 @@ impl Sampler {
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
         sample_speculative: bool,
+        min_new_tokens: Option<&MinNewTokens>,
     ) -> Result<Logprobs> {
-        let logits = logits.to_vec1()?;
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        if let Some(min_new_tokens) = min_new_tokens {
+            min_new_tokens.apply(&mut logits);
+        }
         let mut logits = self.apply_penalties(logits, context)?;
         for processor in &self.logits_processors {
             logits = processor.apply(&logits, context)?;
         }
+        let logits = self.apply_bad_token_ids(logits)?;
         let next_token = if sample_speculative {
             match self.temperature {
-                None => self.sample_speculative_top_kp_min_p(
-                    logits,
-                    return_logprobs,
-                    self.top_k,
-                    self.top_p as f32,
-                    self.min_p as f32,
-                )?,
+                None => self.sample_argmax(logits, return_logprobs)?,
                 Some(temperature) => {
                     let logits = (&logits / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+                    let probs = if self.epsilon_cutoff.is_some() || self.eta_cutoff.is_some() {
+                        let mut probs: Vec<f32> = probs.to_vec1()?;
+                        self.apply_cutoffs(&mut probs);
+                        Tensor::from_vec(probs, logits.shape(), &Device::Cpu)?
+                    } else {
+                        probs
+                    };
 
                     self.sample_speculative_top_kp_min_p(
                         probs,
@@ -669,6 +1485,7 @@ impl Sampler {
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
+                        rng,
                     )?
                 }
             }
@@ -680,19 +1497,69 @@ impl Sampler {
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = probs.to_vec1()?;
 
-                    self.sample_top_kp_min_p(
-                        &mut probs,
-                        self.top_k,
-                        self.top_p as f32,
-                        self.min_p as f32,
-                        return_logprobs,
-                        rng,
-                    )?
+                    if let Some(mirostat) = &self.mirostat {
+                        self.sample_mirostat(&mut probs, mirostat, return_logprobs, rng)?
+                    } else if let Some(typical_p) = self.typical_p {
+                        self.apply_cutoffs(&mut probs);
+                        self.sample_typical(
+                            &mut probs,
+                            self.top_k,
+                            typical_p as f32,
+                            return_logprobs,
+                            rng,
+                        )?
+                    } else {
+                        self.apply_cutoffs(&mut probs);
+                        self.sample_top_kp_min_p(
+                            &mut probs,
+                            self.top_k,
+                            self.top_p as f32,
+                            self.min_p as f32,
+                            return_logprobs,
+                            rng,
+                        )?
+                    }
                 }
             }
         };
         Ok(next_token)
     }
+
+    /// Apply classifier-free guidance: `uncond_logits + cfg_scale * (logits - uncond_logits)`.
+    /// Tokens with a logit of `-inf` stay ruled out.
+    pub fn cfg_logits(logits: &Tensor, uncond_logits: &Tensor, cfg_scale: f64) -> Result<Tensor> {
+        let guided = (uncond_logits + ((logits - uncond_logits)? * cfg_scale)?)?;
+        let ruled_out = Tensor::full(f32::NEG_INFINITY, logits.shape(), logits.device())?;
+        logits
+            .ne(f32::NEG_INFINITY)?
+            .where_cond(&guided, &ruled_out)
+    }
+
+    /// Sample with classifier-free guidance. `logits` are conditioned on the prompt and `uncond_logits`
+    /// on the negative prompt. They are combined with [`Sampler::cfg_logits`] and then sampled as in
+    /// [`Sampler::sample`], so a `cfg_scale` of `1.0` is the same as unguided sampling.
+    #[allow(clippy::too_many_arguments)]
+    pub fn sample_cfg(
+        &self,
+        logits: Tensor,
+        uncond_logits: &Tensor,
+        cfg_scale: f64,
+        context: &[u32],
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        sample_speculative: bool,
+        min_new_tokens: Option<&MinNewTokens>,
+    ) -> Result<Logprobs> {
+        let logits = Self::cfg_logits(&logits, uncond_logits, cfg_scale)?;
+        self.sample(
+            logits,
+            context,
+            return_logprobs,
+            rng,
+            sample_speculative,
+            min_new_tokens,
+        )
+    }
 }
 
 mod tests {
@@ -712,67 +1579,1182 @@ mod tests {
         Tokenizer::from_file(tokenizer_filename).unwrap()
     }
 
+    /// Tokenizer of whitespace-separated words.
+    #[allow(dead_code)]
+    fn word_tokenizer(vocab: &[(&str, u32)]) -> Tokenizer {
+        use std::collections::HashMap;
+        use tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace};
+
+        let vocab = vocab
+            .iter()
+            .map(|(tok, id)| (tok.to_string(), *id))
+            .collect::<HashMap<_, _>>();
+        let mut tokenizer = Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("<unk>".to_string())
+                .build()
+                .unwrap(),
+        );
+        tokenizer.with_pre_tokenizer(Whitespace::default());
+        tokenizer
+    }
+
     #[test]
     fn test_argmax() {
-        use super::Sampler;
+        use super::{Sampler, SamplerConfig};
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler = Sampler::new(
-            None,
-            10,
-            Some(get_tokenizer().into()),
-            None,
-            None,
-            None,
-            32,
-            0.1,
-            0.05,
-            vec![],
-        )
+        let sampler = Sampler::new(SamplerConfig {
+            top_n_logprobs: 10,
+            tokenizer: Some(get_tokenizer().into()),
+            top_k: 32,
+            top_p: 0.1,
+            min_p: 0.05,
+            ..Default::default()
+        })
         .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, false)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                false,
+                rng,
+                false,
+                None,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        let expected = -(0..1024).map(|x| (-x as f64).exp()).sum::<f64>().ln();
+        assert!((res.logprob as f64 - expected).abs() < 1e-5);
     }
 
     #[test]
     fn test_gumbel_speculative() {
-        use super::Sampler;
+        use super::{Sampler, SamplerConfig};
         use candle_core::{Device, Tensor};
         use rand::SeedableRng;
         use rand_isaac::Isaac64Rng;
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler = Sampler::new(
-            None,
-            10,
-            Some(get_tokenizer().into()),
-            None,
-            None,
-            None,
-            32,
-            0.1,
-            0.05,
-            vec![],
-        )
+        let sampler = Sampler::new(SamplerConfig {
+            top_n_logprobs: 10,
+            tokenizer: Some(get_tokenizer().into()),
+            top_k: 32,
+            top_p: 0.1,
+            min_p: 0.05,
+            ..Default::default()
+        })
         .unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
         let res = sampler
-            .sample(logits, &(0..1024).collect::<Vec<_>>(), false, rng, true)
+            .sample(
+                logits,
+                &(0..1024).collect::<Vec<_>>(),
+                false,
+                rng,
+                true,
+                None,
+            )
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        let expected = -(0..1024).map(|x| (-x as f64).exp()).sum::<f64>().ln();
+        assert!((res.logprob as f64 - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_min_p() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(1.0),
+            min_p: 0.05,
+            ..Default::default()
+        })
+        .unwrap();
+        let logits = Tensor::arange(0f32, 64f32, &Device::Cpu)
+            .unwrap()
+            .affine(0.1, 0.)
+            .unwrap();
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        let max_p = probs.iter().copied().fold(0f32, f32::max);
+
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for _ in 0..256 {
+            let res = sampler
+                .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+                .unwrap();
+            assert!(probs[res.token as usize] > max_p * 0.05);
+        }
+
+        // Min-p is applied first, then top-k and top-p on what it keeps.
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(1.0),
+            min_p: 0.5,
+            top_k: 8,
+            top_p: 0.9,
+            ..Default::default()
+        })
+        .unwrap();
+        for sample_speculative in [false, true] {
+            for _ in 0..256 {
+                let res = sampler
+                    .sample(
+                        logits.clone(),
+                        &[0],
+                        false,
+                        rng.clone(),
+                        sample_speculative,
+                        None,
+                    )
+                    .unwrap();
+                assert!(probs[res.token as usize] > max_p * 0.5);
+                assert!(res.token >= 64 - 8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mirostat() {
+        use super::{MirostatConfig, Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let (tau, eta) = (3.0, 0.1);
+        let logits = Tensor::arange(0f32, 256f32, &Device::Cpu)
+            .unwrap()
+            .affine(0.05, 0.)
+            .unwrap();
+
+        for version in [1, 2] {
+            let sampler = Sampler::new(SamplerConfig {
+                temperature: Some(1.0),
+                mirostat: Some(MirostatConfig::new(version, tau, eta)),
+                ..Default::default()
+            })
+            .unwrap();
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+
+            // `mu -= eta * (surprise - tau)`, so the observed surprise can be recovered from `mu`.
+            let mut surprises = Vec::new();
+            for _ in 0..1000 {
+                let mu = sampler.mirostat_mu().unwrap();
+                sampler
+                    .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+                    .unwrap();
+                let new_mu = sampler.mirostat_mu().unwrap();
+                assert!(new_mu.is_finite());
+                surprises.push(tau - (new_mu - mu) / eta);
+            }
+
+            let mean = surprises[500..].iter().sum::<f64>() / 500.;
+            assert!(
+                (mean - tau).abs() < 0.25,
+                "v{version}: mean surprise {mean} did not converge to {tau}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_sample_beam() {
+        use super::{BeamCandidate, Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        let logprobs = [[0.5f32, 0.3, 0.2], [0.1, 0.1, 0.8]].map(|row| row.map(f32::ln));
+        let logits = Tensor::new(&logprobs, &Device::Cpu).unwrap();
+        let beam_scores = [0.9f64.ln(), 0.1f64.ln()];
+
+        let candidates = sampler
+            .sample_beam(&logits, &beam_scores, &[&[0], &[1]], 2)
+            .unwrap();
+        let expected = [(0, 0, 0.45), (0, 1, 0.27), (0, 2, 0.18), (1, 2, 0.08)];
+        assert_eq!(candidates.len(), expected.len());
+        for (
+            BeamCandidate {
+                parent,
+                token,
+                score,
+            },
+            (e_parent, e_token, e_p),
+        ) in candidates.into_iter().zip(expected)
+        {
+            assert_eq!((parent, token), (e_parent, e_token));
+            assert!((score.exp() - e_p).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_contrastive_search() {
+        use super::{ContrastiveSearchConfig, Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        let logits = Tensor::new(&[0.1f32, 2.0, 1.5, -1.0], &Device::Cpu).unwrap();
+        let candidates = sampler
+            .contrastive_search_candidates(&logits, &[0], 3)
+            .unwrap();
+        assert_eq!(
+            candidates.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            [1, 2, 0]
+        );
+
+        // Token 1 repeats the context while token 2 does not.
+        let candidate_hidden =
+            Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.]], &Device::Cpu).unwrap();
+        let context_hidden = Tensor::new(&[[2f32, 0.], [1., -0.1]], &Device::Cpu).unwrap();
+        let select = |penalty_alpha| {
+            ContrastiveSearchConfig {
+                top_k: 3,
+                penalty_alpha,
+            }
+            .select(&candidates, &candidate_hidden, &context_hidden)
+            .unwrap()
+        };
+        // Without the degeneration penalty, this is greedy decoding.
+        let greedy = logits.argmax(0).unwrap().to_scalar::<u32>().unwrap();
+        assert_eq!(select(0.0), greedy);
+        assert_eq!(select(0.6), 2);
+
+        let empty_context = Tensor::zeros((0, 2), candle_core::DType::F32, &Device::Cpu).unwrap();
+        let config = ContrastiveSearchConfig {
+            top_k: 3,
+            penalty_alpha: 0.6,
+        };
+        assert_eq!(
+            config
+                .select(&candidates, &candidate_hidden, &empty_context)
+                .unwrap(),
+            greedy
+        );
+        assert!(ContrastiveSearchConfig {
+            top_k: 3,
+            penalty_alpha: 1.5
+        }
+        .select(&candidates, &candidate_hidden, &context_hidden)
+        .is_err());
+    }
+
+    #[test]
+    fn test_xtc() {
+        use super::{MirostatConfig, Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let logits = Tensor::arange(0f32, 64f32, &Device::Cpu)
+            .unwrap()
+            .affine(0.1, 0.)
+            .unwrap();
+        let argmax = logits.argmax(0).unwrap().to_scalar::<u32>().unwrap();
+
+        // XTC is applied after top-k/p and min-p, typical-p and Mirostat, and when sampling
+        // speculatively. With `eta = 0` the Mirostat `mu` keeps most of the tokens.
+        let paths = [
+            (None, None, false),
+            (Some(0.9), None, false),
+            (None, Some(MirostatConfig::new(2, 5.0, 0.0)), false),
+            (None, None, true),
+        ];
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for (typical_p, mirostat, sample_speculative) in paths {
+            let sampler = Sampler::new(SamplerConfig {
+                temperature: Some(1.0),
+                xtc_probability: 1.0,
+                xtc_threshold: 0.0,
+                typical_p,
+                mirostat,
+                ..Default::default()
+            })
+            .unwrap();
+            for _ in 0..64 {
+                let res = sampler
+                    .sample(
+                        logits.clone(),
+                        &[0],
+                        false,
+                        rng.clone(),
+                        sample_speculative,
+                        None,
+                    )
+                    .unwrap();
+                assert_ne!(res.token, argmax);
+            }
+        }
+    }
+
+    #[test]
+    fn test_typical_p() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::collections::HashSet;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(1.0),
+            typical_p: Some(0.3),
+            ..Default::default()
+        })
+        .unwrap();
+        // The entropy is ~1.333 nats. Sorted by the distance of their surprise to it, the tokens
+        // are 1 (0.276), 2 (0.564), 0 (0.640), 3 and 4, so tokens 1 and 2 reach typical_p = 0.3.
+        let probs = [0.5f32, 0.2, 0.15, 0.1, 0.05];
+        let logits = Tensor::new(&probs, &Device::Cpu).unwrap().log().unwrap();
+
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let mut sampled = HashSet::new();
+        for _ in 0..256 {
+            let res = sampler
+                .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+                .unwrap();
+            sampled.insert(res.token);
+        }
+        assert_eq!(sampled, HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_typical_p_bounds() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let typical_sampler = |top_k, typical_p| {
+            Sampler::new(SamplerConfig {
+                temperature: Some(1.0),
+                top_k,
+                typical_p: Some(typical_p),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let probs = [0.5f32, 0.2, 0.15, 0.1, 0.05];
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+
+        // All tokens are kept at typical_p = 1.0
+        let mut kept = probs.to_vec();
+        typical_sampler(-1, 0.5)
+            .sample_typical(&mut kept, -1, 1.0, false, rng.clone())
+            .unwrap();
+        assert!(kept.iter().all(|p| *p > 0.0));
+
+        // Only the token closest to the entropy is kept at typical_p = 0.0
+        let mut kept = probs.to_vec();
+        let res = typical_sampler(-1, 0.0)
+            .sample_typical(&mut kept, -1, 0.0, false, rng.clone())
+            .unwrap();
+        assert_eq!(kept, [0.0, 0.2, 0.0, 0.0, 0.0]);
+        assert_eq!(res.token, 1);
+
+        // Top-k is applied before, so only the most probable token is left with top_k = 1
+        let logits = Tensor::new(&probs, &Device::Cpu).unwrap().log().unwrap();
+        for _ in 0..16 {
+            let res = typical_sampler(1, 0.3)
+                .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+                .unwrap();
+            assert_eq!(res.token, 0);
+        }
+    }
+
+    fn cutoff_sampler(epsilon_cutoff: Option<f32>, eta_cutoff: Option<f32>) -> super::Sampler {
+        super::Sampler::new(super::SamplerConfig {
+            temperature: Some(1.0),
+            epsilon_cutoff,
+            eta_cutoff,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_epsilon_cutoff() {
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = cutoff_sampler(Some(0.1), None);
+        let mut probs = vec![0.5, 0.3, 0.12, 0.05, 0.03];
+        sampler.apply_cutoffs(&mut probs);
+        assert_eq!(probs, vec![0.5, 0.3, 0.12, 0.0, 0.0]);
+
+        // A cutoff above every probability keeps the most likely token.
+        let sampler = cutoff_sampler(Some(0.5), None);
+        let mut probs = vec![0.2, 0.25, 0.3, 0.25];
+        sampler.apply_cutoffs(&mut probs);
+        assert_eq!(probs, vec![0.0, 0.0, 0.3, 0.0]);
+
+        // Out of range cutoffs are disabled.
+        for epsilon in [0.0, 1.0, -0.5] {
+            let mut probs = vec![0.5, 0.3, 0.2];
+            cutoff_sampler(Some(epsilon), None).apply_cutoffs(&mut probs);
+            assert_eq!(probs, vec![0.5, 0.3, 0.2]);
+        }
+
+        // The cutoff applies after temperature: only the two tokens of probability 0.45 remain.
+        let logits = Tensor::new(&[0.45f32.ln(), 0.45f32.ln(), 0.1f32.ln()], &Device::Cpu).unwrap();
+        let sampler = cutoff_sampler(Some(0.2), None);
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for _ in 0..100 {
+            let res = sampler
+                .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+                .unwrap();
+            assert_ne!(res.token, 2);
+        }
+    }
+
+    #[test]
+    fn test_eta_cutoff() {
+        // A peaked distribution has a low entropy, so the threshold is `eta`.
+        let eta = 0.01f32;
+        let sampler = cutoff_sampler(None, Some(eta));
+        let mut probs = vec![0.97, 0.02, 0.005, 0.005];
+        sampler.apply_cutoffs(&mut probs);
+        assert_eq!(probs, vec![0.97, 0.02, 0.0, 0.0]);
+
+        // A flat distribution has a high entropy, so the threshold is `sqrt(eta) * exp(-entropy)`.
+        let n = 64;
+        let mut probs = vec![1.0 / n as f32; n];
+        let threshold = eta.sqrt() * (-(n as f32).ln()).exp();
+        assert!(threshold < eta && threshold < probs[0]);
+        sampler.apply_cutoffs(&mut probs);
+        assert!(probs.iter().all(|p| *p == 1.0 / n as f32));
+
+        let mut probs = vec![1.0 / 80.0; 80];
+        probs.extend([0.0; 20]);
+        probs[0] += 0.012;
+        probs[1] = 0.0005;
+        let entropy: f32 = probs
+            .iter()
+            .filter(|p| **p > 0.0)
+            .map(|p| -p * p.ln())
+            .sum();
+        let threshold = eta.min(eta.sqrt() * (-entropy).exp());
+        let expected = probs
+            .iter()
+            .map(|p| if *p < threshold { 0.0 } else { *p })
+            .collect::<Vec<_>>();
+        assert!(expected[1] == 0.0 && expected[2] > 0.0);
+        sampler.apply_cutoffs(&mut probs);
+        assert_eq!(probs, expected);
+
+        // With both cutoffs, the larger threshold applies.
+        let mut probs = vec![0.97, 0.02, 0.005, 0.005];
+        cutoff_sampler(Some(0.03), Some(eta)).apply_cutoffs(&mut probs);
+        assert_eq!(probs, vec![0.97, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_frequency_penalty() {
+        use super::{Sampler, SamplerConfig};
+
+        let sampler = Sampler::new(SamplerConfig {
+            frequency_penalty: Some(0.5),
+            ..Default::default()
+        })
+        .unwrap();
+        // Token 1 appears three times, token 2 once and token 3 never.
+        let logits = sampler
+            .apply_penalties(vec![1.0; 4], &[1, 1, 2, 1])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![1.0, -0.5, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn test_presence_penalty() {
+        use super::{Sampler, SamplerConfig};
+
+        let sampler = Sampler::new(SamplerConfig {
+            presence_penalty: Some(0.5),
+            ..Default::default()
+        })
+        .unwrap();
+        // The penalty does not scale with the number of occurrences.
+        let logits = sampler
+            .apply_penalties(vec![1.0; 4], &[1, 1, 2, 1])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![1.0, 0.5, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn test_frequency_and_presence_penalty() {
+        use super::{Sampler, SamplerConfig};
+
+        let sampler = Sampler::new(SamplerConfig {
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(0.25),
+            ..Default::default()
+        })
+        .unwrap();
+        let logits = sampler
+            .apply_penalties(vec![1.0; 4], &[1, 1, 2, 1])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![1.0, -0.75, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn test_penalty_before_temperature() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // Token 63 has the highest logit but was repeated, so the penalty should suppress it.
+        let logits = Tensor::arange(0f32, 64f32, &Device::Cpu)
+            .unwrap()
+            .affine(0.1, 0.)
+            .unwrap();
+        let context = [63, 63, 63, 63];
+
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for temperature in [None, Some(0.5), Some(1.0)] {
+            let sampler = Sampler::new(SamplerConfig {
+                temperature,
+                frequency_penalty: Some(10.0),
+                ..Default::default()
+            })
+            .unwrap();
+            for _ in 0..64 {
+                let res = sampler
+                    .sample(logits.clone(), &context, false, rng.clone(), false, None)
+                    .unwrap();
+                assert_ne!(res.token, 63, "temperature {temperature:?}");
+                if temperature.is_none() {
+                    assert_eq!(res.token, 62);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_repeat_ngram() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // Without the ban, argmax would always pick token 15 and repeat the bigram (15, 15).
+        let logits = Tensor::arange(0f32, 16f32, &Device::Cpu).unwrap();
+
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for (temperature, top_k) in [(None, -1), (Some(1.0), -1), (Some(1.0), 2)] {
+            let sampler = Sampler::new(SamplerConfig {
+                temperature,
+                no_repeat_ngram_size: Some(2),
+                top_k,
+                ..Default::default()
+            })
+            .unwrap();
+            let mut context = vec![15, 14, 15, 14, 15];
+            let mut bigrams = context
+                .windows(2)
+                .map(|w| (w[0], w[1]))
+                .collect::<std::collections::HashSet<_>>();
+            for _ in 0..16 {
+                let res = sampler
+                    .sample(logits.clone(), &context, false, rng.clone(), false, None)
+                    .unwrap();
+                let bigram = (*context.last().unwrap(), res.token);
+                assert!(
+                    bigrams.insert(bigram),
+                    "repeated bigram {bigram:?}, temperature {temperature:?}, top_k {top_k}"
+                );
+                context.push(res.token);
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_repeat_ngram_all_banned() {
+        use super::{Sampler, SamplerConfig};
+
+        let sampler = Sampler::new(SamplerConfig {
+            no_repeat_ngram_size: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        // Token 0 has been followed by every token, so banning would leave nothing to sample.
+        let logits = sampler
+            .apply_penalties(vec![1.0; 4], &[0, 0, 1, 0, 2, 0, 3, 0])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![1.0; 4]);
+
+        // Only token 3 has not followed token 0.
+        let logits = sampler
+            .apply_penalties(vec![1.0; 4], &[0, 0, 1, 0, 2, 0])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(
+            logits,
+            vec![f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY, 1.0]
+        );
+    }
+
+    #[test]
+    fn test_suffix_match_lengths() {
+        use super::suffix_match_lengths;
+
+        let context = [1, 2, 3, 1, 2, 3, 1, 2, 1, 2, 3, 2, 3];
+        let brute_force = (0..context.len())
+            .map(|i| {
+                context[..=i]
+                    .iter()
+                    .rev()
+                    .zip(context.iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(suffix_match_lengths(&context), brute_force);
+        assert_eq!(suffix_match_lengths(&[7; 4]), vec![1, 2, 3, 4]);
+        assert!(suffix_match_lengths(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_dry_penalty() {
+        use super::{DrySamplingParams, Sampler, SamplerConfig};
+        use std::sync::Arc;
+
+        let tokenizer = Arc::new(word_tokenizer(&[
+            ("<unk>", 0),
+            ("a", 1),
+            ("b", 2),
+            ("c", 3),
+            ("d", 4),
+            (":", 5),
+        ]));
+
+        let new_sampler = |frequency_penalty: Option<f32>,
+                           dry_params: Option<DrySamplingParams>| {
+            Sampler::new(SamplerConfig {
+                tokenizer: Some(tokenizer.clone()),
+                frequency_penalty,
+                dry_params,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let dry_params = |sequence_breakers: Vec<&str>| {
+            Some(
+                DrySamplingParams::new_with_defaults(
+                    0.8,
+                    Some(sequence_breakers.into_iter().map(String::from).collect()),
+                    Some(1.75),
+                    Some(2),
+                )
+                .unwrap(),
+            )
+        };
+
+        // The context loops over "a b c d", and "d" continues a match of length 7.
+        let context = [1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3];
+        let logits = vec![0.0, 1.0, 1.0, 1.0, 1.0, 0.0];
+
+        // The frequency penalty penalizes every token of the loop, the continuation the least.
+        let repeat = new_sampler(Some(0.5), None)
+            .apply_penalties(logits.clone(), &context)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(repeat, vec![0.0, -0.5, -0.5, -0.5, 0.0, 0.0]);
+
+        // DRY only penalizes the continuation, exponentially in the match length.
+        let dry = new_sampler(None, dry_params(vec![]))
+            .apply_penalties(logits.clone(), &context)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        let penalty = 0.8 * 1.75f32.powf(5.);
+        assert_eq!(dry, vec![0.0, 1.0, 1.0, 1.0, 1.0 - penalty, 0.0]);
+
+        // A match does not extend back over a sequence breaker.
+        let context = [1, 2, 3, 4, 5, 1, 2, 3, 4, 5, 1, 2, 3];
+        let dry = new_sampler(None, dry_params(vec![":"]))
+            .apply_penalties(logits.clone(), &context)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(dry, vec![0.0, 1.0, 1.0, 1.0, 1.0 - 0.8 * 1.75, 0.0]);
+        let dry = new_sampler(None, dry_params(vec![]))
+            .apply_penalties(logits, &context)
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(
+            dry,
+            vec![0.0, 1.0, 1.0, 1.0, 1.0 - 0.8 * 1.75f32.powf(6.), 0.0]
+        );
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        use super::{Sampler, SamplerConfig};
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        let tokenizer = Arc::new(word_tokenizer(&[("<unk>", 0), ("yes", 1), ("no", 2)]));
+        let new_sampler = |logits_bias: HashMap<u32, f32>,
+                           string_logits_bias: HashMap<String, f32>| {
+            Sampler::new(SamplerConfig {
+                tokenizer: Some(tokenizer.clone()),
+                logits_bias: Some(logits_bias),
+                string_logits_bias: Some(string_logits_bias),
+                ..Default::default()
+            })
+        };
+
+        let sampler = new_sampler(
+            HashMap::from([(2, -1.0)]),
+            HashMap::from([(" yes".to_string(), 5.0)]),
+        )
+        .unwrap();
+        let logits = sampler
+            .apply_penalties(vec![0.0; 3], &[0])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![0.0, 5.0, -1.0]);
+
+        // Both biases of a token add up, and each token of a text is biased.
+        let sampler = new_sampler(
+            HashMap::from([(1, 1.0)]),
+            HashMap::from([("yes no".to_string(), 2.0)]),
+        )
+        .unwrap();
+        let logits = sampler
+            .apply_penalties(vec![0.0; 3], &[0])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![0.0, 3.0, 2.0]);
+
+        let sampler = new_sampler(HashMap::from([(3, 1.0)]), HashMap::new()).unwrap();
+        assert!(sampler.apply_penalties(vec![0.0; 3], &[0]).is_err());
+    }
+
+    #[test]
+    fn test_zero_temperature_is_greedy() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        let tokenizer = Arc::new(word_tokenizer(&[("<unk>", 0), ("yes", 1), ("no", 2)]));
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(0.0),
+            top_n_logprobs: 2,
+            tokenizer: Some(tokenizer),
+            top_k: 2,
+            top_p: 0.1,
+            min_p: 0.5,
+            typical_p: Some(0.2),
+            logits_bias: Some(HashMap::from([(2, 3.0)])),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // All logits are negative, so greedy sampling must not go through the probability filters.
+        let logits = Tensor::new(&[-1.0f32, -2.0, -3.0], &Device::Cpu).unwrap();
+        for sample_speculative in [false, true] {
+            let sample = |seed| {
+                let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(seed)));
+                let res = sampler
+                    .sample(logits.clone(), &[0], true, rng, sample_speculative, None)
+                    .unwrap();
+                serde_json::to_string(&res).unwrap()
+            };
+            let first = sample(0);
+            assert_eq!(first, sample(1), "speculative {sample_speculative}");
+
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+            let res = sampler
+                .sample(logits.clone(), &[0], false, rng, sample_speculative, None)
+                .unwrap();
+            assert_eq!(res.token, 2, "speculative {sample_speculative}");
+            assert_eq!(res.bytes.as_deref(), Some("no"));
+        }
+    }
+
+    #[test]
+    fn test_greedy_logprob() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        let sampler = Sampler::new(SamplerConfig {
+            top_n_logprobs: 3,
+            logits_bias: Some(HashMap::from([(2, 3.0)])),
+            ..Default::default()
+        })
+        .unwrap();
+        let logits = Tensor::new(&[-1.0f32, -2.0, -3.0], &Device::Cpu).unwrap();
+        // The logprobs are those of the biased logits.
+        let biased = [-1.0f32, -2.0, 0.0];
+        let log_sum_exp = biased.iter().map(|x| x.exp()).sum::<f32>().ln();
+        for sample_speculative in [false, true] {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+            let res = sampler
+                .sample(logits.clone(), &[0], true, rng, sample_speculative, None)
+                .unwrap();
+            assert_eq!(res.token, 2);
+            assert!(res.logprob.is_finite() && res.logprob <= 0.0);
+            assert!((res.logprob - (biased[2] - log_sum_exp)).abs() < 1e-5);
+
+            let top_logprobs = res.top_logprobs.unwrap();
+            assert_eq!(
+                top_logprobs.iter().map(|x| x.token).collect::<Vec<_>>(),
+                [2, 0, 1]
+            );
+            for top in top_logprobs {
+                assert!((top.logprob - (biased[top.token as usize] - log_sum_exp)).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_min_new_tokens() {
+        use super::{MinNewTokens, Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        // Token 3 is the EOS token and always the most likely one.
+        let logits = Tensor::new(&[0.0f32, 1.0, 2.0, 3.0], &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+
+        let first = sampler
+            .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+            .unwrap();
+        assert_eq!(first.token, 3);
+
+        let prompt = vec![0u32];
+        let mut context = prompt.clone();
+        loop {
+            let min_new_tokens = MinNewTokens {
+                min_new_tokens: 10,
+                generated: context.len() - prompt.len(),
+                eos_toks: vec![3],
+            };
+            let next = sampler
+                .sample(
+                    logits.clone(),
+                    &context,
+                    false,
+                    rng.clone(),
+                    false,
+                    Some(&min_new_tokens),
+                )
+                .unwrap();
+            if next.token == 3 {
+                break;
+            }
+            assert_eq!(next.token, 2);
+            context.push(next.token);
+        }
+        assert_eq!(context.len() - prompt.len(), 10);
+    }
+
+    #[test]
+    fn test_bad_token_ids() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let new_sampler = |bad_token_ids: Vec<u32>| {
+            Sampler::new(SamplerConfig {
+                temperature: Some(1.0),
+                bad_token_ids: Some(bad_token_ids),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+
+        // Token 3 holds nearly all of the probability mass.
+        let logits = Tensor::new(&[0.0f32, 1.0, 2.0, 20.0], &Device::Cpu).unwrap();
+        let sampler = new_sampler(vec![3]);
+        for _ in 0..100 {
+            let next = sampler
+                .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+                .unwrap();
+            assert_ne!(next.token, 3);
+        }
+
+        // Banning the only possible token falls back to the argmax over the others.
+        let logits = Tensor::new(&[f32::NEG_INFINITY, 1.0, 2.0, 3.0], &Device::Cpu).unwrap();
+        let sampler = new_sampler(vec![1, 2, 3]);
+        let next = sampler
+            .sample(logits, &[0], false, rng.clone(), false, None)
+            .unwrap();
+        assert_eq!(next.token, 0);
+
+        let logits = Tensor::new(&[0.0f32, 1.0, 2.0, 3.0], &Device::Cpu).unwrap();
+        let sampler = new_sampler(vec![4]);
+        assert!(sampler
+            .sample(logits.clone(), &[0], false, rng.clone(), false, None)
+            .is_err());
+        let sampler = new_sampler(vec![0, 1, 2, 3]);
+        assert!(sampler
+            .sample(logits, &[0], false, rng, false, None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_token_healing() {
+        use super::{Sampler, SamplerConfig, TokenHealing};
+        use crate::aici::{bytes::TokRxInfo, toktree::TokTrie};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // "€" is the three bytes E2 82 AC, which also have byte tokens. Token 7 is special.
+        let words = [
+            b"a".to_vec(),
+            vec![0xE2],
+            vec![0x82],
+            vec![0xAC],
+            "€".as_bytes().to_vec(),
+            "€uro".as_bytes().to_vec(),
+            b"b".to_vec(),
+            vec![],
+        ];
+        let tok_trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: words.len() as u32,
+                tok_eos: 7,
+            },
+            &words,
+        );
+
+        // The character is split across the byte tokens at the end of the prompt.
+        let healing = TokenHealing::new(&[0, 1, 2, 3], &tok_trie).unwrap();
+        assert_eq!(healing.n_removed, 3);
+        assert_eq!(healing.removed, "€".as_bytes());
+        assert_eq!(healing.allowed_toks, vec![4, 5]);
+
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        let mut logits = vec![0.0f32, 0.0, 0.0, 0.0, 1.0, 2.0, 10.0, 0.0];
+        healing.apply(&mut logits);
+        let logits = Tensor::new(logits, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let next = sampler
+            .sample(logits, &[0], false, rng, false, None)
+            .unwrap();
+        assert_eq!(next.token, 5);
+        assert_eq!(&tok_trie.token(next.token)[healing.removed.len()..], b"uro");
+
+        // A whole token which other tokens extend is healed.
+        let healing = TokenHealing::new(&[0, 4], &tok_trie).unwrap();
+        assert_eq!(healing.n_removed, 1);
+        assert_eq!(healing.allowed_toks, vec![4, 5]);
+
+        // Nothing extends "a", special tokens have no text, and the prompt is never emptied.
+        assert_eq!(TokenHealing::new(&[6, 0], &tok_trie), None);
+        assert_eq!(TokenHealing::new(&[0, 7], &tok_trie), None);
+        assert_eq!(TokenHealing::new(&[1, 2, 3], &tok_trie), None);
+    }
+
+    #[test]
+    fn test_sample_cfg() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        let logits = Tensor::new(&[2.0f32, 1.5, 0.0], &Device::Cpu).unwrap();
+        // The negative prompt makes token 0 likely as well.
+        let uncond_logits = Tensor::new(&[2.0f32, 0.5, 0.0], &Device::Cpu).unwrap();
+
+        let guided = Sampler::cfg_logits(&logits, &uncond_logits, 2.5).unwrap();
+        assert_eq!(guided.to_vec1::<f32>().unwrap(), vec![2.0, 3.0, 0.0]);
+
+        // Tokens ruled out by the prompt stay ruled out.
+        let masked = Tensor::new(&[2.0f32, f32::NEG_INFINITY, 0.0], &Device::Cpu).unwrap();
+        let guided = Sampler::cfg_logits(&masked, &uncond_logits, 1.0).unwrap();
+        assert_eq!(
+            guided.to_vec1::<f32>().unwrap(),
+            vec![2.0, f32::NEG_INFINITY, 0.0]
+        );
+
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let unguided = sampler
+            .sample_cfg(
+                logits.clone(),
+                &uncond_logits,
+                1.0,
+                &[0],
+                false,
+                rng.clone(),
+                false,
+                None,
+            )
+            .unwrap();
+        assert_eq!(unguided.token, 0);
+        let res = sampler
+            .sample_cfg(logits, &uncond_logits, 2.5, &[0], false, rng, false, None)
+            .unwrap();
+        assert_eq!(res.token, 1);
+    }
+
+    #[test]
+    fn test_cfg_scale_one_is_unguided() {
+        use super::{Sampler, SamplerConfig};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(0.8),
+            top_n_logprobs: 4,
+            ..Default::default()
+        })
+        .unwrap();
+        let logits = Tensor::new(&[0.3f32, 1.2, -0.5, 2.0, 0.9], &Device::Cpu).unwrap();
+        let uncond_logits = Tensor::new(&[1.0f32, 0.2, 0.4, -1.0, 3.0], &Device::Cpu).unwrap();
+
+        let guided = Sampler::cfg_logits(&logits, &uncond_logits, 1.0).unwrap();
+        let diff = (guided - &logits)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-6);
+
+        let guided_rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let unguided_rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for _ in 0..16 {
+            let guided = sampler
+                .sample_cfg(
+                    logits.clone(),
+                    &uncond_logits,
+                    1.0,
+                    &[0],
+                    true,
+                    guided_rng.clone(),
+                    false,
+                    None,
+                )
+                .unwrap();
+            let unguided = sampler
+                .sample(
+                    logits.clone(),
+                    &[0],
+                    true,
+                    unguided_rng.clone(),
+                    false,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(guided.token, unguided.token);
+            assert!((guided.logprob - unguided.logprob).abs() < 1e-6);
+            let top = |logprobs: &super::Logprobs| {
+                logprobs
+                    .top_logprobs
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(|t| t.token)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(top(&guided), top(&unguided));
+        }
+    }
+
+    #[test]
+    fn test_builtin_logits_processors() {
+        use super::{
+            CustomLogitsProcessor, RepetitionPenaltyLogitsProcessor, Sampler,
+            TemperatureLogitsProcessor,
+        };
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let logits = Tensor::new(&[2.0f32, -2.0, 1.5], &Device::Cpu).unwrap();
+
+        let temperature = TemperatureLogitsProcessor { temperature: 2. };
+        let scaled = temperature.apply(&logits, &[0]).unwrap();
+        assert_eq!(scaled.to_vec1::<f32>().unwrap(), vec![1.0, -1.0, 0.75]);
+
+        // Tokens are penalized once, however often they occur.
+        let repetition = RepetitionPenaltyLogitsProcessor { penalty: 2. };
+        let penalized = repetition.apply(&logits, &[0, 1, 0]).unwrap();
+        assert_eq!(penalized.to_vec1::<f32>().unwrap(), vec![1.0, -4.0, 1.5]);
+
+        // Processors run before sampling, so the argmax moves to the unpenalized token.
+        let sampler = Sampler::new(SamplerConfig {
+            logits_processors: vec![Arc::new(repetition)],
+            ..Default::default()
+        })
+        .unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let res = sampler
+            .sample(logits, &[0, 1, 0], false, rng, false, None)
+            .unwrap();
+        assert_eq!(res.token, 2);
     }
 }
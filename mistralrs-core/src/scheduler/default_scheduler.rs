@@ -4,10 +4,15 @@ use std::{
     sync::atomic::Ordering,
 };
 
+use candle_core::Device;
+use tokio::sync::mpsc::Sender;
+
 use crate::{
     engine::TERMINATE_ALL_NEXT_STEP,
     paged_attention::{BlockEngine, BlockTables},
+    response::Response,
     sequence::{Sequence, SequenceState, StopReason},
+    MemoryUsage,
 };
 
 use super::{Scheduler, SchedulerOutput};
@@ -39,6 +44,21 @@ impl FcfsBacker for VecDeque<Sequence> {
     }
 }
 
+/// The length of the longest prefix of `seqs`, sorted by id, with at most `limit` sequences which
+/// does not separate the beams of a request. If the first request alone has more beams than
+/// `limit`, all of them are kept.
+fn beam_group_boundary(seqs: &[Sequence], limit: usize) -> usize {
+    let Some(group_id) = seqs.get(limit).and_then(Sequence::beam_group_id) else {
+        return limit.min(seqs.len());
+    };
+    let in_group = |seq: &Sequence| seq.beam_group_id() == Some(group_id);
+    match seqs[..limit].iter().position(in_group) {
+        Some(0) => seqs.iter().take_while(|seq| in_group(*seq)).count(),
+        Some(start) => start,
+        None => limit,
+    }
+}
+
 pub struct DefaultSchedulerOutput<'a> {
     pub completion: Box<[&'a mut Sequence]>,
     pub prompt: Box<[&'a mut Sequence]>,
@@ -51,6 +71,70 @@ pub struct DefaultSchedulerOutput<'a> {
 #[derive(Clone)]
 pub enum DefaultSchedulerMethod {
     Fixed(NonZeroUsize),
+    /// Run up to `max_batch_size` sequences, fewer while the device is low on free memory.
+    Dynamic(DynamicBatchingConfig),
+}
+
+#[derive(Clone, Debug)]
+pub struct DynamicBatchingConfig {
+    /// Maximum number of running sequences.
+    pub max_batch_size: usize,
+    /// Fraction of the total device memory which should stay free. Before each scheduling step,
+    /// the batch size is halved if less memory is free, and doubled (up to `max_batch_size`)
+    /// otherwise.
+    pub memory_fraction: f64,
+}
+
+/// Source of the free and total device memory used by [`DefaultSchedulerMethod::Dynamic`].
+pub trait MemoryEstimator: Send + Sync {
+    /// Free and total memory, in bytes.
+    fn memory_info(&self) -> candle_core::Result<(usize, usize)>;
+}
+
+/// Queries the memory of a device, with `cuMemGetInfo` on CUDA.
+pub struct DeviceMemoryEstimator(pub Device);
+
+impl MemoryEstimator for DeviceMemoryEstimator {
+    fn memory_info(&self) -> candle_core::Result<(usize, usize)> {
+        Ok((
+            MemoryUsage.get_memory_available(&self.0)?,
+            MemoryUsage.get_total_memory(&self.0)?,
+        ))
+    }
+}
+
+struct DynamicBatchSize {
+    config: DynamicBatchingConfig,
+    estimator: Option<Box<dyn MemoryEstimator>>,
+    current: usize,
+}
+
+impl DynamicBatchSize {
+    fn new(config: DynamicBatchingConfig) -> Self {
+        Self {
+            current: config.max_batch_size.max(1),
+            config,
+            estimator: None,
+        }
+    }
+
+    /// Update the batch size from the free memory. If the memory cannot be queried, the batch
+    /// size is left unchanged.
+    #[allow(clippy::cast_precision_loss)]
+    fn update(&mut self) -> usize {
+        let Some(estimator) = &self.estimator else {
+            return self.current;
+        };
+        if let Ok((free, total)) = estimator.memory_info() {
+            let max = self.config.max_batch_size.max(1);
+            self.current = if (free as f64) < self.config.memory_fraction * total as f64 {
+                (self.current / 2).max(1)
+            } else {
+                self.current.saturating_mul(2).min(max)
+            };
+        }
+        self.current
+    }
 }
 
 pub struct BucketedSeqs<Backer: FcfsBacker> {
@@ -72,7 +156,24 @@ pub trait BucketingManager<Backer: FcfsBacker> {
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
 type BucketKey = (Option<Vec<String>>, usize, bool);
 
-struct FixedBucketingManager;
+struct FixedBucketingManager {
+    /// Whether sequences with different adapters may run in the same batch.
+    mixed_adapter_batches: bool,
+}
+
+impl FixedBucketingManager {
+    fn bucket_key(&self, seq: &Sequence) -> BucketKey {
+        (
+            if self.mixed_adapter_batches {
+                None
+            } else {
+                seq.get_adapters()
+            },
+            seq.len(),
+            seq.images().is_some() && seq.is_prompt(),
+        )
+    }
+}
 
 impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
@@ -88,43 +189,19 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
         for seq in running {
-            let len = seq.len();
-            match seq_buckets.get_mut(&(
-                seq.get_adapters(),
-                len,
-                seq.images().is_some() && seq.is_prompt(),
-            )) {
+            let key = self.bucket_key(&seq);
+            match seq_buckets.get_mut(&key) {
                 Some(bucket) => {
                     if !discrete {
-                        *seq_priorities
-                            .get_mut(&(
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ))
-                            .unwrap() += seq.compute_priority();
+                        *seq_priorities.get_mut(&key).unwrap() += seq.compute_priority();
                     }
                     bucket.push(seq);
                 }
                 None => {
                     if !discrete {
-                        seq_priorities.insert(
-                            (
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ),
-                            seq.compute_priority(),
-                        );
+                        seq_priorities.insert(key.clone(), seq.compute_priority());
                     }
-                    seq_buckets.insert(
-                        (
-                            seq.get_adapters(),
-                            len,
-                            seq.images().is_some() && seq.is_prompt(),
-                        ),
-                        vec![seq],
-                    );
+                    seq_buckets.insert(key, vec![seq]);
                 }
             }
         }
@@ -176,19 +253,56 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    dynamic_batch_size: Option<DynamicBatchSize>,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
     pub fn new(method: DefaultSchedulerMethod) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
-            DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
+            DefaultSchedulerMethod::Fixed(_) | DefaultSchedulerMethod::Dynamic(_) => {
+                Box::new(FixedBucketingManager {
+                    mixed_adapter_batches: false,
+                })
+            }
+        };
+        let dynamic_batch_size = match &method {
+            DefaultSchedulerMethod::Fixed(_) => None,
+            DefaultSchedulerMethod::Dynamic(config) => Some(DynamicBatchSize::new(config.clone())),
         };
         Self {
             running: Vec::new(),
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            dynamic_batch_size,
+        }
+    }
+
+    /// Allow sequences using different adapters to run in the same batch. The pipeline must
+    /// support activating adapters per sequence.
+    pub fn with_mixed_adapter_batches(mut self, mixed_adapter_batches: bool) -> Self {
+        self.bucketing_manager = match self.method {
+            DefaultSchedulerMethod::Fixed(_) | DefaultSchedulerMethod::Dynamic(_) => {
+                Box::new(FixedBucketingManager {
+                    mixed_adapter_batches,
+                })
+            }
+        };
+        self
+    }
+
+    /// Set the memory estimator used by [`DefaultSchedulerMethod::Dynamic`]. Without one, the
+    /// maximum batch size is always used.
+    pub fn with_memory_estimator(mut self, estimator: Box<dyn MemoryEstimator>) -> Self {
+        if let Some(dynamic) = &mut self.dynamic_batch_size {
+            dynamic.estimator = Some(estimator);
         }
+        self
+    }
+
+    /// The current maximum number of running sequences, if it is dynamic.
+    fn batch_size_limit(&self) -> Option<usize> {
+        self.dynamic_batch_size.as_ref().map(|d| d.current)
     }
 
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
@@ -213,6 +327,19 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             .filter(|seq| seq.is_running())
             .collect::<Vec<_>>();
 
+        // Pause the newest sequences if the batch size was reduced. They keep their state and
+        // are resumed once there is room.
+        if let Some(dynamic) = &mut self.dynamic_batch_size {
+            let limit = dynamic.update();
+            if running.len() > limit {
+                running.sort_by_key(|seq| *seq.id());
+                let cut = beam_group_boundary(&running, limit);
+                for seq in running.split_off(cut) {
+                    waiting.add(seq.add_urgency());
+                }
+            }
+        }
+
         match (waiting.len(), running.len()) {
             (0, 0) => {
                 self.running = running;
@@ -222,11 +349,27 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                 };
             }
             (_, 0) => {
+                let limit = self.batch_size_limit();
+                let mut new_waiting = Backer::new();
+                let has_room = |running: &[Sequence], n_seqs: usize| {
+                    limit.is_none_or(|limit| running.is_empty() || running.len() + n_seqs <= limit)
+                };
+                let mut admitted_beam_groups = HashMap::new();
                 for seq in waiting.into_iter() {
+                    let fits = match (seq.beam_group_id(), seq.beam()) {
+                        (Some(group_id), Some(beam)) => *admitted_beam_groups
+                            .entry(group_id)
+                            .or_insert_with(|| has_room(&self.running, beam.config.num_beams)),
+                        _ => has_room(&self.running, 1),
+                    };
+                    if !fits {
+                        new_waiting.add(seq);
+                        continue;
+                    }
                     seq.set_state(SequenceState::RunningPrompt);
                     self.running.push(seq);
                 }
-                self.waiting = Backer::new();
+                self.waiting = new_waiting;
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
                 return DefaultSchedulerOutput {
@@ -253,10 +396,18 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         // Sort the waiting seqs
         waiting.sort_ascending_ids();
 
-        // If the waiting sequence will fit, add it. Otherwise remove it
+        // If the waiting sequence will fit, add it. Otherwise remove it. The beams of a request
+        // are only added together.
         let mut new_waiting = Backer::new();
+        let mut admitted_beam_groups = HashMap::new();
         for seq in waiting.into_iter() {
-            if self.sequence_fits(&running, &seq) {
+            let fits = match (seq.beam_group_id(), seq.beam()) {
+                (Some(group_id), Some(beam)) => *admitted_beam_groups
+                    .entry(group_id)
+                    .or_insert_with(|| self.sequences_fit(&running, beam.config.num_beams)),
+                _ => self.sequences_fit(&running, 1),
+            };
+            if fits {
                 if seq.is_waiting() {
                     seq.set_state(SequenceState::RunningPrompt);
                 }
@@ -292,9 +443,13 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
+    /// Whether `n_seqs` more sequences fit in the batch.
+    fn sequences_fit(&self, running: &[Sequence], n_seqs: usize) -> bool {
         match &self.method {
-            DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
+            DefaultSchedulerMethod::Fixed(n) => (running.len() + n_seqs) <= (*n).into(),
+            DefaultSchedulerMethod::Dynamic(_) => {
+                running.len() + n_seqs <= self.batch_size_limit().unwrap_or(usize::MAX)
+            }
         }
     }
 }
@@ -326,7 +481,102 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
         None
     }
     fn free_finished_sequence_groups(&mut self) {}
+    fn remove_aborted(&mut self) -> Vec<(Sender<Response>, String)> {
+        let mut aborted = Vec::new();
+        // The KV cache of a sequence is owned by it, so it is freed when the sequence is dropped.
+        let mut retain = |seq: Sequence| match seq.abort_reason() {
+            Some(reason) => {
+                aborted.push((seq.responder(), reason));
+                None
+            }
+            None => Some(seq),
+        };
+        let running = std::mem::take(&mut self.running);
+        self.running = running.into_iter().filter_map(&mut retain).collect();
+        let waiting = std::mem::take(&mut self.waiting);
+        for seq in waiting.into_iter().filter_map(&mut retain) {
+            self.waiting.add(seq);
+        }
+        aborted
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::{DefaultScheduler, DefaultSchedulerMethod, DynamicBatchingConfig, MemoryEstimator};
+    use crate::sequence::Sequence;
+
+    const TOTAL: usize = 1000;
+
+    struct MockMemory(Arc<AtomicUsize>);
+
+    impl MemoryEstimator for MockMemory {
+        fn memory_info(&self) -> candle_core::Result<(usize, usize)> {
+            Ok((self.0.load(Ordering::Relaxed), TOTAL))
+        }
+    }
+
+    fn step(scheduler: &mut DefaultScheduler<VecDeque<Sequence>>) -> usize {
+        scheduler.schedule();
+        scheduler.batch_size_limit().unwrap()
+    }
+
+    #[test]
+    fn test_dynamic_batch_size_throttles_at_threshold() {
+        let free = Arc::new(AtomicUsize::new(TOTAL));
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Dynamic(DynamicBatchingConfig {
+                max_batch_size: 8,
+                memory_fraction: 0.2,
+            }),
+        )
+        .with_memory_estimator(Box::new(MockMemory(free.clone())));
+
+        assert_eq!(step(&mut scheduler), 8);
+
+        // Exactly at the threshold is enough free memory.
+        free.store(200, Ordering::Relaxed);
+        assert_eq!(step(&mut scheduler), 8);
+
+        free.store(199, Ordering::Relaxed);
+        assert_eq!(step(&mut scheduler), 4);
+        assert_eq!(step(&mut scheduler), 2);
+        assert_eq!(step(&mut scheduler), 1);
+        assert_eq!(step(&mut scheduler), 1);
+
+        free.store(600, Ordering::Relaxed);
+        assert_eq!(step(&mut scheduler), 2);
+        assert_eq!(step(&mut scheduler), 4);
+        assert_eq!(step(&mut scheduler), 8);
+        assert_eq!(step(&mut scheduler), 8);
+    }
+
+    #[test]
+    fn test_dynamic_batch_size_without_estimator() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Dynamic(DynamicBatchingConfig {
+                max_batch_size: 3,
+                memory_fraction: 0.5,
+            }),
+        );
+        scheduler.schedule();
+        assert_eq!(scheduler.batch_size_limit(), Some(3));
+
+        let fixed = DefaultScheduler::<VecDeque<Sequence>>::new(DefaultSchedulerMethod::Fixed(
+            3.try_into().unwrap(),
+        ))
+        .with_memory_estimator(Box::new(MockMemory(Arc::new(AtomicUsize::new(0)))));
+        assert_eq!(fixed.batch_size_limit(), None);
+    }
+}
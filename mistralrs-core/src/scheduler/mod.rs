@@ -1,12 +1,20 @@
 mod default_scheduler;
 
-pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
+pub use default_scheduler::{
+    DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput, DeviceMemoryEstimator,
+    DynamicBatchingConfig, MemoryEstimator,
+};
+
+use candle_core::Device;
+use tokio::sync::mpsc::Sender;
+use tracing::warn;
 
 use crate::{
     paged_attention::{
         BlockEngine, BlockTables, CacheConfig, PagedAttentionScheduler,
         PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
     },
+    response::Response,
     sequence::Sequence,
 };
 
@@ -22,9 +30,30 @@ pub enum SchedulerConfig {
 }
 
 impl SchedulerConfig {
-    pub fn into_scheduler(self) -> Box<dyn Scheduler> {
+    /// `mixed_adapter_batches` allows sequences using different adapters to share a batch, if the
+    /// scheduler batches by adapter. `device` is queried for free memory by
+    /// [`DefaultSchedulerMethod::Dynamic`].
+    pub fn into_scheduler(
+        self,
+        mixed_adapter_batches: bool,
+        device: &Device,
+    ) -> Box<dyn Scheduler> {
         match self {
-            Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
+            Self::DefaultScheduler { method } => {
+                let dynamic = matches!(method, DefaultSchedulerMethod::Dynamic(_));
+                let mut scheduler =
+                    DefaultScheduler::new(method).with_mixed_adapter_batches(mixed_adapter_batches);
+                if dynamic {
+                    let estimator = DeviceMemoryEstimator(device.clone());
+                    match estimator.memory_info() {
+                        Ok(_) => scheduler = scheduler.with_memory_estimator(Box::new(estimator)),
+                        Err(e) => warn!(
+                            "Cannot query the memory of the device, using the maximum batch size: {e}"
+                        ),
+                    }
+                }
+                Box::new(scheduler)
+            }
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
@@ -52,9 +81,169 @@ pub trait Scheduler {
     fn add_seq(&mut self, seq: Sequence);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
+    /// Remove the sequences which were canceled or timed out, freeing their KV cache. Returns the
+    /// responder and the abort reason of each removed sequence.
+    fn remove_aborted(&mut self) -> Vec<(Sender<Response>, String)>;
 
     // PagedAttention metadata
     fn block_tables(&self) -> Option<&BlockTables>;
     fn block_size(&self) -> Option<usize>;
     fn block_engine(&mut self) -> Option<&mut BlockEngine>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{atomic::AtomicBool, Arc},
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use super::{
+        DefaultScheduler, DefaultSchedulerMethod, DynamicBatchingConfig, Scheduler, SchedulerOutput,
+    };
+    use crate::{
+        paged_attention::{CacheConfig, PagedAttentionScheduler, PagedAttentionSchedulerConfig},
+        response::Response,
+        sampler::{BeamSearchConfig, Sampler, SamplerConfig},
+        sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
+    };
+
+    fn new_seq(id: usize, block_size: Option<usize>) -> (Sequence, Receiver<Response>) {
+        let (tx, rx) = channel(4);
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, 1,
+        )));
+        let seq = Sequence::new_waiting(
+            vec![1, 2, 3, 4, 5],
+            "prompt".to_string(),
+            id,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            block_size,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+        );
+        (seq, rx)
+    }
+
+    #[test]
+    fn test_timed_out_sequence_is_removed() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Fixed(4.try_into().unwrap()),
+        );
+        let (timed_out, _rx) = new_seq(0, None);
+        let (next, _next_rx) = new_seq(1, None);
+        scheduler.add_seq(timed_out.with_timeout(Instant::now(), Duration::ZERO));
+        scheduler.add_seq(next.with_timeout(Instant::now(), Duration::from_secs(3600)));
+
+        let aborted = scheduler.remove_aborted();
+        assert_eq!(aborted.len(), 1);
+        assert_eq!(aborted[0].1, "Request timed out after 0 ms.");
+        assert_eq!(scheduler.waiting_len(), 1);
+        assert!(scheduler.remove_aborted().is_empty());
+
+        // The next request runs unaffected.
+        let SchedulerOutput::DefaultScheduler { output } = Scheduler::schedule(&mut scheduler)
+        else {
+            unreachable!()
+        };
+        assert!(output.completion.is_empty());
+        assert_eq!(output.prompt.len(), 1);
+        assert_eq!(*output.prompt[0].id(), 1);
+        assert_eq!(output.prompt[0].get_toks(), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_beams_are_scheduled_together() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Dynamic(DynamicBatchingConfig {
+                max_batch_size: 3,
+                memory_fraction: 0.5,
+            }),
+        );
+        let config = BeamSearchConfig {
+            num_beams: 2,
+            length_penalty: 1.0,
+            early_stopping: false,
+        };
+        let mut receivers = Vec::new();
+        for id in 0..4 {
+            let (seq, rx) = new_seq(id, None);
+            scheduler.add_seq(seq.with_beam_search(config.clone(), id % 2));
+            receivers.push(rx);
+        }
+
+        // Only one beam of the second request would fit, so it waits.
+        let SchedulerOutput::DefaultScheduler { output } = Scheduler::schedule(&mut scheduler)
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            output
+                .prompt
+                .iter()
+                .map(|seq| *seq.id())
+                .collect::<Vec<_>>(),
+            [0, 1]
+        );
+        assert_eq!(scheduler.waiting_len(), 2);
+    }
+
+    #[test]
+    fn test_canceled_sequence_frees_blocks() {
+        let mut scheduler = PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig { max_num_seqs: 4 },
+            CacheConfig {
+                block_size: 2,
+                num_gpu_blocks: 8,
+                num_cpu_blocks: 0,
+            },
+        );
+        let canceled = Arc::new(AtomicBool::new(false));
+        let (seq, _rx) = new_seq(0, Some(2));
+        scheduler.add_seq(seq.with_cancellation(canceled.clone()));
+        let (next, _next_rx) = new_seq(1, Some(2));
+        scheduler.add_seq(next);
+
+        let _ = Scheduler::schedule(&mut scheduler);
+        assert_eq!(scheduler.block_engine.block_tables.len(), 2);
+
+        canceled.store(true, std::sync::atomic::Ordering::Relaxed);
+        let aborted = Scheduler::remove_aborted(&mut scheduler);
+        assert_eq!(aborted.len(), 1);
+        assert_eq!(aborted[0].1, "Request was canceled.");
+        assert_eq!(
+            scheduler
+                .block_engine
+                .block_tables
+                .keys()
+                .collect::<Vec<_>>(),
+            [&1]
+        );
+        assert_eq!(scheduler.running_len(), 1);
+    }
+}
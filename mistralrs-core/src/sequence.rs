@@ -1,7 +1,10 @@
 use std::{
     fmt::Display,
-    sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -10,6 +13,7 @@ use tokio::sync::{
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
+    grammar::{CfgRecognizer, JsonSchemaFsm},
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     pipeline::DiffusionGenerationParams,
     response::CompletionChoice,
@@ -21,10 +25,14 @@ use crate::{
     get_mut_group,
     pipeline::LayerCaches,
     response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    sampler::{
+        BeamSearchConfig, ContrastiveSearchConfig, Logprobs, MinNewTokens, Sampler, TokenHealing,
+    },
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -37,6 +45,7 @@ pub enum StopReason {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
+    StopRegex,
     Canceled,
     GeneratedImage,
 }
@@ -46,7 +55,9 @@ impl Display for StopReason {
         match self {
             StopReason::Eos => write!(f, "stop"),
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::StopTok(_) | StopReason::StopString { .. } | StopReason::StopRegex => {
+                write!(f, "stop")
+            }
             StopReason::Canceled => write!(f, "canceled"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
         }
@@ -70,6 +81,8 @@ pub enum SequenceState {
 pub enum SequenceRecognizer {
     Regex(Box<StackRecognizer<StateID, RecRx>>),
     Cfg(Box<CfgParser>),
+    Gbnf(Box<CfgRecognizer>),
+    JsonSchema(Box<JsonSchemaFsm>),
     None,
 }
 
@@ -147,6 +160,83 @@ impl SequenceCustomMetadata {
     }
 }
 
+/// The unconditional context of a sequence sampled with classifier-free guidance. It is the
+/// negative prompt followed by the tokens generated so far, and has its own KV cache.
+struct ClassifierFreeGuidance {
+    scale: f64,
+    negative_prompt_toks: Vec<u32>,
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    // The real tokens while the unconditional context is swapped in
+    swapped_toks: Option<Vec<u32>>,
+    uncond_logits: Option<Tensor>,
+}
+
+/// The beam search state of a sequence, which is one beam of its request. The beams of a request
+/// have consecutive ids, starting with beam 0.
+#[derive(Clone, Debug)]
+pub struct BeamState {
+    pub config: BeamSearchConfig,
+    /// Index of this beam in its request.
+    pub index: usize,
+    /// Cumulative log probability of the generated tokens.
+    pub score: f64,
+}
+
+/// The contrastive search state of a sequence.
+pub(crate) struct ContrastiveState {
+    pub(crate) config: ContrastiveSearchConfig,
+    /// Hidden states before the LM head of the tokens the model has run on, of shape
+    /// `(n_tokens, hidden_size)`.
+    pub(crate) context_hidden_states: Option<Tensor>,
+    /// The candidates of the next token and their hidden states, set by the step before sampling.
+    pub(crate) candidates: Option<(Vec<(u32, f32)>, Tensor)>,
+}
+
+impl ContrastiveState {
+    /// Append the hidden states of shape `(n_tokens, hidden_size)` of the new tokens.
+    pub(crate) fn push_context(&mut self, hidden_states: Tensor) -> candle_core::Result<()> {
+        self.context_hidden_states = Some(match self.context_hidden_states.take() {
+            Some(context) => Tensor::cat(&[context, hidden_states], 0)?,
+            None => hidden_states,
+        });
+        Ok(())
+    }
+}
+
+/// The generation state of a beam, which is copied to the beams continuing it.
+#[derive(Clone)]
+pub(crate) struct BeamSnapshot {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    last_logprob: f32,
+    last_completion_bytes_len: usize,
+    last_is_done: Option<StopReason>,
+    completion_bytes: Vec<u8>,
+    stop_search_idx: usize,
+    scaling_cache: Option<Tensor>,
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    score: f64,
+}
+
+impl BeamSnapshot {
+    /// Number of generated tokens.
+    pub(crate) fn generated_len(&self, prompt_len: usize) -> usize {
+        self.tokens.len().saturating_sub(prompt_len)
+    }
+}
+
+/// A finished beam search hypothesis.
+#[derive(Clone)]
+pub(crate) struct BeamHypothesis {
+    /// Score of the hypothesis, normalized by [`BeamSearchConfig::normalized_score`].
+    pub(crate) normalized_score: f64,
+    pub(crate) reason: StopReason,
+    pub(crate) snapshot: BeamSnapshot,
+}
+
 #[derive(Clone, Copy)]
 pub enum SeqStepType {
     PromptAndDecode,
@@ -158,8 +248,11 @@ pub struct Sequence {
     id: usize,
     prompt_len: usize,
     max_len: Option<usize>,
+    min_new_tokens: Option<usize>,
     timestamp: u128,
     sampler: Arc<Sampler>,
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+    token_healing: Option<TokenHealing>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
     return_logprobs: bool,
@@ -188,6 +281,7 @@ pub struct Sequence {
 
     // Adapter dynamic config
     adapters: Option<Vec<String>>,
+    active_adapters: Option<Vec<String>>,
 
     // Cache
     scaling_cache: Option<Tensor>,
@@ -195,6 +289,9 @@ pub struct Sequence {
     draft_cache: LayerCaches,
     xlora_cache: Option<LayerCaches>,
 
+    // Classifier-free guidance
+    guidance: Option<ClassifierFreeGuidance>,
+
     // Mutables
     tokens: Vec<u32>,
     logprobs: Vec<Logprobs>,
@@ -204,6 +301,8 @@ pub struct Sequence {
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
     stream_idx: usize,
+    stop_search_idx: usize,
+    stop_regex: Option<regex::bytes::Regex>,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
     input_images: Option<Vec<image::DynamicImage>>,
@@ -219,6 +318,19 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+
+    // Cancellation
+    canceled: Arc<AtomicBool>,
+    deadline: Option<(Instant, Duration)>,
+
+    // Prompt token ids to return in the response
+    prompt_token_ids: Option<Vec<u32>>,
+
+    // Beam search
+    beam: Option<BeamState>,
+
+    // Contrastive search
+    contrastive: Option<ContrastiveState>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -262,6 +374,7 @@ impl Sequence {
         stop_tokens: Vec<u32>,
         stop_strings: Vec<String>,
         max_len: Option<usize>,
+        min_new_tokens: Option<usize>,
         return_logprobs: bool,
         is_xlora: bool,
         group: Arc<Mutex<SequenceGroup>>,
@@ -280,6 +393,8 @@ impl Sequence {
         image_gen_response_format: Option<ImageGenerationResponseFormat>,
         sequence_stepping_type: SeqStepType,
         diffusion_params: Option<DiffusionGenerationParams>,
+        // Classifier-free guidance scale and negative prompt
+        cfg: Option<(f64, Vec<u32>)>,
     ) -> Self {
         let prompt_len = tokens.len();
         let mut custom_metadata = if let Some(block_size) = block_size {
@@ -307,11 +422,26 @@ impl Sequence {
             } else {
                 None
             },
+            guidance: cfg.map(|(scale, negative_prompt_toks)| ClassifierFreeGuidance {
+                scale,
+                negative_prompt_toks,
+                cache: vec![None; layers],
+                xlora_cache: if is_xlora {
+                    Some(vec![None; layers])
+                } else {
+                    None
+                },
+                swapped_toks: None,
+                uncond_logits: None,
+            }),
             responder,
             sampler: sampler.into(),
+            rng: None,
+            token_healing: None,
             stop_tokens,
             stop_strings,
             max_len,
+            min_new_tokens,
             return_logprobs,
             prompt_tok_per_sec: 0.,
             prompt_timestamp: None,
@@ -326,12 +456,15 @@ impl Sequence {
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
+            stop_search_idx: 0,
+            stop_regex: None,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
             scheduling_urgency: 0,
             adapters,
+            active_adapters: None,
             input_images,
             custom_metadata,
             tok_trie,
@@ -339,6 +472,11 @@ impl Sequence {
             image_gen_response_format,
             sequence_stepping_type,
             diffusion_params,
+            canceled: Arc::new(AtomicBool::new(false)),
+            deadline: None,
+            prompt_token_ids: None,
+            beam: None,
+            contrastive: None,
         }
     }
 
@@ -373,6 +511,169 @@ impl Sequence {
         self
     }
 
+    /// Sample this sequence with its own RNG seeded with `seed`, instead of the engine's RNG. The
+    /// sampled tokens then only depend on the seed and the logits, not on the other sequences.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Some(Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(
+            seed,
+        ))));
+        self
+    }
+
+    /// Share the cancellation flag of the request. Once it is set, the sequence is removed by the
+    /// scheduler before its next step.
+    pub(crate) fn with_cancellation(mut self, canceled: Arc<AtomicBool>) -> Self {
+        self.canceled = canceled;
+        self
+    }
+
+    /// Stop once the completion matches `stop_regex`, see [`Self::check_stop_regex`].
+    pub(crate) fn with_stop_regex(mut self, stop_regex: regex::bytes::Regex) -> Self {
+        self.stop_regex = Some(stop_regex);
+        self
+    }
+
+    /// Remove the sequence if it has not finished `timeout` after `start`.
+    pub(crate) fn with_timeout(mut self, start: Instant, timeout: Duration) -> Self {
+        self.deadline = Some((start + timeout, timeout));
+        self
+    }
+
+    /// Why this sequence must be removed before it finishes: it was canceled, or it timed out.
+    pub fn abort_reason(&self) -> Option<String> {
+        if self.canceled.load(Ordering::Relaxed) {
+            Some("Request was canceled.".to_string())
+        } else {
+            self.deadline
+                .filter(|(deadline, _)| Instant::now() >= *deadline)
+                .map(|(_, timeout)| format!("Request timed out after {} ms.", timeout.as_millis()))
+        }
+    }
+
+    /// The RNG of this sequence, if it was seeded with [`Sequence::with_seed`].
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
+    /// Heal the end of the prompt, whose last `token_healing.n_removed` tokens must already have
+    /// been removed from the tokens of this sequence.
+    /// Return `ids`, the tokenized prompt, in the response.
+    pub(crate) fn with_prompt_token_ids(mut self, ids: Vec<u32>) -> Self {
+        self.prompt_token_ids = Some(ids);
+        self
+    }
+
+    /// The tokenized prompt, if it should be returned in the response.
+    pub fn prompt_token_ids(&self) -> Option<&[u32]> {
+        self.prompt_token_ids.as_deref()
+    }
+
+    /// Make this sequence beam `index` of a beam search. Only beam 0 starts with a finite score,
+    /// so that the first step does not select the same continuation from identical beams.
+    pub(crate) fn with_beam_search(mut self, config: BeamSearchConfig, index: usize) -> Self {
+        self.beam = Some(BeamState {
+            config,
+            index,
+            score: if index == 0 { 0. } else { f64::NEG_INFINITY },
+        });
+        self
+    }
+
+    pub fn beam(&self) -> Option<&BeamState> {
+        self.beam.as_ref()
+    }
+
+    /// Identifies the beams of one request: the id of its beam 0.
+    pub(crate) fn beam_group_id(&self) -> Option<usize> {
+        self.beam.as_ref().map(|beam| self.id - beam.index)
+    }
+
+    pub(crate) fn beam_snapshot(&self) -> BeamSnapshot {
+        BeamSnapshot {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            last_logprob: self.last_logprob,
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            last_is_done: self.last_is_done,
+            completion_bytes: self.completion_bytes.clone(),
+            stop_search_idx: self.stop_search_idx,
+            scaling_cache: self.scaling_cache.clone(),
+            cache: self.cache.clone(),
+            xlora_cache: self.xlora_cache.clone(),
+            score: self.beam.as_ref().map_or(0., |beam| beam.score),
+        }
+    }
+
+    /// Select the tokens of this sequence by contrastive search.
+    pub(crate) fn with_contrastive_search(mut self, config: ContrastiveSearchConfig) -> Self {
+        self.contrastive = Some(ContrastiveState {
+            config,
+            context_hidden_states: None,
+            candidates: None,
+        });
+        self
+    }
+
+    pub(crate) fn contrastive(&self) -> Option<&ContrastiveState> {
+        self.contrastive.as_ref()
+    }
+
+    pub(crate) fn contrastive_mut(&mut self) -> Option<&mut ContrastiveState> {
+        self.contrastive.as_mut()
+    }
+
+    /// Continue the beam of `snapshot` in this sequence, including its KV cache.
+    pub(crate) fn restore_beam_snapshot(&mut self, snapshot: BeamSnapshot) {
+        let BeamSnapshot {
+            tokens,
+            logprobs,
+            cumulative_logprob,
+            last_logprob,
+            last_completion_bytes_len,
+            last_is_done,
+            completion_bytes,
+            stop_search_idx,
+            scaling_cache,
+            cache,
+            xlora_cache,
+            score,
+        } = snapshot;
+        self.tokens = tokens;
+        self.logprobs = logprobs;
+        self.cumulative_logprob = cumulative_logprob;
+        self.last_logprob = last_logprob;
+        self.last_completion_bytes_len = last_completion_bytes_len;
+        self.last_is_done = last_is_done;
+        self.completion_bytes = completion_bytes;
+        self.stop_search_idx = stop_search_idx;
+        self.scaling_cache = scaling_cache;
+        self.cache = cache;
+        self.xlora_cache = xlora_cache;
+        self.prefill_prompt_toks = None;
+        if let Some(beam) = &mut self.beam {
+            beam.score = score;
+        }
+    }
+
+    pub(crate) fn set_beam_score(&mut self, score: f64) {
+        if let Some(beam) = &mut self.beam {
+            beam.score = score;
+        }
+    }
+
+    pub(crate) fn with_token_healing(mut self, token_healing: TokenHealing) -> Self {
+        self.token_healing = Some(token_healing);
+        self
+    }
+
+    /// The token healing to apply when sampling, until the first token is generated.
+    pub(crate) fn token_healing(&self) -> Option<&TokenHealing> {
+        self.token_healing
+            .as_ref()
+            .filter(|_| self.tokens.len() == self.prompt_len)
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -481,6 +782,48 @@ impl Sequence {
         self.xlora_cache.as_mut().expect("No X-LoRA cache.")
     }
 
+    /// The classifier-free guidance scale, if this sequence is sampled with guidance.
+    pub fn cfg_scale(&self) -> Option<f64> {
+        self.guidance.as_ref().map(|guidance| guidance.scale)
+    }
+
+    /// Swap the tokens and KV caches of this sequence with those of its unconditional context.
+    /// Calling this again swaps the real context back in.
+    pub(crate) fn swap_cfg_context(&mut self) {
+        let Some(guidance) = &mut self.guidance else {
+            return;
+        };
+        match guidance.swapped_toks.take() {
+            Some(toks) => self.tokens = toks,
+            None => {
+                let uncond_toks = guidance
+                    .negative_prompt_toks
+                    .iter()
+                    .chain(&self.tokens[self.prompt_len..])
+                    .copied()
+                    .collect();
+                guidance.swapped_toks = Some(std::mem::replace(&mut self.tokens, uncond_toks));
+            }
+        }
+        std::mem::swap(&mut self.cache, &mut guidance.cache);
+        std::mem::swap(&mut self.xlora_cache, &mut guidance.xlora_cache);
+    }
+
+    pub(crate) fn set_cfg_uncond_logits(&mut self, logits: Tensor) {
+        if let Some(guidance) = &mut self.guidance {
+            guidance.uncond_logits = Some(logits);
+        }
+    }
+
+    /// The unconditional logits of the last step and the guidance scale, if they were computed.
+    pub(crate) fn take_cfg_uncond_logits(&mut self) -> Option<(Tensor, f64)> {
+        let guidance = self.guidance.as_mut()?;
+        guidance
+            .uncond_logits
+            .take()
+            .map(|logits| (logits, guidance.scale))
+    }
+
     pub fn scaling_cache(&mut self) -> &mut Option<Tensor> {
         &mut self.scaling_cache
     }
@@ -529,6 +872,13 @@ impl Sequence {
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
         );
+        // The first token starts with the healed end of the prompt, which is not part of the completion.
+        let completion_bytes = match self.token_healing() {
+            Some(token_healing) if completion_bytes.starts_with(&token_healing.removed) => {
+                completion_bytes[token_healing.removed.len()..].to_vec()
+            }
+            _ => completion_bytes,
+        };
         if !stopped_by_token {
             // Completion bytes is used to check for stop strings, and as the response buffer.
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
@@ -556,6 +906,15 @@ impl Sequence {
         self.creation_time
     }
 
+    /// Record one speculative decoding step of this sequence in its group, see
+    /// `GeneralMetadata::record_speculative_step`.
+    pub(crate) fn record_speculative_step(&self, drafted: usize, accepted: usize) {
+        let mut group = get_mut_group!(self);
+        group.speculative_drafted_tokens += drafted;
+        group.speculative_accepted_tokens += accepted;
+        group.speculative_draft_calls += 1;
+    }
+
     pub fn set_state(&self, state: SequenceState) {
         if matches!(state, SequenceState::Error) {
             get_mut_group!(self).n_choices -= 1;
@@ -573,8 +932,13 @@ impl Sequence {
         eos_tok: Option<&[u32]>,
         max_model_len: usize,
     ) -> Option<StopReason> {
+        // EOS is only accepted once `min_new_tokens` tokens have been generated
+        let min_reached = match self.min_new_tokens {
+            Some(min) => self.tokens.len().saturating_sub(self.prompt_len) >= min,
+            None => true,
+        };
         let is_eos = match eos_tok {
-            Some(eos_tok) => eos_tok.iter().any(|t| *t == tok),
+            Some(eos_tok) => min_reached && eos_tok.iter().any(|t| *t == tok),
             None => false,
         };
         if is_eos {
@@ -594,21 +958,69 @@ impl Sequence {
         } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
             Some(StopReason::ModelLength(max_model_len))
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
-                }
-            }
             None
         }
     }
 
+    /// Check the completion for a stop string once the latest token has been added.
+    ///
+    /// Only the bytes added since the last check are searched, starting far enough back that a
+    /// stop string straddling token boundaries is still found.
+    pub fn check_stop_strings(&mut self) -> Option<StopReason> {
+        let max_stop_len = self.stop_strings.iter().map(String::len).max()?;
+        let search_start = self
+            .stop_search_idx
+            .saturating_sub(max_stop_len.saturating_sub(1));
+        self.stop_search_idx = self.completion_bytes.len();
+
+        let window = &self.completion_bytes[search_start..];
+        let reason = self
+            .stop_strings
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !s.is_empty())
+            .filter_map(|(idx, s)| {
+                galil_seiferas::gs_find(window, s.as_bytes()).map(|pos| (idx, search_start + pos))
+            })
+            .min_by_key(|(_, pos)| *pos)
+            .map(
+                |(stop_string_idx, completion_bytes_pos)| StopReason::StopString {
+                    stop_string_idx,
+                    completion_bytes_pos,
+                },
+            );
+        if reason.is_some() {
+            self.last_is_done = reason;
+        }
+        reason
+    }
+
+    /// Check the whole completion against the stop regex once the latest token has been added.
+    /// Unlike stop strings, the completion is kept up to and including the match.
+    pub fn check_stop_regex(&mut self) -> Option<StopReason> {
+        let stop_regex = self.stop_regex.as_ref()?;
+        if !stop_regex.is_match(&self.completion_bytes) {
+            return None;
+        }
+        self.last_is_done = Some(StopReason::StopRegex);
+        self.last_is_done
+    }
+
+    /// Length of the longest suffix of `bytes` which is a proper prefix of some stop string.
+    fn pending_stop_prefix_len(&self, bytes: &[u8]) -> usize {
+        self.stop_strings
+            .iter()
+            .map(|s| {
+                let s = s.as_bytes();
+                (1..s.len().min(bytes.len() + 1))
+                    .rev()
+                    .find(|n| bytes.ends_with(&s[..*n]))
+                    .unwrap_or(0)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
     pub fn logprobs(&self) -> &[Logprobs] {
         &self.logprobs
     }
@@ -621,21 +1033,47 @@ impl Sequence {
         self.prompt_len
     }
 
+    /// The EOS suppression to apply when sampling, if `min_new_tokens` was requested.
+    pub fn min_new_tokens(&self, eos_toks: &[u32]) -> Option<MinNewTokens> {
+        self.min_new_tokens.map(|min_new_tokens| MinNewTokens {
+            min_new_tokens,
+            generated: self.tokens.len().saturating_sub(self.prompt_len),
+            eos_toks: eos_toks.to_vec(),
+        })
+    }
+
     pub fn stop_strings(&self) -> &[String] {
         &self.stop_strings
     }
 
     /// Returns the delta between the last two decoded sequences
+    ///
+    /// Text which may be the start of a stop string is held back until the next tokens resolve it,
+    /// and a matched stop string is never part of the delta.
     pub fn get_delta(
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
+        let end = match self.last_is_done {
+            Some(StopReason::StopString {
+                completion_bytes_pos,
+                ..
+            }) => completion_bytes_pos.max(self.stream_idx),
+            Some(_) => self.completion_bytes.len(),
+            None => {
+                self.completion_bytes.len()
+                    - self.pending_stop_prefix_len(&self.completion_bytes[self.stream_idx..])
+            }
+        };
+        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..end]);
         // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
-        if new_decoded.ends_with('�') {
+        if new_decoded.ends_with('�') && self.last_is_done.is_none() {
+            return Ok(None);
+        }
+        if new_decoded.is_empty() && self.last_is_done.is_none() {
             return Ok(None);
         }
-        self.stream_idx = self.completion_bytes.len();
+        self.stream_idx = end;
 
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
@@ -710,8 +1148,15 @@ impl Sequence {
         get_mut_group!(self).completion_streaming_chunks.push(chunk);
     }
 
+    /// The adapters this sequence runs with: the requested ones unless they were swapped.
     pub fn get_adapters(&self) -> Option<Vec<String>> {
-        self.adapters.clone()
+        self.active_adapters
+            .clone()
+            .or_else(|| self.adapters.clone())
+    }
+
+    pub(crate) fn set_active_adapters(&mut self, adapters: Vec<String>) {
+        self.active_adapters = Some(adapters);
     }
 
     pub fn take_images(&mut self) -> Option<Vec<image::DynamicImage>> {
@@ -750,6 +1195,12 @@ pub struct SequenceGroup {
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    /// Finished beam search hypotheses, best first.
+    pub(crate) beam_hypotheses: Vec<BeamHypothesis>,
+    // Speculative decoding stats of the sequences of this group
+    pub(crate) speculative_drafted_tokens: usize,
+    pub(crate) speculative_accepted_tokens: usize,
+    pub(crate) speculative_draft_calls: usize,
 }
 
 impl SequenceGroup {
@@ -769,9 +1220,27 @@ impl SequenceGroup {
             is_streaming,
             is_chat,
             best_of,
+            beam_hypotheses: Vec::new(),
+            speculative_drafted_tokens: 0,
+            speculative_accepted_tokens: 0,
+            speculative_draft_calls: 0,
         }
     }
 
+    /// The target number of choices to return.
+    pub(crate) fn n_choices(&self) -> usize {
+        self.n_choices
+    }
+
+    /// Add a finished beam search hypothesis, keeping the best `num_beams` hypotheses.
+    pub(crate) fn add_beam_hypothesis(&mut self, hypothesis: BeamHypothesis, num_beams: usize) {
+        let idx = self
+            .beam_hypotheses
+            .partition_point(|h| h.normalized_score >= hypothesis.normalized_score);
+        self.beam_hypotheses.insert(idx, hypothesis);
+        self.beam_hypotheses.truncate(num_beams);
+    }
+
     /// This does not apply best_of.
     pub fn get_choices(&self) -> &[Choice] {
         &self.choices
@@ -808,6 +1277,12 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            speculative_acceptance_rate: (self.speculative_drafted_tokens > 0).then(|| {
+                self.speculative_accepted_tokens as f32 / self.speculative_drafted_tokens as f32
+            }),
+            speculative_tokens_per_draft_call: (self.speculative_draft_calls > 0).then(|| {
+                self.speculative_accepted_tokens as f32 / self.speculative_draft_calls as f32
+            }),
         }
     }
 
@@ -888,3 +1363,306 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+
+    use super::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer, StopReason};
+    use crate::sampler::{Logprobs, Sampler, SamplerConfig};
+
+    fn new_seq(sampler: Sampler, return_logprobs: bool) -> Sequence {
+        new_seq_with_stop_strings(sampler, return_logprobs, vec![])
+    }
+
+    fn new_seq_with_stop_strings(
+        sampler: Sampler,
+        return_logprobs: bool,
+        stop_strings: Vec<String>,
+    ) -> Sequence {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+            1, false, false, 1,
+        )));
+        Sequence::new_waiting(
+            vec![0, 1],
+            "prompt".to_string(),
+            0,
+            0,
+            1,
+            tx,
+            sampler,
+            vec![],
+            stop_strings,
+            None,
+            None,
+            return_logprobs,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_collect_all_logprobs() {
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(1.0),
+            top_n_logprobs: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut seq = new_seq(sampler, true);
+
+        let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let n_generated = 4;
+        let step_logits = |step: usize| [0f32, 1., 2., 3., step as f32 + 0.5];
+        for step in 0..n_generated {
+            let logits = Tensor::new(&step_logits(step), &Device::Cpu).unwrap();
+            let logprobs = seq
+                .sampler()
+                .sample(
+                    logits,
+                    seq.get_toks(),
+                    seq.return_logprobs(),
+                    rng.clone(),
+                    false,
+                    None,
+                )
+                .unwrap();
+            seq.add_token(logprobs, vec![], &None);
+        }
+
+        assert_eq!(seq.get_toks().len() - seq.prompt_tokens(), n_generated);
+        assert_eq!(seq.logprobs().len(), n_generated);
+        let expected_toks = [[3, 2, 1], [3, 2, 4], [3, 4, 2], [4, 3, 2]];
+        for (step, logprob) in seq.logprobs().iter().enumerate() {
+            let logits = step_logits(step);
+            let log_sum_exp = logits.iter().map(|x| x.exp()).sum::<f32>().ln();
+            let top_logprobs = logprob.top_logprobs.as_ref().unwrap();
+            assert_eq!(
+                top_logprobs.iter().map(|x| x.token).collect::<Vec<_>>(),
+                expected_toks[step]
+            );
+            for top in top_logprobs {
+                let expected = logits[top.token as usize] - log_sum_exp;
+                assert!((top.logprob - expected).abs() < 1e-5);
+            }
+            let expected = logits[logprob.token as usize] - log_sum_exp;
+            assert!((logprob.logprob - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_top_logprobs_clamped_to_vocab() {
+        let sampler = Sampler::new(SamplerConfig {
+            temperature: Some(1.0),
+            top_n_logprobs: 8,
+            ..Default::default()
+        })
+        .unwrap();
+        let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let logits = Tensor::new(&[0f32, 2., 1.], &Device::Cpu).unwrap();
+        let logprobs = sampler.sample(logits, &[], true, rng, false, None).unwrap();
+        let toks = logprobs
+            .top_logprobs
+            .unwrap()
+            .iter()
+            .map(|x| x.token)
+            .collect::<Vec<_>>();
+        assert_eq!(toks, [1, 2, 0]);
+    }
+
+    #[test]
+    fn test_seeded_sampling_reproducible() {
+        let generate = |seed: u64| {
+            let sampler = Sampler::new(SamplerConfig {
+                temperature: Some(1.0),
+                ..Default::default()
+            })
+            .unwrap();
+            let mut seq = new_seq(sampler, false).with_seed(seed);
+            for _ in 0..16 {
+                // Uniform logits, so that every token depends on the RNG.
+                let logits = Tensor::zeros(32, candle_core::DType::F32, &Device::Cpu).unwrap();
+                let logprobs = seq
+                    .sampler()
+                    .sample(
+                        logits,
+                        seq.get_toks(),
+                        false,
+                        seq.rng().unwrap(),
+                        false,
+                        None,
+                    )
+                    .unwrap();
+                seq.add_token(logprobs, vec![], &None);
+            }
+            seq.get_toks()[seq.prompt_tokens()..].to_vec()
+        };
+
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+    }
+
+    fn stop_string_seq(stop_strings: &[&str]) -> Sequence {
+        let sampler = Sampler::new(SamplerConfig::default()).unwrap();
+        new_seq_with_stop_strings(
+            sampler,
+            false,
+            stop_strings.iter().map(|s| s.to_string()).collect(),
+        )
+    }
+
+    /// Add a token decoding to `text` and check for stop strings, as the sampling loop does.
+    fn add_text(seq: &mut Sequence, text: &str) -> Option<StopReason> {
+        let logprobs = Logprobs {
+            token: 0,
+            logprob: 0.,
+            bytes: None,
+            top_logprobs: None,
+        };
+        seq.add_token(logprobs, text.as_bytes().to_vec(), &None);
+        seq.check_stop_strings()
+    }
+
+    #[test]
+    fn test_stop_string_split_across_tokens() {
+        let mut seq = stop_string_seq(&["\n\n"]);
+        assert_eq!(add_text(&mut seq, " Hello"), None);
+        assert_eq!(add_text(&mut seq, " world\n"), None);
+        let reason = add_text(&mut seq, "\nNext");
+        assert_eq!(
+            reason,
+            Some(StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: " Hello world".len(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_stop_string_does_not_overlap_prompt() {
+        // Only the completion is searched: a prompt ending in a newline does not complete the
+        // stop string with a first token starting with one.
+        let mut seq = stop_string_seq(&["\n\nHuman:"]);
+        assert_eq!(add_text(&mut seq, "\nHuman: hi"), None);
+        let reason = add_text(&mut seq, "\n\nHuman:");
+        assert_eq!(
+            reason,
+            Some(StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: "\nHuman: hi".len(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_stop_string_special_characters() {
+        let mut seq = stop_string_seq(&["<|im_end|>", ".*", "。\n"]);
+        assert_eq!(add_text(&mut seq, "a <|im"), None);
+        assert_eq!(
+            add_text(&mut seq, "_end|>"),
+            Some(StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: 2,
+            })
+        );
+
+        // Characters are matched literally, not as a pattern
+        let mut seq = stop_string_seq(&[".*"]);
+        assert_eq!(add_text(&mut seq, "a.b*"), None);
+        assert!(add_text(&mut seq, ".*").is_some());
+
+        // A multi-byte character split across tokens
+        let mut seq = stop_string_seq(&["。\n"]);
+        let bytes = "好。\n".as_bytes();
+        let logprobs = Logprobs {
+            token: 0,
+            logprob: 0.,
+            bytes: None,
+            top_logprobs: None,
+        };
+        seq.add_token(logprobs.clone(), bytes[..4].to_vec(), &None);
+        assert_eq!(seq.check_stop_strings(), None);
+        seq.add_token(logprobs, bytes[4..].to_vec(), &None);
+        assert_eq!(
+            seq.check_stop_strings(),
+            Some(StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: "好".len(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_stop_regex() {
+        let stop_regex = regex::bytes::Regex::new(r"\}\s*$").unwrap();
+        let mut seq = stop_string_seq(&[]).with_stop_regex(stop_regex);
+        let tokens = ["{\"a\":", " 1", "}", "\n", "{"];
+        let stopped_at = tokens.iter().position(|text| {
+            assert_eq!(add_text(&mut seq, text), None);
+            seq.check_stop_regex().is_some()
+        });
+        assert_eq!(stopped_at, Some(2));
+        // The match is kept in the output
+        assert_eq!(seq.completion_bytes(), b"{\"a\": 1}");
+        assert_eq!(seq.get_delta().unwrap().as_deref(), Some("{\"a\": 1}"));
+    }
+
+    #[test]
+    fn test_earliest_stop_string_wins() {
+        let mut seq = stop_string_seq(&["END", "\n\n"]);
+        assert_eq!(add_text(&mut seq, "a\n"), None);
+        let reason = add_text(&mut seq, "\nEND");
+        assert_eq!(
+            reason,
+            Some(StopReason::StopString {
+                stop_string_idx: 1,
+                completion_bytes_pos: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_streaming_holds_back_partial_stop_string() {
+        let mut seq = stop_string_seq(&["\n\n"]);
+        add_text(&mut seq, " Hello");
+        assert_eq!(seq.get_delta().unwrap().as_deref(), Some("Hello"));
+
+        // A lone newline could be the start of the stop string, so it is not streamed yet.
+        assert_eq!(add_text(&mut seq, "\n"), None);
+        assert_eq!(seq.get_delta().unwrap(), None);
+
+        // The stop string completes: the pending newline and the stop string are dropped.
+        assert!(add_text(&mut seq, "\nmore").is_some());
+        assert_eq!(seq.get_delta().unwrap().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_streaming_releases_resolved_partial_stop_string() {
+        let mut seq = stop_string_seq(&["\n\n"]);
+        add_text(&mut seq, " Hello\n");
+        assert_eq!(seq.get_delta().unwrap().as_deref(), Some("Hello"));
+
+        // The next token does not continue the stop string, so the held back newline is released.
+        assert_eq!(add_text(&mut seq, "world"), None);
+        assert_eq!(seq.get_delta().unwrap().as_deref(), Some("\nworld"));
+    }
+}
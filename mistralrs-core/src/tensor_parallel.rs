@@ -0,0 +1,560 @@
+//! Tensor parallelism: the weights of the repeating layers are split across several devices, each
+//! of which computes a part of the result.
+//!
+//! As in Megatron-LM, each rank holds its share of the attention heads, with their KV cache, and of
+//! the MLP intermediate features. The query, key, value and MLP up projections are split by output
+//! features and the attention output and MLP down projections by input features, so a rank runs a
+//! whole attention or MLP without communication and computes a partial sum of its output. The
+//! partial sums are added with an all-reduce, once after the attention output projection and once
+//! after the MLP down projection of every layer. With NCCL, the all-reduce runs on the GPUs
+//! without going through the host.
+
+use std::{fmt::Debug, sync::Arc};
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Linear, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+use crate::amoe::{AnyMoeTrainableLayer, MlpLayer};
+
+/// The devices of a tensor parallel group, one per rank. The activations between the attention and
+/// MLP of the layers live on the first device.
+pub struct TensorParallel {
+    devices: Vec<Device>,
+    #[cfg(feature = "nccl")]
+    comms: Option<nccl::Comms>,
+}
+
+impl Debug for TensorParallel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TensorParallel")
+            .field("devices", &self.devices)
+            .finish()
+    }
+}
+
+impl TensorParallel {
+    /// Create the group. If all devices are CUDA devices and the `nccl` feature is enabled, this
+    /// initializes one NCCL communicator per device.
+    pub fn new(devices: Vec<Device>) -> Result<Self> {
+        if devices.is_empty() {
+            candle_core::bail!("Tensor parallelism needs at least one device.");
+        }
+        #[cfg(feature = "nccl")]
+        let comms = nccl::Comms::new(&devices)?;
+        Ok(Self {
+            devices,
+            #[cfg(feature = "nccl")]
+            comms,
+        })
+    }
+
+    pub fn world_size(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// The device of rank 0, which holds the activations.
+    pub fn main_device(&self) -> &Device {
+        &self.devices[0]
+    }
+
+    /// Sum the partial results of all ranks, given in rank order, into a tensor on the main device.
+    pub fn all_reduce(&self, partials: &[Tensor]) -> Result<Tensor> {
+        if partials.len() != self.world_size() {
+            candle_core::bail!(
+                "Expected {} partial results to all-reduce, got {}.",
+                self.world_size(),
+                partials.len()
+            );
+        }
+        #[cfg(feature = "nccl")]
+        if let Some(comms) = &self.comms {
+            return comms.all_reduce(partials);
+        }
+        let main = self.main_device();
+        let mut sum = partials[0].to_device(main)?;
+        for partial in &partials[1..] {
+            sum = (sum + partial.to_device(main)?)?;
+        }
+        Ok(sum)
+    }
+
+    /// Copy `xs` to the device of each rank, in rank order.
+    pub fn broadcast(&self, xs: &Tensor) -> Result<Vec<Tensor>> {
+        self.devices.iter().map(|dev| xs.to_device(dev)).collect()
+    }
+
+    /// Split `xs` along `dim` into one contiguous shard per rank, each on the device of its rank.
+    fn shard(&self, xs: &Tensor, dim: usize) -> Result<Vec<Tensor>> {
+        let size = xs.dim(dim)?;
+        let n = self.world_size();
+        if size % n != 0 {
+            candle_core::bail!("Cannot split dimension {dim} of size {size} across {n} devices.");
+        }
+        let chunk = size / n;
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(rank, dev)| {
+                xs.narrow(dim, rank * chunk, chunk)?
+                    .contiguous()?
+                    .to_device(dev)
+            })
+            .collect()
+    }
+}
+
+fn unquant(weight: Tensor) -> Result<Arc<dyn QuantMethod>> {
+    Ok(Arc::new(UnquantLinear::new(
+        QuantMethodConfig::Unquantized(Linear::new(weight, None)),
+    )?))
+}
+
+/// Load a linear layer without bias split by output features, one shard per rank on the device of
+/// its rank. Each shard computes a slice of the output: used for the query, key and value
+/// projections, this gives each rank its share of the attention heads. The full weight is read on
+/// the CPU and only the shards are moved to the devices.
+pub fn column_parallel_linear_no_bias(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+    tp: &TensorParallel,
+) -> Result<Vec<Arc<dyn QuantMethod>>> {
+    let weight = vb
+        .set_device(Device::Cpu)
+        .get((out_dim, in_dim), "weight")?;
+    tp.shard(&weight, 0)?.into_iter().map(unquant).collect()
+}
+
+/// Load a linear layer without bias split by input features, one shard per rank on the device of
+/// its rank. Each shard takes the slice of the input computed by the column parallel layer before
+/// it, and computes a partial sum of the output, to be summed with [`TensorParallel::all_reduce`].
+pub fn row_parallel_linear_no_bias(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+    tp: &TensorParallel,
+) -> Result<Vec<Arc<dyn QuantMethod>>> {
+    let weight = vb
+        .set_device(Device::Cpu)
+        .get((out_dim, in_dim), "weight")?;
+    tp.shard(&weight, 1)?.into_iter().map(unquant).collect()
+}
+
+/// An MLP split across the ranks by its intermediate features. The MLP of each rank has column
+/// parallel up projections and a row parallel down projection, so it runs on its device without
+/// communication and the only all-reduce is of the outputs of the down projections.
+pub struct TensorParallelMlp {
+    ranks: Vec<Box<dyn MlpLayer>>,
+    tp: Arc<TensorParallel>,
+}
+
+impl TensorParallelMlp {
+    /// `ranks` holds the MLP of each rank, in rank order.
+    pub fn new(ranks: Vec<Box<dyn MlpLayer>>, tp: Arc<TensorParallel>) -> Result<Self> {
+        if ranks.len() != tp.world_size() {
+            candle_core::bail!(
+                "Expected {} tensor parallel MLPs, got {}.",
+                tp.world_size(),
+                ranks.len()
+            );
+        }
+        Ok(Self { ranks, tp })
+    }
+}
+
+impl AnyMoeTrainableLayer for TensorParallelMlp {}
+
+impl MlpLayer for TensorParallelMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let partials = self
+            .ranks
+            .iter()
+            .zip(self.tp.broadcast(xs)?)
+            .map(|(mlp, xs)| mlp.forward(&xs))
+            .collect::<Result<Vec<_>>>()?;
+        self.tp.all_reduce(&partials)
+    }
+    fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        self.ranks
+            .iter_mut()
+            .flat_map(|mlp| mlp.get_isq_layers())
+            .collect()
+    }
+    fn clone(&self) -> Box<dyn MlpLayer> {
+        Box::new(Self {
+            ranks: self.ranks.iter().map(|mlp| mlp.clone()).collect(),
+            tp: self.tp.clone(),
+        })
+    }
+    fn get_params(&self) -> &[usize] {
+        self.ranks[0].get_params()
+    }
+    fn new_added_delta(&self, _deltas: Vec<Option<Tensor>>) -> Result<Box<dyn MlpLayer>> {
+        candle_core::bail!("Tensor parallel layers do not support adding delta weights.")
+    }
+    fn dtype_device(&self) -> (DType, Device) {
+        (
+            self.ranks[0].dtype_device().0,
+            self.tp.main_device().clone(),
+        )
+    }
+}
+
+#[cfg(feature = "nccl")]
+mod nccl {
+    use std::sync::Mutex;
+
+    use candle_core::{
+        backend::BackendStorage,
+        cuda_backend::{CudaDType, WrapErr},
+        CpuStorage, CudaStorage, CustomOp1, DType, Device, Layout, Result, Shape, Storage, Tensor,
+    };
+    use cudarc::{
+        driver::DeviceRepr,
+        nccl::{group_end, group_start, Comm, NcclType, ReduceOp},
+    };
+    use half::{bf16, f16};
+
+    fn nccl_err(e: impl std::fmt::Debug) -> candle_core::Error {
+        candle_core::Error::Msg(format!("NCCL error: {e:?}"))
+    }
+
+    /// One NCCL communicator per device, all owned by this process.
+    pub(super) struct Comms(Mutex<Vec<Comm>>);
+
+    // SAFETY: the communicators are only used while holding the mutex, so by one thread at a time,
+    // and NCCL communicators may be used from any thread.
+    unsafe impl Send for Comms {}
+    unsafe impl Sync for Comms {}
+
+    impl Comms {
+        /// Returns `None` unless there are at least two devices and all of them are CUDA devices.
+        pub(super) fn new(devices: &[Device]) -> Result<Option<Self>> {
+            if devices.len() < 2 {
+                return Ok(None);
+            }
+            let cuda_devices = devices
+                .iter()
+                .map(|dev| match dev {
+                    Device::Cuda(dev) => Some(dev.cuda_device()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            let Some(cuda_devices) = cuda_devices else {
+                return Ok(None);
+            };
+            let comms = Comm::from_devices(cuda_devices).map_err(nccl_err)?;
+            Ok(Some(Self(Mutex::new(comms))))
+        }
+
+        pub(super) fn all_reduce(&self, partials: &[Tensor]) -> Result<Tensor> {
+            let comms = self.0.lock().unwrap();
+            let partials = partials
+                .iter()
+                .map(|p| p.contiguous())
+                .collect::<Result<Vec<_>>>()?;
+            partials[0].apply_op1_no_bwd(&AllReduce {
+                comms: &comms,
+                others: &partials[1..],
+            })
+        }
+    }
+
+    /// Sum of the tensor it is applied to, on rank 0, and `others`, on the other ranks in order.
+    struct AllReduce<'a> {
+        comms: &'a [Comm],
+        others: &'a [Tensor],
+    }
+
+    impl AllReduce<'_> {
+        fn cuda_fwd_t<T: CudaDType + DeviceRepr + NcclType>(
+            &self,
+            storage: &CudaStorage,
+            layout: &Layout,
+        ) -> Result<(CudaStorage, Shape)> {
+            let elem_count = layout.shape().elem_count();
+            let guards = self
+                .others
+                .iter()
+                .map(|t| t.storage_and_layout())
+                .collect::<Vec<_>>();
+            let mut storages = vec![(storage, layout)];
+            for (s, l) in &guards {
+                let Storage::Cuda(s) = &**s else {
+                    candle_core::bail!("NCCL all-reduce expects CUDA tensors.")
+                };
+                if l.shape() != layout.shape() {
+                    candle_core::bail!(
+                        "All-reduce shape mismatch: {:?} and {:?}.",
+                        layout.shape(),
+                        l.shape()
+                    );
+                }
+                storages.push((s, *l));
+            }
+
+            let mut sends = Vec::with_capacity(storages.len());
+            let mut recvs = Vec::with_capacity(storages.len());
+            for (s, l) in &storages {
+                let offset = l.start_offset();
+                sends.push(s.as_cuda_slice::<T>()?.slice(offset..offset + elem_count));
+                recvs.push(unsafe { s.device().alloc::<T>(elem_count) }.w()?);
+            }
+
+            group_start().map_err(nccl_err)?;
+            for ((comm, send), recv) in self.comms.iter().zip(&sends).zip(recvs.iter_mut()) {
+                comm.all_reduce(send, recv, &ReduceOp::Sum)
+                    .map_err(nccl_err)?;
+            }
+            group_end().map_err(nccl_err)?;
+
+            let out = recvs.swap_remove(0);
+            Ok((
+                CudaStorage::wrap_cuda_slice(out, storage.device().clone()),
+                layout.shape().clone(),
+            ))
+        }
+    }
+
+    impl CustomOp1 for AllReduce<'_> {
+        fn name(&self) -> &'static str {
+            "nccl-all-reduce"
+        }
+
+        fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+            candle_core::bail!("NCCL all-reduce requires CUDA tensors.")
+        }
+
+        fn cuda_fwd(&self, storage: &CudaStorage, layout: &Layout) -> Result<(CudaStorage, Shape)> {
+            match storage.dtype() {
+                DType::F32 => self.cuda_fwd_t::<f32>(storage, layout),
+                DType::F16 => self.cuda_fwd_t::<f16>(storage, layout),
+                DType::BF16 => self.cuda_fwd_t::<bf16>(storage, layout),
+                dtype => {
+                    candle_core::bail!("NCCL all-reduce does not support {dtype:?} tensors.")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{DType, Device, Result, Tensor, D};
+    use candle_nn::{Linear, VarBuilder};
+    use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+    use super::{column_parallel_linear_no_bias, row_parallel_linear_no_bias, TensorParallel};
+    use crate::{
+        models::{
+            llama,
+            test_utils::{random_weights, DecoderShapes},
+        },
+        paged_attention::AttentionImplementation,
+        pipeline::{text_models_inputs_processor::FlashParams, NormalLoadingMetadata, NormalModel},
+        DeviceMapMetadata,
+    };
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a.to_device(&Device::Cpu)? - b.to_device(&Device::Cpu)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()
+    }
+
+    /// A column parallel layer followed by a row parallel layer, each rank using only its own
+    /// shards, matches the two unsplit layers after one all-reduce.
+    fn check_layers(devices: Vec<Device>) -> Result<()> {
+        let main = devices[0].clone();
+        let tp = TensorParallel::new(devices)?;
+        let w1 = Tensor::randn(0f32, 1., (8, 12), &Device::Cpu)?;
+        let w2 = Tensor::randn(0f32, 1., (12, 8), &Device::Cpu)?;
+        let x = Tensor::randn(0f32, 1., (2, 3, 12), &Device::Cpu)?;
+        let full = |w: &Tensor| -> Result<UnquantLinear> {
+            UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(w.clone(), None)))
+        };
+        let hidden = full(&w1)?.forward(&x)?;
+        let expected = full(&w2)?.forward(&hidden)?;
+
+        let weights = HashMap::from([("w1.weight".to_string(), w1), ("w2.weight".to_string(), w2)]);
+        let vb = VarBuilder::from_tensors(weights, DType::F32, &main);
+        let column = column_parallel_linear_no_bias(12, 8, vb.pp("w1"), &tp)?;
+        let row = row_parallel_linear_no_bias(8, 12, vb.pp("w2"), &tp)?;
+        let mut slices = Vec::new();
+        let mut partials = Vec::new();
+        for ((column, row), x) in column.iter().zip(&row).zip(tp.broadcast(&x)?) {
+            let slice = column.forward(&x)?;
+            assert!(slice.device().same_device(x.device()));
+            partials.push(row.forward(&slice)?);
+            slices.push(slice.to_device(&Device::Cpu)?);
+        }
+        // Each rank computes a slice of the hidden features.
+        let diff = max_diff(&Tensor::cat(&slices, D::Minus1)?, &hidden)?;
+        assert!(diff < 1e-4, "{diff}");
+        let out = tp.all_reduce(&partials)?;
+        assert!(out.device().same_device(&main));
+        let diff = max_diff(&out, &expected)?;
+        assert!(diff < 1e-4, "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_layers_match_linear() -> Result<()> {
+        for n in [1, 2, 4] {
+            check_layers(vec![Device::Cpu; n])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_reduce() -> Result<()> {
+        let tp = TensorParallel::new(vec![Device::Cpu; 3])?;
+        let partials = (1..=3u8)
+            .map(|i| Tensor::full(f32::from(i), (2, 2), &Device::Cpu))
+            .collect::<Result<Vec<_>>>()?;
+        let sum = tp.all_reduce(&partials)?;
+        assert_eq!(sum.to_vec2::<f32>()?, vec![vec![6f32; 2]; 2]);
+        assert!(tp.all_reduce(&partials[..2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_uneven_split_fails() {
+        let tp = TensorParallel::new(vec![Device::Cpu; 3]).unwrap();
+        let weights = HashMap::from([(
+            "w.weight".to_string(),
+            Tensor::zeros((8, 12), DType::F32, &Device::Cpu).unwrap(),
+        )]);
+        let vb = VarBuilder::from_tensors(weights, DType::F32, &Device::Cpu);
+        assert!(column_parallel_linear_no_bias(12, 8, vb.pp("w"), &tp).is_err());
+        assert!(row_parallel_linear_no_bias(12, 8, vb.pp("w"), &tp).is_ok());
+    }
+
+    fn tiny_llama(
+        cfg: &llama::Config,
+        weights: &HashMap<String, Tensor>,
+        mapper: DeviceMapMetadata,
+    ) -> Result<llama::Llama> {
+        let dev = Device::Cpu;
+        llama::Llama::new(
+            cfg,
+            VarBuilder::from_tensors(weights.clone(), DType::F32, &dev),
+            false,
+            NormalLoadingMetadata {
+                mapper: mapper.into_mapper(cfg.num_hidden_layers, &dev, None)?,
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            AttentionImplementation::Eager,
+        )
+    }
+
+    /// Logits of the last token of `input`, which follows `offset` tokens in the KV cache.
+    fn llama_logits(model: &llama::Llama, input: &[u32], offset: usize) -> Result<Tensor> {
+        let dev = Device::Cpu;
+        let seq_len = input.len();
+        let (start, end) = (
+            i64::try_from(offset).unwrap(),
+            i64::try_from(offset + seq_len).unwrap(),
+        );
+        let cumulative_seqlens = Tensor::new(&[0, u32::try_from(seq_len).unwrap()], &dev)?;
+        model.forward(
+            &Tensor::new(input, &dev)?.unsqueeze(0)?,
+            &[offset],
+            Tensor::arange(start, end, &dev)?.unsqueeze(0)?,
+            vec![(seq_len - 1, 1)],
+            None,
+            &FlashParams {
+                max_q: u32::try_from(seq_len).unwrap(),
+                max_k: u32::try_from(offset + seq_len).unwrap(),
+                cumulative_seqlens_q: cumulative_seqlens.clone(),
+                cumulative_seqlens_k: cumulative_seqlens,
+            },
+        )
+    }
+
+    /// A Llama model split across two simulated ranks computes the same logits as on one device,
+    /// for the prompt and then for each decoded token, with each rank caching its own heads.
+    #[test]
+    fn test_tensor_parallel_llama_matches_single_device() -> Result<()> {
+        let cfg = llama::Config {
+            hidden_size: 16,
+            intermediate_size: 24,
+            vocab_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10000.,
+            max_position_embeddings: 32,
+            ..Default::default()
+        };
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let shapes = DecoderShapes {
+            vocab_size: cfg.vocab_size,
+            hidden_size: cfg.hidden_size,
+            intermediate_size: cfg.intermediate_size,
+            num_hidden_layers: cfg.num_hidden_layers,
+            q_dim: cfg.hidden_size,
+            kv_dim: cfg.num_key_value_heads * head_dim,
+            tie_word_embeddings: false,
+            qkv_bias: false,
+            gated_mlp: true,
+        };
+        let weights = random_weights(shapes.shapes(), &Device::Cpu)?;
+
+        let single = tiny_llama(&cfg, &weights, DeviceMapMetadata::dummy())?;
+        let split = tiny_llama(
+            &cfg,
+            &weights,
+            DeviceMapMetadata::tensor_parallel(vec![0, 1]),
+        )?;
+        let prompt = [1, 5, 7, 2, 9];
+        let mut offset = 0;
+        for input in [&prompt[..], &[4], &[11]] {
+            let expected = llama_logits(&single, input, offset)?;
+            let logits = llama_logits(&split, input, offset)?;
+            assert_eq!(logits.dims(), expected.dims());
+            let diff = max_diff(&logits, &expected)?;
+            assert!(diff < 1e-4, "{diff}");
+            offset += input.len();
+        }
+
+        // One KV cache entry per layer and rank, each with the key-value heads of its rank.
+        let cache = split.cache().lock();
+        assert_eq!(cache.len(), 2 * cfg.num_hidden_layers);
+        for entry in cache.iter() {
+            let (k, v) = entry.as_ref().expect("No KV cache entry.");
+            assert_eq!(k.dims(), [1, cfg.num_key_value_heads / 2, offset, head_dim]);
+            assert_eq!(v.dims(), k.dims());
+        }
+        drop(cache);
+
+        let odd_heads = llama::Config {
+            num_key_value_heads: 1,
+            ..cfg.clone()
+        };
+        assert!(tiny_llama(
+            &odd_heads,
+            &weights,
+            DeviceMapMetadata::tensor_parallel(vec![0, 1])
+        )
+        .is_err());
+        Ok(())
+    }
+
+    /// Two CUDA devices, all-reduced with NCCL.
+    #[cfg(feature = "nccl")]
+    #[test]
+    fn test_parallel_layers_match_linear_nccl() -> Result<()> {
+        let devices = vec![Device::new_cuda(0)?, Device::new_cuda(1)?];
+        check_layers(devices)
+    }
+}
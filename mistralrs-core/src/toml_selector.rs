@@ -3,11 +3,13 @@ use std::{fs::File, num::NonZeroUsize, path::PathBuf};
 use serde::Deserialize;
 
 use crate::{
-    amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, ModelDType,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
+    amoe::AnyMoeConfig,
+    pipeline::{IsqOrganization, KVCacheDtype},
+    AdaptiveGamma, AnyMoeLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
+    GGUFSpecificConfig, Loader, ModelDType, NormalLoaderBuilder, NormalLoaderType,
+    NormalSpecificConfig, PromptLookupConfig, PromptLookupLoader, SpeculativeConfig,
     SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER,
+    VocabMismatchPolicy, GGUF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
@@ -22,6 +24,10 @@ fn default_empty_vec_usize() -> Vec<usize> {
     Vec::new()
 }
 
+fn default_max_ngram_size() -> usize {
+    3
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum TomlModelSelected {
@@ -289,10 +295,30 @@ pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
+    /// Number of draft branches per step
+    #[serde(default = "default_one")]
+    draft_branches: usize,
+
+    /// Lower bound of an adaptive gamma
+    gamma_min: Option<usize>,
+
+    /// Upper bound of an adaptive gamma
+    gamma_max: Option<usize>,
+
     /// Base model
     draft_model: TomlModelSelected,
 }
 
+#[derive(Deserialize)]
+pub struct PromptLookupTomlSelected {
+    /// Maximum number of draft tokens per step
+    gamma: usize,
+
+    /// Maximum length of the end of the sequence which is looked up in the sequence. Defaults to 3.
+    #[serde(default = "default_max_ngram_size")]
+    max_ngram_size: usize,
+}
+
 #[derive(Deserialize)]
 pub struct AnyMoeTomlModelSelected {
     /// Config
@@ -326,6 +352,9 @@ pub struct TomlSelector {
     /// Speculative model selector
     speculative: Option<SpeculativeTomlModelSelected>,
 
+    /// Speculative decoding by prompt lookup, without a draft model
+    prompt_lookup: Option<PromptLookupTomlSelected>,
+
     /// AnyMoE config
     anymoe: Option<AnyMoeTomlModelSelected>,
 }
@@ -337,6 +366,7 @@ struct TomlLoaderInnerParams {
     no_kv_cache: bool,
     tokenizer_json: Option<String>,
     prompt_batchsize: Option<NonZeroUsize>,
+    kv_cache_dtype: KVCacheDtype,
 }
 
 pub struct TomlLoaderArgs {
@@ -344,6 +374,7 @@ pub struct TomlLoaderArgs {
     pub chat_template: Option<String>,
     pub no_kv_cache: bool,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_dtype: KVCacheDtype,
 }
 
 pub fn get_toml_selected_model_dtype(model: &TomlSelector) -> ModelDType {
@@ -379,8 +410,11 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.unwrap_or_default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -403,8 +437,11 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -435,8 +472,11 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -467,6 +507,7 @@ fn loader_from_selected(
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -489,6 +530,7 @@ fn loader_from_selected(
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -519,6 +561,7 @@ fn loader_from_selected(
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -540,6 +583,7 @@ fn loader_from_selected(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -562,6 +606,7 @@ fn loader_from_selected(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -592,6 +637,7 @@ fn loader_from_selected(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -619,7 +665,10 @@ fn loader_from_selected(
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_dtype: args.kv_cache_dtype,
                 topology: Topology::from_option_path(topology)?,
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -642,15 +691,36 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
             no_kv_cache: args.no_kv_cache,
             tokenizer_json: selector.tokenizer_json,
             prompt_batchsize: args.prompt_batchsize,
+            kv_cache_dtype: args.kv_cache_dtype,
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
-        let loader = if let Some(speculative) = selector.speculative {
+        if selector.speculative.is_some() && selector.prompt_lookup.is_some() {
+            anyhow::bail!("Only one of `speculative` and `prompt_lookup` may be specified.");
+        }
+        let loader: Box<dyn Loader> = if let Some(speculative) = selector.speculative {
             let draft_loader = loader_from_selected(args, speculative.draft_model)?;
             Box::new(SpeculativeLoader {
                 target: loader,
                 draft: draft_loader,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    vocab_mismatch_policy: VocabMismatchPolicy::Strict,
+                    draft_branches: speculative.draft_branches,
+                    adaptive_gamma: match (speculative.gamma_min, speculative.gamma_max) {
+                        (None, None) => None,
+                        (gamma_min, gamma_max) => Some(AdaptiveGamma {
+                            gamma_min: gamma_min.unwrap_or(1),
+                            gamma_max: gamma_max.unwrap_or(speculative.gamma),
+                        }),
+                    },
+                },
+            })
+        } else if let Some(prompt_lookup) = selector.prompt_lookup {
+            Box::new(PromptLookupLoader {
+                target: loader,
+                config: PromptLookupConfig {
+                    gamma: prompt_lookup.gamma,
+                    max_ngram_size: prompt_lookup.max_ngram_size,
                 },
             })
         } else {
@@ -7,8 +7,200 @@ use serde_json::Value;
 use std::collections::HashMap;
 use uuid::Uuid;
 
+const MISTRAL_TOOL_CALL_ID_LEN: usize = 9;
+
+/// Model families whose chat templates expect tool calls in a specific form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolCallingModel {
+    /// Llama 3.1 style, with `<|python_tag|>` tool calls.
+    Llama3,
+    /// Mistral-Instruct-v0.3 style, with `[TOOL_CALLS]` and `[TOOL_RESULTS]`. The template rejects
+    /// tool call ids which are not 9 alphanumeric characters.
+    Mistral,
+    /// Hermes-2-Pro style, with `<tool_call>` and `<tool_response>` tags.
+    Hermes,
+    /// Gemma 2, whose template does not support tools. The tools are described in the first user
+    /// message, and the model answers with the calls in a JSON code block.
+    Gemma2,
+    /// Phi-3 style, with the tools given in the system message between `<|tool|>` tags and the
+    /// calls emitted between `<|tool_call|>` tags.
+    Phi3,
+}
+
+impl ToolCallingModel {
+    /// Detect the model family from the tool calling markers in a chat template.
+    pub fn from_template(template: &str) -> Option<Self> {
+        if template.contains("[TOOL_CALLS]") {
+            Some(Self::Mistral)
+        } else if template.contains("<tool_call>") {
+            Some(Self::Hermes)
+        } else if template.contains("<|python_tag|>") {
+            Some(Self::Llama3)
+        } else if template.contains("<|tool|>") {
+            Some(Self::Phi3)
+        } else if template.contains("<start_of_turn>") {
+            Some(Self::Gemma2)
+        } else {
+            None
+        }
+    }
+
+    /// The tool call id in the form accepted by the chat template.
+    pub(crate) fn tool_call_id(&self, id: &str) -> String {
+        match self {
+            Self::Mistral => {
+                let id = id
+                    .chars()
+                    .filter(char::is_ascii_alphanumeric)
+                    .collect::<Vec<_>>();
+                let start = id.len().saturating_sub(MISTRAL_TOOL_CALL_ID_LEN);
+                format!(
+                    "{:0>width$}",
+                    id[start..].iter().collect::<String>(),
+                    width = MISTRAL_TOOL_CALL_ID_LEN
+                )
+            }
+            Self::Llama3 | Self::Hermes | Self::Gemma2 | Self::Phi3 => id.to_string(),
+        }
+    }
+}
+
+/// A tool call as emitted by the model. Llama 3 names the arguments `parameters`.
+#[derive(serde::Deserialize)]
+struct ParsedToolCall {
+    id: Option<String>,
+    name: String,
+    #[serde(alias = "parameters")]
+    arguments: Value,
+}
+
+impl ParsedToolCall {
+    fn into_response(self) -> anyhow::Result<ToolCallResponse> {
+        let arguments = match self.arguments {
+            // Some models emit the arguments as a JSON encoded string
+            Value::String(arguments) => {
+                serde_json::from_str::<Value>(&arguments).map_err(|e| {
+                    anyhow::anyhow!("Invalid arguments for tool `{}`: {e}", self.name)
+                })?;
+                arguments
+            }
+            arguments => serde_json::to_string(&arguments)?,
+        };
+        Ok(ToolCallResponse {
+            id: self
+                .id
+                .unwrap_or_else(|| format!("call-{}", Uuid::new_v4())),
+            tp: ToolCallType::Function,
+            function: CalledFunction {
+                name: self.name,
+                arguments,
+            },
+        })
+    }
+}
+
+/// Models may emit either a single call or a list of calls.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ParsedToolCalls {
+    One(ParsedToolCall),
+    Many(Vec<ParsedToolCall>),
+}
+
+impl ParsedToolCalls {
+    fn into_vec(self) -> Vec<ParsedToolCall> {
+        match self {
+            Self::One(call) => vec![call],
+            Self::Many(calls) => calls,
+        }
+    }
+}
+
+/// Parse the first JSON value of `text`, ignoring anything after it.
+fn parse_json_prefix<T: serde::de::DeserializeOwned>(text: &str) -> anyhow::Result<T> {
+    match serde_json::Deserializer::from_str(text)
+        .into_iter::<T>()
+        .next()
+    {
+        Some(value) => Ok(value?),
+        None => anyhow::bail!("Expected a tool call, got `{text}`."),
+    }
+}
+
+/// Extract the tool calls from the output of a model of the `model` family:
+/// - Llama 3: `<|python_tag|>{"name": ..., "parameters": ...}`, or only the JSON object
+/// - Mistral: `[TOOL_CALLS] [{"name": ..., "arguments": ...}, ...]`
+/// - Hermes: `<tool_call>{"arguments": ..., "name": ...}</tool_call>`, once per call
+/// - Gemma 2: a ```` ```json ```` code block with one call or a list of calls, or only the JSON
+/// - Phi-3: `<|tool_call|>[{"name": ..., "arguments": ...}, ...]<|/tool_call|>`, or only the JSON
+///
+/// Returns no calls if the output does not contain any, and an error if a call is malformed.
+pub fn parse_tool_calls(
+    text: &str,
+    model: ToolCallingModel,
+) -> anyhow::Result<Vec<ToolCallResponse>> {
+    let calls = match model {
+        ToolCallingModel::Llama3 => {
+            let text = text.trim();
+            let call = match text.strip_prefix("<|python_tag|>") {
+                Some(call) => call,
+                None if text.starts_with('{') => text,
+                None => return Ok(Vec::new()),
+            };
+            vec![parse_json_prefix::<ParsedToolCall>(call.trim_start())?]
+        }
+        ToolCallingModel::Mistral => match text.find("[TOOL_CALLS]") {
+            Some(start) => parse_json_prefix::<Vec<ParsedToolCall>>(
+                text[start + "[TOOL_CALLS]".len()..].trim_start(),
+            )?,
+            None => return Ok(Vec::new()),
+        },
+        ToolCallingModel::Hermes => {
+            let mut calls = Vec::new();
+            let mut rest = text;
+            while let Some(start) = rest.find("<tool_call>") {
+                rest = &rest[start + "<tool_call>".len()..];
+                let end = rest.find("</tool_call>").unwrap_or(rest.len());
+                calls.push(parse_json_prefix::<ParsedToolCall>(rest[..end].trim())?);
+                rest = &rest[end..];
+            }
+            calls
+        }
+        ToolCallingModel::Gemma2 => {
+            let text = text.trim();
+            let calls = match text.find("```") {
+                Some(start) => {
+                    let block = &text[start + "```".len()..];
+                    let block = block.strip_prefix("json").unwrap_or(block);
+                    &block[..block.find("```").unwrap_or(block.len())]
+                }
+                None if text.starts_with(['{', '[']) => text,
+                None => return Ok(Vec::new()),
+            };
+            parse_json_prefix::<ParsedToolCalls>(calls.trim())?.into_vec()
+        }
+        ToolCallingModel::Phi3 => {
+            let text = text.trim();
+            let calls = match text.find("<|tool_call|>") {
+                Some(start) => {
+                    let calls = &text[start + "<|tool_call|>".len()..];
+                    &calls[..calls.find("<|/tool_call|>").unwrap_or(calls.len())]
+                }
+                None if text.starts_with(['{', '[']) => text,
+                None => return Ok(Vec::new()),
+            };
+            parse_json_prefix::<ParsedToolCalls>(calls.trim())?.into_vec()
+        }
+    };
+    calls
+        .into_iter()
+        .map(ParsedToolCall::into_response)
+        .collect()
+}
+
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
+    model: Option<ToolCallingModel>,
 }
 
 // Same as CalledFunction, but uses `parameters`
@@ -26,8 +218,9 @@ pub struct CalledFunctionArguments {
 }
 
 impl ToolCallingMatcher {
-    pub fn new(tool_choice: ToolChoice) -> anyhow::Result<Self> {
-        Ok(Self { tool_choice })
+    /// If the model family is known, its tool call format is tried before plain JSON calls.
+    pub fn new(tool_choice: ToolChoice, model: Option<ToolCallingModel>) -> anyhow::Result<Self> {
+        Ok(Self { tool_choice, model })
     }
 
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
@@ -35,6 +228,13 @@ impl ToolCallingMatcher {
             return Ok(Vec::new());
         }
 
+        // A malformed call falls back to the plain JSON formats, and then to a text answer.
+        if let Some(Ok(calls)) = self.model.map(|model| parse_tool_calls(message, model)) {
+            if !calls.is_empty() {
+                return Ok(calls);
+            }
+        }
+
         if let Ok(deser) = serde_json::from_str::<CalledFunctionParameters>(message) {
             let id = format!("call-{}", Uuid::new_v4());
             Ok(vec![ToolCallResponse {
@@ -93,3 +293,117 @@ impl ToolCallingMatcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tool_calls, ToolCallingMatcher, ToolCallingModel, ToolChoice};
+
+    #[test]
+    fn test_parse_tool_calls() {
+        let outputs = [
+            (
+                ToolCallingModel::Llama3,
+                r#"<|python_tag|>{"name": "get_current_weather", "parameters": {"location": "San Francisco, CA", "unit": "celsius"}}"#,
+            ),
+            (
+                ToolCallingModel::Mistral,
+                r#"[TOOL_CALLS] [{"name": "get_current_weather", "arguments": {"location": "San Francisco, CA", "unit": "celsius"}}]"#,
+            ),
+            (
+                ToolCallingModel::Hermes,
+                "<tool_call>\n{\"arguments\": {\"location\": \"San Francisco, CA\", \"unit\": \"celsius\"}, \"name\": \"get_current_weather\"}\n</tool_call>",
+            ),
+            (
+                ToolCallingModel::Gemma2,
+                "```json\n[{\"name\": \"get_current_weather\", \"arguments\": {\"location\": \"San Francisco, CA\", \"unit\": \"celsius\"}}]\n```",
+            ),
+            (
+                ToolCallingModel::Phi3,
+                r#"<|tool_call|>[{"name": "get_current_weather", "arguments": {"location": "San Francisco, CA", "unit": "celsius"}}]<|/tool_call|>"#,
+            ),
+        ];
+        for (model, output) in outputs {
+            let calls = parse_tool_calls(output, model).unwrap();
+            assert_eq!(calls.len(), 1, "{model:?}");
+            assert_eq!(calls[0].function.name, "get_current_weather");
+            let arguments: serde_json::Value =
+                serde_json::from_str(&calls[0].function.arguments).unwrap();
+            assert_eq!(
+                arguments,
+                serde_json::json!({"location": "San Francisco, CA", "unit": "celsius"})
+            );
+
+            // No tool call in a plain answer
+            let calls = parse_tool_calls("The weather in San Francisco is sunny.", model).unwrap();
+            assert!(calls.is_empty(), "{model:?}");
+        }
+
+        // Several calls, with the ids of the model kept
+        let calls = parse_tool_calls(
+            r#"[TOOL_CALLS] [{"name": "get_current_weather", "arguments": {"location": "Paris"}, "id": "3f9a1c2b7"}, {"name": "get_current_time", "arguments": {"timezone": "CET"}, "id": "8d2e4f6a1"}]"#,
+            ToolCallingModel::Mistral,
+        )
+        .unwrap();
+        assert_eq!(
+            calls.iter().map(|c| c.id.as_str()).collect::<Vec<_>>(),
+            ["3f9a1c2b7", "8d2e4f6a1"]
+        );
+        let calls = parse_tool_calls(
+            "<tool_call>\n{\"arguments\": {\"location\": \"Paris\"}, \"name\": \"get_current_weather\"}\n</tool_call>\n<tool_call>\n{\"arguments\": {\"timezone\": \"CET\"}, \"name\": \"get_current_time\"}\n</tool_call>",
+            ToolCallingModel::Hermes,
+        )
+        .unwrap();
+        assert_eq!(
+            calls
+                .iter()
+                .map(|c| c.function.name.as_str())
+                .collect::<Vec<_>>(),
+            ["get_current_weather", "get_current_time"]
+        );
+
+        // A single call in a code block, and a list of calls without the tags
+        let calls = parse_tool_calls(
+            "I will check the weather.\n```json\n{\"name\": \"get_current_weather\", \"arguments\": {\"location\": \"Paris\"}}\n```",
+            ToolCallingModel::Gemma2,
+        )
+        .unwrap();
+        assert_eq!(calls.len(), 1);
+        let calls = parse_tool_calls(
+            r#"[{"name": "get_current_weather", "arguments": {"location": "Paris"}}, {"name": "get_current_time", "arguments": {"timezone": "CET"}}]"#,
+            ToolCallingModel::Phi3,
+        )
+        .unwrap();
+        assert_eq!(calls.len(), 2);
+
+        // Malformed calls and arguments are errors
+        assert!(parse_tool_calls(
+            r#"<|python_tag|>{"name": "get_current_weather", "parameters": {"location": }"#,
+            ToolCallingModel::Llama3
+        )
+        .is_err());
+        assert!(parse_tool_calls(
+            "<tool_call>\n{\"arguments\": \"{location: Paris}\", \"name\": \"get_current_weather\"}\n</tool_call>",
+            ToolCallingModel::Hermes
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_matcher_model_format() {
+        let matcher =
+            ToolCallingMatcher::new(ToolChoice::Auto, Some(ToolCallingModel::Phi3)).unwrap();
+        let calls = matcher
+            .get_call(r#"<|tool_call|>[{"name": "get_current_weather", "arguments": {"location": "Paris"}}]<|/tool_call|>"#)
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_current_weather");
+
+        // A malformed call is returned as text
+        let calls = matcher
+            .get_call(
+                r#"<|tool_call|>[{"name": "get_current_weather", "arguments": }]<|/tool_call|>"#,
+            )
+            .unwrap();
+        assert!(calls.is_empty());
+    }
+}
@@ -142,6 +142,7 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            prompt_token_ids: seq.prompt_token_ids().map(<[u32]>::to_vec),
                         };
 
                         seq.responder()
@@ -160,6 +161,7 @@ macro_rules! handle_pipeline_forward_error {
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            prompt_token_ids: seq.prompt_token_ids().map(<[u32]>::to_vec),
                         };
 
                         seq.responder()
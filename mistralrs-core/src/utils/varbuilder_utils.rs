@@ -260,7 +260,10 @@ impl LoadTensors for XLora {
         tensors
             .filter(|name| !name.contains("internal_xlora_classifier"))
             .map(|name| {
-                let mut new_name = name.replace("base_model.model.model", "model");
+                // Adapters for the language model of a LLaVA model
+                let mut new_name = name
+                    .replace("base_model.model.model", "model")
+                    .replace("base_model.model.language_model", "language_model");
                 // TODO: Add better context to describe intent / requirement:
                 let pos = new_name.find(".lora").expect(expectation);
                 new_name.insert_str(pos + 7, &format!(".{}", self.adapter_index));
@@ -1,10 +1,13 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 // Sourced from https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/clip/vision_model.rs
+use std::sync::Arc;
+
 use candle_core::{IndexOp, Result, Shape, Tensor, D};
 use candle_nn::{Conv2dConfig, Module};
+use mistralrs_quant::QuantMethod;
 
-use crate::{layers::FusedBiasLinear, serde_default_fn, utils::unvarbuilder::UnVarBuilder};
+use crate::{serde_default_fn, utils::unvarbuilder::UnVarBuilder};
 
 #[derive(Debug, Clone, Copy, serde::Deserialize)]
 pub enum Activation {
@@ -110,10 +113,10 @@ impl Module for ClipVisionEmbeddings {
 
 #[derive(Clone, Debug)]
 struct ClipAttention {
-    k_proj: FusedBiasLinear,
-    v_proj: FusedBiasLinear,
-    q_proj: FusedBiasLinear,
-    out_proj: FusedBiasLinear,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    q_proj: Arc<dyn QuantMethod>,
+    out_proj: Arc<dyn QuantMethod>,
     head_dim: usize,
     scale: f64,
     num_attention_heads: usize,
@@ -123,18 +126,18 @@ impl ClipAttention {
     fn new(vs: candle_nn::VarBuilder, c: &ClipConfig) -> Result<Self> {
         let hidden_size = c.hidden_size;
         let num_attention_heads = c.num_attention_heads;
-        let k_proj = candle_nn::linear(hidden_size, hidden_size, vs.pp("k_proj"))?;
-        let v_proj = candle_nn::linear(hidden_size, hidden_size, vs.pp("v_proj"))?;
-        let q_proj = candle_nn::linear(hidden_size, hidden_size, vs.pp("q_proj"))?;
-        let out_proj = candle_nn::linear(hidden_size, hidden_size, vs.pp("out_proj"))?;
+        let k_proj = mistralrs_quant::linear(hidden_size, hidden_size, &None, vs.pp("k_proj"))?;
+        let v_proj = mistralrs_quant::linear(hidden_size, hidden_size, &None, vs.pp("v_proj"))?;
+        let q_proj = mistralrs_quant::linear(hidden_size, hidden_size, &None, vs.pp("q_proj"))?;
+        let out_proj = mistralrs_quant::linear(hidden_size, hidden_size, &None, vs.pp("out_proj"))?;
         let head_dim = hidden_size / num_attention_heads;
         let scale = (head_dim as f64).powf(-0.5);
 
         Ok(ClipAttention {
-            k_proj: k_proj.try_into()?,
-            v_proj: v_proj.try_into()?,
-            q_proj: q_proj.try_into()?,
-            out_proj: out_proj.try_into()?,
+            k_proj,
+            v_proj,
+            q_proj,
+            out_proj,
             head_dim,
             scale,
             num_attention_heads,
@@ -150,17 +153,27 @@ impl ClipAttention {
     fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let (bsz, seq_len, hidden_size) = xs.dims3()?;
 
-        let query_states = (self.q_proj.forward(xs)? * self.scale)?;
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let mut q = self.q_proj.forward(&xs)?;
+        let mut k = self.k_proj.forward(&xs)?;
+        let mut v = self.v_proj.forward(&xs)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            q = q.to_dtype(original_dtype)?;
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
+
+        let query_states = (q * self.scale)?;
         let proj_shape = (bsz * self.num_attention_heads, seq_len, self.head_dim);
         let query_states = self
             .shape(&query_states, seq_len, bsz)?
             .reshape(proj_shape)?;
-        let key_states = self
-            .shape(&self.k_proj.forward(xs)?, seq_len, bsz)?
-            .reshape(proj_shape)?;
-        let value_states = self
-            .shape(&self.v_proj.forward(xs)?, seq_len, bsz)?
-            .reshape(proj_shape)?;
+        let key_states = self.shape(&k, seq_len, bsz)?.reshape(proj_shape)?;
+        let value_states = self.shape(&v, seq_len, bsz)?.reshape(proj_shape)?;
         let attn_weights = query_states.matmul(&key_states.transpose(1, 2)?)?;
 
         let src_len = key_states.dim(1)?;
@@ -177,29 +190,36 @@ impl ClipAttention {
         let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
 
         let attn_output = attn_weights.matmul(&value_states)?;
-        let attn_output = attn_output
+        let mut attn_output = attn_output
             .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
             .transpose(1, 2)?
             .reshape((bsz, seq_len, hidden_size))?;
-        self.out_proj.forward(&attn_output)
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        let mut res = self.out_proj.forward(&attn_output)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
     }
 }
 
 #[derive(Clone, Debug)]
 struct ClipMlp {
-    fc1: FusedBiasLinear,
-    fc2: FusedBiasLinear,
+    fc1: Arc<dyn QuantMethod>,
+    fc2: Arc<dyn QuantMethod>,
     activation: Activation,
 }
 
 impl ClipMlp {
     fn new(vs: candle_nn::VarBuilder, c: &ClipConfig) -> Result<Self> {
-        let fc1 = candle_nn::linear(c.hidden_size, c.intermediate_size, vs.pp("fc1"))?;
-        let fc2 = candle_nn::linear(c.intermediate_size, c.hidden_size, vs.pp("fc2"))?;
+        let fc1 = mistralrs_quant::linear(c.hidden_size, c.intermediate_size, &None, vs.pp("fc1"))?;
+        let fc2 = mistralrs_quant::linear(c.intermediate_size, c.hidden_size, &None, vs.pp("fc2"))?;
 
         Ok(ClipMlp {
-            fc1: fc1.try_into()?,
-            fc2: fc2.try_into()?,
+            fc1,
+            fc2,
             activation: c.hidden_act,
         })
     }
@@ -207,8 +227,17 @@ impl ClipMlp {
 
 impl ClipMlp {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let xs = self.fc1.forward(xs)?;
-        self.fc2.forward(&self.activation.forward(&xs)?)
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.fc1.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let xs = self.fc1.forward(&xs)?;
+        let mut res = self.fc2.forward(&self.activation.forward(&xs)?)?;
+        if self.fc1.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
     }
 }
 
@@ -317,6 +346,21 @@ impl ClipVisionTransformer {
         Ok(result)
     }
 
+    /// The linear layers of the encoder, for ISQ. The embeddings and layer norms are not
+    /// quantized and are returned by [`ClipVisionTransformer::residual_tensors`].
+    pub fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        let mut layers = Vec::new();
+        for layer in &mut self.encoder.layers {
+            layers.push(&mut layer.self_attn.q_proj);
+            layers.push(&mut layer.self_attn.k_proj);
+            layers.push(&mut layer.self_attn.v_proj);
+            layers.push(&mut layer.self_attn.out_proj);
+            layers.push(&mut layer.mlp.fc1);
+            layers.push(&mut layer.mlp.fc2);
+        }
+        layers
+    }
+
     pub fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb = UnVarBuilder::new();
 
@@ -345,16 +389,6 @@ impl ClipVisionTransformer {
 
                 uvb_l.pp("layer_norm1").add(&layer.layer_norm1);
                 uvb_l.pp("layer_norm2").add(&layer.layer_norm2);
-
-                let uvb_mlp = uvb_l.pp("mlp");
-                uvb_mlp.pp("fc1").add(&layer.mlp.fc1);
-                uvb_mlp.pp("fc2").add(&layer.mlp.fc2);
-
-                let uvb_attn = uvb_l.pp("self_attn");
-                uvb_attn.pp("q_proj").add(&layer.self_attn.q_proj);
-                uvb_attn.pp("k_proj").add(&layer.self_attn.k_proj);
-                uvb_attn.pp("v_proj").add(&layer.self_attn.v_proj);
-                uvb_attn.pp("out_proj").add(&layer.self_attn.out_proj);
             }
         }
 
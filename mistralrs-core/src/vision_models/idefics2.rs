@@ -191,6 +191,7 @@ impl From<TextConfig> for mistral::Config {
             head_dim: None,
             quantization_config: None,
             tie_word_embeddings: false,
+            rope_scaling: None,
         }
     }
 }
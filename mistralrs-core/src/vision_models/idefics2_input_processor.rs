@@ -204,6 +204,7 @@ impl InputsProcessor for Idefics2ImageProcessor {
                     pixel_values,
                     pixel_attention_mask,
                     image_sizes: _,
+                    image_sizes_all: _,
                     num_img_tokens: _,
                     aspect_ratio_ids: _,
                     aspect_ratio_mask: _,
@@ -360,6 +361,7 @@ impl ImagePreProcessor for Idefics2ImageProcessor {
             pixel_values: Tensor::cat(&pixel_values, 0)?,
             pixel_attention_mask: Some(Tensor::cat(&patch_masks, 0)?),
             image_sizes: None,
+            image_sizes_all: None,
             num_img_tokens: None,
             aspect_ratio_ids: None,
             aspect_ratio_mask: None,
@@ -14,6 +14,8 @@ pub(crate) struct PreprocessedImages {
     /// Without batch size, safe to unsqueeze & concat in dim0
     pub(crate) pixel_attention_mask: Option<Tensor>,
     pub(crate) image_sizes: Option<(usize, usize)>,
+    /// Size of each image, for processors which handle several images per sequence
+    pub(crate) image_sizes_all: Option<Vec<(u32, u32)>>,
     pub(crate) num_img_tokens: Option<Vec<usize>>,
     /// Without batch size, safe to unsqueeze & concat in dim0
     pub(crate) aspect_ratio_ids: Option<Tensor>,
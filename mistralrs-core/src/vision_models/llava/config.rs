@@ -1,6 +1,6 @@
 use serde::Deserialize;
 
-use crate::layers::{Activation, Llama3RopeConfig};
+use crate::layers::{Activation, LlamaRopeScalingConfig};
 use crate::serde_default_fn;
 
 use crate::models::llama::Config as LLaMAConfig;
@@ -43,7 +43,7 @@ pub struct LLaVATextConfig {
     #[serde(default = "default_vocab_size")]
     pub vocab_size: usize,
     pub sliding_window: Option<usize>,
-    pub rope_scaling: Option<Llama3RopeConfig>,
+    pub rope_scaling: Option<LlamaRopeScalingConfig>,
 }
 
 serde_default_fn!(usize, default_num_hidden_layers, 32);
@@ -101,6 +101,10 @@ impl Config {
             head_dim: None,
             quantization_config: None,
             tie_word_embeddings: false,
+            rope_scaling: match &self.text_config.rope_scaling {
+                Some(LlamaRopeScalingConfig::Scaled(rope_scaling)) => Some(rope_scaling.clone()),
+                Some(LlamaRopeScalingConfig::Llama3(_)) | None => None,
+            },
         }
     }
 
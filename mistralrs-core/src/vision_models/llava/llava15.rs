@@ -3,7 +3,9 @@
     clippy::cast_precision_loss,
     clippy::too_many_arguments
 )]
-use super::llava_llm::{LLaVALLM, Llama, Mistral};
+use std::sync::Arc;
+
+use super::llava_llm::{LLaVAAdapters, LLaVALLM, Llama, Mistral, XLoraMistral};
 use crate::amoe::AnyMoeBaseModelMixin;
 use crate::amoe::MlpLayer;
 use crate::device_map::DeviceMapper;
@@ -20,21 +22,23 @@ use crate::vision_models::llava::config::Config;
 use crate::AnyMoeConfig;
 use crate::AnyMoeExpertType;
 use candle_core::{bail, DType, Device, IndexOp, Result, Tensor};
-use candle_nn::{linear, Activation, Linear, VarBuilder};
+use candle_nn::{Activation, Module, VarBuilder};
+use mistralrs_quant::QuantMethod;
 
 pub(crate) struct LLaVAVisionSpecificArgs; // only a dumb struct to satisfy the trait
 
 pub struct MMProjector {
-    linear_1: Linear,
+    linear_1: Arc<dyn QuantMethod>,
     activation: Activation,
-    linear_2: Linear,
+    linear_2: Arc<dyn QuantMethod>,
 }
 
 impl MMProjector {
     pub fn new(vb: &VarBuilder, config: &Config, device: &Device) -> Result<Self> {
-        let linear_1 = linear(
+        let linear_1 = mistralrs_quant::linear(
             config.vision_config.hidden_size,
             config.text_config.hidden_size,
+            &None,
             vb.pp("multi_modal_projector.linear_1")
                 .set_device(device.clone()),
         )?;
@@ -47,9 +51,10 @@ impl MMProjector {
                 );
             }
         };
-        let linear_2 = linear(
+        let linear_2 = mistralrs_quant::linear(
             config.text_config.hidden_size,
             config.text_config.hidden_size,
+            &None,
             vb.pp("multi_modal_projector.linear_2")
                 .set_device(device.clone()),
         )?;
@@ -61,9 +66,18 @@ impl MMProjector {
     }
 
     pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
-        x.apply(&self.linear_1)?
-            .apply(&self.activation)?
-            .apply(&self.linear_2)
+        let original_dtype = x.dtype();
+        let mut x = x.clone();
+        if let Some(t) = self.linear_1.quantized_act_type() {
+            x = x.to_dtype(t)?;
+        }
+        let mut res = self
+            .linear_2
+            .forward(&self.activation.forward(&self.linear_1.forward(&x)?)?)?;
+        if self.linear_1.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
     }
 }
 
@@ -116,12 +130,15 @@ pub struct Model {
 }
 
 impl Model {
-    pub fn new(
+    /// If `adapters` are given, the language model is loaded with LoRA or X-LoRA adapters. This is
+    /// only supported for a Mistral language model.
+    pub(crate) fn new(
         config: &Config,
         vb: VarBuilder,
         is_gptx: bool,
         normal_loading_metadata: NormalLoadingMetadata,
         attention_mechanism: AttentionImplementation,
+        adapters: Option<LLaVAAdapters>,
     ) -> Result<Self> {
         let device = normal_loading_metadata.real_device.clone();
         let dtype = vb.dtype();
@@ -135,8 +152,20 @@ impl Model {
             &clip_config,
         )?;
 
-        let llm: Box<dyn LLaVALLM> = match config.text_config.model_type.as_str() {
-            "llama" => {
+        let llm: Box<dyn LLaVALLM> = match (config.text_config.model_type.as_str(), adapters) {
+            ("mistral", Some(adapters)) => Box::new(XLoraMistral::new(
+                &config.to_mistral_config(),
+                vb,
+                adapters.lora_config,
+                adapters.xlora_config,
+                adapters.xlora_ordering,
+                normal_loading_metadata,
+                adapters.preload_adapters,
+            )?),
+            (model_type, Some(_)) => {
+                bail!("Adapters are not supported for a `{model_type}` LLaVA language model.");
+            }
+            ("llama", None) => {
                 let llama_config = config.to_llama_config();
                 let llama = Llama::new(
                     &llama_config,
@@ -147,7 +176,7 @@ impl Model {
                 )?;
                 Box::new(llama)
             }
-            "mistral" => {
+            ("mistral", None) => {
                 let mistral_config = config.to_mistral_config();
                 let mistral = Mistral::new(
                     &mistral_config,
@@ -268,18 +297,27 @@ impl IsqModel for Model {
         )>,
         &dyn DeviceMapper,
     ) {
-        self.llm.get_layers()
+        let (mut layers, mapper) = self.llm.get_layers();
+        // The projector and vision tower are not device mapped, they are on the base device.
+        layers.push((&mut self.mm_projector.linear_1, None));
+        layers.push((&mut self.mm_projector.linear_2, None));
+        layers.extend(
+            self.clip_vision_tower
+                .model
+                .get_isq_layers()
+                .into_iter()
+                .map(|layer| (layer, None)),
+        );
+        (layers, mapper)
+    }
+
+    fn has_vision_isq_layers(&self) -> bool {
+        true
     }
 
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb = UnVarBuilder::new();
 
-        // MM projectors
-        uvb.pp("multi_modal_projector.linear_1")
-            .add(&self.mm_projector.linear_1);
-        uvb.pp("multi_modal_projector.linear_2")
-            .add(&self.mm_projector.linear_2);
-
         // Vision tower
         {
             let uvb_vt = uvb.pp("vision_tower.vision_model");
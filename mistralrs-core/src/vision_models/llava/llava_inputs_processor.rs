@@ -127,6 +127,7 @@ impl InputsProcessor for LLaVAInputProcessor {
                     pixel_values,
                     pixel_attention_mask: _,
                     image_sizes: _,
+                    image_sizes_all: _,
                     num_img_tokens,
                     aspect_ratio_ids: _,
                     aspect_ratio_mask: _,
@@ -370,6 +371,7 @@ impl ImagePreProcessor for LLaVAInputProcessor {
             pixel_values,
             pixel_attention_mask: None,
             image_sizes: Some((original_size.0 as usize, original_size.1 as usize)),
+            image_sizes_all: None,
             num_img_tokens: Some(vec![self.get_num_image_tokens()]),
             aspect_ratio_ids: None,
             aspect_ratio_mask: None,
@@ -16,14 +16,14 @@ use crate::{
     attention::SdpaParams,
     device_map::DeviceMapper,
     get_delta_from_lora_ab,
-    layers::{CausalMasker, MatMul, RmsNorm, Sdpa},
+    layers::{CausalMasker, LlamaRopeScalingConfig, MatMul, RmsNorm, Sdpa},
     layers_masker::PastKvLenCache,
     models::llama::Config,
     paged_attention::{AttentionImplementation, ModelConfigMetadata, PagedAttention},
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        IsqModel, NormalLoadingMetadata, NormalModel,
+        IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
     AnyMoeConfig, AnyMoeExpertType,
@@ -53,6 +53,7 @@ impl CausalSelfAttention {
         _start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KVCacheDtype,
         rope_parameter: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -102,8 +103,13 @@ impl CausalSelfAttention {
                 )?
             }
             None => {
-                let (k, v) =
-                    crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+                let (k, v) = crate::pipeline::Cache::update_kv_cache(
+                    &mut kv_cache[block_idx],
+                    k,
+                    v,
+                    false,
+                    kv_cache_dtype,
+                )?;
 
                 Sdpa.run_attention(
                     &q,
@@ -291,6 +297,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut crate::pipeline::LayerCaches,
+        kv_cache_dtype: KVCacheDtype,
         rope_parameters: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -304,6 +311,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             rope_parameters,
             metadata,
             flash_params,
@@ -435,10 +443,15 @@ impl Llama {
                     .expect("Failed to load block.")
                 })
                 .collect();
+        let rope_scaling = match &cfg.rope_scaling {
+            Some(LlamaRopeScalingConfig::Scaled(rope_scaling)) => Some(rope_scaling),
+            Some(LlamaRopeScalingConfig::Llama3(_)) | None => None,
+        };
         let rope_parameters = OrdinaryRoPE::create_parameters(
             head_dim,
             cfg.max_position_embeddings,
             cfg.rope_theta,
+            rope_scaling,
             vb.dtype(),
             &normal_loading_metadata.real_device,
         )?;
@@ -509,6 +522,7 @@ impl LLaVALLM for Llama {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut x = input_embed;
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mut cache = self.kv_cache.lock();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
@@ -528,6 +542,7 @@ impl LLaVALLM for Llama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 (&self.rope_parameters.0, &self.rope_parameters.1),
                 metadata
                     .as_mut()
@@ -18,7 +18,7 @@ use crate::{
     pipeline::{
         extract_logits,
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        Cache, IsqModel, NormalLoadingMetadata, NormalModel,
+        Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
     },
     utils::progress::NiceProgressBar,
     AnyMoeConfig, AnyMoeExpertType,
@@ -200,6 +200,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         rope_parameter: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -256,6 +257,7 @@ impl Attention {
                     attention_mask,
                     self.sliding_window,
                     false,
+                    kv_cache_dtype,
                 )?;
 
                 Sdpa.run_attention(
@@ -333,6 +335,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         rope_parameter: (&Tensor, &Tensor),
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
         flash_params: &FlashParams,
@@ -345,6 +348,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             rope_parameter,
             metadata,
             flash_params,
@@ -413,6 +417,7 @@ impl Model {
             head_dim,
             cfg.max_position_embeddings,
             cfg.rope_theta as f32,
+            cfg.rope_scaling.as_ref(),
             vb_m.dtype(),
             &normal_loading_metadata.real_device,
         )?;
@@ -509,6 +514,7 @@ impl Model {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut xs = input_embeds;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = self.cache.lock();
         let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
             input_ids,
@@ -531,6 +537,7 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 (&self.rope_parameters.0, &self.rope_parameters.1),
                 metadata
                     .as_mut()
@@ -1,9 +1,17 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+use std::collections::HashMap;
+
 use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
 
-use crate::pipeline::{
-    text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-    IsqModel, NormalModel,
+use crate::{
+    layers::RopeScalingConfig,
+    lora::{LoraConfig, Ordering},
+    pipeline::{
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        IsqModel, NormalModel,
+    },
+    xlora_models::XLoraConfig,
 };
 
 pub(crate) trait LLaVALLM: IsqModel + NormalModel + Sync + Send {
@@ -22,6 +30,14 @@ pub(crate) trait LLaVALLM: IsqModel + NormalModel + Sync + Send {
     ) -> Result<Tensor>;
 }
 
+/// LoRA or X-LoRA adapters for the language model of a LLaVA model.
+pub(crate) struct LLaVAAdapters<'a, 'b> {
+    pub(crate) lora_config: &'a [((String, String), LoraConfig)],
+    pub(crate) xlora_config: Option<XLoraConfig>,
+    pub(crate) xlora_ordering: Ordering,
+    pub(crate) preload_adapters: &'a Option<HashMap<String, (VarBuilder<'b>, LoraConfig)>>,
+}
+
 #[derive(Debug)]
 pub(crate) struct OrdinaryRoPE;
 
@@ -30,20 +46,30 @@ impl OrdinaryRoPE {
         n_elem: usize,
         max_seq_len: usize,
         rope_theta: f32,
+        rope_scaling: Option<&RopeScalingConfig>,
         dtype: DType,
         device: &Device,
     ) -> Result<(Tensor, Tensor)> {
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / rope_theta.powf(i as f32 / n_elem as f32))
-            .collect();
+        let (theta, mscale) = match rope_scaling {
+            Some(rope_scaling) => (
+                rope_scaling.inv_freq(rope_theta, n_elem, max_seq_len),
+                rope_scaling.mscale(),
+            ),
+            None => (
+                (0..n_elem)
+                    .step_by(2)
+                    .map(|i| 1f32 / rope_theta.powf(i as f32 / n_elem as f32))
+                    .collect(),
+                1.,
+            ),
+        };
         let theta = Tensor::new(theta.as_slice(), device)?;
         let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
             .to_dtype(DType::F32)?
             .reshape((max_seq_len, 1))?
             .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+        let cos = (idx_theta.cos()? * mscale)?.to_dtype(dtype)?;
+        let sin = (idx_theta.sin()? * mscale)?.to_dtype(dtype)?;
         Result::Ok((cos, sin))
     }
     fn forward(x: &Tensor, index_pos: usize, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
@@ -55,6 +81,8 @@ impl OrdinaryRoPE {
 }
 pub(crate) mod llama;
 pub(crate) mod mistral;
+pub(crate) mod xlora_mistral;
 
 pub use llama::Llama;
 pub use mistral::Model as Mistral;
+pub(crate) use xlora_mistral::XLoraModel as XLoraMistral;
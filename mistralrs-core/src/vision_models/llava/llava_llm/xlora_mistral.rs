@@ -0,0 +1,1082 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::{collections::HashMap, sync::Arc};
+
+/// Mistral LLM with X-LoRA adapters, https://github.com/mistralai/mistral-src
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::VarBuilder;
+use mistralrs_quant::QuantMethod;
+use tqdm::Iter;
+use tracing::info;
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    attention::SdpaParams,
+    device_map::DeviceMapper,
+    layers::{Activation, CausalMasker, RmsNorm, Sdpa},
+    lora::{linear_no_bias, LinearLayerLike, LoraConfig, Ordering},
+    models::mistral::Config,
+    paged_attention::ModelConfigMetadata,
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata, NormalModel,
+    },
+    utils::progress::NiceProgressBar,
+    xlora_models::{NonGranularState, ScalingsMaker, XLoraClassifier, XLoraConfig},
+};
+
+use super::{LLaVALLM, OrdinaryRoPE};
+
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    up_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    down_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    act_fn: Activation,
+}
+
+impl MLP {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        count: &mut usize,
+        ord: &Ordering,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            mapper.set_device(layer_idx, vb.pp("gate_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("gate_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        let up_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            mapper.set_device(layer_idx, vb.pp("up_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("up_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        let down_proj = linear_no_bias(
+            intermediate_sz,
+            hidden_sz,
+            mapper.set_device(layer_idx, vb.pp("down_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("down_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+    ) -> Result<Tensor> {
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.gate_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let lhs = self
+            .gate_proj
+            .lora_forward(
+                &xs,
+                scalings.clone(),
+                global_scaling_weight,
+                is_scaling_pass,
+            )?
+            .apply(&self.act_fn)?;
+        let rhs = self.up_proj.lora_forward(
+            &xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        let mut res = self.down_proj.lora_forward(
+            &(lhs * rhs)?,
+            scalings,
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.gate_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+struct Attention {
+    q_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    k_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    v_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    o_proj: Arc<dyn LinearLayerLike + Send + Sync>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    sliding_window: Option<usize>,
+    sdpa_params: SdpaParams,
+}
+
+impl Attention {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        count: &mut usize,
+        ord: &Ordering,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = cfg.head_dim();
+        let q_proj = linear_no_bias(
+            hidden_sz,
+            num_heads * head_dim,
+            mapper.set_device(layer_idx, vb.pp("q_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("q_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        let k_proj = linear_no_bias(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            mapper.set_device(layer_idx, vb.pp("k_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("k_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        let v_proj = linear_no_bias(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            mapper.set_device(layer_idx, vb.pp("v_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("v_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        let o_proj = linear_no_bias(
+            num_heads * head_dim,
+            hidden_sz,
+            mapper.set_device(layer_idx, vb.pp("o_proj"), loading_isq),
+            mapper.set_device(layer_idx, vb.pp("o_proj"), false),
+            lora_config,
+            count,
+            ord,
+            preload_adapters,
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            sliding_window: cfg.sliding_window,
+            sdpa_params: SdpaParams {
+                n_kv_groups: num_heads / num_kv_heads,
+                use_flash_attn: cfg.use_flash_attn,
+                softcap: None,
+                softmax_scale: 1.0 / (head_dim as f32).sqrt(),
+                sliding_window: cfg.sliding_window,
+            },
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
+        rope_parameter: (&Tensor, &Tensor),
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let original_dtype = xs.dtype();
+        let mut xs = xs.clone();
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        let mut q = self.q_proj.lora_forward(
+            &xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        let mut k = self.k_proj.lora_forward(
+            &xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        let mut v = self.v_proj.lora_forward(
+            &xs,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.q_proj.quantized_act_type().is_some() {
+            q = q.to_dtype(original_dtype)?;
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
+
+        let mut q = q
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let mut k = k
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        q = OrdinaryRoPE::forward(&q, seqlen_offsets[0], rope_parameter.0, rope_parameter.1)?;
+        k = OrdinaryRoPE::forward(&k, seqlen_offsets[0], rope_parameter.0, rope_parameter.1)?;
+        let v = v
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (k, v, attn_mask) = Cache::update_kv_cache_sliding_window(
+            kv_cache,
+            k,
+            v,
+            attention_mask,
+            self.sliding_window,
+            false,
+            kv_cache_dtype,
+        )?;
+
+        let mut attn_output = Sdpa.run_attention(
+            &q,
+            &k,
+            &v,
+            attn_mask.as_ref(),
+            Some(flash_params),
+            &self.sdpa_params,
+        )?;
+
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        let mut res = self.o_proj.lora_forward(
+            &attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?,
+            scalings,
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        if self.q_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        count: &mut usize,
+        ord: &Ordering,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(
+            cfg,
+            vb.pp("self_attn"),
+            lora_config,
+            count,
+            ord,
+            mapper,
+            layer_idx,
+            loading_isq,
+            preload_adapters,
+        )?;
+        let mlp = MLP::new(
+            cfg,
+            vb.pp("mlp"),
+            lora_config,
+            count,
+            ord,
+            mapper,
+            layer_idx,
+            loading_isq,
+            preload_adapters,
+        )?;
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
+        rope_parameter: (&Tensor, &Tensor),
+        scalings: Option<Tensor>,
+        global_scaling_weight: f64,
+        is_scaling_pass: Option<f64>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(
+            &xs,
+            attention_mask,
+            seqlen_offsets,
+            kv_cache,
+            kv_cache_dtype,
+            rope_parameter,
+            scalings.clone(),
+            global_scaling_weight,
+            is_scaling_pass,
+            flash_params,
+        )?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = self.mlp.forward(
+            &xs.apply(&self.post_attention_layernorm)?,
+            scalings,
+            global_scaling_weight,
+            is_scaling_pass,
+        )?;
+        residual + xs
+    }
+}
+
+/// The LLaVA Mistral language model with LoRA or X-LoRA adapters. The scaling and scaled passes
+/// run on input embeddings, so that image features are seen by the X-LoRA classifier as well.
+pub struct XLoraModel {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Arc<dyn LinearLayerLike + Send + Sync>,
+    sliding_window: Option<usize>,
+    dtype: DType,
+    device: Device,
+    cache: Cache,
+    max_seq_len: usize,
+    xlora_classifier: Option<XLoraClassifier>,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    rope_parameters: (Tensor, Tensor),
+    cfg: ModelConfigMetadata,
+}
+
+impl XLoraModel {
+    /// `vb` is the root of the LLaVA weights: the language model is under `language_model` and
+    /// the X-LoRA classifier is at the root.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        lora_config: &[((String, String), LoraConfig)],
+        xlora_config: Option<XLoraConfig>,
+        xlora_ordering: Ordering,
+        normal_loading_metadata: NormalLoadingMetadata,
+        preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Self> {
+        let mapper = normal_loading_metadata.mapper;
+
+        let vb_lm = vb.pp("language_model");
+        let vb_m = vb_lm.pp("model");
+        let embed_tokens = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+        )?;
+        let head_dim = cfg.head_dim();
+        let rope_parameters = OrdinaryRoPE::create_parameters(
+            head_dim,
+            cfg.max_position_embeddings,
+            cfg.rope_theta as f32,
+            cfg.rope_scaling.as_ref(),
+            vb_m.dtype(),
+            &normal_loading_metadata.real_device,
+        )?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        let mut count = 0;
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            let layer = DecoderLayer::new(
+                cfg,
+                vb_l.pp(layer_idx),
+                lora_config,
+                &mut count,
+                &xlora_ordering,
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+                preload_adapters,
+            )?;
+            layers.push(layer)
+        }
+        if xlora_config.is_none() && preload_adapters.is_none() {
+            // We are now a LoRA model so we must merge the weights
+            info!("Merging LoRA adapters.");
+            for layer in layers.iter_mut().tqdm() {
+                Arc::get_mut(&mut layer.self_attn.k_proj)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.self_attn.o_proj)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.self_attn.q_proj)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.self_attn.v_proj)
+                    .unwrap()
+                    .merge_weights()?;
+
+                Arc::get_mut(&mut layer.mlp.down_proj)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.mlp.gate_proj)
+                    .unwrap()
+                    .merge_weights()?;
+                Arc::get_mut(&mut layer.mlp.up_proj)
+                    .unwrap()
+                    .merge_weights()?;
+            }
+        }
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_nm_device(vb_m.pp("norm"), false),
+        )?;
+        let lm_head = linear_no_bias(
+            cfg.hidden_size,
+            cfg.vocab_size,
+            mapper.set_nm_device(vb_lm.pp("lm_head"), normal_loading_metadata.loading_isq),
+            mapper.set_nm_device(vb_lm.pp("lm_head"), false),
+            lora_config,
+            &mut count,
+            &xlora_ordering,
+            preload_adapters,
+        )?;
+        if xlora_config.is_some() && lm_head.is_lora() {
+            // This is why we can pass dummy values (..., None, 1.0, None)?
+            candle_core::bail!("Got an adapter `lm_head` layer, this is unsupported with X-LoRA.");
+        }
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            sliding_window: cfg.sliding_window,
+            device: normal_loading_metadata.real_device,
+            dtype: vb.dtype(),
+            cache: Cache::new(cfg.num_hidden_layers, true),
+            max_seq_len: cfg.max_position_embeddings,
+            xlora_classifier: xlora_config.map(|xlora_config| {
+                XLoraClassifier::new(xlora_config, count, lora_config.len(), vb, false).unwrap()
+            }),
+            mapper,
+            rope_parameters,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_attn_heads: cfg.num_attention_heads,
+                sliding_window: cfg.sliding_window,
+                head_dim: None,
+            },
+        })
+    }
+
+    /// Run the decoder layers. Both the scaling and the scaled pass use the same RoPE parameters
+    /// and sliding window cache update, only the cache differs for the full (X-LoRA) pass.
+    #[allow(clippy::too_many_arguments)]
+    fn inner_forward(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        scalings: Option<Tensor>,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
+        let mut cache = if is_full_pass {
+            if no_kv_cache {
+                let mut new_cache = Vec::new();
+                for _ in 0..self.cache.xlora_lock().len() {
+                    new_cache.push(None);
+                }
+
+                self.cache.xlora_lock().clone_from(&new_cache);
+            }
+            self.cache.xlora_lock()
+        } else {
+            self.cache.lock()
+        };
+        let mut xs = input_embeds;
+        let attention_mask = CausalMasker.make_causal_mask_with_sliding_window_as_attn_bias(
+            input_ids,
+            &*cache,
+            self.sliding_window,
+            xs.dtype(),
+            self.layers[0].self_attn.num_heads,
+        )?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                &mut cache[i],
+                kv_cache_dtype,
+                (&self.rope_parameters.0, &self.rope_parameters.1),
+                scalings.clone(),
+                self.xlora_classifier
+                    .as_ref()
+                    .map(|classifier| classifier.get_global_scaling_weight())
+                    .unwrap_or(1.0),
+                is_scaling_pass,
+                flash_params,
+            )?
+        }
+        let xs = xs.to_device(&self.device)?;
+        xs.apply(&self.norm)
+    }
+
+    fn lm_head_forward(&self, xs: Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let mut xs = xs.contiguous()?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(
+            &self.lm_head.lora_forward(&xs, None, 1.0, None)?,
+            context_lens,
+        )
+    }
+
+    /// The X-LoRA forward pass on input embeddings. `input_embeds_full` are the embeddings of
+    /// `input_ids_full`, which are used if there is no KV cache.
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward_embeds(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        input_ids_full: &Tensor,
+        input_embeds_full: Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        start_offsets_kernel: Tensor,
+        start_offsets_kernel_full: Tensor,
+        no_kv_cache: bool,
+        non_granular_state: &Option<NonGranularState>,
+        context_lens: Vec<(usize, usize)>,
+        flash_params: &FlashParams,
+        flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        if self.xlora_classifier.is_none() {
+            let res = self.inner_forward(
+                input_ids,
+                input_embeds,
+                seqlen_offsets,
+                None,
+                false,
+                no_kv_cache,
+                None,
+                flash_params,
+            )?;
+            return self.lm_head_forward(res, context_lens);
+        }
+
+        let scalings = EmbedsScalingsMaker {
+            model: self,
+            input_embeds: input_embeds.clone(),
+            input_embeds_full: input_embeds_full.clone(),
+        }
+        .get_scalings(
+            input_ids,
+            input_ids_full,
+            seqlen_offsets,
+            seqlen_offsets_full,
+            &start_offsets_kernel,
+            &start_offsets_kernel_full,
+            no_kv_cache,
+            non_granular_state,
+            &vec![usize::MAX; context_lens.len()],
+            flash_params,
+            flash_params_full,
+        )?;
+
+        let res = if no_kv_cache {
+            self.inner_forward(
+                input_ids_full,
+                input_embeds_full,
+                seqlen_offsets_full,
+                Some(scalings),
+                true,
+                no_kv_cache,
+                None,
+                flash_params_full,
+            )?
+        } else {
+            // is_full_pass=true is ok because no_kv_cache=false
+            self.inner_forward(
+                input_ids,
+                input_embeds,
+                seqlen_offsets,
+                Some(scalings),
+                true,
+                no_kv_cache,
+                None,
+                flash_params,
+            )?
+        };
+        self.lm_head_forward(res, context_lens)
+    }
+}
+
+/// Makes the X-LoRA scalings from input embeddings instead of token ids.
+struct EmbedsScalingsMaker<'a> {
+    model: &'a XLoraModel,
+    input_embeds: Tensor,
+    input_embeds_full: Tensor,
+}
+
+impl ScalingsMaker for EmbedsScalingsMaker<'_> {
+    fn dtype(&self) -> DType {
+        self.model.dtype
+    }
+    fn get_cache(&self) -> &Cache {
+        &self.model.cache
+    }
+    fn get_classifier(&self) -> &XLoraClassifier {
+        self.model.xlora_classifier.as_ref().unwrap()
+    }
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        scalings: Tensor,
+        is_full_pass: bool,
+        no_kv_cache: bool,
+        is_scaling_pass: Option<f64>,
+        _context_lens: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        // The scaling pass runs on the full sequence exactly if there is no KV cache.
+        let input_embeds = if no_kv_cache {
+            self.input_embeds_full.clone()
+        } else {
+            self.input_embeds.clone()
+        };
+        self.model.inner_forward(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            Some(scalings),
+            is_full_pass,
+            no_kv_cache,
+            is_scaling_pass,
+            flash_params,
+        )
+    }
+}
+
+impl IsqModel for XLoraModel {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((Arc::get_mut(&mut self.lm_head).unwrap().quant_inner(), None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((
+                Arc::get_mut(&mut layer.self_attn.q_proj)
+                    .unwrap()
+                    .quant_inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.self_attn.k_proj)
+                    .unwrap()
+                    .quant_inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.self_attn.v_proj)
+                    .unwrap()
+                    .quant_inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.self_attn.o_proj)
+                    .unwrap()
+                    .quant_inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.gate_proj)
+                    .unwrap()
+                    .quant_inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.up_proj).unwrap().quant_inner(),
+                Some(i),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.down_proj)
+                    .unwrap()
+                    .quant_inner(),
+                Some(i),
+            ));
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        panic!("Cannot generate UQFF for an adapter model.")
+    }
+}
+
+impl LLaVALLM for XLoraModel {
+    fn embed(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.embed_tokens.forward(input_ids)
+    }
+
+    fn forward_input_embed(
+        &self,
+        input_ids: &Tensor,
+        input_embed: Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        // LLaVA always runs with a KV cache, so the full inputs are never used.
+        self.forward_embeds(
+            input_ids,
+            input_embed.clone(),
+            input_ids,
+            input_embed,
+            seqlen_offsets,
+            seqlen_offsets,
+            start_offsets_kernel.clone(),
+            start_offsets_kernel,
+            false,
+            &None,
+            context_lens,
+            flash_params,
+            flash_params,
+        )
+    }
+}
+
+impl NormalModel for XLoraModel {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        // Used by LLaVA for the steps without image features.
+        self.forward_input_embed(
+            input_ids,
+            self.embed_tokens.forward(input_ids)?,
+            seqlen_offsets,
+            start_offsets_kernel,
+            context_lens,
+            metadata,
+            flash_params,
+        )
+    }
+    fn xlora_forward(
+        &self,
+        input_ids: &Tensor,
+        input_ids_full: &Tensor,
+        seqlen_offsets: &[usize],
+        seqlen_offsets_full: &[usize],
+        start_offsets_kernel: Tensor,
+        start_offsets_kernel_full: Tensor,
+        no_kv_cache: bool,
+        non_granular_state: &Option<NonGranularState>,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        flash_params: &FlashParams,
+        flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_embeds(
+            input_ids,
+            self.embed_tokens.forward(input_ids)?,
+            input_ids_full,
+            self.embed_tokens.forward(input_ids_full)?,
+            seqlen_offsets,
+            seqlen_offsets_full,
+            start_offsets_kernel,
+            start_offsets_kernel_full,
+            no_kv_cache,
+            non_granular_state,
+            context_lens,
+            flash_params,
+            flash_params_full,
+        )
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        true
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter merging is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for XLoraModel {}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_xlora_forward_shape() {
+        use super::XLoraModel;
+        use crate::{
+            device_map::DeviceMapMetadata,
+            layers::Activation,
+            models::mistral::Config,
+            pipeline::NormalModel,
+            pipeline::{text_models_inputs_processor::FlashParams, NormalLoadingMetadata},
+            vision_models::llava::llava_llm::LLaVALLM,
+        };
+        use candle_core::{DType, Device, Tensor};
+        use candle_nn::VarBuilder;
+        use std::collections::HashMap;
+
+        let dev = Device::Cpu;
+        let cfg = Config {
+            vocab_size: 32,
+            hidden_size: 16,
+            intermediate_size: 32,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 64,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            sliding_window: Some(8),
+            ..Default::default()
+        };
+        // A trivial adapter on the attention projections, with the weights set to zero.
+        let lora_config = vec![(
+            ("0".to_string(), "trivial".to_string()),
+            serde_json::from_str(
+                r#"{"r": 2, "lora_alpha": 4.0, "lora_dropout": null, "target_modules": ["q_proj", "v_proj"]}"#,
+            )
+            .unwrap(),
+        )];
+        let xlora_config = serde_json::from_str(
+            r#"{"hidden_size": 16, "base_model_id": "base", "adapters": ["trivial"]}"#,
+        )
+        .unwrap();
+        let ordering =
+            serde_json::from_str(r#"{"order": ["trivial"], "base_model_id": "base"}"#).unwrap();
+        let model = XLoraModel::new(
+            &cfg,
+            VarBuilder::zeros(DType::F32, &dev),
+            &lora_config,
+            Some(xlora_config),
+            ordering,
+            NormalLoadingMetadata {
+                mapper: DeviceMapMetadata::dummy()
+                    .into_mapper(cfg.num_hidden_layers, &dev, None)
+                    .unwrap(),
+                loading_isq: false,
+                real_device: dev.clone(),
+            },
+            &None::<HashMap<_, _>>,
+        )
+        .unwrap();
+
+        let input_ids = Tensor::new(&[[1u32, 2, 3, 4, 5]], &dev).unwrap();
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: Tensor::new(&[0u32], &dev).unwrap(),
+            cumulative_seqlens_k: Tensor::new(&[0u32], &dev).unwrap(),
+        };
+        let input_embeds = model.embed(&input_ids).unwrap();
+        let logits = model
+            .forward_input_embed(
+                &input_ids,
+                input_embeds,
+                &[0],
+                Tensor::new(&[0u32], &dev).unwrap(),
+                vec![(4, 1)],
+                None,
+                &flash_params,
+            )
+            .unwrap();
+        assert_eq!(logits.dims(), &[1, 1, 32]);
+
+        // A decode step without image features runs on the token ids.
+        let next_id = Tensor::new(&[[6u32]], &dev).unwrap();
+        let logits = NormalModel::forward(
+            &model,
+            &next_id,
+            &[5],
+            Tensor::new(&[5u32], &dev).unwrap(),
+            vec![(0, 1)],
+            vec![5],
+            None,
+            &flash_params,
+        )
+        .unwrap();
+        assert_eq!(logits.dims(), &[1, 1, 32]);
+    }
+}
@@ -140,6 +140,7 @@ impl InputsProcessor for LLaVANextInputProcessor {
                     pixel_values,
                     pixel_attention_mask: _,
                     image_sizes,
+                    image_sizes_all: _,
                     num_img_tokens,
                     aspect_ratio_ids: _,
                     aspect_ratio_mask: _,
@@ -428,6 +429,7 @@ impl ImagePreProcessor for LLaVANextInputProcessor {
             pixel_values,
             pixel_attention_mask: None,
             image_sizes: Some((original_size.0 as usize, original_size.1 as usize)),
+            image_sizes_all: None,
             num_img_tokens: Some(vec![self.get_num_image_tokens(original_size)]),
             aspect_ratio_ids: None,
             aspect_ratio_mask: None,
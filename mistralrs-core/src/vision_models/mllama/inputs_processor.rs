@@ -293,6 +293,7 @@ impl InputsProcessor for MLlamaImageProcessor {
                     pixel_values,
                     pixel_attention_mask: _,
                     image_sizes: _,
+                    image_sizes_all: _,
                     num_img_tokens: _,
                     aspect_ratio_ids,
                     aspect_ratio_mask,
@@ -864,6 +865,7 @@ impl ImagePreProcessor for MLlamaImageProcessor {
             pixel_values: images,
             pixel_attention_mask: None,
             image_sizes: None,
+            image_sizes_all: None,
             num_img_tokens: None,
             aspect_ratio_ids: Some(aspect_ratio_ids),
             aspect_ratio_mask: Some(aspect_ratio_mask),
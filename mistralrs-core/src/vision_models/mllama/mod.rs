@@ -14,8 +14,8 @@ use text::MLlamaTextModel;
 use vision::MLlamaVisionModel;
 
 use candle_core::{DType, Device, Result, Tensor, D};
-use candle_nn::{linear, Linear, Module, VarBuilder};
-use mistralrs_quant::QuantMethod;
+use candle_nn::VarBuilder;
+use mistralrs_quant::{linear, QuantMethod};
 
 use crate::{
     amoe::AnyMoeBaseModelMixin,
@@ -86,7 +86,7 @@ fn prepare_cross_attention_mask(
 pub(crate) struct MLlamaModel {
     vision_model: MLlamaVisionModel,
     language_model: MLlamaTextModel,
-    multi_modal_projector: Linear,
+    multi_modal_projector: Arc<dyn QuantMethod>,
     hidden_size: usize,
     dtype: DType,
 }
@@ -123,6 +123,7 @@ impl MLlamaModel {
             multi_modal_projector: linear(
                 cfg.vision_config.vision_output_dim,
                 cfg.text_config.hidden_size,
+                &None,
                 vb.pp("multi_modal_projector")
                     .set_device(real_dev.clone())
                     .set_dtype(vision_model_dtype),
@@ -151,9 +152,12 @@ impl MLlamaModel {
             let Some(aspect_ratio_ids) = aspect_ratio_ids else {
                 candle_core::bail!("`aspect_ratio_ids` must be specified if `pixel_values` is.");
             };
-            let vision_outputs =
+            let mut vision_outputs =
                 self.vision_model
                     .forward(pixel_values, aspect_ratio_ids, aspect_ratio_mask)?;
+            if let Some(t) = self.multi_modal_projector.quantized_act_type() {
+                vision_outputs = vision_outputs.to_dtype(t)?;
+            }
             let cross_attention_states = self
                 .multi_modal_projector
                 .forward(&vision_outputs.flatten(0, 1)?)?
@@ -249,14 +253,25 @@ impl IsqModel for MLlamaModel {
         Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
         &dyn DeviceMapper,
     ) {
-        self.language_model.get_layers()
+        let (mut layers, mapper) = self.language_model.get_layers();
+        // The projector and vision model are not device mapped, they are on the base device.
+        layers.push((&mut self.multi_modal_projector, None));
+        layers.extend(
+            self.vision_model
+                .get_isq_layers()
+                .into_iter()
+                .map(|layer| (layer, None)),
+        );
+        (layers, mapper)
+    }
+
+    fn has_vision_isq_layers(&self) -> bool {
+        true
     }
 
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb = UnVarBuilder::new();
 
-        uvb.pp("multi_modal_projector")
-            .add(&self.multi_modal_projector);
         uvb.pp("language_model")
             .extend(self.language_model.residual_tensors());
         uvb.pp("vision_model")
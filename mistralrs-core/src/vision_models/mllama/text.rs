@@ -12,7 +12,7 @@ use crate::{
     layers::{repeat_kv, CausalMasker, Llama3RotaryEmbedding, MatMul, RmsNorm, Sdpa},
     layers_masker::PastKvLenCache,
     paged_attention::{AttentionImplementation, ModelConfigMetadata},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata},
+    pipeline::{extract_logits, Cache, IsqModel, KVCacheDtype, NormalLoadingMetadata},
     utils::unvarbuilder::UnVarBuilder,
 };
 
@@ -69,6 +69,25 @@ impl MLlamaTextMlp {
     }
 }
 
+/// The attention projections, in the order they are loaded.
+const ATTN_PROJECTIONS: [&str; 4] = ["q_proj", "k_proj", "v_proj", "o_proj"];
+
+/// A projection without a weight is loaded as a dummy layer, so check that the projections either
+/// all have weights or none do (such as when loading from UQFF or a GPTQ/AWQ checkpoint). A
+/// mismatch means a projection is loaded from the wrong name.
+fn check_attn_projections(vb: &VarBuilder) -> Result<()> {
+    let (present, missing): (Vec<_>, Vec<_>) = ATTN_PROJECTIONS
+        .iter()
+        .partition(|name| vb.contains_tensor(&format!("{name}.weight")));
+    if !present.is_empty() && !missing.is_empty() {
+        candle_core::bail!(
+            "Attention at `{}` has weights for {present:?} but not for {missing:?}.",
+            vb.prefix()
+        );
+    }
+    Ok(())
+}
+
 struct MLlamaTextSelfAttention {
     q_proj: Arc<dyn QuantMethod>,
     k_proj: Arc<dyn QuantMethod>,
@@ -87,6 +106,7 @@ impl MLlamaTextSelfAttention {
         vb: VarBuilder,
         rope: Arc<Llama3RotaryEmbedding>,
     ) -> Result<Self> {
+        check_attn_projections(&vb)?;
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
 
         Ok(Self {
@@ -135,6 +155,7 @@ impl MLlamaTextSelfAttention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
     ) -> Result<Tensor> {
         let (bs, q_len, _) = hidden_states.dims3()?;
 
@@ -172,7 +193,7 @@ impl MLlamaTextSelfAttention {
                 .contiguous()?;
         }
 
-        (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+        (k, v) = Cache::update_kv_cache(kv_cache, k, v, false, kv_cache_dtype)?;
 
         let mut attn_output = Sdpa
             .run_attention(&q, &k, &v, attention_mask, None, &self.sdpa_params)?
@@ -238,6 +259,7 @@ impl MLlamaSelfAttentionDecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
     ) -> Result<Tensor> {
         let residual = hidden_states;
 
@@ -249,6 +271,7 @@ impl MLlamaSelfAttentionDecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
         )?;
         hidden_states = (residual + hidden_states)?;
 
@@ -279,6 +302,7 @@ impl MLlamaTextCrossAttention {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
     ) -> Result<Self> {
+        check_attn_projections(&vb)?;
         Ok(Self {
             q_proj: linear_no_bias(
                 cfg.hidden_size,
@@ -326,6 +350,7 @@ impl MLlamaTextCrossAttention {
         cross_attn_states: Option<&Tensor>,
         attention_mask: Option<&Tensor>,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
     ) -> Result<Tensor> {
         let (bs, q_len, _) = hidden_states.dims3()?;
 
@@ -357,6 +382,11 @@ impl MLlamaTextCrossAttention {
                 k = k.to_dtype(original_dtype)?;
             }
             k = self.k_norm.forward(&k)?;
+            debug_assert_eq!(
+                (k.dim(0)?, k.dim(1)?, k.dim(3)?),
+                (bs, self.num_kv_heads, self.head_dim),
+                "Cross attention keys have an unexpected shape after `k_norm`"
+            );
 
             let mut v = self.v_proj.forward(&cross_attn_states)?;
             if self.q_proj.quantized_act_type().is_some() {
@@ -369,10 +399,10 @@ impl MLlamaTextCrossAttention {
             k = repeat_kv(k.clone(), self.num_heads / self.num_kv_heads)?.contiguous()?;
             v = repeat_kv(v.clone(), self.num_heads / self.num_kv_heads)?.contiguous()?;
 
-            (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+            (k, v) = Cache::update_kv_cache(kv_cache, k, v, false, kv_cache_dtype)?;
             (k, v)
         } else if let Some((k_cache, v_cache)) = kv_cache {
-            (k_cache.clone(), v_cache.clone())
+            Cache::read_kv_cache(k_cache, v_cache, q.dtype())?
         } else {
             candle_core::bail!("Cross attn cannot find k,v cache or cross attn hidden states!")
         };
@@ -463,14 +493,19 @@ impl MLlamaCrossAttentionDecoderLayer {
         attention_mask: Option<&Tensor>,
         full_text_row_masked_out_mask: Option<&Tensor>,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
     ) -> Result<Tensor> {
         let residual = hidden_states;
 
         let mut hidden_states = self.input_layernorm.forward(hidden_states)?;
 
-        hidden_states =
-            self.attn
-                .forward(&hidden_states, cross_attn_states, attention_mask, kv_cache)?;
+        hidden_states = self.attn.forward(
+            &hidden_states,
+            cross_attn_states,
+            attention_mask,
+            kv_cache,
+            kv_cache_dtype,
+        )?;
         hidden_states = (residual + hidden_states.broadcast_mul(&self.attn_gate.tanh()?)?)?;
 
         let residual = &hidden_states;
@@ -628,6 +663,7 @@ impl MLlamaTextModel {
     ) -> Result<Tensor> {
         let mut hidden_states = self.embed_tokens.forward(input_ids)?;
 
+        let kv_cache_dtype = self.self_attn_cache.kv_cache_dtype();
         let mut self_cache = self.self_attn_cache.lock();
         let self_mask = CausalMasker.make_causal_mask_as_attn_bias(
             input_ids,
@@ -646,6 +682,7 @@ impl MLlamaTextModel {
                         seqlen_offsets,
                         start_offsets_kernel.clone(),
                         &mut self_cache[i],
+                        kv_cache_dtype,
                     )?;
                 }
                 MLlamaDecoderLayer::CrossAttn(attn) => {
@@ -661,6 +698,7 @@ impl MLlamaTextModel {
                         cross_attention_mask,
                         full_text_row_masked_out_mask,
                         &mut self_cache[i],
+                        kv_cache_dtype,
                     )?;
                 }
             }
@@ -758,3 +796,176 @@ impl IsqModel for MLlamaTextModel {
         uvb.to_safetensors()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use candle_core::{DType, Device, Result, Tensor, D};
+    use candle_nn::VarBuilder;
+    use mistralrs_quant::QuantMethod;
+
+    use super::{MLlamaTextCrossAttention, MLlamaTextSelfAttention, ATTN_PROJECTIONS};
+    use crate::{
+        layers::{repeat_kv, Llama3RotaryEmbedding},
+        vision_models::mllama::MLlamaTextConfig,
+        DeviceMapMetadata, Topology,
+    };
+
+    fn config() -> MLlamaTextConfig {
+        serde_json::from_value(serde_json::json!({
+            "rope_scaling": null,
+            "vocab_size": 16,
+            "hidden_size": 8,
+            "hidden_act": "silu",
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "num_key_value_heads": 1,
+            "intermediate_size": 16,
+            "rope_theta": 10000.0,
+            "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 32,
+            "tie_word_embeddings": false,
+            "cross_attention_layers": [],
+            "quantization_config": null,
+        }))
+        .unwrap()
+    }
+
+    /// Attention weights where every element of a projection's weight is its index in
+    /// [`ATTN_PROJECTIONS`], so each projection can be told apart by its values.
+    fn tagged_weights(cfg: &MLlamaTextConfig) -> Result<HashMap<String, Tensor>> {
+        let q_dim = cfg.num_attention_heads * cfg.head_dim();
+        let kv_dim = cfg.num_key_value_heads * cfg.head_dim();
+        let shapes = [
+            (q_dim, cfg.hidden_size),
+            (kv_dim, cfg.hidden_size),
+            (kv_dim, cfg.hidden_size),
+            (cfg.hidden_size, q_dim),
+        ];
+        let mut weights = HashMap::new();
+        for (tag, (name, shape)) in ATTN_PROJECTIONS.iter().zip(shapes).enumerate() {
+            let weight = Tensor::full(tag as f32, shape, &Device::Cpu)?;
+            weights.insert(format!("{name}.weight"), weight);
+        }
+        for norm in ["q_norm", "k_norm"] {
+            let weight = Tensor::ones(cfg.head_dim(), DType::F32, &Device::Cpu)?;
+            weights.insert(format!("{norm}.weight"), weight);
+        }
+        Ok(weights)
+    }
+
+    fn assert_tagged(projections: [&Arc<dyn QuantMethod>; 4]) -> Result<()> {
+        for (tag, (name, proj)) in ATTN_PROJECTIONS.iter().zip(projections).enumerate() {
+            let values = proj.dequantize_w()?.flatten_all()?.to_vec1::<f32>()?;
+            assert!(
+                values.iter().all(|x| *x == tag as f32),
+                "`{name}` was not loaded from `{name}.weight`"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_attn_projections_load_own_weights() -> Result<()> {
+        let cfg = config();
+        let dev = Device::Cpu;
+        let vb = VarBuilder::from_tensors(tagged_weights(&cfg)?, DType::F32, &dev);
+
+        let rope = Arc::new(Llama3RotaryEmbedding::new_mllama3(
+            DType::F32,
+            &cfg,
+            &dev,
+            true,
+        )?);
+        let attn = MLlamaTextSelfAttention::new(&cfg, vb.clone(), rope)?;
+        assert_tagged([&attn.q_proj, &attn.k_proj, &attn.v_proj, &attn.o_proj])?;
+
+        let mapper = DeviceMapMetadata::dummy().into_mapper(
+            cfg.num_hidden_layers,
+            &dev,
+            Some(&Topology(vec![None])),
+        )?;
+        let attn = MLlamaTextCrossAttention::new(&cfg, vb, &*mapper, 0)?;
+        assert_tagged([&attn.q_proj, &attn.k_proj, &attn.v_proj, &attn.o_proj])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_attn_missing_projection_fails() -> Result<()> {
+        let cfg = config();
+        let dev = Device::Cpu;
+        let mut weights = tagged_weights(&cfg)?;
+        weights.remove("v_proj.weight");
+        let vb = VarBuilder::from_tensors(weights, DType::F32, &dev);
+
+        let rope = Arc::new(Llama3RotaryEmbedding::new_mllama3(
+            DType::F32,
+            &cfg,
+            &dev,
+            true,
+        )?);
+        let err = MLlamaTextSelfAttention::new(&cfg, vb, rope)
+            .err()
+            .expect("Loading without `v_proj.weight` should fail");
+        assert!(err.to_string().contains("v_proj"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_attn_keys_use_key_projection() -> Result<()> {
+        let cfg = config();
+        let dev = Device::Cpu;
+        let kv_dim = cfg.num_key_value_heads * cfg.head_dim();
+        let mut weights = tagged_weights(&cfg)?;
+        let k_weight = (Tensor::arange(0f32, (kv_dim * cfg.hidden_size) as f32, &dev)?
+            .reshape((kv_dim, cfg.hidden_size))?
+            / 10.)?;
+        weights.insert("k_proj.weight".to_string(), k_weight.clone());
+        let vb = VarBuilder::from_tensors(weights, DType::F32, &dev);
+        let mapper = DeviceMapMetadata::dummy().into_mapper(
+            cfg.num_hidden_layers,
+            &dev,
+            Some(&Topology(vec![None])),
+        )?;
+        let attn = MLlamaTextCrossAttention::new(&cfg, vb, &*mapper, 0)?;
+
+        let (bs, q_len, kv_len) = (1, 2, 3);
+        let hidden_states = Tensor::ones((bs, q_len, cfg.hidden_size), DType::F32, &dev)?;
+        let cross_attn_states =
+            (Tensor::arange(0f32, (bs * kv_len * cfg.hidden_size) as f32, &dev)?.reshape((
+                bs,
+                kv_len,
+                cfg.hidden_size,
+            ))? - 5.)?;
+        let mut kv_cache = None;
+        attn.forward(
+            &hidden_states,
+            Some(&cross_attn_states),
+            None,
+            &mut kv_cache,
+            KVCacheDtype::FullPrecision,
+        )?;
+
+        // The cached keys are the normed key projection of the cross attention states.
+        let expected = cross_attn_states
+            .broadcast_matmul(&k_weight.t()?)?
+            .reshape((bs, kv_len, cfg.num_key_value_heads, cfg.head_dim()))?
+            .transpose(1, 2)?;
+        let expected = candle_nn::ops::rms_norm(
+            &expected.contiguous()?,
+            &Tensor::ones(cfg.head_dim(), DType::F32, &dev)?,
+            cfg.rms_norm_eps as f32,
+        )?;
+        let expected = repeat_kv(expected, cfg.num_attention_heads / cfg.num_key_value_heads)?;
+        let (k, _) = kv_cache.expect("Cross attention should fill the cache");
+        assert_eq!(k.dims(), expected.dims());
+        let max_diff = (k - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(D::Minus1)?
+            .to_scalar::<f32>()?;
+        assert!(max_diff < 1e-4, "{max_diff}");
+        Ok(())
+    }
+}
@@ -1,18 +1,16 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::ops::Mul;
+use std::{ops::Mul, sync::Arc};
 
 use candle_core::{DType, Result, Tensor, D};
 use candle_nn::{
-    conv2d_no_bias, embedding, layer_norm, linear, linear_no_bias, Conv2d, Conv2dConfig, Embedding,
-    LayerNorm, LayerNormConfig, Linear, Module, VarBuilder,
+    conv2d_no_bias, embedding, layer_norm, Conv2d, Conv2dConfig, Embedding, LayerNorm,
+    LayerNormConfig, Module, VarBuilder,
 };
+use mistralrs_quant::{linear, linear_no_bias, QuantMethod};
 
 use crate::{
-    attention::SdpaParams,
-    layers::{FusedBiasLinear, Sdpa},
-    pipeline::IsqModel,
-    utils::unvarbuilder::UnVarBuilder,
+    attention::SdpaParams, layers::Sdpa, pipeline::IsqModel, utils::unvarbuilder::UnVarBuilder,
 };
 
 use super::{MLlamaVisionConfig, VisionActivation};
@@ -128,10 +126,10 @@ impl MLlamaPrecomputedAspectRatioEmbedding {
 }
 
 struct MLlamaVisionAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    o_proj: Arc<dyn QuantMethod>,
     sdpa_params: SdpaParams,
     num_heads: usize,
     head_dim: usize,
@@ -144,21 +142,25 @@ impl MLlamaVisionAttention {
             q_proj: linear_no_bias(
                 cfg.hidden_size,
                 cfg.num_attention_heads * head_dim,
+                &None,
                 vb.pp("q_proj"),
             )?,
             k_proj: linear_no_bias(
                 cfg.hidden_size,
                 cfg.num_attention_heads * head_dim,
+                &None,
                 vb.pp("k_proj"),
             )?,
             v_proj: linear_no_bias(
                 cfg.hidden_size,
                 cfg.num_attention_heads * head_dim,
+                &None,
                 vb.pp("v_proj"),
             )?,
             o_proj: linear_no_bias(
                 cfg.hidden_size,
                 cfg.num_attention_heads * head_dim,
+                &None,
                 vb.pp("o_proj"),
             )?,
             sdpa_params: SdpaParams {
@@ -175,9 +177,19 @@ impl MLlamaVisionAttention {
 
     // https://github.com/huggingface/transformers/blob/f2c388e3f946862f657acc1e21b272ec946fc66c/src/transformers/models/mllama/modeling_mllama.py#L243
     fn forward(&self, hidden_state: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
-        let mut q = self.q_proj.forward(hidden_state)?;
-        let mut k = self.k_proj.forward(hidden_state)?;
-        let mut v = self.v_proj.forward(hidden_state)?;
+        let original_dtype = hidden_state.dtype();
+        let mut hidden_state = hidden_state.clone();
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            hidden_state = hidden_state.to_dtype(t)?;
+        }
+        let mut q = self.q_proj.forward(&hidden_state)?;
+        let mut k = self.k_proj.forward(&hidden_state)?;
+        let mut v = self.v_proj.forward(&hidden_state)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            q = q.to_dtype(original_dtype)?;
+            k = k.to_dtype(original_dtype)?;
+            v = v.to_dtype(original_dtype)?;
+        }
 
         // Should be same, no caching...
         let (bs, q_sq, _) = q.dims3()?;
@@ -193,7 +205,7 @@ impl MLlamaVisionAttention {
             .reshape((bs, k_sq, self.num_heads, self.head_dim))?
             .transpose(1, 2)?;
 
-        let attn_output = Sdpa
+        let mut attn_output = Sdpa
             .run_attention(
                 &q.contiguous()?,
                 &k.contiguous()?,
@@ -206,37 +218,46 @@ impl MLlamaVisionAttention {
             .contiguous()?
             .reshape((bs, q_sq, ()))?;
 
-        self.o_proj.forward(&attn_output)
+        if let Some(t) = self.q_proj.quantized_act_type() {
+            attn_output = attn_output.to_dtype(t)?;
+        }
+        let mut res = self.o_proj.forward(&attn_output)?;
+        if self.q_proj.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
     }
 }
 
 struct MLlamaMlp {
     act: VisionActivation,
-    fc1: FusedBiasLinear,
-    fc2: FusedBiasLinear,
+    fc1: Arc<dyn QuantMethod>,
+    fc2: Arc<dyn QuantMethod>,
 }
 
 impl MLlamaMlp {
     fn new(cfg: &MLlamaVisionConfig, vb: VarBuilder) -> Result<Self> {
         Ok(Self {
             act: cfg.hidden_act,
-            fc1: FusedBiasLinear::try_from(linear(
-                cfg.hidden_size,
-                cfg.intermediate_size,
-                vb.pp("fc1"),
-            )?)?,
-            fc2: FusedBiasLinear::try_from(linear(
-                cfg.intermediate_size,
-                cfg.hidden_size,
-                vb.pp("fc2"),
-            )?)?,
+            fc1: linear(cfg.hidden_size, cfg.intermediate_size, &None, vb.pp("fc1"))?,
+            fc2: linear(cfg.intermediate_size, cfg.hidden_size, &None, vb.pp("fc2"))?,
         })
     }
 
     // https://github.com/huggingface/transformers/blob/f2c388e3f946862f657acc1e21b272ec946fc66c/src/transformers/models/mllama/modeling_mllama.py#L223
     fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
-        self.fc2
-            .forward(&self.act.forward(&self.fc1.forward(hidden_states)?)?)
+        let original_dtype = hidden_states.dtype();
+        let mut hidden_states = hidden_states.clone();
+        if let Some(t) = self.fc1.quantized_act_type() {
+            hidden_states = hidden_states.to_dtype(t)?;
+        }
+        let mut res = self
+            .fc2
+            .forward(&self.act.forward(&self.fc1.forward(&hidden_states)?)?)?;
+        if self.fc1.quantized_act_type().is_some() {
+            res = res.to_dtype(original_dtype)?;
+        }
+        Ok(res)
     }
 }
 
@@ -346,6 +367,19 @@ impl MLlamaVisionEncoder {
         Ok((hidden_state, hidden_states))
     }
 
+    fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        let mut layers = Vec::new();
+        for layer in &mut self.layers {
+            layers.push(&mut layer.self_attn.q_proj);
+            layers.push(&mut layer.self_attn.k_proj);
+            layers.push(&mut layer.self_attn.v_proj);
+            layers.push(&mut layer.self_attn.o_proj);
+            layers.push(&mut layer.mlp.fc1);
+            layers.push(&mut layer.mlp.fc2);
+        }
+        layers
+    }
+
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb_t = UnVarBuilder::new();
 
@@ -361,16 +395,6 @@ impl MLlamaVisionEncoder {
             if let Some(gate) = layer.gate_ffn.clone() {
                 uvb_l.add_tensor("gate_ffn", gate);
             }
-
-            let uvb_attn = uvb_l.pp("self_attn");
-            uvb_attn.pp("q_proj").add(&layer.self_attn.q_proj);
-            uvb_attn.pp("k_proj").add(&layer.self_attn.k_proj);
-            uvb_attn.pp("v_proj").add(&layer.self_attn.v_proj);
-            uvb_attn.pp("o_proj").add(&layer.self_attn.o_proj);
-
-            let uvb_mlp = uvb_l.pp("mlp");
-            uvb_mlp.pp("fc1").add(&layer.mlp.fc1);
-            uvb_mlp.pp("fc2").add(&layer.mlp.fc2);
         }
 
         uvb_t.to_safetensors()
@@ -655,6 +679,16 @@ impl MLlamaVisionModel {
     }
 }
 
+impl MLlamaVisionModel {
+    /// The linear layers of the local and global transformers, for ISQ. The embeddings and layer
+    /// norms are not quantized and are returned by the residual tensors.
+    pub(super) fn get_isq_layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        let mut layers = self.transformer.get_isq_layers();
+        layers.extend(self.global_transformer.get_isq_layers());
+        layers
+    }
+}
+
 impl IsqModel for MLlamaVisionModel {
     fn get_layers(
         &mut self,
@@ -665,7 +699,9 @@ impl IsqModel for MLlamaVisionModel {
         )>,
         &dyn crate::device_map::DeviceMapper,
     ) {
-        unreachable!("MLlamaVision model cannot be quantized.");
+        unreachable!(
+            "MLlamaVision layers are quantized through `MLlamaVisionModel::get_isq_layers`."
+        );
     }
     fn residual_tensors(&self) -> Vec<(String, Tensor)> {
         let uvb = UnVarBuilder::new();
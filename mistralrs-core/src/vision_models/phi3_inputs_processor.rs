@@ -124,7 +124,8 @@ impl InputsProcessor for Phi3InputsProcessor {
                 let PreprocessedImages {
                     pixel_values,
                     pixel_attention_mask: _,
-                    image_sizes,
+                    image_sizes: _,
+                    image_sizes_all,
                     num_img_tokens,
                     aspect_ratio_ids: _,
                     aspect_ratio_mask: _,
@@ -137,9 +138,14 @@ impl InputsProcessor for Phi3InputsProcessor {
                         (usize::MAX, usize::MAX), // Don't use it here...
                     )
                     .expect("Preprocessor failed");
-                let image_sizes = image_sizes.unwrap();
                 pixel_values_accum.push(pixel_values);
-                image_sizes_accum.push(image_sizes);
+                // One size per image, as the images of all sequences are concatenated
+                image_sizes_accum.extend(
+                    image_sizes_all
+                        .unwrap()
+                        .into_iter()
+                        .map(|(h, w)| (h as usize, w as usize)),
+                );
                 num_img_tokens_accum.push(num_img_tokens.unwrap());
             }
             (
@@ -222,71 +228,12 @@ impl InputsProcessor for Phi3InputsProcessor {
                 .iter_mut()
                 .zip(num_img_tokens.unwrap().into_iter().zip(n_images)),
         ) {
-            let splits = self
-                .image_tag_splitter
-                .split(&detokenized)
-                .map(|span| &detokenized[span.range()])
-                .collect::<Vec<_>>();
-            let prompt_chunks = tokenizer
-                .encode_batch(splits, true)
-                .expect("Encode failed")
-                .into_iter()
-                .map(|enc| enc.get_ids().to_vec())
-                .collect::<Vec<_>>();
-
-            let image_tags = self.image_tag_splitter.find_iter(&detokenized);
-            let image_ids = image_tags
-                .into_iter()
-                .map(|s| {
-                    let s = &detokenized[s.range()];
-                    s.split('|')
-                        .nth(1)
-                        .unwrap()
-                        .split('_')
-                        .nth(1)
-                        .unwrap()
-                        .parse::<u32>()
-                        .expect("Failed to parse image id to u32")
-                })
-                .collect::<Vec<_>>();
-            let unique_image_ids = image_ids
-                .iter()
-                .copied()
-                .unique()
-                .sorted()
-                .collect::<Vec<_>>();
-            // `image_ids` must start from 1, and must be continuous int, e.g. [1, 2, 3], cannot be [1, 4, 5]
-            if unique_image_ids != (1u32..unique_image_ids.len() as u32 + 1).collect::<Vec<_>>() {
-                return Box::new(std::iter::once(Err(anyhow::Error::msg(
-                    "`image_ids` must start from 1, and must be continuous, e.g. [1, 2, 3], cannot be [1, 4, 5].",
-                ))));
-            }
-            // Total images must be the same as the number of image tags
-            if unique_image_ids.len() != n_images {
-                return Box::new(std::iter::once(Err(anyhow::Error::msg(
-                    "Total images must be the same as the number of image tags.",
-                ))));
-            }
-
-            // Use the TryInto + unwrap_or to handle case when id==0
-            let image_ids_pad = image_ids
-                .iter()
-                .map(|id| {
-                    [-(*id as i64)].repeat(
-                        num_img_tokens[TryInto::<usize>::try_into(*id as isize - 1)
-                            .unwrap_or(num_img_tokens.len() - 1)],
-                    )
-                })
-                .collect::<Vec<_>>();
-
-            let mut input_ids: Vec<i64> = Vec::new();
-            for item in prompt_chunks
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .interleave(image_ids_pad)
-            {
-                input_ids.extend(item);
-            }
+            let input_ids =
+                match self.expand_image_tokens(&tokenizer, &detokenized, &num_img_tokens, n_images)
+                {
+                    Ok(input_ids) => input_ids,
+                    Err(e) => return Box::new(std::iter::once(Err(e))),
+                };
 
             // NOTE(EricLBuehler): Casting to u32 is fine, we don't care about the other toks
             seq.set_toks(
@@ -361,6 +308,81 @@ impl InputsProcessor for Phi3InputsProcessor {
 }
 
 impl Phi3InputsProcessor {
+    /// Tokenize a prompt, expanding each `<|image_N|>` tag into `num_img_tokens[N - 1]` image
+    /// tokens with the id `-N`.
+    fn expand_image_tokens(
+        &self,
+        tokenizer: &Tokenizer,
+        detokenized: &str,
+        num_img_tokens: &[usize],
+        n_images: usize,
+    ) -> anyhow::Result<Vec<i64>> {
+        let splits = self
+            .image_tag_splitter
+            .split(detokenized)
+            .map(|span| &detokenized[span.range()])
+            .collect::<Vec<_>>();
+        let prompt_chunks = tokenizer
+            .encode_batch(splits, true)
+            .map_err(anyhow::Error::msg)?
+            .into_iter()
+            .map(|enc| enc.get_ids().to_vec())
+            .collect::<Vec<_>>();
+
+        let image_tags = self.image_tag_splitter.find_iter(detokenized);
+        let image_ids = image_tags
+            .into_iter()
+            .map(|s| {
+                let s = &detokenized[s.range()];
+                s.split('|')
+                    .nth(1)
+                    .unwrap()
+                    .split('_')
+                    .nth(1)
+                    .unwrap()
+                    .parse::<u32>()
+                    .expect("Failed to parse image id to u32")
+            })
+            .collect::<Vec<_>>();
+        let unique_image_ids = image_ids
+            .iter()
+            .copied()
+            .unique()
+            .sorted()
+            .collect::<Vec<_>>();
+        // `image_ids` must start from 1, and must be continuous int, e.g. [1, 2, 3], cannot be [1, 4, 5]
+        if unique_image_ids != (1u32..unique_image_ids.len() as u32 + 1).collect::<Vec<_>>() {
+            anyhow::bail!(
+                "`image_ids` must start from 1, and must be continuous, e.g. [1, 2, 3], cannot be [1, 4, 5]."
+            );
+        }
+        // Total images must be the same as the number of image tags
+        if unique_image_ids.len() != n_images {
+            anyhow::bail!("Total images must be the same as the number of image tags.");
+        }
+
+        // Use the TryInto + unwrap_or to handle case when id==0
+        let image_ids_pad = image_ids
+            .iter()
+            .map(|id| {
+                [-(*id as i64)].repeat(
+                    num_img_tokens[TryInto::<usize>::try_into(*id as isize - 1)
+                        .unwrap_or(num_img_tokens.len() - 1)],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut input_ids: Vec<i64> = Vec::new();
+        for item in prompt_chunks
+            .iter()
+            .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
+            .interleave(image_ids_pad)
+        {
+            input_ids.extend(item);
+        }
+        Ok(input_ids)
+    }
+
     fn pad_image(
         image: &DynamicImage,
         top: u32,
@@ -471,22 +493,8 @@ impl ImagePreProcessor for Phi3InputsProcessor {
         let mut image_sizes = Vec::new();
         let mut padded_images = Vec::new();
         let mut num_img_tokens = Vec::new();
-        // If >1 images, resize them all to the largest, potentially destroying aspect ratio
-        let mut max_size = None;
-        for image in images.iter() {
-            if max_size.is_none() {
-                max_size = Some((image.dimensions().0 as usize, image.dimensions().1 as usize))
-            } else if max_size.is_some_and(|(x, _)| image.dimensions().0 as usize > x) {
-                max_size = Some((image.dimensions().0 as usize, max_size.unwrap().1));
-            } else if max_size.is_some_and(|(_, y)| image.dimensions().1 as usize > y) {
-                max_size = Some((max_size.unwrap().0, image.dimensions().1 as usize));
-            }
-        }
-        let (max_h, max_w) = max_size.unwrap();
-        for image in images.iter_mut() {
-            *image = image.resize_exact(max_w as u32, max_h as u32, FilterType::Nearest);
-        }
-
+        // Each image is HD transformed at its own size. All of them are padded to the same number
+        // of crops, so they can be stacked.
         for image in images.iter_mut() {
             // Convert to rgb, default to true
             if config.do_convert_rgb.unwrap_or(true) {
@@ -533,18 +541,14 @@ impl ImagePreProcessor for Phi3InputsProcessor {
                 &hd_image_reshape,
                 config.num_crops.expect("Need `num_crops`") + 1,
             )?;
-            image_sizes.push((h, w));
+            image_sizes.push((h as u32, w as u32));
             padded_images.push(image_transformed);
             num_img_tokens.push(num_image_tokens);
         }
-        if padded_images.len() > 1 {
-            candle_core::bail!("Can only process one image per batch");
-        }
-        let image_sizes = image_sizes[0];
-
         Ok(PreprocessedImages {
             pixel_values: Tensor::stack(&padded_images, 0)?,
-            image_sizes: Some((image_sizes.0, image_sizes.1)),
+            image_sizes: None,
+            image_sizes_all: Some(image_sizes),
             pixel_attention_mask: None,
             num_img_tokens: Some(num_img_tokens),
             aspect_ratio_ids: None,
@@ -553,3 +557,186 @@ impl ImagePreProcessor for Phi3InputsProcessor {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, IndexOp};
+    use image::{DynamicImage, GenericImageView};
+    use regex_automata::meta::Regex;
+    use tokenizers::{
+        models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, Tokenizer,
+    };
+
+    use super::Phi3InputsProcessor;
+    use crate::vision_models::{
+        image_processor::ImagePreProcessor, preprocessor_config::PreProcessorConfig,
+    };
+
+    #[test]
+    fn test_preprocess_1000x600() {
+        // preprocessor_config.json of microsoft/Phi-3-vision-128k-instruct
+        let config: PreProcessorConfig = serde_json::from_str(
+            r#"{
+                "do_convert_rgb": true,
+                "image_mean": [0.48145466, 0.4578275, 0.40821073],
+                "image_std": [0.26862954, 0.26130258, 0.27577711],
+                "num_crops": 16,
+                "num_img_tokens": 144
+            }"#,
+        )
+        .unwrap();
+        let processor = Phi3InputsProcessor {
+            image_tag_splitter: Regex::new(r"<\|image_\d+\|>").unwrap(),
+        };
+        let image = DynamicImage::new_rgb8(1000, 600);
+        assert_eq!(
+            Phi3InputsProcessor::hd_transform(&image, 16).dimensions(),
+            (1680, 1008)
+        );
+
+        let preprocessed = processor
+            .preprocess(vec![image], &config, &Device::Cpu, (0, 0))
+            .unwrap();
+
+        // Matches `Phi3VImageProcessor` in transformers: the 1008x1680 HD image is split into a
+        // 3x5 grid of 336x336 crops, preceded by the global image and padded to `num_crops + 1`.
+        assert_eq!(preprocessed.pixel_values.dims(), &[1, 17, 3, 336, 336]);
+        assert_eq!(preprocessed.image_sizes_all, Some(vec![(1008, 1680)]));
+        assert_eq!(
+            preprocessed.num_img_tokens,
+            Some(vec![(3 * 5 + 1) * 144 + (3 + 1) * 12 + 1])
+        );
+    }
+
+    #[test]
+    fn test_preprocess_1024x768() {
+        let config: PreProcessorConfig = serde_json::from_str(
+            r#"{
+                "do_convert_rgb": true,
+                "num_crops": 16,
+                "num_img_tokens": 144
+            }"#,
+        )
+        .unwrap();
+        let processor = Phi3InputsProcessor {
+            image_tag_splitter: Regex::new(r"<\|image_\d+\|>").unwrap(),
+        };
+        let image = DynamicImage::new_rgb8(1024, 768);
+        assert_eq!(
+            Phi3InputsProcessor::hd_transform(&image, 16).dimensions(),
+            (1344, 1008)
+        );
+        // A portrait image is transposed for the transform and transposed back.
+        assert_eq!(
+            Phi3InputsProcessor::hd_transform(&DynamicImage::new_rgb8(768, 1024), 16).dimensions(),
+            (1008, 1344)
+        );
+
+        let preprocessed = processor
+            .preprocess(vec![image], &config, &Device::Cpu, (0, 0))
+            .unwrap();
+
+        // The 1008x1344 HD image is a 3x4 grid of crops. With the global image, 13 of the
+        // `num_crops + 1` crops are filled and the rest are zero padding.
+        assert_eq!(preprocessed.pixel_values.dims(), &[1, 17, 3, 336, 336]);
+        let filled = (0..17)
+            .map(|i| {
+                preprocessed
+                    .pixel_values
+                    .i((0, i))
+                    .unwrap()
+                    .abs()
+                    .unwrap()
+                    .sum_all()
+                    .unwrap()
+                    .to_scalar::<f32>()
+                    .unwrap()
+                    > 0.
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(filled, [vec![true; 13], vec![false; 4]].concat());
+        assert_eq!(preprocessed.image_sizes_all, Some(vec![(1008, 1344)]));
+        assert_eq!(
+            preprocessed.num_img_tokens,
+            Some(vec![(3 * 4 + 1) * 144 + (3 + 1) * 12 + 1])
+        );
+    }
+
+    #[test]
+    fn test_preprocess_two_images() {
+        let config: PreProcessorConfig = serde_json::from_str(
+            r#"{
+                "do_convert_rgb": true,
+                "num_crops": 16,
+                "num_img_tokens": 144
+            }"#,
+        )
+        .unwrap();
+        let processor = Phi3InputsProcessor {
+            image_tag_splitter: Regex::new(r"<\|image_\d+\|>").unwrap(),
+        };
+        let images = vec![
+            DynamicImage::new_rgb8(512, 512),
+            DynamicImage::new_rgb8(512, 512),
+        ];
+
+        let preprocessed = processor
+            .preprocess(images, &config, &Device::Cpu, (0, 0))
+            .unwrap();
+
+        // Each image is HD transformed to 1344x1344, a 4x4 grid of crops plus the global image.
+        assert_eq!(preprocessed.pixel_values.dims(), &[2, 17, 3, 336, 336]);
+        assert_eq!(
+            preprocessed.image_sizes_all,
+            Some(vec![(1344, 1344), (1344, 1344)])
+        );
+        let num_img_tokens = (4 * 4 + 1) * 144 + (4 + 1) * 12 + 1;
+        assert_eq!(
+            preprocessed.num_img_tokens,
+            Some(vec![num_img_tokens, num_img_tokens])
+        );
+    }
+
+    #[test]
+    fn test_expand_image_tokens() {
+        let vocab = HashMap::from_iter(
+            ["<unk>", "Describe", "this", "image", "."]
+                .into_iter()
+                .enumerate()
+                .map(|(i, w)| (w.to_string(), i as u32)),
+        );
+        let mut tokenizer = Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("<unk>".to_string())
+                .build()
+                .unwrap(),
+        );
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        let processor = Phi3InputsProcessor {
+            image_tag_splitter: Regex::new(r"<\|image_\d+\|>").unwrap(),
+        };
+
+        // Image tokens of the 1000x600 image in `test_preprocess_1000x600`.
+        let num_img_tokens = (3 * 5 + 1) * 144 + (3 + 1) * 12 + 1;
+        let input_ids = processor
+            .expand_image_tokens(
+                &tokenizer,
+                "<|image_1|>\nDescribe this image.",
+                &[num_img_tokens],
+                1,
+            )
+            .unwrap();
+
+        assert_eq!(input_ids.len(), num_img_tokens + 4);
+        assert!(input_ids[..num_img_tokens].iter().all(|id| *id == -1));
+        assert_eq!(input_ids[num_img_tokens..], [1, 2, 3, 4]);
+
+        // The number of image tags must match the number of images.
+        assert!(processor
+            .expand_image_tokens(&tokenizer, "Describe this image.", &[num_img_tokens], 1)
+            .is_err());
+    }
+}
@@ -8,7 +8,7 @@ use crate::{
     paged_attention::ModelConfigMetadata,
     pipeline::{
         text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
-        IsqModel,
+        IsqModel, KVCacheDtype,
     },
     utils::progress::NiceProgressBar,
 };
@@ -51,6 +51,7 @@ impl CausalSelfAttention {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut LayerCaches,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -119,8 +120,13 @@ impl CausalSelfAttention {
                 .contiguous()?;
         }
 
-        let (k, v) =
-            crate::pipeline::Cache::update_kv_cache(&mut kv_cache[block_idx], k, v, false)?;
+        let (k, v) = crate::pipeline::Cache::update_kv_cache(
+            &mut kv_cache[block_idx],
+            k,
+            v,
+            false,
+            kv_cache_dtype,
+        )?;
 
         let y = Sdpa.run_attention(
             &q,
@@ -336,6 +342,7 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut LayerCaches,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -350,6 +357,7 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -447,6 +455,7 @@ impl XLoraLlama {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut x = self.wte.forward(input_ids)?;
+        let kv_cache_dtype = self.kv_cache.kv_cache_dtype();
         let mut cache = if is_full_pass {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
@@ -475,6 +484,7 @@ impl XLoraLlama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -824,7 +834,7 @@ impl NormalModel for XLoraLlama {
     fn max_seq_len(&self) -> usize {
         self.blocks[0].attn.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -832,26 +842,91 @@ impl NormalModel for XLoraLlama {
         for layer in self.blocks.iter_mut() {
             sum += Arc::get_mut(&mut layer.attn.k_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .activate_weighted(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    fn activate_adapters_per_seq(
+        &mut self,
+        adapter_names: Vec<Option<Vec<String>>>,
+    ) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter merging is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.attn.o_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .merge_adapters(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.attn.q_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .merge_adapters(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.attn.v_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .merge_adapters(&adapter_names)?;
 
             sum += Arc::get_mut(&mut layer.mlp.c_fc1)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .merge_adapters(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.mlp.c_fc2)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .merge_adapters(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.mlp.c_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .merge_adapters(&adapter_names)?;
         }
         Ok(sum)
     }
@@ -14,7 +14,7 @@ use crate::{
 };
 /// Mistral LLM, https://github.com/mistralai/mistral-src
 use candle_core::{DType, Device, Module, Result, Tensor};
-use candle_nn::{RotaryEmbedding, VarBuilder};
+use candle_nn::VarBuilder;
 use mistralrs_quant::QuantMethod;
 use std::{collections::HashMap, sync::Arc};
 use tqdm::Iter;
@@ -22,9 +22,9 @@ use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{Activation, CausalMasker, RmsNorm},
+    layers::{Activation, CausalMasker, Llama3RotaryEmbedding, RmsNorm, RotaryEmbedding},
     models::mistral::Config,
-    pipeline::{extract_logits, Cache, NormalModel},
+    pipeline::{extract_logits, Cache, KVCacheDtype, NormalModel},
 };
 
 use super::{classifier::XLoraClassifier, config::XLoraConfig, NonGranularState, ScalingsMaker};
@@ -139,7 +139,7 @@ struct Attention {
     num_heads: usize,
     num_kv_heads: usize,
     head_dim: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<Llama3RotaryEmbedding>,
     sliding_window: Option<usize>,
     sdpa_params: SdpaParams,
 }
@@ -147,7 +147,7 @@ struct Attention {
 impl Attention {
     #[allow(clippy::too_many_arguments)]
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<Llama3RotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         lora_config: &[((String, String), LoraConfig)],
@@ -230,6 +230,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -305,6 +306,7 @@ impl Attention {
             attention_mask,
             self.sliding_window,
             false,
+            kv_cache_dtype,
         )?;
 
         let mut attn_output = Sdpa.run_attention(
@@ -342,7 +344,7 @@ struct DecoderLayer {
 impl DecoderLayer {
     #[allow(clippy::too_many_arguments)]
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<Llama3RotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         lora_config: &[((String, String), LoraConfig)],
@@ -402,6 +404,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -415,6 +418,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -484,14 +488,25 @@ impl XLoraModel {
                 .unwrap_or(&normal_loading_metadata.real_device);
             ropes.insert(
                 device.location(),
-                Arc::new(RotaryEmbedding::new(
-                    cfg.rope_theta as f32,
-                    head_dim,
-                    cfg.max_position_embeddings,
-                    device,
-                    is_gptx,
-                    vb_m.dtype(),
-                )?),
+                Arc::new(match &cfg.rope_scaling {
+                    Some(rope_scaling) => Llama3RotaryEmbedding::new_scaled(
+                        vb_m.dtype(),
+                        rope_scaling,
+                        cfg.rope_theta as f32,
+                        head_dim,
+                        cfg.max_position_embeddings,
+                        device,
+                        is_gptx,
+                    )?,
+                    None => Llama3RotaryEmbedding::Default(RotaryEmbedding::new(
+                        cfg.rope_theta as f32,
+                        head_dim,
+                        cfg.max_position_embeddings,
+                        device,
+                        is_gptx,
+                        vb_m.dtype(),
+                    )?),
+                }),
             );
         }
         let mut count = 0;
@@ -603,6 +618,7 @@ impl XLoraModel {
         is_scaling_pass: Option<f64>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = if is_full_pass {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
@@ -635,6 +651,7 @@ impl XLoraModel {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -857,7 +874,7 @@ impl NormalModel for XLoraModel {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -865,26 +882,91 @@ impl NormalModel for XLoraModel {
         for layer in self.layers.iter_mut() {
             sum += Arc::get_mut(&mut layer.self_attn.k_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.self_attn.o_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.self_attn.q_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.self_attn.v_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
 
             sum += Arc::get_mut(&mut layer.mlp.down_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.mlp.gate_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.mlp.up_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    fn activate_adapters_per_seq(
+        &mut self,
+        adapter_names: Vec<Option<Vec<String>>>,
+    ) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter merging is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
         }
         Ok(sum)
     }
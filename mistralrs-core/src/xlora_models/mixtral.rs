@@ -26,7 +26,7 @@ use crate::{
     device_map::DeviceMapper,
     layers::{CausalMasker, RmsNorm},
     models::mixtral::Config,
-    pipeline::{extract_logits, Cache, NormalModel},
+    pipeline::{extract_logits, Cache, KVCacheDtype, NormalModel},
 };
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
@@ -130,6 +130,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -205,6 +206,7 @@ impl Attention {
             attention_mask,
             self.sliding_window,
             false,
+            kv_cache_dtype,
         )?;
 
         let mut attn_output = Sdpa.run_attention(
@@ -540,6 +542,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -553,6 +556,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            kv_cache_dtype,
             scalings.clone(),
             global_scaling_weight,
             is_scaling_pass,
@@ -744,6 +748,7 @@ impl XLoraModel {
         is_scaling_pass: Option<f64>,
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = if is_full_pass {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
@@ -776,6 +781,7 @@ impl XLoraModel {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -993,7 +999,7 @@ impl NormalModel for XLoraModel {
     fn max_seq_len(&self) -> usize {
         self.max_seq_len
     }
-    fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
@@ -1001,30 +1007,105 @@ impl NormalModel for XLoraModel {
         for layer in self.layers.iter_mut() {
             sum += Arc::get_mut(&mut layer.self_attn.k_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.self_attn.o_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.self_attn.q_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             sum += Arc::get_mut(&mut layer.self_attn.v_proj)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
 
             sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
                 .unwrap()
-                .activate(&adapter_names)?;
+                .activate_weighted(&adapter_names)?;
             for expert in &mut layer.block_sparse_moe.experts {
                 sum += Arc::get_mut(&mut expert.w1)
                     .unwrap()
-                    .activate(&adapter_names)?;
+                    .activate_weighted(&adapter_names)?;
                 sum += Arc::get_mut(&mut expert.w2)
                     .unwrap()
-                    .activate(&adapter_names)?;
+                    .activate_weighted(&adapter_names)?;
                 sum += Arc::get_mut(&mut expert.w3)
                     .unwrap()
-                    .activate(&adapter_names)?;
+                    .activate_weighted(&adapter_names)?;
+            }
+        }
+        Ok(sum)
+    }
+    fn activate_adapters_per_seq(
+        &mut self,
+        adapter_names: Vec<Option<Vec<String>>>,
+    ) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .activate_per_seq(&adapter_names)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1)
+                    .unwrap()
+                    .activate_per_seq(&adapter_names)?;
+                sum += Arc::get_mut(&mut expert.w2)
+                    .unwrap()
+                    .activate_per_seq(&adapter_names)?;
+                sum += Arc::get_mut(&mut expert.w3)
+                    .unwrap()
+                    .activate_per_seq(&adapter_names)?;
+            }
+        }
+        Ok(sum)
+    }
+    fn merge_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter merging is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .merge_adapters(&adapter_names)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1)
+                    .unwrap()
+                    .merge_adapters(&adapter_names)?;
+                sum += Arc::get_mut(&mut expert.w2)
+                    .unwrap()
+                    .merge_adapters(&adapter_names)?;
+                sum += Arc::get_mut(&mut expert.w3)
+                    .unwrap()
+                    .merge_adapters(&adapter_names)?;
             }
         }
         Ok(sum)
@@ -15,6 +15,7 @@ use std::sync::Arc;
 
 use crate::{lora::Ordering, pipeline::text_models_inputs_processor::FlashParams};
 use candle_core::{DType, Device, Result, Tensor};
+pub(crate) use classifier::XLoraClassifier;
 pub(crate) use config::XLoraConfig;
 pub(crate) use gemma::XLoraModel as XLoraGemma;
 pub(crate) use gemma2::Model as XLoraGemma2;
@@ -30,14 +31,12 @@ use tokio::sync::Mutex;
 
 use crate::{get_mut_arcmutex, pipeline::Cache};
 
-use self::classifier::XLoraClassifier;
-
 pub struct NonGranularState {
     pub non_granular_index: Arc<Mutex<usize>>,
     pub tgt_non_granular_index: usize,
 }
 
-trait ScalingsMaker {
+pub(crate) trait ScalingsMaker {
     fn get_classifier(&self) -> &XLoraClassifier;
     /// For dummy scalings
     fn dtype(&self) -> DType;
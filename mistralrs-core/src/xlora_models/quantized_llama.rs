@@ -13,13 +13,13 @@ use crate::utils::progress::NiceProgressBar;
 use candle_core::quantized::ggml_file;
 use candle_core::quantized::QMatMul;
 use candle_core::{DType, Device, Result, Tensor};
-use candle_nn::{Embedding, Module, RotaryEmbedding, VarBuilder};
+use candle_nn::{Embedding, Module, VarBuilder};
 use tqdm::Iter;
 use tracing::info;
 
 use crate::device_map::DeviceMapper;
-use crate::layers::{CausalMasker, MatMul, QRmsNorm, Sdpa};
-use crate::pipeline::{extract_logits, Cache};
+use crate::layers::{CausalMasker, Llama3RotaryEmbedding, MatMul, QRmsNorm, RotaryEmbedding, Sdpa};
+use crate::pipeline::{extract_logits, Cache, KVCacheDtype};
 use crate::{DeviceMapMetadata, Topology};
 
 use super::classifier::XLoraClassifier;
@@ -180,7 +180,7 @@ struct LayerWeights {
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
-    rotary: Arc<RotaryEmbedding>,
+    rotary: Arc<Llama3RotaryEmbedding>,
     sdpa_params: SdpaParams,
 }
 
@@ -193,6 +193,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -250,7 +251,7 @@ impl LayerWeights {
                 .contiguous()?;
         }
 
-        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
+        let (k, v) = Cache::update_kv_cache(kv_cache, k, v, false, kv_cache_dtype)?;
 
         let y = Sdpa.run_attention(
             &q,
@@ -295,7 +296,7 @@ impl ModelConfig::FromAdapterGGML for ModelWeights {
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let rotary = RotaryEmbedding::new_partial(
+        let rotary = Llama3RotaryEmbedding::Default(RotaryEmbedding::new_partial(
             10000.,
             head_dim,
             ct.hparams.n_rot as usize,
@@ -303,7 +304,7 @@ impl ModelConfig::FromAdapterGGML for ModelWeights {
             &ct.device,
             false,
             DType::F32,
-        )?;
+        )?);
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
         let norm = QRmsNorm::new(ct.remove("norm.weight")?, 1e-5)?;
@@ -511,6 +512,7 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
             rms_norm_eps,
             max_seq_len,
             rope_freq_base,
+            rope_scaling,
             key_length,
             value_length,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
@@ -540,14 +542,25 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
             let device = mapper.device_for(layer_idx, false).unwrap_or(device);
             ropes.insert(
                 device.location(),
-                Arc::new(RotaryEmbedding::new(
-                    rope_freq_base,
-                    rope_dim,
-                    max_seq_len,
-                    device,
-                    false,
-                    DType::F32,
-                )?),
+                Arc::new(match &rope_scaling {
+                    Some(rope_scaling) => Llama3RotaryEmbedding::new_scaled(
+                        DType::F32,
+                        rope_scaling,
+                        rope_freq_base,
+                        rope_dim,
+                        max_seq_len,
+                        device,
+                        false,
+                    )?,
+                    None => Llama3RotaryEmbedding::Default(RotaryEmbedding::new(
+                        rope_freq_base,
+                        rope_dim,
+                        max_seq_len,
+                        device,
+                        false,
+                        DType::F32,
+                    )?),
+                }),
             );
         }
 
@@ -779,21 +792,52 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
-            sum += layer.attention_wk.activate(&adapter_names)?;
-            sum += layer.attention_wo.activate(&adapter_names)?;
-            sum += layer.attention_wq.activate(&adapter_names)?;
-            sum += layer.attention_wv.activate(&adapter_names)?;
+            sum += layer.attention_wk.activate_weighted(&adapter_names)?;
+            sum += layer.attention_wo.activate_weighted(&adapter_names)?;
+            sum += layer.attention_wq.activate_weighted(&adapter_names)?;
+            sum += layer.attention_wv.activate_weighted(&adapter_names)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.activate_weighted(&adapter_names)?;
+                    sum += m.feed_forward_w2.activate_weighted(&adapter_names)?;
+                    sum += m.feed_forward_w3.activate_weighted(&adapter_names)?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.activate_weighted(&adapter_names)?;
+                        sum += expert.feed_forward_w2.activate_weighted(&adapter_names)?;
+                        sum += expert.feed_forward_w3.activate_weighted(&adapter_names)?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
+    pub fn merge_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter merging is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.merge_adapters(&adapter_names)?;
+            sum += layer.attention_wo.merge_adapters(&adapter_names)?;
+            sum += layer.attention_wq.merge_adapters(&adapter_names)?;
+            sum += layer.attention_wv.merge_adapters(&adapter_names)?;
             match &mut layer.mlp_or_moe {
                 MlpOrMoe::Mlp(ref mut m) => {
-                    sum += m.feed_forward_w1.activate(&adapter_names)?;
-                    sum += m.feed_forward_w2.activate(&adapter_names)?;
-                    sum += m.feed_forward_w3.activate(&adapter_names)?;
+                    sum += m.feed_forward_w1.merge_adapters(&adapter_names)?;
+                    sum += m.feed_forward_w2.merge_adapters(&adapter_names)?;
+                    sum += m.feed_forward_w3.merge_adapters(&adapter_names)?;
                 }
                 MlpOrMoe::MoE {
                     n_expert_used: _,
@@ -801,9 +845,9 @@ impl ModelWeights {
                     experts,
                 } => {
                     for expert in experts {
-                        sum += expert.feed_forward_w1.activate(&adapter_names)?;
-                        sum += expert.feed_forward_w2.activate(&adapter_names)?;
-                        sum += expert.feed_forward_w3.activate(&adapter_names)?;
+                        sum += expert.feed_forward_w1.merge_adapters(&adapter_names)?;
+                        sum += expert.feed_forward_w2.merge_adapters(&adapter_names)?;
+                        sum += expert.feed_forward_w3.merge_adapters(&adapter_names)?;
                     }
                 }
             }
@@ -824,6 +868,7 @@ impl ModelWeights {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = if is_full_pass {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
@@ -856,6 +901,7 @@ impl ModelWeights {
                 start_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
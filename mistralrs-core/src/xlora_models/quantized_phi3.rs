@@ -35,6 +35,7 @@ use super::NonGranularState;
 use super::ScalingsMaker;
 use super::XLoraConfig;
 use crate::models::quantized_phi3::PropsGGUF;
+use crate::pipeline::KVCacheDtype;
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 
@@ -119,6 +120,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache_dtype: KVCacheDtype,
         scalings: Option<Tensor>,
         global_scaling_weight: f64,
         is_scaling_pass: Option<f64>,
@@ -169,6 +171,7 @@ impl LayerWeights {
             mask,
             Some(self.sliding_window),
             true,
+            kv_cache_dtype,
         )?;
 
         let y = Sdpa.run_attention(
@@ -387,16 +390,29 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
 }
 
 impl ModelWeights {
-    pub fn activate_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+    pub fn activate_adapters(&mut self, adapter_names: Vec<(String, f64)>) -> Result<usize> {
         if self.xlora_classifier.is_some() {
             candle_core::bail!("Adapter activation is not supported for X-LoRA models as the adapter set must remain the same.");
         }
         let mut sum = 0;
         for layer in self.layers.iter_mut() {
-            sum += layer.attn_qkv.activate(&adapter_names)?;
-            sum += layer.attn_output.activate(&adapter_names)?;
-            sum += layer.mlp.ffn_down.activate(&adapter_names)?;
-            sum += layer.mlp.ffn_up.activate(&adapter_names)?;
+            sum += layer.attn_qkv.activate_weighted(&adapter_names)?;
+            sum += layer.attn_output.activate_weighted(&adapter_names)?;
+            sum += layer.mlp.ffn_down.activate_weighted(&adapter_names)?;
+            sum += layer.mlp.ffn_up.activate_weighted(&adapter_names)?;
+        }
+        Ok(sum)
+    }
+    pub fn merge_adapters(&mut self, adapter_names: Vec<String>) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter merging is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.merge_adapters(&adapter_names)?;
+            sum += layer.attn_output.merge_adapters(&adapter_names)?;
+            sum += layer.mlp.ffn_down.merge_adapters(&adapter_names)?;
+            sum += layer.mlp.ffn_up.merge_adapters(&adapter_names)?;
         }
         Ok(sum)
     }
@@ -413,6 +429,7 @@ impl ModelWeights {
         flash_params: &FlashParams,
     ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
+        let kv_cache_dtype = self.cache.kv_cache_dtype();
         let mut cache = if is_full_pass {
             if no_kv_cache {
                 let mut new_cache = Vec::new();
@@ -446,6 +463,7 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 &mut cache[i],
+                kv_cache_dtype,
                 scalings.clone(),
                 self.xlora_classifier
                     .as_ref()
@@ -0,0 +1,66 @@
+/*
+ * FP8 (E4M3) KV cache, as in vLLM. A cache of `uint8_t` holds the E4M3 bits of the keys or values
+ * divided by a per-tensor scale. They are quantized when written by `reshape_and_cache` and
+ * dequantized to the activations type when read by the attention kernels.
+ */
+#pragma once
+
+#include "attention_generic.cuh"
+#include "dtype_float16.cuh"
+#include "dtype_float32.cuh"
+#include "dtype_bfloat16.cuh"
+
+#include <cuda_fp8.h>
+#include <stdint.h>
+
+namespace vllm {
+namespace fp8 {
+
+inline __device__ uint8_t quantize(float x, float scale) {
+  return __nv_cvt_float_to_fp8(x / scale, __NV_SATFINITE, __NV_E4M3);
+}
+
+inline __device__ float dequantize(uint8_t x, float scale) {
+  const __half_raw h = __nv_cvt_fp8_to_halfraw(x, __NV_E4M3);
+  return __half2float(__half(h)) * scale;
+}
+
+} // namespace fp8
+
+// Converts an activation to the value stored in a cache of `cache_t`.
+template<typename scalar_t, typename cache_t>
+struct CacheStore {
+  static inline __device__ cache_t store(scalar_t x, float scale) {
+    return x;
+  }
+};
+
+template<typename scalar_t>
+struct CacheStore<scalar_t, uint8_t> {
+  static inline __device__ uint8_t store(scalar_t x, float scale) {
+    return fp8::quantize(to_float(x), scale);
+  }
+};
+
+// Loads `VEC_SIZE` consecutive elements of a cache of `cache_t` as a vector of activations.
+template<typename Vec_t, typename scalar_t, typename cache_t, int VEC_SIZE>
+struct CacheLoad {
+  static inline __device__ Vec_t load(const cache_t* ptr, float scale) {
+    return *reinterpret_cast<const Vec_t*>(ptr);
+  }
+};
+
+template<typename Vec_t, typename scalar_t, int VEC_SIZE>
+struct CacheLoad<Vec_t, scalar_t, uint8_t, VEC_SIZE> {
+  static inline __device__ Vec_t load(const uint8_t* ptr, float scale) {
+    Vec_t out;
+    scalar_t* out_ptr = reinterpret_cast<scalar_t*>(&out);
+#pragma unroll
+    for (int i = 0; i < VEC_SIZE; i++) {
+      from_float(out_ptr[i], fp8::dequantize(ptr[i], scale));
+    }
+    return out;
+  }
+};
+
+} // namespace vllm
@@ -5,12 +5,47 @@ use candle::cuda_backend::cudarc::driver::DevicePtr;
 use candle::cuda_backend::WrapErr;
 use candle::{CpuStorage, CudaStorage, DType, Layout, Result, Shape, Storage, Tensor};
 use candle_core as candle;
+use float8::F8E4M3;
 use half::{bf16, f16};
-use std::ffi::c_int;
+use std::ffi::{c_int, c_void};
+
+/// The `cache_dtype` of the kernels: 0 if a cache holds keys or values of the activation dtype
+/// `dtype`, 1 if it holds them quantized to FP8 (E4M3).
+fn cache_dtype(cache: &CudaStorage, dtype: DType) -> Result<u32> {
+    match cache.dtype() {
+        cache_dtype if cache_dtype == dtype => Ok(0),
+        DType::F8E4M3 => Ok(1),
+        cache_dtype => {
+            candle::bail!("a {cache_dtype:?} cache is not supported for {dtype:?} keys and values")
+        }
+    }
+}
+
+/// Device pointer to the first element of a cache of `T` or FP8 (E4M3) values.
+fn cache_ptr<
+    T: candle::cuda_backend::CudaDType + candle::cuda_backend::cudarc::driver::DeviceRepr,
+>(
+    cache: &CudaStorage,
+    layout: &Layout,
+) -> Result<*const c_void> {
+    let ptr = match cache.dtype() {
+        DType::F8E4M3 => *cache
+            .as_cuda_slice::<F8E4M3>()?
+            .slice(layout.start_offset()..)
+            .device_ptr(),
+        _ => *cache
+            .as_cuda_slice::<T>()?
+            .slice(layout.start_offset()..)
+            .device_ptr(),
+    };
+    Ok(ptr as *const c_void)
+}
 
 struct PagedAttention {
     softmax_scale: f32,
     softcapping: f32,
+    k_scale: f32,
+    v_scale: f32,
 
     key_cache: Tensor,
     value_cache: Tensor,
@@ -87,17 +122,24 @@ impl PagedAttention {
             )
         }
 
+        let cache_dtype = cache_dtype(kc, dtype)?;
+        if vc.dtype() != kc.dtype() {
+            candle::bail!(
+                "dtype mismatch key_cache {:?} and value_cache {:?}",
+                kc.dtype(),
+                vc.dtype()
+            )
+        }
+        let kc_ptr = cache_ptr::<T>(kc, kc_l)?;
+        let vc_ptr = cache_ptr::<T>(vc, vc_l)?;
+
         // Get cuda slices for all tensors
         let q = q.as_cuda_slice::<T>()?;
-        let kc = kc.as_cuda_slice::<T>()?;
-        let vc = vc.as_cuda_slice::<T>()?;
         let cl = cl.as_cuda_slice::<u32>()?; // Should be i32!
         let bt = bt.as_cuda_slice::<u32>()?; // Should be i32!
 
         // Get cuda views for all tensors
         let q = q.slice(q_l.start_offset()..);
-        let kc = kc.slice(kc_l.start_offset()..);
-        let vc = vc.slice(vc_l.start_offset()..);
         let cl = cl.slice(cl_l.start_offset()..);
         let bt = bt.slice(bt_l.start_offset()..);
 
@@ -161,8 +203,6 @@ impl PagedAttention {
 
         let out_ptr = *out.device_ptr() as *const core::ffi::c_void;
         let q_ptr = *q.device_ptr() as *const core::ffi::c_void;
-        let kc_ptr = *kc.device_ptr() as *const core::ffi::c_void;
-        let vc_ptr = *vc.device_ptr() as *const core::ffi::c_void;
         let bt_ptr = *bt.device_ptr() as *const core::ffi::c_int;
         let cl_ptr = *cl.device_ptr() as *const core::ffi::c_int;
 
@@ -188,6 +228,9 @@ impl PagedAttention {
                     kv_block_stride as c_int,
                     kv_head_stride as c_int,
                     internal_type,
+                    cache_dtype,
+                    self.k_scale,
+                    self.v_scale,
                 )
             }
         } else {
@@ -225,6 +268,9 @@ impl PagedAttention {
                     kv_block_stride as c_int,
                     kv_head_stride as c_int,
                     internal_type,
+                    cache_dtype,
+                    self.k_scale,
+                    self.v_scale,
                 )
             }
         }
@@ -263,13 +309,17 @@ impl candle::CustomOp1 for PagedAttention {
 ///
 /// * `q` - Query tensor with shape `(num_sequences, num_heads_q, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`
-///   with `x` being the size of an element in bytes.
-/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`.
+///   with `x` being the number of elements in 16 bytes. It has the dtype of `q`, or is an F8E4M3 cache
+///   written by [`reshape_and_cache`].
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`,
+///   of the dtype of `key_cache`.
 /// * `block_tables` - Padded table associating blocks to each sequence of shape `(num_sequences, max_context_len // block_size)`
 /// * `context_lens` - Tensor associating lengths to each sequence of shape `(num_sequences)`
 /// * `max_context_len` - Max of `context_len`
 /// * `softmax_scale` - scaling factor
 /// * `softcapping`- Softcapping value as in Gemma 2. Using 1.0 means do nothing.
+/// * `k_scale` - Scale the keys of an F8E4M3 cache are multiplied by when read, ignored otherwise.
+/// * `v_scale` - Scale the values of an F8E4M3 cache are multiplied by when read, ignored otherwise.
 ///
 /// The resulting tensor has dimensions `(num_sequences, num_heads_q, head_size)`.
 #[allow(clippy::too_many_arguments)]
@@ -282,9 +332,13 @@ pub fn paged_attention(
     max_context_len: usize,
     softmax_scale: f32,
     softcapping: f32,
+    k_scale: f32,
+    v_scale: f32,
 ) -> Result<Tensor> {
     let op = PagedAttention {
         softmax_scale,
+        k_scale,
+        v_scale,
         key_cache: key_cache.clone(),
         value_cache: value_cache.clone(),
         block_tables: block_tables.clone(),
@@ -303,6 +357,8 @@ fn update_cache<
     key_cache: &Tensor,
     value_cache: &Tensor,
     slot_mapping: &Tensor,
+    k_scale: f32,
+    v_scale: f32,
 ) -> Result<()> {
     let dtype = key.dtype();
 
@@ -366,18 +422,25 @@ fn update_cache<
         )
     }
 
+    let cache_dtype = cache_dtype(kc, dtype)?;
+    if vc.dtype() != kc.dtype() {
+        candle::bail!(
+            "dtype mismatch key_cache {:?} and value_cache {:?}",
+            kc.dtype(),
+            vc.dtype()
+        )
+    }
+    let kc_ptr = cache_ptr::<T>(kc, kc_l)?;
+    let vc_ptr = cache_ptr::<T>(vc, vc_l)?;
+
     // Get cuda slices for all tensors
     let k = k.as_cuda_slice::<T>()?;
     let v = v.as_cuda_slice::<T>()?;
-    let kc = kc.as_cuda_slice::<T>()?;
-    let vc = vc.as_cuda_slice::<T>()?;
     let s = s.as_cuda_slice::<i64>()?;
 
     // Get cuda views for all tensors
     let k = k.slice(k_l.start_offset()..);
     let v = v.slice(v_l.start_offset()..);
-    let kc = kc.slice(kc_l.start_offset()..);
-    let vc = vc.slice(vc_l.start_offset()..);
     let s = s.slice(s_l.start_offset()..);
 
     let (num_tokens, num_heads, head_size) = k_l.shape().dims3()?;
@@ -415,8 +478,6 @@ fn update_cache<
 
     let k_ptr = *k.device_ptr() as *const core::ffi::c_void;
     let v_ptr = *v.device_ptr() as *const core::ffi::c_void;
-    let kc_ptr = *kc.device_ptr() as *const core::ffi::c_void;
-    let vc_ptr = *vc.device_ptr() as *const core::ffi::c_void;
     let s_ptr = *s.device_ptr() as *const core::ffi::c_long;
 
     unsafe {
@@ -434,6 +495,9 @@ fn update_cache<
             key_stride,
             value_stride,
             internal_type,
+            cache_dtype,
+            k_scale,
+            v_scale,
         )
     }
     Ok(())
@@ -446,20 +510,51 @@ fn update_cache<
 /// * `key` - Key tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `value` - Value tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads, head_size / x, block_size, x)`
-///   with `x` being the size of an element in bytes.
-/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
+///   with `x` being the number of elements in 16 bytes. It has the dtype of `key`, or is F8E4M3, in
+///   which case the keys and values are quantized as they are written.
+/// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`,
+///   of the dtype of `key_cache`.
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
+/// * `k_scale` - Scale the keys are divided by when written to an F8E4M3 cache, ignored otherwise.
+/// * `v_scale` - Scale the values are divided by when written to an F8E4M3 cache, ignored otherwise.
+#[allow(clippy::too_many_arguments)]
 pub fn reshape_and_cache(
     key: &Tensor,
     value: &Tensor,
     key_cache: &Tensor,
     value_cache: &Tensor,
     slot_mapping: &Tensor,
+    k_scale: f32,
+    v_scale: f32,
 ) -> Result<()> {
     match key.dtype() {
-        DType::F16 => update_cache::<f16>(key, value, key_cache, value_cache, slot_mapping),
-        DType::BF16 => update_cache::<bf16>(key, value, key_cache, value_cache, slot_mapping),
-        DType::F32 => update_cache::<f32>(key, value, key_cache, value_cache, slot_mapping),
+        DType::F16 => update_cache::<f16>(
+            key,
+            value,
+            key_cache,
+            value_cache,
+            slot_mapping,
+            k_scale,
+            v_scale,
+        ),
+        DType::BF16 => update_cache::<bf16>(
+            key,
+            value,
+            key_cache,
+            value_cache,
+            slot_mapping,
+            k_scale,
+            v_scale,
+        ),
+        DType::F32 => update_cache::<f32>(
+            key,
+            value,
+            key_cache,
+            value_cache,
+            slot_mapping,
+            k_scale,
+            v_scale,
+        ),
         dt => {
             candle::bail!("reshape_and_cache is only supported for f32, f16 and bf16 ({dt:?})")
         }
@@ -79,4 +79,11 @@ extern "C" __global__ void copy_blocks_kernel_bf16(int64_t* key_cache_ptrs,
   const int64_t* __restrict__ block_mapping,
   const int numel_per_block) {
   copy_blocks_internal_kernel<int16_t>(key_cache_ptrs, value_cache_ptrs, block_mapping, numel_per_block);
-}
\ No newline at end of file
+}
+// An FP8 (E4M3) cache holds the bits of its values in bytes.
+extern "C" __global__ void copy_blocks_kernel_f8_e4m3(int64_t* key_cache_ptrs,
+  int64_t* value_cache_ptrs,
+  const int64_t* __restrict__ block_mapping,
+  const int numel_per_block) {
+  copy_blocks_internal_kernel<uint8_t>(key_cache_ptrs, value_cache_ptrs, block_mapping, numel_per_block);
+}
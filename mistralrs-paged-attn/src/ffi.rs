@@ -17,6 +17,9 @@ extern "C" {
         value_stride: c_int,
 
         dtype: u32,
+        cache_dtype: u32,
+        k_scale: f32,
+        v_scale: f32,
     );
 
     pub fn paged_attention_v1(
@@ -41,6 +44,9 @@ extern "C" {
         kv_head_stride: c_int,
 
         dtype: u32,
+        cache_dtype: u32,
+        k_scale: f32,
+        v_scale: f32,
     );
 
     pub fn paged_attention_v2(
@@ -68,5 +74,8 @@ extern "C" {
         kv_head_stride: c_int,
 
         dtype: u32,
+        cache_dtype: u32,
+        k_scale: f32,
+        v_scale: f32,
     );
 }
@@ -89,8 +89,11 @@ inline __device__ float fast_tanh(float x) {
 
 // TODO(woosuk): Merge the last two dimensions of the grid.
 // Grid: (num_heads, num_seqs, max_num_partitions).
+// The caches hold `cache_t`, which is `scalar_t` or `uint8_t` for an FP8 cache whose keys and
+// values are dequantized with `k_scale` and `v_scale` when they are loaded.
 template<
   typename scalar_t,
+  typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
@@ -100,8 +103,8 @@ __device__ void paged_attention_kernel(
   float* __restrict__ max_logits,         // [num_seqs, num_heads, max_num_partitions]
   scalar_t* __restrict__ out,             // [num_seqs, num_heads, max_num_partitions, head_size]
   const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ k_cache,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const scalar_t* __restrict__ v_cache,   // [num_blocks, num_kv_heads, head_size, block_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const float softcapping,
@@ -111,7 +114,9 @@ __device__ void paged_attention_kernel(
   const float* __restrict__ alibi_slopes, // [num_heads]
   const int q_stride,
   const int kv_block_stride,
-  const int kv_head_stride) {
+  const int kv_head_stride,
+  const float k_scale,
+  const float v_scale) {
   const int seq_idx = blockIdx.y;
   const int partition_idx = blockIdx.z;
   const int max_num_partitions = gridDim.z;
@@ -189,7 +194,7 @@ __device__ void paged_attention_kernel(
 
   // x == THREAD_GROUP_SIZE * VEC_SIZE
   // Each thread group fetches x elements from the key at a time.
-  constexpr int x = 16 / sizeof(scalar_t);
+  constexpr int x = 16 / sizeof(cache_t);
   float qk_max = -FLT_MAX;
 
   // Iterate over the key blocks.
@@ -215,13 +220,14 @@ __device__ void paged_attention_kernel(
 
 #pragma unroll
       for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
-        const scalar_t* k_ptr = k_cache + physical_block_number * kv_block_stride
+        const cache_t* k_ptr = k_cache + physical_block_number * kv_block_stride
                                         + kv_head_idx * kv_head_stride
                                         + physical_block_offset * x;
         const int vec_idx = thread_group_offset + j * THREAD_GROUP_SIZE;
         const int offset1 = (vec_idx * VEC_SIZE) / x;
         const int offset2 = (vec_idx * VEC_SIZE) % x;
-        k_vecs[j] = *reinterpret_cast<const K_vec*>(k_ptr + offset1 * BLOCK_SIZE * x + offset2);
+        k_vecs[j] = CacheLoad<K_vec, scalar_t, cache_t, VEC_SIZE>::load(
+          k_ptr + offset1 * BLOCK_SIZE * x + offset2, k_scale);
       }
 
       // Compute dot product.
@@ -326,14 +332,14 @@ __device__ void paged_attention_kernel(
     L_vec logits_vec;
     from_float(logits_vec, *reinterpret_cast<Float_L_vec*>(logits + token_idx - start_token_idx));
 
-    const scalar_t* v_ptr = v_cache + physical_block_number * kv_block_stride
+    const cache_t* v_ptr = v_cache + physical_block_number * kv_block_stride
                                     + kv_head_idx * kv_head_stride;
 #pragma unroll
     for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
       const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
       if (row_idx < HEAD_SIZE) {
         const int offset = row_idx * BLOCK_SIZE + physical_block_offset;
-        V_vec v_vec = *reinterpret_cast<const V_vec*>(v_ptr + offset);
+        V_vec v_vec = CacheLoad<V_vec, scalar_t, cache_t, V_VEC_SIZE>::load(v_ptr + offset, v_scale);
         if (block_idx == num_context_blocks - 1) {
           // NOTE(woosuk): When v_vec contains the tokens that are out of the context,
           // we should explicitly zero out the values since they may contain NaNs.
@@ -414,14 +420,15 @@ __device__ void paged_attention_kernel(
 // Grid: (num_heads, num_seqs, 1).
 template<
   typename scalar_t,
+  typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS>
 __global__ void paged_attention_v1_kernel(
   scalar_t* __restrict__ out,             // [num_seqs, num_heads, head_size]
   const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ k_cache,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const scalar_t* __restrict__ v_cache,   // [num_blocks, num_kv_heads, head_size, block_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const float softcapping,
@@ -431,16 +438,20 @@ __global__ void paged_attention_v1_kernel(
   const float* __restrict__ alibi_slopes, // [num_heads]
   const int q_stride,
   const int kv_block_stride,
-  const int kv_head_stride) {
-  paged_attention_kernel<scalar_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>(
+  const int kv_head_stride,
+  const float k_scale,
+  const float v_scale) {
+  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>(
     /* exp_sums */ nullptr, /* max_logits */ nullptr,
     out, q, k_cache, v_cache, num_kv_heads, scale, softcapping, block_tables, context_lens,
-    max_num_blocks_per_seq, alibi_slopes, q_stride, kv_block_stride, kv_head_stride);
+    max_num_blocks_per_seq, alibi_slopes, q_stride, kv_block_stride, kv_head_stride,
+    k_scale, v_scale);
 }
 
 // Grid: (num_heads, num_seqs, max_num_partitions).
 template<
   typename scalar_t,
+  typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
@@ -450,8 +461,8 @@ __global__ void paged_attention_v2_kernel(
   float* __restrict__ max_logits,         // [num_seqs, num_heads, max_num_partitions]
   scalar_t* __restrict__ tmp_out,         // [num_seqs, num_heads, max_num_partitions, head_size]
   const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ k_cache,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const scalar_t* __restrict__ v_cache,   // [num_blocks, num_kv_heads, head_size, block_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const float softcapping,
@@ -461,11 +472,13 @@ __global__ void paged_attention_v2_kernel(
   const float* __restrict__ alibi_slopes, // [num_heads]
   const int q_stride,
   const int kv_block_stride,
-  const int kv_head_stride) {
-  paged_attention_kernel<scalar_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE>(
+  const int kv_head_stride,
+  const float k_scale,
+  const float v_scale) {
+  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE>(
     exp_sums, max_logits, tmp_out, q, k_cache, v_cache, num_kv_heads, scale, softcapping,
     block_tables, context_lens, max_num_blocks_per_seq, alibi_slopes,
-    q_stride, kv_block_stride, kv_head_stride);
+    q_stride, kv_block_stride, kv_head_stride, k_scale, v_scale);
 }
 
 // Grid: (num_heads, num_seqs).
@@ -571,14 +584,14 @@ __global__ void paged_attention_v2_reduce_kernel(
 
 #define LAUNCH_PAGED_ATTENTION_V1(HEAD_SIZE)                                                  \
   VLLM_DevFuncAttribute_SET_MaxDynamicSharedMemorySize(                                       \
-    ((void*)vllm::paged_attention_v1_kernel<T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>),          \
+    ((void*)vllm::paged_attention_v1_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>), \
     shared_mem_size);                                                                         \
-  vllm::paged_attention_v1_kernel<T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>                      \
+  vllm::paged_attention_v1_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>             \
   <<<grid, block, shared_mem_size, stream>>>(                                                 \
     reinterpret_cast<T*>(out),                                                                \
     reinterpret_cast<T*>(query),                                                              \
-    reinterpret_cast<T*>(key_cache),                                                          \
-    reinterpret_cast<T*>(value_cache),                                                        \
+    reinterpret_cast<CACHE_T*>(key_cache),                                                    \
+    reinterpret_cast<CACHE_T*>(value_cache),                                                  \
     num_kv_heads,                                                                             \
     scale,                                                                                    \
     softcapping,                                                                              \
@@ -588,11 +601,14 @@ __global__ void paged_attention_v2_reduce_kernel(
     alibi_slopes_ptr,                                                                         \
     q_stride,                                                                                 \
     kv_block_stride,                                                                          \
-    kv_head_stride);
+    kv_head_stride,                                                                           \
+    k_scale,                                                                                  \
+    v_scale);
 
 // TODO(woosuk): Tune NUM_THREADS.
 template<
   typename T,
+  typename CACHE_T,
   int BLOCK_SIZE,
   int NUM_THREADS = 128>
 void paged_attention_v1_launcher(
@@ -613,7 +629,9 @@ void paged_attention_v1_launcher(
   int max_num_blocks_per_seq,
   int q_stride,
   int kv_block_stride,
-  int kv_head_stride
+  int kv_head_stride,
+  float k_scale,
+  float v_scale
   ) {
 
   // int thread_group_size = MAX(WARP_SIZE / BLOCK_SIZE, 1);
@@ -660,8 +678,8 @@ void paged_attention_v1_launcher(
   }
 }
 
-#define CALL_V1_LAUNCHER(T, BLOCK_SIZE)                             \
-  paged_attention_v1_launcher<T, BLOCK_SIZE>(                       \
+#define CALL_V1_LAUNCHER(T, CACHE_T, BLOCK_SIZE)                    \
+  paged_attention_v1_launcher<T, CACHE_T, BLOCK_SIZE>(              \
     out,                                                            \
     query,                                                          \
     key_cache,                                                      \
@@ -678,25 +696,35 @@ void paged_attention_v1_launcher(
     max_num_blocks_per_seq,                                         \
     q_stride,                                                       \
     kv_block_stride,                                                \
-    kv_head_stride);
+    kv_head_stride,                                                 \
+    k_scale,                                                        \
+    v_scale);
 
 // NOTE(woosuk): To reduce the compilation time, we omitted block sizes
 // 1, 2, 4, 64, 128, 256.
-#define CALL_V1_LAUNCHER_BLOCK_SIZE(T)                              \
+#define CALL_V1_LAUNCHER_BLOCK_SIZE(T, CACHE_T)                     \
   switch (block_size) {                                             \
     case 8:                                                         \
-      CALL_V1_LAUNCHER(T, 8);                                       \
+      CALL_V1_LAUNCHER(T, CACHE_T, 8);                              \
       break;                                                        \
     case 16:                                                        \
-      CALL_V1_LAUNCHER(T, 16);                                      \
+      CALL_V1_LAUNCHER(T, CACHE_T, 16);                             \
       break;                                                        \
     case 32:                                                        \
-      CALL_V1_LAUNCHER(T, 32);                                      \
+      CALL_V1_LAUNCHER(T, CACHE_T, 32);                             \
       break;                                                        \
     default:                                                        \
       break;                                                        \
   }
 
+// The cache holds the keys and values as they are, or quantized to FP8.
+#define CALL_V1_LAUNCHER_CACHE_DTYPE(T)                             \
+  if (cache_dtype == 1) {                                           \
+    CALL_V1_LAUNCHER_BLOCK_SIZE(T, uint8_t);                        \
+  } else {                                                          \
+    CALL_V1_LAUNCHER_BLOCK_SIZE(T, T);                              \
+  }
+
 extern "C" void paged_attention_v1(
   void *out,             // [num_seqs, num_heads, head_size]
   void *query,           // [num_seqs, num_heads, head_size]
@@ -718,36 +746,41 @@ extern "C" void paged_attention_v1(
   int32_t kv_block_stride,
   int32_t kv_head_stride,
 
-  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t dtype,       // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t cache_dtype, // 0 => same as the query; 1 => fp8 e4m3
+  float k_scale,        // keys are dequantized by multiplying by `k_scale` in an fp8 cache
+  float v_scale         // values are dequantized by multiplying by `v_scale` in an fp8 cache
   ) {
   if (dtype == 2) {
-    CALL_V1_LAUNCHER_BLOCK_SIZE(float);
+    CALL_V1_LAUNCHER_CACHE_DTYPE(float);
   } else if (dtype == 0) {
-    CALL_V1_LAUNCHER_BLOCK_SIZE(uint16_t);
+    CALL_V1_LAUNCHER_CACHE_DTYPE(uint16_t);
   } else if (dtype == 1) {
-    CALL_V1_LAUNCHER_BLOCK_SIZE(__nv_bfloat16);
+    CALL_V1_LAUNCHER_CACHE_DTYPE(__nv_bfloat16);
   }
 }
 
 #define LAUNCH_PAGED_ATTENTION_V2(HEAD_SIZE)                                                  \
-  vllm::paged_attention_v2_kernel<T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE>      \
+  vllm::paged_attention_v2_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE> \
   <<<grid, block, shared_mem_size, stream>>>(                                                 \
     exp_sums,                                                                                 \
     max_logits,                                                                               \
     tmp_out_ptr,                                                                              \
     reinterpret_cast<T*>(query),                                                              \
-    reinterpret_cast<T*>(key_cache),                                                          \
-    reinterpret_cast<T*>(value_cache),                                                        \
+    reinterpret_cast<CACHE_T*>(key_cache),                                                    \
+    reinterpret_cast<CACHE_T*>(value_cache),                                                  \
     num_kv_heads,                                                                             \
     scale,                                                                                    \
-    softcapping,                                                                                    \
+    softcapping,                                                                              \
     block_tables,                                                                             \
     context_lens,                                                                             \
     max_num_blocks_per_seq,                                                                   \
     alibi_slopes,                                                                             \
     q_stride,                                                                                 \
     kv_block_stride,                                                                          \
-    kv_head_stride);                                                                          \
+    kv_head_stride,                                                                           \
+    k_scale,                                                                                  \
+    v_scale);                                                                                 \
   vllm::paged_attention_v2_reduce_kernel<T, HEAD_SIZE, NUM_THREADS, PARTITION_SIZE>           \
   <<<reduce_grid, block, reduce_shared_mem_size, stream>>>(                                   \
     reinterpret_cast<T*>(out),                                                                \
@@ -759,6 +792,7 @@ extern "C" void paged_attention_v1(
 
 template<
   typename T,
+  typename CACHE_T,
   int BLOCK_SIZE,
   int NUM_THREADS = 128,
   int PARTITION_SIZE = 512>
@@ -783,8 +817,9 @@ void paged_attention_v2_launcher(
   int max_num_blocks_per_seq,
   int q_stride,
   int kv_block_stride,
-  int kv_head_stride
-
+  int kv_head_stride,
+  float k_scale,
+  float v_scale
   ) {
   // int thread_group_size = MAX(WARP_SIZE / BLOCK_SIZE, 1);
 
@@ -834,8 +869,8 @@ void paged_attention_v2_launcher(
   }
 }
 
-#define CALL_V2_LAUNCHER(T, BLOCK_SIZE)                             \
-  paged_attention_v2_launcher<T, BLOCK_SIZE>(                       \
+#define CALL_V2_LAUNCHER(T, CACHE_T, BLOCK_SIZE)                    \
+  paged_attention_v2_launcher<T, CACHE_T, BLOCK_SIZE>(              \
     out,                                                            \
     exp_sums,                                                       \
     max_logits,                                                     \
@@ -855,25 +890,35 @@ void paged_attention_v2_launcher(
     max_num_blocks_per_seq,                                         \
     q_stride,                                                       \
     kv_block_stride,                                                \
-    kv_head_stride);
+    kv_head_stride,                                                 \
+    k_scale,                                                        \
+    v_scale);
 
 // NOTE(woosuk): To reduce the compilation time, we omitted block sizes
 // 1, 2, 4, 64, 128, 256.
-#define CALL_V2_LAUNCHER_BLOCK_SIZE(T)                              \
+#define CALL_V2_LAUNCHER_BLOCK_SIZE(T, CACHE_T)                     \
   switch (block_size) {                                             \
     case 8:                                                         \
-      CALL_V2_LAUNCHER(T, 8);                                       \
+      CALL_V2_LAUNCHER(T, CACHE_T, 8);                              \
       break;                                                        \
     case 16:                                                        \
-      CALL_V2_LAUNCHER(T, 16);                                      \
+      CALL_V2_LAUNCHER(T, CACHE_T, 16);                             \
       break;                                                        \
     case 32:                                                        \
-      CALL_V2_LAUNCHER(T, 32);                                      \
+      CALL_V2_LAUNCHER(T, CACHE_T, 32);                             \
       break;                                                        \
     default:                                                        \
       break;                                                        \
   }
 
+// The cache holds the keys and values as they are, or quantized to FP8.
+#define CALL_V2_LAUNCHER_CACHE_DTYPE(T)                             \
+  if (cache_dtype == 1) {                                           \
+    CALL_V2_LAUNCHER_BLOCK_SIZE(T, uint8_t);                        \
+  } else {                                                          \
+    CALL_V2_LAUNCHER_BLOCK_SIZE(T, T);                              \
+  }
+
 extern "C" void paged_attention_v2(
   void *out,             // [num_seqs, num_heads, head_size]
   float *exp_sums,        // [num_seqs, num_heads, max_num_partitions]
@@ -898,14 +943,17 @@ extern "C" void paged_attention_v2(
   int32_t kv_block_stride,
   int32_t kv_head_stride,
 
-  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t dtype,       // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t cache_dtype, // 0 => same as the query; 1 => fp8 e4m3
+  float k_scale,        // keys are dequantized by multiplying by `k_scale` in an fp8 cache
+  float v_scale         // values are dequantized by multiplying by `v_scale` in an fp8 cache
   ) {
   if (dtype == 2) {
-    CALL_V2_LAUNCHER_BLOCK_SIZE(float);
+    CALL_V2_LAUNCHER_CACHE_DTYPE(float);
   } else if (dtype == 0) {
-    CALL_V2_LAUNCHER_BLOCK_SIZE(uint16_t);
+    CALL_V2_LAUNCHER_CACHE_DTYPE(uint16_t);
   } else if (dtype == 1) {
-    CALL_V2_LAUNCHER_BLOCK_SIZE(__nv_bfloat16);
+    CALL_V2_LAUNCHER_CACHE_DTYPE(__nv_bfloat16);
   }
 }
 
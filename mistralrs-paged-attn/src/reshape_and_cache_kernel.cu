@@ -3,6 +3,7 @@
 #include <stdint.h>
 
 #include "cuda_compat.h"
+#include "attention/dtype_fp8.cuh"
 
 #include <algorithm>
 #include <cassert>
@@ -11,19 +12,21 @@
 
 namespace vllm {
 
-template<typename scalar_t>
+template<typename scalar_t, typename cache_t>
 __global__ void reshape_and_cache_kernel(
   const scalar_t* __restrict__ key,           // [num_tokens, num_heads, head_size]
   const scalar_t* __restrict__ value,         // [num_tokens, num_heads, head_size]
-  scalar_t* __restrict__ key_cache,           // [num_blocks, num_heads, head_size/x, block_size, x]
-  scalar_t* __restrict__ value_cache,         // [num_blocks, num_heads, head_size, block_size]
+  cache_t* __restrict__ key_cache,            // [num_blocks, num_heads, head_size/x, block_size, x]
+  cache_t* __restrict__ value_cache,          // [num_blocks, num_heads, head_size, block_size]
   const int64_t* __restrict__ slot_mapping,   // [num_tokens]
   const int key_stride,
   const int value_stride,
   const int num_heads,
   const int head_size,
   const int block_size,
-  const int x) {
+  const int x,
+  const float k_scale,
+  const float v_scale) {
   const int64_t token_idx = blockIdx.x;
   const int64_t slot_idx = slot_mapping[token_idx];
   if (slot_idx < 0) {
@@ -53,24 +56,34 @@ __global__ void reshape_and_cache_kernel(
                                   + head_idx * head_size * block_size
                                   + head_offset * block_size
                                   + block_offset;
-    key_cache[tgt_key_idx] = key[src_key_idx];
-    value_cache[tgt_value_idx] = value[src_value_idx];
+    key_cache[tgt_key_idx] = CacheStore<scalar_t, cache_t>::store(key[src_key_idx], k_scale);
+    value_cache[tgt_value_idx] = CacheStore<scalar_t, cache_t>::store(value[src_value_idx], v_scale);
   }
 }
 
-#define CALL_RESHAPE_AND_CACHE(T)                                     \
-  vllm::reshape_and_cache_kernel<T><<<grid, block, 0, stream>>>(      \
+#define CALL_RESHAPE_AND_CACHE(T, CACHE_T)                            \
+  vllm::reshape_and_cache_kernel<T, CACHE_T><<<grid, block, 0, stream>>>( \
     reinterpret_cast<T*>(key),                                        \
     reinterpret_cast<T*>(value),                                      \
-    reinterpret_cast<T*>(key_cache),                                  \
-    reinterpret_cast<T*>(value_cache),                                \
+    reinterpret_cast<CACHE_T*>(key_cache),                            \
+    reinterpret_cast<CACHE_T*>(value_cache),                          \
     slot_mapping,                                                     \
     key_stride,                                                       \
     value_stride,                                                     \
     num_heads,                                                        \
     head_size,                                                        \
     block_size,                                                       \
-    x);
+    x,                                                                \
+    k_scale,                                                          \
+    v_scale);
+
+// The cache holds the keys and values as they are, or quantized to FP8.
+#define CALL_RESHAPE_AND_CACHE_BY_CACHE_DTYPE(T)                      \
+  if (cache_dtype == 1) {                                             \
+    CALL_RESHAPE_AND_CACHE(T, uint8_t);                               \
+  } else {                                                            \
+    CALL_RESHAPE_AND_CACHE(T, T);                                     \
+  }
 
 
 } // namespace vllm
@@ -90,7 +103,10 @@ extern "C" void reshape_and_cache(
   int32_t key_stride,
   int32_t value_stride,
 
-  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t dtype,      // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t cache_dtype, // 0 => same as the keys and values; 1 => fp8 e4m3
+  float k_scale,        // keys are stored divided by `k_scale` in an fp8 cache
+  float v_scale         // values are stored divided by `v_scale` in an fp8 cache
   )
 {
   dim3 grid(num_tokens);
@@ -98,10 +114,10 @@ extern "C" void reshape_and_cache(
   const cudaStream_t stream = 0;
 
   if (dtype == 0){
-    CALL_RESHAPE_AND_CACHE(uint16_t);
+    CALL_RESHAPE_AND_CACHE_BY_CACHE_DTYPE(uint16_t);
   } else if (dtype == 1) {
-    CALL_RESHAPE_AND_CACHE(__nv_bfloat16);
+    CALL_RESHAPE_AND_CACHE_BY_CACHE_DTYPE(__nv_bfloat16);
   } else if (dtype == 2) {
-    CALL_RESHAPE_AND_CACHE(float);
+    CALL_RESHAPE_AND_CACHE_BY_CACHE_DTYPE(float);
   }
 }
\ No newline at end of file
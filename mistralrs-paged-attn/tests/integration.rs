@@ -0,0 +1,152 @@
+#![cfg(all(feature = "cuda", target_family = "unix"))]
+
+use candle_core::{DType, Device, Result, Tensor};
+use mistralrs_paged_attn::{paged_attention, reshape_and_cache};
+
+const NUM_HEADS: usize = 4;
+const NUM_KV_HEADS: usize = 2;
+const HEAD_SIZE: usize = 64;
+const BLOCK_SIZE: usize = 16;
+const NUM_BLOCKS: usize = 8;
+/// Relative rounding error of F8E4M3 (3 mantissa bits), with a margin for the f16 activations.
+const FP8_REL_ERR: f32 = 0.07;
+
+/// Paged key and value caches of `dtype`, as the cache engine allocates them.
+fn zeroed_caches(dtype: DType, dev: &Device) -> Result<(Tensor, Tensor)> {
+    let x = 16 / dtype.size_in_bytes();
+    let key_cache = Tensor::zeros(
+        (NUM_BLOCKS, NUM_KV_HEADS, HEAD_SIZE / x, BLOCK_SIZE, x),
+        dtype,
+        dev,
+    )?;
+    let value_cache = Tensor::zeros(
+        (NUM_BLOCKS, NUM_KV_HEADS, HEAD_SIZE, BLOCK_SIZE),
+        dtype,
+        dev,
+    )?;
+    Ok((key_cache, value_cache))
+}
+
+/// The caches as f32 `(num_blocks, num_kv_heads, block_size, head_size)` tensors, multiplied by
+/// the scales of an FP8 cache.
+fn read_caches(
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    k_scale: f32,
+    v_scale: f32,
+) -> Result<(Tensor, Tensor)> {
+    let keys = key_cache
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .permute((0, 1, 3, 2, 4))?
+        .reshape((NUM_BLOCKS, NUM_KV_HEADS, BLOCK_SIZE, HEAD_SIZE))?
+        .affine(k_scale as f64, 0.)?;
+    let values = value_cache
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .transpose(2, 3)?
+        .affine(v_scale as f64, 0.)?;
+    Ok((keys, values))
+}
+
+fn max_abs(xs: &Tensor) -> Result<f32> {
+    xs.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+#[test]
+fn fp8_cache_matches_full_precision() -> Result<()> {
+    let dev = Device::new_cuda(0)?;
+    // Two sequences, the first one ending inside its second block.
+    let context_lens = [20u32, 33];
+    let block_tables = [[0u32, 1, 0], [2, 3, 4]];
+    let slot_mapping = context_lens
+        .iter()
+        .zip(&block_tables)
+        .flat_map(|(len, blocks)| {
+            (0..*len as usize).map(|pos| {
+                (blocks[pos / BLOCK_SIZE] as usize * BLOCK_SIZE + pos % BLOCK_SIZE) as i64
+            })
+        })
+        .collect::<Vec<_>>();
+    let num_tokens = slot_mapping.len();
+    let slot_mapping = Tensor::new(slot_mapping, &dev)?;
+    let block_tables = Tensor::new(&block_tables, &dev)?;
+    let max_context_len = *context_lens.iter().max().unwrap() as usize;
+    let context_lens = Tensor::new(&context_lens, &dev)?;
+
+    // Keys larger than the E4M3 range unless divided by their scale.
+    let (k_scale, v_scale) = (2f32, 0.5f32);
+    let key = (Tensor::randn(0f32, 1., (num_tokens, NUM_KV_HEADS, HEAD_SIZE), &dev)? * 150.)?
+        .to_dtype(DType::F16)?;
+    let value = Tensor::randn(0f32, 1., (num_tokens, NUM_KV_HEADS, HEAD_SIZE), &dev)?
+        .to_dtype(DType::F16)?;
+    let query = (Tensor::randn(0f32, 1., (2, NUM_HEADS, HEAD_SIZE), &dev)? * 0.005)?
+        .to_dtype(DType::F16)?;
+    let softmax_scale = 1. / (HEAD_SIZE as f32).sqrt();
+
+    let (key_cache, value_cache) = zeroed_caches(DType::F16, &dev)?;
+    reshape_and_cache(
+        &key,
+        &value,
+        &key_cache,
+        &value_cache,
+        &slot_mapping,
+        1.,
+        1.,
+    )?;
+    let (fp8_key_cache, fp8_value_cache) = zeroed_caches(DType::F8E4M3, &dev)?;
+    reshape_and_cache(
+        &key,
+        &value,
+        &fp8_key_cache,
+        &fp8_value_cache,
+        &slot_mapping,
+        k_scale,
+        v_scale,
+    )?;
+
+    // The FP8 cache holds each key and value within its rounding error.
+    let (keys, values) = read_caches(&key_cache, &value_cache, 1., 1.)?;
+    let (fp8_keys, fp8_values) = read_caches(&fp8_key_cache, &fp8_value_cache, k_scale, v_scale)?;
+    for (full, fp8, scale) in [(&keys, &fp8_keys, k_scale), (&values, &fp8_values, v_scale)] {
+        // Subnormal E4M3 values are multiples of 2^-9.
+        let bound = full
+            .abs()?
+            .affine(FP8_REL_ERR as f64, (scale / 512.) as f64)?;
+        let excess = ((full - fp8)?.abs()? - bound)?;
+        assert!(max_abs(&excess.relu()?)? == 0.);
+    }
+    assert!(max_abs(&fp8_values)? > 0.);
+
+    let out = paged_attention(
+        &query,
+        &key_cache,
+        &value_cache,
+        &block_tables,
+        &context_lens,
+        max_context_len,
+        softmax_scale,
+        1.,
+        1.,
+        1.,
+    )?
+    .to_dtype(DType::F32)?;
+    let fp8_out = paged_attention(
+        &query,
+        &fp8_key_cache,
+        &fp8_value_cache,
+        &block_tables,
+        &context_lens,
+        max_context_len,
+        softmax_scale,
+        1.,
+        k_scale,
+        v_scale,
+    )?
+    .to_dtype(DType::F32)?;
+    assert_eq!(fp8_out.dims(), &[2, NUM_HEADS, HEAD_SIZE]);
+
+    let rel_err = max_abs(&(&fp8_out - &out)?)? / max_abs(&out)?;
+    assert!(rel_err < 2. * FP8_REL_ERR, "relative error {rel_err}");
+    Ok(())
+}
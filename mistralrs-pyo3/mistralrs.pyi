@@ -39,6 +39,9 @@ class ChatCompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    mirostat_version: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
 
 @dataclass
 class CompletionRequest:
@@ -67,6 +70,9 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    mirostat_version: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -353,6 +359,11 @@ class AnyMoeConfig:
         gate_model_id: str | None = None,
         training: bool = False,
         loss_csv_path: str | None = None,
+        checkpoint_dir: str | None = None,
+        resume_from_checkpoint: bool = False,
+        gradient_checkpointing: bool = False,
+        validation_split: float = 0.0,
+        early_stopping_patience: int | None = None,
     ) -> None:
         """
         Create an AnyMoE config from the hidden size, dataset, and other metadata. The model IDs may be local paths.
@@ -370,6 +381,16 @@ class AnyMoeConfig:
             Otherwise, the pretrained safetensors will be loaded and no training occurs.
 
         > Note: if `training == True`, `loss_csv_path` has no effect. Otherwise, an csv loss file will be saved here.
+
+        > Note: if `checkpoint_dir` is specified, the gating layers are checkpointed there at the end of each epoch.
+            With `resume_from_checkpoint`, a matching checkpoint is loaded and training continues from it.
+
+        > Note: with `gradient_checkpointing`, the gating layer outputs are recomputed for the backward pass
+            instead of being kept during the forward pass, reducing device memory use while training.
+
+        > Note: `validation_split` holds out this fraction of the dataset to compute a validation loss at the end of
+            each epoch. With `early_stopping_patience`, training stops once the validation loss has not improved for
+            that many epochs, and the gating layers from the best epoch are kept.
         """
         ...
 
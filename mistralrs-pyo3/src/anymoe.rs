@@ -44,6 +44,11 @@ pub struct AnyMoeConfig {
     pub(crate) gate_model_id: Option<String>,
     pub(crate) training: bool,
     pub(crate) loss_csv_path: Option<String>,
+    pub(crate) checkpoint_dir: Option<String>,
+    pub(crate) resume_from_checkpoint: bool,
+    pub(crate) gradient_checkpointing: bool,
+    pub(crate) validation_split: f64,
+    pub(crate) early_stopping_patience: Option<usize>,
 }
 
 #[pymethods]
@@ -63,6 +68,11 @@ impl AnyMoeConfig {
         gate_model_id = None,
         training = true,
         loss_csv_path = None,
+        checkpoint_dir = None,
+        resume_from_checkpoint = false,
+        gradient_checkpointing = false,
+        validation_split = 0.0,
+        early_stopping_patience = None,
     ))]
     fn new(
         hidden_size: usize,
@@ -78,6 +88,11 @@ impl AnyMoeConfig {
         gate_model_id: Option<String>,
         training: bool,
         loss_csv_path: Option<String>,
+        checkpoint_dir: Option<String>,
+        resume_from_checkpoint: bool,
+        gradient_checkpointing: bool,
+        validation_split: f64,
+        early_stopping_patience: Option<usize>,
     ) -> Self {
         Self {
             hidden_size,
@@ -93,6 +108,11 @@ impl AnyMoeConfig {
             gate_model_id,
             training,
             loss_csv_path,
+            checkpoint_dir,
+            resume_from_checkpoint,
+            gradient_checkpointing,
+            validation_split,
+            early_stopping_patience,
         }
     }
 }
@@ -23,10 +23,11 @@ use mistralrs_core::{
     DeviceLayerMapMetadata, DeviceMapMetadata, DiffusionGenerationParams, DiffusionLoaderBuilder,
     DiffusionSpecificConfig, DrySamplingParams, GGMLLoaderBuilder, GGMLSpecificConfig,
     GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat,
-    Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, PagedAttentionConfig, Request as _Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens,
-    TokenSource, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
+    Loader, MemoryGpuConfig, MirostatConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder,
+    NormalRequest, NormalSpecificConfig, PagedAttentionConfig, Request as _Request, RequestMessage,
+    Response, ResponseOk, SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader,
+    StopTokens, TokenSource, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
+    VocabMismatchPolicy,
 };
 use pyo3::prelude::*;
 use std::fs::File;
@@ -94,8 +95,11 @@ fn parse_which(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.map(Into::into).unwrap_or(Default::default()),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -120,8 +124,11 @@ fn parse_which(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -154,8 +161,11 @@ fn parse_which(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -185,6 +195,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -206,6 +217,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -235,6 +247,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -259,6 +272,7 @@ fn parse_which(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -284,6 +298,7 @@ fn parse_which(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -317,6 +332,7 @@ fn parse_which(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -346,7 +362,10 @@ fn parse_which(
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_dtype: Default::default(),
                 topology: Topology::from_option_path(topology)?,
+                isq_exclude: Vec::new(),
+                isq_layer_types: Vec::new(),
                 write_uqff,
                 from_uqff,
             },
@@ -468,6 +487,9 @@ impl Runner {
                 draft,
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    vocab_mismatch_policy: VocabMismatchPolicy::Strict,
+                    draft_branches: 1,
+                    adaptive_gamma: None,
                 },
             })
         } else {
@@ -485,6 +507,11 @@ impl Runner {
                     gate_model_id: amoe_conf.gate_model_id.clone(),
                     training: amoe_conf.training,
                     loss_csv_path: amoe_conf.loss_csv_path.clone(),
+                    checkpoint_dir: amoe_conf.checkpoint_dir.clone().map(Into::into),
+                    resume_from_checkpoint: amoe_conf.resume_from_checkpoint,
+                    gradient_checkpointing: amoe_conf.gradient_checkpointing,
+                    validation_split: amoe_conf.validation_split,
+                    early_stopping_patience: amoe_conf.early_stopping_patience,
                 },
                 path: amoe_conf.dataset_json,
                 prefix: amoe_conf.prefix,
@@ -653,9 +680,23 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                let Some(grammar) = request.grammar.as_ref() else {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                };
+                Constraint::JsonSchema(serde_json::from_str(grammar)?)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, `gbnf` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -671,6 +712,13 @@ impl Runner {
             } else {
                 None
             };
+            let mirostat = request.mirostat_version.map(|version| {
+                MirostatConfig::new_with_defaults(
+                    version,
+                    request.mirostat_tau,
+                    request.mirostat_eta,
+                )
+            });
 
             let messages = match request.messages {
                 Either::Left(ref messages) => {
@@ -844,14 +892,31 @@ impl Runner {
                     top_k: request.top_k,
                     top_p: request.top_p,
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
+                    collect_all_logprobs: false,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    no_repeat_ngram_size: None,
                     max_len: request.max_tokens,
+                    min_new_tokens: None,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
+                    string_logits_bias: None,
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    xtc_probability: None,
+                    xtc_threshold: None,
+                    typical_p: None,
+                    epsilon_cutoff: None,
+                    eta_cutoff: None,
                     dry_params,
+                    mirostat,
+                    beam_search: None,
+                    contrastive_search: None,
+                    cfg_scale: None,
+                    negative_prompt: None,
+                    seed: None,
+                    bad_token_ids: None,
+                    token_healing: false,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -861,6 +926,8 @@ impl Runner {
                 adapters: request.adapters.clone(),
                 tool_choice,
                 tools,
+                timeout_ms: None,
+                return_prompt_tokens: false,
                 logits_processors: None,
             });
 
@@ -884,6 +951,7 @@ impl Runner {
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::ImageGenerationProgress(_) => unreachable!(),
                 }
             }
         })
@@ -915,9 +983,23 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                let Some(grammar) = request.grammar.as_ref() else {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                };
+                Constraint::JsonSchema(serde_json::from_str(grammar)?)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, `gbnf` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -948,6 +1030,13 @@ impl Runner {
             } else {
                 None
             };
+            let mirostat = request.mirostat_version.map(|version| {
+                MirostatConfig::new_with_defaults(
+                    version,
+                    request.mirostat_tau,
+                    request.mirostat_eta,
+                )
+            });
 
             let model_request = _Request::Normal(NormalRequest {
                 id: {
@@ -967,14 +1056,31 @@ impl Runner {
                     top_k: request.top_k,
                     top_p: request.top_p,
                     top_n_logprobs: 1,
+                    collect_all_logprobs: false,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    no_repeat_ngram_size: None,
                     max_len: request.max_tokens,
+                    min_new_tokens: None,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
+                    string_logits_bias: None,
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    xtc_probability: None,
+                    xtc_threshold: None,
+                    typical_p: None,
+                    epsilon_cutoff: None,
+                    eta_cutoff: None,
                     dry_params,
+                    mirostat,
+                    beam_search: None,
+                    contrastive_search: None,
+                    cfg_scale: None,
+                    negative_prompt: None,
+                    seed: None,
+                    bad_token_ids: None,
+                    token_healing: false,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -984,6 +1090,8 @@ impl Runner {
                 adapters: request.adapters.clone(),
                 tool_choice,
                 tools,
+                timeout_ms: None,
+                return_prompt_tokens: false,
                 logits_processors: None,
             });
 
@@ -1003,6 +1111,7 @@ impl Runner {
                 Response::ModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::ImageGenerationProgress(_) => unreachable!(),
             }
         })
     }
@@ -1039,6 +1148,8 @@ impl Runner {
             adapters: None,
             tool_choice: None,
             tools: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
             logits_processors: None,
         });
 
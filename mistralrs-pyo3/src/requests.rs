@@ -43,6 +43,9 @@ pub struct CompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) mirostat_version: Option<u8>,
+    pub(crate) mirostat_tau: Option<f64>,
+    pub(crate) mirostat_eta: Option<f64>,
 }
 
 #[pymethods]
@@ -73,6 +76,9 @@ impl CompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        mirostat_version=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
     ))]
     fn new(
         prompt: String,
@@ -99,6 +105,9 @@ impl CompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        mirostat_version: Option<u8>,
+        mirostat_tau: Option<f64>,
+        mirostat_eta: Option<f64>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -125,6 +134,9 @@ impl CompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            mirostat_version,
+            mirostat_tau,
+            mirostat_eta,
         })
     }
 }
@@ -166,6 +178,9 @@ pub struct ChatCompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) mirostat_version: Option<u8>,
+    pub(crate) mirostat_tau: Option<f64>,
+    pub(crate) mirostat_eta: Option<f64>,
 }
 
 #[pymethods]
@@ -196,6 +211,9 @@ impl ChatCompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        mirostat_version=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -222,6 +240,9 @@ impl ChatCompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        mirostat_version: Option<u8>,
+        mirostat_tau: Option<f64>,
+        mirostat_eta: Option<f64>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -296,6 +317,9 @@ impl ChatCompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            mirostat_version,
+            mirostat_tau,
+            mirostat_eta,
         })
     }
 }
@@ -10,6 +10,7 @@ fn main() {
         let lib_files = vec![
             "kernels/gptq/q_gemm.cu",
             "kernels/hqq/hqq.cu",
+            "kernels/awq/awq.cu",
             "kernels/ops/ops.cu",
             "kernels/marlin/marlin_kernel.cu",
         ];
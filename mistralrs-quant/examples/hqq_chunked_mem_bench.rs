@@ -0,0 +1,89 @@
+//! Compares the peak heap usage of an HQQ forward pass on the CPU when dequantizing the whole
+//! weight (`force_dequantize`) against the chunked dequantize-matmul.
+//!
+//! ```bash
+//! cargo run --release -p mistralrs-quant --example hqq_chunked_mem_bench
+//! ```
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use candle_core::{DType, Device, Result, Tensor};
+use mistralrs_quant::{HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod};
+
+struct PeakAlloc {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = self.current.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            self.peak.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        self.current.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc {
+    current: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+/// Extra heap bytes allocated at the peak of one forward pass, in MiB. A first, unmeasured
+/// forward pass sets up the thread pool and matmul buffers, which would otherwise be counted
+/// against whichever layer runs first.
+fn forward_peak_mib(layer: &HqqLayer, xs: &Tensor) -> Result<f64> {
+    layer.forward(xs)?;
+    let base = ALLOC.current.load(Ordering::Relaxed);
+    ALLOC.peak.store(base, Ordering::Relaxed);
+    layer.forward(xs)?;
+    let peak = ALLOC.peak.load(Ordering::Relaxed);
+    Ok((peak - base) as f64 / (1024. * 1024.))
+}
+
+fn main() -> Result<()> {
+    let dev = Device::Cpu;
+
+    println!(
+        "{:>6} {:>5} {:>17} {:>16} {:>8}",
+        "size", "bits", "full dequant MiB", "chunked MiB", "ratio"
+    );
+    for size in [1024, 2048, 4096, 8192] {
+        let w = Tensor::rand(-1f32, 1f32, (size, size), &dev)?.to_dtype(DType::BF16)?;
+        let xs = Tensor::rand(-1f32, 1f32, (1, size), &dev)?.to_dtype(DType::BF16)?;
+        for bits in [HqqBits::Eight, HqqBits::Four] {
+            let cfg = |force_dequantize| HqqConfig {
+                bits,
+                group_size: 64.try_into().unwrap(),
+                axis: HqqAxis::Zero,
+                optimization_steps: None,
+                round_zeros: false,
+                channel_wise: true,
+                force_dequantize,
+            };
+            let full = HqqLayer::quantize(&w, &dev, cfg(true))?;
+            let chunked = HqqLayer::quantize(&w, &dev, cfg(false))?;
+
+            let full_mib = forward_peak_mib(&full, &xs)?;
+            let chunked_mib = forward_peak_mib(&chunked, &xs)?;
+            println!(
+                "{size:>6} {:>5} {full_mib:>17.2} {chunked_mib:>16.2} {:>7.2}x",
+                bits as usize,
+                full_mib / chunked_mib
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! Compares the fused 4 bit HQQ dequantize-matmul kernel against dequantizing the weight and then
+//! running a regular matmul.
+//!
+//! ```bash
+//! cargo run --release -p mistralrs-quant --features fused-hqq --example hqq_fused_bench
+//! ```
+
+use std::time::Instant;
+
+use candle_core::{DType, Device, Result, Tensor};
+use mistralrs_quant::{HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod};
+
+const WARMUP_ITERS: usize = 3;
+const BENCH_ITERS: usize = 20;
+
+fn bench(layer: &HqqLayer, xs: &Tensor, dev: &Device) -> Result<f64> {
+    for _ in 0..WARMUP_ITERS {
+        layer.forward(xs)?;
+    }
+    dev.synchronize()?;
+    let start = Instant::now();
+    for _ in 0..BENCH_ITERS {
+        layer.forward(xs)?;
+    }
+    dev.synchronize()?;
+    Ok(start.elapsed().as_secs_f64() * 1e3 / BENCH_ITERS as f64)
+}
+
+fn main() -> Result<()> {
+    let dev = Device::new_cuda(0)?;
+
+    println!(
+        "{:>6} {:>6} {:>14} {:>14} {:>8}",
+        "size", "batch", "two-step (ms)", "fused (ms)", "speedup"
+    );
+    for size in [256, 512, 1024, 2048, 4096, 8192] {
+        let w = Tensor::rand(-1f32, 1f32, (size, size), &dev)?.to_dtype(DType::BF16)?;
+        let cfg = |force_dequantize| HqqConfig {
+            bits: HqqBits::Four,
+            group_size: 64.try_into().unwrap(),
+            axis: HqqAxis::Zero,
+            optimization_steps: None,
+            round_zeros: false,
+            channel_wise: true,
+            force_dequantize,
+        };
+        let two_step = HqqLayer::quantize(&w, &dev, cfg(true))?;
+        let fused = HqqLayer::quantize(&w, &dev, cfg(false))?;
+
+        for batch in [1, 16] {
+            let xs = Tensor::rand(-1f32, 1f32, (batch, size), &dev)?.to_dtype(DType::BF16)?;
+            let two_step_ms = bench(&two_step, &xs, &dev)?;
+            let fused_ms = bench(&fused, &xs, &dev)?;
+            println!(
+                "{size:>6} {batch:>6} {two_step_ms:>14.4} {fused_ms:>14.4} {:>7.2}x",
+                two_step_ms / fused_ms
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+// https://github.com/casper-hansen/AutoAWQ/blob/main/awq/utils/packing_utils.py
+
+#include <cuda.h>
+#include <cuda_runtime.h>
+
+#include "cuda_fp16.h"
+#include "cuda_bf16.h"
+
+inline unsigned int cdiv(unsigned int a, unsigned int b) { return (a + b - 1) / b;}
+#define BLOCK_SIZE 256
+#define AWQ_PACK_FACTOR 8
+
+// Logical column `j` of each packed int32 is stored at nibble `AWQ_REVERSE_ORDER[j]`.
+__constant__ int AWQ_REVERSE_ORDER[AWQ_PACK_FACTOR] = {0, 4, 1, 5, 2, 6, 3, 7};
+
+// One thread per packed element of `qweight` (in_features, out_features / 8).
+// Writes the dequantized weight transposed, as (out_features, in_features).
+template <typename T>
+__global__ void dequantize_4bit_awq_kernel(int* qweight, int* qzeros, T* scales, T* W_r, int in_features, int out_packed, int group_size) {
+	int i = blockIdx.x*blockDim.x + threadIdx.x;
+	if(i >= in_features*out_packed) return;
+
+	int row = i / out_packed;
+	int col = i % out_packed;
+	int g   = row / group_size;
+	int out_features = out_packed*AWQ_PACK_FACTOR;
+
+	unsigned int w = (unsigned int)qweight[i];
+	unsigned int z = (unsigned int)qzeros[g*out_packed + col];
+	#pragma unroll
+	for(int j = 0; j < AWQ_PACK_FACTOR; j++) {
+		int shift = AWQ_REVERSE_ORDER[j]*4;
+		int n     = col*AWQ_PACK_FACTOR + j;
+		float wv  = (float)((w >> shift) & 0xF);
+		float zv  = (float)((z >> shift) & 0xF);
+		W_r[n*in_features + row] = (T)((wv - zv)*(float)scales[g*out_features + n]);
+	}
+}
+
+extern "C" void dequantize_4bit_awq_kernel_f32(int* qweight, int* qzeros, float* scales, float* W_r, int in_features, int out_packed, int group_size) {
+    int blocks = cdiv(in_features*out_packed, BLOCK_SIZE);
+    dequantize_4bit_awq_kernel<<<blocks, BLOCK_SIZE>>>(qweight, qzeros, scales, W_r, in_features, out_packed, group_size);
+}
+
+extern "C" void dequantize_4bit_awq_kernel_f16(int* qweight, int* qzeros, __half* scales, __half* W_r, int in_features, int out_packed, int group_size) {
+    int blocks = cdiv(in_features*out_packed, BLOCK_SIZE);
+    dequantize_4bit_awq_kernel<<<blocks, BLOCK_SIZE>>>(qweight, qzeros, scales, W_r, in_features, out_packed, group_size);
+}
+
+extern "C" void dequantize_4bit_awq_kernel_bf16(int* qweight, int* qzeros, __nv_bfloat16* scales, __nv_bfloat16* W_r, int in_features, int out_packed, int group_size) {
+    int blocks = cdiv(in_features*out_packed, BLOCK_SIZE);
+    dequantize_4bit_awq_kernel<<<blocks, BLOCK_SIZE>>>(qweight, qzeros, scales, W_r, in_features, out_packed, group_size);
+}
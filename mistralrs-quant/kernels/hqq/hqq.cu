@@ -95,6 +95,59 @@ extern "C" void dequantize_4bit_u8_kernel_bf16(unsigned char* Wq_packed, __nv_bf
 }
 //#endif
 
+//Fused dequantize + matmul: out[b, o] = sum_i x[b, i] * W_dq[o, i], without materializing W_dq.
+//W_dq is the (2h, w) unpacked weight, whose row-major flattening is the (out_features, in_features) weight.
+//Each block computes one output for one row of x: the threads dequantize strided elements of the weight row
+//into registers, accumulate their dot products in float and reduce the partial sums in shared memory.
+template <typename T>
+__global__ void fused_dequantize_matmul_4bit_u8_kernel(unsigned char* Wq_packed, T* scale, T* zero, T* x, T* out, int h, int w, int in_features, int out_features) {
+	int o = blockIdx.x;
+	int b = blockIdx.y;
+	__shared__ float partial[BLOCK_SIZE];
+
+	float acc = 0.f;
+	long long row_start = (long long)o * in_features;
+	for (int i = threadIdx.x; i < in_features; i += blockDim.x) {
+		long long f = row_start + i;
+		int r = (int)(f / w);
+		int j = (int)(f % w);
+		unsigned char q = r < h ? (Wq_packed[r * w + j] & 0xF0) >> 4 : (Wq_packed[(r - h) * w + j] & 0x0F);
+		float w_dq = ((float)q - (float)zero[j]) * (float)scale[j];
+		acc += w_dq * (float)x[(long long)b * in_features + i];
+	}
+	partial[threadIdx.x] = acc;
+	__syncthreads();
+
+	for (int stride = blockDim.x / 2; stride > 0; stride >>= 1) {
+		if (threadIdx.x < stride) {
+			partial[threadIdx.x] += partial[threadIdx.x + stride];
+		}
+		__syncthreads();
+	}
+	if (threadIdx.x == 0) {
+		out[(long long)b * out_features + o] = (T)partial[0];
+	}
+}
+
+extern "C" void fused_dequantize_matmul_4bit_u8_kernel_f32(unsigned char* Wq_packed, float* scale, float* zero, float* x, float* out, int h, int w, int batch, int in_features, int out_features) {
+    dim3 blocks(out_features, batch);
+    fused_dequantize_matmul_4bit_u8_kernel<<<blocks, BLOCK_SIZE>>>(Wq_packed, scale, zero, x, out, h, w, in_features, out_features);
+}
+
+//#if __CUDA_ARCH__ >= 630
+extern "C" void fused_dequantize_matmul_4bit_u8_kernel_f16(unsigned char* Wq_packed, __half* scale, __half* zero, __half* x, __half* out, int h, int w, int batch, int in_features, int out_features) {
+    dim3 blocks(out_features, batch);
+    fused_dequantize_matmul_4bit_u8_kernel<<<blocks, BLOCK_SIZE>>>(Wq_packed, scale, zero, x, out, h, w, in_features, out_features);
+}
+//#endif
+
+//#if __CUDA_ARCH__ >= 800
+extern "C" void fused_dequantize_matmul_4bit_u8_kernel_bf16(unsigned char* Wq_packed, __nv_bfloat16* scale, __nv_bfloat16* zero, __nv_bfloat16* x, __nv_bfloat16* out, int h, int w, int batch, int in_features, int out_features) {
+    dim3 blocks(out_features, batch);
+    fused_dequantize_matmul_4bit_u8_kernel<<<blocks, BLOCK_SIZE>>>(Wq_packed, scale, zero, x, out, h, w, in_features, out_features);
+}
+//#endif
+
 /*******************************************************************************************************************************************/
 /************* 2-bit *************/
 /*******************************************************************************************************************************************/
@@ -0,0 +1,76 @@
+use candle_core::{CpuStorage, CustomOp3, Layout, Result, Shape, WithDType};
+
+use super::{AWQ_PACK_FACTOR, AWQ_REVERSE_ORDER};
+
+/*
+ 4 bit
+*/
+pub(crate) struct Dequant4BitAwq {
+    pub(crate) in_features: usize,
+    pub(crate) out_packed: usize,
+    pub(crate) group_size: usize,
+}
+
+impl Dequant4BitAwq {
+    /// Returns the dequantized weight transposed, as `(out_features, in_features)`.
+    fn dequantize<T: WithDType>(&self, w: &[i32], s: &[T], z: &[i32]) -> Vec<T> {
+        let out_features = self.out_packed * AWQ_PACK_FACTOR;
+        let mut out = vec![T::from_f64(0.); self.in_features * out_features];
+        for (i, w) in w.iter().enumerate() {
+            let row = i / self.out_packed;
+            let col = i % self.out_packed;
+            let g = row / self.group_size;
+            let w = *w as u32;
+            let z = z[g * self.out_packed + col] as u32;
+            for (j, order) in AWQ_REVERSE_ORDER.iter().enumerate() {
+                let shift = order * 4;
+                let n = col * AWQ_PACK_FACTOR + j;
+                let wv = ((w >> shift) & 0xF) as f64;
+                let zv = ((z >> shift) & 0xF) as f64;
+                out[n * self.in_features + row] = T::from_f64(wv - zv) * s[g * out_features + n];
+            }
+        }
+        out
+    }
+}
+
+impl CustomOp3 for Dequant4BitAwq {
+    fn name(&self) -> &'static str {
+        "dequant-awq-4bit"
+    }
+    fn cpu_fwd(
+        &self,
+        w: &CpuStorage,
+        l_w: &Layout,
+        s: &CpuStorage,
+        l_s: &Layout,
+        z: &CpuStorage,
+        l_z: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let CpuStorage::I32(w_slice) = w else {
+            candle_core::bail!("Weight must be i32, AWQ dequant 4-bit");
+        };
+        let CpuStorage::I32(z_slice) = z else {
+            candle_core::bail!("Zeros must be i32, AWQ dequant 4-bit");
+        };
+        if !(l_w.is_contiguous() && l_s.is_contiguous() && l_z.is_contiguous()) {
+            candle_core::bail!("All inputs must be contiguous");
+        }
+        let shape = Shape::from_dims(&[self.out_packed * AWQ_PACK_FACTOR, self.in_features]);
+        match s {
+            CpuStorage::F32(s_slice) => Ok((
+                CpuStorage::F32(self.dequantize(w_slice, s_slice, z_slice)),
+                shape,
+            )),
+            CpuStorage::F16(s_slice) => Ok((
+                CpuStorage::F16(self.dequantize(w_slice, s_slice, z_slice)),
+                shape,
+            )),
+            CpuStorage::BF16(s_slice) => Ok((
+                CpuStorage::BF16(self.dequantize(w_slice, s_slice, z_slice)),
+                shape,
+            )),
+            _ => candle_core::bail!("Dtype mismatch, expected one of f32, f16, bf16"),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+macro_rules! dequant_kernel {
+    ($scalar:ty, $postfix:tt) => {
+        paste! {
+            pub(crate) fn [< dequantize_ $postfix >](
+                qweight: *const i32,
+                qzeros: *const i32,
+                scales: *const $scalar,
+                out: *const $scalar,
+                in_features: i32,
+                out_packed: i32,
+                group_size: i32,
+            );
+        }
+    };
+}
+
+pub mod four_bit {
+    use half::{bf16, f16};
+    use paste::paste;
+
+    #[allow(dead_code)]
+    extern "C" {
+        dequant_kernel!(f32, 4bit_awq_kernel_f32);
+        dequant_kernel!(f16, 4bit_awq_kernel_f16);
+        dequant_kernel!(bf16, 4bit_awq_kernel_bf16);
+    }
+}
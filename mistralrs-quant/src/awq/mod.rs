@@ -0,0 +1,445 @@
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Linear, VarBuilder};
+
+#[cfg(feature = "cuda")]
+use candle_core::{
+    cuda::{cudarc::driver::DevicePtr, CudaStorageSlice, WrapErr},
+    from_storage_no_op, CudaStorage, Shape, Storage,
+};
+
+#[cfg(feature = "cuda")]
+use half::{bf16, f16};
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::AtomicUsize, Arc},
+};
+
+use crate::{
+    DummyLayer, IsqType, QuantMethod, QuantMethodConfig, QuantizedConfig, QuantizedSerde,
+    UnquantLinear,
+};
+
+#[cfg(feature = "cuda")]
+use crate::utils::{get_cuda_device, get_cuda_slice};
+
+#[cfg(feature = "cuda")]
+use ffi::four_bit;
+
+#[cfg(feature = "cuda")]
+mod ffi;
+
+#[cfg(not(feature = "cuda"))]
+mod awq_cpu;
+
+/// Number of 4 bit values packed into one `i32` of `qweight` and `qzeros`.
+pub(crate) const AWQ_PACK_FACTOR: usize = 8;
+/// Logical column `j` of each packed `i32` is stored at nibble `AWQ_REVERSE_ORDER[j]`.
+// https://github.com/casper-hansen/AutoAWQ/blob/main/awq/utils/packing_utils.py
+#[cfg(not(feature = "cuda"))]
+pub(crate) const AWQ_REVERSE_ORDER: [usize; AWQ_PACK_FACTOR] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+#[cfg(feature = "cuda")]
+macro_rules! dequant_for_dtype {
+    ($this:expr, sz=$scale_t:ty, $dtype:ident, $dev:expr, $postfix:tt) => {{
+        paste::paste! {
+            let w_slice = get_cuda_slice::<i32>(&$this.qweight)?;
+            let zero_slice = get_cuda_slice::<i32>(&$this.qzeros)?;
+            let scale_slice = get_cuda_slice::<$scale_t>(&$this.scales)?;
+
+            let (in_features, out_packed) = $this.qweight.dims2()?;
+            let out_shape = Shape::from_dims(&[out_packed * AWQ_PACK_FACTOR, in_features]);
+
+            let out = unsafe { $dev.alloc::<$scale_t>(out_shape.elem_count()).w()? };
+            let out_ptr = *out.device_ptr() as *mut $scale_t;
+            unsafe {
+                four_bit::[< dequantize_ $postfix >](
+                    w_slice,
+                    zero_slice,
+                    scale_slice,
+                    out_ptr,
+                    in_features as i32,
+                    out_packed as i32,
+                    $this.group_size as i32,
+                );
+            }
+
+            let storage = CudaStorage {
+                slice: CudaStorageSlice::$dtype(out),
+                device: $dev.clone(),
+            };
+            let storage = Storage::Cuda(storage);
+
+            from_storage_no_op(storage, out_shape, false)
+        }
+    }};
+}
+
+#[derive(Debug)]
+pub struct AwqLayer {
+    qweight: Tensor, // i32, (in_features, out_features / 8)
+    qzeros: Tensor,  // i32, (in_features / group_size, out_features / 8)
+    scales: Tensor,  // (in_features / group_size, out_features)
+    bias: Option<Tensor>,
+    group_size: usize,
+}
+
+impl AwqLayer {
+    /// Dequantize `self` into a weight of shape `(out_features, in_features)`.
+    #[cfg(not(feature = "cuda"))]
+    fn dequantize(&self) -> Result<Tensor> {
+        use crate::awq::awq_cpu::Dequant4BitAwq;
+
+        if !(self.qweight.is_contiguous()
+            && self.scales.is_contiguous()
+            && self.qzeros.is_contiguous())
+        {
+            candle_core::bail!("All tensors must be contiguous!");
+        }
+        let (in_features, out_packed) = self.qweight.dims2()?;
+        self.qweight.apply_op3_no_bwd(
+            &self.scales,
+            &self.qzeros,
+            &Dequant4BitAwq {
+                in_features,
+                out_packed,
+                group_size: self.group_size,
+            },
+        )
+    }
+
+    /// Dequantize `self` into a weight of shape `(out_features, in_features)`.
+    #[cfg(feature = "cuda")]
+    fn dequantize(&self) -> Result<Tensor> {
+        if !(self.qweight.is_contiguous()
+            && self.scales.is_contiguous()
+            && self.qzeros.is_contiguous())
+        {
+            candle_core::bail!("All tensors must be contiguous!");
+        }
+        let dev = get_cuda_device(&self.qweight)?;
+
+        let inner = match self.scales.dtype() {
+            DType::F32 => dequant_for_dtype!(self, sz = f32, F32, dev, 4bit_awq_kernel_f32),
+            DType::F16 => dequant_for_dtype!(self, sz = f16, F16, dev, 4bit_awq_kernel_f16),
+            DType::BF16 => dequant_for_dtype!(self, sz = bf16, BF16, dev, 4bit_awq_kernel_bf16),
+            dt => candle_core::bail!("Unsupported dtype for AWQ scales {dt:?}"),
+        };
+        Ok(inner)
+    }
+
+    fn dequantize_matmul(&self, xs: &Tensor) -> Result<Tensor> {
+        let w = self.dequantize()?;
+        let w = match *xs.dims() {
+            [b1, b2, _, _] => w.broadcast_left((b1, b2))?.t()?,
+            [bsize, _, _] => w.broadcast_left(bsize)?.t()?,
+            _ => w.t()?,
+        };
+        let res = xs.matmul(&w)?;
+        if let Some(ref bias) = self.bias {
+            res.broadcast_add(bias)
+        } else {
+            Ok(res)
+        }
+    }
+}
+
+impl QuantMethod for AwqLayer {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Gptq { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Dummy
+            | QuantMethodConfig::FP8 { .. } => {
+                unreachable!()
+            }
+            QuantMethodConfig::Awq {
+                bits,
+                q_weight,
+                qzeros,
+                scales,
+                group_size,
+                bias,
+            } => {
+                if bits != 4 {
+                    candle_core::bail!("AWQ only supports 4 bit quantization, got {bits} bits.");
+                }
+                Ok(Self {
+                    qweight: q_weight,
+                    qzeros,
+                    scales,
+                    bias,
+                    group_size,
+                })
+            }
+        }
+    }
+
+    fn dequantize_w(&self) -> Result<Tensor> {
+        self.dequantize()
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        self.dequantize_matmul(a)
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        Some(self.scales.dtype())
+    }
+
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        candle_core::bail!("AWQ quantization does not support adding weight delta.")
+    }
+
+    fn dtype_and_device(&self) -> (DType, Device) {
+        (self.scales.dtype(), self.scales.device().clone())
+    }
+
+    fn get_bias_mut(&mut self) -> Option<&mut Tensor> {
+        self.bias.as_mut()
+    }
+
+    fn apply_isq(
+        self: Arc<Self>,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        // Requantize from the dequantized weight, as for an unquantized layer.
+        let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(
+            self.dequantize()?,
+            self.bias.clone(),
+        )))?;
+        Arc::new(unquant).apply_isq(dtype, device, n_quantized)
+    }
+
+    fn get_max_isq_cpu_threads(&self, dtype: IsqType) -> Option<NonZeroUsize> {
+        match dtype {
+            IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | IsqType::HQQ4 | IsqType::HQQ8 => {
+                // Use 1 because our HQQ quantizes on the GPU
+                Some(1.try_into().unwrap())
+            }
+            IsqType::F8E4M3
+            | IsqType::Q2K
+            | IsqType::Q3K
+            | IsqType::Q4K
+            | IsqType::Q4_0
+            | IsqType::Q4_1
+            | IsqType::Q5K
+            | IsqType::Q5_0
+            | IsqType::Q5_1
+            | IsqType::Q6K
+            | IsqType::Q8K
+            | IsqType::Q8_0
+            | IsqType::Q8_1 => None,
+        }
+    }
+}
+
+impl QuantizedSerde for AwqLayer {
+    fn name(&self) -> &'static str {
+        "awq"
+    }
+}
+
+pub fn awq_linear(
+    in_dim: usize,
+    out_dim: usize,
+    config: &QuantizedConfig,
+    vb: VarBuilder,
+) -> Result<Arc<dyn QuantMethod>> {
+    // Handle the case where the layer is dummy (no tensors)
+    if !(vb.contains_tensor("qweight")
+        && vb.contains_tensor("qzeros")
+        && vb.contains_tensor("scales"))
+    {
+        let layer = <DummyLayer as QuantMethod>::new(QuantMethodConfig::Dummy)?;
+        return Ok(Arc::new(layer) as Arc<dyn QuantMethod>);
+    }
+    if config.bits != 4 {
+        candle_core::bail!(
+            "AWQ only supports 4 bit quantization, got {} bits.",
+            config.bits
+        );
+    }
+
+    let qweight = vb.get_with_hints_dtype(
+        (in_dim, out_dim / AWQ_PACK_FACTOR),
+        "qweight",
+        Default::default(),
+        DType::I32,
+    )?;
+    let scale_and_zero_size = in_dim / config.group_size;
+    let qzeros = vb.get_with_hints_dtype(
+        (scale_and_zero_size, out_dim / AWQ_PACK_FACTOR),
+        "qzeros",
+        Default::default(),
+        DType::I32,
+    )?;
+    let scales = vb.get_with_hints_dtype(
+        (scale_and_zero_size, out_dim),
+        "scales",
+        Default::default(),
+        DType::F16,
+    )?;
+    let bias = if vb.contains_tensor("bias") {
+        Some(vb.get_with_hints_dtype((out_dim,), "bias", Default::default(), DType::F16)?)
+    } else {
+        None
+    };
+
+    let config = QuantMethodConfig::Awq {
+        bits: config.bits,
+        q_weight: qweight,
+        qzeros,
+        scales,
+        group_size: config.group_size,
+        bias,
+    };
+    Ok(Arc::new(AwqLayer::new(config)?))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "cuda"))]
+    use super::{AWQ_PACK_FACTOR, AWQ_REVERSE_ORDER};
+
+    /// Pack row-major `(rows, cols)` 4 bit values into `(rows, cols / 8)` AWQ-ordered `i32`s.
+    #[cfg(not(feature = "cuda"))]
+    fn pack_awq(vals: &[u32]) -> Vec<i32> {
+        vals.chunks(AWQ_PACK_FACTOR)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .zip(AWQ_REVERSE_ORDER)
+                    .fold(0u32, |acc, (v, order)| acc | (v << (order * 4))) as i32
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_awq_dequantize_cpu() -> candle_core::Result<()> {
+        use candle_core::{DType, Device, Tensor};
+
+        use crate::{AwqLayer, QuantMethod, QuantMethodConfig};
+
+        let dev = Device::Cpu;
+        let (in_dim, out_dim, group_size) = (8, 16, 4);
+        let groups = in_dim / group_size;
+
+        // Row-major `(in_dim, out_dim)` and `(groups, out_dim)` values.
+        let w_int = (0..in_dim * out_dim)
+            .map(|i| ((i / out_dim * 7 + i % out_dim * 3) % 16) as u32)
+            .collect::<Vec<_>>();
+        let z_int = (0..groups * out_dim)
+            .map(|i| ((i / out_dim * 5 + i % out_dim) % 16) as u32)
+            .collect::<Vec<_>>();
+        let scales = (0..groups * out_dim)
+            .map(|i| 0.01 * (1 + i / out_dim + i % out_dim) as f32)
+            .collect::<Vec<_>>();
+        let packed = out_dim / AWQ_PACK_FACTOR;
+
+        let layer = AwqLayer::new(QuantMethodConfig::Awq {
+            bits: 4,
+            q_weight: Tensor::from_vec(pack_awq(&w_int), (in_dim, packed), &dev)?,
+            qzeros: Tensor::from_vec(pack_awq(&z_int), (groups, packed), &dev)?,
+            scales: Tensor::from_vec(scales.clone(), (groups, out_dim), &dev)?,
+            group_size,
+            bias: None,
+        })?;
+
+        // Transposed to the `(out_dim, in_dim)` weight layout.
+        let expected = (0..out_dim * in_dim)
+            .map(|i| {
+                let (n, k) = (i / in_dim, i % in_dim);
+                let g = k / group_size;
+                (w_int[k * out_dim + n] as f32 - z_int[g * out_dim + n] as f32)
+                    * scales[g * out_dim + n]
+            })
+            .collect::<Vec<_>>();
+        let expected = Tensor::from_vec(expected, (out_dim, in_dim), &dev)?;
+
+        let dequant = layer.dequantize_w()?;
+        assert_eq!(dequant.dims(), &[out_dim, in_dim]);
+        let diff = (&dequant - &expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6, "max diff {diff}");
+
+        let xs = Tensor::randn(0f32, 1f32, (2, 3, in_dim), &dev)?;
+        let out = layer.forward(&xs)?;
+        let expected_out = xs.broadcast_matmul(&expected.t()?)?;
+        let diff = (out - expected_out)?
+            .abs()?
+            .to_dtype(DType::F32)?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "max diff {diff}");
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_awq_linear_from_checkpoint() -> candle_core::Result<()> {
+        use std::collections::HashMap;
+
+        use candle_core::{DType, Device, Tensor};
+        use candle_nn::VarBuilder;
+
+        use crate::{QuantMethodType, QuantizedConfig};
+
+        let dev = Device::Cpu;
+        let (in_dim, out_dim, group_size) = (8, 16, 4);
+        let (groups, packed) = (in_dim / group_size, out_dim / AWQ_PACK_FACTOR);
+        let config = QuantizedConfig {
+            bits: 4,
+            quant_method: QuantMethodType::Awq,
+            group_size,
+            checkpoint_format: None,
+        };
+
+        // Every nibble of the weight is 9 and of the zeros is 1, so the weight is `8 * scale`.
+        let ws = pack_awq(&vec![9; in_dim * out_dim]);
+        let zs = pack_awq(&vec![1; groups * out_dim]);
+        let vb = VarBuilder::from_tensors(
+            HashMap::from([
+                (
+                    "qweight".to_string(),
+                    Tensor::from_vec(ws, (in_dim, packed), &dev)?,
+                ),
+                (
+                    "qzeros".to_string(),
+                    Tensor::from_vec(zs, (groups, packed), &dev)?,
+                ),
+                (
+                    "scales".to_string(),
+                    Tensor::full(0.5f32, (groups, out_dim), &dev)?.to_dtype(DType::F16)?,
+                ),
+            ]),
+            DType::F16,
+            &dev,
+        );
+        let layer = super::awq_linear(in_dim, out_dim, &config, vb)?;
+        assert_eq!(layer.dtype_and_device().0, DType::F16);
+        assert_eq!(layer.quantized_act_type(), Some(DType::F16));
+        let w = layer.dequantize_w()?.to_dtype(DType::F32)?;
+        assert_eq!(w.dims(), &[out_dim, in_dim]);
+        assert_eq!(
+            w.flatten_all()?.to_vec1::<f32>()?,
+            vec![4.; out_dim * in_dim]
+        );
+
+        // Layers without AWQ tensors, e.g. on another pipeline rank, load as dummies.
+        let empty = VarBuilder::from_tensors(HashMap::new(), DType::F16, &dev);
+        let dummy = super::awq_linear(in_dim, out_dim, &config, empty)?;
+        assert_eq!(dummy.name(), "dummy");
+        Ok(())
+    }
+}
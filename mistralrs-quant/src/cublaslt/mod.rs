@@ -42,9 +42,10 @@ pub fn maybe_init_cublas_lt_wrapper() {
                 CUBLASLT = driver::result::init()
                     .ok()
                     .and_then(|_| Device::cuda_if_available(0).ok())
-                    .and_then(|device| match device {
-                        Device::Cuda(_) => Some(CublasLtWrapper {
+                    .and_then(|device| match &device {
+                        Device::Cuda(dev) => Some(CublasLtWrapper {
                             cublaslt: CublasLt::new(&device).unwrap(),
+                            supports_f8: supports_f8(dev),
                         }),
                         _ => None,
                     });
@@ -55,10 +56,28 @@ pub fn maybe_init_cublas_lt_wrapper() {
     }
 }
 
+/// Minimum compute capability, as `(major, minor)`, for FP8 matmuls.
+const MIN_F8_CC: (i32, i32) = (8, 9);
+
+#[cfg(feature = "cuda")]
+fn supports_f8(device: &candle_core::CudaDevice) -> bool {
+    use candle_core::cuda_backend::cudarc::driver::sys::CUdevice_attribute;
+
+    let dev = device.cuda_device();
+    let major = dev.attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR);
+    let minor = dev.attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR);
+    match (major, minor) {
+        (Ok(major), Ok(minor)) => (major, minor) >= MIN_F8_CC,
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CublasLtWrapper {
     #[cfg(feature = "cuda")]
     pub cublaslt: CublasLt,
+    /// Whether the device can run FP8 matmuls, which needs compute capability 8.9 or newer.
+    pub supports_f8: bool,
 }
 
 impl CublasLtWrapper {
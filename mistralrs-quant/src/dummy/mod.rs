@@ -27,6 +27,9 @@ impl QuantMethod for DummyLayer {
     fn dtype_and_device(&self) -> (candle_core::DType, candle_core::Device) {
         (candle_core::DType::F64, candle_core::Device::Cpu)
     }
+    fn dequantize_w(&self) -> candle_core::Result<candle_core::Tensor> {
+        candle_core::bail!("DummyLayer should not ever be present in forward pass!")
+    }
     fn forward(&self, _a: &candle_core::Tensor) -> candle_core::Result<candle_core::Tensor> {
         candle_core::bail!("DummyLayer should not ever be present in forward pass!")
     }
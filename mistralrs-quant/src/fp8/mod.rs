@@ -16,9 +16,9 @@ use crate::{
     cublaslt::{maybe_init_cublas_lt_wrapper, F8MatmulOutType, CUBLASLT_HANDLE},
     utils::{
         deserialize_tensor, read_dtype, serialize_tensor, version_is_compatible, write_dtype,
-        HQFF_VERSION,
+        HQFF_VERSION, HQFF_VERSION_FP8_SCALE_TENSORS,
     },
-    IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType,
+    IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType, UnquantLinear,
 };
 
 #[derive(Debug)]
@@ -41,13 +41,14 @@ impl QuantMethod for FP8Linear {
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::Unquantized(_) => unreachable!(),
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Awq { .. } => unreachable!(),
             QuantMethodConfig::FP8 { lin, dtype } => {
                 let QuantizationResult {
                     qw,
                     quantize_scale,
                     dequantize_scale,
-                } = Self::quantize(lin.weight(), dtype)?;
+                } = Self::quantize(lin.weight(), dtype, None)?;
                 Ok(Self {
                     lin: Linear::new(qw, lin.bias().cloned()),
                     dequant_x_scale: dequantize_scale.clone(), // This is probably wrong!
@@ -59,12 +60,21 @@ impl QuantMethod for FP8Linear {
         }
     }
 
+    fn dequantize_w(&self) -> Result<Tensor> {
+        Ok(self.dequantize(DType::F32)?.weight().clone())
+    }
+
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
         // Batch matrix multiplication
         maybe_init_cublas_lt_wrapper();
 
         match *CUBLASLT_HANDLE.lock().unwrap() {
-            Some(handle) => {
+            // cuBLASLt only supports per-tensor scales
+            Some(handle)
+                if handle.supports_f8
+                    && x.device().is_cuda()
+                    && self.dequant_w_scale.dims().is_empty() =>
+            {
                 let n_dims = x.dims().len();
                 if n_dims < 3 {
                     candle_core::bail!(
@@ -85,7 +95,7 @@ impl QuantMethod for FP8Linear {
                         qw,
                         quantize_scale: _,
                         dequantize_scale,
-                    } = Self::quantize(&x, DType::F8E4M3)?;
+                    } = Self::quantize(&x, DType::F8E4M3, None)?;
                     x = qw;
                     dequant_x_scale = dequantize_scale;
                 }
@@ -116,11 +126,16 @@ impl QuantMethod for FP8Linear {
                     )?
                     .reshape(tgt_shape)
             }
-            None => {
-                // Dequantize matmul
-                let dequant_x = x.clone();
-                let lin = self.dequantize(x.dtype())?;
-                lin.forward(&dequant_x)
+            _ => {
+                // FP8 matmuls are not supported: dequantize and compute in BF16 on CUDA, or in
+                // the activation dtype on other devices
+                let dtype = if x.device().is_cuda() {
+                    DType::BF16
+                } else {
+                    x.dtype()
+                };
+                let lin = self.dequantize(dtype)?;
+                lin.forward(&x.to_dtype(dtype)?)?.to_dtype(x.dtype())
             }
         }
     }
@@ -148,11 +163,28 @@ impl QuantMethod for FP8Linear {
 
     fn apply_isq(
         self: Arc<Self>,
-        _dtype: Option<IsqType>,
-        _device: Device,
-        _n_quantized: &AtomicUsize,
+        dtype: Option<IsqType>,
+        device: Device,
+        n_quantized: &AtomicUsize,
     ) -> Result<Arc<dyn QuantMethod>> {
-        todo!()
+        let Some(dtype) = dtype else {
+            let bias = self.lin.bias().map(|b| b.to_device(&device)).transpose()?;
+            return Ok(Arc::new(Self {
+                lin: Linear::new(self.lin.weight().to_device(&device)?, bias),
+                dequant_w_scale: self.dequant_w_scale.to_device(&device)?,
+                dequant_x_scale: self.dequant_x_scale.to_device(&device)?,
+                quant_scale: self.quant_scale.to_device(&device)?,
+                dtype: self.dtype,
+            }));
+        };
+        // Requantize from the dequantized weights
+        let lin = self.dequantize(DType::BF16)?;
+        let bias = lin.bias().map(|b| b.to_device(&device)).transpose()?;
+        let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(
+            lin.weight().to_device(&device)?,
+            bias,
+        )))?;
+        Arc::new(unquant).apply_isq(Some(dtype), device, n_quantized)
     }
 
     fn get_max_isq_cpu_threads(&self, dtype: IsqType) -> Option<NonZeroUsize> {
@@ -170,6 +202,9 @@ impl QuantMethod for FP8Linear {
             | IsqType::Q8K
             | IsqType::Q8_0
             | IsqType::Q8_1
+            | IsqType::HQQ1
+            | IsqType::HQQ2
+            | IsqType::HQQ3
             | IsqType::HQQ4
             | IsqType::HQQ8 => None,
         }
@@ -187,11 +222,11 @@ impl QuantMethod for FP8Linear {
 // -----------------------
 // Weight tensor data generated by `serialize_tensor`. Refer to its docs for layout.
 // -----------------------
-// Dequant W scalar, f32, little endian
+// Dequant W scale tensor data generated by `serialize_tensor`
 // -----------------------
-// Dequant X scalar, f32, little endian
+// Dequant X scale tensor data generated by `serialize_tensor`
 // -----------------------
-// Quant scalar, f32, little endian
+// Quant scale tensor data generated by `serialize_tensor`
 // -----------------------
 // Quantization type, u32, little endian
 // -----------------------
@@ -220,11 +255,11 @@ impl QuantizedSerde for FP8Linear {
         serialize_tensor(&mut buffer, self.lin.weight())?;
 
         // Dequant a scale
-        buffer.extend(self.dequant_w_scale.to_scalar::<f32>()?.to_le_bytes());
+        serialize_tensor(&mut buffer, &self.dequant_w_scale)?;
         // Dequant b scale
-        buffer.extend(self.dequant_x_scale.to_scalar::<f32>()?.to_le_bytes());
+        serialize_tensor(&mut buffer, &self.dequant_x_scale)?;
         // Quant scale
-        buffer.extend(self.quant_scale.to_scalar::<f32>()?.to_le_bytes());
+        serialize_tensor(&mut buffer, &self.quant_scale)?;
 
         // DType
         write_dtype(self.dtype, &mut buffer);
@@ -260,9 +295,21 @@ impl QuantizedSerde for FP8Linear {
 
         let w = deserialize_tensor(&mut buffer, device)?;
 
-        let dequant_w_scale = Tensor::new(buffer.read_f32::<LittleEndian>()?, device)?;
-        let dequant_x_scale = Tensor::new(buffer.read_f32::<LittleEndian>()?, device)?;
-        let quant_scale = Tensor::new(buffer.read_f32::<LittleEndian>()?, device)?;
+        // Before v0.1.3, the scales were written as f32 scalars
+        let (dequant_w_scale, dequant_x_scale, quant_scale) =
+            if version >= HQFF_VERSION_FP8_SCALE_TENSORS {
+                (
+                    deserialize_tensor(&mut buffer, device)?,
+                    deserialize_tensor(&mut buffer, device)?,
+                    deserialize_tensor(&mut buffer, device)?,
+                )
+            } else {
+                (
+                    Tensor::new(buffer.read_f32::<LittleEndian>()?, device)?,
+                    Tensor::new(buffer.read_f32::<LittleEndian>()?, device)?,
+                    Tensor::new(buffer.read_f32::<LittleEndian>()?, device)?,
+                )
+            };
 
         // DType
         let dtype = read_dtype(&mut buffer)?;
@@ -1,4 +1,4 @@
-use candle_core::{DType, Result, Tensor};
+use candle_core::{DType, Result, Tensor, D};
 use candle_nn::Linear;
 use float8::F8E4M3;
 
@@ -7,20 +7,45 @@ use super::FP8Linear;
 pub(super) struct QuantizationResult {
     /// Quantized tensor (f8)
     pub(super) qw: Tensor,
-    /// Scalar, f32 tensor.
+    /// f32 tensor: a scalar, or one scale per group of the last dimension when quantizing
+    /// group-wise.
     ///
     /// Convert unquantized to quantized tensor as follows:
     /// `q = x * qs`
     pub(super) quantize_scale: Tensor,
-    /// Scalar, f32 tensor. Reciprocal of `quantize_scale`.
+    /// f32 tensor with the shape of `quantize_scale`. Reciprocal of `quantize_scale`.
     ///
     /// Convert unquantized to quantized tensor as follows:
     /// `x = q * dqs`
     pub(super) dequantize_scale: Tensor,
 }
 
+/// Multiply `xs` by `scale`, which is either a scalar or has one value per group of the last
+/// dimension of `xs`.
+pub(super) fn apply_scale(xs: &Tensor, scale: &Tensor) -> Result<Tensor> {
+    let scale = scale.to_dtype(xs.dtype())?;
+    if scale.dims().is_empty() {
+        return xs.broadcast_mul(&scale);
+    }
+    let n_groups = scale.dim(D::Minus1)?;
+    let mut grouped_shape = scale.dims().to_vec();
+    grouped_shape.push(xs.dim(D::Minus1)? / n_groups);
+    xs.reshape(grouped_shape)?
+        .broadcast_mul(&scale.unsqueeze(D::Minus1)?)?
+        .reshape(xs.shape())
+}
+
 impl FP8Linear {
-    pub(super) fn quantize(data: &Tensor, dtype: DType) -> Result<QuantizationResult> {
+    /// Quantize `data` with one scale for the whole tensor, or if `group_size` is specified, one
+    /// scale per `group_size` consecutive elements of the last dimension.
+    pub(super) fn quantize(
+        data: &Tensor,
+        dtype: DType,
+        group_size: Option<usize>,
+    ) -> Result<QuantizationResult> {
+        if let Some(group_size) = group_size {
+            return Self::quantize_grouped(data, dtype, group_size);
+        }
         let data = data.to_dtype(DType::BF16)?;
         let mut absmax = data.clone();
         let mut absmin = data.clone();
@@ -46,27 +71,73 @@ impl FP8Linear {
         })
     }
 
+    fn quantize_grouped(
+        data: &Tensor,
+        dtype: DType,
+        group_size: usize,
+    ) -> Result<QuantizationResult> {
+        let last_dim = data.dim(D::Minus1)?;
+        if group_size == 0 || last_dim % group_size != 0 {
+            candle_core::bail!(
+                "FP8 group size {group_size} must divide the last dimension ({last_dim})"
+            );
+        }
+        let mut grouped_shape = data.dims().to_vec();
+        *grouped_shape.last_mut().unwrap() = last_dim / group_size;
+        grouped_shape.push(group_size);
+
+        let grouped = data.to_dtype(DType::F32)?.reshape(grouped_shape)?;
+        // Avoid infinite scales for groups which are all zero
+        let amax = grouped
+            .abs()?
+            .max_keepdim(D::Minus1)?
+            .clamp(1e-12f32, f32::MAX)?;
+        let scale = (amax.recip()? * f64::from(F8E4M3::MAX.to_f32()))?;
+        let qw = grouped
+            .broadcast_mul(&scale)?
+            .reshape(data.shape())?
+            .to_dtype(dtype)?;
+        let scale = scale.squeeze(D::Minus1)?;
+        Ok(QuantizationResult {
+            qw,
+            dequantize_scale: scale.recip()?,
+            quantize_scale: scale,
+        })
+    }
+
     pub(super) fn dequantize(&self, dtype: DType) -> Result<Linear> {
-        let dequant_w = self
-            .lin
-            .weight()
-            .to_dtype(dtype)?
-            .broadcast_mul(&self.dequant_w_scale.to_dtype(dtype)?)?;
+        let dequant_w = apply_scale(&self.lin.weight().to_dtype(dtype)?, &self.dequant_w_scale)?;
         Ok(Linear::new(dequant_w, self.lin.bias().cloned()))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        borrow::Cow,
+        sync::{atomic::AtomicUsize, Arc},
+    };
+
     use candle_core::{
         quantized::{GgmlDType, QTensor},
         DType, Device, Result, Tensor,
     };
+    use candle_nn::Linear;
 
-    use crate::fp8::FP8Linear;
+    use crate::{
+        fp8::FP8Linear,
+        utils::{serialize_tensor, write_dtype},
+        IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType, UnquantLinear,
+    };
 
     use super::QuantizationResult;
 
+    fn unquant_layer(w: Tensor) -> Result<Arc<dyn QuantMethod>> {
+        Ok(Arc::new(UnquantLinear::new(
+            QuantMethodConfig::Unquantized(Linear::new(w, None)),
+        )?))
+    }
+
     #[test]
     fn test_roundtrip_f8e4m3() -> Result<()> {
         let dev = Device::cuda_if_available(0)?;
@@ -77,7 +148,7 @@ mod tests {
             qw,
             quantize_scale: _,
             dequantize_scale,
-        } = FP8Linear::quantize(&data, DType::F8E4M3)?;
+        } = FP8Linear::quantize(&data, DType::F8E4M3, None)?;
 
         let dequant = qw.to_dtype(DType::F32)?.broadcast_mul(&dequantize_scale)?;
 
@@ -92,6 +163,71 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_grouped_f8e4m3_outlier_channels() -> Result<()> {
+        let dev = Device::Cpu;
+        // The first two output channels are outliers, which push the small weights of the others
+        // into the subnormal range of a per-tensor scale.
+        let outliers = (Tensor::randn(0f32, 0.02, (2, 512), &dev)? * 1e5)?;
+        let w = Tensor::cat(&[outliers, Tensor::randn(0f32, 0.02, (62, 512), &dev)?], 0)?;
+
+        let reconstruction_error = |group_size| -> Result<f32> {
+            let QuantizationResult {
+                qw,
+                quantize_scale,
+                dequantize_scale,
+            } = FP8Linear::quantize(&w, DType::F8E4M3, group_size)?;
+            assert_eq!(quantize_scale.dims(), dequantize_scale.dims());
+            let dequant = super::apply_scale(&qw.to_dtype(DType::F32)?, &dequantize_scale)?;
+            (dequant - &w)?
+                .narrow(0, 2, 62)?
+                .abs()?
+                .mean_all()?
+                .to_scalar::<f32>()
+        };
+        let per_tensor = reconstruction_error(None)?;
+        let grouped = reconstruction_error(Some(128))?;
+        assert!(4. * grouped < per_tensor, "{grouped} vs {per_tensor}");
+
+        let QuantizationResult {
+            dequantize_scale, ..
+        } = FP8Linear::quantize(&w, DType::F8E4M3, Some(128))?;
+        assert_eq!(dequantize_scale.dims(), &[64, 4]);
+        assert!(FP8Linear::quantize(&w, DType::F8E4M3, Some(100)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_grouped_f8e4m3_forward() -> Result<()> {
+        let dev = Device::Cpu;
+        let w = Tensor::randn(0f32, 1., (32, 64), &dev)?;
+        let x = Tensor::randn(0f32, 1., (1, 4, 64), &dev)?;
+        let expected = unquant_layer(w.clone())?.forward(&x)?;
+
+        let QuantizationResult {
+            qw,
+            quantize_scale,
+            dequantize_scale,
+        } = FP8Linear::quantize(&w, DType::F8E4M3, Some(16))?;
+        let layer = FP8Linear {
+            lin: Linear::new(qw, None),
+            dequant_x_scale: dequantize_scale.clone(),
+            dequant_w_scale: dequantize_scale,
+            quant_scale: quantize_scale,
+            dtype: DType::F8E4M3,
+        };
+        let out = layer.forward(&x)?;
+        let err = (out - &expected)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+        let mean = expected.abs()?.mean_all()?.to_scalar::<f32>()?;
+        assert!(err < 0.1 * mean, "{err} >= 0.1 * {mean}");
+
+        // Group-wise scales round-trip through serialization
+        let data = layer.serialize()?.into_owned();
+        let loaded = FP8Linear::deserialize(Cow::from(data.clone()), &dev)?;
+        assert_eq!(loaded.serialize()?.as_ref(), data.as_slice());
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "cuda")]
     fn test_cublaslt_matmul() -> Result<()> {
@@ -110,7 +246,7 @@ mod tests {
             qw,
             quantize_scale: quant_scale,
             dequantize_scale: dequant_a_scale,
-        } = FP8Linear::quantize(&w, DType::F8E4M3)?;
+        } = FP8Linear::quantize(&w, DType::F8E4M3, None)?;
 
         let mut dequant_b_scale = dequant_a_scale.clone();
         if !matches!(x.dtype(), DType::F8E4M3) {
@@ -118,7 +254,7 @@ mod tests {
                 qw,
                 quantize_scale: _,
                 dequantize_scale,
-            } = FP8Linear::quantize(&x, DType::F8E4M3)?;
+            } = FP8Linear::quantize(&x, DType::F8E4M3, None)?;
             x = qw;
             dequant_b_scale = dequantize_scale;
         }
@@ -143,4 +279,150 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_isq_f8e4m3() -> Result<()> {
+        let dev = Device::Cpu;
+        let n_quantized = AtomicUsize::new(0);
+        let unquant = unquant_layer(Tensor::randn(0f32, 1., (32, 64), &dev)?)?;
+        let x = Tensor::randn(0f32, 1., (1, 4, 64), &dev)?;
+        let expected = unquant.forward(&x)?;
+
+        let fp8 = unquant
+            .clone()
+            .apply_isq(Some(IsqType::F8E4M3), dev.clone(), &n_quantized)?;
+        assert_eq!(fp8.name(), "fp8-linear");
+        let out = fp8.forward(&x)?;
+        assert_eq!(out.dtype(), DType::F32);
+        let err = (out - &expected)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+        let mean = expected.abs()?.mean_all()?.to_scalar::<f32>()?;
+        assert!(err < 0.1 * mean, "{err} >= 0.1 * {mean}");
+
+        // FP8 layers can be moved, and requantized to other types.
+        let moved = fp8.clone().apply_isq(None, dev.clone(), &n_quantized)?;
+        assert_eq!(moved.name(), "fp8-linear");
+        let q8_0 = fp8.apply_isq(Some(IsqType::Q8_0), dev.clone(), &n_quantized)?;
+        assert_eq!(q8_0.name(), "gguf");
+
+        // HQQ and GGUF layers can be requantized to FP8.
+        let hqq = unquant.apply_isq(Some(IsqType::HQQ8), dev.clone(), &n_quantized)?;
+        for layer in [hqq, q8_0] {
+            let fp8 = layer.apply_isq(Some(IsqType::F8E4M3), dev.clone(), &n_quantized)?;
+            assert_eq!(fp8.name(), "fp8-linear");
+        }
+        Ok(())
+    }
+
+    fn assert_same_forward(a: &dyn QuantMethod, b: &dyn QuantMethod) -> Result<()> {
+        let x = Tensor::randn(0f32, 1., (1, 4, 64), &Device::Cpu)?;
+        let expected = a.forward(&x)?.flatten_all()?.to_vec1::<f32>()?;
+        assert_eq!(b.forward(&x)?.flatten_all()?.to_vec1::<f32>()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_roundtrip_f8e4m3() -> Result<()> {
+        let dev = Device::Cpu;
+        let lin = Linear::new(
+            Tensor::randn(0f32, 1., (32, 64), &dev)?,
+            Some(Tensor::randn(0f32, 1., 32, &dev)?),
+        );
+        let layer = FP8Linear::new(QuantMethodConfig::FP8 {
+            lin,
+            dtype: DType::F8E4M3,
+        })?;
+
+        let data = layer.serialize()?.into_owned();
+        let loaded = FP8Linear::deserialize(Cow::from(data.clone()), &dev)?;
+        assert_eq!(loaded.name(), "fp8-linear");
+        // The weight, bias and scales round-trip with their dtypes and shapes
+        assert_eq!(loaded.serialize()?.as_ref(), data.as_slice());
+        assert_same_forward(&layer, loaded.as_ref())?;
+
+        // Scales which are not f32 keep their dtype
+        let bf16_scales = FP8Linear {
+            lin: layer.lin.clone(),
+            dequant_w_scale: layer.dequant_w_scale.to_dtype(DType::BF16)?,
+            dequant_x_scale: layer.dequant_x_scale.to_dtype(DType::BF16)?,
+            quant_scale: layer.quant_scale.to_dtype(DType::BF16)?,
+            dtype: layer.dtype,
+        };
+        let data = bf16_scales.serialize()?.into_owned();
+        let loaded = FP8Linear::deserialize(Cow::from(data.clone()), &dev)?;
+        assert_eq!(loaded.serialize()?.as_ref(), data.as_slice());
+        assert_same_forward(&bf16_scales, loaded.as_ref())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_f8e4m3_scalar_scales() -> Result<()> {
+        let dev = Device::Cpu;
+        let layer = FP8Linear::new(QuantMethodConfig::FP8 {
+            lin: Linear::new(Tensor::randn(0f32, 1., (32, 64), &dev)?, None),
+            dtype: DType::F8E4M3,
+        })?;
+
+        // Layout written by v0.1.2, with the scales as f32 scalars
+        let mut data = Vec::new();
+        data.extend(((1u32 << 8) | 2).to_le_bytes());
+        data.push(QuantizedSerdeType::Fp8 as u8);
+        data.push(0);
+        serialize_tensor(&mut data, layer.lin.weight())?;
+        for scale in [
+            &layer.dequant_w_scale,
+            &layer.dequant_x_scale,
+            &layer.quant_scale,
+        ] {
+            data.extend(scale.to_scalar::<f32>()?.to_le_bytes());
+        }
+        write_dtype(DType::F8E4M3, &mut data);
+
+        let loaded = FP8Linear::deserialize(Cow::from(data), &dev)?;
+        assert_same_forward(&layer, loaded.as_ref())
+    }
+
+    /// Throughput of FP8 and BF16 matmuls with the linear layer sizes of a 7B model. Run with
+    /// `cargo test --release --features cuda -p mistralrs-quant bench_fp8_bf16 -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    #[cfg(feature = "cuda")]
+    fn bench_fp8_bf16() -> Result<()> {
+        use std::time::Instant;
+
+        const ITERS: u32 = 50;
+        let dev = Device::new_cuda(0)?;
+        // (out, in) of the attention, MLP up and MLP down projections of Mistral 7B
+        for (out_dim, in_dim) in [(4096usize, 4096usize), (14336, 4096), (4096, 14336)] {
+            for tokens in [16usize, 512] {
+                let w =
+                    Tensor::randn(0f32, 0.02, (out_dim, in_dim), &dev)?.to_dtype(DType::BF16)?;
+                let x =
+                    Tensor::randn(0f32, 1., (1, tokens, in_dim), &dev)?.to_dtype(DType::BF16)?;
+                let bf16 = unquant_layer(w)?;
+                let fp8 = bf16.clone().apply_isq(
+                    Some(IsqType::F8E4M3),
+                    dev.clone(),
+                    &AtomicUsize::new(0),
+                )?;
+                for (name, layer) in [("bf16", &bf16), ("fp8", &fp8)] {
+                    // Warm up
+                    layer.forward(&x)?;
+                    dev.synchronize()?;
+                    let start = Instant::now();
+                    for _ in 0..ITERS {
+                        layer.forward(&x)?;
+                    }
+                    dev.synchronize()?;
+                    let secs = start.elapsed().as_secs_f64() / f64::from(ITERS);
+                    let flops = 2. * (out_dim * in_dim * tokens) as f64;
+                    println!(
+                        "{name}: ({tokens}, {in_dim}) x ({in_dim}, {out_dim}) in {:.3} ms, {:.2} TFLOPS",
+                        secs * 1e3,
+                        flops / secs / 1e12
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
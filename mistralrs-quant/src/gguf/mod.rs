@@ -10,12 +10,12 @@ use candle_core::{
     quantized::{ggml_file::qtensor_from_ggml, GgmlDType, QMatMul, QTensor},
     DType, Device, Result, Tensor,
 };
-use candle_nn::Module;
+use candle_nn::{Linear, Module};
 
 use crate::{
     generate_isq,
     utils::{deserialize_tensor, serialize_tensor, version_is_compatible, HQFF_VERSION},
-    IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType,
+    IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType, UnquantLinear,
 };
 
 #[derive(Debug)]
@@ -38,7 +38,15 @@ impl QuantMethod for GgufMatMul {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => unreachable!(),
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => unreachable!(),
+        }
+    }
+
+    fn dequantize_w(&self) -> Result<Tensor> {
+        match &self.w {
+            QMatMul::QTensor(q) => q.dequantize(&q.device()),
+            QMatMul::TensorF16(t) | QMatMul::Tensor(t) => Ok(t.clone()),
         }
     }
 
@@ -115,6 +123,15 @@ impl QuantMethod for GgufMatMul {
                 QMatMul::QTensor(q) => q.dequantize(&q.device())?,
                 QMatMul::TensorF16(t) | QMatMul::Tensor(t) => t.clone(),
             };
+            if let IsqType::F8E4M3 = dtype {
+                // Quantize the dequantized weights with the FP8 path of `UnquantLinear`
+                let bias = self.b.as_ref().map(|b| b.to_device(&device)).transpose()?;
+                let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(
+                    t.to_device(&device)?,
+                    bias,
+                )))?;
+                return Arc::new(unquant).apply_isq(Some(dtype), device, n_quantized);
+            }
             let dtype = dtype.try_into()?;
             let res = generate_isq!(t, device, dtype, n_quantized);
             Ok(Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
@@ -20,12 +20,17 @@ impl QuantMethod for GptqLayer {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => {
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
         }
     }
 
+    fn dequantize_w(&self) -> Result<Tensor> {
+        candle_core::bail!("GPTQ is only supported on CUDA.")
+    }
+
     fn forward(&self, _a: &Tensor) -> Result<Tensor> {
         todo!()
     }
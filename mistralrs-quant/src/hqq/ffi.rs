@@ -13,6 +13,25 @@ macro_rules! dequant_kernel {
     };
 }
 
+macro_rules! fused_dequant_matmul_kernel {
+    ($wq:ty, $scalar:ty, $postfix:tt) => {
+        paste! {
+            pub(crate) fn [< fused_dequantize_matmul_ $postfix >](
+                wq_packed: *const $wq,
+                scale: *const $scalar,
+                zero: *const $scalar,
+                x: *const $scalar,
+                out: *const $scalar,
+                h: i32,
+                w: i32,
+                batch: i32,
+                in_features: i32,
+                out_features: i32
+            );
+        }
+    };
+}
+
 pub mod eight_bit {
     use half::{bf16, f16};
     use paste::paste;
@@ -35,6 +54,14 @@ pub mod four_bit {
         dequant_kernel!(u8, f16, 4bit_u8_kernel_f16);
         dequant_kernel!(u8, bf16, 4bit_u8_kernel_bf16);
     }
+
+    #[cfg(feature = "fused-hqq")]
+    #[allow(dead_code)]
+    extern "C" {
+        fused_dequant_matmul_kernel!(u8, f32, 4bit_u8_kernel_f32);
+        fused_dequant_matmul_kernel!(u8, f16, 4bit_u8_kernel_f16);
+        fused_dequant_matmul_kernel!(u8, bf16, 4bit_u8_kernel_bf16);
+    }
 }
 
 pub mod three_bit {
@@ -10,7 +10,7 @@ pub(crate) struct Dequant8Bit {
 
 impl Dequant8Bit {
     fn dequantize<T: WithDType>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let mut out = Vec::with_capacity(w.len());
+        let mut out = vec![T::from_f64(0.); w.len()];
         for (i, w) in w.iter().enumerate() {
             let j = i % self.w;
             out[i] = (T::from_f64(*w as f64) - z[j]) * s[j];
@@ -66,7 +66,7 @@ pub(crate) struct Dequant4Bit {
 
 impl Dequant4Bit {
     fn dequantize<T: WithDType>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let mut out = Vec::with_capacity(w.len());
+        let mut out = vec![T::from_f64(0.); w.len() * 2];
         for (i, w) in w.iter().enumerate() {
             let j = i % self.w;
             let nrows = self.h * self.w;
@@ -126,7 +126,7 @@ pub(crate) struct Dequant2Bit {
 
 impl Dequant2Bit {
     fn dequantize<T: WithDType>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let mut out = Vec::with_capacity(w.len());
+        let mut out = vec![T::from_f64(0.); w.len() * 4];
         for (i, w) in w.iter().enumerate() {
             let j = i % self.w;
             let nrows = self.h * self.w;
@@ -188,7 +188,7 @@ pub(crate) struct Dequant1Bit {
 
 impl Dequant1Bit {
     fn dequantize<T: WithDType>(&self, w: &[u8], s: &[T], z: &[T]) -> Vec<T> {
-        let mut out = Vec::with_capacity(w.len());
+        let mut out = vec![T::from_f64(0.); w.len() * 8];
         for (i, w) in w.iter().enumerate() {
             let j = i % self.w;
             let nrows = self.h * self.w;
@@ -254,7 +254,7 @@ pub(crate) struct Dequant3Bit {
 
 impl Dequant3Bit {
     fn dequantize<T: WithDType>(&self, w: &[i32], s: &[T], z: &[T]) -> Vec<T> {
-        let mut out = Vec::with_capacity(w.len());
+        let mut out = vec![T::from_f64(0.); w.len() * 10];
         for (i, w) in w.iter().enumerate() {
             let j = i % self.w;
             let nrows = self.h * self.w;
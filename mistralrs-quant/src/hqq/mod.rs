@@ -1,5 +1,6 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use candle_core::{DType, Device, Result, Shape, Tensor};
+use candle_core::{DType, Device, Result, Shape, Tensor, D};
+use candle_nn::Linear;
 
 #[cfg(feature = "cuda")]
 use candle_core::{
@@ -19,9 +20,9 @@ use std::{
 use crate::{
     utils::{
         deserialize_tensor, serialize_tensor, version_is_compatible, BitWiseOp, LeftshiftOp,
-        HQFF_VERSION,
+        HQFF_VERSION, HQFF_VERSION_HQQ_FORCE_DEQUANTIZE,
     },
-    IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType,
+    IsqType, QuantMethod, QuantMethodConfig, QuantizedSerde, QuantizedSerdeType, UnquantLinear,
 };
 
 #[cfg(feature = "cuda")]
@@ -39,17 +40,29 @@ mod hqq_cpu;
 mod optimize;
 mod quantize;
 
+/// Divisible by the pack factor of every `HqqBits` width (3 bit is padded).
 pub(crate) const ISQ_HQQ_GROUP_SIZE: usize = 64;
 pub(crate) const ISQ_HQQ_DEFAULT_OPT_STEPS: Option<usize> = Some(10);
 pub(crate) const OPTIMIZER_HQQ_DEFAULT_STEPS: usize = 20;
+/// Number of packed weight elements unpacked and dequantized at once by the chunked matmul, used
+/// when `force_dequantize` is not set.
+pub(crate) const HQQ_CHUNK_ELEMS: usize = 1 << 20;
+/// Largest number of input rows for which `HqqLayer::forward` uses the fused matmul. The kernel
+/// recomputes the dequantized weight for every row, so larger inputs (prompts) are faster with a
+/// single dequantization followed by a GEMM.
+#[cfg(feature = "fused-hqq")]
+pub(crate) const FUSED_MATMUL_MAX_ROWS: usize = 16;
+/// Rows per fused kernel launch, bounded by the maximum y dimension of a CUDA grid.
+#[cfg(feature = "fused-hqq")]
+const FUSED_MATMUL_LAUNCH_ROWS: usize = 65535;
 
 #[cfg(feature = "cuda")]
 macro_rules! dequant_for_dtype {
-    ($this:expr, w=$wq_t:ty, sz=$scale_t:ty, $dtype:ident, pack=$pack:expr, $dev:expr, $bit_thing:ident, $postfix:tt) => {{
+    ($this:expr, $scales:expr, $zeros:expr, w=$wq_t:ty, sz=$scale_t:ty, $dtype:ident, pack=$pack:expr, $dev:expr, $bit_thing:ident, $postfix:tt) => {{
         paste::paste! {
             let w_slice = get_cuda_slice::<$wq_t>(&$this.w_q)?;
-            let scale_slice = get_cuda_slice::<$scale_t>(&$this.scales)?;
-            let zero_slice = get_cuda_slice::<$scale_t>(&$this.zeros)?;
+            let scale_slice = get_cuda_slice::<$scale_t>(&$scales)?;
+            let zero_slice = get_cuda_slice::<$scale_t>(&$zeros)?;
 
             let (h, w) = $this.w_q.dims2()?;
             let num_packed_elems = $pack;
@@ -79,6 +92,46 @@ macro_rules! dequant_for_dtype {
     }};
 }
 
+#[cfg(feature = "fused-hqq")]
+macro_rules! fused_matmul_for_dtype {
+    ($this:expr, $x:expr, batch=$batch:expr, in=$in_features:expr, out=$out_features:expr, sz=$scale_t:ty, $dtype:ident, $dev:expr, $postfix:tt) => {{
+        paste::paste! {
+            let w_slice = get_cuda_slice::<u8>(&$this.w_q)?;
+            let scale_slice = get_cuda_slice::<$scale_t>(&$this.scales)?;
+            let zero_slice = get_cuda_slice::<$scale_t>(&$this.zeros)?;
+            let x_slice = get_cuda_slice::<$scale_t>(&$x)?;
+
+            let (h, w) = $this.w_q.dims2()?;
+            let out_shape = Shape::from_dims(&[$batch, $out_features]);
+
+            let out = unsafe { $dev.alloc::<$scale_t>(out_shape.elem_count()).w()? };
+            let out_ptr = *out.device_ptr() as *mut $scale_t;
+            unsafe {
+                four_bit::[< fused_dequantize_matmul_ $postfix >](
+                    w_slice,
+                    scale_slice,
+                    zero_slice,
+                    x_slice,
+                    out_ptr,
+                    h as i32,
+                    w as i32,
+                    $batch as i32,
+                    $in_features as i32,
+                    $out_features as i32,
+                );
+            }
+
+            let storage = CudaStorage {
+                slice: CudaStorageSlice::$dtype(out),
+                device: $dev.clone(),
+            };
+            let storage = Storage::Cuda(storage);
+
+            from_storage_no_op(storage, out_shape, false)
+        }
+    }};
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum HqqAxis {
     Zero = 0,
@@ -120,6 +173,17 @@ impl TryFrom<usize> for HqqBits {
 }
 
 impl HqqBits {
+    /// Number of quantized values packed into one element of the packed weight.
+    pub(crate) fn pack_factor(&self) -> usize {
+        match self {
+            Self::Eight => 1,
+            Self::Four => 2,
+            Self::Three => 10,
+            Self::Two => 4,
+            Self::One => 8,
+        }
+    }
+
     // https://github.com/mobiusml/hqq/blob/306e30d9400629523c8e0af70101d8d7073cb3d5/hqq/core/bitpack.py#L10
     pub(crate) fn bitpack_type(&self) -> impl Fn(Tensor) -> Result<Tensor> {
         match self {
@@ -213,8 +277,9 @@ pub struct HqqConfig {
     pub group_size: NonZeroUsize,
     pub axis: HqqAxis,
     pub optimization_steps: Option<usize>,
-    pub round_zeros: bool,  // default false
-    pub channel_wise: bool, // default true
+    pub round_zeros: bool,      // default false
+    pub channel_wise: bool,     // default true
+    pub force_dequantize: bool, // default false
 }
 
 #[derive(Debug)]
@@ -245,37 +310,28 @@ impl HqqLayer {
         {
             candle_core::bail!("All tensors must be contiguous!");
         }
-        if self.cfg.axis as usize != 0 {
-            candle_core::bail!(
-                "CPU HQQ dequantization requires axis == 0, got {}.",
-                self.cfg.axis as usize
-            );
-        }
         let (h, w) = self.w_q.dims2()?;
+        let (scales, zeros) = self.dequant_scales_zeros(w)?;
 
-        match self.cfg.bits as usize {
+        let res = match self.cfg.bits as usize {
             8 => self
                 .w_q
-                .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant8Bit { h, w })?
-                .reshape(&self.w_shape),
+                .apply_op3_no_bwd(&scales, &zeros, &Dequant8Bit { h, w })?,
             4 => self
                 .w_q
-                .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant4Bit { h, w })?
-                .reshape(&self.w_shape),
+                .apply_op3_no_bwd(&scales, &zeros, &Dequant4Bit { h, w })?,
             3 => self
                 .w_q
-                .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant3Bit { h, w })?
-                .reshape(&self.w_shape),
+                .apply_op3_no_bwd(&scales, &zeros, &Dequant3Bit { h, w })?,
             2 => self
                 .w_q
-                .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant2Bit { h, w })?
-                .reshape(&self.w_shape),
+                .apply_op3_no_bwd(&scales, &zeros, &Dequant2Bit { h, w })?,
             1 => self
                 .w_q
-                .apply_op3_no_bwd(&self.scales, &self.zeros, &Dequant1Bit { h, w })?
-                .reshape(&self.w_shape),
+                .apply_op3_no_bwd(&scales, &zeros, &Dequant1Bit { h, w })?,
             b => candle_core::bail!("Unreachable bits {b}"),
-        }
+        };
+        self.finish_dequantize(res)
     }
 
     /// Dequantize `self` into a tensor of shape `scales` or `zeros`.
@@ -291,19 +347,16 @@ impl HqqLayer {
         {
             candle_core::bail!("All tensors must be contiguous!");
         }
-        if self.cfg.axis as usize != 0 {
-            candle_core::bail!(
-                "CUDA HQQ dequantization requires axis == 0, got {}.",
-                self.cfg.axis as usize
-            );
-        }
         let dev = get_cuda_device(&self.w_q)?;
+        let (scales, zeros) = self.dequant_scales_zeros(self.w_q.dim(1)?)?;
 
         let inner = match (self.cfg.bits as usize, self.scales.dtype()) {
             // 8 bits
             (8, DType::F32) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f32,
                     F32,
@@ -316,6 +369,8 @@ impl HqqLayer {
             (8, DType::F16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f16,
                     F16,
@@ -328,6 +383,8 @@ impl HqqLayer {
             (8, DType::BF16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = bf16,
                     BF16,
@@ -342,6 +399,8 @@ impl HqqLayer {
             (4, DType::F32) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f32,
                     F32,
@@ -354,6 +413,8 @@ impl HqqLayer {
             (4, DType::F16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f16,
                     F16,
@@ -366,6 +427,8 @@ impl HqqLayer {
             (4, DType::BF16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = bf16,
                     BF16,
@@ -379,8 +442,10 @@ impl HqqLayer {
             // 3 bits
             // https://github.com/mobiusml/hqq/blob/306e30d9400629523c8e0af70101d8d7073cb3d5/hqq/kernels/hqq_aten_cuda.cpp#L42-L45
             (3, DType::F32) => {
-                let res = dequant_for_dtype!(
+                dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = i32,
                     sz = f32,
                     F32,
@@ -388,12 +453,13 @@ impl HqqLayer {
                     dev,
                     three_bit,
                     3bit_32_kernel_f32
-                );
-                res.narrow(self.cfg.axis as usize, 0, self.cfg.group_size.into())?
+                )
             }
             (3, DType::F16) => {
-                let res = dequant_for_dtype!(
+                dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = i32,
                     sz = f16,
                     F16,
@@ -401,12 +467,13 @@ impl HqqLayer {
                     dev,
                     three_bit,
                     3bit_32_kernel_f16
-                );
-                res.narrow(self.cfg.axis as usize, 0, self.cfg.group_size.into())?
+                )
             }
             (3, DType::BF16) => {
-                let res = dequant_for_dtype!(
+                dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = i32,
                     sz = bf16,
                     BF16,
@@ -414,14 +481,15 @@ impl HqqLayer {
                     dev,
                     three_bit,
                     3bit_32_kernel_bf16
-                );
-                res.narrow(self.cfg.axis as usize, 0, self.cfg.group_size.into())?
+                )
             }
 
             // 2 bits
             (2, DType::F32) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f32,
                     F32,
@@ -434,6 +502,8 @@ impl HqqLayer {
             (2, DType::F16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f16,
                     F16,
@@ -446,6 +516,8 @@ impl HqqLayer {
             (2, DType::BF16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = bf16,
                     BF16,
@@ -460,6 +532,8 @@ impl HqqLayer {
             (1, DType::F32) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f32,
                     F32,
@@ -472,6 +546,8 @@ impl HqqLayer {
             (1, DType::F16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = f16,
                     F16,
@@ -484,6 +560,8 @@ impl HqqLayer {
             (1, DType::BF16) => {
                 dequant_for_dtype!(
                     self,
+                    scales,
+                    zeros,
                     w = u8,
                     sz = bf16,
                     BF16,
@@ -495,7 +573,41 @@ impl HqqLayer {
             }
             (bits, dtype) => candle_core::bail!("Unsupported bit width {bits} and dtype {dtype:?}"),
         };
-        inner.reshape(&self.w_shape)
+        self.finish_dequantize(inner)
+    }
+
+    /// The dequantization kernels index the scales and zeros by column of the packed weight, which
+    /// only matches the `axis == 0` layout. For `axis == 1` they are applied in `finish_dequantize`,
+    /// so the kernels are given an identity transform of width `w` instead.
+    fn dequant_scales_zeros(&self, w: usize) -> Result<(Tensor, Tensor)> {
+        match self.cfg.axis {
+            HqqAxis::Zero => Ok((self.scales.clone(), self.zeros.clone())),
+            HqqAxis::One => Ok((
+                Tensor::ones(w, self.scales.dtype(), self.scales.device())?,
+                Tensor::zeros(w, self.zeros.dtype(), self.zeros.device())?,
+            )),
+        }
+    }
+
+    /// Remove the 3 bit packing padding, apply the `axis == 1` scales and zeros and restore the
+    /// original weight shape.
+    fn finish_dequantize(&self, w: Tensor) -> Result<Tensor> {
+        let group_size: usize = self.cfg.group_size.into();
+        // The packed dimension is always 0: `group_size` rows for axis 0, one row per group for axis 1.
+        let rows = match self.cfg.axis {
+            HqqAxis::Zero => group_size,
+            HqqAxis::One => self.w_shape.elem_count() / group_size,
+        };
+        // https://github.com/mobiusml/hqq/blob/306e30d9400629523c8e0af70101d8d7073cb3d5/hqq/kernels/hqq_aten_cuda.cpp#L42-L45
+        let w = match self.cfg.bits {
+            HqqBits::Three => w.narrow(0, 0, rows)?,
+            _ => w,
+        };
+        let w = match self.cfg.axis {
+            HqqAxis::Zero => w,
+            HqqAxis::One => w.broadcast_sub(&self.zeros)?.broadcast_mul(&self.scales)?,
+        };
+        w.reshape(&self.w_shape)
     }
 
     fn dequantize_matmul(&self, xs: &Tensor) -> Result<Tensor> {
@@ -507,12 +619,188 @@ impl HqqLayer {
         };
         let res = xs.matmul(&w)?;
         if let Some(ref bias) = self.bias {
-            res + bias
+            res.broadcast_add(bias)
+        } else {
+            Ok(res)
+        }
+    }
+
+    /// Matmul which only casts, unpacks and dequantizes `HQQ_CHUNK_ELEMS` packed weight elements at
+    /// a time instead of the whole weight. Supported for 8 and 4 bit with `axis == 0`, otherwise
+    /// falls back to `dequantize_matmul`.
+    fn dequantize_matmul_chunked(&self, xs: &Tensor) -> Result<Tensor> {
+        let (h, n) = self.w_q.dims2()?;
+        let in_dim = match self.w_shape.dims() {
+            [_, in_dim] => *in_dim,
+            _ => return self.dequantize_matmul(xs),
+        };
+        // Each row of the unpacked weight is `n` contiguous elements of the original weight, so it
+        // must span whole output rows for the chunks to be sliceable.
+        if !matches!(self.cfg.bits, HqqBits::Eight | HqqBits::Four)
+            || !matches!(self.cfg.axis, HqqAxis::Zero)
+            || !self.cfg.channel_wise
+            || n % in_dim != 0
+        {
+            return self.dequantize_matmul(xs);
+        }
+        let out_rows_per_row = n / in_dim;
+        let dtype = self.scales.dtype();
+
+        let chunk_matmul = |plane: Tensor, len: usize| -> Result<Tensor> {
+            let w = plane
+                .to_dtype(dtype)?
+                .broadcast_sub(&self.zeros)?
+                .broadcast_mul(&self.scales)?
+                .reshape((len * out_rows_per_row, in_dim))?;
+            xs.broadcast_matmul(&w.t()?)
+        };
+
+        // The unpacked weight is the concatenation of the planes along dim 0. For 4 bit, the high
+        // nibbles of `w_q` hold the first half and the low nibbles the second half. Only one chunk
+        // of packed rows is cast and unpacked at a time.
+        let chunk_rows = (HQQ_CHUNK_ELEMS / n).max(1);
+        let mut high_outputs = Vec::new();
+        let mut low_outputs = Vec::new();
+        for start in (0..h).step_by(chunk_rows) {
+            let len = chunk_rows.min(h - start);
+            let w_q = self.w_q.narrow(0, start, len)?.to_dtype(DType::F32)?;
+            match self.cfg.bits {
+                HqqBits::Eight => high_outputs.push(chunk_matmul(w_q, len)?),
+                HqqBits::Four => {
+                    let high = (&w_q / 16.)?.floor()?;
+                    let low = (&w_q - (&high * 16.)?)?;
+                    high_outputs.push(chunk_matmul(high, len)?);
+                    low_outputs.push(chunk_matmul(low, len)?);
+                }
+                _ => unreachable!(),
+            }
+        }
+        high_outputs.extend(low_outputs);
+        let res = Tensor::cat(&high_outputs, D::Minus1)?;
+        if let Some(ref bias) = self.bias {
+            res.broadcast_add(bias)
+        } else {
+            Ok(res)
+        }
+    }
+
+    /// Whether [`HqqLayer::fused_matmul`] supports this layer: 4 bit, channel-wise, `axis == 0`
+    /// weights of a linear layer, on CUDA.
+    #[cfg(feature = "fused-hqq")]
+    fn can_fuse_matmul(&self) -> bool {
+        matches!(self.cfg.bits, HqqBits::Four)
+            && matches!(self.cfg.axis, HqqAxis::Zero)
+            && self.cfg.channel_wise
+            && self.w_shape.rank() == 2
+            && self.w_q.device().is_cuda()
+    }
+
+    /// Matmul with a CUDA kernel which dequantizes the packed weights while accumulating the dot
+    /// products, so the dequantized weight is never materialized. Only supports the layers of
+    /// [`HqqLayer::can_fuse_matmul`].
+    #[cfg(feature = "fused-hqq")]
+    pub fn fused_matmul(&self, xs: &Tensor) -> Result<Tensor> {
+        if !self.can_fuse_matmul() {
+            candle_core::bail!(
+                "The fused HQQ matmul requires 4 bit, channel-wise weights with axis 0 on CUDA."
+            );
+        }
+        let (out_features, in_features) = self.w_shape.dims2()?;
+        if xs.dim(D::Minus1)? != in_features {
+            candle_core::bail!(
+                "Expected an input with {in_features} features, got shape {:?}.",
+                xs.shape()
+            );
+        }
+        if xs.dtype() != self.scales.dtype() {
+            candle_core::bail!(
+                "Expected an input of dtype {:?}, got {:?}.",
+                self.scales.dtype(),
+                xs.dtype()
+            );
+        }
+        if !(self.w_q.is_contiguous() && self.scales.is_contiguous() && self.zeros.is_contiguous())
+        {
+            candle_core::bail!("All tensors must be contiguous!");
+        }
+
+        let mut out_dims = xs.dims().to_vec();
+        *out_dims.last_mut().expect("Input has no dimensions.") = out_features;
+        let x = xs.reshape(((), in_features))?.contiguous()?;
+        let rows = x.dim(0)?;
+        // The kernel runs one block per row of `x` and output feature, along the y and x grid axes,
+        // so larger inputs are split over several launches.
+        let res = if rows <= FUSED_MATMUL_LAUNCH_ROWS {
+            self.fused_matmul_launch(&x, in_features, out_features)?
+        } else {
+            let mut outputs = Vec::with_capacity(rows.div_ceil(FUSED_MATMUL_LAUNCH_ROWS));
+            for start in (0..rows).step_by(FUSED_MATMUL_LAUNCH_ROWS) {
+                let len = FUSED_MATMUL_LAUNCH_ROWS.min(rows - start);
+                outputs.push(self.fused_matmul_launch(
+                    &x.narrow(0, start, len)?,
+                    in_features,
+                    out_features,
+                )?);
+            }
+            Tensor::cat(&outputs, 0)?
+        }
+        .reshape(out_dims)?;
+        if let Some(ref bias) = self.bias {
+            res.broadcast_add(bias)
         } else {
             Ok(res)
         }
     }
 
+    /// Launch the fused kernel for a contiguous 2D input of at most `FUSED_MATMUL_LAUNCH_ROWS`
+    /// rows.
+    #[cfg(feature = "fused-hqq")]
+    fn fused_matmul_launch(
+        &self,
+        x: &Tensor,
+        in_features: usize,
+        out_features: usize,
+    ) -> Result<Tensor> {
+        let dev = get_cuda_device(&self.w_q)?;
+        let batch = x.dim(0)?;
+        match self.scales.dtype() {
+            DType::F32 => fused_matmul_for_dtype!(
+                self,
+                x,
+                batch = batch,
+                in = in_features,
+                out = out_features,
+                sz = f32,
+                F32,
+                dev,
+                4bit_u8_kernel_f32
+            ),
+            DType::F16 => fused_matmul_for_dtype!(
+                self,
+                x,
+                batch = batch,
+                in = in_features,
+                out = out_features,
+                sz = f16,
+                F16,
+                dev,
+                4bit_u8_kernel_f16
+            ),
+            DType::BF16 => fused_matmul_for_dtype!(
+                self,
+                x,
+                batch = batch,
+                in = in_features,
+                out = out_features,
+                sz = bf16,
+                BF16,
+                dev,
+                4bit_u8_kernel_bf16
+            ),
+            dtype => candle_core::bail!("Unsupported dtype {dtype:?} for the fused HQQ matmul."),
+        }
+    }
+
     pub fn with_bias(mut self, bias: Tensor) -> Self {
         self.bias = Some(bias);
         self
@@ -529,7 +817,8 @@ impl QuantMethod for HqqLayer {
             | QuantMethodConfig::Unquantized(_)
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => {
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => {
                 unreachable!()
             }
             QuantMethodConfig::Hqq {
@@ -540,6 +829,7 @@ impl QuantMethod for HqqLayer {
                 optimization_steps,
                 round_zeros,
                 channel_wise,
+                force_dequantize,
                 bias,
             } => {
                 let cfg = HqqConfig {
@@ -549,6 +839,7 @@ impl QuantMethod for HqqLayer {
                     optimization_steps,
                     round_zeros: round_zeros.unwrap_or(false),
                     channel_wise: channel_wise.unwrap_or(true),
+                    force_dequantize: force_dequantize.unwrap_or(false),
                 };
 
                 let this = Self::quantize(&tensor, tensor.device(), cfg)?;
@@ -561,22 +852,40 @@ impl QuantMethod for HqqLayer {
         }
     }
 
+    fn dequantize_w(&self) -> Result<Tensor> {
+        self.dequantize()
+    }
+
     fn forward(&self, a: &Tensor) -> Result<Tensor> {
-        /*
+        #[cfg(feature = "fused-hqq")]
+        if !self.cfg.force_dequantize
+            && self.can_fuse_matmul()
+            && a.elem_count() / a.dim(D::Minus1)? <= FUSED_MATMUL_MAX_ROWS
+        {
+            return self.fused_matmul(a);
+        }
+        // Without `force_dequantize`, the full dequantized weight is never materialized, also on
+        // CUDA, unless the chunked matmul does not support the layer.
         if self.cfg.force_dequantize {
             self.dequantize_matmul(a)
         } else {
-            todo!()
-        } */
-        self.dequantize_matmul(a)
+            self.dequantize_matmul_chunked(a)
+        }
     }
 
     fn quantized_act_type(&self) -> Option<DType> {
         Some(self.scales.dtype())
     }
 
-    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
-        candle_core::bail!("HQQ quantization does not support adding weight delta.")
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        let (dtype, device) = self.dtype_and_device();
+        let w = (self.dequantize()? + delta.to_device(&device)?.to_dtype(dtype)?)?;
+        let res = Self::quantize(&w, &device, self.cfg)?;
+        if let Some(ref bias) = self.bias {
+            Ok(Arc::new(res.with_bias(bias.clone())))
+        } else {
+            Ok(Arc::new(res))
+        }
     }
 
     fn dtype_and_device(&self) -> (DType, Device) {
@@ -593,13 +902,26 @@ impl QuantMethod for HqqLayer {
         device: Device,
         n_quantized: &AtomicUsize,
     ) -> Result<Arc<dyn QuantMethod>> {
+        if let Some(IsqType::F8E4M3) = dtype {
+            // Quantize the dequantized weights with the FP8 path of `UnquantLinear`
+            let bias = self
+                .bias
+                .as_ref()
+                .map(|b| b.to_device(&device))
+                .transpose()?;
+            let unquant = UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(
+                self.dequantize()?.to_device(&device)?,
+                bias,
+            )))?;
+            return Arc::new(unquant).apply_isq(dtype, device, n_quantized);
+        }
         n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let bits = match dtype {
             Some(IsqType::HQQ8) => HqqBits::Eight,
             Some(IsqType::HQQ4) => HqqBits::Four,
-            // Some(IsqType::HQQ3) => HqqBits::Three,
-            // Some(IsqType::HQQ2) => HqqBits::Two,
-            // Some(IsqType::HQQ1) => HqqBits::One,
+            Some(IsqType::HQQ3) => HqqBits::Three,
+            Some(IsqType::HQQ2) => HqqBits::Two,
+            Some(IsqType::HQQ1) => HqqBits::One,
             _ => candle_core::bail!("Expected a HQQ ISQ type."),
         };
         let cfg = HqqConfig {
@@ -609,6 +931,7 @@ impl QuantMethod for HqqLayer {
             optimization_steps: ISQ_HQQ_DEFAULT_OPT_STEPS,
             round_zeros: false,
             channel_wise: true,
+            force_dequantize: false,
         };
         let dequant = self.dequantize()?;
         let res = Self::quantize(&dequant, &device, cfg)?;
@@ -661,6 +984,8 @@ impl QuantMethod for HqqLayer {
 // -----------------------
 // Cfg channel_wise, boolean u8, little endian
 // -----------------------
+// Cfg force_dequantize, boolean u8, little endian (since v0.1.4)
+// -----------------------
 // [OPTIONAL] Bias tensor data generated by `serialize_tensor`. Refer to its docs for layout.
 // -----------------------
 
@@ -702,6 +1027,7 @@ impl QuantizedSerde for HqqLayer {
         buffer.extend(&(self.cfg.optimization_steps.unwrap_or(0) as u32).to_le_bytes());
         buffer.push(self.cfg.round_zeros as u8);
         buffer.push(self.cfg.channel_wise as u8);
+        buffer.push(self.cfg.force_dequantize as u8);
 
         if let Some(bias) = &self.bias {
             // Bias
@@ -753,6 +1079,9 @@ impl QuantizedSerde for HqqLayer {
         };
         let round_zeros = buffer.read_u8()? != 0;
         let channel_wise = buffer.read_u8()? != 0;
+        // Before v0.1.4, the flag was not stored and always unset
+        let force_dequantize =
+            version >= HQFF_VERSION_HQQ_FORCE_DEQUANTIZE && buffer.read_u8()? != 0;
 
         let cfg = HqqConfig {
             bits,
@@ -761,6 +1090,7 @@ impl QuantizedSerde for HqqLayer {
             optimization_steps,
             round_zeros,
             channel_wise,
+            force_dequantize,
         };
 
         let b = if has_bias {
@@ -779,3 +1109,350 @@ impl QuantizedSerde for HqqLayer {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_hqq_serde_roundtrip_cpu() -> candle_core::Result<()> {
+        use candle_core::{DType, Device, Tensor};
+
+        use crate::{
+            HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod, QuantizedSerde, UnquantLinear,
+        };
+
+        let dev = Device::Cpu;
+        let w = Tensor::rand(-1., 1., (128, 256), &dev)?.to_dtype(DType::F32)?;
+        let b = Tensor::rand(-1., 1., 128, &dev)?.to_dtype(DType::F32)?;
+        let xs = Tensor::rand(-1., 1., (1, 4, 256), &dev)?.to_dtype(DType::F32)?;
+
+        for (bits, force_dequantize) in [
+            (HqqBits::Eight, false),
+            (HqqBits::Four, false),
+            (HqqBits::Four, true),
+            (HqqBits::Three, false),
+        ] {
+            let layer = HqqLayer::quantize(
+                &w,
+                &dev,
+                HqqConfig {
+                    bits,
+                    group_size: 64.try_into()?,
+                    axis: HqqAxis::Zero,
+                    optimization_steps: Some(5),
+                    round_zeros: false,
+                    channel_wise: true,
+                    force_dequantize,
+                },
+            )?
+            .with_bias(b.clone());
+
+            let data = layer.serialize()?;
+            let reloaded = HqqLayer::deserialize(data.clone(), &dev)?;
+
+            let expected = layer.forward(&xs)?.to_vec3::<f32>()?;
+            let actual = reloaded.forward(&xs)?.to_vec3::<f32>()?;
+            assert_eq!(expected, actual);
+            // The config, including `force_dequantize`, must survive the round trip.
+            assert_eq!(data, reloaded.serialize()?);
+
+            // An HQQ artifact must not load as another quantization type.
+            assert!(UnquantLinear::deserialize(data, &dev).is_err());
+        }
+
+        Ok(())
+    }
+
+    /// `forward` without `force_dequantize` uses the chunked matmul, which must match the matmul
+    /// with the full dequantized weight.
+    fn check_hqq_chunked_matmul(dev: &candle_core::Device) -> candle_core::Result<()> {
+        use candle_core::{DType, Tensor};
+
+        use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod};
+
+        let dev = dev.clone();
+        // Large enough to be split into several `HQQ_CHUNK_ELEMS` chunks.
+        let w = Tensor::rand(-1., 1., (1024, 4096), &dev)?.to_dtype(DType::F32)?;
+        let b = Tensor::rand(-1., 1., 1024, &dev)?.to_dtype(DType::F32)?;
+        let xs = Tensor::rand(-1., 1., (2, 3, 4096), &dev)?.to_dtype(DType::F32)?;
+
+        for bits in [HqqBits::Eight, HqqBits::Four] {
+            let layer = HqqLayer::quantize(
+                &w,
+                &dev,
+                HqqConfig {
+                    bits,
+                    group_size: 64.try_into()?,
+                    axis: HqqAxis::Zero,
+                    optimization_steps: None,
+                    round_zeros: false,
+                    channel_wise: true,
+                    force_dequantize: false,
+                },
+            )?
+            .with_bias(b.clone());
+
+            let chunked = layer.forward(&xs)?;
+            let expected = layer.dequantize_matmul(&xs)?;
+            assert_eq!(chunked.dims(), &[2, 3, 1024]);
+
+            let max_diff = (chunked - expected)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(max_diff < 1e-3, "{bits:?}: max difference {max_diff}");
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_hqq_chunked_matmul_cpu() -> candle_core::Result<()> {
+        check_hqq_chunked_matmul(&candle_core::Device::Cpu)
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn test_hqq_chunked_matmul_cuda() -> candle_core::Result<()> {
+        check_hqq_chunked_matmul(&candle_core::Device::new_cuda(0)?)
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_hqq_add_delta_w_cpu() -> candle_core::Result<()> {
+        use candle_core::{DType, Device, Tensor};
+
+        use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod};
+
+        let dev = Device::Cpu;
+        let w = Tensor::rand(-1., 1., (128, 256), &dev)?.to_dtype(DType::F32)?;
+        let b = Tensor::rand(-1., 1., 128, &dev)?.to_dtype(DType::F32)?;
+        let xs = Tensor::rand(-1., 1., (1, 4, 256), &dev)?.to_dtype(DType::F32)?;
+
+        // Rank 4 delta, as a LoRA `B @ A` would produce.
+        let lora_b = Tensor::rand(-0.1, 0.1, (128, 4), &dev)?.to_dtype(DType::F32)?;
+        let lora_a = Tensor::rand(-0.1, 0.1, (4, 256), &dev)?.to_dtype(DType::F32)?;
+        let delta = lora_b.matmul(&lora_a)?;
+
+        let layer = HqqLayer::quantize(
+            &w,
+            &dev,
+            HqqConfig {
+                bits: HqqBits::Eight,
+                group_size: 64.try_into()?,
+                axis: HqqAxis::Zero,
+                optimization_steps: None,
+                round_zeros: false,
+                channel_wise: true,
+                force_dequantize: true,
+            },
+        )?
+        .with_bias(b.clone());
+
+        let merged = layer.add_delta_w(&delta)?;
+        let expected = xs
+            .broadcast_matmul(&(layer.dequantize()? + &delta)?.t()?)?
+            .broadcast_add(&b)?;
+        let actual = merged.forward(&xs)?;
+
+        let err = (actual - expected)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+        assert!(err < 0.05, "mean abs error {err}");
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_dequantize_w_cpu() -> candle_core::Result<()> {
+        use candle_core::{DType, Device, Tensor};
+        use candle_nn::Linear;
+
+        use crate::{
+            HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod, QuantMethodConfig, UnquantLinear,
+        };
+
+        let dev = Device::Cpu;
+        let w = Tensor::rand(-1., 1., (128, 256), &dev)?.to_dtype(DType::F32)?;
+        let w_norm = w.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()?;
+
+        for (bits, max_rel_err) in [(HqqBits::Eight, 0.01), (HqqBits::Four, 0.1)] {
+            let layer: &dyn QuantMethod = &HqqLayer::quantize(
+                &w,
+                &dev,
+                HqqConfig {
+                    bits,
+                    group_size: 64.try_into()?,
+                    axis: HqqAxis::Zero,
+                    optimization_steps: None,
+                    round_zeros: false,
+                    channel_wise: true,
+                    force_dequantize: false,
+                },
+            )?;
+            let deq = layer.dequantize_w()?;
+            assert_eq!(deq.dims(), w.dims());
+
+            let err = (deq.to_dtype(DType::F32)? - &w)?
+                .sqr()?
+                .sum_all()?
+                .sqrt()?
+                .to_scalar::<f32>()?;
+            assert!(
+                err / w_norm < max_rel_err,
+                "{bits:?}: relative error {}",
+                err / w_norm
+            );
+        }
+
+        let unquant =
+            UnquantLinear::new(QuantMethodConfig::Unquantized(Linear::new(w.clone(), None)))?;
+        let deq = unquant.dequantize_w()?;
+        assert_eq!(deq.to_vec2::<f32>()?, w.to_vec2::<f32>()?);
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "cuda"))]
+    #[test]
+    fn test_dequantize_axis_one_matches_axis_zero_cpu() -> candle_core::Result<()> {
+        use candle_core::{Device, Tensor};
+
+        use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+
+        let dev = Device::Cpu;
+        // `rows` groups of `group_size` for axis 1, which is the transpose of the axis 0 grouping.
+        let (rows, group_size) = (40, 64);
+
+        for bits in [
+            HqqBits::Eight,
+            HqqBits::Four,
+            HqqBits::Three,
+            HqqBits::Two,
+            HqqBits::One,
+        ] {
+            let max_q = (1 << bits as usize) as f32;
+            let q = (0..rows * group_size)
+                .map(|i| ((i / group_size * 7 + i % group_size * 3) as f32) % max_q)
+                .collect::<Vec<_>>();
+            let q = Tensor::from_vec(q, (rows, group_size), &dev)?;
+            let scales = Tensor::arange(1f32, (rows + 1) as f32, &dev)?.affine(0.01, 0.)?;
+            let zeros = Tensor::arange(0f32, rows as f32, &dev)?.affine(0.5, 0.)?;
+
+            let layer = |w_q: &Tensor, scales: Tensor, zeros: Tensor, axis| {
+                candle_core::Result::Ok(HqqLayer {
+                    w_q: bits.bitpack_type()(w_q.clone())?,
+                    zeros,
+                    scales,
+                    bias: None,
+                    w_shape: w_q.shape().clone(),
+                    cfg: HqqConfig {
+                        bits,
+                        group_size: group_size.try_into()?,
+                        axis,
+                        optimization_steps: None,
+                        round_zeros: false,
+                        channel_wise: true,
+                        force_dequantize: false,
+                    },
+                })
+            };
+            let axis_zero = layer(
+                &q.t()?.contiguous()?,
+                scales.reshape((1, rows))?,
+                zeros.reshape((1, rows))?,
+                HqqAxis::Zero,
+            )?;
+            let axis_one = layer(
+                &q,
+                scales.reshape((rows, 1))?,
+                zeros.reshape((rows, 1))?,
+                HqqAxis::One,
+            )?;
+
+            let deq_zero = axis_zero.dequantize()?;
+            let deq_one = axis_one.dequantize()?;
+            assert_eq!(deq_zero.dims(), &[group_size, rows]);
+            assert_eq!(deq_one.dims(), &[rows, group_size]);
+            assert_eq!(
+                deq_zero.t()?.to_vec2::<f32>()?,
+                deq_one.to_vec2::<f32>()?,
+                "{bits:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "fused-hqq")]
+    #[test]
+    fn test_hqq_fused_matmul_cuda() -> candle_core::Result<()> {
+        use candle_core::{DType, Device, Tensor};
+
+        use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer, QuantMethod};
+
+        let dev = Device::new_cuda(0)?;
+        let w = Tensor::rand(-1., 1., (384, 256), &dev)?.to_dtype(DType::F32)?;
+        let b = Tensor::rand(-1., 1., 384, &dev)?.to_dtype(DType::F32)?;
+        let xs = Tensor::rand(-1., 1., (2, 3, 256), &dev)?.to_dtype(DType::F32)?;
+
+        for dtype in [DType::F32, DType::BF16] {
+            let layer = HqqLayer::quantize(
+                &w.to_dtype(dtype)?,
+                &dev,
+                HqqConfig {
+                    bits: HqqBits::Four,
+                    group_size: 64.try_into()?,
+                    axis: HqqAxis::Zero,
+                    optimization_steps: None,
+                    round_zeros: false,
+                    channel_wise: true,
+                    force_dequantize: false,
+                },
+            )?
+            .with_bias(b.to_dtype(dtype)?);
+            let xs = xs.to_dtype(dtype)?;
+
+            let fused = layer.fused_matmul(&xs)?;
+            assert_eq!(fused.dims(), &[2, 3, 384]);
+            assert_eq!(layer.forward(&xs)?.dims(), fused.dims());
+            let expected = layer.dequantize_matmul(&xs)?;
+
+            let max_diff = (fused - expected)?
+                .to_dtype(DType::F32)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            let tol = if dtype == DType::F32 { 1e-3 } else { 0.5 };
+            assert!(max_diff < tol, "{dtype:?}: max difference {max_diff}");
+        }
+
+        // More rows than a single launch supports are split over several launches.
+        let layer = HqqLayer::quantize(
+            &w,
+            &dev,
+            HqqConfig {
+                bits: HqqBits::Four,
+                group_size: 64.try_into()?,
+                axis: HqqAxis::Zero,
+                optimization_steps: None,
+                round_zeros: false,
+                channel_wise: true,
+                force_dequantize: false,
+            },
+        )?;
+        let xs = Tensor::rand(-1f32, 1., (65536 + 7, 256), &dev)?;
+        let fused = layer.fused_matmul(&xs)?;
+        assert_eq!(fused.dims(), &[65536 + 7, 384]);
+        let max_diff = (fused - layer.forward(&xs)?)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(max_diff < 1e-3, "max difference {max_diff}");
+
+        Ok(())
+    }
+}
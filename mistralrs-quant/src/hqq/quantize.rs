@@ -2,7 +2,7 @@ use candle_core::{DType, Device, Result, Tensor};
 
 use crate::hqq::optimize::OptResults;
 
-use super::{optimize::OptParams, HqqAxis, HqqConfig, HqqLayer};
+use super::{optimize::OptParams, HqqAxis, HqqBits, HqqConfig, HqqLayer};
 
 impl HqqLayer {
     /// Quantize the model into HQQ
@@ -24,6 +24,16 @@ impl HqqLayer {
             w
         };
 
+        // 3 bit packing pads the packed dimension, the other widths need it to divide evenly.
+        let pack_factor = cfg.bits.pack_factor();
+        if !matches!(cfg.bits, HqqBits::Three) && w.dim(0)? % pack_factor != 0 {
+            candle_core::bail!(
+                "HQQ {}-bit packing requires the packed dimension ({}) to be divisible by {pack_factor}.",
+                cfg.bits as usize,
+                w.dim(0)?
+            );
+        }
+
         // Get min and max valyes
         let (min, max) = if !cfg.channel_wise {
             // TODO we need min_all
@@ -98,7 +108,7 @@ impl HqqLayer {
 }
 
 mod test {
-    #[cfg(all(feature = "cuda", test))]
+    #[cfg(test)]
     use candle_core::{Device, Result, Tensor};
 
     #[cfg(all(feature = "cuda", test))]
@@ -120,6 +130,7 @@ mod test {
                 optimization_steps: None,
                 round_zeros: false,
                 channel_wise: true,
+                force_dequantize: false,
             },
         )?;
 
@@ -128,4 +139,129 @@ mod test {
         // dbg!(&(&dequant - &data)?.abs()?.mean_all()?);
         Ok(())
     }
+
+    #[cfg(all(not(feature = "cuda"), test))]
+    #[test]
+    fn test_quantize_hqq_roundtrip_cpu() -> Result<()> {
+        use candle_core::DType;
+
+        use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+
+        let dev = Device::Cpu;
+        let data = Tensor::rand(0., 1., (256, 128), &dev)?.to_dtype(DType::F32)?;
+
+        // Mean absolute error bounds for weights in [0, 1), loosely `1 / (2^bits - 1)`.
+        for (bits, max_err) in [
+            (HqqBits::Eight, 0.01),
+            (HqqBits::Four, 0.05),
+            (HqqBits::Three, 0.1),
+            (HqqBits::Two, 0.2),
+            (HqqBits::One, 0.5),
+        ] {
+            let hqq = HqqLayer::quantize(
+                &data,
+                &dev,
+                HqqConfig {
+                    bits,
+                    group_size: 64.try_into()?,
+                    axis: HqqAxis::Zero,
+                    optimization_steps: None,
+                    round_zeros: false,
+                    channel_wise: true,
+                    force_dequantize: false,
+                },
+            )?;
+
+            let dequant = hqq.dequantize()?;
+            assert_eq!(dequant.dims(), data.dims());
+
+            let err = (&dequant - &data)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+            assert!(
+                err < max_err,
+                "{bits:?}: mean abs error {err} exceeds {max_err}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(not(feature = "cuda"), test))]
+    #[test]
+    fn test_isq_hqq_low_bits_cpu() -> Result<()> {
+        use std::sync::{atomic::AtomicUsize, Arc};
+
+        use candle_core::DType;
+        use candle_nn::Linear;
+
+        use crate::{IsqType, QuantMethod, QuantMethodConfig, UnquantLinear};
+
+        let dev = Device::Cpu;
+        let w = Tensor::rand(-1., 1., (128, 256), &dev)?.to_dtype(DType::F32)?;
+        let xs = Tensor::rand(-1., 1., (1, 4, 256), &dev)?.to_dtype(DType::F32)?;
+
+        for isq_type in [IsqType::HQQ3, IsqType::HQQ2, IsqType::HQQ1] {
+            let layer = Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                Linear::new(w.clone(), None),
+            ))?);
+            let n_quantized = AtomicUsize::new(0);
+            let quantized = layer.apply_isq(Some(isq_type), dev.clone(), &n_quantized)?;
+            assert_eq!(n_quantized.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+            let out = quantized.forward(&xs)?;
+            assert_eq!(out.dims(), &[1, 4, 128]);
+            let mean = out.abs()?.mean_all()?.to_scalar::<f32>()?;
+            assert!(mean.is_finite(), "{isq_type:?}: non-finite output");
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(not(feature = "cuda"), test))]
+    #[test]
+    fn test_quantize_hqq_axis_one_cpu() -> Result<()> {
+        use candle_core::DType;
+
+        use crate::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+
+        let dev = Device::Cpu;
+        let data = Tensor::rand(0., 1., (384, 128), &dev)?.to_dtype(DType::F32)?;
+
+        for bits in [
+            HqqBits::Eight,
+            HqqBits::Four,
+            HqqBits::Three,
+            HqqBits::Two,
+            HqqBits::One,
+        ] {
+            let mut errs = Vec::new();
+            for axis in [HqqAxis::Zero, HqqAxis::One] {
+                let hqq = HqqLayer::quantize(
+                    &data,
+                    &dev,
+                    HqqConfig {
+                        bits,
+                        group_size: 64.try_into()?,
+                        axis,
+                        optimization_steps: None,
+                        round_zeros: false,
+                        channel_wise: true,
+                        force_dequantize: false,
+                    },
+                )?;
+
+                let dequant = hqq.dequantize()?;
+                assert_eq!(dequant.dims(), data.dims());
+                errs.push((&dequant - &data)?.abs()?.mean_all()?.to_scalar::<f32>()?);
+            }
+
+            // Both groupings see the same value distribution, so their error should be comparable.
+            let (err0, err1) = (errs[0], errs[1]);
+            assert!(
+                err1 < err0 * 1.5 && err0 < err1 * 1.5,
+                "{bits:?}: axis 0 error {err0} and axis 1 error {err1} differ too much"
+            );
+        }
+
+        Ok(())
+    }
 }
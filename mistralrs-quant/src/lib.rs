@@ -10,6 +10,7 @@ use candle_core::{
     DType, Device, Result, Tensor,
 };
 
+mod awq;
 mod cublaslt;
 mod dummy;
 mod fp8;
@@ -19,6 +20,8 @@ mod hqq;
 mod unquantized;
 mod utils;
 
+use awq::awq_linear;
+pub use awq::AwqLayer;
 pub use dummy::DummyLayer;
 pub use fp8::FP8Linear;
 pub use gguf::GgufMatMul;
@@ -26,6 +29,7 @@ use gptq::gptq_linear;
 pub use gptq::GptqLayer;
 pub use hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
 pub use unquantized::UnquantLinear;
+pub use utils::{artifact_version, HQFF_VERSION_VISION_ISQ};
 
 use candle_nn::{Linear, VarBuilder};
 use serde::{Deserialize, Serialize};
@@ -35,12 +39,15 @@ pub enum QuantMethodType {
     #[default]
     #[serde(rename = "gptq")]
     Gptq,
+    #[serde(rename = "awq")]
+    Awq,
 }
 
 impl Display for QuantMethodType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Gptq => write!(f, "GPTQ"),
+            Self::Awq => write!(f, "AWQ"),
         }
     }
 }
@@ -79,6 +86,7 @@ pub enum QuantMethodConfig {
         optimization_steps: Option<usize>,
         round_zeros: Option<bool>,
         channel_wise: Option<bool>,
+        force_dequantize: Option<bool>,
         bias: Option<Tensor>,
     },
     Dummy,
@@ -86,6 +94,14 @@ pub enum QuantMethodConfig {
         lin: Linear,
         dtype: DType,
     },
+    Awq {
+        bits: usize,
+        q_weight: Tensor,
+        qzeros: Tensor,
+        scales: Tensor,
+        group_size: usize,
+        bias: Option<Tensor>,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
@@ -104,9 +120,9 @@ pub enum IsqType {
     Q8K,
     HQQ8,
     HQQ4,
-    // HQQ3,
-    // HQQ2,
-    // HQQ1,
+    HQQ3,
+    HQQ2,
+    HQQ1,
     F8E4M3,
 }
 
@@ -193,6 +209,9 @@ pub trait QuantMethod: Send + Sync + Debug + QuantizedSerde {
     where
         Self: Sized;
 
+    /// Dequantize the weight into a float tensor of the original weight shape.
+    fn dequantize_w(&self) -> Result<Tensor>;
+
     /// Compute matmul of `self` and `a`. `self` should contain the weights.
     fn forward(&self, a: &Tensor) -> Result<Tensor>;
 
@@ -238,6 +257,7 @@ pub fn linear_no_bias(
     let layer = if let Some(quant_conf) = &config {
         match quant_conf.quant_method {
             QuantMethodType::Gptq => gptq_linear(in_dim, out_dim, quant_conf, vb)?,
+            QuantMethodType::Awq => awq_linear(in_dim, out_dim, quant_conf, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
@@ -263,6 +283,7 @@ pub fn linear(
     let layer = if let Some(quant_conf) = &config {
         match quant_conf.quant_method {
             QuantMethodType::Gptq => gptq_linear(in_dim, out_dim, quant_conf, vb)?,
+            QuantMethodType::Awq => awq_linear(in_dim, out_dim, quant_conf, vb)?,
         }
     } else {
         // Handle the case where the layer is dummy (no tensors)
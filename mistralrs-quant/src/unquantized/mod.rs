@@ -30,11 +30,16 @@ impl QuantMethod for UnquantLinear {
             | QuantMethodConfig::Gptq { .. }
             | QuantMethodConfig::Hqq { .. }
             | QuantMethodConfig::Dummy
-            | QuantMethodConfig::FP8 { .. } => unreachable!(),
+            | QuantMethodConfig::FP8 { .. }
+            | QuantMethodConfig::Awq { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self(l)),
         }
     }
 
+    fn dequantize_w(&self) -> Result<Tensor> {
+        Ok(self.0.weight().clone())
+    }
+
     fn forward(&self, a: &Tensor) -> Result<Tensor> {
         self.0.forward(a)
     }
@@ -65,15 +70,14 @@ impl QuantMethod for UnquantLinear {
         n_quantized: &AtomicUsize,
     ) -> Result<Arc<dyn QuantMethod>> {
         match dtype {
-            /*Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
-            Some(IsqType::HQQ4 | IsqType::HQQ8) => {
+            Some(IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | IsqType::HQQ4 | IsqType::HQQ8) => {
                 n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 let bits = match dtype.unwrap() {
                     IsqType::HQQ8 => HqqBits::Eight,
                     IsqType::HQQ4 => HqqBits::Four,
-                    // IsqType::HQQ3 => HqqBits::Three,
-                    // IsqType::HQQ2 => HqqBits::Two,
-                    // IsqType::HQQ1 => HqqBits::One,
+                    IsqType::HQQ3 => HqqBits::Three,
+                    IsqType::HQQ2 => HqqBits::Two,
+                    IsqType::HQQ1 => HqqBits::One,
                     _ => unreachable!(),
                 };
                 let cfg = HqqConfig {
@@ -83,6 +87,7 @@ impl QuantMethod for UnquantLinear {
                     optimization_steps: ISQ_HQQ_DEFAULT_OPT_STEPS,
                     round_zeros: false,
                     channel_wise: true,
+                    force_dequantize: false,
                 };
                 let res = HqqLayer::quantize(&self.0.weight().to_device(&device)?, &device, cfg)?;
                 if let Some(bias) = self.0.bias() {
@@ -120,6 +125,7 @@ impl QuantMethod for UnquantLinear {
                 })?))
             }
             Some(IsqType::F8E4M3) => {
+                n_quantized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 let w = self.0.weight().to_device(&device)?;
                 let b = if let Some(b) = self.0.bias() {
                     Some(b.to_device(&device)?)
@@ -147,8 +153,7 @@ impl QuantMethod for UnquantLinear {
 
     fn get_max_isq_cpu_threads(&self, dtype: IsqType) -> Option<NonZeroUsize> {
         match dtype {
-            /*IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | */
-            IsqType::HQQ4 | IsqType::HQQ8 => {
+            IsqType::HQQ1 | IsqType::HQQ2 | IsqType::HQQ3 | IsqType::HQQ4 | IsqType::HQQ8 => {
                 // Use 1 because our HQQ quantizes on the GPU
                 Some(1.try_into().unwrap())
             }
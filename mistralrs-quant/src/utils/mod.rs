@@ -6,9 +6,10 @@ mod ops;
 mod uqff;
 
 pub use ops::{BitWiseOp, LeftshiftOp};
+pub use uqff::{artifact_version, HQFF_VERSION_VISION_ISQ};
 pub(crate) use uqff::{
     deserialize_tensor, read_dtype, serialize_tensor, version_is_compatible, write_dtype,
-    HQFF_VERSION,
+    HQFF_VERSION, HQFF_VERSION_FP8_SCALE_TENSORS, HQFF_VERSION_HQQ_FORCE_DEQUANTIZE,
 };
 
 #[cfg(feature = "cuda")]
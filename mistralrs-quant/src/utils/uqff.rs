@@ -7,15 +7,35 @@ use half::{bf16, f16};
 // v0.1.0: initial release
 // v0.1.1: add i16 dtype
 // v0.1.2: add F8E4M3
+// v0.1.3: store FP8 scales as tensors
+// v0.1.4: store the HQQ force_dequantize flag
+// v0.1.5: quantize the vision encoders and projectors of vision models
 
 const HQFF_VERSION_MAJOR: u32 = 0;
 const HQFF_VERSION_MINOR: u32 = 1;
-const HQFF_VERSION_PATCH: u32 = 2;
+const HQFF_VERSION_PATCH: u32 = 5;
 
 /// Format 4 bytes, little endian: [ UNSPECIFIED ] [ MAJOR ] [ MINOR ] [ PATCH ]
 pub(crate) const HQFF_VERSION: u32 =
     (HQFF_VERSION_MAJOR << (8 * 2)) | (HQFF_VERSION_MINOR << 8) | HQFF_VERSION_PATCH;
 
+/// First version where FP8 scales are stored as tensors rather than f32 scalars.
+pub(crate) const HQFF_VERSION_FP8_SCALE_TENSORS: u32 =
+    (HQFF_VERSION_MAJOR << (8 * 2)) | (1 << 8) | 3;
+
+/// First version where the HQQ `force_dequantize` flag is stored.
+pub(crate) const HQFF_VERSION_HQQ_FORCE_DEQUANTIZE: u32 =
+    (HQFF_VERSION_MAJOR << (8 * 2)) | (1 << 8) | 4;
+
+/// First version where the vision encoder and projector layers of vision models are ISQ layers.
+/// Older UQFF files of these models do not contain them.
+pub const HQFF_VERSION_VISION_ISQ: u32 = (HQFF_VERSION_MAJOR << (8 * 2)) | (1 << 8) | 5;
+
+/// Version of a serialized ISQ artifact, stored in its first 4 bytes.
+pub fn artifact_version(mut data: &[u8]) -> Result<u32> {
+    Ok(data.read_u32::<LittleEndian>()?)
+}
+
 /// Check if major version matches: is backwards compatible
 pub(crate) fn version_is_compatible(version: u32) -> Result<()> {
     let major = version >> (8 * 2);
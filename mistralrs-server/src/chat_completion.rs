@@ -26,8 +26,9 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, DrySamplingParams, MirostatConfig, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -81,6 +82,7 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::ImageGenerationProgress(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -178,6 +180,23 @@ async fn parse_request(
                         message_map.insert("role".to_string(), Either::Left(message.role));
                         message_map
                             .insert("content".to_string(), Either::Left(content.to_string()));
+                        if let Some(tool_calls) = message.tool_calls {
+                            let tool_calls = tool_calls
+                                .into_iter()
+                                .map(|call| {
+                                    IndexMap::from([
+                                        ("id".to_string(), call.id),
+                                        ("name".to_string(), call.function.name),
+                                        ("arguments".to_string(), call.function.arguments),
+                                    ])
+                                })
+                                .collect();
+                            message_map.insert("tool_calls".to_string(), Either::Right(tool_calls));
+                        }
+                        if let Some(tool_call_id) = message.tool_call_id {
+                            message_map
+                                .insert("tool_call_id".to_string(), Either::Left(tool_call_id));
+                        }
                         messages.push(message_map);
                     }
                     Either::Right(image_messages) => {
@@ -297,6 +316,9 @@ async fn parse_request(
     };
 
     let is_streaming = oairequest.stream.unwrap_or(false);
+    let mirostat = oairequest.mirostat_version.map(|version| {
+        MirostatConfig::new_with_defaults(version, oairequest.mirostat_tau, oairequest.mirostat_eta)
+    });
     Ok((
         Request::Normal(NormalRequest {
             id: state.next_request_id(),
@@ -306,14 +328,31 @@ async fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
+                xtc_probability: oairequest.xtc_probability,
+                xtc_threshold: oairequest.xtc_threshold,
+                typical_p: oairequest.typical_p,
+                epsilon_cutoff: oairequest.epsilon_cutoff,
+                eta_cutoff: oairequest.eta_cutoff,
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
+                collect_all_logprobs: false,
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
                 max_len: oairequest.max_tokens,
+                min_new_tokens: oairequest.min_new_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
+                string_logits_bias: oairequest.string_logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                mirostat,
+                beam_search: None,
+                contrastive_search: None,
+                cfg_scale: oairequest.cfg_scale,
+                negative_prompt: oairequest.negative_prompt,
+                seed: oairequest.seed,
+                bad_token_ids: oairequest.bad_token_ids,
+                token_healing: oairequest.token_healing,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
@@ -321,12 +360,16 @@ async fn parse_request(
             suffix: None,
             constraint: match oairequest.grammar {
                 Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
+                Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
+                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
+            timeout_ms: None,
+            return_prompt_tokens: oairequest.return_prompt_tokens,
             logits_processors: None,
         }),
         is_streaming,
@@ -409,6 +452,7 @@ pub async fn chatcompletions(
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::ImageGenerationProgress(_) => unreachable!(),
         }
     }
 }
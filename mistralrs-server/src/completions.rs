@@ -19,11 +19,10 @@ use axum::{
     },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    CompletionResponse, Constraint, DrySamplingParams, MirostatConfig, MistralRs, NormalRequest,
+    Request, RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
-use tracing::warn;
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -75,6 +74,7 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::ImageGenerationProgress(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -157,10 +157,6 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
     let is_streaming = oairequest.stream.unwrap_or(false);
 
     let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
@@ -173,6 +169,9 @@ fn parse_request(
     } else {
         None
     };
+    let mirostat = oairequest.mirostat_version.map(|version| {
+        MirostatConfig::new_with_defaults(version, oairequest.mirostat_tau, oairequest.mirostat_eta)
+    });
     Ok((
         Request::Normal(NormalRequest {
             id: state.next_request_id(),
@@ -186,14 +185,31 @@ fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                xtc_probability: oairequest.xtc_probability,
+                xtc_threshold: oairequest.xtc_threshold,
+                typical_p: oairequest.typical_p,
+                epsilon_cutoff: oairequest.epsilon_cutoff,
+                eta_cutoff: oairequest.eta_cutoff,
+                top_n_logprobs: oairequest.logprobs.unwrap_or(1),
+                collect_all_logprobs: oairequest.logprobs.is_some(),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
                 max_len: oairequest.max_tokens,
+                min_new_tokens: oairequest.min_new_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
+                string_logits_bias: oairequest.string_logit_bias,
                 n_choices: oairequest.n_choices,
                 dry_params,
+                mirostat,
+                beam_search: None,
+                contrastive_search: None,
+                cfg_scale: oairequest.cfg_scale,
+                negative_prompt: oairequest.negative_prompt,
+                seed: oairequest.seed,
+                bad_token_ids: oairequest.bad_token_ids,
+                token_healing: oairequest.token_healing,
             },
             response: tx,
             return_logprobs: false,
@@ -201,12 +217,16 @@ fn parse_request(
             suffix: oairequest.suffix,
             constraint: match oairequest.grammar {
                 Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
+                Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
+                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
+            timeout_ms: None,
+            return_prompt_tokens: oairequest.return_prompt_tokens,
             logits_processors: None,
         }),
         is_streaming,
@@ -226,12 +246,6 @@ pub async fn completions(
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
     let (tx, mut rx) = channel(10_000);
-    if oairequest.logprobs.is_some() {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
-    }
-
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => {
@@ -296,6 +310,7 @@ pub async fn completions(
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::ImageGenerationProgress(_) => unreachable!(),
         }
     }
 }
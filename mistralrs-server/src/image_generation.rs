@@ -1,12 +1,22 @@
 use anyhow::Result;
-use std::{error::Error, sync::Arc};
-use tokio::sync::mpsc::{channel, Sender};
+use std::{
+    env,
+    error::Error,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::openai::ImageGenerationRequest;
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
 };
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, ImageGenerationResponse, MistralRs, NormalRequest,
@@ -14,7 +24,58 @@ use mistralrs_core::{
 };
 use serde::Serialize;
 
+/// Streams one event per denoising step, then the generated image as the last event.
+pub struct Streamer {
+    rx: Receiver<Response>,
+    is_done: bool,
+    state: Arc<MistralRs>,
+}
+
+impl futures::Stream for Streamer {
+    type Item = Result<Event, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_done {
+            return Poll::Ready(None);
+        }
+        match self.rx.try_recv() {
+            Ok(resp) => match resp {
+                Response::ImageGenerationProgress(progress) => {
+                    Poll::Ready(Some(Event::default().json_data(progress)))
+                }
+                Response::ImageGeneration(response) => {
+                    self.is_done = true;
+                    MistralRs::maybe_log_response(self.state.clone(), &response);
+                    Poll::Ready(Some(Event::default().json_data(response)))
+                }
+                Response::ValidationError(e) => {
+                    self.is_done = true;
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
+                Response::InternalError(e) => {
+                    self.is_done = true;
+                    MistralRs::maybe_log_error(self.state.clone(), &*e);
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
+                Response::CompletionModelError(m, _) => {
+                    self.is_done = true;
+                    let e = anyhow::Error::msg(m.to_string());
+                    MistralRs::maybe_log_error(self.state.clone(), &*e);
+                    Poll::Ready(Some(Ok(Event::default().data(m))))
+                }
+                Response::CompletionDone(_) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::Done(_) => unreachable!(),
+                Response::ModelError(_, _) => unreachable!(),
+            },
+            Err(_) => Poll::Pending,
+        }
+    }
+}
+
 pub enum ImageGenerationResponder {
+    Sse(Sse<Streamer>),
     Json(ImageGenerationResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
@@ -43,6 +104,7 @@ impl ErrorToResponse for JsonError {}
 impl IntoResponse for ImageGenerationResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
+            ImageGenerationResponder::Sse(s) => s.into_response(),
             ImageGenerationResponder::Json(s) => Json(s).into_response(),
             ImageGenerationResponder::InternalError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
@@ -58,31 +120,37 @@ fn parse_request(
     oairequest: ImageGenerationRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
-) -> Result<Request> {
+) -> Result<(Request, bool)> {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
-    Ok(Request::Normal(NormalRequest {
-        id: state.next_request_id(),
-        messages: RequestMessage::ImageGeneration {
-            prompt: oairequest.prompt,
-            format: oairequest.response_format,
-            generation_params: DiffusionGenerationParams {
-                height: oairequest.height,
-                width: oairequest.width,
+    let is_streaming = oairequest.stream.unwrap_or(false);
+    Ok((
+        Request::Normal(NormalRequest {
+            id: state.next_request_id(),
+            messages: RequestMessage::ImageGeneration {
+                prompt: oairequest.prompt,
+                format: oairequest.response_format,
+                generation_params: DiffusionGenerationParams {
+                    height: oairequest.height,
+                    width: oairequest.width,
+                },
             },
-        },
-        sampling_params: SamplingParams::deterministic(),
-        response: tx,
-        return_logprobs: false,
-        is_streaming: false,
-        suffix: None,
-        constraint: Constraint::None,
-        adapters: None,
-        tool_choice: None,
-        tools: None,
-        logits_processors: None,
-    }))
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
+            logits_processors: None,
+        }),
+        is_streaming,
+    ))
 }
 
 #[utoipa::path(
@@ -99,7 +167,7 @@ pub async fn image_generation(
 ) -> ImageGenerationResponder {
     let (tx, mut rx) = channel(10_000);
 
-    let request = match parse_request(oairequest, state.clone(), tx) {
+    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
@@ -115,6 +183,26 @@ pub async fn image_generation(
         return ImageGenerationResponder::InternalError(e.into());
     }
 
+    if is_streaming {
+        let streamer = Streamer {
+            rx,
+            is_done: false,
+            state,
+        };
+
+        return ImageGenerationResponder::Sse(
+            Sse::new(streamer).keep_alive(
+                KeepAlive::new()
+                    .interval(Duration::from_millis(
+                        env::var("KEEP_ALIVE_INTERVAL")
+                            .map(|val| val.parse::<u64>().unwrap_or(1000))
+                            .unwrap_or(1000),
+                    ))
+                    .text("keep-alive-text"),
+            ),
+        );
+    }
+
     let response = match rx.recv().await {
         Some(response) => response,
         None => {
@@ -139,6 +227,7 @@ pub async fn image_generation(
             MistralRs::maybe_log_error(state, &*e);
             ImageGenerationResponder::InternalError(e.into())
         }
+        Response::ImageGenerationProgress(_) => unreachable!(),
         Response::CompletionDone(_) => unreachable!(),
         Response::CompletionChunk(_) => unreachable!(),
         Response::Chunk(_) => unreachable!(),
@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use anyhow::Result;
+use mistralrs_core::{read_gguf_metadata, GgufMetadata, GgufValue};
+
+/// Arrays with more elements are summarized by their length.
+const MAX_ARRAY_ELEMS: usize = 8;
+/// Longer values are truncated.
+const MAX_VALUE_CHARS: usize = 80;
+
+fn format_value(value: &GgufValue) -> String {
+    match value {
+        GgufValue::Array(values) if values.len() > MAX_ARRAY_ELEMS => {
+            format!("[{} values]", values.len())
+        }
+        GgufValue::Array(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(format_value)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        GgufValue::String(x) => x.escape_debug().to_string(),
+        GgufValue::Bool(x) => x.to_string(),
+        GgufValue::F32(x) => x.to_string(),
+        GgufValue::F64(x) => x.to_string(),
+        GgufValue::I8(x) => x.to_string(),
+        GgufValue::I16(x) => x.to_string(),
+        GgufValue::I32(x) => x.to_string(),
+        GgufValue::I64(x) => x.to_string(),
+        GgufValue::U8(x) => x.to_string(),
+        GgufValue::U16(x) => x.to_string(),
+        GgufValue::U32(x) => x.to_string(),
+        GgufValue::U64(x) => x.to_string(),
+    }
+}
+
+fn truncate(value: String) -> String {
+    if value.chars().count() <= MAX_VALUE_CHARS {
+        value
+    } else {
+        let truncated = value.chars().take(MAX_VALUE_CHARS - 3).collect::<String>();
+        format!("{truncated}...")
+    }
+}
+
+/// Print the metadata of the GGUF file at `path` as a table, sorted by key.
+pub fn inspect_gguf(path: &Path) -> Result<()> {
+    let metadata = read_gguf_metadata(path)?;
+
+    let unknown = || "unknown".to_string();
+    println!("File:           {}", path.display());
+    println!(
+        "Architecture:   {}",
+        metadata
+            .architecture()
+            .map(str::to_string)
+            .unwrap_or_else(unknown)
+    );
+    println!(
+        "Context length: {}",
+        metadata
+            .context_length()
+            .map(|x| x.to_string())
+            .unwrap_or_else(unknown)
+    );
+    println!(
+        "Vocab size:     {}",
+        metadata
+            .vocab_size()
+            .map(|x| x.to_string())
+            .unwrap_or_else(unknown)
+    );
+    println!();
+
+    let mut keys = metadata.keys().collect::<Vec<_>>();
+    keys.sort();
+    let key_width = keys.iter().map(|k| k.len()).max().unwrap_or(0).max(3);
+    println!("{:key_width$} | Value", "Key");
+    println!(
+        "{}-+-{}",
+        "-".repeat(key_width),
+        "-".repeat(MAX_VALUE_CHARS)
+    );
+    for key in keys {
+        println!(
+            "{key:key_width$} | {}",
+            truncate(format_value(&metadata[key]))
+        );
+    }
+    Ok(())
+}
@@ -85,14 +85,31 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        xtc_probability: None,
+        xtc_threshold: None,
+        typical_p: None,
+        epsilon_cutoff: None,
+        eta_cutoff: None,
         top_n_logprobs: 0,
+        collect_all_logprobs: false,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        no_repeat_ngram_size: None,
         max_len: Some(4096),
+        min_new_tokens: None,
         stop_toks: None,
         logits_bias: None,
+        string_logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        beam_search: None,
+        contrastive_search: None,
+        cfg_scale: None,
+        negative_prompt: None,
+        seed: None,
+        bad_token_ids: None,
+        token_healing: false,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -173,6 +190,8 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             adapters: None,
             tool_choice: None,
             tools: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
             logits_processors: None,
         });
         sender.send(req).await.unwrap();
@@ -213,6 +232,7 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::ImageGenerationProgress(_) => unreachable!(),
             }
         }
         if throughput {
@@ -239,14 +259,31 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_k: Some(32),
         top_p: Some(0.1),
         min_p: Some(0.05),
+        xtc_probability: None,
+        xtc_threshold: None,
+        typical_p: None,
+        epsilon_cutoff: None,
+        eta_cutoff: None,
         top_n_logprobs: 0,
+        collect_all_logprobs: false,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        no_repeat_ngram_size: None,
         max_len: Some(4096),
+        min_new_tokens: None,
         stop_toks: None,
         logits_bias: None,
+        string_logits_bias: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        mirostat: None,
+        beam_search: None,
+        contrastive_search: None,
+        cfg_scale: None,
+        negative_prompt: None,
+        seed: None,
+        bad_token_ids: None,
+        token_healing: false,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -355,6 +392,8 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             adapters: None,
             tool_choice: None,
             tools: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
             logits_processors: None,
         });
         sender.send(req).await.unwrap();
@@ -395,6 +434,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::ImageGenerationProgress(_) => unreachable!(),
             }
         }
         if throughput {
@@ -476,6 +516,8 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
             adapters: None,
             tool_choice: None,
             tools: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
             logits_processors: None,
         });
         sender.send(req).await.unwrap();
@@ -10,8 +10,8 @@ use clap::Parser;
 use mistralrs_core::{
     get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
     parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType,
-    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    KVCacheDtype, Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder,
+    ModelSelected, PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
 };
 use openai::{
     ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
@@ -23,6 +23,7 @@ use std::{num::NonZeroUsize, sync::Arc};
 mod chat_completion;
 mod completions;
 mod image_generation;
+mod inspect;
 mod interactive_mode;
 mod openai;
 mod util;
@@ -110,6 +111,16 @@ struct Args {
     #[arg(short, long, value_parser, value_delimiter = ';')]
     num_device_layers: Option<Vec<String>>,
 
+    /// Keep at most this many repeating layers on the GPU, paging the others in from the CPU when
+    /// they are run. Only supported for unquantized plain models.
+    #[arg(long, conflicts_with = "num_device_layers")]
+    paged_gpu_layers: Option<usize>,
+
+    /// Split every repeating layer across the CUDA devices with these ordinals, separated by `;`.
+    /// The first ordinal must be the model device. Only supported for unquantized plain Llama models.
+    #[arg(long, value_delimiter = ';', conflicts_with_all = ["num_device_layers", "paged_gpu_layers"])]
+    tensor_parallel: Option<Vec<usize>>,
+
     /// In-situ quantization to apply. You may specify one of the GGML data type (except F32 or F16): formatted like this: `Q4_0` or `Q4K`.
     #[arg(long = "isq", value_parser = parse_isq_value)]
     in_situ_quant: Option<IsqType>,
@@ -149,6 +160,10 @@ struct Args {
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
+
+    /// Dtype the KV cache is stored in: `full`, `int8` or `fp8`. Quantized KV caches reduce memory usage, they are not used with PagedAttention.
+    #[arg(long = "kv-cache-dtype", default_value_t = KVCacheDtype::FullPrecision)]
+    kv_cache_dtype: KVCacheDtype,
 }
 
 #[utoipa::path(
@@ -158,15 +173,7 @@ struct Args {
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
 async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
-    Json(ModelObjects {
-        object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
-    })
+    Json(ModelObjects::new(state.get_id(), state.get_creation_time()))
 }
 
 #[utoipa::path(
@@ -273,6 +280,10 @@ async fn main() -> Result<()> {
     let mut args = Args::parse();
     initialize_logging();
 
+    if let ModelSelected::Inspect { path } = &args.model {
+        return inspect::inspect_gguf(path);
+    }
+
     #[cfg(not(feature = "flash-attn"))]
     let use_flash_attn = false;
     #[cfg(feature = "flash-attn")]
@@ -298,6 +309,7 @@ async fn main() -> Result<()> {
         .with_chat_template(args.chat_template)
         .with_use_flash_attn(use_flash_attn)
         .with_prompt_batchsize(prompt_batchsize)
+        .with_kv_cache_dtype(args.kv_cache_dtype)
         .build()?;
 
     #[cfg(feature = "metal")]
@@ -326,7 +338,11 @@ async fn main() -> Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let mapper = if let Some(ordinals) = args.tensor_parallel {
+        DeviceMapMetadata::tensor_parallel(ordinals)
+    } else if let Some(max_gpu_layers) = args.paged_gpu_layers {
+        DeviceMapMetadata::paged(max_gpu_layers)
+    } else if let Some(device_layers) = args.num_device_layers {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
             DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
@@ -482,3 +498,49 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, sync::Arc};
+
+    use mistralrs_core::{DefaultSchedulerMethod, MistralRsBuilder, MockPipeline, SchedulerConfig};
+    use serde_json::{json, Value};
+
+    use super::get_router;
+
+    #[tokio::test]
+    async fn test_models_route() {
+        let pipeline = MockPipeline::new(16, Arc::new(|context: &[u32]| context[0]));
+        let mistralrs = MistralRsBuilder::new(
+            Arc::new(tokio::sync::Mutex::new(pipeline)),
+            SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(NonZeroUsize::new(1).unwrap()),
+            },
+        )
+        .build();
+        let created = mistralrs.get_creation_time();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, get_router(mistralrs)).await });
+
+        let response = reqwest::get(format!("http://{addr}/v1/models"))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let models: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        // The list models response of the OpenAI API, for the served pipeline.
+        assert_eq!(
+            models,
+            json!({
+                "object": "list",
+                "data": [{
+                    "id": "mock",
+                    "object": "model",
+                    "created": created,
+                    "owned_by": "mistralrs",
+                }],
+            })
+        );
+    }
+}
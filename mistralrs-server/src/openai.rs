@@ -29,11 +29,28 @@ impl Deref for MessageContent {
     }
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct FunctionCalled {
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ToolCall {
+    pub id: String,
+    pub function: FunctionCalled,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct Message {
     pub content: MessageContent,
     pub role: String,
     pub name: Option<String>,
+    /// Tool calls made by the assistant
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The tool call a `tool` message is the result of
+    pub tool_call_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -74,6 +91,10 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "gbnf")]
+    Gbnf(String),
+    #[serde(rename = "json_schema")]
+    JsonSchema(serde_json::Value),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -86,6 +107,8 @@ pub struct ChatCompletionRequest {
     pub model: String,
     #[schema(example = json!(Option::None::<HashMap<u32, f32>>))]
     pub logit_bias: Option<HashMap<u32, f32>>,
+    #[schema(example = json!(Option::None::<HashMap<String, f32>>))]
+    pub string_logit_bias: Option<HashMap<String, f32>>,
     #[serde(default = "default_false")]
     #[schema(example = false)]
     pub logprobs: bool,
@@ -101,6 +124,24 @@ pub struct ChatCompletionRequest {
     pub presence_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
     pub frequency_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub min_new_tokens: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub cfg_scale: Option<f64>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub bad_token_ids: Option<Vec<u32>>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub token_healing: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_prompt_tokens: bool,
     #[serde(rename = "stop")]
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
@@ -124,6 +165,22 @@ pub struct ChatCompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub xtc_probability: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub xtc_threshold: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub epsilon_cutoff: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub eta_cutoff: Option<f32>,
+    #[schema(example = json!(Option::None::<u8>))]
+    pub mirostat_version: Option<u8>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub mirostat_tau: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub mirostat_eta: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -148,6 +205,22 @@ pub struct ModelObjects {
     pub data: Vec<ModelObject>,
 }
 
+impl ModelObjects {
+    /// The list of served models, in the format of OpenAI's `/v1/models`, for the model `id`
+    /// created at the Unix timestamp `created`.
+    pub fn new(id: String, created: u64) -> Self {
+        Self {
+            object: "list",
+            data: vec![ModelObject {
+                id,
+                object: "model",
+                created,
+                owned_by: "mistralrs",
+            }],
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct CompletionRequest {
     #[schema(example = "mistral")]
@@ -166,8 +239,28 @@ pub struct CompletionRequest {
     pub presence_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
     pub frequency_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub min_new_tokens: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub cfg_scale: Option<f64>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub negative_prompt: Option<String>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub bad_token_ids: Option<Vec<u32>>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub token_healing: bool,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_prompt_tokens: bool,
     #[schema(example = json!(Option::None::<HashMap<u32, f32>>))]
     pub logit_bias: Option<HashMap<u32, f32>>,
+    #[schema(example = json!(Option::None::<HashMap<String, f32>>))]
+    pub string_logit_bias: Option<HashMap<String, f32>>,
     #[schema(example = json!(Option::None::<usize>))]
     pub logprobs: Option<usize>,
     #[schema(example = 16)]
@@ -202,6 +295,22 @@ pub struct CompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub xtc_probability: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub xtc_threshold: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub epsilon_cutoff: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub eta_cutoff: Option<f32>,
+    #[schema(example = json!(Option::None::<u8>))]
+    pub mirostat_version: Option<u8>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub mirostat_tau: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub mirostat_eta: Option<f64>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -231,4 +340,47 @@ pub struct ImageGenerationRequest {
     #[serde(default = "default_1280usize")]
     #[schema(example = 1280)]
     pub width: usize,
+    /// Stream a progress event after each denoising step, followed by the generated image.
+    #[schema(example = false)]
+    pub stream: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{CompletionRequest, ModelObjects};
+
+    #[test]
+    fn test_model_objects() {
+        let models =
+            serde_json::to_value(ModelObjects::new("mistral".to_string(), 1700000000)).unwrap();
+        // The list models response of the OpenAI API.
+        assert_eq!(
+            models,
+            json!({
+                "object": "list",
+                "data": [{
+                    "id": "mistral",
+                    "object": "model",
+                    "created": 1700000000,
+                    "owned_by": "mistralrs",
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_mirostat_keys() {
+        let request: CompletionRequest = serde_json::from_value(json!({
+            "model": "mistral",
+            "prompt": "Hello",
+            "mirostat_version": 2,
+            "mirostat_tau": 3.0,
+        }))
+        .unwrap();
+        assert_eq!(request.mirostat_version, Some(2));
+        assert_eq!(request.mirostat_tau, Some(3.0));
+        assert_eq!(request.mirostat_eta, None);
+    }
 }
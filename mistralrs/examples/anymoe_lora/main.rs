@@ -22,6 +22,11 @@ async fn main() -> Result<()> {
             gate_model_id: None, // Set this to Some("path/to/model/id") for the pretrained gating model id
             training: true,
             loss_csv_path: None,
+            checkpoint_dir: None,
+            resume_from_checkpoint: false,
+            gradient_checkpointing: false,
+            validation_split: 0.0,
+            early_stopping_patience: None,
         },
         "model.layers",
         "mlp",
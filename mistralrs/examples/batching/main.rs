@@ -25,12 +25,9 @@ async fn main() -> Result<()> {
             "Hello! How are you? Please write generic binary search function in Rust.",
         );
 
-    let mut handles = Vec::new();
-    for _ in 0..N_REQUESTS {
-        handles.push(model.send_chat_request(messages.clone()));
-    }
-    let responses = futures::future::join_all(handles)
-        .await
+    let responses = model
+        .send_chat_requests_batch(vec![messages; N_REQUESTS])
+        .await?
         .into_iter()
         .collect::<Result<Vec<_>>>()?;
 
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use mistralrs::{EmbeddingModelBuilder, HiddenStateLayer, IsqType, TextModelBuilder};
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = EmbeddingModelBuilder::from_text_model_builder(
+        TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+            .with_isq(IsqType::Q8_0)
+            .with_logging(),
+    )
+    .with_hidden_state_layer(HiddenStateLayer::BeforeHead)
+    .build()
+    .await?;
+
+    let sentences = [
+        "The cat sat on the mat.",
+        "A kitten was sitting on the rug.",
+        "Stock markets fell sharply on Monday.",
+    ];
+    let embeddings = model.embed_batch(sentences.to_vec()).await?;
+
+    for i in 0..sentences.len() {
+        for j in i + 1..sentences.len() {
+            println!(
+                "{:.4}: `{}` and `{}`",
+                cosine_similarity(&embeddings[i], &embeddings[j]),
+                sentences[i],
+                sentences[j]
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::fs::File;
+
+use anyhow::Result;
+use mistralrs::{LoraModelBuilder, RequestBuilder, TextMessageRole, TextModelBuilder};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model =
+        LoraModelBuilder::from_text_model_builder(
+            TextModelBuilder::new("HuggingFaceH4/zephyr-7b-beta").with_logging(),
+            "lamm-mit/x-lora",
+            serde_json::from_reader(File::open("my-ordering-file.json").unwrap_or_else(|_| {
+                panic!("Could not load ordering file at my-ordering-file.json")
+            }))?,
+        )
+        .build()
+        .await?;
+
+    let messages = || {
+        RequestBuilder::new()
+            .set_deterministic_sampler()
+            .set_sampler_max_len(64)
+            .add_message(
+                TextMessageRole::User,
+                "Hello! How are you? Please write generic binary search function in Rust.",
+            )
+    };
+
+    // First, run with the adapter applied on the fly
+    let unmerged = model
+        .send_chat_request(messages().set_adapters(vec!["adapter_1".to_string()]))
+        .await?;
+    let unmerged = unmerged.choices[0].message.content.clone().unwrap();
+
+    // Then merge the adapter into the base weights, it is used for all subsequent requests
+    model.merge_adapters(vec!["adapter_1"])?;
+
+    let merged = model.send_chat_request(messages()).await?;
+    let merged = merged.choices[0].message.content.clone().unwrap();
+
+    println!("{merged}");
+    if merged == unmerged {
+        println!("Merged output matches the unmerged output.");
+    } else {
+        println!("Merged output differs from the unmerged output:\n{unmerged}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Compare the throughput of a batch mixing LoRA adapters with a batch using a single adapter.
+//! Half of the mixed batch uses `adapter_1` and half `adapter_2`, so each LoRA layer runs both
+//! adapters and masks their outputs per sequence. The single adapter batch activates `adapter_1`
+//! for every sequence directly. Both batches run the same prompts for the same number of tokens.
+
+use std::{fs::File, time::Instant};
+
+use anyhow::Result;
+use mistralrs::{LoraModelBuilder, RequestBuilder, TextMessageRole, TextModelBuilder};
+
+const N_REQUESTS: usize = 8;
+const MAX_LEN: usize = 128;
+
+const PROMPTS: &[&str] = &[
+    "Write a generic binary search function in Rust.",
+    "Explain the difference between a process and a thread.",
+    "Summarize the plot of Romeo and Juliet in one paragraph.",
+    "List ten uses of a paperclip.",
+];
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // The ordering file must preload `adapter_1` and `adapter_2`.
+    let model =
+        LoraModelBuilder::from_text_model_builder(
+            TextModelBuilder::new("HuggingFaceH4/zephyr-7b-beta"),
+            "lamm-mit/x-lora",
+            serde_json::from_reader(File::open("my-ordering-file.json").unwrap_or_else(|_| {
+                panic!("Could not load ordering file at my-ordering-file.json")
+            }))?,
+        )
+        .build()
+        .await?;
+
+    for (name, adapters) in [
+        ("single adapter", ["adapter_1", "adapter_1"]),
+        ("mixed adapters", ["adapter_1", "adapter_2"]),
+    ] {
+        let requests = (0..N_REQUESTS)
+            .map(|i| {
+                RequestBuilder::new()
+                    .set_adapters(vec![adapters[i % 2].to_string()])
+                    .add_message(TextMessageRole::User, PROMPTS[i % PROMPTS.len()])
+                    .set_deterministic_sampler()
+                    .set_sampler_max_len(MAX_LEN)
+            })
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let responses = model
+            .send_chat_requests_batch(requests)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let elapsed = start.elapsed().as_secs_f32();
+
+        let completion_tokens = responses
+            .iter()
+            .map(|response| response.usage.completion_tokens)
+            .sum::<usize>();
+        println!(
+            "{name}: {completion_tokens} tokens in {elapsed:.2}s, {:.2} tok/s",
+            completion_tokens as f32 / elapsed,
+        );
+    }
+
+    Ok(())
+}
@@ -28,8 +28,11 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
             organization: Default::default(),
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
             write_uqff: None,
             from_uqff: None,
         },
@@ -49,6 +52,11 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             gate_model_id: None, // Set this to Some("path/to/model/id") for the pretrained gating model id
             training: true,
             loss_csv_path: None,
+            checkpoint_dir: None,
+            resume_from_checkpoint: false,
+            gradient_checkpointing: false,
+            validation_split: 0.0,
+            early_stopping_patience: None,
         },
         prefix: "model.layers".to_string(),
         mlp: "mlp".to_string(),
@@ -96,6 +104,8 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
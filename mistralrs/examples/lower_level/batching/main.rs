@@ -20,6 +20,7 @@ async fn setup() -> anyhow::Result<Arc<MistralRs>> {
         vec!["mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string()],
         GGUFSpecificConfig {
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
         },
     )
@@ -84,6 +85,8 @@ async fn bench_mistralrs(n_requests: usize) -> anyhow::Result<()> {
             adapters: None,
             tools: None,
             tool_choice: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
             logits_processors: None,
         });
         mistralrs.get_sender()?.send(request).await?;
@@ -41,8 +41,11 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
             organization: Default::default(),
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
             write_uqff: None,
             from_uqff: None,
         },
@@ -95,6 +98,8 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: Some(vec![
             Arc::new(move |logits: &Tensor, _context: &[u32]| logits * random_value),
             Arc::new(ThresholdLogitsProcessor { threshold }),
@@ -33,6 +33,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         vec!["mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string()],
         GGUFSpecificConfig {
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
         },
     )
@@ -77,6 +78,8 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
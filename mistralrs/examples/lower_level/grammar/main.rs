@@ -27,8 +27,11 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
             organization: Default::default(),
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
             write_uqff: None,
             from_uqff: None,
         },
@@ -77,6 +80,8 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tool_choice: None,
         tools: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
@@ -28,7 +28,10 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         VisionSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
             write_uqff: None,
             from_uqff: None,
         },
@@ -97,6 +100,8 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
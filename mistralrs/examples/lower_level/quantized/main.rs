@@ -31,6 +31,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         vec!["mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string()],
         GGUFSpecificConfig {
             prompt_batchsize: None,
+            kv_cache_dtype: Default::default(),
             topology: None,
         },
     )
@@ -75,6 +76,8 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tools: None,
         tool_choice: None,
+        timeout_ms: None,
+        return_prompt_tokens: false,
         logits_processors: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
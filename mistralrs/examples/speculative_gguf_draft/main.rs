@@ -0,0 +1,42 @@
+use anyhow::Result;
+use mistralrs::{
+    GgufModelBuilder, IsqType, SpeculativeModelBuilder, TextMessageRole, TextMessages,
+    TextModelBuilder, VocabMismatchPolicy,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let target = TextModelBuilder::new("meta-llama/Meta-Llama-3.1-8B-Instruct")
+        .with_isq(IsqType::Q8_0)
+        .with_logging();
+    let draft = GgufModelBuilder::new(
+        "bartowski/Llama-3.2-1B-Instruct-GGUF",
+        vec!["Llama-3.2-1B-Instruct-Q4_K_M.gguf"],
+    )
+    .with_tok_model_id("meta-llama/Llama-3.2-1B-Instruct");
+
+    let model = SpeculativeModelBuilder::from_text_model_builder(target, draft, 4)
+        .with_vocab_mismatch_policy(VocabMismatchPolicy::IgnoreSpecial)
+        .build()
+        .await?;
+
+    let messages = TextMessages::new()
+        .add_message(
+            TextMessageRole::System,
+            "You are an AI agent with a specialty in programming.",
+        )
+        .add_message(
+            TextMessageRole::User,
+            "Hello! How are you? Please write generic binary search function in Rust.",
+        );
+
+    let response = model.send_chat_request(messages).await?;
+
+    println!("{}", response.choices[0].message.content.as_ref().unwrap());
+    dbg!(
+        response.usage.avg_prompt_tok_per_sec,
+        response.usage.avg_compl_tok_per_sec
+    );
+
+    Ok(())
+}
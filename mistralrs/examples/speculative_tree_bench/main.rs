@@ -0,0 +1,62 @@
+//! Compare tree speculative decoding with a single draft chain of the same total number of draft
+//! tokens: `BRANCHES` branches of `GAMMA` tokens against one chain of `BRANCHES * GAMMA` tokens.
+//! Each configuration generates the same greedy completions, so only the speed differs.
+
+use std::time::Instant;
+
+use anyhow::Result;
+use mistralrs::{
+    GgufModelBuilder, IsqType, RequestBuilder, SpeculativeModelBuilder, TextMessageRole,
+    TextModelBuilder, VocabMismatchPolicy,
+};
+
+const GAMMA: usize = 4;
+const BRANCHES: usize = 3;
+const MAX_LEN: usize = 256;
+
+const PROMPTS: &[&str] = &[
+    "Write a generic binary search function in Rust.",
+    "Explain the difference between a process and a thread.",
+    "Summarize the plot of Romeo and Juliet in one paragraph.",
+    "List ten uses of a paperclip.",
+];
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    for (branches, gamma) in [(1, BRANCHES * GAMMA), (BRANCHES, GAMMA)] {
+        let target =
+            TextModelBuilder::new("meta-llama/Meta-Llama-3.1-8B-Instruct").with_isq(IsqType::Q8_0);
+        let draft = GgufModelBuilder::new(
+            "bartowski/Llama-3.2-1B-Instruct-GGUF",
+            vec!["Llama-3.2-1B-Instruct-Q4_K_M.gguf"],
+        )
+        .with_tok_model_id("meta-llama/Llama-3.2-1B-Instruct");
+        let model = SpeculativeModelBuilder::from_text_model_builder(target, draft, gamma)
+            .with_vocab_mismatch_policy(VocabMismatchPolicy::IgnoreSpecial)
+            .with_draft_branches(branches)
+            .build()
+            .await?;
+
+        let mut completion_tokens = 0;
+        let mut tokens_per_step = Vec::new();
+        let start = Instant::now();
+        for prompt in PROMPTS {
+            let request = RequestBuilder::new()
+                .add_message(TextMessageRole::User, prompt)
+                .set_deterministic_sampler()
+                .set_sampler_max_len(MAX_LEN);
+            let response = model.send_chat_request(request).await?;
+            completion_tokens += response.usage.completion_tokens;
+            tokens_per_step.extend(response.usage.speculative_tokens_per_draft_call);
+        }
+        let elapsed = start.elapsed().as_secs_f32();
+
+        println!(
+            "draft_branches = {branches}, gamma = {gamma}: {completion_tokens} tokens in {elapsed:.2}s, {:.2} tok/s, {:.2} accepted draft tokens per step",
+            completion_tokens as f32 / elapsed,
+            tokens_per_step.iter().sum::<f32>() / tokens_per_step.len().max(1) as f32,
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::io::Write;
+
+use anyhow::Result;
+use futures::StreamExt;
+use mistralrs::{
+    IsqType, PagedAttentionMetaBuilder, StreamingTextModel, TextMessageRole, TextMessages,
+    TextModelBuilder,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+        .with_isq(IsqType::Q8_0)
+        .with_logging()
+        .with_paged_attn(|| PagedAttentionMetaBuilder::default().build())?
+        .build()
+        .await?;
+
+    let messages = TextMessages::new()
+        .add_message(
+            TextMessageRole::System,
+            "You are an AI agent with a specialty in programming.",
+        )
+        .add_message(
+            TextMessageRole::User,
+            "Hello! How are you? Please write generic binary search function in Rust.",
+        );
+
+    let mut stream = std::pin::pin!(model.stream_chat(messages));
+    while let Some(delta) = stream.next().await {
+        print!("{}", delta?);
+        std::io::stdout().flush()?;
+    }
+    println!();
+
+    // Next example: Stream the raw chunks. Dropping the stream after a few chunks cancels the request.
+    let messages = TextMessages::new().add_message(
+        TextMessageRole::User,
+        "Please write a long story about a crab crossing the road.",
+    );
+
+    let mut stream = std::pin::pin!(model.send_chat_request_streaming(messages).await?);
+    for _ in 0..16 {
+        let Some(chunk) = stream.next().await else {
+            break;
+        };
+        print!("{}", chunk?.choices[0].delta.content);
+    }
+    println!();
+
+    Ok(())
+}
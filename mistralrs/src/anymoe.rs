@@ -43,8 +43,11 @@ impl AnyMoeModelBuilder {
         let config = NormalSpecificConfig {
             use_flash_attn: self.base.use_flash_attn,
             prompt_batchsize: self.base.prompt_batchsize,
+            kv_cache_dtype: self.base.kv_cache_dtype,
             topology: self.base.topology,
             organization: self.base.organization,
+            isq_exclude: self.base.isq_exclude,
+            isq_layer_types: self.base.isq_layer_types,
             write_uqff: self.base.write_uqff,
             from_uqff: self.base.from_uqff,
         };
@@ -0,0 +1,117 @@
+use mistralrs_core::*;
+
+use crate::{best_device, Model, TextModelBuilder};
+
+/// Wrapper of [`TextModelBuilder`] for contrastive decoding with a weaker amateur model.
+///
+/// The model of the [`TextModelBuilder`] is the expert. The amateur model is loaded with the same
+/// settings, excluding the topology and UQFF files, and must share the expert's tokenizer.
+pub struct ContrastiveModelBuilder {
+    text_model: TextModelBuilder,
+    amateur_model_id: String,
+    amateur_loader_type: Option<NormalLoaderType>,
+    alpha: f64,
+}
+
+impl ContrastiveModelBuilder {
+    /// `alpha` weighs the amateur model's log probabilities which are subtracted from the expert's.
+    pub fn from_text_model_builder(
+        text_model: TextModelBuilder,
+        amateur_model_id: impl ToString,
+        alpha: f64,
+    ) -> Self {
+        Self {
+            text_model,
+            amateur_model_id: amateur_model_id.to_string(),
+            amateur_loader_type: None,
+            alpha,
+        }
+    }
+
+    /// Manually set the model loader type of the amateur model. Otherwise, it will attempt to automatically
+    /// determine the loader type.
+    pub fn with_amateur_loader_type(mut self, loader_type: NormalLoaderType) -> Self {
+        self.amateur_loader_type = Some(loader_type);
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
+        let config = NormalSpecificConfig {
+            use_flash_attn: self.text_model.use_flash_attn,
+            prompt_batchsize: self.text_model.prompt_batchsize,
+            kv_cache_dtype: self.text_model.kv_cache_dtype,
+            topology: self.text_model.topology,
+            organization: self.text_model.organization,
+            isq_exclude: self.text_model.isq_exclude,
+            isq_layer_types: self.text_model.isq_layer_types,
+            write_uqff: self.text_model.write_uqff,
+            from_uqff: self.text_model.from_uqff,
+        };
+        let amateur_config = NormalSpecificConfig {
+            use_flash_attn: self.text_model.use_flash_attn,
+            prompt_batchsize: self.text_model.prompt_batchsize,
+            kv_cache_dtype: self.text_model.kv_cache_dtype,
+            topology: None,
+            organization: self.text_model.organization,
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
+            write_uqff: None,
+            from_uqff: None,
+        };
+
+        if self.text_model.with_logging {
+            initialize_logging();
+        }
+
+        let expert = NormalLoaderBuilder::new(
+            config,
+            self.text_model.chat_template.clone(),
+            self.text_model.tokenizer_json.clone(),
+            Some(self.text_model.model_id),
+        )
+        .with_no_kv_cache(self.text_model.no_kv_cache)
+        .build(self.text_model.loader_type)?;
+        let amateur = NormalLoaderBuilder::new(
+            amateur_config,
+            self.text_model.chat_template,
+            self.text_model.tokenizer_json,
+            Some(self.amateur_model_id),
+        )
+        .with_no_kv_cache(self.text_model.no_kv_cache)
+        .build(self.amateur_loader_type)?;
+
+        let loader = ContrastiveLoader {
+            expert,
+            config: ContrastiveConfig {
+                amateur,
+                alpha: self.alpha,
+            },
+        };
+
+        // Load, into a Pipeline
+        let pipeline = loader.load_model_from_hf(
+            self.text_model.hf_revision,
+            self.text_model.token_source,
+            &self.text_model.dtype,
+            &best_device(self.text_model.force_cpu)?,
+            !self.text_model.with_logging,
+            self.text_model
+                .device_mapping
+                .unwrap_or(DeviceMapMetadata::dummy()),
+            self.text_model.isq,
+            None,
+        )?;
+
+        // PagedAttention is not supported by contrastive decoding.
+        let scheduler_method = SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(self.text_model.max_num_seqs.try_into()?),
+        };
+
+        let runner = MistralRsBuilder::new(pipeline, scheduler_method)
+            .with_no_kv_cache(self.text_model.no_kv_cache)
+            .with_gemm_full_precision_f16(true)
+            .with_no_prefix_cache(true);
+
+        Ok(Model::new(runner.build()))
+    }
+}
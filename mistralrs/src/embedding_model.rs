@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use candle_core::Tensor;
+use mistralrs_core::*;
+use tokio::sync::Mutex;
+
+use crate::{best_device, TextModelBuilder};
+
+/// Wrapper of [`TextModelBuilder`] for computing embeddings from the hidden states of a model.
+///
+/// The model is loaded with the settings of the [`TextModelBuilder`], except that PagedAttention
+/// is not used. The embedding of a text pools the hidden states of its tokens, by default with their
+/// mean.
+pub struct EmbeddingModelBuilder {
+    text_model: TextModelBuilder,
+    layer: HiddenStateLayer,
+    pooling: EmbeddingPooling,
+}
+
+impl EmbeddingModelBuilder {
+    pub fn from_text_model_builder(text_model: TextModelBuilder) -> Self {
+        Self {
+            text_model,
+            layer: HiddenStateLayer::BeforeHead,
+            pooling: EmbeddingPooling::Mean,
+        }
+    }
+
+    /// Set which hidden states are pooled into the embeddings. Defaults to
+    /// [`HiddenStateLayer::BeforeHead`].
+    pub fn with_hidden_state_layer(mut self, layer: HiddenStateLayer) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    /// Set how the hidden states of a text are pooled into its embedding. Defaults to
+    /// [`EmbeddingPooling::Mean`].
+    pub fn with_pooling(mut self, pooling: EmbeddingPooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<EmbeddingModel> {
+        if let Some(rope_scaling) = &self.text_model.rope_scaling {
+            if rope_scaling.factor() <= 1. {
+                anyhow::bail!(
+                    "RoPE scaling factor must be greater than 1, got {}.",
+                    rope_scaling.factor()
+                );
+            }
+        }
+
+        let config = NormalSpecificConfig {
+            use_flash_attn: self.text_model.use_flash_attn,
+            prompt_batchsize: self.text_model.prompt_batchsize,
+            kv_cache_dtype: self.text_model.kv_cache_dtype,
+            topology: self.text_model.topology,
+            organization: self.text_model.organization,
+            isq_exclude: self.text_model.isq_exclude,
+            isq_layer_types: self.text_model.isq_layer_types,
+            write_uqff: self.text_model.write_uqff,
+            from_uqff: self.text_model.from_uqff,
+        };
+
+        if self.text_model.with_logging {
+            initialize_logging();
+        }
+
+        let mut loader = NormalLoaderBuilder::new(
+            config,
+            self.text_model.chat_template,
+            self.text_model.tokenizer_json,
+            Some(self.text_model.model_id),
+        )
+        .with_no_kv_cache(self.text_model.no_kv_cache);
+        if let Some(rope_scaling) = self.text_model.rope_scaling {
+            loader = loader.with_rope_scaling(rope_scaling);
+        }
+        let loader = loader.build(self.text_model.loader_type)?;
+
+        // Load, into a Pipeline
+        let pipeline = loader.load_model_from_hf(
+            self.text_model.hf_revision,
+            self.text_model.token_source,
+            &self.text_model.dtype,
+            &best_device(self.text_model.force_cpu)?,
+            !self.text_model.with_logging,
+            self.text_model
+                .device_mapping
+                .unwrap_or(DeviceMapMetadata::dummy()),
+            self.text_model.isq,
+            None,
+        )?;
+
+        Ok(EmbeddingModel {
+            pipeline,
+            layer: self.layer,
+            pooling: self.pooling,
+        })
+    }
+}
+
+/// A model which computes embeddings. Requests are run one at a time, without a scheduler.
+pub struct EmbeddingModel {
+    pipeline: Arc<Mutex<dyn Pipeline + Send + Sync>>,
+    layer: HiddenStateLayer,
+    pooling: EmbeddingPooling,
+}
+
+impl EmbeddingModel {
+    /// The hidden states of each token of `tokens`, of shape `(tokens.len(), hidden_size)`.
+    pub async fn get_hidden_states(&self, tokens: &[u32]) -> anyhow::Result<Tensor> {
+        self.pipeline
+            .lock()
+            .await
+            .get_hidden_states(tokens, self.layer)
+    }
+
+    /// Embed `text`, which is tokenized including any special tokens the tokenizer adds.
+    pub async fn embed(&self, text: impl ToString) -> anyhow::Result<Vec<f32>> {
+        let mut embeddings = self.embed_batch(vec![text]).await?;
+        Ok(embeddings.remove(0))
+    }
+
+    /// Embed each of `texts`, returning one embedding of `hidden_size` values per text.
+    pub async fn embed_batch(&self, texts: Vec<impl ToString>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let pipeline = self.pipeline.lock().await;
+        let Some(tokenizer) = pipeline.tokenizer() else {
+            anyhow::bail!("The model has no tokenizer.");
+        };
+        let inputs = texts
+            .into_iter()
+            .map(|text| {
+                Ok(tokenizer
+                    .encode(text.to_string(), true)
+                    .map_err(anyhow::Error::msg)?
+                    .get_ids()
+                    .to_vec())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        pipeline.get_embeddings(&inputs, self.layer, self.pooling)
+    }
+}
@@ -1,5 +1,9 @@
 use mistralrs_core::*;
-use std::num::NonZeroUsize;
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
 use crate::{best_device, Model};
 
@@ -17,8 +21,10 @@ pub struct GgufModelBuilder {
 
     // Model running
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
+    pub(crate) kv_cache_dtype: KVCacheDtype,
     pub(crate) force_cpu: bool,
     pub(crate) topology: Option<Topology>,
+    pub(crate) rope_scaling: Option<RopeScalingConfig>,
 
     // Other things
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
@@ -38,6 +44,7 @@ impl GgufModelBuilder {
             model_id: model_id.to_string(),
             files: files.into_iter().map(|f| f.to_string()).collect::<Vec<_>>(),
             prompt_batchsize: None,
+            kv_cache_dtype: KVCacheDtype::FullPrecision,
             chat_template: None,
             tokenizer_json: None,
             force_cpu: false,
@@ -49,11 +56,42 @@ impl GgufModelBuilder {
             prefix_cache_n: Some(16),
             with_logging: false,
             topology: None,
+            rope_scaling: None,
             tok_model_id: None,
             device_mapping: None,
         }
     }
 
+    /// Load the model from local GGUF shards, such as `model-00001-of-00003.gguf`, instead of the
+    /// files given to [`GgufModelBuilder::new`]. Every shard is kept open while loading, and each
+    /// tensor is read from the shard containing it. A tensor present in several shards is an error.
+    ///
+    /// Shards outside the directory of the first shard must be given as absolute paths.
+    pub fn with_shards(mut self, paths: Vec<PathBuf>) -> Self {
+        let dir_of = |p: &Path| match p.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let dir = paths
+            .first()
+            .map_or_else(|| PathBuf::from("."), |p| dir_of(p));
+        self.files = paths
+            .iter()
+            .map(|p| match p.file_name() {
+                Some(name) if dir_of(p) == dir => name.to_string_lossy().to_string(),
+                _ => p.display().to_string(),
+            })
+            .collect();
+        self.model_id = dir.display().to_string();
+        self
+    }
+
+    /// Read the metadata of a local GGUF file without loading its tensors, for example to check the
+    /// architecture or [context length](GgufMetadata::context_length) before choosing a model.
+    pub fn inspect_metadata(path: &Path) -> anyhow::Result<HashMap<String, GgufValue>> {
+        read_gguf_metadata(path)
+    }
+
     /// Source the tokenizer and chat template from this model ID (must contain `tokenizer.json` and `tokenizer_config.json`).
     pub fn with_tok_model_id(mut self, tok_model_id: impl ToString) -> Self {
         self.tok_model_id = Some(tok_model_id.to_string());
@@ -66,6 +104,12 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Set the dtype used to store the KV cache.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KVCacheDtype) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -151,24 +195,53 @@ impl GgufModelBuilder {
         self
     }
 
-    pub async fn build(self) -> anyhow::Result<Model> {
+    /// Scale the RoPE frequencies to extend the context window past the one the model was trained
+    /// with. The scaling is written into the `rope.scaling` GGUF metadata before loading, and the factor must be
+    /// greater than 1. `context_length` in the GGUF metadata must be the extended context length, a
+    /// warning is logged if the metadata does not already use the same scaling. Only the `llama`
+    /// architecture supports this.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScalingConfig) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
+    /// Build the loader of this model, without loading it. This is used to load the model as the
+    /// draft model of a [`SpeculativeModelBuilder`](crate::SpeculativeModelBuilder).
+    pub(crate) fn build_loader(&self) -> anyhow::Result<Box<dyn Loader>> {
+        if let Some(rope_scaling) = &self.rope_scaling {
+            if rope_scaling.factor() <= 1. {
+                anyhow::bail!(
+                    "RoPE scaling factor must be greater than 1, got {}.",
+                    rope_scaling.factor()
+                );
+            }
+        }
+
         let config = GGUFSpecificConfig {
             prompt_batchsize: self.prompt_batchsize,
-            topology: self.topology,
+            kv_cache_dtype: self.kv_cache_dtype,
+            topology: self.topology.clone(),
         };
 
+        let mut loader = GGUFLoaderBuilder::new(
+            self.chat_template.clone(),
+            self.tok_model_id.clone(),
+            self.model_id.clone(),
+            self.files.clone(),
+            config,
+        );
+        if let Some(rope_scaling) = &self.rope_scaling {
+            loader = loader.with_rope_scaling(rope_scaling.clone());
+        }
+        Ok(loader.build())
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
         if self.with_logging {
             initialize_logging();
         }
 
-        let loader = GGUFLoaderBuilder::new(
-            self.chat_template,
-            self.tok_model_id,
-            self.model_id,
-            self.files,
-            config,
-        )
-        .build();
+        let loader = self.build_loader()?;
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
@@ -9,6 +9,9 @@
 //! - [`GgufXLoraModelBuilder`]
 //! - [`VisionModelBuilder`]
 //! - [`AnyMoeModelBuilder`]
+//! - [`ContrastiveModelBuilder`]
+//! - [`EmbeddingModelBuilder`]
+//! - [`SpeculativeModelBuilder`]
 //!
 //! Check out the [`v0_4_api`] module for concise documentation of this, newer API.
 //!
@@ -51,13 +54,16 @@
 //! ```
 
 mod anymoe;
+mod contrastive_model;
 mod diffusion_model;
+mod embedding_model;
 mod gguf;
 mod gguf_lora_model;
 mod gguf_xlora_model;
 mod lora_model;
 mod messages;
 mod model;
+mod speculative_model;
 mod text_model;
 mod vision_model;
 mod xlora_model;
@@ -65,7 +71,9 @@ mod xlora_model;
 /// This will be the API as of v0.4.0. Other APIs will *not* be deprecated, but moved into a module such as this one.
 pub mod v0_4_api {
     pub use super::anymoe::AnyMoeModelBuilder;
+    pub use super::contrastive_model::ContrastiveModelBuilder;
     pub use super::diffusion_model::DiffusionModelBuilder;
+    pub use super::embedding_model::{EmbeddingModel, EmbeddingModelBuilder};
     pub use super::gguf::GgufModelBuilder;
     pub use super::gguf_lora_model::GgufLoraModelBuilder;
     pub use super::gguf_xlora_model::GgufXLoraModelBuilder;
@@ -73,7 +81,8 @@ pub mod v0_4_api {
     pub use super::messages::{
         RequestBuilder, RequestLike, TextMessageRole, TextMessages, VisionMessages,
     };
-    pub use super::model::{best_device, Model};
+    pub use super::model::{best_device, Model, StreamingTextModel};
+    pub use super::speculative_model::SpeculativeModelBuilder;
     pub use super::text_model::{PagedAttentionMetaBuilder, TextModelBuilder};
     pub use super::vision_model::VisionModelBuilder;
     pub use super::xlora_model::XLoraModelBuilder;
@@ -15,6 +15,9 @@ pub trait RequestLike {
     fn take_constraint(&mut self) -> Constraint;
     fn take_tools(&mut self) -> Option<(Vec<Tool>, ToolChoice)>;
     fn take_sampling_params(&mut self) -> SamplingParams;
+    fn return_prompt_tokens(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -69,6 +72,56 @@ impl TextMessages {
         self.0.clear();
         self
     }
+
+    /// Parse messages in the OpenAI format, `[{"role": "user", "content": "Hello!"}]`. The roles
+    /// must be `system`, `user` or `assistant`.
+    pub fn from_openai_json(json: &str) -> anyhow::Result<Self> {
+        let messages: Vec<HashMap<String, String>> = serde_json::from_str(json)?;
+        let mut this = Self::new();
+        for mut message in messages {
+            let (Some(role), Some(content)) = (message.remove("role"), message.remove("content"))
+            else {
+                anyhow::bail!("Each message must have a `role` and a `content`.");
+            };
+            let role = match role.as_str() {
+                "system" => TextMessageRole::System,
+                "user" => TextMessageRole::User,
+                "assistant" => TextMessageRole::Assistant,
+                other => anyhow::bail!(
+                    "Unknown message role `{other}`, expected `system`, `user` or `assistant`."
+                ),
+            };
+            this = this.add_message(role, content);
+        }
+        Ok(this)
+    }
+
+    /// Serialize the messages in the OpenAI format, as parsed by [`Self::from_openai_json`].
+    pub fn to_openai_json(&self) -> String {
+        let messages = self
+            .0
+            .iter()
+            .map(|message| {
+                message
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = match value {
+                            Either::Left(text) => serde_json::Value::from(text.clone()),
+                            Either::Right(parts) => {
+                                serde_json::Value::from_iter(parts.iter().map(|part| {
+                                    serde_json::Value::from_iter(
+                                        part.iter().map(|(k, v)| (k.clone(), v.clone())),
+                                    )
+                                }))
+                            }
+                        };
+                        (key.clone(), value)
+                    })
+                    .collect::<IndexMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&messages).expect("JSON values always serialize.")
+    }
 }
 
 impl RequestLike for TextMessages {
@@ -271,6 +324,7 @@ pub struct RequestBuilder {
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     adapters: Vec<String>,
     return_logprobs: bool,
+    return_prompt_tokens: bool,
     constraint: Constraint,
     tools: Vec<Tool>,
     tool_choice: ToolChoice,
@@ -291,6 +345,7 @@ impl From<TextMessages> for RequestBuilder {
             logits_processors: Vec::new(),
             adapters: Vec::new(),
             return_logprobs: false,
+            return_prompt_tokens: false,
             constraint: Constraint::None,
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
@@ -307,6 +362,7 @@ impl From<VisionMessages> for RequestBuilder {
             logits_processors: Vec::new(),
             adapters: Vec::new(),
             return_logprobs: false,
+            return_prompt_tokens: false,
             constraint: Constraint::None,
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
@@ -323,6 +379,7 @@ impl RequestBuilder {
             logits_processors: Vec::new(),
             adapters: Vec::new(),
             return_logprobs: false,
+            return_prompt_tokens: false,
             constraint: Constraint::None,
             tools: Vec::new(),
             tool_choice: ToolChoice::Auto,
@@ -378,6 +435,12 @@ impl RequestBuilder {
         self
     }
 
+    /// Include the token ids the prompt was tokenized into in the response.
+    pub fn return_prompt_tokens(mut self, return_prompt_tokens: bool) -> Self {
+        self.return_prompt_tokens = return_prompt_tokens;
+        self
+    }
+
     pub fn set_constraint(mut self, constraint: Constraint) -> Self {
         self.constraint = constraint;
         self
@@ -419,11 +482,37 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_xtc(mut self, probability: f64, threshold: f64) -> Self {
+        self.sampling_params.xtc_probability = Some(probability);
+        self.sampling_params.xtc_threshold = Some(threshold);
+        self
+    }
+
+    pub fn set_sampler_typical_p(mut self, typical_p: f64) -> Self {
+        self.sampling_params.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn set_sampler_epsilon_cutoff(mut self, epsilon_cutoff: f32) -> Self {
+        self.sampling_params.epsilon_cutoff = Some(epsilon_cutoff);
+        self
+    }
+
+    pub fn set_sampler_eta_cutoff(mut self, eta_cutoff: f32) -> Self {
+        self.sampling_params.eta_cutoff = Some(eta_cutoff);
+        self
+    }
+
     pub fn set_sampler_topn_logprobs(mut self, top_n_logprobs: usize) -> Self {
         self.sampling_params.top_n_logprobs = top_n_logprobs;
         self
     }
 
+    pub fn set_sampler_collect_all_logprobs(mut self, collect_all_logprobs: bool) -> Self {
+        self.sampling_params.collect_all_logprobs = collect_all_logprobs;
+        self
+    }
+
     pub fn set_sampler_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
         self.sampling_params.frequency_penalty = Some(frequency_penalty);
         self
@@ -434,21 +523,78 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_no_repeat_ngram_size(mut self, no_repeat_ngram_size: usize) -> Self {
+        self.sampling_params.no_repeat_ngram_size = Some(no_repeat_ngram_size);
+        self
+    }
+
     pub fn set_sampler_stop_toks(mut self, stop_toks: StopTokens) -> Self {
         self.sampling_params.stop_toks = Some(stop_toks);
         self
     }
 
+    /// Stop generating once the completion contains one of these strings, such as `"\n\nHuman:"`.
+    /// Strings spanning several tokens are matched on the decoded text, and the stop string is
+    /// not part of the response.
+    pub fn set_sampler_stop_sequences(
+        self,
+        stop_sequences: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.set_sampler_stop_toks(StopTokens::Seqs(
+            stop_sequences.into_iter().map(|s| s.to_string()).collect(),
+        ))
+    }
+
     pub fn set_sampler_max_len(mut self, max_len: usize) -> Self {
         self.sampling_params.max_len = Some(max_len);
         self
     }
 
+    pub fn set_sampler_min_new_tokens(mut self, min_new_tokens: usize) -> Self {
+        self.sampling_params.min_new_tokens = Some(min_new_tokens);
+        self
+    }
+
+    /// Sample with classifier-free guidance, optionally against a negative prompt. Without a
+    /// negative prompt, the last prompt token is used as the unconditional context. A `cfg_scale`
+    /// of `1.0` is unguided sampling.
+    pub fn set_sampler_cfg(mut self, cfg_scale: f64, negative_prompt: Option<String>) -> Self {
+        self.sampling_params.cfg_scale = Some(cfg_scale);
+        self.sampling_params.negative_prompt = negative_prompt;
+        self
+    }
+
+    /// Seed the sampling of this request so that it is reproducible.
+    pub fn set_sampler_seed(mut self, seed: u64) -> Self {
+        self.sampling_params.seed = Some(seed);
+        self
+    }
+
+    /// Never sample the given token IDs, whatever their probability.
+    pub fn set_sampler_bad_token_ids(mut self, bad_token_ids: Vec<u32>) -> Self {
+        self.sampling_params.bad_token_ids = Some(bad_token_ids);
+        self
+    }
+
+    /// Heal the end of the prompt, so that the first generated token may complete its last word
+    /// or character. The healed text is not part of the completion.
+    pub fn set_sampler_token_healing(mut self, token_healing: bool) -> Self {
+        self.sampling_params.token_healing = token_healing;
+        self
+    }
+
     pub fn set_sampler_logits_bias(mut self, logits_bias: HashMap<u32, f32>) -> Self {
         self.sampling_params.logits_bias = Some(logits_bias);
         self
     }
 
+    /// Bias the logits of the tokens of each text, on top of the bias set by
+    /// [`RequestBuilder::set_sampler_logits_bias`].
+    pub fn set_sampler_string_logits_bias(mut self, logits_bias: HashMap<String, f32>) -> Self {
+        self.sampling_params.string_logits_bias = Some(logits_bias);
+        self
+    }
+
     pub fn set_sampler_n_choices(mut self, n_choices: usize) -> Self {
         self.sampling_params.n_choices = n_choices;
         self
@@ -458,6 +604,11 @@ impl RequestBuilder {
         self.sampling_params.dry_params = Some(dry_params);
         self
     }
+
+    pub fn set_sampler_mirostat(mut self, mirostat: MirostatConfig) -> Self {
+        self.sampling_params.mirostat = Some(mirostat);
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
@@ -506,6 +657,10 @@ impl RequestLike for RequestBuilder {
         self.return_logprobs
     }
 
+    fn return_prompt_tokens(&self) -> bool {
+        self.return_prompt_tokens
+    }
+
     fn take_constraint(&mut self) -> Constraint {
         let mut other = Constraint::None;
         std::mem::swap(&mut other, &mut self.constraint);
@@ -529,3 +684,24 @@ impl RequestLike for RequestBuilder {
         other
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{TextMessageRole, TextMessages};
+
+    #[test]
+    fn test_openai_json_roundtrip() -> anyhow::Result<()> {
+        let json = r#"[{"role":"system","content":"Be brief."},{"role":"user","content":"Hi"},{"role":"assistant","content":"Hello!"}]"#;
+        let messages = TextMessages::from_openai_json(json)?;
+        let expected = TextMessages::new()
+            .add_message(TextMessageRole::System, "Be brief.")
+            .add_message(TextMessageRole::User, "Hi")
+            .add_message(TextMessageRole::Assistant, "Hello!");
+        assert_eq!(messages, expected);
+        assert_eq!(messages.to_openai_json(), json);
+
+        assert!(TextMessages::from_openai_json(r#"[{"role":"tool","content":"1"}]"#).is_err());
+        assert!(TextMessages::from_openai_json(r#"[{"role":"user"}]"#).is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use anyhow::Context;
-use candle_core::{Device, Result};
+use candle_core::{Device, Result, Tensor};
+use futures::{Stream, TryStreamExt};
 use mistralrs_core::*;
 use std::sync::Arc;
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::RequestLike;
+use crate::{RequestLike, TextMessages};
 
 /// Gets the best device, cpu, cuda if compiled with CUDA, or Metal
 pub fn best_device(force_cpu: bool) -> Result<Device> {
@@ -51,45 +52,94 @@ impl Model {
         Self { runner }
     }
 
-    /// Generate with the model.
-    pub async fn send_chat_request<R: RequestLike>(
-        &self,
+    fn chat_request<R: RequestLike>(
         mut request: R,
-    ) -> anyhow::Result<ChatCompletionResponse> {
-        let (tx, mut rx) = channel(1);
-
+        response: Sender<Response>,
+        is_streaming: bool,
+    ) -> Request {
         let (tools, tool_choice) = if let Some((a, b)) = request.take_tools() {
             (Some(a), Some(b))
         } else {
             (None, None)
         };
-        let request = Request::Normal(NormalRequest {
+        Request::Normal(NormalRequest {
             messages: request.take_messages(),
             sampling_params: request.take_sampling_params(),
-            response: tx,
+            response,
             return_logprobs: request.return_logprobs(),
-            is_streaming: false,
+            is_streaming,
             id: 0,
             constraint: request.take_constraint(),
             suffix: None,
             adapters: request.take_adapters(),
             tools,
             tool_choice,
+            timeout_ms: None,
+            return_prompt_tokens: request.return_prompt_tokens(),
             logits_processors: request.take_logits_processors(),
-        });
+        })
+    }
 
+    /// Generate with the model.
+    pub async fn send_chat_request<R: RequestLike>(
+        &self,
+        request: R,
+    ) -> anyhow::Result<ChatCompletionResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Self::chat_request(request, tx, false);
         self.runner.get_sender()?.send(request).await?;
 
-        let ResponseOk::Done(response) = rx
-            .recv()
-            .await
-            .context("Channel was erroneously closed!")?
-            .as_result()?
-        else {
-            anyhow::bail!("Got unexpected response type.")
-        };
+        recv_chat_response(&mut rx).await
+    }
 
-        Ok(response)
+    /// Generate with the model for several requests, returning one result per request in the
+    /// order of `requests`, whatever order they finish in.
+    ///
+    /// All requests are sent before waiting for any response, so the engine schedules them as it
+    /// would concurrent requests: at most the maximum number of sequences run at once, and the
+    /// others wait in the queue. A failing request does not stop the others.
+    pub async fn send_chat_requests_batch<R: RequestLike>(
+        &self,
+        requests: Vec<R>,
+    ) -> anyhow::Result<Vec<anyhow::Result<ChatCompletionResponse>>> {
+        let sender = self.runner.get_sender()?;
+        let mut receivers = Vec::with_capacity(requests.len());
+        for request in requests {
+            let (tx, rx) = channel(1);
+            sender.send(Self::chat_request(request, tx, false)).await?;
+            receivers.push(rx);
+        }
+
+        Ok(recv_chat_responses(receivers).await)
+    }
+
+    /// Generate with the model, streaming the response chunks as they are produced. The stream
+    /// ends after every choice has finished, or after the first error.
+    ///
+    /// Dropping the stream cancels the request: the engine stops generating for it as soon as it
+    /// fails to send the next chunk to the closed receiver.
+    pub async fn send_chat_request_streaming<R: RequestLike>(
+        &self,
+        request: R,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<ChatCompletionChunkResponse>>> {
+        let (tx, rx) = channel(10_000);
+
+        let request = Self::chat_request(request, tx, true);
+        self.runner.get_sender()?.send(request).await?;
+
+        Ok(futures::stream::unfold(Some(rx), |rx| async move {
+            let mut rx = rx?;
+            let chunk = match rx.recv().await?.as_result() {
+                Ok(ResponseOk::Chunk(chunk)) => chunk,
+                Ok(_) => {
+                    return Some((Err(anyhow::anyhow!("Got unexpected response type.")), None))
+                }
+                Err(e) => return Some((Err(e.into()), None)),
+            };
+            let done = chunk.choices.iter().all(|c| c.finish_reason.is_some());
+            Some((Ok(chunk), (!done).then_some(rx)))
+        }))
     }
 
     pub async fn generate_image(
@@ -116,6 +166,8 @@ impl Model {
             adapters: None,
             tool_choice: None,
             tools: None,
+            timeout_ms: None,
+            return_prompt_tokens: false,
             logits_processors: None,
         });
 
@@ -145,6 +197,44 @@ impl Model {
         Ok(self.runner.get_sender()?.send(request).await?)
     }
 
+    /// Activate adapters blended by weight, for example `[("a", 0.7), ("b", 0.3)]`: each adapter
+    /// delta is scaled by its weight before they are summed. Weights must be finite.
+    pub async fn activate_weighted_adapters<A: ToString>(
+        &self,
+        adapters: Vec<(A, f64)>,
+    ) -> anyhow::Result<()> {
+        let adapters = adapters
+            .into_iter()
+            .map(|(a, weight)| {
+                let a = a.to_string();
+                if !weight.is_finite() {
+                    anyhow::bail!("Weight of adapter `{a}` must be finite, got {weight}.");
+                }
+                Ok((a, weight))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let request = Request::ActivateWeightedAdapters(adapters);
+
+        Ok(self.runner.get_sender()?.send(request).await?)
+    }
+
+    /// Merge adapters into the base weights of the model, removing the per-layer adapter compute.
+    /// The adapter weights are dropped, so they cannot be activated again afterwards.
+    pub fn merge_adapters<A: ToString>(&self, adapters: Vec<A>) -> anyhow::Result<()> {
+        self.runner.merge_adapters(
+            &adapters
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Dequantized weight of the layer with this checkpoint name, for example
+    /// `model.layers.0.self_attn.q_proj`, as a float tensor of the original shape.
+    pub fn get_layer_weights(&self, layer_name: impl ToString) -> anyhow::Result<Tensor> {
+        self.runner.get_layer_weights(&layer_name.to_string())
+    }
+
     /// Reapply ISQ to the model. This will be done on whatever device the model is already on.
     pub async fn re_isq_model(&self, isq_type: IsqType) -> anyhow::Result<()> {
         let request = Request::ReIsq(isq_type);
@@ -157,3 +247,112 @@ impl Model {
         self.runner.config()
     }
 }
+
+async fn recv_chat_response(rx: &mut Receiver<Response>) -> anyhow::Result<ChatCompletionResponse> {
+    let ResponseOk::Done(response) = rx
+        .recv()
+        .await
+        .context("Channel was erroneously closed!")?
+        .as_result()?
+    else {
+        anyhow::bail!("Got unexpected response type.")
+    };
+
+    Ok(response)
+}
+
+/// Wait for the response of each receiver, in order.
+async fn recv_chat_responses(
+    receivers: Vec<Receiver<Response>>,
+) -> Vec<anyhow::Result<ChatCompletionResponse>> {
+    let mut responses = Vec::with_capacity(receivers.len());
+    for mut rx in receivers {
+        responses.push(recv_chat_response(&mut rx).await);
+    }
+    responses
+}
+
+/// Stream the text generated for a chat request.
+pub trait StreamingTextModel {
+    /// Send the messages and stream the content of each delta as it is generated. If there are
+    /// several choices, the deltas of one step are concatenated. Dropping the stream cancels the
+    /// request.
+    fn stream_chat(
+        &self,
+        messages: impl Into<TextMessages>,
+    ) -> impl Stream<Item = anyhow::Result<String>> + '_;
+}
+
+impl StreamingTextModel for Model {
+    fn stream_chat(
+        &self,
+        messages: impl Into<TextMessages>,
+    ) -> impl Stream<Item = anyhow::Result<String>> + '_ {
+        futures::stream::once(self.send_chat_request_streaming(messages.into()))
+            .try_flatten()
+            .try_filter_map(|chunk| async move {
+                let text = chunk
+                    .choices
+                    .into_iter()
+                    .map(|choice| choice.delta.content)
+                    .collect::<String>();
+                Ok((!text.is_empty()).then_some(text))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::{ChatCompletionResponse, Response, Usage};
+    use tokio::sync::mpsc::channel;
+
+    use super::recv_chat_responses;
+
+    fn response(id: &str) -> ChatCompletionResponse {
+        ChatCompletionResponse {
+            id: id.to_string(),
+            choices: Vec::new(),
+            created: 0,
+            model: String::new(),
+            system_fingerprint: String::new(),
+            object: "chat.completion".to_string(),
+            usage: Usage {
+                completion_tokens: 0,
+                prompt_tokens: 0,
+                total_tokens: 0,
+                avg_tok_per_sec: 0.,
+                avg_prompt_tok_per_sec: 0.,
+                avg_compl_tok_per_sec: 0.,
+                total_time_sec: 0.,
+                total_prompt_time_sec: 0.,
+                total_completion_time_sec: 0.,
+                speculative_acceptance_rate: None,
+                speculative_tokens_per_draft_call: None,
+            },
+            prompt_token_ids: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_responses_keep_request_order() {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..3).map(|_| channel(1)).unzip();
+        let collect = tokio::spawn(recv_chat_responses(receivers));
+
+        // Complete the requests out of order
+        for i in [2, 0, 1] {
+            senders[i]
+                .send(Response::Done(response(&i.to_string())))
+                .await
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        let responses = collect.await.unwrap();
+        assert_eq!(responses.len(), 3);
+        let ids = responses
+            .into_iter()
+            .map(|r| r.unwrap().id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["0", "1", "2"]);
+    }
+}
@@ -0,0 +1,141 @@
+use mistralrs_core::*;
+
+use crate::{best_device, GgufModelBuilder, Model, TextModelBuilder};
+
+/// Wrapper of [`TextModelBuilder`] for speculative decoding with a GGUF draft model.
+///
+/// The model of the [`TextModelBuilder`] is the target. The draft model is loaded from the files of
+/// the [`GgufModelBuilder`], and only its model ID, files, tokenizer model ID, chat template,
+/// topology and RoPE scaling are used. Both models are loaded with the settings of the target,
+/// except that in-situ quantization is not applied to the already quantized draft.
+pub struct SpeculativeModelBuilder {
+    text_model: TextModelBuilder,
+    draft_model: GgufModelBuilder,
+    gamma: usize,
+    vocab_mismatch_policy: VocabMismatchPolicy,
+    draft_branches: usize,
+    adaptive_gamma: Option<AdaptiveGamma>,
+}
+
+impl SpeculativeModelBuilder {
+    /// `gamma` is the number of tokens generated by the draft model before each run of the target.
+    pub fn from_text_model_builder(
+        text_model: TextModelBuilder,
+        draft_model: GgufModelBuilder,
+        gamma: usize,
+    ) -> Self {
+        Self {
+            text_model,
+            draft_model,
+            gamma,
+            vocab_mismatch_policy: VocabMismatchPolicy::Strict,
+            draft_branches: 1,
+            adaptive_gamma: None,
+        }
+    }
+
+    /// Set how the tokenizer vocabs of the target and draft models are compared. Use
+    /// [`VocabMismatchPolicy::IgnoreSpecial`] if the draft tokenizer is built from the GGUF file
+    /// and only differs from the target one by its special tokens.
+    /// Use [`VocabMismatchPolicy::TokenMap`] if the draft tokenizer assigns other ids or only shares
+    /// most of the target vocab.
+    pub fn with_vocab_mismatch_policy(mut self, policy: VocabMismatchPolicy) -> Self {
+        self.vocab_mismatch_policy = policy;
+        self
+    }
+
+    /// Draft a tree of `branches` branches of `gamma` tokens per step instead of one chain, keeping
+    /// the most likely continuations at each depth, and verify them in one batched run of the
+    /// target model.
+    pub fn with_draft_branches(mut self, branches: usize) -> Self {
+        self.draft_branches = branches;
+        self
+    }
+
+    /// Adjust the number of draft tokens per step between `gamma_min` and `gamma_max`, starting
+    /// from `gamma`: more while the draft tokens are mostly accepted, fewer while they are not.
+    pub fn with_adaptive_gamma(mut self, gamma_min: usize, gamma_max: usize) -> Self {
+        self.adaptive_gamma = Some(AdaptiveGamma {
+            gamma_min,
+            gamma_max,
+        });
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Model> {
+        if let Some(rope_scaling) = &self.text_model.rope_scaling {
+            if rope_scaling.factor() <= 1. {
+                anyhow::bail!(
+                    "RoPE scaling factor must be greater than 1, got {}.",
+                    rope_scaling.factor()
+                );
+            }
+        }
+
+        let config = NormalSpecificConfig {
+            use_flash_attn: self.text_model.use_flash_attn,
+            prompt_batchsize: self.text_model.prompt_batchsize,
+            kv_cache_dtype: self.text_model.kv_cache_dtype,
+            topology: self.text_model.topology,
+            organization: self.text_model.organization,
+            isq_exclude: self.text_model.isq_exclude,
+            isq_layer_types: self.text_model.isq_layer_types,
+            write_uqff: self.text_model.write_uqff,
+            from_uqff: self.text_model.from_uqff,
+        };
+
+        if self.text_model.with_logging {
+            initialize_logging();
+        }
+
+        let mut target = NormalLoaderBuilder::new(
+            config,
+            self.text_model.chat_template,
+            self.text_model.tokenizer_json,
+            Some(self.text_model.model_id),
+        )
+        .with_no_kv_cache(self.text_model.no_kv_cache);
+        if let Some(rope_scaling) = self.text_model.rope_scaling {
+            target = target.with_rope_scaling(rope_scaling);
+        }
+        let target = target.build(self.text_model.loader_type)?;
+        let draft = self.draft_model.build_loader()?;
+
+        let loader = SpeculativeLoader {
+            target,
+            draft,
+            config: SpeculativeConfig {
+                gamma: self.gamma,
+                vocab_mismatch_policy: self.vocab_mismatch_policy,
+                draft_branches: self.draft_branches,
+                adaptive_gamma: self.adaptive_gamma,
+            },
+        };
+
+        // Load, into a Pipeline
+        let pipeline = loader.load_model_from_hf(
+            self.text_model.hf_revision,
+            self.text_model.token_source,
+            &self.text_model.dtype,
+            &best_device(self.text_model.force_cpu)?,
+            !self.text_model.with_logging,
+            self.text_model
+                .device_mapping
+                .unwrap_or(DeviceMapMetadata::dummy()),
+            self.text_model.isq,
+            None,
+        )?;
+
+        // PagedAttention is not supported by speculative decoding.
+        let scheduler_method = SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(self.text_model.max_num_seqs.try_into()?),
+        };
+
+        let runner = MistralRsBuilder::new(pipeline, scheduler_method)
+            .with_no_kv_cache(self.text_model.no_kv_cache)
+            .with_gemm_full_precision_f16(true)
+            .with_no_prefix_cache(true);
+
+        Ok(Model::new(runner.build()))
+    }
+}
@@ -14,16 +14,21 @@ pub struct TextModelBuilder {
     pub(crate) chat_template: Option<String>,
     pub(crate) tokenizer_json: Option<String>,
     pub(crate) device_mapping: Option<DeviceMapMetadata>,
+    pub(crate) auto_detect_quantization: bool,
 
     // Model running
     pub(crate) use_flash_attn: bool,
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
+    pub(crate) kv_cache_dtype: KVCacheDtype,
     pub(crate) topology: Option<Topology>,
     pub(crate) organization: IsqOrganization,
+    pub(crate) isq_exclude: Vec<String>,
+    pub(crate) isq_layer_types: Vec<(String, IsqType)>,
     pub(crate) loader_type: Option<NormalLoaderType>,
     pub(crate) dtype: ModelDType,
     pub(crate) force_cpu: bool,
     pub(crate) isq: Option<IsqType>,
+    pub(crate) rope_scaling: Option<RopeScalingConfig>,
 
     // Other things
     pub(crate) paged_attn_cfg: Option<PagedAttentionConfig>,
@@ -72,13 +77,17 @@ impl TextModelBuilder {
     /// - Token source is from the cache (.cache/huggingface/token)
     /// - Maximum number of sequences running is 32
     /// - Number of sequences to hold in prefix cache is 16.
+    /// - GPTQ or AWQ quantization of the weights is detected from the model config files
     pub fn new(model_id: impl ToString) -> Self {
         Self {
             model_id: model_id.to_string(),
             use_flash_attn: cfg!(feature = "flash-attn"),
             prompt_batchsize: None,
+            kv_cache_dtype: KVCacheDtype::FullPrecision,
             topology: None,
             organization: IsqOrganization::Default,
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
             write_uqff: None,
             from_uqff: None,
             chat_template: None,
@@ -89,21 +98,43 @@ impl TextModelBuilder {
             token_source: TokenSource::CacheToken,
             hf_revision: None,
             isq: None,
+            rope_scaling: None,
             paged_attn_cfg: None,
             max_num_seqs: 32,
             no_kv_cache: false,
             prefix_cache_n: Some(16),
             with_logging: false,
             device_mapping: None,
+            auto_detect_quantization: true,
         }
     }
 
+    /// Detect the quantization of a model from its `config.json` or `quantize_config.json`, using
+    /// the cached Hugging Face token. `model_id` may also be a local directory.
+    pub fn detect_quantization(model_id: &str) -> anyhow::Result<QuantizationInfo> {
+        mistralrs_core::detect_quantization(model_id, None, &TokenSource::CacheToken)
+    }
+
+    /// Set whether to detect GPTQ or AWQ quantized weights before loading, see
+    /// [`Self::detect_quantization`]. This is enabled by default, and is skipped when loading
+    /// from UQFF.
+    pub fn with_auto_detect_quantization(mut self, auto_detect_quantization: bool) -> Self {
+        self.auto_detect_quantization = auto_detect_quantization;
+        self
+    }
+
     /// Set the prompt batchsize to use for inference.
     pub fn with_prompt_batchsize(mut self, prompt_batchsize: NonZeroUsize) -> Self {
         self.prompt_batchsize = Some(prompt_batchsize);
         self
     }
 
+    /// Set the dtype used to store the KV cache.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KVCacheDtype) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -165,6 +196,32 @@ impl TextModelBuilder {
         self
     }
 
+    /// Keep layers whose name matches any of these regexes unquantized when applying ISQ, for
+    /// example `lm_head` or `layers\.[0-3]\.self_attn\.q_proj`. Names follow the checkpoint
+    /// (`model.layers.0.mlp.down_proj`); models which do not report layer names ignore this.
+    pub fn with_isq_exclusions(
+        mut self,
+        patterns: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.isq_exclude = patterns.into_iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Use a different ISQ type for layers whose name matches a regex, for example 8-bit attention
+    /// with `(r"self_attn", IsqType::Q8_0)`. The first matching regex is used; other layers use
+    /// the ISQ type from [`Self::with_isq`] or the topology. Names are matched as in
+    /// [`Self::with_isq_exclusions`], which takes precedence.
+    pub fn with_isq_layer_types(
+        mut self,
+        layer_types: impl IntoIterator<Item = (impl ToString, IsqType)>,
+    ) -> Self {
+        self.isq_layer_types = layer_types
+            .into_iter()
+            .map(|(p, ty)| (p.to_string(), ty))
+            .collect();
+        self
+    }
+
     /// Enable PagedAttention. Configure PagedAttention with a [`PagedAttentionConfig`] object, which
     /// can be created with sensible values with a [`PagedAttentionMetaBuilder`].
     ///
@@ -212,6 +269,15 @@ impl TextModelBuilder {
         self
     }
 
+    /// Scale the RoPE frequencies to extend the context window past the one the model was trained
+    /// with. The scaling is written into the model config before loading, and the factor must be
+    /// greater than 1. `max_position_embeddings` in the model config must be the extended context
+    /// length, a warning is logged if the config does not already use the same scaling.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScalingConfig) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
     /// Path to read a UQFF file from.
     pub fn from_uqff(mut self, path: PathBuf) -> Self {
         self.from_uqff = Some(path);
@@ -232,11 +298,39 @@ impl TextModelBuilder {
     }
 
     pub async fn build(self) -> anyhow::Result<Model> {
+        if let Some(rope_scaling) = &self.rope_scaling {
+            if rope_scaling.factor() <= 1. {
+                anyhow::bail!(
+                    "RoPE scaling factor must be greater than 1, got {}.",
+                    rope_scaling.factor()
+                );
+            }
+        }
+
+        let quantization = if self.auto_detect_quantization && self.from_uqff.is_none() {
+            mistralrs_core::detect_quantization(
+                &self.model_id,
+                self.hf_revision.clone(),
+                &self.token_source,
+            )?
+        } else {
+            QuantizationInfo::Unquantized
+        };
+        if matches!(quantization, QuantizationInfo::Gptq { .. }) && self.isq.is_some() {
+            anyhow::bail!(
+                "`{}` has GPTQ quantized weights, which cannot be quantized again with ISQ.",
+                self.model_id
+            );
+        }
+
         let config = NormalSpecificConfig {
             use_flash_attn: self.use_flash_attn,
             prompt_batchsize: self.prompt_batchsize,
+            kv_cache_dtype: self.kv_cache_dtype,
             topology: self.topology,
             organization: self.organization,
+            isq_exclude: self.isq_exclude,
+            isq_layer_types: self.isq_layer_types,
             write_uqff: self.write_uqff,
             from_uqff: self.from_uqff,
         };
@@ -245,14 +339,18 @@ impl TextModelBuilder {
             initialize_logging();
         }
 
-        let loader = NormalLoaderBuilder::new(
+        let mut loader = NormalLoaderBuilder::new(
             config,
             self.chat_template,
             self.tokenizer_json,
             Some(self.model_id),
         )
         .with_no_kv_cache(self.no_kv_cache)
-        .build(self.loader_type)?;
+        .with_quantization(&quantization)?;
+        if let Some(rope_scaling) = self.rope_scaling {
+            loader = loader.with_rope_scaling(rope_scaling);
+        }
+        let loader = loader.build(self.loader_type)?;
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
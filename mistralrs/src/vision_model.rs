@@ -18,11 +18,15 @@ pub struct VisionModelBuilder {
     // Model running
     pub(crate) use_flash_attn: bool,
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
+    pub(crate) kv_cache_dtype: KVCacheDtype,
     pub(crate) topology: Option<Topology>,
+    pub(crate) isq_exclude: Vec<String>,
+    pub(crate) isq_layer_types: Vec<(String, IsqType)>,
     pub(crate) loader_type: VisionLoaderType,
     pub(crate) dtype: ModelDType,
     pub(crate) force_cpu: bool,
     pub(crate) isq: Option<IsqType>,
+    pub(crate) rope_scaling: Option<RopeScalingConfig>,
 
     // Other things
     pub(crate) max_num_seqs: usize,
@@ -38,9 +42,12 @@ impl VisionModelBuilder {
             model_id: model_id.to_string(),
             use_flash_attn: cfg!(feature = "flash-attn"),
             topology: None,
+            isq_exclude: Vec::new(),
+            isq_layer_types: Vec::new(),
             write_uqff: None,
             from_uqff: None,
             prompt_batchsize: None,
+            kv_cache_dtype: KVCacheDtype::FullPrecision,
             chat_template: None,
             tokenizer_json: None,
             loader_type,
@@ -49,6 +56,7 @@ impl VisionModelBuilder {
             token_source: TokenSource::CacheToken,
             hf_revision: None,
             isq: None,
+            rope_scaling: None,
             max_num_seqs: 32,
             with_logging: false,
             device_mapping: None,
@@ -61,6 +69,12 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Set the dtype used to store the KV cache.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KVCacheDtype) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -109,6 +123,32 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Keep layers whose name matches any of these regexes unquantized when applying ISQ, for
+    /// example `lm_head` or `layers\.[0-3]\.self_attn\.q_proj`. Names follow the checkpoint
+    /// (`model.layers.0.mlp.down_proj`); models which do not report layer names ignore this.
+    pub fn with_isq_exclusions(
+        mut self,
+        patterns: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.isq_exclude = patterns.into_iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Use a different ISQ type for layers whose name matches a regex, for example 8-bit attention
+    /// with `(r"self_attn", IsqType::Q8_0)`. The first matching regex is used; other layers use
+    /// the ISQ type from [`Self::with_isq`] or the topology. Names are matched as in
+    /// [`Self::with_isq_exclusions`], which takes precedence.
+    pub fn with_isq_layer_types(
+        mut self,
+        layer_types: impl IntoIterator<Item = (impl ToString, IsqType)>,
+    ) -> Self {
+        self.isq_layer_types = layer_types
+            .into_iter()
+            .map(|(p, ty)| (p.to_string(), ty))
+            .collect();
+        self
+    }
+
     /// Set the maximum number of sequences which can be run at once.
     pub fn with_max_num_seqs(mut self, max_num_seqs: usize) -> Self {
         self.max_num_seqs = max_num_seqs;
@@ -128,6 +168,15 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Scale the RoPE frequencies to extend the context window past the one the model was trained
+    /// with. The scaling is written into the model config before loading, and the factor must be
+    /// greater than 1. `max_position_embeddings` in the model config must be the extended context
+    /// length, a warning is logged if the config does not already use the same scaling.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScalingConfig) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
     /// Path to read a UQFF file from.
     pub fn from_uqff(mut self, path: PathBuf) -> Self {
         self.from_uqff = Some(path);
@@ -148,10 +197,22 @@ impl VisionModelBuilder {
     }
 
     pub async fn build(self) -> anyhow::Result<Model> {
+        if let Some(rope_scaling) = &self.rope_scaling {
+            if rope_scaling.factor() <= 1. {
+                anyhow::bail!(
+                    "RoPE scaling factor must be greater than 1, got {}.",
+                    rope_scaling.factor()
+                );
+            }
+        }
+
         let config = VisionSpecificConfig {
             use_flash_attn: self.use_flash_attn,
             prompt_batchsize: self.prompt_batchsize,
+            kv_cache_dtype: self.kv_cache_dtype,
             topology: self.topology,
+            isq_exclude: self.isq_exclude,
+            isq_layer_types: self.isq_layer_types,
             write_uqff: self.write_uqff,
             from_uqff: self.from_uqff,
         };
@@ -160,13 +221,16 @@ impl VisionModelBuilder {
             initialize_logging();
         }
 
-        let loader = VisionLoaderBuilder::new(
+        let mut loader = VisionLoaderBuilder::new(
             config,
             self.chat_template,
             self.tokenizer_json,
             Some(self.model_id),
-        )
-        .build(self.loader_type);
+        );
+        if let Some(rope_scaling) = self.rope_scaling {
+            loader = loader.with_rope_scaling(rope_scaling);
+        }
+        let loader = loader.build(self.loader_type);
 
         // Load, into a Pipeline
         let pipeline = loader.load_model_from_hf(
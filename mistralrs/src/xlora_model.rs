@@ -33,8 +33,11 @@ impl XLoraModelBuilder {
         let config = NormalSpecificConfig {
             use_flash_attn: self.text_model.use_flash_attn,
             prompt_batchsize: self.text_model.prompt_batchsize,
+            kv_cache_dtype: self.text_model.kv_cache_dtype,
             topology: self.text_model.topology,
             organization: self.text_model.organization,
+            isq_exclude: self.text_model.isq_exclude,
+            isq_layer_types: self.text_model.isq_layer_types,
             write_uqff: self.text_model.write_uqff,
             from_uqff: self.text_model.from_uqff,
         };